@@ -0,0 +1,293 @@
+//! TCP Fast Open: 第一块数据随SYN发出, 对端收到SYN即可处理, 短连接节省一次往返
+//!
+//! 只支持Linux, 需要系统开启 `net.ipv4.tcp_fastopen` (客户端为1, 服务端为2, 两者都开启为3).
+//! 不支持或对端不接受时退回普通握手
+
+use socket2::Socket;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Sleep;
+
+/// 推迟握手的连接等待第一次写入的时间, 超时后不带数据发起握手
+pub const HANDSHAKE_DELAY: Duration = Duration::from_millis(200);
+
+/// 监听套接字接受SYN中的数据, `queue_len` 为尚未完成握手的TFO连接数上限
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_listener(socket: &Socket, queue_len: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    linux::set_int(
+        socket.as_raw_fd(),
+        libc::TCP_FASTOPEN,
+        queue_len.min(i32::MAX as u32) as i32,
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_listener(_socket: &Socket, _queue_len: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP fast open is not supported on this platform",
+    ))
+}
+
+/// 连接对端, 有对端的TFO cookie时推迟握手, 第一次写入的数据随SYN发出
+///
+/// 推迟握手时立即返回, 连接失败要到读写时才能发现; 没有cookie时正常握手并向对端请求cookie.
+/// 返回的连接需要用 [`FastOpenStream`] 包装, 否则后端先发送数据的协议会一直等待
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    use std::os::fd::AsRawFd;
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    // 内核不支持时忽略, 正常握手
+    let _ = linux::set_int(socket.as_raw_fd(), libc::TCP_FASTOPEN_CONNECT, 1);
+    socket.connect(addr).await
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    TcpStream::connect(addr).await
+}
+
+/// 连接的SYN是否携带了数据并被对端接受, 握手完成后才有意义
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn syn_data_acked(stream: &TcpStream) -> bool {
+    use std::os::fd::AsRawFd;
+    linux::syn_data_acked(stream.as_raw_fd())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn syn_data_acked(_stream: &TcpStream) -> bool {
+    false
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn start_handshake(stream: &TcpStream) {
+    use std::os::fd::AsRawFd;
+    linux::start_handshake(stream.as_raw_fd())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn start_handshake(_stream: &TcpStream) {}
+
+/// [`connect`] 返回的连接
+///
+/// [`HANDSHAKE_DELAY`] 内没有写入时主动发起握手, 避免后端先发送数据的协议(例如SSH、SMTP)一直等待.
+/// 第一次读到数据时调用 `on_established`, 参数为SYN中的数据是否被对端接受;
+/// 在此之前推迟的握手失败(例如连接被拒绝)时以错误调用
+pub struct FastOpenStream {
+    inner: TcpStream,
+    handshake_delay: Option<Pin<Box<Sleep>>>,
+    on_established: Option<EstablishedCallback>,
+}
+
+type EstablishedCallback = Box<dyn FnOnce(io::Result<bool>) + Send>;
+
+impl FastOpenStream {
+    pub fn new(
+        inner: TcpStream,
+        on_established: impl FnOnce(io::Result<bool>) + Send + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            handshake_delay: Some(Box::pin(tokio::time::sleep(HANDSHAKE_DELAY))),
+            on_established: Some(Box::new(on_established)),
+        }
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.inner
+    }
+
+    /// 还没有发起握手时立即发起
+    fn handshake_now(&mut self) {
+        if self.handshake_delay.take().is_some() {
+            start_handshake(&self.inner);
+        }
+    }
+
+    /// 连接建立前的读写错误如果是握手失败, 通知 `on_established`
+    fn check_connect_error<T>(&mut self, result: &Poll<io::Result<T>>) {
+        if let Poll::Ready(Err(err)) = result {
+            if is_connect_error(err.kind()) {
+                if let Some(on_established) = self.on_established.take() {
+                    on_established(Err(io::Error::new(err.kind(), err.to_string())));
+                }
+            }
+        }
+    }
+}
+
+/// 推迟的握手失败时读写返回的错误
+fn is_connect_error(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+impl AsyncRead for FastOpenStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(delay) = this.handshake_delay.as_mut() {
+            if delay.as_mut().poll(cx).is_ready() {
+                this.handshake_now();
+            }
+        }
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            if let Some(on_established) = this.on_established.take() {
+                on_established(Ok(syn_data_acked(&this.inner)));
+            }
+        }
+        this.check_connect_error(&result);
+        result
+    }
+}
+
+impl AsyncWrite for FastOpenStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // 第一次写入由内核发起握手
+        this.handshake_delay = None;
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.check_connect_error(&result);
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.handshake_now();
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::io;
+    use std::os::fd::RawFd;
+
+    /// `tcp_info.tcpi_options` 中表示SYN携带的数据被接受的标志
+    const TCPI_OPT_SYN_DATA: u8 = 32;
+
+    pub(super) fn set_int(fd: RawFd, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                option,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(super) fn syn_data_acked(fd: RawFd) -> bool {
+        // 只需要 `tcp_info` 开头的几个字段, 内核按传入的长度截断
+        let mut info = [0u8; 8];
+        let mut len = info.len() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                info.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        // tcpi_state, tcpi_ca_state, tcpi_retransmits, tcpi_probes, tcpi_backoff, tcpi_options
+        result == 0 && len >= 6 && info[5] & TCPI_OPT_SYN_DATA != 0
+    }
+
+    /// 推迟握手的连接写入0字节会发起不带数据的握手, 已经发起过时没有作用
+    pub(super) fn start_handshake(fd: RawFd) {
+        let buf = [0u8; 0];
+        unsafe {
+            libc::send(
+                fd,
+                buf.as_ptr() as *const libc::c_void,
+                0,
+                libc::MSG_NOSIGNAL | libc::MSG_DONTWAIT,
+            );
+        }
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn listen() -> tokio::net::TcpListener {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _ = set_listener(&Socket::from(listener.try_clone().unwrap()), 16);
+        listener.set_nonblocking(true).unwrap();
+        tokio::net::TcpListener::from_std(listener).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fast_open_stream() {
+        let listener = listen().await;
+        let addr = listener.local_addr().unwrap();
+
+        // 第一次连接取得cookie, 第二次连接数据随SYN发出
+        for _ in 0..2 {
+            let (used_tx, used_rx) = tokio::sync::oneshot::channel();
+            let mut client = FastOpenStream::new(connect(addr).await.unwrap(), move |used| {
+                let _ = used_tx.send(used.unwrap());
+            });
+            client.write_all(b"ping").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            server.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+            // 是否真的使用了TFO取决于系统设置, 这里只检查回调被调用
+            used_rx.await.unwrap();
+        }
+
+        // 后端先发送数据时, 推迟的握手在等待后发起
+        let mut client = FastOpenStream::new(connect(addr).await.unwrap(), |_| {});
+        let mut buf = [0u8; 5];
+        let (result, server) = tokio::join!(client.read_exact(&mut buf), async {
+            let (mut server, _) = listener.accept().await.unwrap();
+            server.write_all(b"hello").await.unwrap();
+            server
+        });
+        result.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(server);
+    }
+}
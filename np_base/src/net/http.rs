@@ -0,0 +1,113 @@
+use crate::net::{tls, BoxedStream};
+use anyhow::anyhow;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
+
+/// 简单的HTTP(S)接口地址, 只用于POST JSON
+pub struct HttpEndpoint {
+    pub(crate) url: String,
+    pub(crate) is_https: bool,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) path: String,
+}
+
+impl HttpEndpoint {
+    /// 地址格式为 `http(s)://主机[:端口][/路径]`
+    pub fn parse(url: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow!("invalid url: {url}");
+        let (is_https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let default_port = if is_https { 443 } else { 80 };
+        let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+            // [IPv6]:端口
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            match rest.strip_prefix(':') {
+                Some(port) => (host, port.parse().map_err(|_| invalid())?),
+                None if rest.is_empty() => (host, default_port),
+                None => return Err(invalid()),
+            }
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+                None => (authority, default_port),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            url: url.to_string(),
+            is_https,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    #[inline]
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// POST JSON请求, 返回HTTP状态码
+    pub async fn post(&self, headers: &[(&str, String)], body: &str) -> anyhow::Result<u16> {
+        let addr = if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream: BoxedStream = if self.is_https {
+            let connector = TlsConnector::from(Arc::new(tls::client_config(true, "")));
+            let server_name = ServerName::try_from(self.host.as_str())
+                .map_err(|_| anyhow!("invalid TLS server name: {}", self.host))?;
+            Box::new(connector.connect(server_name, stream).await?)
+        } else {
+            Box::new(stream)
+        };
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        // 只需要状态行: HTTP/1.1 200 OK
+        let mut response = Vec::new();
+        let mut buf = [0u8; 512];
+        while !response.windows(2).any(|x| x == b"\r\n") {
+            let len = stream.read(&mut buf).await?;
+            if len == 0 || response.len() > 8192 {
+                break;
+            }
+            response.extend_from_slice(&buf[..len]);
+        }
+        let status_line = String::from_utf8_lossy(&response);
+        status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| anyhow!("invalid response from {}", self.url))
+    }
+}
@@ -1,14 +1,58 @@
+use socket2::Socket;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 
+pub mod fast_open;
+pub mod http;
 pub mod session_delegate;
+pub mod splice;
 pub mod tcp_server;
 pub mod tcp_session;
 pub mod tls;
 pub mod udp_server;
 pub mod udp_session;
+#[cfg(unix)]
+pub mod unix_server;
+pub mod ws;
+
+/// 监听地址为IPv6通配地址时需要设置的 `IPV6_V6ONLY`, 其他地址返回None
+pub(crate) fn v6only_for(addr: &str, ipv6_only: Option<bool>) -> Option<(SocketAddr, bool)> {
+    match addr.parse::<SocketAddr>() {
+        Ok(addr @ SocketAddr::V6(v6)) if v6.ip().is_unspecified() => {
+            ipv6_only.map(|only_v6| (addr, only_v6))
+        }
+        _ => None,
+    }
+}
+
+/// 把套接字绑定到指定网卡(`SO_BINDTODEVICE`), 需要root或 `CAP_NET_RAW` 权限
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn bind_device(socket: &Socket, device: &str) -> anyhow::Result<()> {
+    socket.bind_device(Some(device.as_bytes())).map_err(|err| {
+        if err.kind() == std::io::ErrorKind::PermissionDenied {
+            anyhow::anyhow!("bind to device '{device}' requires root or CAP_NET_RAW: {err}")
+        } else {
+            anyhow::anyhow!("bind to device '{device}' failed: {err}")
+        }
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn bind_device(_socket: &Socket, device: &str) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "bind to device '{device}' is unsupported on this platform"
+    ))
+}
+
+/// 可以作为会话运行的双向字节流
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
 
 pub type SendMessageFuncType =
     Box<dyn Fn() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
@@ -20,4 +64,8 @@ pub enum WriterMessage {
     Send(Vec<u8>, bool),
     SendTo(Vec<u8>, SocketAddr),
     SendAndThen(Vec<u8>, SendMessageFuncType),
+    /// 写入后不立即刷新, 与时间窗口内的后续写入合并后再刷新, 写入缓存后回调
+    ///
+    /// 只对流式会话有效, UDP会话与 `SendAndThen` 相同
+    SendCoalesced(Vec<u8>, Duration, SendMessageFuncType),
 }
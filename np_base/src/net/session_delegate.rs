@@ -2,7 +2,9 @@ use crate::net::WriterMessage;
 use async_trait::async_trait;
 use bytes::BytesMut;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
 
 #[async_trait]
 pub trait SessionDelegate
@@ -23,6 +25,21 @@ where
         tx: UnboundedSender<WriterMessage>,
     ) -> anyhow::Result<()>;
 
+    /// TLS握手完成, 在会话开始前调用
+    ///
+    /// [`alpn_protocol`] 与对方协商出的ALPN协议
+    fn on_tls_handshake(&mut self, _alpn_protocol: Option<&[u8]>) {}
+
+    /// 对方在SYN中携带了数据(TCP Fast Open), 在会话开始前调用
+    fn on_fast_open(&mut self) {}
+
+    /// 强制结束会话的信号, 在会话开始后取一次
+    ///
+    /// 关闭消息排在尚未写完的数据之后, 对方不读取时写入会一直挂起, 需要立即结束时通知此信号
+    fn abort_signal(&self) -> Option<Arc<Notify>> {
+        None
+    }
+
     /// 会话关闭
     async fn on_session_close(&mut self) -> anyhow::Result<()>;
 
@@ -0,0 +1,182 @@
+//! 用 `splice()` 在两个TCP连接之间转发数据, 数据经内核管道直接搬运, 不复制到用户态
+//!
+//! 只支持Linux, 其他平台 [`is_supported`] 返回false, [`relay`] 直接返回错误
+
+use std::io;
+use tokio::net::TcpStream;
+
+/// 当前平台是否支持splice
+pub fn is_supported() -> bool {
+    cfg!(any(target_os = "linux", target_os = "android"))
+}
+
+/// 在两个连接之间双向转发, 一个方向读到EOF后关闭另一端的写方向, 两个方向都结束后返回
+///
+/// [`on_a_to_b`] [`on_b_to_a`] 每转发一块数据回调一次, 参数为字节数
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub async fn relay(
+    a: &TcpStream,
+    b: &TcpStream,
+    on_a_to_b: impl Fn(u64),
+    on_b_to_a: impl Fn(u64),
+) -> io::Result<()> {
+    tokio::try_join!(
+        linux::splice_half(a, b, on_a_to_b),
+        linux::splice_half(b, a, on_b_to_a)
+    )?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub async fn relay(
+    _a: &TcpStream,
+    _b: &TcpStream,
+    _on_a_to_b: impl Fn(u64),
+    _on_b_to_a: impl Fn(u64),
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "splice is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use socket2::SockRef;
+    use std::io;
+    use std::net::Shutdown;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    /// 每次搬运的最大字节数, 与管道的默认容量相同
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            unsafe {
+                Ok(Self {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                })
+            }
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    /// 单方向转发: 从 `from` 读入管道, 再从管道写到 `to`
+    pub(super) async fn splice_half(
+        from: &TcpStream,
+        to: &TcpStream,
+        on_bytes: impl Fn(u64),
+    ) -> io::Result<()> {
+        let pipe = Pipe::new()?;
+        loop {
+            // 每轮开始时管道为空, EAGAIN只可能是连接上没有数据
+            from.readable().await?;
+            let len = match from.try_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK_SIZE)
+            }) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            };
+
+            let mut remaining = len;
+            while remaining > 0 {
+                to.writable().await?;
+                match to.try_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), remaining)
+                }) {
+                    Ok(n) => remaining -= n,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            on_bytes(len as u64);
+        }
+        // 对端可能已经关闭, 忽略错误
+        let _ = SockRef::from(to).shutdown(Shutdown::Write);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, inlet) = pair(&listener).await;
+        let (outlet, mut backend) = pair(&listener).await;
+
+        let a_to_b = AtomicU64::new(0);
+        let b_to_a = AtomicU64::new(0);
+        let relay_task = relay(
+            &inlet,
+            &outlet,
+            |len| {
+                a_to_b.fetch_add(len, Ordering::Relaxed);
+            },
+            |len| {
+                b_to_a.fetch_add(len, Ordering::Relaxed);
+            },
+        );
+
+        let request: Vec<u8> = (0..300_000).map(|x| x as u8).collect();
+        let peers = async {
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            backend.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, request);
+
+            backend.write_all(b"pong").await.unwrap();
+            backend.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"pong");
+        };
+        let (result, _) = tokio::join!(relay_task, peers);
+        result.unwrap();
+        assert_eq!(a_to_b.load(Ordering::Relaxed), request.len() as u64);
+        assert_eq!(b_to_a.load(Ordering::Relaxed), 4);
+    }
+}
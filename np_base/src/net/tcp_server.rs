@@ -1,8 +1,12 @@
 use crate::net::session_delegate::CreateSessionDelegateCallback;
-use crate::net::tcp_session;
+use crate::net::tls::ReloadableServerConfig;
+use crate::net::{fast_open, tcp_session, BoxedStream};
+use anyhow::anyhow;
 use log::{debug, error};
 use log::{info, trace};
-use std::future::Future;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::{pending, Future};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
@@ -19,9 +23,30 @@ pub type StreamInitCallbackType = Arc<
         + Sync,
 >;
 
-struct TlsConfiguration {
-    certificate: String,
-    key: String,
+/// 在会话开始前接管新连接, 返回None表示连接已被处理, 否则按正常会话继续处理返回的连接
+///
+/// 参数为会话id、对端地址、连接和服务器关闭的通知
+pub type StreamTakeoverCallbackType = Arc<
+    dyn Fn(
+            u32,
+            SocketAddr,
+            TcpStream,
+            broadcast::Receiver<()>,
+        ) -> Pin<Box<dyn Future<Output = Option<TcpStream>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// 由其他服务接收并交给TCP服务器处理的连接, 例如WebSocket
+pub type StreamReceiverType = mpsc::UnboundedReceiver<(BoxedStream, SocketAddr)>;
+
+enum TlsConfiguration {
+    /// 证书和私钥文件
+    Files { certificate: String, key: String },
+    /// 已创建的配置, 例如需要协商ALPN时
+    Config(Arc<ServerConfig>),
+    /// 可以重新加载的配置, 每个新连接使用当时的配置
+    Reloadable(Arc<ReloadableServerConfig>),
 }
 
 struct Server {
@@ -30,31 +55,52 @@ struct Server {
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     async fn start_server(
         &self,
         listener: TcpListener,
         on_create_session_delegate_callback: CreateSessionDelegateCallback,
         on_stream_init_callback: Option<StreamInitCallbackType>,
+        on_stream_takeover_callback: Option<StreamTakeoverCallbackType>,
         tls_configuration: Option<TlsConfiguration>,
+        mut stream_receiver: Option<StreamReceiverType>,
+        fast_open: bool,
     ) -> anyhow::Result<()> {
-        let tls_acceptor: Option<TlsAcceptor> = match tls_configuration {
-            Some(tls_configuration) => {
-                let certs = super::tls::load_certs(&tls_configuration.certificate)?;
-                let keys = super::tls::load_private_key(&tls_configuration.key)?;
+        let tls_configuration = match tls_configuration {
+            Some(TlsConfiguration::Files { certificate, key }) => {
+                let certs = super::tls::load_certs(&certificate)?;
+                let keys = super::tls::load_private_key(&key)?;
 
                 let server_config = ServerConfig::builder()
                     .with_safe_defaults()
                     .with_no_client_auth()
                     .with_single_cert(certs, keys)?;
 
-                Some(TlsAcceptor::from(Arc::new(server_config)))
+                Some(TlsConfiguration::Config(Arc::new(server_config)))
             }
-            None => None,
+            other => other,
         };
 
         let mut session_id_seed = 0;
         loop {
-            let (mut stream, addr) = listener.accept().await?;
+            let (mut stream, addr) = select! {
+                result = listener.accept() => result?,
+                result = Self::recv_stream(&mut stream_receiver) => {
+                    match result {
+                        Some((stream, addr)) => {
+                            session_id_seed += 1;
+                            self.spawn_session(
+                                session_id_seed,
+                                addr,
+                                &on_create_session_delegate_callback,
+                                stream,
+                            );
+                        }
+                        None => stream_receiver = None,
+                    }
+                    continue;
+                }
+            };
 
             if let Some(ref on_stream_init_callback) = on_stream_init_callback {
                 match on_stream_init_callback(stream).await {
@@ -71,18 +117,53 @@ impl Server {
             session_id_seed += 1;
 
             let session_id = session_id_seed;
-            let tls_acceptor = tls_acceptor.clone();
-            let delegate = on_create_session_delegate_callback();
+            let syn_data_acked = fast_open && fast_open::syn_data_acked(&stream);
+            let tls_acceptor = match tls_configuration {
+                Some(TlsConfiguration::Config(ref server_config)) => {
+                    Some(TlsAcceptor::from(server_config.clone()))
+                }
+                Some(TlsConfiguration::Reloadable(ref server_config)) => {
+                    Some(TlsAcceptor::from(server_config.current()))
+                }
+                Some(TlsConfiguration::Files { .. }) | None => None,
+            };
+            let mut delegate = on_create_session_delegate_callback();
+            if syn_data_acked {
+                delegate.on_fast_open();
+            }
             let shutdown = self.notify_shutdown.subscribe();
             let shutdown_complete = self.shutdown_complete_tx.clone();
+            let on_stream_takeover_callback = on_stream_takeover_callback.clone();
 
             // 新连接单独起一个异步任务处理
             tokio::spawn(async move {
                 trace!("TCP Server new connection: {}", addr);
 
+                // 终止TLS的连接不能被接管
+                if let (Some(on_stream_takeover_callback), None) =
+                    (on_stream_takeover_callback, &tls_acceptor)
+                {
+                    match on_stream_takeover_callback(
+                        session_id,
+                        addr,
+                        stream,
+                        shutdown.resubscribe(),
+                    )
+                    .await
+                    {
+                        Some(s) => stream = s,
+                        None => {
+                            trace!("TCP Server disconnect: {}", addr);
+                            drop(shutdown_complete);
+                            return;
+                        }
+                    }
+                }
+
                 if let Some(tls_acceptor) = tls_acceptor {
                     match Self::try_tls(stream, tls_acceptor).await {
                         Ok(stream) => {
+                            delegate.on_tls_handshake(stream.get_ref().1.alpn_protocol());
                             tcp_session::run(session_id, addr, delegate, shutdown, stream).await;
                         }
                         Err(err) => {
@@ -101,6 +182,34 @@ impl Server {
         }
     }
 
+    async fn recv_stream(
+        stream_receiver: &mut Option<StreamReceiverType>,
+    ) -> Option<(BoxedStream, SocketAddr)> {
+        match stream_receiver {
+            Some(receiver) => receiver.recv().await,
+            None => pending().await,
+        }
+    }
+
+    /// 其他服务转交的连接已完成握手, 直接运行会话
+    fn spawn_session(
+        &self,
+        session_id: u32,
+        addr: SocketAddr,
+        on_create_session_delegate_callback: &CreateSessionDelegateCallback,
+        stream: BoxedStream,
+    ) {
+        let delegate = on_create_session_delegate_callback();
+        let shutdown = self.notify_shutdown.subscribe();
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+        tokio::spawn(async move {
+            trace!("TCP Server new forwarded connection: {}", addr);
+            tcp_session::run(session_id, addr, delegate, shutdown, stream).await;
+            trace!("TCP Server disconnect: {}", addr);
+            drop(shutdown_complete);
+        });
+    }
+
     const TIMEOUT_TLS: u64 = 15;
 
     // ref https://github.com/netskillzgh/rollo/blob/master/rollo/src/server/world_socket_mgr.rs#L183
@@ -121,6 +230,9 @@ pub struct Builder {
     create_session_delegate_callback: CreateSessionDelegateCallback,
     tls_configuration: Option<TlsConfiguration>,
     steam_init_callback: Option<StreamInitCallbackType>,
+    stream_takeover_callback: Option<StreamTakeoverCallbackType>,
+    stream_receiver: Option<StreamReceiverType>,
+    fast_open: bool,
 }
 
 impl Builder {
@@ -129,9 +241,18 @@ impl Builder {
             create_session_delegate_callback,
             tls_configuration: None,
             steam_init_callback: None,
+            stream_takeover_callback: None,
+            stream_receiver: None,
+            fast_open: false,
         }
     }
 
+    /// 除了监听的连接, 同时处理由其他服务转交的连接
+    pub fn set_stream_receiver(mut self, stream_receiver: StreamReceiverType) -> Self {
+        self.stream_receiver = Some(stream_receiver);
+        self
+    }
+
     pub fn set_on_steam_init_callback(
         mut self,
         steam_init_callback: StreamInitCallbackType,
@@ -140,14 +261,44 @@ impl Builder {
         self
     }
 
+    /// 新连接先交给回调, 回调可以不经过会话直接处理连接, 只对不终止TLS的连接有效
+    pub fn set_stream_takeover_callback(
+        mut self,
+        stream_takeover_callback: StreamTakeoverCallbackType,
+    ) -> Self {
+        self.stream_takeover_callback = Some(stream_takeover_callback);
+        self
+    }
+
+    /// 监听套接字启用了TCP Fast Open, 每个新连接检查对方是否在SYN中携带了数据, 见 [`bind`]
+    pub fn set_fast_open(mut self, fast_open: bool) -> Self {
+        self.fast_open = fast_open;
+        self
+    }
+
     pub fn set_tls_configuration<A: ToString>(mut self, certificate: A, key: A) -> Self {
-        self.tls_configuration = Some(TlsConfiguration {
+        self.tls_configuration = Some(TlsConfiguration::Files {
             certificate: certificate.to_string(),
             key: key.to_string(),
         });
         self
     }
 
+    /// 使用已创建的TLS配置
+    pub fn set_tls_server_config(mut self, server_config: Arc<ServerConfig>) -> Self {
+        self.tls_configuration = Some(TlsConfiguration::Config(server_config));
+        self
+    }
+
+    /// 使用可以重新加载的TLS配置, 重新加载后新连接使用新证书
+    pub fn set_reloadable_tls_server_config(
+        mut self,
+        server_config: Arc<ReloadableServerConfig>,
+    ) -> Self {
+        self.tls_configuration = Some(TlsConfiguration::Reloadable(server_config));
+        self
+    }
+
     pub async fn build_with_listener(
         self,
         listener: TcpListener,
@@ -162,7 +313,7 @@ impl Builder {
         };
 
         select! {
-            res = server.start_server(listener, self.create_session_delegate_callback, self.steam_init_callback, self.tls_configuration) => {
+            res = server.start_server(listener, self.create_session_delegate_callback, self.steam_init_callback, self.stream_takeover_callback, self.tls_configuration, self.stream_receiver, self.fast_open) => {
                 if let Err(err) = res {
                     error!("TCP Server error: {}", err);
                 }
@@ -189,7 +340,10 @@ impl Builder {
         };
 
         // 设置超时时间，无法优雅退出则强制退出
-        if let Err(_) = tokio::time::timeout(Duration::from_secs(600), wait_task).await {
+        if tokio::time::timeout(Duration::from_secs(600), wait_task)
+            .await
+            .is_err()
+        {
             error!("TCP Server exit timeout, forced exit");
         }
 
@@ -207,3 +361,59 @@ impl Builder {
         self.build_with_listener(listener, shutdown_condition).await
     }
 }
+
+/// 监听队列长度默认值, 与 [`TcpListener::bind`] 相同
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// 绑定TCP监听地址
+///
+/// [`ipv6_only`] 监听IPv6通配地址(`[::]`)时是否只接受IPv6连接, 为None时使用系统默认设置
+/// (Linux默认同时接受IPv4连接, 以 `::ffff:a.b.c.d` 形式的地址出现)
+///
+/// [`device`] 绑定的网卡名称, 只支持Linux
+///
+/// [`backlog`] 监听队列长度(`listen()` 的参数), 为0时使用 [`DEFAULT_LISTEN_BACKLOG`].
+/// 系统会把超出上限的值截断: Linux为 `net.core.somaxconn` (5.4以后默认4096, 之前为128),
+/// macOS为 `kern.ipc.somaxconn` (默认128), Windows为 `SOMAXCONN` (约200)
+///
+/// [`fast_open`] 是否接受SYN中携带的数据(TCP Fast Open), 系统不支持时忽略
+pub async fn bind(
+    addr: &str,
+    ipv6_only: Option<bool>,
+    device: Option<&str>,
+    backlog: u32,
+    fast_open: bool,
+) -> anyhow::Result<TcpListener> {
+    let v6only = super::v6only_for(addr, ipv6_only);
+    let addr = match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("cannot resolve listen address: {addr}"))?,
+    };
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some((_, only_v6)) = v6only {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(device) = device {
+        super::bind_device(&socket, device)?;
+    }
+    // 与tokio保持一致, windows下不设置SO_REUSEADDR
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    let backlog = match backlog {
+        0 => DEFAULT_LISTEN_BACKLOG,
+        backlog => backlog,
+    };
+    // 尚未完成握手的TFO连接数与监听队列长度相同
+    if fast_open {
+        if let Err(err) = fast_open::set_listener(&socket, backlog) {
+            debug!("TCP fast open is unavailable on {addr}: {err}");
+        }
+    }
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
@@ -4,14 +4,18 @@ use anyhow::anyhow;
 use bytes::BytesMut;
 use log::{error, info};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::io::{
     AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadHalf, WriteHalf,
 };
 use tokio::select;
-use tokio::sync::broadcast;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::{broadcast, Notify};
 use tokio::task::yield_now;
-use tokio::time::sleep;
+use tokio::time::{sleep, sleep_until, Instant};
+
+/// 合并写入时缓存的数据达到此大小立即刷新
+const COALESCE_MAX_BYTES: usize = 4 * 1024;
 
 /// run
 ///
@@ -43,6 +47,7 @@ pub async fn run<S>(
         error!("[{addr}] on_session_start error:{err}");
         return;
     }
+    let abort = delegate.abort_signal();
 
     select! {
         err = poll_read(addr, &mut delegate, reader) => {
@@ -52,6 +57,7 @@ pub async fn run<S>(
         }
         _ = poll_write(addr, delegate_receiver, writer) => {}
         _ = shutdown.recv() => {}
+        _ = wait_abort(abort) => {}
     }
 
     if let Err(err) = delegate.on_session_close().await {
@@ -59,6 +65,14 @@ pub async fn run<S>(
     }
 }
 
+/// 等待强制结束信号, 没有信号时一直等待
+async fn wait_abort(abort: Option<Arc<Notify>>) {
+    match abort {
+        Some(abort) => abort.notified().await,
+        None => std::future::pending().await,
+    }
+}
+
 /// 循环写入数据
 async fn poll_write<S>(
     addr: SocketAddr,
@@ -68,11 +82,34 @@ async fn poll_write<S>(
     S: AsyncRead + AsyncWrite + Send + 'static,
 {
     let mut writer = BufWriter::new(writer);
+    // 合并写入的数据最晚的刷新时间
+    let mut flush_deadline: Option<Instant> = None;
+
+    loop {
+        let message = match flush_deadline {
+            Some(deadline) => select! {
+                message = delegate_receiver.recv() => message,
+                _ = sleep_until(deadline) => {
+                    flush_deadline = None;
+                    if let Err(error) = writer.flush().await {
+                        error!("[{addr}] error when flushing {:?}", error);
+                        break;
+                    }
+                    continue;
+                }
+            },
+            None => delegate_receiver.recv().await,
+        };
+        let Some(message) = message else {
+            break;
+        };
 
-    while let Some(message) = delegate_receiver.recv().await {
         match message {
             WriterMessage::Close => break,
             WriterMessage::CloseDelayed(duration) => {
+                if flush_deadline.take().is_some() {
+                    let _ = writer.flush().await;
+                }
                 sleep(duration).await;
                 break;
             }
@@ -88,12 +125,13 @@ async fn poll_write<S>(
                 }
 
                 if flush {
+                    flush_deadline = None;
                     if let Err(error) = writer.flush().await {
                         error!("[{addr}] error when flushing {:?}", error);
                     }
                 }
             }
-            WriterMessage::SendTo(_, ..) => {
+            WriterMessage::SendTo(..) => {
                 panic!("not support");
             }
             WriterMessage::SendAndThen(data, callback) => {
@@ -106,12 +144,30 @@ async fn poll_write<S>(
                     error!("[{addr}] error when write_all {:?}", error);
                     break;
                 }
+                flush_deadline = None;
                 if let Err(error) = writer.flush().await {
                     error!("[{addr}] error when flushing {:?}", error);
                 }
                 callback().await;
             }
+            WriterMessage::SendCoalesced(data, window, callback) => {
+                if let Err(error) = writer.write_all(&data).await {
+                    error!("[{addr}] error when write_all {:?}", error);
+                    break;
+                }
+                if writer.buffer().len() >= COALESCE_MAX_BYTES || window.is_zero() {
+                    flush_deadline = None;
+                    if let Err(error) = writer.flush().await {
+                        error!("[{addr}] error when flushing {:?}", error);
+                    }
+                } else if flush_deadline.is_none() {
+                    // 从第一次未刷新的写入开始计时, 后续写入不会推迟刷新
+                    flush_deadline = Some(Instant::now() + window);
+                }
+                callback().await;
+            }
             WriterMessage::Flush => {
+                flush_deadline = None;
                 if let Err(error) = writer.flush().await {
                     error!("[{addr}] error when flushing {:?}", error);
                 }
@@ -119,6 +175,11 @@ async fn poll_write<S>(
         }
     }
 
+    // 关闭前写出尚未刷新的合并数据
+    if flush_deadline.is_some() {
+        let _ = writer.flush().await;
+    }
+
     delegate_receiver.close();
 }
 
@@ -1,7 +1,15 @@
 use anyhow::anyhow;
+use log::{info, warn};
 use std::fs::File;
 use std::io::BufReader;
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    ServerConfig, ServerConnection, ServerName,
+};
+use webpki_roots::TLS_SERVER_ROOTS;
 
 pub fn load_certs(path: &str) -> anyhow::Result<Vec<Certificate>> {
     let cert_file = File::open(path)?;
@@ -19,9 +27,193 @@ pub fn load_private_key(path: &str) -> anyhow::Result<PrivateKey> {
     let mut reader = BufReader::new(File::open(path)?);
     let private_key_der = rustls_pemfile::private_key(&mut reader)?;
 
-    return if let Some(private_key_der) = private_key_der {
+    if let Some(private_key_der) = private_key_der {
         Ok(PrivateKey(private_key_der.secret_der().into()))
     } else {
         Err(anyhow!("The private key file ({path}) format is incorrect"))
-    };
+    }
+}
+
+/// 从证书和私钥文件创建服务端TLS配置
+///
+/// [`alpn_protocols`] 支持的ALPN协议, 按优先级排列, 为空时不协商
+pub fn server_config(
+    certificate: &str,
+    key: &str,
+    alpn_protocols: &[String],
+) -> anyhow::Result<ServerConfig> {
+    let certs = load_certs(certificate)?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found in {certificate}"));
+    }
+    let key = load_private_key(key)?;
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|x| x.as_bytes().to_vec())
+        .collect();
+    Ok(config)
+}
+
+/// 用新配置在内存中完成一次握手, 确认私钥与证书匹配
+///
+/// 不校验证书链, 但客户端仍会用证书中的公钥验证服务端的握手签名
+fn check_server_config(config: Arc<ServerConfig>) -> anyhow::Result<()> {
+    let mut client = ClientConnection::new(
+        Arc::new(client_config(false, "")),
+        ServerName::try_from("localhost")?,
+    )?;
+    let mut server = ServerConnection::new(config)?;
+    let mut buf = Vec::new();
+    for _ in 0..8 {
+        if !client.is_handshaking() && !server.is_handshaking() {
+            return Ok(());
+        }
+        buf.clear();
+        while client.wants_write() {
+            client.write_tls(&mut buf)?;
+        }
+        let mut reader = buf.as_slice();
+        while !reader.is_empty() {
+            server.read_tls(&mut reader)?;
+            server.process_new_packets()?;
+        }
+
+        buf.clear();
+        while server.wants_write() {
+            server.write_tls(&mut buf)?;
+        }
+        let mut reader = buf.as_slice();
+        while !reader.is_empty() {
+            client.read_tls(&mut reader)?;
+            client
+                .process_new_packets()
+                .map_err(|err| anyhow!("certificate and private key do not match: {err}"))?;
+        }
+    }
+    Err(anyhow!("TLS self check did not complete"))
+}
+
+/// 证书或私钥文件变化后可以重新加载的服务端TLS配置, 用于证书续期时不重启服务
+///
+/// 新的握手使用新证书, 已建立的连接不受影响. 新证书无法加载时继续使用原来的证书
+pub struct ReloadableServerConfig {
+    certificate: String,
+    key: String,
+    alpn_protocols: Vec<String>,
+    config: RwLock<Arc<ServerConfig>>,
+    // 上次加载时证书和私钥文件的修改时间
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadableServerConfig {
+    pub fn new(certificate: &str, key: &str, alpn_protocols: &[String]) -> anyhow::Result<Self> {
+        let modified = (modified_time(certificate), modified_time(key));
+        let config = Arc::new(server_config(certificate, key, alpn_protocols)?);
+        check_server_config(config.clone())?;
+        Ok(Self {
+            certificate: certificate.to_string(),
+            key: key.to_string(),
+            alpn_protocols: alpn_protocols.to_vec(),
+            config: RwLock::new(config),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// 当前使用的配置
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// 重新加载证书和私钥, 校验通过后才替换
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = (modified_time(&self.certificate), modified_time(&self.key));
+        // 即使失败也记录修改时间, 文件再次变化前不重复尝试
+        *self.modified.lock().unwrap() = modified;
+        let config = Arc::new(server_config(
+            &self.certificate,
+            &self.key,
+            &self.alpn_protocols,
+        )?);
+        check_server_config(config.clone())?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// 证书或私钥文件的修改时间变化时重新加载, 返回是否重新加载过
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = (modified_time(&self.certificate), modified_time(&self.key));
+        if *self.modified.lock().unwrap() == modified {
+            return false;
+        }
+        match self.reload() {
+            Ok(()) => info!("TLS certificate {} reloaded", self.certificate),
+            Err(err) => warn!(
+                "failed to reload TLS certificate {}, keep using the old one: {err}",
+                self.certificate
+            ),
+        }
+        true
+    }
+
+    /// 定时检查文件是否变化, 不会返回
+    pub async fn watch(&self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            self.reload_if_changed();
+        }
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// 不校验服务器证书
+pub struct NoCertificateVerifier;
+
+impl ServerCertVerifier for NoCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// 创建连接后端使用的客户端TLS配置, 使用内置的根证书校验
+///
+/// [`verify`] 为false时不校验证书, 用于自签证书的后端
+///
+/// [`alpn_protocol`] 向后端提供的ALPN协议, 为空时不协商
+pub fn client_config(verify: bool, alpn_protocol: &str) -> ClientConfig {
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store.add_server_trust_anchors(TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+    if !verify {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerifier));
+    }
+    if !alpn_protocol.is_empty() {
+        config.alpn_protocols = vec![alpn_protocol.as_bytes().to_vec()];
+    }
+    config
 }
@@ -1,6 +1,7 @@
 use crate::net::session_delegate::CreateSessionDelegateCallback;
 use crate::net::udp_session;
 use log::{error, info, trace};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::HashMap;
 use std::future::Future;
 use std::net::SocketAddr;
@@ -11,6 +12,30 @@ use tokio::select;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{broadcast, mpsc, Mutex};
 
+/// 绑定UDP地址, [`ipv6_only`] 和 [`device`] 含义与 [`crate::net::tcp_server::bind`] 相同
+pub async fn bind(
+    addr: &str,
+    ipv6_only: Option<bool>,
+    device: Option<&str>,
+) -> anyhow::Result<UdpSocket> {
+    let v6only = super::v6only_for(addr, ipv6_only);
+    if v6only.is_none() && device.is_none() {
+        return Ok(UdpSocket::bind(addr).await?);
+    }
+
+    let addr = addr.parse::<SocketAddr>()?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some((_, only_v6)) = v6only {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(device) = device {
+        super::bind_device(&socket, device)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
 pub async fn run_server(
     socket: UdpSocket,
     on_create_session_delegate_callback: CreateSessionDelegateCallback,
@@ -53,10 +78,7 @@ pub async fn run_server(
 
                 // 创建无界通道
                 let (udp_recv_sender, udp_recv_receiver) = mpsc::unbounded_channel::<Vec<u8>>();
-                hashmap
-                    .lock()
-                    .await
-                    .insert(addr.clone(), udp_recv_sender.clone());
+                hashmap.lock().await.insert(addr, udp_recv_sender.clone());
 
                 let hashmap_cloned = hashmap.clone();
                 // 新连接单独起一个异步任务处理
@@ -102,7 +124,10 @@ pub async fn run_server(
     };
 
     // 设置超时时间，无法优雅退出则强制退出
-    if let Err(_) = tokio::time::timeout(Duration::from_secs(600), wait_task).await {
+    if tokio::time::timeout(Duration::from_secs(600), wait_task)
+        .await
+        .is_err()
+    {
         error!("UDP Server exit timeout, forced exit");
     }
 
@@ -101,7 +101,8 @@ async fn poll_write(
                     break;
                 }
             }
-            WriterMessage::SendAndThen(data, callback) => {
+            WriterMessage::SendAndThen(data, callback)
+            | WriterMessage::SendCoalesced(data, _, callback) => {
                 if data.is_empty() {
                     callback().await;
                     yield_now().await;
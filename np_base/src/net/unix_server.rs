@@ -0,0 +1,76 @@
+use crate::net::session_delegate::CreateSessionDelegateCallback;
+use crate::net::tcp_session;
+use log::{error, info, trace};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
+
+/// Unix域套接字没有网络地址，会话使用此占位地址
+pub const UNIX_PEER_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+pub async fn run_server(
+    listener: UnixListener,
+    on_create_session_delegate_callback: CreateSessionDelegateCallback,
+    shutdown: impl Future,
+) {
+    let (notify_shutdown, _) = broadcast::channel::<()>(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+    let accept_task = async {
+        let mut session_id_seed = 0;
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!("Unix Server accept error: {err}");
+                    break;
+                }
+            };
+
+            session_id_seed += 1;
+
+            let session_id = session_id_seed;
+            let delegate = on_create_session_delegate_callback();
+            let shutdown = notify_shutdown.subscribe();
+            let shutdown_complete = shutdown_complete_tx.clone();
+
+            // 新连接单独起一个异步任务处理
+            tokio::spawn(async move {
+                trace!("Unix Server new connection: {session_id}");
+                tcp_session::run(session_id, UNIX_PEER_ADDR, delegate, shutdown, stream).await;
+                trace!("Unix Server disconnect: {session_id}");
+                // 反向通知此会话结束
+                drop(shutdown_complete);
+            });
+        }
+    };
+
+    select! {
+        _= accept_task => {},
+        _= shutdown => { info!("Unix Server shutting down"); }
+    };
+
+    // 销毁notify_shutdown 是为了触发 tcp_session run函数中shutdown.recv()返回
+    drop(notify_shutdown);
+    // 此处必须将 shutdown_complete_tx 并销毁，否则会一直卡在shutdown_complete_rx.recv().await
+    drop(shutdown_complete_tx);
+
+    // 等待服务器优雅退出任务
+    let wait_task = async {
+        let _ = shutdown_complete_rx.recv().await;
+    };
+
+    // 设置超时时间，无法优雅退出则强制退出
+    if tokio::time::timeout(Duration::from_secs(600), wait_task)
+        .await
+        .is_err()
+    {
+        error!("Unix Server exit timeout, forced exit");
+    }
+
+    info!("Unix Server shutdown finish");
+}
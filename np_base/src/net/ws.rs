@@ -0,0 +1,180 @@
+//! WebSocket客户端传输
+//!
+//! 握手完成后把二进制消息当作字节流读写, 上层的消息分包逻辑不需要任何修改
+
+use bytes::{Buf, Bytes};
+use futures_util::{Sink, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// 单次写入生成的消息的最大长度, 超出部分由下次写入发送
+const MAX_WRITE_LEN: usize = 64 * 1024;
+
+/// 在已建立的连接(TCP或TLS)上发起WebSocket握手
+///
+/// [`host`] 请求头中的Host
+///
+/// [`path`] 请求路径, 例如 `/ctl`
+///
+/// [`max_message_size`] 单个消息的最大长度, 超出时断开连接
+pub async fn connect<S>(
+    stream: S,
+    host: &str,
+    path: &str,
+    max_message_size: usize,
+) -> anyhow::Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = WebSocketConfig::default()
+        .write_buffer_size(0)
+        .max_message_size(Some(max_message_size))
+        .max_frame_size(Some(max_message_size));
+    let (inner, _) = tokio_tungstenite::client_async_with_config(
+        format!("ws://{host}{path}"),
+        stream,
+        Some(config),
+    )
+    .await?;
+    Ok(WsStream::new(inner))
+}
+
+/// 握手完成的WebSocket连接, 读写的是二进制消息中的数据
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    // 已收到等待读取的数据
+    payload: Bytes,
+    // 收到关闭消息或连接已断开
+    closed: bool,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            payload: Bytes::new(),
+            closed: false,
+        }
+    }
+}
+
+fn to_io_error(err: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    match err {
+        tokio_tungstenite::tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.payload.is_empty() {
+                let n = this.payload.len().min(buf.remaining());
+                buf.put_slice(&this.payload[..n]);
+                this.payload.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            // Ping由tungstenite自动回复
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.payload = data,
+                Some(Ok(Message::Close(_))) | None => this.closed = true,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(err))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // 上一个消息写完后才接收新数据
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(to_io_error)?;
+        let len = data.len().min(MAX_WRITE_LEN);
+        Pin::new(&mut this.inner)
+            .start_send(Message::binary(data[..len].to_vec()))
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_ws_stream() {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let server = tokio::spawn(tokio_tungstenite::accept_async(server));
+        let mut stream = connect(client, "127.0.0.1:8118", "/ctl", 1024 * 1024)
+            .await
+            .unwrap();
+        let mut server = server.await.unwrap().unwrap();
+
+        // 客户端写入的数据作为二进制消息发出
+        let data = b"npipe".repeat(100);
+        stream.write_all(&data).await.unwrap();
+        stream.flush().await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::binary(data));
+
+        // 服务器的多个消息按顺序拼接为字节流, Ping自动回复Pong
+        server.send(Message::binary(&b"hello "[..])).await.unwrap();
+        server
+            .send(Message::Ping(Bytes::from_static(b"ping")))
+            .await
+            .unwrap();
+        server.send(Message::binary(&b"world"[..])).await.unwrap();
+        server.close(None).await.unwrap();
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello world");
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Pong(Bytes::from_static(b"ping"))
+        );
+
+        // 超过上限的消息断开连接
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let server = tokio::spawn(tokio_tungstenite::accept_async(server));
+        let mut stream = connect(client, "127.0.0.1:8118", "/ctl", 1024)
+            .await
+            .unwrap();
+        let mut server = server.await.unwrap().unwrap();
+        server.send(Message::binary(vec![0u8; 2048])).await.unwrap();
+        let mut buf = [0u8; 16];
+        assert!(stream.read(&mut buf).await.is_err());
+    }
+}
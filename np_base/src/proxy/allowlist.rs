@@ -0,0 +1,244 @@
+use crate::proxy::geoip::{ip_to_u128, parse_network};
+use anyhow::anyhow;
+use std::net::{IpAddr, SocketAddr};
+
+/// 目标地址不在白名单中时回复给入口的错误
+pub const DESTINATION_NOT_ALLOWED: &str = "destination not allowed";
+
+#[derive(Clone, Debug)]
+enum HostRule {
+    /// 任意地址
+    Any,
+    /// 网段(起止地址), IPv4地址转为IPv4映射的IPv6地址
+    Network(u128, u128),
+    /// 域名, 不区分大小写
+    Domain(String),
+    /// 域名后缀, 对应 `*.example.com`, 不包含 `example.com` 本身
+    DomainSuffix(String),
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    host: HostRule,
+    ports: (u16, u16),
+}
+
+/// 出口允许连接的目标地址白名单, 为空时不限制
+///
+/// 每条规则为 `主机[:端口]`, 主机可以是 `*`、IP、CIDR网段、域名或 `*.example.com`,
+/// 端口可以是单个端口、`8000-9000` 或 `*`, 省略时允许任意端口, IPv6地址带端口时需要加方括号,
+/// 例如 `10.0.0.0/8:22`、`[2001:db8::/32]:443`、`*.example.com:80-443`
+#[derive(Clone, Debug, Default)]
+pub struct DestinationAllowlist {
+    rules: Vec<Rule>,
+}
+
+impl DestinationAllowlist {
+    pub fn parse(rules: &[String]) -> anyhow::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| parse_rule(x).ok_or_else(|| anyhow!("invalid allowlist rule: '{x}'")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn allows_ip(&self, ip: IpAddr, port: u16) -> bool {
+        let ip = ip_to_u128(ip);
+        self.rules.iter().any(|rule| {
+            port_matches(rule.ports, port)
+                && match rule.host {
+                    HostRule::Any => true,
+                    HostRule::Network(start, end) => start <= ip && ip <= end,
+                    _ => false,
+                }
+        })
+    }
+
+    fn allows_domain(&self, domain: &str, port: u16) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.rules.iter().any(|rule| {
+            port_matches(rule.ports, port)
+                && match &rule.host {
+                    HostRule::Any => true,
+                    HostRule::Domain(x) => *x == domain,
+                    HostRule::DomainSuffix(x) => domain
+                        .strip_suffix(x.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.')),
+                    _ => false,
+                }
+        })
+    }
+
+    /// 检查入口请求的目标地址(`主机:端口`), 返回实际连接的地址
+    ///
+    /// 域名不匹配域名规则时解析后检查IP, 所有解析结果都必须在白名单中,
+    /// 并且返回已检查的IP, 避免连接时再次解析得到不同的地址
+    pub async fn check(&self, addr: &str) -> anyhow::Result<String> {
+        if self.rules.is_empty() {
+            return Ok(addr.to_string());
+        }
+        let not_allowed = || anyhow!(DESTINATION_NOT_ALLOWED);
+        let (host, port) = split_host_port(addr).ok_or_else(not_allowed)?;
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return if self.allows_ip(ip, port) {
+                Ok(addr.to_string())
+            } else {
+                Err(not_allowed())
+            };
+        }
+        if self.allows_domain(host, port) {
+            return Ok(addr.to_string());
+        }
+
+        let targets: Vec<SocketAddr> = tokio::net::lookup_host(addr)
+            .await
+            .map_err(|_| not_allowed())?
+            .collect();
+        match targets.first() {
+            Some(target) if targets.iter().all(|x| self.allows_ip(x.ip(), port)) => {
+                Ok(target.to_string())
+            }
+            _ => Err(not_allowed()),
+        }
+    }
+}
+
+fn port_matches(ports: (u16, u16), port: u16) -> bool {
+    ports.0 <= port && port <= ports.1
+}
+
+/// 拆分 `主机:端口`, 支持 `[IPv6]:端口`
+fn split_host_port(addr: &str) -> Option<(&str, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    Some((host, port.parse().ok()?))
+}
+
+fn parse_rule(rule: &str) -> Option<Rule> {
+    let (host, ports) = if let Some(rest) = rule.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':')?)),
+        }
+    } else if rule.matches(':').count() > 1 {
+        // 不带方括号的IPv6地址或网段, 不能指定端口
+        (rule, None)
+    } else {
+        match rule.split_once(':') {
+            Some((host, ports)) => (host, Some(ports)),
+            None => (rule, None),
+        }
+    };
+
+    let ports = match ports {
+        None | Some("*") => (0, u16::MAX),
+        Some(ports) => match ports.split_once('-') {
+            Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+            None => {
+                let port = ports.parse().ok()?;
+                (port, port)
+            }
+        },
+    };
+    if ports.0 > ports.1 {
+        return None;
+    }
+
+    let host = if host == "*" {
+        HostRule::Any
+    } else if host.contains('/') {
+        let (start, end) = parse_network(host)?;
+        HostRule::Network(start, end)
+    } else if let Ok(ip) = host.parse::<IpAddr>() {
+        let ip = ip_to_u128(ip);
+        HostRule::Network(ip, ip)
+    } else {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let (suffix, name) = match host.strip_prefix("*.") {
+            Some(name) => (true, name.to_string()),
+            None => (false, host),
+        };
+        let valid = !name.is_empty()
+            && name.split('.').all(|x| {
+                !x.is_empty()
+                    && x.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        if !valid {
+            return None;
+        }
+        if suffix {
+            HostRule::DomainSuffix(name)
+        } else {
+            HostRule::Domain(name)
+        }
+    };
+    Some(Rule { host, ports })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_destination_allowlist() {
+        let rules: Vec<String> = [
+            "10.0.0.0/8:22",
+            "192.168.1.10:8000-8100",
+            "[2001:db8::/32]:443",
+            "*.example.com:80-443",
+            "localhost",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let allowlist = DestinationAllowlist::parse(&rules).unwrap();
+
+        assert!(allowlist.check("10.1.2.3:22").await.is_ok());
+        assert!(allowlist.check("10.1.2.3:23").await.is_err());
+        assert!(allowlist.check("11.0.0.1:22").await.is_err());
+        assert!(allowlist.check("192.168.1.10:8050").await.is_ok());
+        assert!(allowlist.check("192.168.1.10:8101").await.is_err());
+        assert!(allowlist.check("[2001:db8::1]:443").await.is_ok());
+        assert!(allowlist.check("[2001:db9::1]:443").await.is_err());
+        assert!(allowlist.check("www.Example.com:443").await.is_ok());
+        assert!(allowlist.check("example.com:443").await.is_err());
+        assert!(allowlist.check("badexample.com:443").await.is_err());
+        assert!(allowlist.check("localhost:5432").await.is_ok());
+
+        // 不匹配域名规则的域名解析后按IP检查, 并返回解析得到的地址
+        let rules = vec!["127.0.0.0/8:9000".to_string()];
+        let allowlist = DestinationAllowlist::parse(&rules).unwrap();
+        let addr = allowlist.check("localhost:9000").await;
+        if let Ok(addr) = addr {
+            assert_eq!(addr, "127.0.0.1:9000");
+        }
+        let err = allowlist.check("127.0.0.1:9001").await.unwrap_err();
+        assert_eq!(err.to_string(), DESTINATION_NOT_ALLOWED);
+
+        assert!(DestinationAllowlist::default()
+            .check("8.8.8.8:53")
+            .await
+            .is_ok());
+        for rule in [
+            "10.0.0.0/33",
+            "host:70000",
+            "host:90-80",
+            "bad host",
+            "[::1",
+        ] {
+            assert!(DestinationAllowlist::parse(&[rule.to_string()]).is_err());
+        }
+    }
+}
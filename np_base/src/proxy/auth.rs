@@ -0,0 +1,219 @@
+use crate::net::http::HttpEndpoint;
+use crate::proxy::crypto;
+use crate::proxy::rate_limit::RateLimiter;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+/// 外部认证接口的超时时间
+const HTTP_AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 同一客户端IP每秒允许的认证次数和突发次数
+const PEER_AUTH_RATE: u32 = 1;
+const PEER_AUTH_BURST: u32 = 5;
+
+/// 最多记录的客户端IP数, 超出时释放令牌已补满的记录
+const MAX_TRACKED_PEERS: usize = 4096;
+
+/// 同时进行的密码哈希校验数, 所有通道共用
+const MAX_CONCURRENT_VERIFY: usize = 4;
+
+/// 等待校验名额的超时时间, 超时视为认证失败
+const VERIFY_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static VERIFY_PERMITS: Semaphore = Semaphore::const_new(MAX_CONCURRENT_VERIFY);
+
+/// 代理用户认证, SOCKS5入口在握手时调用
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// 校验客户端提供的用户名和密码, 返回是否允许使用代理
+    async fn authenticate(&self, username: &str, password: &[u8], client_addr: SocketAddr) -> bool;
+}
+
+/// 默认的认证方式: 与通道配置的用户名密码比较
+///
+/// 密码哈希校验的计算量较大, 先按客户端IP限制尝试次数, 再限制同时进行的校验数
+pub struct StaticAuthenticator {
+    username: String,
+    password: String,
+    peers: Mutex<HashMap<IpAddr, RateLimiter>>,
+}
+
+impl StaticAuthenticator {
+    pub fn new(username: String, password: String) -> Self {
+        Self {
+            username,
+            password,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 取一次该IP的认证机会, 超出限制时返回false
+    fn allow_peer(&self, ip: IpAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.len() >= MAX_TRACKED_PEERS && !peers.contains_key(&ip) {
+            peers.retain(|_, limiter| !limiter.is_full());
+        }
+        peers
+            .entry(ip)
+            .or_insert_with(|| RateLimiter::new(PEER_AUTH_RATE, PEER_AUTH_BURST))
+            .allow()
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    async fn authenticate(&self, username: &str, password: &[u8], client_addr: SocketAddr) -> bool {
+        if !self.allow_peer(client_addr.ip()) {
+            debug!("too many auth attempts from {}", client_addr.ip());
+            return false;
+        }
+        if username != self.username {
+            return false;
+        }
+
+        let Ok(Ok(permit)) = timeout(VERIFY_QUEUE_TIMEOUT, VERIFY_PERMITS.acquire()).await else {
+            warn!("password verification queue is full, rejecting {client_addr}");
+            return false;
+        };
+        let stored = self.password.clone();
+        let password = password.to_vec();
+        let result =
+            tokio::task::spawn_blocking(move || crypto::verify_password(&stored, &password))
+                .await
+                .unwrap_or(false);
+        drop(permit);
+        result
+    }
+}
+
+/// 拒绝所有用户, 认证方式配置有误时使用, 避免退化为不认证
+pub struct DenyAuthenticator;
+
+#[async_trait]
+impl Authenticator for DenyAuthenticator {
+    async fn authenticate(&self, _: &str, _: &[u8], _: SocketAddr) -> bool {
+        false
+    }
+}
+
+/// 调用外部HTTP接口认证
+///
+/// 向接口POST `{"username": "...", "password": "...", "client_addr": "IP:端口"}`,
+/// 回复2xx表示允许, 其余状态码、超时或连接失败都视为拒绝
+pub struct HttpAuthenticator {
+    endpoint: HttpEndpoint,
+}
+
+impl HttpAuthenticator {
+    /// 地址格式为 `http(s)://主机[:端口][/路径]`
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let endpoint = HttpEndpoint::parse(url).map_err(|_| anyhow!("invalid auth url: {url}"))?;
+        Ok(Self { endpoint })
+    }
+}
+
+#[async_trait]
+impl Authenticator for HttpAuthenticator {
+    async fn authenticate(&self, username: &str, password: &[u8], client_addr: SocketAddr) -> bool {
+        let body = format!(
+            "{{\"username\":{},\"password\":{},\"client_addr\":{}}}",
+            json_string(username),
+            json_string(&String::from_utf8_lossy(password)),
+            json_string(&client_addr.to_string())
+        );
+        match timeout(HTTP_AUTH_TIMEOUT, self.endpoint.post(&[], &body)).await {
+            Ok(Ok(status)) => {
+                debug!(
+                    "auth url {} returned {status} for user '{username}'",
+                    self.endpoint.url()
+                );
+                (200..300).contains(&status)
+            }
+            Ok(Err(err)) => {
+                warn!("auth url {} request failed: {err}", self.endpoint.url());
+                false
+            }
+            Err(_) => {
+                warn!("auth url {} request timeout", self.endpoint.url());
+                false
+            }
+        }
+    }
+}
+
+/// 转为JSON字符串
+fn json_string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_static_authenticator_peer_limit() {
+        let auth = StaticAuthenticator::new("user".into(), "pass".into());
+        let peer = SocketAddr::from(([10, 0, 0, 1], 1000));
+        assert!(auth.authenticate("user", b"pass", peer).await);
+        for _ in 1..PEER_AUTH_BURST {
+            assert!(!auth.authenticate("user", b"wrong", peer).await);
+        }
+
+        // 超出次数后正确的密码也会被拒绝, 不再进行哈希校验
+        assert!(!auth.authenticate("user", b"pass", peer).await);
+
+        // 其他IP不受影响
+        let other = SocketAddr::from(([10, 0, 0, 2], 1000));
+        assert!(auth.authenticate("user", b"pass", other).await);
+    }
+
+    #[test]
+    fn test_http_authenticator_url() {
+        let auth = HttpAuthenticator::new("https://auth.example.com/api/check").unwrap();
+        assert!(auth.endpoint.is_https);
+        assert_eq!(
+            (auth.endpoint.host.as_str(), auth.endpoint.port),
+            ("auth.example.com", 443)
+        );
+        assert_eq!(auth.endpoint.path, "/api/check");
+
+        let auth = HttpAuthenticator::new("http://[::1]:8080").unwrap();
+        assert_eq!(
+            (auth.endpoint.host.as_str(), auth.endpoint.port),
+            ("::1", 8080)
+        );
+        assert_eq!(auth.endpoint.path, "/");
+
+        for url in [
+            "",
+            "ftp://host/",
+            "http://",
+            "http://host:port/",
+            "http://[::1/",
+        ] {
+            assert!(HttpAuthenticator::new(url).is_err(), "{url}");
+        }
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
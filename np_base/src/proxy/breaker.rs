@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 熔断器状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// 正常放行
+    Closed,
+    /// 已熔断, 直接拒绝新会话
+    Open,
+    /// 冷却结束, 放行一个会话探测后端是否恢复
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+/// 熔断器配置
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    /// 统计窗口内连接失败多少次后熔断, 为0时不启用
+    pub threshold: u32,
+    /// 统计窗口
+    pub window: Duration,
+    /// 熔断后的冷却时间
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn new(threshold: u32, window_secs: u64, cooldown_secs: u64) -> Self {
+        Self {
+            threshold,
+            window: Duration::from_secs(window_secs),
+            cooldown: Duration::from_secs(cooldown_secs),
+        }
+    }
+}
+
+/// 熔断状态变化回调
+pub type BreakerCallback = Arc<dyn Fn(BreakerState) + Send + Sync>;
+
+struct BreakerInner {
+    state: BreakerState,
+    // 窗口内的失败时间
+    failures: VecDeque<Instant>,
+    // 进入熔断或开始探测的时间
+    since: Instant,
+}
+
+/// 后端连接失败过多时拒绝新会话, 冷却后放行单个会话探测
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<BreakerInner>,
+    on_change: Option<BreakerCallback>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: CircuitBreakerConfig, on_change: Option<BreakerCallback>) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                failures: VecDeque::new(),
+                since: Instant::now(),
+            }),
+            on_change,
+        }
+    }
+
+    pub(crate) fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// 是否允许新会话
+    pub(crate) fn allow(&self) -> bool {
+        if self.config.threshold == 0 {
+            return true;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            // 探测会话可能在收到连接结果前就已断开, 超过冷却时间后允许重新探测
+            BreakerState::Open | BreakerState::HalfOpen
+                if inner.since.elapsed() >= self.config.cooldown =>
+            {
+                inner.since = Instant::now();
+                self.transition(&mut inner, BreakerState::HalfOpen);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 记录一次向后端发起连接的结果
+    pub(crate) fn record(&self, success: bool) {
+        if self.config.threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        if success {
+            if inner.state == BreakerState::HalfOpen {
+                inner.failures.clear();
+                self.transition(&mut inner, BreakerState::Closed);
+            }
+            return;
+        }
+
+        match inner.state {
+            BreakerState::Closed => {
+                inner.failures.push_back(now);
+                while inner
+                    .failures
+                    .front()
+                    .is_some_and(|x| now.duration_since(*x) > self.config.window)
+                {
+                    inner.failures.pop_front();
+                }
+                if inner.failures.len() >= self.config.threshold as usize {
+                    inner.since = now;
+                    self.transition(&mut inner, BreakerState::Open);
+                }
+            }
+            BreakerState::HalfOpen => {
+                inner.since = now;
+                self.transition(&mut inner, BreakerState::Open);
+            }
+            BreakerState::Open => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut BreakerInner, state: BreakerState) {
+        if inner.state == state {
+            return;
+        }
+        inner.state = state;
+        if let Some(ref on_change) = self.on_change {
+            on_change(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig::new(3, 60, 0), None);
+        breaker.record(false);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // 冷却时间为0, 立即进入半开状态探测
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        assert!(breaker.allow());
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig::new(1, 60, 60), None);
+        breaker.record(false);
+        assert!(!breaker.allow());
+    }
+}
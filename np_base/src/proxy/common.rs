@@ -1,31 +1,120 @@
-use crate::net::WriterMessage;
-use crate::proxy::crypto::EncryptionMethod;
-use crate::proxy::{crypto, OutputFuncType, ProxyMessage};
+use crate::net::{SendMessageFuncType, WriterMessage};
+use crate::proxy::crypto::{EncryptionMethod, KeyDerivation};
+use crate::proxy::dictionary::CompressionDictionary;
+use crate::proxy::framing::LengthPrefix;
+use crate::proxy::transport::MessageTransport;
+use crate::proxy::{crypto, ProxyMessage};
 use anyhow::anyhow;
-use std::net::SocketAddr;
+use base64::prelude::*;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::sync::mpsc::{Receiver, UnboundedSender};
-use tokio::sync::RwLock;
-use tokio::task::yield_now;
+use tokio::sync::{Notify, RwLock};
+use tokio::task::JoinSet;
 
-const READ_BUF_MAX_LEN: usize = 1024 * 1024 * 1;
+pub(crate) const READ_BUF_MAX_LEN: usize = 1024 * 1024;
+
+/// 进程内所有会话已发送但还未被对端确认的字节数
+static GLOBAL_BUFFERED: AtomicUsize = AtomicUsize::new(0);
+/// 全局缓存预算, 为0时不限制
+static GLOBAL_BUFFER_BUDGET: AtomicUsize = AtomicUsize::new(0);
+/// 全局缓存回落到预算以内时通知
+static GLOBAL_BUFFER_NOTIFY: Notify = Notify::const_new();
+
+/// 设置全局缓存预算(字节), 所有会话的读缓存总和超过预算时暂停读取, 为0时不限制
+pub fn set_global_buffer_budget(budget: usize) {
+    GLOBAL_BUFFER_BUDGET.store(budget, Ordering::Relaxed);
+    GLOBAL_BUFFER_NOTIFY.notify_waiters();
+}
+
+/// 所有会话已发送但还未被对端确认的字节数
+pub fn global_buffered_bytes() -> usize {
+    GLOBAL_BUFFERED.load(Ordering::Relaxed)
+}
+
+/// 默认的全局缓存预算: 物理内存的1/4, 无法获取时为1GB
+pub fn default_global_buffer_budget() -> usize {
+    const FALLBACK: usize = 1024 * 1024 * 1024;
+    // MemTotal:       16303836 kB
+    let total = std::fs::read_to_string("/proc/meminfo").ok().and_then(|x| {
+        x.lines()
+            .find_map(|line| line.strip_prefix("MemTotal:"))
+            .and_then(|x| x.trim().trim_end_matches("kB").trim().parse::<usize>().ok())
+    });
+    total.map_or(FALLBACK, |kb| kb * 1024 / 4)
+}
+
+fn global_buffer_exceeded() -> bool {
+    let budget = GLOBAL_BUFFER_BUDGET.load(Ordering::Relaxed);
+    budget > 0 && GLOBAL_BUFFERED.load(Ordering::Relaxed) > budget
+}
+
+/// 会话计入全局缓存的字节数, 会话结束时归还未确认的部分
+#[derive(Default)]
+struct GlobalBufferShare(AtomicUsize);
+
+impl GlobalBufferShare {
+    fn acquire(&self, len: usize) {
+        self.0.fetch_add(len, Ordering::Relaxed);
+        GLOBAL_BUFFERED.fetch_add(len, Ordering::Relaxed);
+    }
+
+    fn release(&self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.0.fetch_sub(len, Ordering::Relaxed);
+        let previous = GLOBAL_BUFFERED.fetch_sub(len, Ordering::Relaxed);
+        let budget = GLOBAL_BUFFER_BUDGET.load(Ordering::Relaxed);
+        if budget > 0 && previous > budget && previous - len <= budget {
+            GLOBAL_BUFFER_NOTIFY.notify_waiters();
+        }
+    }
+}
+
+impl Drop for GlobalBufferShare {
+    fn drop(&mut self) {
+        self.release(self.0.load(Ordering::Relaxed));
+    }
+}
+
+/// Unix域套接字地址前缀
+pub const UNIX_ADDR_PREFIX: &str = "unix:";
+
+/// 派生两个方向的key时使用的info, 同一会话的两个方向使用不同的key
+pub(crate) const I2O_KEY_INFO: &[u8] = b"npipe i2o";
+pub(crate) const O2I_KEY_INFO: &[u8] = b"npipe o2i";
 
 // 输入通道发送端类型
 pub type InputSenderType = UnboundedSender<WriterMessage>;
 
+/// 设置了压缩阈值时每块数据的头部: 未压缩
+const UNCOMPRESSED_FLAG: u8 = 0;
+/// 设置了压缩阈值时每块数据的头部: 已压缩
+const COMPRESSED_FLAG: u8 = 1;
+
+/// 单方向的数据编码方式
 #[derive(Clone)]
-pub struct SessionCommonInfo {
+pub struct DataCodec {
     // 是否压缩数据
     pub is_compressed: bool,
     // 加密方法
     pub encryption_method: EncryptionMethod,
     // 加密key
     pub encryption_key: Vec<u8>,
-    // 读缓存大小
-    pub read_buf_len: Arc<RwLock<usize>>,
+    // 压缩字典, 为空时使用lz4压缩
+    pub dictionary: Option<Arc<CompressionDictionary>>,
+    // 派生key的方式和盐, 为None时key随连接请求发给对端
+    pub derivation: Option<(KeyDerivation, Vec<u8>)>,
+    // 小于该长度的数据不压缩, 每块数据带1字节头部标记是否压缩, 为0时全部压缩且没有头部
+    pub compress_threshold: usize,
 }
 
-impl SessionCommonInfo {
+impl DataCodec {
     pub fn new(
         is_compressed: bool,
         encryption_method: EncryptionMethod,
@@ -35,21 +124,130 @@ impl SessionCommonInfo {
             is_compressed,
             encryption_method,
             encryption_key,
-            read_buf_len: Arc::new(RwLock::new(0)),
+            dictionary: None,
+            derivation: None,
+            compress_threshold: 0,
         }
     }
 
-    pub fn from_method_name(is_compressed: bool, encryption_method: String) -> Self {
-        let encryption_method = crypto::get_method(encryption_method.as_str());
+    /// 由预共享密钥和会话的盐派生key, 两端按相同的参数得到相同的key, key不再随连接请求发送
+    pub fn from_derived_key(
+        is_compressed: bool,
+        encryption_method: &str,
+        kdf: KeyDerivation,
+        secret: &[u8],
+        salt: &[u8],
+        info: &[u8],
+    ) -> Self {
+        let encryption_method = crypto::get_method(encryption_method);
+        let encryption_key = crypto::derive_key(&encryption_method, kdf, secret, salt, info);
+        let derivation = if encryption_method.is_none() || kdf == KeyDerivation::Random {
+            None
+        } else {
+            Some((kdf, salt.to_vec()))
+        };
+        Self {
+            derivation,
+            ..Self::new(is_compressed, encryption_method, encryption_key)
+        }
+    }
+
+    /// 设置压缩字典, 两端必须一致
+    pub fn set_dictionary(mut self, dictionary: Option<Arc<CompressionDictionary>>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// 设置压缩阈值, 两端必须一致
+    pub fn set_compress_threshold(mut self, compress_threshold: usize) -> Self {
+        self.compress_threshold = compress_threshold;
+        self
+    }
+
+    /// 压缩字典的哈希值, 不使用字典时为空
+    pub fn dictionary_hash(&self) -> String {
+        self.dictionary
+            .as_ref()
+            .map_or(String::new(), |x| x.hash().to_string())
+    }
+
+    /// 根据加密方法名生成随机key
+    pub fn from_method_name(is_compressed: bool, encryption_method: &str) -> Self {
+        let encryption_method = crypto::get_method(encryption_method);
         let encryption_key = crypto::generate_key(&encryption_method);
         Self::new(is_compressed, encryption_method, encryption_key)
     }
 
-    pub async fn encode_data_and_limiting(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-        if self.is_compressed {
-            data = crypto::compress_data(data.as_slice())?;
+    /// 解析对端发来的编码方式(key为base64编码)
+    pub fn from_remote(
+        is_compressed: bool,
+        encryption_method: &str,
+        encryption_key: &str,
+    ) -> anyhow::Result<Self> {
+        let encryption_method = crypto::get_method(encryption_method);
+        let encryption_key = BASE64_STANDARD.decode(encryption_key.as_bytes())?;
+        if !encryption_method.is_none() && encryption_key.is_empty() {
+            return Err(anyhow!("missing encryption key for {encryption_method}"));
         }
+        Ok(Self::new(is_compressed, encryption_method, encryption_key))
+    }
+
+    /// 转为发送给对端的参数(是否压缩, 加密方法, base64编码的key), 派生的key不发送
+    pub fn to_remote(&self) -> (bool, String, String) {
+        let encryption_key = if self.derivation.is_some() {
+            String::new()
+        } else {
+            BASE64_STANDARD.encode(&self.encryption_key)
+        };
+        (
+            self.is_compressed,
+            self.encryption_method.to_string(),
+            encryption_key,
+        )
+    }
+
+    /// 转为发送给对端的派生参数(派生方式, base64编码的盐)
+    pub fn remote_derivation(&self) -> Option<(u32, String)> {
+        self.derivation
+            .as_ref()
+            .map(|(kdf, salt)| (kdf.to_u32(), BASE64_STANDARD.encode(salt)))
+    }
 
+    fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(match &self.dictionary {
+            Some(dictionary) => dictionary.compress(data)?,
+            None => crypto::compress_data(data)?,
+        })
+    }
+
+    fn decompress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(match &self.dictionary {
+            Some(dictionary) => dictionary.decompress(data)?,
+            None => crypto::decompress_data(data)?,
+        })
+    }
+
+    fn encode(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if self.is_compressed {
+            data = if self.compress_threshold == 0 {
+                self.compress(&data)?
+            } else {
+                // 太短或压缩后没有变小的数据原样发送
+                let compressed = if data.len() < self.compress_threshold {
+                    None
+                } else {
+                    Some(self.compress(&data)?).filter(|x| x.len() < data.len())
+                };
+                let (flag, body) = match compressed {
+                    Some(compressed) => (COMPRESSED_FLAG, compressed),
+                    None => (UNCOMPRESSED_FLAG, data),
+                };
+                let mut framed = Vec::with_capacity(body.len() + 1);
+                framed.push(flag);
+                framed.extend_from_slice(&body);
+                framed
+            };
+        }
         if !self.encryption_method.is_none() {
             data = crypto::encrypt(
                 &self.encryption_method,
@@ -57,19 +255,10 @@ impl SessionCommonInfo {
                 data,
             )?;
         }
-
-        while *self.read_buf_len.read().await > READ_BUF_MAX_LEN {
-            yield_now().await;
-        }
-
-        let mut read_buf_len_rw = self.read_buf_len.write().await;
-        *read_buf_len_rw = *read_buf_len_rw + data.len();
-        drop(read_buf_len_rw);
-
         Ok(data)
     }
 
-    pub fn decode_data(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    fn decode(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
         if !self.encryption_method.is_none() {
             data = crypto::decrypt(
                 &self.encryption_method,
@@ -78,20 +267,187 @@ impl SessionCommonInfo {
             )?;
         }
         if self.is_compressed {
-            data = crypto::decompress_data(data.as_slice())?;
+            data = if self.compress_threshold == 0 {
+                self.decompress(&data)?
+            } else {
+                match data.split_first() {
+                    Some((&COMPRESSED_FLAG, body)) => self.decompress(body)?,
+                    Some((&UNCOMPRESSED_FLAG, _)) => data.split_off(1),
+                    _ => return Err(anyhow!("invalid compression flag")),
+                }
+            };
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Clone)]
+pub struct SessionCommonInfo {
+    // 本端发出数据的编码方式
+    pub outbound: DataCodec,
+    // 本端收到数据的编码方式
+    pub inbound: DataCodec,
+    // 两个方向是否使用相同的编码方式
+    pub is_symmetric: bool,
+    // 读缓存大小
+    pub read_buf_len: Arc<RwLock<usize>>,
+    // 读缓存上限, 超过时挂起读取, 为0时只受全局缓存上限限制
+    pub read_buf_max_len: usize,
+    // 读缓存低于上限时通知
+    read_buf_notify: Arc<Notify>,
+    // 读缓存计入全局缓存的部分
+    global_share: Arc<GlobalBufferShare>,
+    // 合并写入的时间窗口, 为0时每次写入都立即刷新
+    pub write_coalesce: Duration,
+    // 发送数据的分块大小, 为0时不分块
+    pub chunk_size: usize,
+    // 本端连接上的数据按长度前缀分帧
+    pub length_prefix: LengthPrefix,
+}
+
+impl SessionCommonInfo {
+    pub fn new(outbound: DataCodec, inbound: Option<DataCodec>) -> Self {
+        Self {
+            is_symmetric: inbound.is_none(),
+            inbound: inbound.unwrap_or_else(|| outbound.clone()),
+            outbound,
+            read_buf_len: Arc::new(RwLock::new(0)),
+            read_buf_max_len: READ_BUF_MAX_LEN,
+            read_buf_notify: Arc::new(Notify::new()),
+            global_share: Arc::new(GlobalBufferShare::default()),
+            write_coalesce: Duration::ZERO,
+            chunk_size: 0,
+            length_prefix: LengthPrefix::None,
         }
+    }
+
+    /// 设置合并写入的时间窗口
+    pub fn set_write_coalesce(mut self, write_coalesce: Duration) -> Self {
+        self.write_coalesce = write_coalesce;
+        self
+    }
+
+    /// 设置读缓存上限, 为0时不限制单个会话
+    pub fn set_read_buf_max_len(mut self, read_buf_max_len: usize) -> Self {
+        self.read_buf_max_len = read_buf_max_len;
+        self
+    }
+
+    /// 设置发送数据的分块大小
+    pub fn set_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// 设置本端连接上数据的分帧方式
+    pub fn set_length_prefix(mut self, length_prefix: LengthPrefix) -> Self {
+        self.length_prefix = length_prefix;
+        self
+    }
+
+    /// 按分块大小拆分待发送的数据, 每块单独编码发送, 对端按顺序写入即可还原
+    ///
+    /// 分帧时对端为每块数据加上前缀, 不能拆分
+    pub(crate) fn split_chunks(&self, mut data: Vec<u8>) -> Vec<Vec<u8>> {
+        if self.chunk_size == 0 || data.len() <= self.chunk_size || !self.length_prefix.is_none() {
+            return vec![data];
+        }
+        let mut chunks = Vec::with_capacity(data.len().div_ceil(self.chunk_size));
+        while data.len() > self.chunk_size {
+            let rest = data.split_off(self.chunk_size);
+            chunks.push(std::mem::replace(&mut data, rest));
+        }
+        chunks.push(data);
+        chunks
+    }
+
+    /// 生成写入本端连接的消息, 开启合并写入时不立即刷新
+    pub(crate) fn write_message(
+        &self,
+        data: Vec<u8>,
+        callback: SendMessageFuncType,
+    ) -> WriterMessage {
+        if self.write_coalesce.is_zero() {
+            WriterMessage::SendAndThen(data, callback)
+        } else {
+            WriterMessage::SendCoalesced(data, self.write_coalesce, callback)
+        }
+    }
+
+    /// 两个方向使用相同的编码方式
+    pub fn symmetric(codec: DataCodec) -> Self {
+        Self::new(codec, None)
+    }
+
+    /// 发送给对端的key派生参数, 两个方向使用同一个盐
+    pub(crate) fn remote_derivation(&self) -> Option<(u32, String)> {
+        self.outbound
+            .remote_derivation()
+            .or_else(|| self.inbound.remote_derivation())
+    }
+
+    pub async fn encode_data_and_limiting(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let data = self.outbound.encode(data)?;
+
+        // 本会话或全局缓存超过上限时挂起, 直到对端确认的数据使缓存回落
+        loop {
+            let notified = self.read_buf_notify.notified();
+            let global_notified = GLOBAL_BUFFER_NOTIFY.notified();
+            tokio::pin!(notified, global_notified);
+            notified.as_mut().enable();
+            global_notified.as_mut().enable();
+            if self.read_buf_exceeded(*self.read_buf_len.read().await) {
+                notified.await;
+            } else if global_buffer_exceeded() {
+                global_notified.await;
+            } else {
+                break;
+            }
+        }
+
+        let mut read_buf_len_rw = self.read_buf_len.write().await;
+        *read_buf_len_rw += data.len();
+        self.global_share.acquire(data.len());
+        drop(read_buf_len_rw);
 
         Ok(data)
     }
+
+    /// 对端确认收到数据, 释放读缓存
+    pub async fn release_read_buf(&self, data_len: usize) {
+        let mut read_buf_len = self.read_buf_len.write().await;
+        let released = data_len.min(*read_buf_len);
+        *read_buf_len -= released;
+        self.global_share.release(released);
+        let exceeded = self.read_buf_exceeded(*read_buf_len);
+        drop(read_buf_len);
+
+        if !exceeded {
+            self.read_buf_notify.notify_waiters();
+        }
+    }
+
+    pub fn decode_data(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.inbound.decode(data)
+    }
+
+    fn read_buf_exceeded(&self, read_buf_len: usize) -> bool {
+        self.read_buf_max_len != 0 && read_buf_len > self.read_buf_max_len
+    }
+
+    /// 本会话或全局缓存超过上限, 下一次读取会被挂起
+    pub(crate) fn read_buf_stalled(&self, read_buf_len: usize) -> bool {
+        self.read_buf_exceeded(read_buf_len) || global_buffer_exceeded()
+    }
 }
 
 pub async fn async_receive_output(
     mut output_rx: Receiver<ProxyMessage>,
-    on_output_callback: OutputFuncType,
+    transport: Arc<dyn MessageTransport>,
 ) {
     loop {
         if let Some(message) = output_rx.recv().await {
-            on_output_callback(message).await;
+            transport.send(message).await;
         }
     }
 }
@@ -100,9 +456,198 @@ pub async fn parse_addr(host: &String) -> anyhow::Result<SocketAddr> {
     if let Ok(addr) = host.parse::<SocketAddr>() {
         return Ok(addr);
     } else {
-        for addr in tokio::net::lookup_host(host).await? {
+        if let Some(addr) = tokio::net::lookup_host(host).await?.next() {
             return Ok(addr);
         }
     }
-    return Err(anyhow!("The address format is invalid: '{}'", host));
+    Err(anyhow!("The address format is invalid: '{}'", host))
+}
+
+/// 从指定的本地地址连接目标地址, 只尝试与本地地址协议族相同的目标地址
+pub(crate) async fn connect_from(bind_addr: IpAddr, addr: &str) -> anyhow::Result<TcpStream> {
+    let mut last_err = None;
+    for target in tokio::net::lookup_host(addr).await? {
+        if target.is_ipv4() != bind_addr.is_ipv4() {
+            continue;
+        }
+        let socket = if bind_addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(SocketAddr::new(bind_addr, 0))?;
+        match socket.connect(target).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(match last_err {
+        Some(err) => err.into(),
+        None => anyhow!("no address of {addr} matches local address {bind_addr}"),
+    })
+}
+
+/// 前一个连接尝试还未完成时, 等待多久发起下一个尝试(RFC 8305推荐250毫秒)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// 按RFC 8305(Happy Eyeballs)连接, 域名同时解析出IPv4和IPv6地址时两种地址交替尝试
+///
+/// 从解析结果的第一个地址开始, 前一个尝试在 [`CONNECTION_ATTEMPT_DELAY`] 内没有完成或已失败时
+/// 发起下一个, 使用最先成功的连接并取消其余尝试
+pub(crate) async fn connect_happy_eyeballs(addr: &str) -> anyhow::Result<TcpStream> {
+    let targets: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    if targets.is_empty() {
+        return Err(anyhow!("cannot resolve address: {addr}"));
+    }
+    Ok(race_connect(interleave_families(targets), CONNECTION_ATTEMPT_DELAY).await?)
+}
+
+/// 交替排列IPv4和IPv6地址, 第一个地址的地址族优先
+fn interleave_families(targets: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_is_ipv6 = targets.first().is_some_and(|x| x.is_ipv6());
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = targets
+        .into_iter()
+        .partition(|x| x.is_ipv6() == first_is_ipv6);
+    let mut result = VecDeque::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        result.extend(preferred.pop_front());
+        result.extend(other.pop_front());
+    }
+    result
+}
+
+async fn race_connect(
+    mut targets: VecDeque<SocketAddr>,
+    attempt_delay: Duration,
+) -> std::io::Result<TcpStream> {
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    loop {
+        if let Some(target) = targets.pop_front() {
+            attempts.spawn(TcpStream::connect(target));
+        }
+        let result = if targets.is_empty() {
+            match attempts.join_next().await {
+                Some(result) => result,
+                None => break,
+            }
+        } else {
+            match tokio::time::timeout(attempt_delay, attempts.join_next()).await {
+                Ok(Some(result)) => result,
+                // 等待超时, 发起下一个尝试
+                Ok(None) | Err(_) => continue,
+            }
+        };
+        match result {
+            // 返回时JoinSet被销毁, 其余尝试随之取消
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => last_err = Some(err),
+            Err(err) => last_err = Some(std::io::Error::other(err)),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| std::io::Error::other("no address to connect")))
+}
+
+/// 将IPv4映射的IPv6地址(`::ffff:a.b.c.d`)还原为IPv4地址, 便于日志和访问控制
+pub(crate) fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// 获取 `unix:/path/to.sock` 形式地址中的套接字路径
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_ADDR_PREFIX)
+        .filter(|path| !path.is_empty())
+}
+
+/// 监听unix套接字, 先清理上次异常退出遗留的套接字文件
+///
+/// 只删除没有进程监听的套接字文件, 路径上已有其他类型的文件时返回错误, 避免配置错误时误删文件
+#[cfg(unix)]
+pub fn bind_unix_listener(path: &str) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(anyhow!("{path} already exists and is not a unix socket"));
+        }
+        Ok(_) => {
+            if std::os::unix::net::UnixStream::connect(path).is_err() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(tokio::net::UnixListener::bind(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_unix_listener() {
+        let path = std::env::temp_dir().join(format!("np_base_unix_{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // 不是套接字的文件不删除
+        std::fs::write(&path, b"data").unwrap();
+        assert!(bind_unix_listener(path_str).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+
+        // 遗留的套接字文件被清理, 正在监听的不清理
+        drop(bind_unix_listener(path_str).unwrap());
+        let listener = bind_unix_listener(path_str).unwrap();
+        assert!(bind_unix_listener(path_str).is_err());
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let targets: Vec<SocketAddr> = ["[::1]:80", "[::2]:80", "[::3]:80", "1.1.1.1:80"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let result: Vec<String> = interleave_families(targets)
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(result, ["[::1]:80", "1.1.1.1:80", "[::2]:80", "[::3]:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_race_connect() {
+        let closed = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        // 第一个地址被拒绝后立即尝试下一个
+        let targets = VecDeque::from([closed, local]);
+        let stream = tokio::time::timeout(
+            Duration::from_secs(2),
+            race_connect(targets, Duration::from_millis(50)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), local);
+
+        drop(listener);
+        assert!(
+            race_connect(VecDeque::from([local]), Duration::from_millis(50))
+                .await
+                .is_err()
+        );
+    }
 }
@@ -1,6 +1,15 @@
 use anyhow::anyhow;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use lz4_flex::block::{compress_prepend_size, decompress_size_prepended};
 use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
 // Function to compress data using Brotli
@@ -11,7 +20,7 @@ pub fn compress_data(input: &[u8]) -> Result<Vec<u8>, io::Error> {
 
 // Function to decompress data using Brotli
 pub fn decompress_data(compressed: &[u8]) -> Result<Vec<u8>, io::Error> {
-    let uncompressed = decompress_size_prepended(&compressed).unwrap();
+    let uncompressed = decompress_size_prepended(compressed).unwrap();
     Ok(uncompressed)
 }
 
@@ -24,19 +33,126 @@ pub enum EncryptionMethod {
 
 impl EncryptionMethod {
     pub fn is_none(&self) -> bool {
-        match self {
-            EncryptionMethod::None => true,
-            _ => false,
-        }
+        matches!(self, EncryptionMethod::None)
     }
 }
 
+/// 未知的方法按None处理
 pub fn get_method(method: &str) -> EncryptionMethod {
+    parse_method(method).unwrap_or(EncryptionMethod::None)
+}
+
+/// 解析加密方法名称, 未知的方法返回None
+pub fn parse_method(method: &str) -> Option<EncryptionMethod> {
     match method {
-        "Aes128" => EncryptionMethod::Aes128,
-        "None" => EncryptionMethod::None,
-        "Xor" => EncryptionMethod::Xor,
-        _ => EncryptionMethod::None,
+        "Aes128" => Some(EncryptionMethod::Aes128),
+        "None" => Some(EncryptionMethod::None),
+        "Xor" => Some(EncryptionMethod::Xor),
+        _ => None,
+    }
+}
+
+/// 出口不支持入口选择的加密方法时连接失败的错误信息, 后面是方法名称
+pub const UNSUPPORTED_METHOD: &str = "unsupported encryption method: ";
+
+/// 解析逗号分隔的加密方法偏好列表, 例如 `Aes128,Xor,None`, 列表为空或有未知的方法时返回错误
+pub fn parse_method_list(list: &str) -> anyhow::Result<Vec<EncryptionMethod>> {
+    if list.trim().is_empty() {
+        return Err(anyhow!("empty encryption method list"));
+    }
+    list.split(',')
+        .map(|x| x.trim())
+        .map(|x| parse_method(x).ok_or_else(|| anyhow!("unknown encryption method: '{x}'")))
+        .collect()
+}
+
+/// 出口拒绝某个方法后, 这段时间内新会话不再使用它, 过期后重新尝试
+pub const METHOD_REJECT_TTL: Duration = Duration::from_secs(300);
+
+/// 入口的加密方法偏好列表
+///
+/// 默认新会话总是使用第一个本端支持的方法, 出口拒绝时连接失败.
+/// 开启降级后按出口分别记录拒绝过的方法, 只有最近应答过的出口都拒绝时新会话才改用下一个, 拒绝记录会过期
+pub struct MethodPreference {
+    methods: Vec<String>,
+    fallback: bool,
+    outlets: Mutex<HashMap<u32, OutletMethods>>,
+}
+
+/// 一个出口最近应答的时间和拒绝过的方法
+struct OutletMethods {
+    seen: Instant,
+    rejected: HashMap<String, Instant>,
+}
+
+impl MethodPreference {
+    /// 本端不认识的方法直接跳过, 都不认识时与单个未知方法一样按None处理
+    ///
+    /// `fallback` 为false时不会改用第一个之后的方法
+    pub fn new(list: &str, fallback: bool) -> Self {
+        Self {
+            methods: list
+                .split(',')
+                .map(|x| x.trim())
+                .filter(|x| parse_method(x).is_some())
+                .map(|x| x.to_string())
+                .collect(),
+            fallback,
+            outlets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 新会话使用的方法, 全部被拒绝时仍使用第一个, 连接失败而不是降级为不加密
+    pub fn select(&self) -> String {
+        self.select_at(Instant::now())
+    }
+
+    fn select_at(&self, now: Instant) -> String {
+        let first = self
+            .methods
+            .first()
+            .cloned()
+            .unwrap_or_else(|| EncryptionMethod::None.to_string());
+        if !self.fallback {
+            return first;
+        }
+        let mut outlets = self.outlets.lock().unwrap();
+        outlets.retain(|_, outlet| {
+            outlet
+                .rejected
+                .retain(|_, time| now.duration_since(*time) < METHOD_REJECT_TTL);
+            now.duration_since(outlet.seen) < METHOD_REJECT_TTL
+        });
+        self.methods
+            .iter()
+            .find(|method| {
+                outlets.is_empty() || !outlets.values().all(|x| x.rejected.contains_key(*method))
+            })
+            .cloned()
+            .unwrap_or(first)
+    }
+
+    /// 记录出口对连接请求的应答, `rejected` 为出口拒绝的方法, 第一次拒绝时返回true
+    pub fn record(&self, outlet_id: u32, method: &str, rejected: bool) -> bool {
+        self.record_at(outlet_id, method, rejected, Instant::now())
+    }
+
+    fn record_at(&self, outlet_id: u32, method: &str, rejected: bool, now: Instant) -> bool {
+        if !self.fallback {
+            return false;
+        }
+        let mut outlets = self.outlets.lock().unwrap();
+        let outlet = outlets.entry(outlet_id).or_insert_with(|| OutletMethods {
+            seen: now,
+            rejected: HashMap::new(),
+        });
+        outlet.seen = now;
+        if !rejected {
+            outlet.rejected.remove(method);
+            return false;
+        }
+        self.methods.iter().any(|x| x == method)
+            && outlet.rejected.insert(method.to_string(), now).is_none()
     }
 }
 
@@ -81,6 +197,101 @@ pub fn generate_key(method: &EncryptionMethod) -> Vec<u8> {
     }
 }
 
+/// 会话密钥的来源
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyDerivation {
+    /// 每个会话随机生成, 随连接请求发给出口
+    #[default]
+    Random,
+    /// 用HKDF-SHA256从预共享密钥和会话的盐派生
+    HkdfSha256,
+    /// 预共享密钥先用PBKDF2-SHA256拉伸(每个通道只计算一次), 再按HKDF-SHA256派生
+    Pbkdf2Sha256,
+}
+
+impl KeyDerivation {
+    /// 0:随机 1:HKDF-SHA256 2:PBKDF2-SHA256
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Random),
+            1 => Some(Self::HkdfSha256),
+            2 => Some(Self::Pbkdf2Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+/// 派生密钥时每个会话使用的随机盐
+pub fn generate_salt() -> Vec<u8> {
+    rand::thread_rng().gen::<[u8; 16]>().to_vec()
+}
+
+/// PBKDF2拉伸预共享密钥时使用的固定盐, 外部实现需要使用相同的值
+pub const KEY_STRETCH_SALT: &[u8] = b"npipe key derivation";
+
+/// PBKDF2拉伸预共享密钥的迭代次数
+pub const KEY_STRETCH_ROUNDS: u32 = 100_000;
+
+/// 把通道配置的密码转为派生会话key的预共享密钥
+///
+/// 计算量可能较大, 每个通道只需在配置变化时计算一次, 不要在每个会话中调用
+pub fn prepare_key_secret(kdf: KeyDerivation, password: &str) -> Vec<u8> {
+    match kdf {
+        KeyDerivation::Pbkdf2Sha256 => pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(
+            password.as_bytes(),
+            KEY_STRETCH_SALT,
+            KEY_STRETCH_ROUNDS,
+        )
+        .to_vec(),
+        _ => password.as_bytes().to_vec(),
+    }
+}
+
+/// 从 [`prepare_key_secret`] 得到的预共享密钥派生会话密钥, 两端使用相同的参数得到相同的key
+///
+/// [`info`] 区分同一会话中不同用途的key(例如两个方向), 随机方式忽略其他参数直接生成
+pub fn derive_key(
+    method: &EncryptionMethod,
+    kdf: KeyDerivation,
+    secret: &[u8],
+    salt: &[u8],
+    info: &[u8],
+) -> Vec<u8> {
+    if method.is_none() {
+        return generate_key(method);
+    }
+    match kdf {
+        KeyDerivation::Random => generate_key(method),
+        KeyDerivation::HkdfSha256 | KeyDerivation::Pbkdf2Sha256 => {
+            hkdf_sha256(secret, salt, info).to_vec()
+        }
+    }
+}
+
+/// HMAC-SHA256签名, 返回十六进制字符串
+pub fn hmac_sha256_hex(key: &[u8], data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect()
+}
+
+/// RFC 5869, 输出32字节
+fn hkdf_sha256(secret: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), secret)
+        .expand(info, &mut okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
 pub fn encrypt(method: &EncryptionMethod, key: &[u8], data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
     match method {
         EncryptionMethod::None => Ok(data),
@@ -130,3 +341,163 @@ fn xor_encrypt_decrypt(mut data: Vec<u8>, key: &[u8]) -> Vec<u8> {
     //     .map(|(&data_byte, &key_byte)| data_byte ^ key_byte)
     //     .collect()
 }
+
+/// 密码哈希的前缀, PHC字符串格式
+const PASSWORD_HASH_PREFIX: &str = "$argon2";
+
+/// 是否为 `hash_password` 生成的哈希
+pub fn is_password_hash(stored: &str) -> bool {
+    stored.starts_with(PASSWORD_HASH_PREFIX)
+}
+
+/// 生成加盐的Argon2id密码哈希, 格式为PHC字符串 `$argon2id$v=19$m=..,t=..,p=..$<盐>$<哈希>`
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 with default params")
+        .to_string()
+}
+
+/// 校验密码, 兼容未哈希的旧数据
+///
+/// Argon2计算量较大, 在异步任务中调用时应放到阻塞线程池
+pub fn verify_password(stored: &str, password: &[u8]) -> bool {
+    if !is_password_hash(stored) {
+        return constant_time_eq(stored.as_bytes(), password);
+    }
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return false;
+    };
+    Argon2::default().verify_password(password, &hash).is_ok()
+}
+
+/// 密码的摘要, 用于日志和变更检测, 不泄露密码本身
+pub fn password_fingerprint(stored: &str) -> String {
+    if stored.is_empty() {
+        return String::new();
+    }
+    let digest = Sha256::digest(stored.as_bytes());
+    format!(
+        "***{:02x}{:02x}{:02x}{:02x}",
+        digest[0], digest[1], digest[2], digest[3]
+    )
+}
+
+/// 恒定时间比较, 避免通过响应时间猜测密码或令牌
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hash() {
+        let stored = hash_password("p@ssw0rd");
+        assert!(is_password_hash(&stored));
+        assert_ne!(stored, hash_password("p@ssw0rd"));
+        assert!(verify_password(&stored, b"p@ssw0rd"));
+        assert!(!verify_password(&stored, b"p@ssw0rd1"));
+        // 未哈希的旧数据
+        assert!(verify_password("plain", b"plain"));
+        assert!(!verify_password("plain", b"plain1"));
+        assert!(!password_fingerprint(&stored).contains("p@ssw0rd"));
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(!verify_password("$argon2id$broken", b"p@ssw0rd"));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token1"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
+
+    #[test]
+    fn test_method_preference() {
+        assert_eq!(parse_method_list("Aes128, Xor,None").unwrap().len(), 3);
+        assert!(parse_method_list("Aes128,Aes256").is_err());
+        assert!(parse_method_list("").is_err());
+
+        // 未开启降级时总是使用第一个
+        let preference = MethodPreference::new("Aes128,Xor", false);
+        assert!(!preference.record(1, "Aes128", true));
+        assert_eq!(preference.select(), "Aes128");
+
+        // 本端不认识的方法跳过, 出口拒绝后改用下一个
+        let preference = MethodPreference::new("Aes256,Aes128,Xor", true);
+        let now = Instant::now();
+        assert_eq!(preference.select_at(now), "Aes128");
+        assert!(preference.record_at(1, "Aes128", true, now));
+        assert!(!preference.record_at(1, "Aes128", true, now));
+        assert_eq!(preference.select_at(now), "Xor");
+        // 另一个出口支持时不降级
+        preference.record_at(2, "Aes128", false, now);
+        assert_eq!(preference.select_at(now), "Aes128");
+        assert!(preference.record_at(2, "Aes128", true, now));
+        // 全部被拒绝时不会降级为不加密
+        preference.record_at(1, "Xor", true, now);
+        preference.record_at(2, "Xor", true, now);
+        assert_eq!(preference.select_at(now), "Aes128");
+        // 出口又接受了, 拒绝记录过期后重新尝试第一个
+        preference.record_at(1, "Xor", false, now);
+        assert_eq!(preference.select_at(now), "Xor");
+        assert_eq!(preference.select_at(now + METHOD_REJECT_TTL), "Aes128");
+        assert!(preference.record_at(1, "Aes128", true, now + METHOD_REJECT_TTL));
+        assert_eq!(preference.select_at(now + METHOD_REJECT_TTL), "Xor");
+        assert_eq!(MethodPreference::new("Aes256", true).select(), "None");
+    }
+
+    #[test]
+    fn test_derive_key() {
+        // RFC 5869 A.1, 取前32字节
+        let okm = hkdf_sha256(
+            &[0x0b; 22],
+            &(0x00..=0x0c).collect::<Vec<u8>>(),
+            &(0xf0..=0xf9).collect::<Vec<u8>>(),
+        );
+        assert_eq!(
+            okm.iter().map(|x| format!("{x:02x}")).collect::<String>(),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf"
+        );
+
+        // HKDF直接使用密码, 外部实现按相同的规则即可得到相同的key
+        assert_eq!(prepare_key_secret(KeyDerivation::HkdfSha256, "psk"), b"psk");
+        let stretched = prepare_key_secret(KeyDerivation::Pbkdf2Sha256, "psk");
+        assert_eq!(stretched.len(), 32);
+        assert_eq!(
+            stretched,
+            prepare_key_secret(KeyDerivation::Pbkdf2Sha256, "psk")
+        );
+
+        let salt = generate_salt();
+        for kdf in [KeyDerivation::HkdfSha256, KeyDerivation::Pbkdf2Sha256] {
+            let secret = prepare_key_secret(kdf, "psk");
+            let key = derive_key(&EncryptionMethod::Aes128, kdf, &secret, &salt, b"i2o");
+            assert_eq!(
+                key,
+                derive_key(&EncryptionMethod::Aes128, kdf, &secret, &salt, b"i2o")
+            );
+            assert_ne!(
+                key,
+                derive_key(&EncryptionMethod::Aes128, kdf, &secret, &salt, b"o2i")
+            );
+            let secret2 = prepare_key_secret(kdf, "psk2");
+            assert_ne!(
+                key,
+                derive_key(&EncryptionMethod::Aes128, kdf, &secret2, &salt, b"i2o")
+            );
+            let data = encrypt(&EncryptionMethod::Aes128, &key, b"hello".to_vec()).unwrap();
+            assert_eq!(
+                decrypt(&EncryptionMethod::Aes128, &key, data).unwrap(),
+                b"hello"
+            );
+        }
+    }
+}
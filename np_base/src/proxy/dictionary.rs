@@ -0,0 +1,139 @@
+use anyhow::anyhow;
+use base64::prelude::*;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use zstd::bulk::{Compressor, Decompressor};
+
+/// 压缩级别
+const COMPRESSION_LEVEL: i32 = 3;
+/// 解压后的最大长度, 防止对端发来的长度字段导致分配过多内存
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+/// 每个方向最多保留的空闲上下文数量, 超出的用完即释放
+const MAX_IDLE_CONTEXTS: usize = 16;
+
+/// 预先训练的zstd压缩字典(可以用 `zstd --train` 生成), 对重复性强的协议能明显提高小包的压缩率
+///
+/// 入口和出口必须使用同一个字典, 连接时通过哈希值校验
+///
+/// 加载了字典的压缩和解压上下文创建开销较大, 用完后放回空闲列表, 由使用同一字典的所有会话复用
+pub struct CompressionDictionary {
+    data: Vec<u8>,
+    compressors: Mutex<Vec<Compressor<'static>>>,
+    decompressors: Mutex<Vec<Decompressor<'static>>>,
+    hash: String,
+}
+
+impl CompressionDictionary {
+    pub fn new(data: &[u8]) -> anyhow::Result<Self> {
+        if data.is_empty() {
+            return Err(anyhow!("empty compression dictionary"));
+        }
+        let digest = Sha256::digest(data);
+        Ok(Self {
+            data: data.to_vec(),
+            compressors: Mutex::new(Vec::new()),
+            decompressors: Mutex::new(Vec::new()),
+            hash: digest[..8].iter().map(|x| format!("{x:02x}")).collect(),
+        })
+    }
+
+    /// 加载字典, `base64:` 开头时为内联的base64编码字典, 否则为文件路径, 为空时不使用字典
+    pub fn load(source: &str) -> anyhow::Result<Option<Arc<Self>>> {
+        let source = source.trim();
+        if source.is_empty() {
+            return Ok(None);
+        }
+        let data = match source.strip_prefix("base64:") {
+            Some(encoded) => BASE64_STANDARD
+                .decode(encoded.trim())
+                .map_err(|err| anyhow!("invalid compression dictionary: {err}"))?,
+            None => std::fs::read(source)
+                .map_err(|err| anyhow!("failed to read compression dictionary {source}: {err}"))?,
+        };
+        Ok(Some(Arc::new(Self::new(&data)?)))
+    }
+
+    /// 字典内容的哈希值(sha256的前8字节), 用于确认两端的字典一致
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// 压缩数据, 前4字节为原始长度
+    pub fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let idle = self.compressors.lock().unwrap().pop();
+        let mut compressor = match idle {
+            Some(compressor) => compressor,
+            None => Compressor::with_dictionary(COMPRESSION_LEVEL, &self.data)?,
+        };
+        let compressed = compressor.compress(data)?;
+        release(&self.compressors, compressor);
+        let mut output = Vec::with_capacity(compressed.len() + 4);
+        output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        output.extend_from_slice(&compressed);
+        Ok(output)
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if data.len() < 4 {
+            return Err(anyhow!("compressed data too short"));
+        }
+        let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if size > MAX_DECOMPRESSED_SIZE {
+            return Err(anyhow!("decompressed size too large: {size}"));
+        }
+        let idle = self.decompressors.lock().unwrap().pop();
+        let mut decompressor = match idle {
+            Some(decompressor) => decompressor,
+            None => Decompressor::with_dictionary(&self.data)?,
+        };
+        let output = decompressor.decompress(&data[4..], size)?;
+        release(&self.decompressors, decompressor);
+        if output.len() != size {
+            return Err(anyhow!("decompressed size mismatch"));
+        }
+        Ok(output)
+    }
+}
+
+/// 把上下文放回空闲列表, 出错的上下文不会放回, 下次使用时重新创建
+fn release<T>(idle: &Mutex<Vec<T>>, context: T) {
+    let mut idle = idle.lock().unwrap();
+    if idle.len() < MAX_IDLE_CONTEXTS {
+        idle.push(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_dictionary() {
+        let dictionary =
+            b"GET / HTTP/1.1\r\nHost: example.com\r\nUser-Agent: npipe\r\nAccept: */*\r\n\r\n";
+        let source = format!("base64:{}", BASE64_STANDARD.encode(dictionary));
+        let dict = CompressionDictionary::load(&source).unwrap().unwrap();
+        assert_eq!(dict.hash().len(), 16);
+
+        let data = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nUser-Agent: npipe\r\n\r\n";
+        let compressed = dict.compress(data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(dict.decompress(&compressed).unwrap(), data);
+
+        // 上下文被复用, 不会每次压缩都重新创建
+        assert_eq!(dict.compress(data).unwrap(), compressed);
+        assert_eq!(dict.compressors.lock().unwrap().len(), 1);
+        assert_eq!(dict.decompressors.lock().unwrap().len(), 1);
+
+        // 不同的字典哈希不同, 解压结果也不正确
+        let other = CompressionDictionary::new(b"some other dictionary content").unwrap();
+        assert_ne!(other.hash(), dict.hash());
+        assert!(other
+            .decompress(&compressed)
+            .map_or(true, |x| x.as_slice() != data));
+
+        assert!(CompressionDictionary::load("").unwrap().is_none());
+        assert!(CompressionDictionary::load("base64:!!!").is_err());
+        assert!(CompressionDictionary::load("/nonexistent/dictionary").is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use bytes::{Buf, BytesMut};
+
+/// DNS报文头部长度
+const HEADER_LEN: usize = 12;
+
+/// 从DNS over TCP数据流中提取一个完整的DNS报文(去掉2字节长度前缀)
+///
+/// 数据不完整时返回 `Ok(None)`, 同一连接上流水线发送的多个查询依次提取
+pub(crate) fn extract_message(buffer: &mut BytesMut) -> anyhow::Result<Option<Vec<u8>>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    if len < HEADER_LEN {
+        return Err(anyhow::anyhow!("invalid DNS message length: {len}"));
+    }
+    if buffer.len() < 2 + len {
+        return Ok(None);
+    }
+    buffer.advance(2);
+    Ok(Some(buffer.split_to(len).to_vec()))
+}
+
+/// 为DNS报文加上2字节长度前缀
+pub(crate) fn prefix_message(message: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| anyhow::anyhow!("DNS message too large: {}", message.len()))?;
+    let mut data = Vec::with_capacity(2 + message.len());
+    data.extend_from_slice(&len.to_be_bytes());
+    data.extend(message);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut message = id.to_be_bytes().to_vec();
+        // 标志位: 期望递归, 1个问题
+        message.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+        // 根标签, QTYPE=A, QCLASS=IN
+        message.extend_from_slice(&[0, 0, 1, 0, 1]);
+        message
+    }
+
+    #[test]
+    fn test_pipelined_queries() {
+        let first = query(0x1234, "example.com");
+        let second = query(0xABCD, "rust-lang.org");
+
+        let mut stream = prefix_message(first.clone()).unwrap();
+        stream.extend(prefix_message(second.clone()).unwrap());
+
+        // 第二个查询只到达了一部分
+        let mut buffer = BytesMut::from(&stream[..stream.len() - 3]);
+        assert_eq!(extract_message(&mut buffer).unwrap(), Some(first.clone()));
+        assert_eq!(extract_message(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(&stream[stream.len() - 3..]);
+        let message = extract_message(&mut buffer).unwrap().unwrap();
+        assert_eq!(message, second);
+        assert_eq!(u16::from_be_bytes([message[0], message[1]]), 0xABCD);
+        assert!(buffer.is_empty());
+
+        // 两个查询一次性到达, 各自的ID保持不变
+        let mut buffer = BytesMut::from(&stream[..]);
+        let ids: Vec<u16> = std::iter::from_fn(|| extract_message(&mut buffer).unwrap())
+            .map(|x| u16::from_be_bytes([x[0], x[1]]))
+            .collect();
+        assert_eq!(ids, vec![0x1234, 0xABCD]);
+
+        let mut buffer = BytesMut::from(&[0x00, 0x02, 0x00, 0x00][..]);
+        assert!(extract_message(&mut buffer).is_err());
+    }
+}
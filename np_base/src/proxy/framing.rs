@@ -0,0 +1,166 @@
+use anyhow::anyhow;
+use bytes::{Buf, BytesMut};
+
+/// 单条消息的最大长度, 声明的长度超出时关闭会话, 避免缓存无限增长
+pub const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// 按长度前缀分帧的方式
+///
+/// 默认不分帧, 收到多少数据就转发多少. 分帧时入口和出口都按前缀拆出完整的消息,
+/// 每条消息单独转发, 写入对端时重新加上前缀, 对端收到的消息边界与发送方一致
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LengthPrefix {
+    #[default]
+    None,
+    U16Be,
+    U16Le,
+    U32Be,
+    U32Le,
+}
+
+impl LengthPrefix {
+    /// 0:不分帧 1:u16大端 2:u16小端 3:u32大端 4:u32小端
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::None),
+            1 => Some(Self::U16Be),
+            2 => Some(Self::U16Le),
+            3 => Some(Self::U32Be),
+            4 => Some(Self::U32Le),
+            _ => None,
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Self::None
+    }
+
+    /// 前缀的字节数
+    fn prefix_len(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::U16Be | Self::U16Le => 2,
+            Self::U32Be | Self::U32Le => 4,
+        }
+    }
+
+    /// 该前缀能表示的最大长度
+    fn max_len(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::U16Be | Self::U16Le => u16::MAX as usize,
+            Self::U32Be | Self::U32Le => MAX_MESSAGE_LEN,
+        }
+    }
+
+    /// 从数据流中提取一条完整的消息(去掉长度前缀)
+    ///
+    /// 前缀或消息不完整时返回 `Ok(None)`, 声明的长度超过上限时返回错误
+    pub(crate) fn extract(&self, buffer: &mut BytesMut) -> anyhow::Result<Option<Vec<u8>>> {
+        let prefix_len = self.prefix_len();
+        if buffer.len() < prefix_len {
+            return Ok(None);
+        }
+        let len = match self {
+            Self::None => return Ok(Some(buffer.split().to_vec())),
+            Self::U16Be => u16::from_be_bytes([buffer[0], buffer[1]]) as usize,
+            Self::U16Le => u16::from_le_bytes([buffer[0], buffer[1]]) as usize,
+            Self::U32Be => {
+                u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize
+            }
+            Self::U32Le => {
+                u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize
+            }
+        };
+        if len > self.max_len() {
+            return Err(anyhow!("framed message too large: {len}"));
+        }
+        if buffer.len() < prefix_len + len {
+            // 预留空间, 避免大消息多次扩容
+            buffer.reserve(prefix_len + len - buffer.len());
+            return Ok(None);
+        }
+        buffer.advance(prefix_len);
+        Ok(Some(buffer.split_to(len).to_vec()))
+    }
+
+    /// 为消息加上长度前缀
+    pub(crate) fn prefix(&self, message: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if self.is_none() {
+            return Ok(message);
+        }
+        if message.len() > self.max_len() {
+            return Err(anyhow!("framed message too large: {}", message.len()));
+        }
+        let mut data = Vec::with_capacity(self.prefix_len() + message.len());
+        match self {
+            Self::None => {}
+            Self::U16Be => data.extend_from_slice(&(message.len() as u16).to_be_bytes()),
+            Self::U16Le => data.extend_from_slice(&(message.len() as u16).to_le_bytes()),
+            Self::U32Be => data.extend_from_slice(&(message.len() as u32).to_be_bytes()),
+            Self::U32Le => data.extend_from_slice(&(message.len() as u32).to_le_bytes()),
+        }
+        data.extend(message);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragmented_prefix() {
+        for prefix in [
+            LengthPrefix::U16Be,
+            LengthPrefix::U16Le,
+            LengthPrefix::U32Be,
+            LengthPrefix::U32Le,
+        ] {
+            let first = b"hello".to_vec();
+            let second = vec![0xAB; 300];
+            let mut stream = prefix.prefix(first.clone()).unwrap();
+            stream.extend(prefix.prefix(Vec::new()).unwrap());
+            stream.extend(prefix.prefix(second.clone()).unwrap());
+
+            // 逐字节到达, 前缀被拆开时不能提前提取
+            let mut buffer = BytesMut::new();
+            let mut messages = Vec::new();
+            for byte in stream {
+                buffer.extend_from_slice(&[byte]);
+                while let Some(message) = prefix.extract(&mut buffer).unwrap() {
+                    messages.push(message);
+                }
+            }
+            assert_eq!(messages, vec![first, Vec::new(), second], "{prefix:?}");
+            assert!(buffer.is_empty());
+        }
+
+        // 前缀的字节序不同, 同样的数据解析出的长度不同
+        let mut buffer = BytesMut::from(&[0x00, 0x01, 0x7F][..]);
+        assert_eq!(
+            LengthPrefix::U16Be.extract(&mut buffer).unwrap(),
+            Some(vec![0x7F])
+        );
+        let mut buffer = BytesMut::from(&[0x00, 0x01, 0x7F][..]);
+        assert_eq!(LengthPrefix::U16Le.extract(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn test_oversized_message() {
+        // 只收到前缀, 声明的长度已超出上限
+        let mut buffer = BytesMut::from(&((MAX_MESSAGE_LEN + 1) as u32).to_be_bytes()[..]);
+        assert!(LengthPrefix::U32Be.extract(&mut buffer).is_err());
+
+        let mut buffer = BytesMut::from(&[0xFF, 0xFF, 0xFF][..]);
+        assert_eq!(LengthPrefix::U32Le.extract(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&[0xFF]);
+        assert!(LengthPrefix::U32Le.extract(&mut buffer).is_err());
+
+        assert!(LengthPrefix::U16Be.prefix(vec![0; 65536]).is_err());
+        assert!(LengthPrefix::U32Le
+            .prefix(vec![0; MAX_MESSAGE_LEN + 1])
+            .is_err());
+        assert_eq!(LengthPrefix::from_u32(5), None);
+    }
+}
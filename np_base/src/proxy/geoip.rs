@@ -0,0 +1,146 @@
+use anyhow::anyhow;
+use std::net::IpAddr;
+
+/// 没有数据库或查不到时的国家代码
+pub const UNKNOWN_COUNTRY: &str = "unknown";
+
+/// 客户端地址的地理位置信息
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// ISO国家代码
+    pub country: String,
+    /// 自治系统号, 0表示未知
+    pub asn: u32,
+}
+
+impl GeoInfo {
+    /// 日志中使用的标签, 例如 `CN/AS4134`
+    pub fn tag(&self) -> String {
+        if self.asn == 0 {
+            self.country.clone()
+        } else {
+            format!("{}/AS{}", self.country, self.asn)
+        }
+    }
+}
+
+/// 启动时整体加载到内存的地址段数据库, 查询时不读文件
+///
+/// 文件为MaxMind GeoLite2 CSV导出的简化格式, 每行 `网段,国家代码[,ASN]`, 例如 `1.0.0.0/24,AU,13335`,
+/// 空行和 `#` 开头的行会被忽略
+pub struct GeoDatabase {
+    // 按起始地址排序的地址段, IPv4地址转为IPv4映射的IPv6地址
+    ranges: Vec<(u128, u128, GeoInfo)>,
+}
+
+impl GeoDatabase {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("failed to read geoip database '{path}': {err}"))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || anyhow!("invalid geoip record at line {}: {line}", index + 1);
+            let mut fields = line.split(',').map(str::trim);
+            let (start, end) = fields.next().and_then(parse_network).ok_or_else(invalid)?;
+            let country = match fields.next() {
+                Some(country) if !country.is_empty() => country.to_ascii_uppercase(),
+                _ => return Err(invalid()),
+            };
+            let asn = match fields.next() {
+                Some(asn) if !asn.is_empty() => asn
+                    .trim_start_matches("AS")
+                    .parse()
+                    .map_err(|_| invalid())?,
+                _ => 0,
+            };
+            ranges.push((start, end, GeoInfo { country, asn }));
+        }
+        ranges.sort_by_key(|x| x.0);
+        Ok(Self { ranges })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<&GeoInfo> {
+        let ip = ip_to_u128(ip);
+        let index = self.ranges.partition_point(|x| x.0 <= ip);
+        if index == 0 {
+            return None;
+        }
+        let (_, end, info) = &self.ranges[index - 1];
+        (ip <= *end).then_some(info)
+    }
+}
+
+pub(crate) fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+/// 解析CIDR网段, 返回起止地址
+pub(crate) fn parse_network(network: &str) -> Option<(u128, u128)> {
+    let (ip, prefix) = network.split_once('/')?;
+    let ip = ip.parse::<IpAddr>().ok()?;
+    let prefix = prefix.parse::<u32>().ok()?;
+    // IPv4网段在映射地址中的前缀多96位
+    let prefix = match ip {
+        IpAddr::V4(_) if prefix <= 32 => prefix + 96,
+        IpAddr::V6(_) if prefix <= 128 => prefix,
+        _ => return None,
+    };
+    let host_mask = u128::MAX.checked_shr(prefix).unwrap_or(0);
+    let start = ip_to_u128(ip) & !host_mask;
+    Some((start, start | host_mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let database = GeoDatabase::parse(
+            "# network,country,asn\n\
+             1.0.0.0/24,au,13335\n\
+             10.0.0.0/8,ZZ\n\
+             2001:db8::/32,JP,AS2497\n",
+        )
+        .unwrap();
+        assert_eq!(database.len(), 3);
+
+        let info = database.lookup("1.0.0.200".parse().unwrap()).unwrap();
+        assert_eq!(info.tag(), "AU/AS13335");
+        assert_eq!(
+            database
+                .lookup("10.255.0.1".parse().unwrap())
+                .unwrap()
+                .tag(),
+            "ZZ"
+        );
+        assert_eq!(
+            database.lookup("2001:db8::1".parse().unwrap()).unwrap().asn,
+            2497
+        );
+        assert!(database.lookup("1.0.1.0".parse().unwrap()).is_none());
+        assert!(database.lookup("::1".parse().unwrap()).is_none());
+
+        assert!(GeoDatabase::parse("1.0.0.0/33,AU").is_err());
+        assert!(GeoDatabase::parse("1.0.0.0/24").is_err());
+    }
+}
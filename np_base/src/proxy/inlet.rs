@@ -1,21 +1,43 @@
 use crate::net::session_delegate::SessionDelegate;
-use crate::net::{tcp_server, udp_server};
+use crate::net::tls::ReloadableServerConfig;
+use crate::net::{splice, tcp_server, udp_server};
 use crate::net::{SendMessageFuncType, WriterMessage};
-use crate::proxy::common::{InputSenderType, SessionCommonInfo};
+use crate::proxy::auth::{Authenticator, StaticAuthenticator};
+use crate::proxy::breaker::{BreakerCallback, BreakerState, CircuitBreaker, CircuitBreakerConfig};
+use crate::proxy::common::{
+    DataCodec, InputSenderType, SessionCommonInfo, I2O_KEY_INFO, O2I_KEY_INFO,
+};
+use crate::proxy::crypto::{EncryptionMethod, KeyDerivation, MethodPreference};
+use crate::proxy::dictionary::CompressionDictionary;
+use crate::proxy::framing::LengthPrefix;
+use crate::proxy::geoip::{GeoDatabase, GeoInfo, UNKNOWN_COUNTRY};
+use crate::proxy::outlet::OutletDataEx;
+use crate::proxy::proxy_protocol::{parse_proxy_header, ProxyHeader};
+use crate::proxy::rate_limit::RateLimiter;
+use crate::proxy::recorder::{Direction, MessageRecorder};
+use crate::proxy::reject::{RejectReason, RejectResponse, Socks5Rejection};
 use crate::proxy::socks5::Socks5Context;
-use crate::proxy::{common, OutputFuncType, ProxyMessage};
+use crate::proxy::stats::{
+    BackpressureStats, RttRecorder, RttStats, SessionCloseCounter, SessionCloseReason,
+    SessionCloseStats, SessionEvent, SessionEventCallback, SessionSummary, TrafficCallback,
+    TrafficCounter, TrafficStats,
+};
+use crate::proxy::transport::{MessageTransport, RecordingTransport};
+use crate::proxy::vhost::{LabelLine, PeekResult};
+use crate::proxy::{common, crypto, dns, stats, vhost, ProxyMessage};
 use anyhow::anyhow;
 use async_trait::async_trait;
-use base64::prelude::*;
-use log::{error, trace};
+use bytes::{Buf, BytesMut};
+use log::{debug, error, trace, warn};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
 use tokio::select;
 use tokio::sync::mpsc::{Sender, UnboundedReceiver, UnboundedSender};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, watch, Notify, RwLock};
 use tokio::task::yield_now;
 
 #[derive(Clone)]
@@ -23,6 +45,14 @@ pub enum InletProxyType {
     TCP,
     UDP,
     SOCKS5,
+    /// 根据TLS SNI选择后端的TCP代理
+    HTTPS,
+    /// 根据HTTP Host请求头选择后端的TCP代理
+    HTTP,
+    /// DNS over TCP, 每个查询作为单独的UDP包转发给出口
+    DNS,
+    /// 按客户端发送的第一行标签由出口选择后端的TCP代理, 标签行不转发给后端
+    LABEL,
 }
 
 impl InletProxyType {
@@ -31,6 +61,10 @@ impl InletProxyType {
             0 => Some(InletProxyType::TCP),
             1 => Some(InletProxyType::UDP),
             2 => Some(InletProxyType::SOCKS5),
+            4 => Some(InletProxyType::HTTPS),
+            5 => Some(InletProxyType::HTTP),
+            6 => Some(InletProxyType::DNS),
+            7 => Some(InletProxyType::LABEL),
             _ => None,
         }
     }
@@ -40,62 +74,874 @@ impl InletProxyType {
             InletProxyType::TCP => 0,
             InletProxyType::UDP => 1,
             InletProxyType::SOCKS5 => 2,
+            InletProxyType::HTTPS => 4,
+            InletProxyType::HTTP => 5,
+            InletProxyType::DNS => 6,
+            InletProxyType::LABEL => 7,
         }
     }
 
     pub fn is_socks5(&self) -> bool {
-        match self {
-            InletProxyType::SOCKS5 => true,
-            _ => false,
-        }
+        matches!(self, InletProxyType::SOCKS5)
     }
 
     pub fn is_tcp(&self) -> bool {
-        match self {
-            InletProxyType::TCP => true,
-            _ => false,
-        }
+        matches!(self, InletProxyType::TCP)
+    }
+
+    /// 是否是基于字节流的代理
+    pub fn is_stream(&self) -> bool {
+        matches!(
+            self,
+            InletProxyType::TCP
+                | InletProxyType::SOCKS5
+                | InletProxyType::HTTPS
+                | InletProxyType::HTTP
+                | InletProxyType::DNS
+                | InletProxyType::LABEL
+        )
+    }
+
+    /// 是否需要根据主机名选择后端
+    pub fn is_vhost(&self) -> bool {
+        matches!(self, InletProxyType::HTTPS | InletProxyType::HTTP)
+    }
+
+    pub fn is_http(&self) -> bool {
+        matches!(self, InletProxyType::HTTP)
+    }
+
+    pub fn is_dns(&self) -> bool {
+        matches!(self, InletProxyType::DNS)
+    }
+
+    pub fn is_label(&self) -> bool {
+        matches!(self, InletProxyType::LABEL)
     }
 }
 
 struct SessionInfo {
+    // 会话代数, 用于丢弃发给同id旧会话的消息
+    generation: u32,
     proxy_message_tx: Option<mpsc::UnboundedSender<ProxyMessage>>,
     write_msg_tx: InputSenderType,
     common_info: SessionCommonInfo,
+    activity: Arc<SessionActivity>,
+    peer_addr: SocketAddr,
+    // 客户端地址的地理位置, 没有配置数据库或查不到时为None
+    geo: Option<GeoInfo>,
+    // 由本端主动关闭时记录原因
+    close_reason: Option<SessionCloseReason>,
+    // 开启连接重试时的重试状态
+    connect_retry: Option<ConnectRetry>,
+    // 出口已经应答连接成功
+    connected: bool,
+    // 出口实际连接的后端地址, 连接成功前为空
+    backend_addr: String,
+    // 等待写给客户端的字节数上限, 为0时不限制
+    write_queue_limit: usize,
+}
+
+impl SessionInfo {
+    /// 消息是否属于当前会话, 代数为0的消息来自不支持代数的出口, 不做检查
+    fn accepts(&self, generation: u32) -> bool {
+        generation == 0 || generation == self.generation
+    }
+}
+
+/// 分配下一个会话代数, 跳过0
+fn next_generation(counter: &AtomicU32) -> u32 {
+    loop {
+        let generation = counter.fetch_add(1, Ordering::Relaxed);
+        if generation != 0 {
+            return generation;
+        }
+    }
+}
+
+/// 检查是否拒绝新会话, 被限速时记录到关闭统计
+fn check_reject(
+    over_quota: &AtomicBool,
+    paused: &AtomicBool,
+    breaker: &CircuitBreaker,
+    rate_limiter: &RateLimiter,
+    close_counter: &SessionCloseCounter,
+) -> Option<RejectReason> {
+    if over_quota.load(Ordering::Relaxed) {
+        return Some(RejectReason::QuotaExceeded);
+    }
+    if paused.load(Ordering::Relaxed) {
+        return Some(RejectReason::Paused);
+    }
+    if !breaker.allow() {
+        return Some(RejectReason::CircuitOpen);
+    }
+    if !rate_limiter.allow() {
+        close_counter.record_rate_limited();
+        return Some(RejectReason::RateLimited);
+    }
+    None
+}
+
+/// 不经过帧转发, 直接splice到后端的TCP会话
+///
+/// 会话与普通会话一样登记到会话表, 可以列出和单独关闭, 由回收任务检查存活时间和空闲超时
+struct SpliceSession {
+    output_addr: String,
+    data_ex: SharedDataEx,
+    session_info_map: SessionInfoMap,
+    generation: Arc<AtomicU32>,
+    paused: Arc<AtomicBool>,
+    over_quota: Arc<AtomicBool>,
+    breaker: Arc<CircuitBreaker>,
+    rate_limiter: Arc<RateLimiter>,
+    close_counter: Arc<SessionCloseCounter>,
+    traffic_counter: Arc<TrafficCounter>,
+}
+
+impl SpliceSession {
+    /// 当前设置不能splice时交还连接, 按帧转发
+    async fn run(
+        &self,
+        session_id: u32,
+        addr: SocketAddr,
+        stream: TcpStream,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> Option<TcpStream> {
+        let data_ex = self.data_ex.read().unwrap().clone();
+        let Some(outlet) = data_ex.splice_outlet() else {
+            return Some(stream);
+        };
+        let peer_addr = common::normalize_addr(addr);
+        if let Some(reason) = check_reject(
+            &self.over_quota,
+            &self.paused,
+            &self.breaker,
+            &self.rate_limiter,
+            &self.close_counter,
+        ) {
+            debug!("inlet rejected new session from {peer_addr}: {reason}");
+            return None;
+        }
+
+        let backend = match outlet.allowlist.check(&self.output_addr).await {
+            Ok(target_addr) => outlet.dial(&target_addr).await,
+            Err(err) => Err(err),
+        };
+        self.breaker.record(backend.is_ok());
+        let backend = match backend {
+            Ok(backend) => backend,
+            Err(err) => {
+                debug!(
+                    "splice session({session_id}) connect {} error: {err}",
+                    self.output_addr
+                );
+                return None;
+            }
+        };
+        trace!("splice session({session_id}) start {peer_addr}");
+        let activity = Arc::new(SessionActivity::new(self.traffic_counter.clone()));
+        // 被强制关闭或由回收任务关闭时收到Close
+        let (close_tx, mut close_rx) = mpsc::unbounded_channel();
+        self.session_info_map.write().await.insert(
+            session_id,
+            SessionInfo {
+                generation: next_generation(&self.generation),
+                proxy_message_tx: None,
+                write_msg_tx: close_tx,
+                common_info: SessionCommonInfo::new(
+                    DataCodec::new(false, EncryptionMethod::None, Vec::new()),
+                    None,
+                ),
+                activity: activity.clone(),
+                peer_addr,
+                geo: data_ex
+                    .geo_database
+                    .as_ref()
+                    .and_then(|x| x.lookup(peer_addr.ip()))
+                    .cloned(),
+                close_reason: None,
+                connect_retry: None,
+                // 后端已经连接成功
+                connected: true,
+                backend_addr: backend.peer_addr().map_or(String::new(), |x| x.to_string()),
+                write_queue_limit: data_ex.write_queue_limit,
+            },
+        );
+        if let Some(ref on_event) = data_ex.on_session_event {
+            on_event(SessionEvent::Open {
+                session_id,
+                peer_addr,
+            });
+        }
+
+        let on_traffic = |len: u64| {
+            activity.touch();
+            self.traffic_counter.add_bytes_spliced(len);
+            if let Some(ref on_traffic) = data_ex.on_spliced_traffic {
+                on_traffic(len);
+            }
+        };
+        let relay = splice::relay(
+            &stream,
+            &backend,
+            |len| {
+                activity.add_bytes_in(len);
+                on_traffic(len);
+            },
+            |len| {
+                activity.add_bytes_out(len);
+                on_traffic(len);
+            },
+        );
+        select! {
+            result = relay => {
+                if let Err(err) = result {
+                    debug!("splice session({session_id}) error: {err}");
+                }
+            }
+            _ = close_rx.recv() => {}
+            _ = shutdown.recv() => {}
+        }
+
+        trace!("splice session({session_id}) close");
+        // 已被强制关闭的会话不在会话表中, 关闭时已经记录
+        if let Some(session) = self.session_info_map.write().await.remove(&session_id) {
+            let reason = session.close_reason.unwrap_or(SessionCloseReason::Peer);
+            self.close_counter.record(reason);
+            if let Some(ref on_event) = data_ex.on_session_event {
+                on_event(SessionEvent::Close {
+                    session_id,
+                    peer_addr,
+                    reason,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// 连接出口失败时重新发起连接所需的状态
+struct ConnectRetry {
+    // 重新发起连接的消息
+    message: ProxyMessage,
+    // 剩余重试次数
+    remaining: u32,
+    // 重试间隔
+    delay: Duration,
+    // 连接结果, None表示还在连接中
+    result_tx: watch::Sender<Option<bool>>,
+}
+
+/// 后端暂时不可用的错误(例如后端重启期间), 可以重试连接
+fn is_retryable_error(error_msg: &str) -> bool {
+    let error_msg = error_msg.to_ascii_lowercase();
+    ["refused", "reset", "timed out"]
+        .iter()
+        .any(|x| error_msg.contains(x))
+}
+
+/// 会话的读写进度, 用于检测停滞的连接
+struct SessionActivity {
+    // 会话开始时间
+    start_time: Instant,
+    // 最后一次读写时距离会话开始的毫秒数
+    last_active: AtomicU64,
+    // 已提交但尚未写完的数据块数量
+    pending_writes: AtomicUsize,
+    // 已提交但尚未写完的字节数
+    pending_write_bytes: AtomicUsize,
+    // 强制结束会话, 不等待排队的数据写完
+    abort: Arc<Notify>,
+    // 从客户端收到的字节数
+    bytes_in: AtomicU64,
+    // 写给客户端的字节数
+    bytes_out: AtomicU64,
+    // 入口的累计流量
+    traffic: Arc<TrafficCounter>,
+}
+
+impl SessionActivity {
+    fn new(traffic: Arc<TrafficCounter>) -> Self {
+        Self {
+            start_time: Instant::now(),
+            last_active: AtomicU64::new(0),
+            pending_writes: AtomicUsize::new(0),
+            pending_write_bytes: AtomicUsize::new(0),
+            abort: Arc::new(Notify::new()),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            traffic,
+        }
+    }
+
+    fn add_bytes_in(&self, len: u64) {
+        self.bytes_in.fetch_add(len, Ordering::Relaxed);
+        self.traffic.add_bytes_in(len);
+    }
+
+    fn add_bytes_out(&self, len: u64) {
+        self.bytes_out.fetch_add(len, Ordering::Relaxed);
+        self.traffic.add_bytes_out(len);
+    }
+
+    /// 有数据读写时刷新
+    fn touch(&self) {
+        let elapsed = self.start_time.elapsed().as_millis() as u64;
+        self.last_active.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// 距离最后一次读写的时间
+    fn idle_time(&self) -> Duration {
+        self.start_time
+            .elapsed()
+            .saturating_sub(Duration::from_millis(
+                self.last_active.load(Ordering::Relaxed),
+            ))
+    }
 }
 
 type SessionInfoMap = Arc<RwLock<HashMap<u32, SessionInfo>>>;
 
+/// 运行中可以替换的入口设置, 新会话创建时取当前值
+type SharedDataEx = Arc<std::sync::RwLock<Arc<InletDataEx>>>;
+
 pub struct Inlet {
     is_running: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    // 通道流量超过配额, 与暂停一样不接受新连接
+    over_quota: Arc<AtomicBool>,
+    unix_socket_path: Option<String>,
+    // 实际监听的地址, 监听端口为0时由系统分配
+    local_addr: Option<SocketAddr>,
     input: Option<UnboundedSender<ProxyMessage>>,
+    recorder: Option<Arc<MessageRecorder>>,
     session_info_map: SessionInfoMap,
+    close_counter: Arc<SessionCloseCounter>,
+    traffic_counter: Arc<TrafficCounter>,
+    rtt: Arc<RttRecorder>,
+    breaker: Arc<CircuitBreaker>,
+    // 会话代数计数器, 随机起始避免重启后与出口上残留的旧会话重复
+    generation: Arc<AtomicU32>,
+    data_ex: SharedDataEx,
+    rate_limiter: Arc<RateLimiter>,
     description: String,
-    on_output_callback: OutputFuncType,
+    // 写入日志的描述, 不含用户名和密码
+    redacted_description: String,
+    // 可以在运行中应用的设置的描述
+    settings_description: String,
+    transport: Arc<dyn MessageTransport>,
+    // 终止TLS时使用的证书, 文件变化后自动重新加载
+    tls_server_config: Option<Arc<ReloadableServerConfig>>,
 }
 
+/// 单次提取的最大帧大小默认值
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// 每个会话等待写给客户端的字节数上限默认值
+pub const DEFAULT_WRITE_QUEUE_LIMIT: usize = 64 * 1024 * 1024;
+
+/// 通道默认的压缩阈值(字节)
+pub const DEFAULT_COMPRESS_THRESHOLD: u32 = 128;
+
+/// 检查证书文件是否变化的间隔
+const TLS_RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 回复拒绝原因后等待客户端读取的时间
+const REJECT_CLOSE_DELAY: Duration = Duration::from_secs(1);
+
+/// 拒绝SOCKS5连接时等待客户端完成握手的最长时间
+const SOCKS5_REJECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct InletDataEx {
     pub(crate) username: String,
     pub(crate) password: String,
+    pub(crate) max_frame_size: usize,
+    pub(crate) chunk_size: usize,
+    pub(crate) host_routes: HashMap<String, String>,
+    pub(crate) max_session_lifetime: Duration,
+    pub(crate) read_timeout: Duration,
+    pub(crate) write_timeout: Duration,
+    pub(crate) o2i_is_compressed: Option<bool>,
+    pub(crate) o2i_encryption_method: Option<String>,
+    pub(crate) accept_proxy_protocol: bool,
+    pub(crate) write_coalesce: Duration,
+    pub(crate) ipv6_only: Option<bool>,
+    pub(crate) bind_device: Option<String>,
+    pub(crate) tls_cert: String,
+    pub(crate) tls_key: String,
+    pub(crate) tls_alpn: Vec<String>,
+    pub(crate) backend_tls: Option<bool>,
+    pub(crate) compression_dictionary: Option<Arc<CompressionDictionary>>,
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) connect_retries: u32,
+    pub(crate) connect_retry_delay: Duration,
+    pub(crate) conn_rate_limit: u32,
+    pub(crate) conn_rate_burst: u32,
+    pub(crate) circuit_breaker: CircuitBreakerConfig,
+    pub(crate) on_breaker_change: Option<BreakerCallback>,
+    pub(crate) on_session_event: Option<SessionEventCallback>,
+    pub(crate) geo_database: Option<Arc<GeoDatabase>>,
+    pub(crate) recorder: Option<Arc<MessageRecorder>>,
+    pub(crate) listen_backlog: u32,
+    pub(crate) nodelay: bool,
+    pub(crate) fast_open: bool,
+    pub(crate) metadata: HashMap<String, String>,
+    pub(crate) length_prefix: LengthPrefix,
+    pub(crate) reject_response: RejectResponse,
+    pub(crate) key_derivation: Option<(KeyDerivation, Vec<u8>)>,
+    pub(crate) encryption_fallback: bool,
+    pub(crate) splice_outlet: Option<OutletDataEx>,
+    pub(crate) on_spliced_traffic: Option<TrafficCallback>,
+    pub(crate) compress_threshold: usize,
+    pub(crate) probe_interval: Duration,
+    pub(crate) write_queue_limit: usize,
 }
 
 impl InletDataEx {
     pub fn new(username: String, password: String) -> Self {
-        Self { username, password }
+        Self {
+            username,
+            password,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            chunk_size: 0,
+            host_routes: HashMap::new(),
+            max_session_lifetime: Duration::ZERO,
+            read_timeout: Duration::ZERO,
+            write_timeout: Duration::ZERO,
+            o2i_is_compressed: None,
+            o2i_encryption_method: None,
+            accept_proxy_protocol: false,
+            write_coalesce: Duration::ZERO,
+            ipv6_only: None,
+            bind_device: None,
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_alpn: Vec::new(),
+            backend_tls: None,
+            compression_dictionary: None,
+            authenticator: None,
+            connect_retries: 0,
+            connect_retry_delay: Duration::ZERO,
+            conn_rate_limit: 0,
+            conn_rate_burst: 0,
+            circuit_breaker: CircuitBreakerConfig::default(),
+            on_breaker_change: None,
+            on_session_event: None,
+            geo_database: None,
+            recorder: None,
+            listen_backlog: 0,
+            nodelay: true,
+            fast_open: false,
+            metadata: HashMap::new(),
+            length_prefix: LengthPrefix::None,
+            reject_response: RejectResponse::default(),
+            key_derivation: None,
+            encryption_fallback: false,
+            splice_outlet: None,
+            on_spliced_traffic: None,
+            compress_threshold: 0,
+            probe_interval: Duration::ZERO,
+            write_queue_limit: DEFAULT_WRITE_QUEUE_LIMIT,
+        }
+    }
+
+    /// 设置出口到入口方向的压缩与加密方式, 为None时与入口到出口方向相同
+    pub fn set_o2i_codec(
+        mut self,
+        is_compressed: Option<bool>,
+        encryption_method: Option<String>,
+    ) -> Self {
+        self.o2i_is_compressed = is_compressed;
+        self.o2i_encryption_method = encryption_method;
+        self
+    }
+
+    /// 生成出口到入口方向的编码方式, 与入口到出口方向相同时返回None
+    fn o2i_codec(&self, i2o: &DataCodec, salt: &[u8]) -> Option<DataCodec> {
+        let is_compressed = self.o2i_is_compressed.unwrap_or(i2o.is_compressed);
+        let i2o_method = i2o.encryption_method.to_string();
+        let encryption_method = self
+            .o2i_encryption_method
+            .as_deref()
+            .unwrap_or(i2o_method.as_str());
+        if is_compressed == i2o.is_compressed
+            && crypto::get_method(encryption_method).to_string() == i2o_method
+        {
+            return None;
+        }
+        Some(
+            self.new_codec(is_compressed, encryption_method, salt, O2I_KEY_INFO)
+                .set_dictionary(i2o.dictionary.clone()),
+        )
+    }
+
+    /// 设置会话的最大存活时间(秒), 超时后无论是否活跃都会被关闭, 为0时不限制
+    pub fn set_max_session_lifetime(mut self, secs: u64) -> Self {
+        self.max_session_lifetime = Duration::from_secs(secs);
+        self
+    }
+
+    /// 设置流式会话的读写超时(秒), 为0时不限制
+    ///
+    /// 读超时: 双向都没有任何数据传输的时间; 写超时: 有数据等待写入但对端一直不读取的时间.
+    /// 任意方向传输数据都会重置计时, 超时的会话会被关闭
+    pub fn set_idle_deadlines(mut self, read_secs: u64, write_secs: u64) -> Self {
+        self.read_timeout = Duration::from_secs(read_secs);
+        self.write_timeout = Duration::from_secs(write_secs);
+        self
+    }
+
+    /// 设置是否接受PROXY协议(v1/v2)头部, 开启后使用头部中的客户端地址, 只对流式入口生效
+    pub fn set_accept_proxy_protocol(mut self, accept_proxy_protocol: bool) -> Self {
+        self.accept_proxy_protocol = accept_proxy_protocol;
+        self
+    }
+
+    /// 设置合并写入的时间窗口(毫秒), 入口和出口写入连接的小块数据在窗口内合并后再刷新, 为0时不合并
+    ///
+    /// 以少量延迟换取吞吐, 交互式的通道不要开启
+    pub fn set_write_coalesce(mut self, millis: u64) -> Self {
+        self.write_coalesce = Duration::from_millis(millis);
+        self
+    }
+
+    /// 设置监听IPv6通配地址(`[::]`)时是否只接受IPv6连接, 为None时使用系统默认设置
+    pub fn set_ipv6_only(mut self, ipv6_only: Option<bool>) -> Self {
+        self.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// 设置每个会话都带给出口的元数据(例如通道标签), 出口用于日志和路由
+    pub fn set_metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// 设置TCP监听队列长度, 连接突发时队列满会导致SYN被丢弃, 为0时使用默认值1024
+    ///
+    /// 实际长度受系统上限限制, 见 [`tcp_server::bind`]
+    pub fn set_listen_backlog(mut self, listen_backlog: u32) -> Self {
+        self.listen_backlog = listen_backlog;
+        self
+    }
+
+    /// 设置入口接受的TCP连接是否关闭Nagle算法(TCP_NODELAY), 默认关闭
+    ///
+    /// 关闭后发往客户端的小包立即发出, 交互式的下行流量延迟更低; 开启时内核合并小包,
+    /// 大量数据下载时包数更少、吞吐更高. 修改后需要重启入口
+    pub fn set_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// 设置TCP监听是否接受SYN中携带的数据(TCP Fast Open, 只支持Linux), 默认不接受
+    ///
+    /// 支持TFO的客户端重复连接时省去一次往返, 需要系统开启 `net.ipv4.tcp_fastopen` 的服务端标志(2).
+    /// 修改后需要重启入口
+    pub fn set_fast_open(mut self, fast_open: bool) -> Self {
+        self.fast_open = fast_open;
+        self
+    }
+
+    /// 设置TCP通道按长度前缀分帧, 每条完整的消息单独转发给出口, 出口必须使用相同的设置
+    ///
+    /// 只对TCP入口生效, 声明的长度超过 [`crate::proxy::framing::MAX_MESSAGE_LEN`] 时关闭会话
+    pub fn set_length_prefix(mut self, length_prefix: LengthPrefix) -> Self {
+        self.length_prefix = length_prefix;
+        self
+    }
+
+    /// 设置拒绝新连接(暂停、超过配额、限速、熔断)时回复给客户端的内容, 未设置时直接断开
+    pub fn set_reject_response(mut self, reject_response: RejectResponse) -> Self {
+        self.reject_response = reject_response;
+        self
+    }
+
+    /// 设置会话key的派生方式, 派生时key不随连接请求发送, 出口用相同的密码计算
+    ///
+    /// 预共享密钥在这里计算一次, 会话只做HKDF. 随机方式或密码为空时每个会话随机生成key
+    pub fn set_key_derivation(mut self, kdf: KeyDerivation, password: &str) -> Self {
+        self.key_derivation = if kdf == KeyDerivation::Random || password.is_empty() {
+            None
+        } else {
+            Some((kdf, crypto::prepare_key_secret(kdf, password)))
+        };
+        self
+    }
+
+    /// 设置加密方法偏好列表是否允许降级: 出口拒绝第一个方法时新会话改用列表中后面的方法
+    ///
+    /// 默认不降级, 出口拒绝时连接失败. 修改后需要重启入口
+    pub fn set_encryption_fallback(mut self, fallback: bool) -> Self {
+        self.encryption_fallback = fallback;
+        self
+    }
+
+    /// 设置出口在本进程内时直接连接后端使用的出口设置, 为None时不开启splice
+    ///
+    /// 只对Linux上不压缩不加密的TCP入口生效, 会话不经过帧转发, 数据用splice在两个连接之间直接搬运.
+    /// 开启了PROXY协议、认证、分帧、合并写入、读写超时或录制等需要处理数据的设置时仍按帧转发
+    pub fn set_splice_outlet(mut self, outlet: Option<OutletDataEx>) -> Self {
+        self.splice_outlet = outlet;
+        self
+    }
+
+    /// 设置splice转发数据时的回调, 参数为两个方向转发的字节数
+    ///
+    /// splice的数据不经过消息转发, 需要按消息统计流量(例如流量配额)时通过这个回调计入
+    pub fn set_on_spliced_traffic(mut self, on_traffic: TrafficCallback) -> Self {
+        self.on_spliced_traffic = Some(on_traffic);
+        self
+    }
+
+    /// 当前设置下新会话能否直接splice
+    fn splice_outlet(&self) -> Option<&OutletDataEx> {
+        let outlet = self.splice_outlet.as_ref()?;
+        let o2i_plain = self.o2i_is_compressed != Some(true)
+            && self
+                .o2i_encryption_method
+                .as_deref()
+                .is_none_or(|x| matches!(crypto::get_method(x), EncryptionMethod::None));
+        let eligible = o2i_plain
+            && !self.accept_proxy_protocol
+            && self.authenticator.is_none()
+            && self.length_prefix.is_none()
+            && self.write_coalesce.is_zero()
+            && self.read_timeout.is_zero()
+            && self.write_timeout.is_zero()
+            && self.recorder.is_none()
+            && self.backend_tls.is_none()
+            && !outlet.send_proxy_protocol;
+        eligible.then_some(outlet)
+    }
+
+    /// 设置压缩阈值, 开启压缩时小于该长度的数据不压缩, 为0时全部压缩
+    ///
+    /// 不为0时每块数据带1字节头部标记是否压缩, 出口需要支持此格式
+    pub fn set_compress_threshold(mut self, compress_threshold: usize) -> Self {
+        self.compress_threshold = compress_threshold;
+        self
+    }
+
+    /// 生成本端的编码方式, 需要派生key时使用会话的盐
+    fn new_codec(
+        &self,
+        is_compressed: bool,
+        encryption_method: &str,
+        salt: &[u8],
+        info: &[u8],
+    ) -> DataCodec {
+        match &self.key_derivation {
+            Some((kdf, secret)) => DataCodec::from_derived_key(
+                is_compressed,
+                encryption_method,
+                *kdf,
+                secret,
+                salt,
+                info,
+            ),
+            None => DataCodec::from_method_name(is_compressed, encryption_method),
+        }
+        .set_compress_threshold(self.compress_threshold)
+    }
+
+    /// 设置监听绑定的网卡名称(`SO_BINDTODEVICE`), 为空时不绑定
+    ///
+    /// 只支持Linux, 需要root或 `CAP_NET_RAW` 权限, 其他平台启动时返回错误
+    pub fn set_bind_device(mut self, device: String) -> Self {
+        self.bind_device = if device.is_empty() {
+            None
+        } else {
+            Some(device)
+        };
+        self
+    }
+
+    /// 设置在入口终止客户端的TLS, 证书为空时不启用, 只支持TCP、HTTP和SOCKS5入口
+    ///
+    /// [`alpn`] 逗号分隔的ALPN协议, 按优先级排列, 协商结果转交给出口用于连接后端
+    pub fn set_tls(mut self, cert: String, key: String, alpn: String) -> Self {
+        self.tls_cert = cert;
+        self.tls_key = key;
+        self.tls_alpn = alpn
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect();
+        self
+    }
+
+    /// 设置出口连接后端的方式: 0明文, 1 TLS并校验证书, 2 TLS不校验证书(自签证书的后端)
+    pub fn set_backend_tls(mut self, mode: u32) -> Self {
+        self.backend_tls = match mode {
+            1 => Some(true),
+            2 => Some(false),
+            _ => None,
+        };
+        self
+    }
+
+    /// 设置SOCKS5入口的用户认证方式, 不设置时与通道配置的用户名密码比较
+    pub fn set_authenticator(mut self, authenticator: Option<Arc<dyn Authenticator>>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// 设置消息录制, 入口收发的所有消息写入录制文件, 重启入口后生效
+    pub fn set_recorder(mut self, recorder: Option<Arc<MessageRecorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// 是否需要用户认证
+    pub(crate) fn requires_auth(&self) -> bool {
+        self.authenticator.is_some() || !self.username.is_empty() || !self.password.is_empty()
+    }
+
+    /// 当前使用的用户认证方式
+    pub(crate) fn authenticator(&self) -> Arc<dyn Authenticator> {
+        self.authenticator.clone().unwrap_or_else(|| {
+            Arc::new(StaticAuthenticator::new(
+                self.username.clone(),
+                self.password.clone(),
+            ))
+        })
+    }
+
+    /// 设置压缩字典, 出口必须使用同一个字典, 否则会话连接失败
+    pub fn set_compression_dictionary(
+        mut self,
+        dictionary: Option<Arc<CompressionDictionary>>,
+    ) -> Self {
+        self.compression_dictionary = dictionary;
+        self
+    }
+
+    /// 设置连接出口失败(连接被拒绝等可重试的错误)时的重试次数和间隔(毫秒), 只对TCP类入口生效, 为0时不重试
+    ///
+    /// 重试期间保持客户端连接, 暂停读取客户端数据, 全部失败后才关闭会话
+    pub fn set_connect_retry(mut self, retries: u32, delay_millis: u64) -> Self {
+        self.connect_retries = retries;
+        self.connect_retry_delay = Duration::from_millis(delay_millis);
+        self
+    }
+
+    /// 设置每秒接受的新连接数和允许的突发连接数, 超出的连接直接关闭, 不影响已建立的会话
+    ///
+    /// `rate` 为0时不限制, `burst` 为0时与 `rate` 相同
+    pub fn set_conn_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.conn_rate_limit = rate;
+        self.conn_rate_burst = burst;
+        self
+    }
+
+    /// 设置熔断器, 后端连接连续失败时直接拒绝新会话
+    pub fn set_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = config;
+        self
+    }
+
+    /// 设置熔断状态变化回调
+    pub fn set_on_breaker_change(mut self, on_change: BreakerCallback) -> Self {
+        self.on_breaker_change = Some(on_change);
+        self
+    }
+
+    /// 设置会话开始和结束的回调
+    pub fn set_on_session_event(mut self, on_event: SessionEventCallback) -> Self {
+        self.on_session_event = Some(on_event);
+        self
+    }
+
+    /// 设置地理位置数据库, 会话开始时标记客户端地址的国家和ASN
+    pub fn set_geo_database(mut self, geo_database: Option<Arc<GeoDatabase>>) -> Self {
+        self.geo_database = geo_database;
+        self
+    }
+
+    /// 设置主机名到后端地址的映射, 用于按主机名选择后端的入口
+    pub fn set_host_routes(mut self, host_routes: HashMap<String, String>) -> Self {
+        self.host_routes = host_routes;
+        self
+    }
+
+    /// 设置单次提取的最大帧大小, 为0时使用默认值
+    pub fn set_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = if max_frame_size == 0 {
+            DEFAULT_MAX_FRAME_SIZE
+        } else {
+            max_frame_size
+        };
+        self
+    }
+
+    /// 设置发往出口的数据分块大小, 超过该大小的帧拆分为多个 `I2oSendData` 分别编码发送, 为0时不分块
+    ///
+    /// 大帧不必整体压缩加密, 分块之间也会等待读缓存回落. 只对字节流的入口生效, UDP和DNS入口不分块
+    pub fn set_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// 设置延迟探测的间隔(秒), 为0时不探测
+    ///
+    /// 探测消息不属于任何会话, 出口直接回复, 不转发给后端, 结果见 [`Inlet::rtt_stats`]
+    pub fn set_probe_interval(mut self, secs: u64) -> Self {
+        self.probe_interval = Duration::from_secs(secs);
+        self
+    }
+
+    /// 设置每个会话等待写给客户端的字节数上限, 为0时不限制
+    ///
+    /// 客户端不读取时出口发来的数据会一直排队, 超过上限后关闭会话, 关闭原因为 `overflow`
+    pub fn set_write_queue_limit(mut self, write_queue_limit: usize) -> Self {
+        self.write_queue_limit = write_queue_limit;
+        self
     }
 }
 
 impl Inlet {
-    pub fn new(on_output_callback: OutputFuncType, description: String) -> Self {
+    pub fn new(transport: Arc<dyn MessageTransport>, description: String) -> Self {
         Self {
             is_running: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            over_quota: Arc::new(AtomicBool::new(false)),
+            unix_socket_path: None,
+            local_addr: None,
             session_info_map: Arc::new(RwLock::new(HashMap::new())),
+            close_counter: Arc::new(SessionCloseCounter::default()),
+            traffic_counter: Arc::new(TrafficCounter::new()),
+            rtt: Arc::new(RttRecorder::new()),
+            breaker: Arc::new(CircuitBreaker::new(CircuitBreakerConfig::default(), None)),
+            generation: Arc::new(AtomicU32::new(rand::random())),
+            data_ex: Arc::new(std::sync::RwLock::new(Arc::new(InletDataEx::new(
+                String::new(),
+                String::new(),
+            )))),
+            rate_limiter: Arc::new(RateLimiter::new(0, 0)),
             input: None,
+            recorder: None,
+            redacted_description: description.clone(),
             description,
-            on_output_callback,
+            settings_description: String::new(),
+            transport,
+            tls_server_config: None,
         }
     }
 
+    /// 设置写入日志的描述, 未设置时与完整描述相同
+    pub fn set_redacted_description(mut self, redacted_description: String) -> Self {
+        self.redacted_description = redacted_description;
+        self
+    }
+
+    /// 设置可以在运行中应用的设置的描述, 与 [`Inlet::reconfigure`] 配合判断是否需要重新应用
+    pub fn set_settings_description(mut self, settings_description: String) -> Self {
+        self.settings_description = settings_description;
+        self
+    }
+
     pub async fn start(
         &mut self,
         inlet_proxy_type: InletProxyType,
@@ -119,6 +965,79 @@ impl Inlet {
         let output_tx_cloned = output_tx.clone();
         let inlet_proxy_type_cloned = inlet_proxy_type.clone();
         let data_ex = Arc::new(data_ex);
+        *self.data_ex.write().unwrap() = data_ex.clone();
+        let shared_data_ex = self.data_ex.clone();
+        let reap_data_ex = self.data_ex.clone();
+        let ipv6_only = data_ex.ipv6_only;
+        let bind_device = data_ex.bind_device.clone();
+        let listen_backlog = data_ex.listen_backlog;
+        let nodelay = data_ex.nodelay;
+        let fast_open = data_ex.fast_open;
+        let tls_server_config = if data_ex.tls_cert.is_empty() {
+            None
+        } else {
+            if !matches!(
+                inlet_proxy_type,
+                InletProxyType::TCP | InletProxyType::HTTP | InletProxyType::SOCKS5
+            ) || common::unix_socket_path(&listen_addr).is_some()
+            {
+                return Err(anyhow!(
+                    "TLS termination only supports TCP, HTTP and SOCKS5 tunnels listening on a TCP address"
+                ));
+            }
+            let config =
+                ReloadableServerConfig::new(&data_ex.tls_cert, &data_ex.tls_key, &data_ex.tls_alpn)
+                    .map_err(|err| anyhow!("failed to load inlet TLS certificate: {err}"))?;
+            Some(Arc::new(config))
+        };
+        self.tls_server_config = tls_server_config.clone();
+        let paused = self.paused.clone();
+        let over_quota = self.over_quota.clone();
+        let close_counter = self.close_counter.clone();
+        let traffic_counter = self.traffic_counter.clone();
+        self.breaker = Arc::new(CircuitBreaker::new(
+            data_ex.circuit_breaker.clone(),
+            data_ex.on_breaker_change.clone(),
+        ));
+        let breaker = self.breaker.clone();
+        let input_breaker = self.breaker.clone();
+        // 加密方法可以是偏好列表, 每个新会话按出口的支持情况选择
+        let methods = Arc::new(MethodPreference::new(
+            &encryption_method,
+            data_ex.encryption_fallback,
+        ));
+        let input_methods = methods.clone();
+        self.rate_limiter
+            .set_rate(data_ex.conn_rate_limit, data_ex.conn_rate_burst);
+        let rate_limiter = self.rate_limiter.clone();
+        let input_is_dns = inlet_proxy_type.is_dns();
+        let generation = self.generation.clone();
+        // 能否splice还取决于运行中可以修改的设置, 每个新连接单独判断
+        let splice_session = if splice::is_supported()
+            && matches!(inlet_proxy_type, InletProxyType::TCP)
+            && !is_compressed
+            && matches!(
+                crypto::get_method(&methods.select()),
+                EncryptionMethod::None
+            )
+            && tls_server_config.is_none()
+            && data_ex.splice_outlet.is_some()
+        {
+            Some(Arc::new(SpliceSession {
+                output_addr: output_addr.clone(),
+                data_ex: self.data_ex.clone(),
+                session_info_map: self.session_info_map.clone(),
+                generation: self.generation.clone(),
+                paused: self.paused.clone(),
+                over_quota: self.over_quota.clone(),
+                breaker: self.breaker.clone(),
+                rate_limiter: self.rate_limiter.clone(),
+                close_counter: self.close_counter.clone(),
+                traffic_counter: self.traffic_counter.clone(),
+            }))
+        } else {
+            None
+        };
 
         let create_session_delegate_func = Box::new(move || -> Box<dyn SessionDelegate> {
             Box::new(InletSession::new(
@@ -126,55 +1045,169 @@ impl Inlet {
                 output_addr.clone(),
                 session_info_map.clone(),
                 is_compressed,
-                encryption_method.clone(),
+                methods.select(),
                 output_tx.clone(),
-                data_ex.clone(),
+                shared_data_ex.read().unwrap().clone(),
+                paused.clone(),
+                over_quota.clone(),
+                close_counter.clone(),
+                traffic_counter.clone(),
+                breaker.clone(),
+                rate_limiter.clone(),
+                generation.clone(),
             ))
         });
 
-        let on_output_callback = self.on_output_callback.clone();
+        self.recorder = data_ex.recorder.clone();
+        let transport: Arc<dyn MessageTransport> = match data_ex.recorder.clone() {
+            Some(recorder) => RecordingTransport::new(self.transport.clone(), recorder),
+            None => self.transport.clone(),
+        };
         let session_info_map = self.session_info_map.clone();
+        let reap_session_info_map = self.session_info_map.clone();
+        // 探测不经过录制
+        let probe_task = Self::async_probe(
+            self.data_ex.clone(),
+            self.transport.clone(),
+            self.rtt.clone(),
+        );
         let is_running = self.is_running.clone();
         is_running.store(true, Ordering::Relaxed);
 
+        if let Some(path) = common::unix_socket_path(&listen_addr) {
+            if !inlet_proxy_type_cloned.is_stream() {
+                is_running.store(false, Ordering::Relaxed);
+                return Err(anyhow!("unix socket only supports stream tunnels"));
+            }
+            if bind_device.is_some() {
+                is_running.store(false, Ordering::Relaxed);
+                return Err(anyhow!(
+                    "unix socket cannot be bound to a network interface"
+                ));
+            }
+            let listener = match Self::bind_unix(path).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    is_running.store(false, Ordering::Relaxed);
+                    return Err(err);
+                }
+            };
+            self.unix_socket_path = Some(path.to_owned());
+
+            #[cfg(unix)]
+            tokio::spawn(async move {
+                let server_task = crate::net::unix_server::run_server(
+                    listener,
+                    create_session_delegate_func,
+                    Self::async_receive_input(
+                        input_rx,
+                        output_tx_cloned,
+                        session_info_map,
+                        input_breaker,
+                        input_methods,
+                        input_is_dns,
+                    ),
+                );
+
+                select! {
+                    _= server_task => {},
+                    _= common::async_receive_output(output_rx, transport) => {},
+                    _= Self::async_reap_sessions(reap_session_info_map, reap_data_ex, true) => {}
+                    _= probe_task => {}
+                }
+
+                is_running.store(false, Ordering::Relaxed);
+            });
+            return Ok(());
+        }
+
         match inlet_proxy_type_cloned {
-            InletProxyType::TCP | InletProxyType::SOCKS5 => {
-                let listener = TcpListener::bind(&listen_addr).await?;
+            InletProxyType::TCP
+            | InletProxyType::SOCKS5
+            | InletProxyType::HTTPS
+            | InletProxyType::HTTP
+            | InletProxyType::DNS
+            | InletProxyType::LABEL => {
+                let listener = tcp_server::bind(
+                    &listen_addr,
+                    ipv6_only,
+                    bind_device.as_deref(),
+                    listen_backlog,
+                    fast_open,
+                )
+                .await?;
+                self.local_addr = listener.local_addr().ok();
 
                 tokio::spawn(async move {
-                    let server_task = tcp_server::Builder::new(create_session_delegate_func)
-                        .set_on_steam_init_callback(Arc::new(|stream: TcpStream| {
+                    let mut builder = tcp_server::Builder::new(create_session_delegate_func)
+                        .set_fast_open(fast_open)
+                        .set_on_steam_init_callback(Arc::new(move |stream: TcpStream| {
                             Box::pin(async move {
-                                stream.set_nodelay(true)?;
+                                stream.set_nodelay(nodelay)?;
                                 Ok(stream)
                             })
-                        }))
-                        .build_with_listener(
-                            listener,
-                            Self::async_receive_input(input_rx, output_tx_cloned, session_info_map),
-                        );
+                        }));
+                    if let Some(ref tls_server_config) = tls_server_config {
+                        builder =
+                            builder.set_reloadable_tls_server_config(tls_server_config.clone());
+                    }
+                    if let Some(splice_session) = splice_session {
+                        builder = builder.set_stream_takeover_callback(Arc::new(
+                            move |session_id, addr, stream, shutdown| {
+                                let splice_session = splice_session.clone();
+                                Box::pin(async move {
+                                    splice_session.run(session_id, addr, stream, shutdown).await
+                                })
+                            },
+                        ));
+                    }
+                    let server_task = builder.build_with_listener(
+                        listener,
+                        Self::async_receive_input(
+                            input_rx,
+                            output_tx_cloned,
+                            session_info_map,
+                            input_breaker,
+                            input_methods,
+                            input_is_dns,
+                        ),
+                    );
 
                     select! {
                         _= server_task => {},
-                        _= common::async_receive_output(output_rx, on_output_callback) => {}
+                        _= common::async_receive_output(output_rx, transport) => {},
+                    _= Self::async_reap_sessions(reap_session_info_map, reap_data_ex, true) => {}
+                        _= Self::async_watch_tls(tls_server_config) => {}
+                        _= probe_task => {}
                     }
 
                     is_running.store(false, Ordering::Relaxed);
                 });
             }
             InletProxyType::UDP => {
-                let socket = UdpSocket::bind(&listen_addr).await?;
+                let socket =
+                    udp_server::bind(&listen_addr, ipv6_only, bind_device.as_deref()).await?;
+                self.local_addr = socket.local_addr().ok();
 
                 tokio::spawn(async move {
                     let server_task = udp_server::run_server(
                         socket,
                         create_session_delegate_func,
-                        Self::async_receive_input(input_rx, output_tx_cloned, session_info_map),
+                        Self::async_receive_input(
+                            input_rx,
+                            output_tx_cloned,
+                            session_info_map,
+                            input_breaker,
+                            input_methods,
+                            input_is_dns,
+                        ),
                     );
 
                     select! {
                         _= server_task => {},
-                        _= common::async_receive_output(output_rx, on_output_callback) => {}
+                        _= common::async_receive_output(output_rx, transport) => {},
+                    _= Self::async_reap_sessions(reap_session_info_map, reap_data_ex, false) => {}
+                        _= probe_task => {}
                     }
 
                     is_running.store(false, Ordering::Relaxed);
@@ -185,35 +1218,291 @@ impl Inlet {
         Ok(())
     }
 
+    #[cfg(unix)]
+    async fn bind_unix(path: &str) -> anyhow::Result<tokio::net::UnixListener> {
+        common::bind_unix_listener(path)
+    }
+
+    #[cfg(not(unix))]
+    async fn bind_unix(_path: &str) -> anyhow::Result<()> {
+        Err(anyhow!("unix socket is not supported on this platform"))
+    }
+
     pub async fn input(&self, proxy_message: ProxyMessage) {
+        // 探测回复不属于任何会话, 直接记录延迟
+        if let ProxyMessage::O2iProbe(ticks) = proxy_message {
+            self.rtt.record(ticks);
+            return;
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::Input, &proxy_message);
+        }
         if let Some(sender) = &self.input {
             let _ = sender.send(proxy_message);
         }
     }
 
+    /// 立即重新加载TLS证书, 例如收到证书续期的通知时, 新证书无效时继续使用原来的证书
+    ///
+    /// 入口没有终止TLS时返回 `Ok(false)`
+    pub fn reload_tls(&self) -> anyhow::Result<bool> {
+        match self.tls_server_config {
+            Some(ref config) => config.reload().map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// 定时检查证书文件, 变化时重新加载
+    async fn async_watch_tls(config: Option<Arc<ReloadableServerConfig>>) {
+        match config {
+            Some(config) => config.watch(TLS_RELOAD_CHECK_INTERVAL).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// 暂停/恢复接受新连接, 已有会话不受影响
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// 设置通道流量是否超过配额, 超过时不接受新连接, 已有会话不受影响
+    pub fn set_over_quota(&self, over_quota: bool) {
+        self.over_quota.store(over_quota, Ordering::Relaxed);
+    }
+
     pub fn running(&self) -> bool {
         self.is_running.load(Ordering::Relaxed)
     }
 
     pub async fn stop(&mut self) {
+        // 还没有结束的会话都是因入口停止而关闭
+        for session in self.session_info_map.write().await.values_mut() {
+            session
+                .close_reason
+                .get_or_insert(SessionCloseReason::Shutdown);
+        }
         self.input.take();
+        self.tls_server_config.take();
         while self.running() {
             yield_now().await;
         }
         self.session_info_map.write().await.clear();
+        self.local_addr = None;
+        if let Some(path) = self.unix_socket_path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// 优雅停止: 不再接受新连接, 等待已有会话结束(最多等待timeout)后停止
+    pub async fn stop_graceful(&mut self, timeout: Duration) {
+        self.set_paused(true);
+        let condition = async {
+            while !self.session_info_map.read().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        if tokio::time::timeout(timeout, condition).await.is_err() {
+            warn!(
+                "inlet({}) drain timeout, {} session(s) will be closed",
+                self.redacted_description,
+                self.session_info_map.read().await.len()
+            );
+        }
+        self.stop().await;
     }
 
+    /// 完整描述, 用于判断配置是否变化
     pub fn description(&self) -> &String {
         &self.description
     }
 
+    pub fn settings_description(&self) -> &String {
+        &self.settings_description
+    }
+
+    /// 运行中应用新的设置, 不重新监听也不断开已有会话
+    ///
+    /// 连接速率限制立即生效, 最大存活时间和读写超时由回收任务应用到已有会话,
+    /// 其余设置对之后的新会话生效; 监听相关的设置(地址、网卡、TLS证书等)需要重启入口
+    pub fn reconfigure(&mut self, settings_description: String, data_ex: InletDataEx) {
+        self.rate_limiter
+            .set_rate(data_ex.conn_rate_limit, data_ex.conn_rate_burst);
+        *self.data_ex.write().unwrap() = Arc::new(data_ex);
+        self.settings_description = settings_description;
+    }
+
+    pub fn redacted_description(&self) -> &String {
+        &self.redacted_description
+    }
+
+    /// 实际监听的地址, 监听端口为0时可以从这里取得系统分配的端口. 未启动或监听unix套接字时返回None
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// 按关闭原因统计的会话数量
+    pub fn close_stats(&self) -> SessionCloseStats {
+        self.close_counter.snapshot()
+    }
+
+    /// 入口创建或上次重置以来的累计流量
+    pub fn stats_snapshot(&self) -> TrafficStats {
+        self.traffic_counter.snapshot()
+    }
+
+    /// 重置累计流量, 返回重置前的统计, 用于按周期计费
+    pub fn reset_stats(&self) -> TrafficStats {
+        self.traffic_counter.reset()
+    }
+
+    /// 最近的延迟探测结果, 没有开启探测或还没有收到回复时样本数为0
+    pub fn rtt_stats(&self) -> RttStats {
+        self.rtt.snapshot()
+    }
+
+    /// 熔断器状态
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.state()
+    }
+
+    /// 所有会话的概要
+    pub async fn list_sessions(&self) -> Vec<SessionSummary> {
+        self.session_info_map
+            .read()
+            .await
+            .iter()
+            .map(|(session_id, session)| SessionSummary {
+                session_id: *session_id,
+                peer_addr: session.peer_addr,
+                country: session
+                    .geo
+                    .as_ref()
+                    .map_or(UNKNOWN_COUNTRY, |x| x.country.as_str())
+                    .to_string(),
+                bytes_in: session.activity.bytes_in.load(Ordering::Relaxed),
+                bytes_out: session.activity.bytes_out.load(Ordering::Relaxed),
+                age: session.activity.start_time.elapsed(),
+                backend_addr: session.backend_addr.clone(),
+            })
+            .collect()
+    }
+
+    /// 强制关闭会话, 会话不存在时什么也不做
+    pub async fn kill_session(&self, session_id: u32) {
+        if let Some(session) = self.session_info_map.write().await.remove(&session_id) {
+            trace!("inlet session({session_id}) killed");
+            self.close_counter.record(SessionCloseReason::Killed);
+            let _ = session.write_msg_tx.send(WriterMessage::Close);
+        }
+    }
+
+    /// 所有会话的背压统计
+    pub async fn backpressure_stats(&self) -> BackpressureStats {
+        let mut sessions = Vec::new();
+        for (session_id, session) in self.session_info_map.read().await.iter() {
+            sessions.push(stats::session_backpressure(*session_id, &session.common_info).await);
+        }
+        BackpressureStats::from_sessions(sessions)
+    }
+
+    /// 定期关闭超过最大存活时间或读写停滞的会话
+    ///
+    /// UDP会话有单独的空闲超时, 不检查读写停滞
+    async fn async_reap_sessions(
+        session_info_map: SessionInfoMap,
+        data_ex: SharedDataEx,
+        is_stream: bool,
+    ) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            // 每次取当前设置, 运行中修改后对已有会话生效
+            let (max_session_lifetime, read_timeout, write_timeout) = {
+                let data_ex = data_ex.read().unwrap();
+                if is_stream {
+                    (
+                        data_ex.max_session_lifetime,
+                        data_ex.read_timeout,
+                        data_ex.write_timeout,
+                    )
+                } else {
+                    (data_ex.max_session_lifetime, Duration::ZERO, Duration::ZERO)
+                }
+            };
+            if max_session_lifetime.is_zero() && read_timeout.is_zero() && write_timeout.is_zero() {
+                continue;
+            }
+            for (session_id, session) in session_info_map.write().await.iter_mut() {
+                if session.close_reason.is_some() {
+                    continue;
+                }
+
+                let activity = &session.activity;
+                let idle_time = activity.idle_time();
+                let reason = if !max_session_lifetime.is_zero()
+                    && activity.start_time.elapsed() >= max_session_lifetime
+                {
+                    trace!("inlet session({session_id}) exceeded max lifetime, closing");
+                    SessionCloseReason::Lifetime
+                } else if !read_timeout.is_zero() && idle_time >= read_timeout {
+                    trace!("inlet session({session_id}) read timeout, closing");
+                    SessionCloseReason::Idle
+                } else if !write_timeout.is_zero()
+                    && idle_time >= write_timeout
+                    && activity.pending_writes.load(Ordering::Relaxed) > 0
+                {
+                    trace!("inlet session({session_id}) write timeout, closing");
+                    SessionCloseReason::Idle
+                } else {
+                    continue;
+                };
+
+                session.close_reason = Some(reason);
+                let _ = session.write_msg_tx.send(WriterMessage::Close);
+            }
+        }
+    }
+
+    /// 按设置的间隔向出口发送延迟探测, 每次取当前设置, 运行中修改后生效
+    async fn async_probe(
+        data_ex: SharedDataEx,
+        transport: Arc<dyn MessageTransport>,
+        rtt: Arc<RttRecorder>,
+    ) {
+        loop {
+            let probe_interval = data_ex.read().unwrap().probe_interval;
+            if probe_interval.is_zero() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            tokio::time::sleep(probe_interval).await;
+            transport.send(ProxyMessage::I2oProbe(rtt.ticks())).await;
+        }
+    }
+
     async fn async_receive_input(
         mut input: UnboundedReceiver<ProxyMessage>,
         output: Sender<ProxyMessage>,
         session_info_map: SessionInfoMap,
+        breaker: Arc<CircuitBreaker>,
+        methods: Arc<MethodPreference>,
+        is_dns: bool,
     ) {
         while let Some(message) = input.recv().await {
-            if let Err(err) = Self::input_internal(message, &output, &session_info_map).await {
+            if let Err(err) = Self::input_internal(
+                message,
+                &output,
+                &session_info_map,
+                &breaker,
+                &methods,
+                is_dns,
+            )
+            .await
+            {
                 error!("inlet async_receive_input error: {}", err.to_string());
             }
         }
@@ -223,17 +1512,95 @@ impl Inlet {
         message: ProxyMessage,
         output: &Sender<ProxyMessage>,
         session_info_map: &SessionInfoMap,
+        breaker: &CircuitBreaker,
+        methods: &MethodPreference,
+        is_dns: bool,
     ) -> anyhow::Result<()> {
         match message {
-            ProxyMessage::O2iConnect(session_id, success, error_msg) => {
+            ProxyMessage::O2iConnect(
+                session_id,
+                generation,
+                mut success,
+                mut error_msg,
+                independent_codec,
+                backend_addr,
+                outlet_id,
+            ) => {
                 trace!(
                     "O2iConnect: session_id:{session_id}, success:{success}, error_msg:{error_msg}"
                 );
 
-                if let Some(session) = session_info_map.read().await.get(&session_id) {
+                if let Some(session) = session_info_map
+                    .write()
+                    .await
+                    .get_mut(&session_id)
+                    .filter(|x| x.accepts(generation))
+                {
+                    // 出口推迟握手(TCP Fast Open)时, 成功的应答之后仍可能报告后端连接失败.
+                    // 客户端的数据可能已经发出, 不再重试, 只记录失败并关闭会话
+                    if session.connected {
+                        if !success {
+                            debug!(
+                                "session({session_id}) connect error after connected: {error_msg}"
+                            );
+                            breaker.record(false);
+                            session.write_msg_tx.send(WriterMessage::Close)?;
+                        }
+                        return Ok(());
+                    }
+                    // 出口与入口对出口到入口方向的编码方式不一致, 无法解码数据
+                    if success && session.common_info.is_symmetric == independent_codec {
+                        success = false;
+                        error_msg = "outlet does not support per-direction codec".into();
+                    }
+                    session.connected = success;
+                    if let Some(ref mut retry) = session.connect_retry {
+                        if !success && retry.remaining > 0 && is_retryable_error(&error_msg) {
+                            retry.remaining -= 1;
+                            debug!(
+                                "connect error: {error_msg}, retry session({session_id}), {} retries left",
+                                retry.remaining
+                            );
+                            let output = output.clone();
+                            let message = retry.message.clone();
+                            let delay = retry.delay;
+                            let session_info_map = session_info_map.clone();
+                            tokio::spawn(async move {
+                                tokio::time::sleep(delay).await;
+                                // 等待期间客户端已断开
+                                if session_info_map.read().await.contains_key(&session_id) {
+                                    let _ = output.send(message).await;
+                                }
+                            });
+                            return Ok(());
+                        }
+                        let _ = retry.result_tx.send(Some(success));
+                    }
+                    if let Some(method) = error_msg.strip_prefix(crypto::UNSUPPORTED_METHOD) {
+                        if methods.record(outlet_id, method, true) {
+                            warn!(
+                                "outlet({outlet_id}) does not support encryption method {method}, new sessions use {}",
+                                methods.select()
+                            );
+                        }
+                    } else if success {
+                        let method = session.common_info.outbound.encryption_method.to_string();
+                        methods.record(outlet_id, &method, false);
+                    }
+                    breaker.record(success);
+                    if success && !backend_addr.is_empty() {
+                        session.backend_addr.clone_from(&backend_addr);
+                    }
                     if let Some(ref proxy_message_tx) = session.proxy_message_tx {
-                        proxy_message_tx
-                            .send(ProxyMessage::O2iConnect(session_id, success, error_msg))?;
+                        proxy_message_tx.send(ProxyMessage::O2iConnect(
+                            session_id,
+                            generation,
+                            success,
+                            error_msg,
+                            independent_codec,
+                            backend_addr,
+                            outlet_id,
+                        ))?;
                     } else {
                         if !success {
                             error!("connect error: {error_msg}");
@@ -242,30 +1609,39 @@ impl Inlet {
                     }
                 }
             }
-            ProxyMessage::O2iDisconnect(session_id) => {
+            ProxyMessage::O2iDisconnect(session_id, generation) => {
                 trace!("O2iDisconnect: session_id:{session_id}");
-                if let Some(session) = session_info_map.read().await.get(&session_id) {
+                if let Some(session) = session_info_map
+                    .read()
+                    .await
+                    .get(&session_id)
+                    .filter(|x| x.accepts(generation))
+                {
                     session.write_msg_tx.send(WriterMessage::Close)?;
                 }
             }
-            ProxyMessage::O2iSendDataResult(session_id, data_len) => {
+            ProxyMessage::O2iSendDataResult(session_id, generation, data_len) => {
                 // trace!("O2iSendDataResult: session_id:{session_id}, data_len:{data_len}");
-                if let Some(session) = session_info_map.read().await.get(&session_id) {
-                    let mut read_buf_len = session.common_info.read_buf_len.write().await;
-                    if *read_buf_len <= data_len {
-                        *read_buf_len = 0;
-                    } else {
-                        *read_buf_len = *read_buf_len - data_len;
-                    }
-                    // trace!("O2iSendDataResult: session_id:{session_id}, data_len:{data_len}, read_buf_len:{}", *read_buf_len);
-                    drop(read_buf_len);
+                if let Some(session) = session_info_map
+                    .read()
+                    .await
+                    .get(&session_id)
+                    .filter(|x| x.accepts(generation))
+                {
+                    session.common_info.release_read_buf(data_len).await;
                 }
             }
-            ProxyMessage::O2iRecvDataFrom(session_id, data, remote_addr) => {
-                if let Some(session) = session_info_map.read().await.get(&session_id) {
+            ProxyMessage::O2iRecvDataFrom(session_id, generation, data, remote_addr) => {
+                if let Some(session) = session_info_map
+                    .read()
+                    .await
+                    .get(&session_id)
+                    .filter(|x| x.accepts(generation))
+                {
                     if let Some(ref proxy_message_tx) = session.proxy_message_tx {
                         proxy_message_tx.send(ProxyMessage::O2iRecvDataFrom(
                             session_id,
+                            generation,
                             data,
                             remote_addr,
                         ))?;
@@ -274,32 +1650,87 @@ impl Inlet {
                     }
                 }
             }
-            ProxyMessage::O2iRecvData(session_id, mut data) => {
+            ProxyMessage::O2iRecvData(session_id, generation, mut data) => {
                 // trace!("O2iRecvData: session_id:{session_id}");
-                if let Some(session) = session_info_map.read().await.get(&session_id) {
-                    if let Some(ref proxy_message_tx) = session.proxy_message_tx {
-                        proxy_message_tx.send(ProxyMessage::O2iRecvData(session_id, data))?;
-                    } else {
-                        let data_len = data.len();
-                        data = session.common_info.decode_data(data)?;
+                let sessions = session_info_map.read().await;
+                let session = match sessions.get(&session_id) {
+                    Some(session) if session.accepts(generation) => session,
+                    Some(_) => {
+                        // 确认不带代数, 会话id已被新会话使用时不能确认, 否则会释放新会话的额度
+                        trace!("O2iRecvData: stale session:{session_id}");
+                        return Ok(());
+                    }
+                    None => {
+                        drop(sessions);
+                        // 本地已关闭的会话仍要确认, 出口在收到断开前不会因等待确认而停止读取
+                        trace!("O2iRecvData: unknown session:{session_id}");
+                        output
+                            .send(ProxyMessage::I2oRecvDataResult(session_id, data.len()))
+                            .await?;
+                        return Ok(());
+                    }
+                };
 
-                        // 写入完毕回调
-                        let output = output.clone();
-                        let callback: SendMessageFuncType = Box::new(move || {
-                            let output = output.clone();
-                            Box::pin(async move {
-                                let _ = output
-                                    .send(ProxyMessage::I2oRecvDataResult(session_id, data_len))
-                                    .await;
-                            })
-                        });
+                if let Some(ref proxy_message_tx) = session.proxy_message_tx {
+                    session.activity.touch();
+                    session.activity.add_bytes_out(data.len() as u64);
+                    proxy_message_tx
+                        .send(ProxyMessage::O2iRecvData(session_id, generation, data))?;
+                } else {
+                    let data_len = data.len();
+                    data = session.common_info.decode_data(data)?;
+                    if is_dns {
+                        // 出口返回的每个UDP包是一个完整的DNS回复
+                        data = dns::prefix_message(data)?;
+                    }
+                    // 出口返回的每块数据是一条完整的消息
+                    data = session.common_info.length_prefix.prefix(data)?;
+                    let write_len = data.len();
 
-                        session
-                            .write_msg_tx
-                            .send(WriterMessage::SendAndThen(data, callback))?;
+                    let limit = session.write_queue_limit;
+                    let queued = session.activity.pending_write_bytes.load(Ordering::Relaxed);
+                    if limit != 0 && queued + write_len > limit {
+                        drop(sessions);
+                        debug!("inlet session({session_id}) write queue exceeded {limit} bytes, closing");
+                        let mut sessions = session_info_map.write().await;
+                        if let Some(session) = sessions
+                            .get_mut(&session_id)
+                            .filter(|x| x.accepts(generation))
+                        {
+                            session
+                                .close_reason
+                                .get_or_insert(SessionCloseReason::Overflow);
+                            // 写入已经挂起, 关闭消息排在队尾无法送达, 直接结束会话
+                            session.activity.abort.notify_one();
+                        }
+                        return Ok(());
                     }
-                } else {
-                    trace!("O2iRecvData: unknown session:{session_id}");
+
+                    // 写入完毕回调
+                    let output = output.clone();
+                    let activity = session.activity.clone();
+                    activity.pending_writes.fetch_add(1, Ordering::Relaxed);
+                    activity
+                        .pending_write_bytes
+                        .fetch_add(write_len, Ordering::Relaxed);
+                    let callback: SendMessageFuncType = Box::new(move || {
+                        let output = output.clone();
+                        activity.pending_writes.fetch_sub(1, Ordering::Relaxed);
+                        activity
+                            .pending_write_bytes
+                            .fetch_sub(write_len, Ordering::Relaxed);
+                        activity.add_bytes_out(write_len as u64);
+                        activity.touch();
+                        Box::pin(async move {
+                            let _ = output
+                                .send(ProxyMessage::I2oRecvDataResult(session_id, data_len))
+                                .await;
+                        })
+                    });
+
+                    session
+                        .write_msg_tx
+                        .send(session.common_info.write_message(data, callback))?;
                 }
             }
             _ => {
@@ -316,13 +1747,38 @@ struct InletSession {
     output_addr: String,
     session_info_map: SessionInfoMap,
     session_id: u32,
+    // 本会话的代数, 会话开始时分配
+    generation: u32,
+    generation_counter: Arc<AtomicU32>,
+    peer_addr: SocketAddr,
+    // 等待选择后端期间预读的数据
+    route_buffer: Option<Vec<u8>>,
+    // 等待PROXY协议头部期间暂存的写入通道
+    pending_start: Option<UnboundedSender<WriterMessage>>,
     output: Sender<ProxyMessage>,
     common_data: SessionCommonInfo,
     socks5context: Option<Arc<RwLock<Socks5Context>>>,
     data_ex: Arc<InletDataEx>,
+    paused: Arc<AtomicBool>,
+    over_quota: Arc<AtomicBool>,
+    close_counter: Arc<SessionCloseCounter>,
+    breaker: Arc<CircuitBreaker>,
+    rate_limiter: Arc<RateLimiter>,
+    activity: Arc<SessionActivity>,
+    // 已拒绝的会话在回复拒绝原因期间的写入通道, SOCKS5入口还需要完成握手
+    rejected: Option<(UnboundedSender<WriterMessage>, Option<Socks5Rejection>)>,
+    // 开启连接重试时等待连接结果, 连接成功前不转发客户端数据
+    connect_result: Option<watch::Receiver<Option<bool>>>,
+    // 入口终止TLS时与客户端协商出的ALPN协议
+    alpn_protocol: Option<String>,
+    // 客户端在SYN中携带了数据
+    fast_open: bool,
+    // 连接时带给出口的会话元数据
+    metadata: HashMap<String, String>,
 }
 
 impl InletSession {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         inlet_proxy_type: InletProxyType,
         output_addr: String,
@@ -331,83 +1787,376 @@ impl InletSession {
         encryption_method: String,
         output: Sender<ProxyMessage>,
         data_ex: Arc<InletDataEx>,
+        paused: Arc<AtomicBool>,
+        over_quota: Arc<AtomicBool>,
+        close_counter: Arc<SessionCloseCounter>,
+        traffic_counter: Arc<TrafficCounter>,
+        breaker: Arc<CircuitBreaker>,
+        rate_limiter: Arc<RateLimiter>,
+        generation_counter: Arc<AtomicU32>,
     ) -> Self {
+        // 只有TCP入口按长度前缀分帧
+        let length_prefix = if matches!(inlet_proxy_type, InletProxyType::TCP) {
+            data_ex.length_prefix
+        } else {
+            LengthPrefix::None
+        };
+        // 只拆分字节流, UDP和DNS的每个数据包必须完整转发
+        let chunk_size = if inlet_proxy_type.is_stream() && !inlet_proxy_type.is_dns() {
+            data_ex.chunk_size
+        } else {
+            0
+        };
         Self {
             inlet_proxy_type,
             output_addr,
             session_info_map,
             session_id: 0,
+            generation: 0,
+            generation_counter,
+            peer_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            route_buffer: None,
+            pending_start: None,
             output,
-            common_data: SessionCommonInfo::from_method_name(is_compressed, encryption_method),
+            common_data: {
+                let salt = match data_ex.key_derivation {
+                    Some(_) => crypto::generate_salt(),
+                    None => Vec::new(),
+                };
+                let i2o = data_ex
+                    .new_codec(is_compressed, &encryption_method, &salt, I2O_KEY_INFO)
+                    .set_dictionary(data_ex.compression_dictionary.clone());
+                let o2i = data_ex.o2i_codec(&i2o, &salt);
+                SessionCommonInfo::new(i2o, o2i)
+                    .set_write_coalesce(data_ex.write_coalesce)
+                    .set_chunk_size(chunk_size)
+                    .set_length_prefix(length_prefix)
+            },
             socks5context: None,
+            metadata: data_ex.metadata.clone(),
             data_ex,
+            paused,
+            over_quota,
+            close_counter,
+            breaker,
+            rate_limiter,
+            activity: Arc::new(SessionActivity::new(traffic_counter)),
+            rejected: None,
+            connect_result: None,
+            alpn_protocol: None,
+            fast_open: false,
         }
     }
 }
 
-#[async_trait]
-impl SessionDelegate for InletSession {
-    async fn on_session_start(
+impl InletSession {
+    /// 检查是否接受新会话, 返回拒绝的原因
+    fn check_reject(&self) -> Option<RejectReason> {
+        check_reject(
+            &self.over_quota,
+            &self.paused,
+            &self.breaker,
+            &self.rate_limiter,
+            &self.close_counter,
+        )
+    }
+
+    /// 按配置向客户端回复拒绝原因后断开, 期间丢弃客户端发来的数据, TCP等入口直接断开
+    fn reject(
         &mut self,
-        session_id: u32,
-        addr: &SocketAddr,
+        reason: RejectReason,
         write_msg_tx: UnboundedSender<WriterMessage>,
     ) -> anyhow::Result<()> {
-        trace!("inlet on session({session_id}) start {addr}");
+        if !self.data_ex.reject_response.is_enabled()
+            || !(self.inlet_proxy_type.is_http() || self.inlet_proxy_type.is_socks5())
+        {
+            return Err(anyhow!(
+                "inlet rejected new session from {}: {reason}",
+                self.peer_addr
+            ));
+        }
+        debug!(
+            "inlet rejected new session from {}: {reason}",
+            self.peer_addr
+        );
+        let socks5_rejection = if self.inlet_proxy_type.is_http() {
+            write_msg_tx.send(WriterMessage::Send(
+                self.data_ex.reject_response.http_response(reason),
+                true,
+            ))?;
+            write_msg_tx.send(WriterMessage::CloseDelayed(REJECT_CLOSE_DELAY))?;
+            None
+        } else {
+            // 客户端一直不完成握手时也要断开
+            let write_msg_tx = write_msg_tx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(SOCKS5_REJECT_TIMEOUT).await;
+                let _ = write_msg_tx.send(WriterMessage::Close);
+            });
+            Some(Socks5Rejection::new(reason))
+        };
+        self.rejected = Some((write_msg_tx, socks5_rejection));
+        Ok(())
+    }
 
-        self.session_id = session_id;
+    /// 已拒绝的会话收到数据, SOCKS5入口继续握手, 回复应答码后断开
+    fn on_rejected_data(&mut self, buffer: &mut BytesMut) -> anyhow::Result<()> {
+        let Some((write_msg_tx, socks5_rejection)) = self.rejected.as_mut() else {
+            return Ok(());
+        };
+        let Some(socks5_rejection) = socks5_rejection else {
+            buffer.clear();
+            return Ok(());
+        };
+        while !socks5_rejection.is_done() {
+            match socks5_rejection.on_data(buffer)? {
+                Some(reply) => write_msg_tx.send(WriterMessage::Send(reply, true))?,
+                None => return Ok(()),
+            }
+        }
+        buffer.clear();
+        write_msg_tx.send(WriterMessage::CloseDelayed(REJECT_CLOSE_DELAY))?;
+        Ok(())
+    }
+
+    /// 拒绝无法路由的会话, HTTP入口回复404
+    async fn reject_unrouted(&self) -> anyhow::Result<()> {
+        trace!("inlet session({}) no route matched", self.session_id);
+        if let Some(session) = self.session_info_map.read().await.get(&self.session_id) {
+            if self.inlet_proxy_type.is_http() {
+                let body = "no route for this host\n";
+                let response = format!(
+                    "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                session
+                    .write_msg_tx
+                    .send(WriterMessage::Send(response.into_bytes(), true))?;
+            }
+            session
+                .write_msg_tx
+                .send(WriterMessage::CloseDelayed(Duration::from_secs(1)))?;
+        }
+        Ok(())
+    }
+
+    /// 登记会话并向出口发起连接
+    async fn start_session(
+        &mut self,
+        write_msg_tx: UnboundedSender<WriterMessage>,
+    ) -> anyhow::Result<()> {
+        let geo = self
+            .data_ex
+            .geo_database
+            .as_ref()
+            .and_then(|x| x.lookup(self.peer_addr.ip()))
+            .cloned();
+        debug!(
+            "inlet session({}) start from {} [{}]{}",
+            self.session_id,
+            self.peer_addr,
+            geo.as_ref()
+                .map_or(UNKNOWN_COUNTRY.to_string(), |x| x.tag()),
+            if self.fast_open {
+                " (TCP fast open)"
+            } else {
+                ""
+            }
+        );
+        if let Some(ref geo) = geo {
+            self.metadata.insert("country".into(), geo.country.clone());
+        }
 
         if self.inlet_proxy_type.is_socks5() {
             let (socks5context, proxy_message_tx) = Socks5Context::new(
                 write_msg_tx.clone(),
                 self.output.clone(),
                 self.session_id,
-                addr.clone(),
+                self.generation,
+                self.peer_addr,
                 self.data_ex.clone(),
                 self.common_data.clone(),
+                self.metadata.clone(),
             )
             .await;
 
             self.socks5context = Some(socks5context);
 
             self.session_info_map.write().await.insert(
-                session_id,
+                self.session_id,
                 SessionInfo {
+                    generation: self.generation,
                     proxy_message_tx: Some(proxy_message_tx),
                     write_msg_tx,
                     common_info: self.common_data.clone(),
+                    activity: self.activity.clone(),
+                    peer_addr: self.peer_addr,
+                    geo: geo.clone(),
+                    close_reason: None,
+                    connect_retry: None,
+                    connected: false,
+                    backend_addr: String::new(),
+                    write_queue_limit: self.data_ex.write_queue_limit,
                 },
             );
         } else {
             self.session_info_map.write().await.insert(
-                session_id,
+                self.session_id,
                 SessionInfo {
+                    generation: self.generation,
                     proxy_message_tx: None,
                     write_msg_tx,
                     common_info: self.common_data.clone(),
+                    activity: self.activity.clone(),
+                    peer_addr: self.peer_addr,
+                    geo,
+                    close_reason: None,
+                    connect_retry: None,
+                    connected: false,
+                    backend_addr: String::new(),
+                    write_queue_limit: self.data_ex.write_queue_limit,
                 },
             );
 
-            self.output
-                .send(ProxyMessage::I2oConnect(
-                    session_id,
-                    self.inlet_proxy_type.to_u8(),
-                    self.inlet_proxy_type.is_tcp(),
-                    self.common_data.is_compressed,
-                    self.output_addr.clone(),
-                    self.common_data.encryption_method.to_string(),
-                    BASE64_STANDARD.encode(&self.common_data.encryption_key),
-                    addr.to_string(),
-                ))
-                .await?;
+            if self.inlet_proxy_type.is_vhost() || self.inlet_proxy_type.is_label() {
+                // 收到主机名或标签后再发起连接
+                self.route_buffer = Some(Vec::new());
+            } else {
+                self.send_connect(self.output_addr.clone()).await?;
+            }
         }
 
+        if let Some(ref on_event) = self.data_ex.on_session_event {
+            on_event(SessionEvent::Open {
+                session_id: self.session_id,
+                peer_addr: self.peer_addr,
+            });
+        }
         Ok(())
     }
 
+    /// 向出口发起连接
+    async fn send_connect(&mut self, endpoint: String) -> anyhow::Result<()> {
+        // 按主机名或标签路由的入口对出口而言就是普通的TCP代理, DNS入口则是UDP代理
+        let tunnel_type = if self.inlet_proxy_type.is_vhost() || self.inlet_proxy_type.is_label() {
+            InletProxyType::TCP
+        } else if self.inlet_proxy_type.is_dns() {
+            InletProxyType::UDP
+        } else {
+            self.inlet_proxy_type.clone()
+        };
+        let (is_compressed, encryption_method, encryption_key) =
+            self.common_data.outbound.to_remote();
+        let o2i_codec = if self.common_data.is_symmetric {
+            None
+        } else {
+            Some(self.common_data.inbound.to_remote())
+        };
+        let message = ProxyMessage::I2oConnect {
+            session_id: self.session_id,
+            generation: self.generation,
+            tunnel_type: tunnel_type.to_u8(),
+            is_tcp: tunnel_type.is_tcp(),
+            is_compressed,
+            addr: endpoint,
+            encryption_method,
+            encryption_key,
+            client_addr: self.peer_addr.to_string(),
+            o2i_codec,
+            write_coalesce: self.common_data.write_coalesce.as_millis() as u32,
+            backend_tls: self.data_ex.backend_tls.map(|verify| {
+                let alpn = self.alpn_protocol.clone().unwrap_or_default();
+                (verify, alpn)
+            }),
+            compression_dictionary: self.common_data.outbound.dictionary_hash(),
+            metadata: self.metadata.clone(),
+            key_derivation: self.common_data.remote_derivation(),
+            compress_threshold: self.common_data.outbound.compress_threshold as u32,
+        };
+
+        if self.data_ex.connect_retries > 0 && tunnel_type.is_tcp() {
+            if let Some(session) = self
+                .session_info_map
+                .write()
+                .await
+                .get_mut(&self.session_id)
+            {
+                let (result_tx, result_rx) = watch::channel(None);
+                session.connect_retry = Some(ConnectRetry {
+                    message: message.clone(),
+                    remaining: self.data_ex.connect_retries,
+                    delay: self.data_ex.connect_retry_delay,
+                    result_tx,
+                });
+                self.connect_result = Some(result_rx);
+            }
+        }
+
+        self.output.send(message).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionDelegate for InletSession {
+    fn on_tls_handshake(&mut self, alpn_protocol: Option<&[u8]>) {
+        self.alpn_protocol = alpn_protocol.map(|x| String::from_utf8_lossy(x).to_string());
+    }
+
+    fn on_fast_open(&mut self) {
+        self.fast_open = true;
+    }
+
+    fn abort_signal(&self) -> Option<Arc<Notify>> {
+        Some(self.activity.abort.clone())
+    }
+
+    async fn on_session_start(
+        &mut self,
+        session_id: u32,
+        addr: &SocketAddr,
+        write_msg_tx: UnboundedSender<WriterMessage>,
+    ) -> anyhow::Result<()> {
+        trace!("inlet on session({session_id}) start {addr}");
+
+        self.peer_addr = common::normalize_addr(*addr);
+
+        if let Some(reason) = self.check_reject() {
+            return self.reject(reason, write_msg_tx);
+        }
+
+        self.session_id = session_id;
+        self.generation = next_generation(&self.generation_counter);
+
+        if self.data_ex.accept_proxy_protocol && self.inlet_proxy_type.is_stream() {
+            // 收到PROXY协议头部后再开始会话
+            self.pending_start = Some(write_msg_tx);
+            return Ok(());
+        }
+        self.start_session(write_msg_tx).await
+    }
+
     async fn on_session_close(&mut self) -> anyhow::Result<()> {
         trace!("inlet on session({}) close", self.session_id);
-        self.session_info_map.write().await.remove(&self.session_id);
+        // 被拒绝的会话没有登记, 也没有通知出口
+        if self.rejected.is_some() {
+            return Ok(());
+        }
+        if let Some(session) = self.session_info_map.write().await.remove(&self.session_id) {
+            // UDP会话只会因空闲超时而结束
+            let reason = session.close_reason.unwrap_or(match self.inlet_proxy_type {
+                InletProxyType::UDP => SessionCloseReason::Idle,
+                _ => SessionCloseReason::Peer,
+            });
+            self.close_counter.record(reason);
+            if let Some(ref on_event) = self.data_ex.on_session_event {
+                on_event(SessionEvent::Close {
+                    session_id: self.session_id,
+                    peer_addr: self.peer_addr,
+                    reason,
+                });
+            }
+        }
         self.output
             .send(ProxyMessage::I2oDisconnect(self.session_id))
             .await?;
@@ -417,16 +2166,437 @@ impl SessionDelegate for InletSession {
         Ok(())
     }
 
+    async fn on_try_extract_frame(
+        &mut self,
+        buffer: &mut BytesMut,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.rejected.is_some() {
+            self.on_rejected_data(buffer)?;
+            return Ok(None);
+        }
+
+        if self.pending_start.is_some() {
+            match parse_proxy_header(buffer) {
+                ProxyHeader::Incomplete => return Ok(None),
+                ProxyHeader::Invalid => {
+                    return Err(anyhow!(
+                        "inlet session({}) invalid PROXY protocol header from {}",
+                        self.session_id,
+                        self.peer_addr
+                    ));
+                }
+                ProxyHeader::Parsed(len, addr) => {
+                    buffer.advance(len);
+                    if let Some(addr) = addr {
+                        trace!(
+                            "inlet session({}) real client address {addr}",
+                            self.session_id
+                        );
+                        self.peer_addr = common::normalize_addr(addr);
+                    }
+                    if let Some(write_msg_tx) = self.pending_start.take() {
+                        self.start_session(write_msg_tx).await?;
+                    }
+                }
+            }
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+        }
+
+        // 每个DNS查询单独转发
+        if self.inlet_proxy_type.is_dns() {
+            return dns::extract_message(buffer);
+        }
+
+        // 分帧时每条消息单独转发
+        if !self.common_data.length_prefix.is_none() {
+            return self.common_data.length_prefix.extract(buffer);
+        }
+
+        // 限制单次提取的数据大小, 超出部分留待下次提取
+        let len = buffer.len().min(self.data_ex.max_frame_size);
+        Ok(Some(buffer.split_to(len).to_vec()))
+    }
+
     async fn on_recv_frame(&mut self, mut frame: Vec<u8>) -> anyhow::Result<()> {
+        self.activity.touch();
+        self.activity.add_bytes_in(frame.len() as u64);
+
         if let Some(ref context) = self.socks5context {
             context.write().await.recv_frame(frame).await?;
             return Ok(());
         }
 
-        frame = self.common_data.encode_data_and_limiting(frame).await?;
-        self.output
-            .send(ProxyMessage::I2oSendData(self.session_id, frame))
-            .await?;
+        // 按标签路由时, 读到标签行后发起连接
+        if self.inlet_proxy_type.is_label() {
+            if let Some(mut buffer) = self.route_buffer.take() {
+                buffer.extend(frame);
+                match vhost::peek_label(&buffer) {
+                    LabelLine::Incomplete => {
+                        self.route_buffer = Some(buffer);
+                        return Ok(());
+                    }
+                    LabelLine::Label(label, len) => {
+                        // 标签行不转发给后端, 由出口按标签选择后端
+                        buffer.drain(..len);
+                        if !label.is_empty() {
+                            self.metadata.insert("label".into(), label);
+                        }
+                    }
+                    LabelLine::NoLabel => {}
+                }
+                self.send_connect(self.output_addr.clone()).await?;
+                // 只收到了标签行
+                if buffer.is_empty() {
+                    return Ok(());
+                }
+                frame = buffer;
+            }
+        }
+
+        if let Some(mut buffer) = self.route_buffer.take() {
+            buffer.extend(frame);
+            let result = if self.inlet_proxy_type.is_http() {
+                vhost::peek_http_host(&buffer)
+            } else {
+                vhost::peek_tls_sni(&buffer)
+            };
+            let endpoint = match result {
+                PeekResult::Incomplete if buffer.len() < vhost::PEEK_MAX_LEN => {
+                    self.route_buffer = Some(buffer);
+                    return Ok(());
+                }
+                PeekResult::Host(host) => {
+                    let endpoint = vhost::match_route(&self.data_ex.host_routes, &host)
+                        .unwrap_or(&self.output_addr)
+                        .clone();
+                    let key = if self.inlet_proxy_type.is_http() {
+                        "host"
+                    } else {
+                        "sni"
+                    };
+                    self.metadata.insert(key.into(), host);
+                    endpoint
+                }
+                _ => self.output_addr.clone(),
+            };
+            // 没有匹配的后端也没有默认后端
+            if endpoint.is_empty() {
+                return self.reject_unrouted().await;
+            }
+            trace!("inlet session({}) route to {endpoint}", self.session_id);
+            self.send_connect(endpoint).await?;
+            // 预读的数据原样作为第一个数据帧转发
+            frame = buffer;
+        }
+
+        if let Some(mut connect_result) = self.connect_result.take() {
+            // 连接成功前暂停读取客户端数据, 避免重试期间数据被出口丢弃
+            let connected = *connect_result.wait_for(|x| x.is_some()).await?;
+            if connected != Some(true) {
+                return Err(anyhow!(
+                    "inlet session({}) failed to connect to outlet",
+                    self.session_id
+                ));
+            }
+        }
+
+        for chunk in self.common_data.split_chunks(frame) {
+            let chunk = self.common_data.encode_data_and_limiting(chunk).await?;
+            self.output
+                .send(ProxyMessage::I2oSendData(self.session_id, chunk))
+                .await?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::transport::CallbackTransport;
+    use crate::proxy::OutputFuncType;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::time::timeout;
+
+    async fn recv(rx: &mut UnboundedReceiver<ProxyMessage>) -> ProxyMessage {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no message from inlet")
+            .unwrap()
+    }
+
+    /// 启动TCP入口并建立一个会话, 返回会话id和代数
+    async fn start_session(
+        data_ex: InletDataEx,
+    ) -> (Inlet, UnboundedReceiver<ProxyMessage>, TcpStream, u32, u32) {
+        let (tx, mut rx) = unbounded_channel();
+        let output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
+            let _ = tx.send(message);
+            Box::pin(async {})
+        });
+        let mut inlet = Inlet::new(CallbackTransport::new(output), "".into());
+        inlet
+            .start(
+                InletProxyType::TCP,
+                "127.0.0.1:0".into(),
+                "127.0.0.1:1".into(),
+                false,
+                "None".into(),
+                data_ex,
+            )
+            .await
+            .unwrap();
+        let client = TcpStream::connect(inlet.local_addr().unwrap())
+            .await
+            .unwrap();
+        let ProxyMessage::I2oConnect {
+            session_id,
+            generation,
+            ..
+        } = recv(&mut rx).await
+        else {
+            panic!("expected I2oConnect");
+        };
+        inlet
+            .input(ProxyMessage::O2iConnect(
+                session_id,
+                generation,
+                true,
+                "".into(),
+                false,
+                "".into(),
+                0,
+            ))
+            .await;
+        (inlet, rx, client, session_id, generation)
+    }
+
+    #[tokio::test]
+    async fn test_ack_data_after_local_close() {
+        let (mut inlet, mut rx, mut client, session_id, generation) =
+            start_session(InletDataEx::new("".into(), "".into())).await;
+
+        // 客户端关闭后出口的数据才到达
+        client.shutdown().await.unwrap();
+        drop(client);
+        loop {
+            if let ProxyMessage::I2oDisconnect(id) = recv(&mut rx).await {
+                assert_eq!(id, session_id);
+                break;
+            }
+        }
+        inlet
+            .input(ProxyMessage::O2iRecvData(
+                session_id,
+                generation,
+                vec![0u8; 10],
+            ))
+            .await;
+        assert!(matches!(
+            recv(&mut rx).await,
+            ProxyMessage::I2oRecvDataResult(id, 10) if id == session_id
+        ));
+        inlet.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_error_after_connected() {
+        let data_ex = InletDataEx::new("".into(), "".into())
+            .set_circuit_breaker(CircuitBreakerConfig::new(1, 60, 60))
+            .set_connect_retry(3, 0);
+        let (mut inlet, mut rx, mut client, session_id, generation) = start_session(data_ex).await;
+
+        // 出口推迟握手时成功应答之后才发现后端连接失败, 不再重试
+        inlet
+            .input(ProxyMessage::O2iConnect(
+                session_id,
+                generation,
+                false,
+                "connect error: Connection refused".into(),
+                false,
+                "".into(),
+                0,
+            ))
+            .await;
+        loop {
+            match recv(&mut rx).await {
+                ProxyMessage::I2oDisconnect(id) => {
+                    assert_eq!(id, session_id);
+                    break;
+                }
+                ProxyMessage::I2oConnect { .. } => panic!("unexpected retry"),
+                _ => {}
+            }
+        }
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(inlet.breaker.state(), BreakerState::Open);
+        inlet.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_close_on_write_queue_overflow() {
+        let data_ex = InletDataEx::new("".into(), "".into()).set_write_queue_limit(1024 * 1024);
+        let (mut inlet, mut rx, _client, session_id, generation) = start_session(data_ex).await;
+
+        // 客户端不读取, 系统缓冲区写满后数据在入口排队, 超过上限后关闭会话
+        let mut closed = false;
+        for _ in 0..400 {
+            inlet
+                .input(ProxyMessage::O2iRecvData(
+                    session_id,
+                    generation,
+                    vec![0u8; 256 * 1024],
+                ))
+                .await;
+            while let Ok(message) = rx.try_recv() {
+                closed |= matches!(message, ProxyMessage::I2oDisconnect(id) if id == session_id);
+            }
+            if closed {
+                break;
+            }
+            yield_now().await;
+        }
+        while !closed {
+            closed =
+                matches!(recv(&mut rx).await, ProxyMessage::I2oDisconnect(id) if id == session_id);
+        }
+        assert_eq!(inlet.close_stats().closed_by_overflow, 1);
+        inlet.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_splice_session() {
+        if !splice::is_supported() {
+            return;
+        }
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let spliced = Arc::new(AtomicU64::new(0));
+        let spliced_cloned = spliced.clone();
+        let data_ex = InletDataEx::new("".into(), "".into())
+            .set_splice_outlet(Some(OutletDataEx::new()))
+            .set_on_spliced_traffic(Arc::new(move |len| {
+                spliced_cloned.fetch_add(len, Ordering::Relaxed);
+            }));
+        let mut inlet = Inlet::new(
+            CallbackTransport::new(Arc::new(|_| Box::pin(async {}))),
+            "".into(),
+        );
+        inlet
+            .start(
+                InletProxyType::TCP,
+                "127.0.0.1:0".into(),
+                backend.local_addr().unwrap().to_string(),
+                false,
+                "None".into(),
+                data_ex,
+            )
+            .await
+            .unwrap();
+        let connect = || async {
+            let mut client = TcpStream::connect(inlet.local_addr().unwrap())
+                .await
+                .unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let (mut server, _) = backend.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            tokio::io::AsyncReadExt::read_exact(&mut server, &mut buf)
+                .await
+                .unwrap();
+            (client, server)
+        };
+
+        // splice的会话出现在会话列表中, 流量计入回调, 可以单独关闭
+        let (mut client, _server) = connect().await;
+        // 写入后端后才回调, 可能晚于后端读到数据
+        while spliced.load(Ordering::Relaxed) < 4 {
+            yield_now().await;
+        }
+        let sessions = inlet.list_sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].bytes_in, 4);
+        assert_eq!(spliced.load(Ordering::Relaxed), 4);
+        inlet.kill_session(sessions[0].session_id).await;
+        let mut buf = [0u8; 1];
+        let len = timeout(
+            Duration::from_secs(5),
+            tokio::io::AsyncReadExt::read(&mut client, &mut buf),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(len, 0);
+        assert!(inlet.list_sessions().await.is_empty());
+        assert_eq!(inlet.close_stats().closed_by_kill, 1);
+
+        // 入口停止时关闭的会话记为shutdown
+        let _session = connect().await;
+        inlet.stop().await;
+        assert_eq!(inlet.close_stats().closed_by_shutdown, 1);
+    }
+
+    #[tokio::test]
+    async fn test_splice_session_data() {
+        if !splice::is_supported() {
+            return;
+        }
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let data_ex = InletDataEx::new("".into(), "".into())
+            .set_splice_outlet(Some(OutletDataEx::new()))
+            .set_write_queue_limit(1024 * 1024);
+        let mut inlet = Inlet::new(
+            CallbackTransport::new(Arc::new(|_| Box::pin(async {}))),
+            "".into(),
+        );
+        inlet
+            .start(
+                InletProxyType::TCP,
+                "127.0.0.1:0".into(),
+                backend.local_addr().unwrap().to_string(),
+                false,
+                "None".into(),
+                data_ex,
+            )
+            .await
+            .unwrap();
+
+        let mut client = TcpStream::connect(inlet.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = backend.accept().await.unwrap();
+
+        // 两个方向的数据都直接转发
+        let payload: Vec<u8> = (0..256 * 1024).map(|x| x as u8).collect();
+        let mut received = vec![0u8; payload.len()];
+        let (write, read) = tokio::join!(
+            client.write_all(&payload),
+            tokio::io::AsyncReadExt::read_exact(&mut server, &mut received)
+        );
+        write.unwrap();
+        read.unwrap();
+        assert_eq!(received, payload);
+        server.write_all(b"pong").await.unwrap();
+        let mut buf = [0u8; 4];
+        timeout(
+            Duration::from_secs(5),
+            tokio::io::AsyncReadExt::read_exact(&mut client, &mut buf),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // 会话建立后即为已连接, 使用入口配置的写队列上限
+        {
+            let sessions = inlet.session_info_map.read().await;
+            let session = sessions.values().next().unwrap();
+            assert!(session.connected);
+            assert_eq!(session.write_queue_limit, 1024 * 1024);
+        }
+        drop(client);
+        inlet.stop().await;
+    }
+}
@@ -1,34 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 
+pub mod allowlist;
+pub mod auth;
+pub mod breaker;
 pub(crate) mod common;
 pub mod crypto;
+pub mod dictionary;
+pub(crate) mod dns;
+pub mod framing;
+pub mod geoip;
 pub mod inlet;
 pub mod outlet;
+pub(crate) mod pool;
+pub(crate) mod proxy_protocol;
+pub(crate) mod rate_limit;
+pub mod recorder;
+pub mod reject;
 pub(crate) mod socks5;
+pub mod stats;
+pub mod transport;
+pub(crate) mod vhost;
 
+#[cfg(unix)]
+pub use common::bind_unix_listener;
+pub use common::{
+    default_global_buffer_budget, global_buffered_bytes, set_global_buffer_budget, unix_socket_path,
+};
+pub use pool::{global_pool_idle, set_global_pool_limit, DEFAULT_GLOBAL_POOL_LIMIT};
+
+// 会话代数由入口为每个会话分配, 出口在回复中原样带回.
+// 会话id被回收复用后, 入口丢弃代数不匹配的旧消息, 代数为0表示对端不支持, 不做检查
+#[derive(Clone, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum ProxyMessage {
-    // 向输出端请求发起连接(u32:会话id  u8:通道类型 bool 是否TCP bool:是否压缩数据 String:目标地址 String:加密方式 String:加密密码 String:客户端地址)
-    I2oConnect(u32, u8, bool, bool, String, String, String, String),
-    // 连接结果(u32:会话id  bool:是否是成功 String:错误信息)
-    O2iConnect(u32, bool, String),
+    // 向输出端请求发起连接
+    I2oConnect {
+        session_id: u32,
+        // 会话代数
+        generation: u32,
+        // 通道类型
+        tunnel_type: u8,
+        is_tcp: bool,
+        // 是否压缩数据
+        is_compressed: bool,
+        // 目标地址
+        addr: String,
+        encryption_method: String,
+        encryption_key: String,
+        // 客户端地址(IP和端口, 入口接受PROXY协议时为头部中的原始地址, 出口可以通过PROXY协议转交给后端)
+        client_addr: String,
+        // 出口到入口方向的编码方式(是否压缩, 加密方式, 加密密码), None表示与入口到出口方向相同
+        o2i_codec: Option<(bool, String, String)>,
+        // 合并写入的时间窗口(毫秒), 为0时不合并
+        write_coalesce: u32,
+        // 出口连接后端时使用的TLS(是否校验证书, 客户端协商出的ALPN协议), None表示明文连接
+        backend_tls: Option<(bool, String)>,
+        // 压缩字典的哈希值, 为空表示不使用字典
+        compression_dictionary: String,
+        // 会话元数据(例如通道标签、客户端请求的主机名、认证的用户名), 出口用于日志和路由, 可以为空
+        metadata: HashMap<String, String>,
+        // key的派生方式(u32:派生方式 String:base64编码的盐), 此时两个方向的加密密码都为空, 出口用预共享密钥派生, None表示密码随消息发送
+        key_derivation: Option<(u32, String)>,
+        // 压缩阈值, 两个方向小于该长度的数据不压缩, 每块数据带1字节头部标记是否压缩, 为0时全部压缩且没有头部
+        compress_threshold: u32,
+    },
+    // 连接结果(u32:会话id  u32:会话代数  bool:是否是成功 String:错误信息 bool:是否使用了独立的出口到入口编码方式
+    // String:出口实际连接的后端地址(解析后的IP和端口), 失败或未知时为空
+    // u32:应答的出口(玩家id), 由服务器转发时填写, 出口发送时为0)
+    O2iConnect(u32, u32, bool, String, bool, String, u32),
     // 向输出端请求发送数据(u32:会话id  Vec<u8>:数据)
-    I2oSendData(u32, Vec<u8>),
+    I2oSendData(u32, #[serde(with = "recorder::base64_bytes")] Vec<u8>),
     // 向输出端请求发送数据(u32:会话id  Vec<u8>:数据 String:udp包目标地址)
-    I2oSendToData(u32, Vec<u8>, String),
-    // 发送结果(u32:会话id, u32:完成长度)
-    O2iSendDataResult(u32, usize),
-    // 输出端收到数据返回给输入端(u32:会话id  Vec<u8>:数据 String:udp包远端地址)
-    O2iRecvDataFrom(u32, Vec<u8>, String),
-    // 输出端收到数据返回给输入端(u32:会话id)
-    O2iRecvData(u32, Vec<u8>),
+    I2oSendToData(
+        u32,
+        #[serde(with = "recorder::base64_bytes")] Vec<u8>,
+        String,
+    ),
+    // 发送结果(u32:会话id, u32:会话代数, u32:完成长度)
+    O2iSendDataResult(u32, u32, usize),
+    // 输出端收到数据返回给输入端(u32:会话id  u32:会话代数  Vec<u8>:数据 String:udp包远端地址)
+    O2iRecvDataFrom(
+        u32,
+        u32,
+        #[serde(with = "recorder::base64_bytes")] Vec<u8>,
+        String,
+    ),
+    // 输出端收到数据返回给输入端(u32:会话id  u32:会话代数)
+    O2iRecvData(u32, u32, #[serde(with = "recorder::base64_bytes")] Vec<u8>),
     // 接收数据处理结果(u32:会话id, u32:完成长度)
     I2oRecvDataResult(u32, usize),
     // 断开连接
     I2oDisconnect(u32),
-    // 断开连接
-    O2iDisconnect(u32),
+    // 断开连接(u32:会话id  u32:会话代数)
+    O2iDisconnect(u32, u32),
+    // 请求出口为会话主动建立一条到服务器的连接(u32:会话id  u32:会话代数 String:绑定连接用的令牌)
+    // 用于无法被直接连接的出口, 出口用令牌在新连接上绑定会话后, 服务器再转交I2oConnect
+    I2oOpenStream(u32, u32, String),
+    // 入口发出的延迟探测(u64:入口的时间戳, 微秒), 不属于任何会话, 出口收到后原样回复O2iProbe, 不转发给后端
+    I2oProbe(u64),
+    // 出口回复的延迟探测(u64:I2oProbe中的时间戳)
+    O2iProbe(u64),
+}
+
+impl ProxyMessage {
+    /// 消息所属的会话id
+    pub fn session_id(&self) -> u32 {
+        match self {
+            ProxyMessage::I2oConnect { session_id, .. }
+            | ProxyMessage::O2iConnect(session_id, ..)
+            | ProxyMessage::I2oSendData(session_id, ..)
+            | ProxyMessage::I2oSendToData(session_id, ..)
+            | ProxyMessage::O2iSendDataResult(session_id, ..)
+            | ProxyMessage::O2iRecvDataFrom(session_id, ..)
+            | ProxyMessage::O2iRecvData(session_id, ..)
+            | ProxyMessage::I2oRecvDataResult(session_id, ..)
+            | ProxyMessage::I2oDisconnect(session_id)
+            | ProxyMessage::O2iDisconnect(session_id, ..)
+            | ProxyMessage::I2oOpenStream(session_id, ..) => *session_id,
+            // 会话id从1开始, 0不会与会话冲突
+            ProxyMessage::I2oProbe(_) | ProxyMessage::O2iProbe(_) => 0,
+        }
+    }
 }
 
 // 输出函数类型
@@ -37,11 +133,23 @@ pub type OutputFuncType =
 
 #[cfg(test)]
 mod tests {
-    use crate::proxy::inlet::{Inlet, InletProxyType};
+    use crate::proxy::common::{DataCodec, SessionCommonInfo, I2O_KEY_INFO, READ_BUF_MAX_LEN};
+    use crate::proxy::crypto::KeyDerivation;
+    use crate::proxy::inlet::{Inlet, InletDataEx, InletProxyType};
+    use crate::proxy::transport::CallbackTransport;
     use crate::proxy::ProxyMessage;
     use crate::proxy::{crypto, OutputFuncType};
+    use base64::prelude::*;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
+    use std::task::{Context, Poll};
     use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio::sync::Mutex;
     use tokio::time::sleep;
 
     #[tokio::test]
@@ -61,7 +169,7 @@ mod tests {
             })
         });
 
-        let mut inlet = Inlet::new(output.clone(), "".into());
+        let mut inlet = Inlet::new(CallbackTransport::new(output.clone()), "".into());
         inlet
             .start(
                 InletProxyType::TCP,
@@ -69,6 +177,7 @@ mod tests {
                 "www.baidu.com:80".into(),
                 false,
                 "None".into(),
+                InletDataEx::new("".into(), "".into()),
             )
             .await
             .unwrap();
@@ -83,6 +192,7 @@ mod tests {
                 "www.baidu.com:80".into(),
                 false,
                 "None".into(),
+                InletDataEx::new("".into(), "".into()),
             )
             .await
             .unwrap();
@@ -94,6 +204,366 @@ mod tests {
         inlet.stop().await;
     }
 
+    /// 模拟出口的状态
+    #[derive(Default)]
+    struct MockOutlet {
+        common_info: Option<SessionCommonInfo>,
+        generation: u32,
+        received: Vec<u8>,
+        // 收到的最大单块数据
+        max_chunk_len: usize,
+        disconnected: bool,
+    }
+
+    /// 模拟出口: 按I2oConnect中的参数解码数据, 把收到的数据转为大写后返回
+    async fn run_mock_outlet(
+        mut rx: UnboundedReceiver<ProxyMessage>,
+        inlet: Arc<Inlet>,
+        state: Arc<Mutex<MockOutlet>>,
+    ) {
+        while let Some(message) = rx.recv().await {
+            match message {
+                ProxyMessage::I2oConnect {
+                    session_id,
+                    generation,
+                    is_compressed,
+                    encryption_method,
+                    encryption_key,
+                    o2i_codec,
+                    compress_threshold,
+                    ..
+                } => {
+                    let compress_threshold = compress_threshold as usize;
+                    let i2o =
+                        DataCodec::from_remote(is_compressed, &encryption_method, &encryption_key)
+                            .unwrap()
+                            .set_compress_threshold(compress_threshold);
+                    let independent_codec = o2i_codec.is_some();
+                    let common_info = match o2i_codec {
+                        Some((is_compressed, encryption_method, encryption_key)) => {
+                            let o2i = DataCodec::from_remote(
+                                is_compressed,
+                                &encryption_method,
+                                &encryption_key,
+                            )
+                            .unwrap()
+                            .set_compress_threshold(compress_threshold);
+                            SessionCommonInfo::new(o2i, Some(i2o))
+                        }
+                        None => SessionCommonInfo::symmetric(i2o),
+                    };
+                    state.lock().await.common_info = Some(common_info);
+                    state.lock().await.generation = generation;
+                    inlet
+                        .input(ProxyMessage::O2iConnect(
+                            session_id,
+                            generation,
+                            true,
+                            "".into(),
+                            independent_codec,
+                            "127.0.0.1:80".into(),
+                            0,
+                        ))
+                        .await;
+                }
+                ProxyMessage::I2oSendData(session_id, data) => {
+                    let data_len = data.len();
+                    let common_info = state.lock().await.common_info.clone().unwrap();
+                    let generation = state.lock().await.generation;
+                    let data = common_info.decode_data(data).unwrap();
+                    {
+                        let mut state = state.lock().await;
+                        state.received.extend_from_slice(&data);
+                        state.max_chunk_len = state.max_chunk_len.max(data.len());
+                    }
+                    inlet
+                        .input(ProxyMessage::O2iSendDataResult(
+                            session_id, generation, data_len,
+                        ))
+                        .await;
+
+                    // 发给同id旧会话的数据应被入口丢弃, 入口不会确认这部分数据
+                    let stale = common_info
+                        .encode_data_and_limiting(b"stale".to_vec())
+                        .await
+                        .unwrap();
+                    common_info.release_read_buf(stale.len()).await;
+                    inlet
+                        .input(ProxyMessage::O2iRecvData(
+                            session_id,
+                            generation.wrapping_add(1).max(1),
+                            stale,
+                        ))
+                        .await;
+
+                    let reply = common_info
+                        .encode_data_and_limiting(data.to_ascii_uppercase())
+                        .await
+                        .unwrap();
+                    inlet
+                        .input(ProxyMessage::O2iRecvData(session_id, generation, reply))
+                        .await;
+                }
+                ProxyMessage::I2oRecvDataResult(_, data_len) => {
+                    let common_info = state.lock().await.common_info.clone().unwrap();
+                    common_info.release_read_buf(data_len).await;
+                }
+                ProxyMessage::I2oDisconnect(_) => {
+                    state.lock().await.disconnected = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inlet_data_flow() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listen_addr = format!("127.0.0.1:{port}");
+
+        let (tx, rx) = unbounded_channel();
+        let output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
+            let _ = tx.send(message);
+            Box::pin(async {})
+        });
+
+        // 入口到出口压缩并加密, 出口到入口使用另一种加密方式, 大帧分块发送
+        let mut inlet = Inlet::new(CallbackTransport::new(output), "".into());
+        inlet
+            .start(
+                InletProxyType::TCP,
+                listen_addr.clone(),
+                "mock:80".into(),
+                true,
+                "Aes128".into(),
+                InletDataEx::new("".into(), "".into())
+                    .set_o2i_codec(None, Some("Xor".into()))
+                    .set_chunk_size(1000),
+            )
+            .await
+            .unwrap();
+        let inlet = Arc::new(inlet);
+        let state = Arc::new(Mutex::new(MockOutlet::default()));
+        let outlet_task = tokio::spawn(run_mock_outlet(rx, inlet.clone(), state.clone()));
+
+        let mut client = TcpStream::connect(&listen_addr).await.unwrap();
+        let payload = b"hello npipe ".repeat(1000);
+        client.write_all(&payload).await.unwrap();
+
+        let mut reply = vec![0u8; payload.len()];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+            .await
+            .expect("timeout waiting for reply")
+            .unwrap();
+        assert_eq!(reply, payload.to_ascii_uppercase());
+        assert_eq!(state.lock().await.received, payload);
+        assert!(state.lock().await.max_chunk_len <= 1000);
+
+        // 双方确认后读缓存回落到0
+        sleep(Duration::from_millis(100)).await;
+        let stats = inlet.backpressure_stats().await;
+        assert_eq!(stats.sessions.len(), 1);
+        assert_eq!(stats.sessions[0].read_buf_len, 0);
+        let outlet_info = state.lock().await.common_info.clone().unwrap();
+        assert_eq!(*outlet_info.read_buf_len.read().await, 0);
+        assert!(!outlet_info.is_symmetric);
+
+        let sessions = inlet.list_sessions().await;
+        assert_eq!(sessions[0].bytes_in, payload.len() as u64);
+        assert_eq!(sessions[0].bytes_out, payload.len() as u64);
+        assert_eq!(sessions[0].backend_addr, "127.0.0.1:80");
+
+        // 客户端断开后会话被清理并通知出口
+        drop(client);
+        sleep(Duration::from_millis(200)).await;
+        assert!(inlet.list_sessions().await.is_empty());
+        assert!(state.lock().await.disconnected);
+
+        outlet_task.abort();
+        let _ = outlet_task.await;
+        if let Ok(mut inlet) = Arc::try_unwrap(inlet) {
+            inlet.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_not_chunked() {
+        let listen_addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let (tx, mut rx) = unbounded_channel();
+        let output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
+            let _ = tx.send(message);
+            Box::pin(async {})
+        });
+        let mut inlet = Inlet::new(CallbackTransport::new(output), "".into());
+        inlet
+            .start(
+                InletProxyType::UDP,
+                listen_addr.clone(),
+                "127.0.0.1:1".into(),
+                false,
+                "None".into(),
+                InletDataEx::new("".into(), "".into()).set_chunk_size(10),
+            )
+            .await
+            .unwrap();
+
+        // 超过分块大小的数据包仍然作为一个完整的数据包发给出口
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let datagram: Vec<u8> = (0..100).collect();
+        for _ in 0..2 {
+            client.send_to(&datagram, &listen_addr).await.unwrap();
+            loop {
+                let message = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                    .await
+                    .expect("no message from inlet")
+                    .unwrap();
+                if let ProxyMessage::I2oSendData(_, data) = message {
+                    assert_eq!(data, datagram);
+                    break;
+                }
+            }
+        }
+        inlet.stop().await;
+    }
+
+    /// 统计被轮询次数的Future
+    struct PollCount<F> {
+        inner: Pin<Box<F>>,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl<F: Future> Future for PollCount<F> {
+        type Output = F::Output;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            self.inner.as_mut().poll(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_wakeup() {
+        let common_info = SessionCommonInfo::symmetric(DataCodec::from_method_name(false, "None"));
+
+        // 填满读缓存
+        common_info
+            .encode_data_and_limiting(vec![0u8; READ_BUF_MAX_LEN + 1])
+            .await
+            .unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let common_info_cloned = common_info.clone();
+        let handle = tokio::spawn(PollCount {
+            inner: Box::pin(async move {
+                common_info_cloned
+                    .encode_data_and_limiting(vec![0u8; 16])
+                    .await
+                    .unwrap();
+            }),
+            count: count.clone(),
+        });
+
+        // 背压期间发送方应挂起且不被反复轮询
+        sleep(Duration::from_millis(100)).await;
+        assert!(!handle.is_finished());
+        assert!(count.load(Ordering::Relaxed) <= 2);
+
+        // 收到确认后应立即恢复
+        common_info.release_read_buf(READ_BUF_MAX_LEN + 1).await;
+        tokio::time::timeout(Duration::from_millis(100), handle)
+            .await
+            .expect("sender was not woken up")
+            .unwrap();
+        assert_eq!(*common_info.read_buf_len.read().await, 16);
+    }
+
+    #[tokio::test]
+    async fn test_per_direction_codec() {
+        let raw = b"per-direction codec".to_vec();
+
+        // 入口: 入口到出口只压缩, 出口到入口只加密
+        let i2o = DataCodec::from_method_name(true, "None");
+        let o2i = DataCodec::from_method_name(false, "Aes128");
+        let inlet = SessionCommonInfo::new(i2o, Some(o2i));
+        assert!(!inlet.is_symmetric);
+
+        // 出口根据I2oConnect中的参数构造
+        let (is_compressed, method, key) = inlet.outbound.to_remote();
+        let i2o = DataCodec::from_remote(is_compressed, &method, &key).unwrap();
+        let (is_compressed, method, key) = inlet.inbound.to_remote();
+        let o2i = DataCodec::from_remote(is_compressed, &method, &key).unwrap();
+        let outlet = SessionCommonInfo::new(o2i, Some(i2o));
+
+        let data = inlet.encode_data_and_limiting(raw.clone()).await.unwrap();
+        assert_eq!(outlet.decode_data(data).unwrap(), raw);
+        let data = outlet.encode_data_and_limiting(raw.clone()).await.unwrap();
+        assert_ne!(data, raw);
+        assert_eq!(inlet.decode_data(data).unwrap(), raw);
+
+        assert!(DataCodec::from_remote(false, "Aes128", "").is_err());
+
+        // 派生key时key不随连接请求发送, 出口用预共享密钥和盐得到相同的key
+        let salt = crypto::generate_salt();
+        let new_codec = |kdf: KeyDerivation, salt: &[u8]| {
+            DataCodec::from_derived_key(false, "Aes128", kdf, b"psk", salt, I2O_KEY_INFO)
+        };
+        let inlet = SessionCommonInfo::symmetric(new_codec(KeyDerivation::HkdfSha256, &salt));
+        assert_eq!(inlet.outbound.to_remote().2, "");
+        let (kdf, salt) = inlet.remote_derivation().unwrap();
+        let salt = BASE64_STANDARD.decode(salt).unwrap();
+        let outlet =
+            SessionCommonInfo::symmetric(new_codec(KeyDerivation::from_u32(kdf).unwrap(), &salt));
+        let data = inlet.encode_data_and_limiting(raw.clone()).await.unwrap();
+        assert_eq!(outlet.decode_data(data).unwrap(), raw);
+    }
+
+    #[tokio::test]
+    async fn test_compress_threshold() {
+        let info = |threshold: usize| {
+            SessionCommonInfo::symmetric(
+                DataCodec::from_method_name(true, "None").set_compress_threshold(threshold),
+            )
+        };
+        let session = info(128);
+
+        // 小于阈值的数据不压缩, 只多出1字节头部
+        let small = b"ping".to_vec();
+        let data = session
+            .encode_data_and_limiting(small.clone())
+            .await
+            .unwrap();
+        assert_eq!(data, [&[0u8][..], &small].concat());
+        assert_eq!(session.decode_data(data).unwrap(), small);
+
+        let large = vec![b'a'; 4096];
+        let data = session
+            .encode_data_and_limiting(large.clone())
+            .await
+            .unwrap();
+        assert_eq!(data[0], 1);
+        assert!(data.len() < 1024);
+        assert_eq!(session.decode_data(data).unwrap(), large);
+        assert!(session.decode_data(vec![2, 0]).is_err());
+
+        // 阈值为0时与旧格式兼容, 没有头部
+        let legacy = info(0);
+        let data = legacy
+            .encode_data_and_limiting(small.clone())
+            .await
+            .unwrap();
+        assert_ne!(data[0], 0);
+        assert_eq!(legacy.decode_data(data).unwrap(), small);
+    }
+
     #[test]
     fn test_crypto() {
         let raw_str = String::from("xxtea-nostd is an implementation of the XXTEA encryption algorithm designed for no-std environments. The code uses native endianess to interpret the byte slices passed to the library as 4-byte words.");
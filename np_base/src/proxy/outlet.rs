@@ -1,62 +1,424 @@
+use crate::net::fast_open::{self, FastOpenStream};
 use crate::net::session_delegate::SessionDelegate;
-use crate::net::{tcp_session, udp_session, SendMessageFuncType, WriterMessage};
-use crate::proxy::common::{InputSenderType, SessionCommonInfo};
-use crate::proxy::crypto::get_method;
+use crate::net::{tcp_session, tls, udp_session, BoxedStream, SendMessageFuncType, WriterMessage};
+use crate::proxy::allowlist::DestinationAllowlist;
+use crate::proxy::common::{
+    DataCodec, InputSenderType, SessionCommonInfo, I2O_KEY_INFO, O2I_KEY_INFO, READ_BUF_MAX_LEN,
+};
+use crate::proxy::crypto::KeyDerivation;
+use crate::proxy::dictionary::CompressionDictionary;
+use crate::proxy::framing::LengthPrefix;
 use crate::proxy::inlet::InletProxyType;
+use crate::proxy::pool::{ConnectionPool, ConnectionPoolConfig, DEFAULT_POOL_MAX_IDLE};
+use crate::proxy::proxy_protocol::{encode_v1_header, V1_UNKNOWN_HEADER};
+use crate::proxy::socks5::client::Socks5Upstream;
+use crate::proxy::stats::{BackpressureStats, ConnectionPoolStats};
+use crate::proxy::transport::MessageTransport;
 use crate::proxy::ProxyMessage;
-use crate::proxy::{common, OutputFuncType};
+use crate::proxy::{common, crypto, socks5, stats};
 use anyhow::anyhow;
 use async_trait::async_trait;
 use base64::prelude::*;
-use log::{debug, error, info, trace};
+use bytes::BytesMut;
+use log::{debug, error, info, trace, warn};
 use socket2::{SockRef, TcpKeepalive};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpStream, UdpSocket};
 use tokio::select;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::task::yield_now;
+use tokio::time::{timeout, Instant};
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
 
 struct SessionInfo {
+    // 入口分配的会话代数, 回复入口的消息中原样带回
+    generation: u32,
     sender: InputSenderType,
     common_info: SessionCommonInfo,
+    // 设置了发送超时时, 数据先交给会话自己的发送队列
+    send_queue: Option<UnboundedSender<(Vec<u8>, usize)>>,
 }
 
 type SessionInfoMap = Arc<RwLock<HashMap<u32, SessionInfo>>>;
 
+/// 入口与出口的压缩字典不一致时回复给入口的错误
+pub const COMPRESSION_DICTIONARY_MISMATCH: &str = "compression dictionary mismatch";
+
+/// 每个会话等待入口确认的数据的默认上限(字节)
+pub const DEFAULT_BUFFER_LIMIT: u32 = READ_BUF_MAX_LEN as u32;
+
+/// 设置了发送超时时, 每个会话最多等待写入后端的消息数
+const SEND_QUEUE_SIZE: usize = 64;
+
+/// 出口到后端连接的TCP保活设置
+#[derive(Clone, Debug)]
+pub struct TcpKeepaliveConfig {
+    /// 连接空闲多久后开始发送保活探测, 为0时不开启保活
+    pub time: Duration,
+    /// 探测间隔, 为0时使用系统默认值
+    pub interval: Duration,
+    /// 探测失败多少次后断开, 为0时使用系统默认值
+    pub retries: u32,
+}
+
+impl Default for TcpKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            time: Duration::from_secs(30),
+            interval: Duration::ZERO,
+            retries: 0,
+        }
+    }
+}
+
+impl TcpKeepaliveConfig {
+    fn apply(&self, stream: &TcpStream) -> anyhow::Result<()> {
+        if self.time.is_zero() {
+            return Ok(());
+        }
+        let mut keepalive = TcpKeepalive::new().with_time(self.time);
+        if !self.interval.is_zero() {
+            keepalive = keepalive.with_interval(self.interval);
+        }
+        #[cfg(not(windows))]
+        if self.retries > 0 {
+            keepalive = keepalive.with_retries(self.retries);
+        }
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct OutletDataEx {
+    /// 上游socks5代理
+    pub(crate) upstream_socks5: Option<Socks5Upstream>,
+    /// 发起连接时绑定的本地地址
+    pub(crate) bind_addr: Option<IpAddr>,
+    /// 连接后端后先发送PROXY协议v1头部, 把客户端的IP和端口告知后端
+    pub(crate) send_proxy_protocol: bool,
+    /// 允许连接的目标地址, 为空时不限制
+    pub(crate) allowlist: DestinationAllowlist,
+    /// 压缩字典, 与入口的字典不一致时拒绝连接
+    pub(crate) compression_dictionary: Option<Arc<CompressionDictionary>>,
+    /// 连接后端的TCP保活设置
+    pub(crate) keepalive: TcpKeepaliveConfig,
+    /// 预连接池设置
+    pub(crate) pool: ConnectionPoolConfig,
+    /// TCP通道按长度前缀分帧, 必须与入口一致
+    pub(crate) length_prefix: LengthPrefix,
+    /// 每个会话等待入口确认的数据上限, 未设置时为默认值
+    pub(crate) buffer_limit: Option<usize>,
+    /// 派生会话key的方式和预共享密钥, 必须与入口一致
+    pub(crate) key_derivation: Option<(KeyDerivation, Vec<u8>)>,
+    /// 等待写入后端的消息过多时最长等待的时间, 为0时不限制
+    pub(crate) send_timeout: Duration,
+    /// UDP会话连续发送超时多少次后关闭, TCP会话第一次超时就关闭
+    pub(crate) max_send_timeouts: u32,
+    /// 到后端的连接关闭Nagle算法
+    pub(crate) nodelay: bool,
+    /// 连接后端时使用TCP Fast Open
+    pub(crate) fast_open: bool,
+    /// 按入口读到的标签选择后端, 键为标签, 值为后端地址
+    pub(crate) label_routes: HashMap<String, String>,
+}
+
+impl OutletDataEx {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 设置上游socks5代理, 出口的tcp连接将通过此代理发起
+    pub fn set_upstream_socks5(mut self, addr: String, username: String, password: String) -> Self {
+        self.upstream_socks5 = if addr.is_empty() {
+            None
+        } else {
+            Some(Socks5Upstream {
+                addr,
+                username,
+                password,
+            })
+        };
+        self
+    }
+
+    /// 设置发起连接时绑定的本地地址, 用于多出口网卡时指定源IP
+    pub fn set_bind_addr(mut self, bind_addr: Option<IpAddr>) -> Self {
+        self.bind_addr = bind_addr;
+        self
+    }
+
+    /// 设置是否向后端发送PROXY协议头部, 后端需要支持PROXY协议, 否则会把头部当作普通数据
+    pub fn set_send_proxy_protocol(mut self, send_proxy_protocol: bool) -> Self {
+        self.send_proxy_protocol = send_proxy_protocol;
+        self
+    }
+
+    /// 设置允许连接的目标地址白名单, 不论入口发来什么地址都在出口检查
+    pub fn set_allowlist(mut self, allowlist: DestinationAllowlist) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// 设置压缩字典, 入口必须使用同一个字典
+    pub fn set_compression_dictionary(
+        mut self,
+        dictionary: Option<Arc<CompressionDictionary>>,
+    ) -> Self {
+        self.compression_dictionary = dictionary;
+        self
+    }
+
+    /// 设置连接后端的TCP保活, 避免空闲连接被中间设备或后端悄悄断开
+    pub fn set_keepalive(mut self, keepalive: TcpKeepaliveConfig) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// 设置预连接池, `size` 为0时不使用, `max_idle` 为0时使用默认值
+    ///
+    /// 池中的连接会被任意会话取用, 只适合连接建立后没有状态的后端
+    pub fn set_connection_pool(mut self, size: usize, max_idle: Duration) -> Self {
+        self.pool = ConnectionPoolConfig {
+            size,
+            max_idle: if max_idle.is_zero() {
+                DEFAULT_POOL_MAX_IDLE
+            } else {
+                max_idle
+            },
+        };
+        self
+    }
+
+    /// 设置TCP通道按长度前缀分帧, 后端返回的每条完整消息单独转发给入口
+    pub fn set_length_prefix(mut self, length_prefix: LengthPrefix) -> Self {
+        self.length_prefix = length_prefix;
+        self
+    }
+
+    /// 设置每个会话等待入口确认的数据上限(字节), 超过时暂停读取后端, 为0时不限制
+    pub fn set_buffer_limit(mut self, buffer_limit: usize) -> Self {
+        self.buffer_limit = Some(buffer_limit);
+        self
+    }
+
+    /// 设置会话key的派生方式和通道密码, 入口要求派生key时使用, 未设置或方式不一致时拒绝这样的连接
+    ///
+    /// 预共享密钥在这里计算一次, 会话只做HKDF
+    pub fn set_key_derivation(mut self, kdf: KeyDerivation, password: &str) -> Self {
+        self.key_derivation = if kdf == KeyDerivation::Random || password.is_empty() {
+            None
+        } else {
+            Some((kdf, crypto::prepare_key_secret(kdf, password)))
+        };
+        self
+    }
+
+    /// 设置向后端写入数据的超时, `timeout` 为0时不限制
+    ///
+    /// 每个会话等待写入后端的消息超过上限时最多等待 `timeout`, 超时的数据被丢弃.
+    /// TCP会话丢弃数据后立即关闭, UDP会话连续超时 `max_timeouts` 次后关闭.
+    /// 等待在会话自己的发送队列中进行, 卡住的会话不会阻塞其他会话的数据
+    pub fn set_send_timeout(mut self, timeout: Duration, max_timeouts: u32) -> Self {
+        self.send_timeout = timeout;
+        self.max_send_timeouts = max_timeouts.max(1);
+        self
+    }
+
+    /// 设置到后端的连接是否关闭Nagle算法(TCP_NODELAY), 默认不关闭
+    ///
+    /// 关闭后发往后端的小包立即发出, 交互式的上行流量延迟更低, 但大量小块写入时包数增多、吞吐下降
+    pub fn set_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// 设置连接后端时是否使用TCP Fast Open(只支持Linux), 默认不使用
+    ///
+    /// 已取得后端的TFO cookie时推迟握手, 第一块数据随SYN发出, 短连接省去一次往返;
+    /// 后端不支持时退回普通握手. 推迟握手时连接失败要到第一次读写时才能发现,
+    /// 也不再交替尝试IPv4和IPv6地址. 通过上游代理或绑定本地地址连接时不使用
+    pub fn set_fast_open(mut self, fast_open: bool) -> Self {
+        self.fast_open = fast_open;
+        self
+    }
+
+    /// 设置标签路由, 入口连接消息中的标签(区分大小写)在这里找到时连接对应的后端,
+    /// 没有标签或没有匹配时连接通道的默认后端
+    pub fn set_label_routes(mut self, label_routes: HashMap<String, String>) -> Self {
+        self.label_routes = label_routes;
+        self
+    }
+
+    /// 按出口的连接方式(上游代理、绑定地址)连接后端
+    async fn connect(&self, addr: &str) -> anyhow::Result<TcpStream> {
+        match self.upstream_socks5 {
+            Some(ref upstream) => socks5::client::connect(upstream, addr, self.bind_addr).await,
+            None => match self.bind_addr {
+                Some(bind_addr) => common::connect_from(bind_addr, addr).await,
+                None => common::connect_happy_eyeballs(addr).await,
+            },
+        }
+    }
+
+    /// 连接后端并设置保活
+    pub(crate) async fn dial(&self, addr: &str) -> anyhow::Result<TcpStream> {
+        let stream = self.connect(addr).await?;
+        self.keepalive.apply(&stream)?;
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        Ok(stream)
+    }
+
+    /// 为会话连接后端, 返回连接和后端地址
+    ///
+    /// 启用了TCP Fast Open且直接连接只解析出一个地址的后端时, 握手推迟到第一次写入,
+    /// 推迟的握手失败时调用 `on_connect_error`. 解析出多个地址时仍按Happy Eyeballs并行连接, 不使用TFO
+    async fn dial_session(
+        &self,
+        addr: &str,
+        session_id: u32,
+        on_connect_error: impl FnOnce(std::io::Error) + Send + 'static,
+    ) -> anyhow::Result<(BoxedStream, SocketAddr)> {
+        let target =
+            if !self.fast_open || self.upstream_socks5.is_some() || self.bind_addr.is_some() {
+                None
+            } else {
+                let targets: Vec<_> = tokio::net::lookup_host(addr).await?.collect();
+                match targets[..] {
+                    [target] => Some(target),
+                    [] => return Err(anyhow!("cannot resolve address: {addr}")),
+                    _ => None,
+                }
+            };
+        let Some(target) = target else {
+            let stream = self.dial(addr).await?;
+            let peer_addr = stream.peer_addr()?;
+            return Ok((Box::new(stream), peer_addr));
+        };
+        let stream = fast_open::connect(target).await?;
+        self.keepalive.apply(&stream)?;
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        let stream = FastOpenStream::new(stream, move |result| match result {
+            Ok(syn_data_acked) => {
+                debug!("outlet session({session_id}) TCP fast open: {syn_data_acked}")
+            }
+            Err(err) => on_connect_error(err),
+        });
+        Ok((Box::new(stream), target))
+    }
+
+    /// 探测出口能否连接后端, 返回建立连接的耗时
+    ///
+    /// 与正常会话一样检查白名单并使用相同的连接方式, 连接成功后立即关闭
+    pub async fn probe(&self, addr: &str, probe_timeout: Duration) -> anyhow::Result<Duration> {
+        let start = Instant::now();
+        let addr = self.allowlist.check(addr).await?;
+        timeout(probe_timeout, self.connect(&addr))
+            .await
+            .map_err(|_| anyhow!("connect timeout"))??;
+        Ok(start.elapsed())
+    }
+}
+
+/// 与后端TLS握手的超时时间(秒)
+const BACKEND_TLS_TIMEOUT: u64 = 15;
+
+/// 从 `主机:端口` 中取出校验后端证书使用的名称
+fn tls_server_name(addr: &str) -> anyhow::Result<ServerName> {
+    let host = addr.rsplit_once(':').map_or(addr, |x| x.0);
+    let host = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    ServerName::try_from(host).map_err(|_| anyhow!("invalid TLS server name: {host}"))
+}
+
+/// 用于日志的会话元数据, 按键排序, 没有元数据时为空
+fn format_metadata(metadata: &HashMap<String, String>) -> String {
+    if metadata.is_empty() {
+        return String::new();
+    }
+    let mut items: Vec<_> = metadata.iter().collect();
+    items.sort();
+    let items: Vec<String> = items.iter().map(|(k, v)| format!("{k}={v}")).collect();
+    format!(" [{}]", items.join(", "))
+}
+
+/// 检查本地地址是否可以绑定
+pub fn check_bind_addr(bind_addr: IpAddr) -> anyhow::Result<()> {
+    std::net::TcpListener::bind((bind_addr, 0))
+        .map_err(|err| anyhow!("cannot bind local address {bind_addr}: {err}"))?;
+    Ok(())
+}
+
 pub struct Outlet {
     session_info_map: SessionInfoMap,
+    data_ex: OutletDataEx,
     description: String,
     notify_shutdown: RwLock<Option<broadcast::Sender<()>>>,
     receiver_shutdown: broadcast::Receiver<()>,
     output: mpsc::Sender<ProxyMessage>,
     input: UnboundedSender<ProxyMessage>,
+    pool: Option<Arc<ConnectionPool>>,
 }
 
 impl Outlet {
-    pub fn new(on_output_callback: OutputFuncType, description: String) -> Arc<Self> {
+    pub fn new(
+        transport: Arc<dyn MessageTransport>,
+        description: String,
+        data_ex: OutletDataEx,
+    ) -> Arc<Self> {
         let (notify_shutdown, mut receiver_shutdown) = broadcast::channel::<()>(1);
         let (input_tx, input_rx) = mpsc::unbounded_channel();
         let (output_tx, output_rx) = mpsc::channel::<ProxyMessage>(1000);
+        let pool = if data_ex.pool.size > 0 {
+            Some(ConnectionPool::new(data_ex.pool.clone()))
+        } else {
+            None
+        };
 
         let outlet = Arc::new(Self {
             session_info_map: Arc::new(RwLock::new(HashMap::new())),
+            data_ex,
             description,
             notify_shutdown: RwLock::new(Some(notify_shutdown)),
             receiver_shutdown: receiver_shutdown.resubscribe(),
             output: output_tx,
             input: input_tx,
+            pool: pool.clone(),
         });
 
         let outlet_cloned = outlet.clone();
 
+        // 定期清理连接池中空闲过久或已断开的连接
+        if let Some(pool) = pool {
+            let mut receiver_shutdown = receiver_shutdown.resubscribe();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(pool.max_idle() / 2);
+                loop {
+                    select! {
+                        _ = interval.tick() => pool.evict_stale(),
+                        _ = receiver_shutdown.recv() => break,
+                    }
+                }
+                pool.close();
+            });
+        }
+
         // 通知会话结束
         tokio::spawn(async move {
             select! {
-                _= common::async_receive_output(output_rx, on_output_callback) => {}
+                _= common::async_receive_output(output_rx, transport) => {}
                 _= receiver_shutdown.recv() =>{}
                 _= outlet.async_receive_input(input_rx) =>{}
             }
@@ -74,6 +436,9 @@ impl Outlet {
         let notify_shutdown = self.notify_shutdown.write().await.take();
         if let Some(notify_shutdown) = notify_shutdown {
             drop(notify_shutdown);
+            if let Some(ref pool) = self.pool {
+                pool.close();
+            }
 
             let condition = async {
                 while !self.session_info_map.read().await.is_empty() {
@@ -94,6 +459,20 @@ impl Outlet {
         &self.description
     }
 
+    /// 所有会话的背压统计
+    pub async fn backpressure_stats(&self) -> BackpressureStats {
+        let mut sessions = Vec::new();
+        for (session_id, session) in self.session_info_map.read().await.iter() {
+            sessions.push(stats::session_backpressure(*session_id, &session.common_info).await);
+        }
+        BackpressureStats::from_sessions(sessions)
+    }
+
+    /// 连接池的统计, 未开启连接池时返回None
+    pub fn pool_stats(&self) -> Option<ConnectionPoolStats> {
+        self.pool.as_ref().map(|x| x.stats())
+    }
+
     async fn async_receive_input(&self, mut input: UnboundedReceiver<ProxyMessage>) {
         while let Some(message) = input.recv().await {
             if let Err(err) = self.input_internal(message).await {
@@ -104,8 +483,9 @@ impl Outlet {
 
     async fn input_internal(&self, message: ProxyMessage) -> anyhow::Result<()> {
         match message {
-            ProxyMessage::I2oConnect(
+            ProxyMessage::I2oConnect {
                 session_id,
+                generation,
                 tunnel_type,
                 is_tcp,
                 is_compressed,
@@ -113,40 +493,91 @@ impl Outlet {
                 encryption_method,
                 encryption_key,
                 client_addr,
-            ) => {
+                o2i_codec,
+                write_coalesce,
+                backend_tls,
+                compression_dictionary: dictionary_hash,
+                metadata,
+                key_derivation,
+                compress_threshold,
+            } => {
+                let independent_codec = o2i_codec.is_some();
+                let addr = match metadata
+                    .get("label")
+                    .and_then(|label| self.data_ex.label_routes.get(label))
+                {
+                    Some(endpoint) => endpoint.clone(),
+                    None => addr,
+                };
+                let metadata = format_metadata(&metadata);
                 trace!(
                     "I2oConnect: session_id:{session_id}, addr:{addr}, tunnel_type:{tunnel_type}"
                 );
-                if let Err(err) = self
+                match self
                     .on_i2o_connect(
                         session_id,
+                        generation,
                         tunnel_type,
                         is_tcp,
                         is_compressed,
                         addr.clone(),
+                        &client_addr,
                         encryption_method,
                         encryption_key,
+                        o2i_codec,
+                        write_coalesce,
+                        backend_tls,
+                        &dictionary_hash,
+                        key_derivation,
+                        compress_threshold as usize,
                     )
                     .await
                 {
-                    error!(
-                        "Failed to connect to {}, error: {}, remote client addr {}",
-                        addr,
-                        err.to_string(),
-                        client_addr
-                    );
+                    Err(err) => {
+                        error!(
+                            "Failed to connect to {}, error: {}, remote client addr {}{metadata}",
+                            addr,
+                            err.to_string(),
+                            client_addr
+                        );
 
-                    self.output
-                        .send(ProxyMessage::O2iConnect(session_id, false, err.to_string()))
-                        .await?;
-                } else {
-                    info!(
-                        "Successfully connected to {}, remote client addr {}",
-                        addr, client_addr
-                    );
-                    self.output
-                        .send(ProxyMessage::O2iConnect(session_id, true, "".into()))
-                        .await?;
+                        self.output
+                            .send(ProxyMessage::O2iConnect(
+                                session_id,
+                                generation,
+                                false,
+                                err.to_string(),
+                                false,
+                                "".into(),
+                                0,
+                            ))
+                            .await?;
+                    }
+                    Ok(backend_addr) => {
+                        // 域名解析出多个地址时, 记录实际连接的是哪一个及其地址族
+                        let resolved = match backend_addr.parse::<SocketAddr>() {
+                            Ok(x) if backend_addr != addr => {
+                                let family = if x.is_ipv6() { "IPv6" } else { "IPv4" };
+                                format!(" ({backend_addr}, {family})")
+                            }
+                            _ => String::new(),
+                        };
+                        info!(
+                            "Successfully connected to {}{resolved}, remote client addr {}{metadata}",
+                            addr, client_addr
+                        );
+                        self.output
+                            .send(ProxyMessage::O2iConnect(
+                                session_id,
+                                generation,
+                                true,
+                                "".into(),
+                                independent_codec,
+                                backend_addr,
+                                0,
+                            ))
+                            .await?;
+                    }
                 }
             }
             ProxyMessage::I2oSendData(session_id, data) => {
@@ -165,6 +596,10 @@ impl Outlet {
                 // trace!("I2oRecvDataResult: session_id:{session_id}, data_len:{data_len}");
                 self.on_i2o_recv_data_result(session_id, data_len).await?;
             }
+            ProxyMessage::I2oProbe(ticks) => {
+                // 延迟探测原样回复, 不经过会话
+                self.output.send(ProxyMessage::O2iProbe(ticks)).await?;
+            }
             _ => {
                 return Err(anyhow!("Unknown message"));
             }
@@ -172,41 +607,175 @@ impl Outlet {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn on_i2o_connect(
         &self,
         session_id: u32,
+        generation: u32,
         tunnel_type: u8,
         is_tcp: bool,
         is_compressed: bool,
         addr: String,
+        client_addr: &str,
         encryption_method: String,
         encryption_key: String,
-    ) -> anyhow::Result<()> {
-        if self.session_info_map.read().await.contains_key(&session_id) {
-            return Err(anyhow!("repeated connection"));
+        o2i_codec: Option<(bool, String, String)>,
+        write_coalesce: u32,
+        backend_tls: Option<(bool, String)>,
+        dictionary_hash: &str,
+        key_derivation: Option<(u32, String)>,
+        compress_threshold: usize,
+    ) -> anyhow::Result<String> {
+        // 字典不一致时双方都无法解压对端的数据
+        let dictionary = self.data_ex.compression_dictionary.clone();
+        if dictionary.as_ref().map_or("", |x| x.hash()) != dictionary_hash {
+            return Err(anyhow!(COMPRESSION_DICTIONARY_MISMATCH));
+        }
+        // 不认识的方法按None处理会导致双方无法解码, 拒绝后入口改用偏好列表中的下一个
+        let o2i_method = o2i_codec.as_ref().map(|x| x.1.as_str());
+        for method in std::iter::once(encryption_method.as_str()).chain(o2i_method) {
+            if crypto::parse_method(method).is_none() {
+                return Err(anyhow!("{}{method}", crypto::UNSUPPORTED_METHOD));
+            }
         }
 
-        let encryption_method = get_method(&encryption_method);
-        let encryption_key = BASE64_STANDARD.decode(encryption_key.as_bytes())?;
-        let common_info = SessionCommonInfo::new(is_compressed, encryption_method, encryption_key);
+        {
+            let mut session_info_map = self.session_info_map.write().await;
+            if let Some(session) = session_info_map.get(&session_id) {
+                if generation == 0 || session.generation == generation {
+                    return Err(anyhow!("repeated connection"));
+                }
+                // 入口已复用了这个会话id, 旧会话的断开消息丢失或还未到达
+                debug!("session({session_id}) reused by inlet, closing stale session");
+                if let Some(session) = session_info_map.remove(&session_id) {
+                    let _ = session.sender.send(WriterMessage::Close);
+                }
+            }
+        }
+
+        // 证书按请求中的主机名校验, 白名单检查可能把域名替换为解析出的IP
+        let backend_tls = match backend_tls {
+            Some((verify, alpn)) => {
+                let connector = TlsConnector::from(Arc::new(tls::client_config(verify, &alpn)));
+                Some((connector, tls_server_name(&addr)?))
+            }
+            None => None,
+        };
+
+        // socks5的udp会话没有目标地址, 每个数据包单独检查
+        let addr = if addr.is_empty() {
+            addr
+        } else {
+            self.data_ex.allowlist.check(&addr).await?
+        };
+
+        // 入口要求派生key时, 用本端的预共享密钥和入口发来的盐计算
+        let derivation = match key_derivation {
+            Some((kdf, salt)) => {
+                let (local_kdf, secret) = self
+                    .data_ex
+                    .key_derivation
+                    .as_ref()
+                    .ok_or(anyhow!("key derivation requires a pre-shared key"))?;
+                if local_kdf.to_u32() != kdf {
+                    return Err(anyhow!("key derivation mismatch: {kdf}"));
+                }
+                Some((*local_kdf, secret, BASE64_STANDARD.decode(salt)?))
+            }
+            None => None,
+        };
+        let new_codec = |is_compressed: bool,
+                         encryption_method: &str,
+                         encryption_key: &str,
+                         info: &[u8]| match &derivation {
+            Some((kdf, secret, salt)) => Ok(DataCodec::from_derived_key(
+                is_compressed,
+                encryption_method,
+                *kdf,
+                secret,
+                salt,
+                info,
+            )),
+            None => DataCodec::from_remote(is_compressed, encryption_method, encryption_key),
+        };
+
+        // 出口发送的是出口到入口方向的数据, 接收的是入口到出口方向的数据
+        let i2o = new_codec(
+            is_compressed,
+            &encryption_method,
+            &encryption_key,
+            I2O_KEY_INFO,
+        )?
+        .set_dictionary(dictionary.clone())
+        .set_compress_threshold(compress_threshold);
+        let common_info = match o2i_codec {
+            Some((is_compressed, encryption_method, encryption_key)) => {
+                let o2i = new_codec(
+                    is_compressed,
+                    &encryption_method,
+                    &encryption_key,
+                    O2I_KEY_INFO,
+                )?
+                .set_dictionary(dictionary)
+                .set_compress_threshold(compress_threshold);
+                SessionCommonInfo::new(o2i, Some(i2o))
+            }
+            None => SessionCommonInfo::symmetric(i2o),
+        }
+        .set_write_coalesce(Duration::from_millis(write_coalesce as u64))
+        .set_read_buf_max_len(self.data_ex.buffer_limit.unwrap_or(READ_BUF_MAX_LEN));
 
         let tunnel_type = InletProxyType::from_u32(tunnel_type as u32)
             .ok_or(anyhow!("unsupported tunnel_type: {tunnel_type}"))?;
-        match tunnel_type {
-            InletProxyType::TCP => self.tcp_connect(addr, session_id, common_info).await?,
-            InletProxyType::UDP => {
-                self.udp_connect(addr, session_id, common_info, tunnel_type)
+        let common_info = if matches!(tunnel_type, InletProxyType::TCP) {
+            common_info.set_length_prefix(self.data_ex.length_prefix)
+        } else {
+            common_info
+        };
+        let backend_addr = match tunnel_type {
+            InletProxyType::TCP
+            | InletProxyType::HTTPS
+            | InletProxyType::HTTP
+            | InletProxyType::LABEL => {
+                self.tcp_connect(
+                    addr,
+                    client_addr,
+                    session_id,
+                    generation,
+                    common_info,
+                    backend_tls,
+                    true,
+                )
+                .await?
+            }
+            InletProxyType::UDP | InletProxyType::DNS => {
+                self.udp_connect(addr, session_id, generation, common_info, tunnel_type)
                     .await?
             }
             InletProxyType::SOCKS5 => {
                 if is_tcp {
-                    self.tcp_connect(addr, session_id, common_info).await?
+                    self.tcp_connect(
+                        addr,
+                        client_addr,
+                        session_id,
+                        generation,
+                        common_info,
+                        backend_tls,
+                        false,
+                    )
+                    .await?
                 } else {
-                    self.udp_connect("".to_string(), session_id, common_info, tunnel_type)
-                        .await?
+                    self.udp_connect(
+                        "".to_string(),
+                        session_id,
+                        generation,
+                        common_info,
+                        tunnel_type,
+                    )
+                    .await?
                 }
             }
-        }
+        };
 
         let condition = async {
             while !self.session_info_map.read().await.contains_key(&session_id) {
@@ -222,7 +791,7 @@ impl Outlet {
                 "Waiting for the function on_session_start to call a response timeout"
             ));
         }
-        Ok(())
+        Ok(backend_addr_string(backend_addr))
     }
 
     async fn on_i2o_send_data(&self, session_id: u32, mut data: Vec<u8>) -> anyhow::Result<()> {
@@ -230,21 +799,27 @@ impl Outlet {
             let data_len = data.len();
 
             data = session.common_info.decode_data(data)?;
+            // 入口发来的每块数据是一条完整的消息
+            data = session.common_info.length_prefix.prefix(data)?;
 
-            // 写入完毕回调
-            let output = self.output.clone();
-            let callback: SendMessageFuncType = Box::new(move || {
-                let output = output.clone();
-                Box::pin(async move {
-                    let _ = output
-                        .send(ProxyMessage::O2iSendDataResult(session_id, data_len))
-                        .await;
-                })
-            });
-
-            session
-                .sender
-                .send(WriterMessage::SendAndThen(data, callback))?;
+            match session.send_queue {
+                // 由会话的发送队列等待写入名额, 这里不等待
+                Some(ref send_queue) => {
+                    let _ = send_queue.send((data, data_len));
+                }
+                None => {
+                    let callback = send_result_callback(
+                        self.output.clone(),
+                        session_id,
+                        session.generation,
+                        data_len,
+                        None,
+                    );
+                    session
+                        .sender
+                        .send(session.common_info.write_message(data, callback))?;
+                }
+            }
         }
         Ok(())
     }
@@ -260,15 +835,24 @@ impl Outlet {
 
             data = session.common_info.decode_data(data)?;
 
-            let target_addr = common::parse_addr(&target_addr).await?;
-            session
-                .sender
-                .send(WriterMessage::SendTo(data, target_addr))?;
+            match self.data_ex.allowlist.check(&target_addr).await {
+                Ok(target_addr) => {
+                    let target_addr = common::parse_addr(&target_addr).await?;
+                    session
+                        .sender
+                        .send(WriterMessage::SendTo(data, target_addr))?;
+                }
+                Err(_) => debug!("session({session_id}) drop packet to {target_addr}: not allowed"),
+            }
 
             // 写入完毕回调
             let _ = self
                 .output
-                .send(ProxyMessage::O2iSendDataResult(session_id, data_len))
+                .send(ProxyMessage::O2iSendDataResult(
+                    session_id,
+                    session.generation,
+                    data_len,
+                ))
                 .await;
         }
         Ok(())
@@ -289,36 +873,87 @@ impl Outlet {
         data_len: usize,
     ) -> anyhow::Result<()> {
         if let Some(client) = self.session_info_map.read().await.get(&session_id) {
-            let mut read_buf_len = client.common_info.read_buf_len.write().await;
-            if *read_buf_len <= data_len {
-                *read_buf_len = 0;
-            } else {
-                *read_buf_len = *read_buf_len - data_len;
-            }
-            // trace!("on_i2o_recv_data_result: session_id:{session_id}, data_len:{data_len}, read_buf_len:{}", *read_buf_len);
-            drop(read_buf_len);
+            client.common_info.release_read_buf(data_len).await;
         }
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn tcp_connect(
         &self,
         addr: String,
+        client_addr: &str,
         session_id: u32,
+        generation: u32,
         common_info: SessionCommonInfo,
-    ) -> anyhow::Result<()> {
+        backend_tls: Option<(TlsConnector, ServerName)>,
+        use_pool: bool,
+    ) -> anyhow::Result<SocketAddr> {
         debug!("tcp_connect: {}", addr);
-        let stream = TcpStream::connect(&addr).await?;
-
-        // set tcp keepalive
-        let ka = TcpKeepalive::new().with_time(Duration::from_secs(30));
-        let sf = SockRef::from(&stream);
-        sf.set_tcp_keepalive(&ka)?;
+        // 推迟握手(TCP Fast Open)的连接在成功应答之后才可能失败, 再次应答失败让入口记录并关闭会话.
+        // 与会话结束的消息走同一个通道, 保证先于断开消息到达
+        let output = self.output.clone();
+        let on_connect_error = move |err: std::io::Error| {
+            error!("outlet session({session_id}) TCP fast open connect error: {err}");
+            let _ = output.try_send(ProxyMessage::O2iConnect(
+                session_id,
+                generation,
+                false,
+                format!("connect error: {err}"),
+                false,
+                String::new(),
+                0,
+            ));
+        };
+        // socks5的目标地址不固定, 不使用连接池
+        let (mut stream, addr) = match self.pool.as_ref().filter(|_| use_pool) {
+            Some(pool) => {
+                let stream = pool.take(&addr);
+                pool.refill(addr.clone(), self.data_ex.clone());
+                match stream {
+                    Some(stream) => {
+                        let peer_addr = stream.peer_addr()?;
+                        (Box::new(stream) as BoxedStream, peer_addr)
+                    }
+                    None => {
+                        self.data_ex
+                            .dial_session(&addr, session_id, on_connect_error)
+                            .await?
+                    }
+                }
+            }
+            None => {
+                self.data_ex
+                    .dial_session(&addr, session_id, on_connect_error)
+                    .await?
+            }
+        };
 
-        let addr = stream.peer_addr()?;
+        if self.data_ex.send_proxy_protocol {
+            // 入口无法取得客户端地址时(例如unix socket入口)发送UNKNOWN
+            let header = match client_addr.parse::<SocketAddr>() {
+                Ok(client_addr) if !client_addr.ip().is_unspecified() => {
+                    encode_v1_header(client_addr, addr)
+                }
+                _ => V1_UNKNOWN_HEADER.to_vec(),
+            };
+            stream.write_all(&header).await?;
+        }
+        let stream: BoxedStream = match backend_tls {
+            Some((connector, server_name)) => Box::new(
+                timeout(
+                    Duration::from_secs(BACKEND_TLS_TIMEOUT),
+                    connector.connect(server_name, stream),
+                )
+                .await
+                .map_err(|_| anyhow!("backend TLS handshake timeout"))??,
+            ),
+            None => stream,
+        };
         let output = self.output.clone();
         let session_info_map = self.session_info_map.clone();
         let shutdown = self.receiver_shutdown.resubscribe();
+        let send_timeout = (self.data_ex.send_timeout, self.data_ex.max_send_timeouts);
 
         tokio::spawn(async move {
             tcp_session::run(
@@ -326,9 +961,11 @@ impl Outlet {
                 addr,
                 Box::new(OutletSession::new(
                     session_info_map,
+                    generation,
                     common_info,
                     output,
                     InletProxyType::TCP,
+                    send_timeout,
                 )),
                 shutdown,
                 stream,
@@ -337,19 +974,24 @@ impl Outlet {
             trace!("tcp client stop, peer addr: {}", addr);
         });
 
-        Ok(())
+        Ok(addr)
     }
 
     async fn udp_connect(
         &self,
         addr: String,
         session_id: u32,
+        generation: u32,
         common_info: SessionCommonInfo,
         tunnel_type: InletProxyType,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<SocketAddr> {
         debug!("udp_connect: {}", addr);
         let any_addr = "0.0.0.0:0".parse::<SocketAddr>()?;
-        let socket = Arc::new(UdpSocket::bind(any_addr).await?);
+        let bind_addr = match self.data_ex.bind_addr {
+            Some(bind_addr) => SocketAddr::new(bind_addr, 0),
+            None => any_addr,
+        };
+        let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
 
         let addr = if addr.is_empty() {
             any_addr
@@ -361,6 +1003,7 @@ impl Outlet {
         let output = self.output.clone();
         let session_info_map = self.session_info_map.clone();
         let shutdown = self.receiver_shutdown.resubscribe();
+        let send_timeout = (self.data_ex.send_timeout, self.data_ex.max_send_timeouts);
 
         tokio::spawn(async move {
             udp_session::run(
@@ -368,9 +1011,11 @@ impl Outlet {
                 addr,
                 Box::new(OutletSession::new(
                     session_info_map,
+                    generation,
                     common_info,
                     output,
                     tunnel_type,
+                    send_timeout,
                 )),
                 None,
                 shutdown,
@@ -380,31 +1025,152 @@ impl Outlet {
             trace!("udp client stop, peer addr: {}", addr);
         });
 
-        Ok(())
+        Ok(addr)
+    }
+}
+
+/// 写入完毕回调, 回复入口数据已写入. 回调释放时归还发送队列的名额
+fn send_result_callback(
+    output: mpsc::Sender<ProxyMessage>,
+    session_id: u32,
+    generation: u32,
+    data_len: usize,
+    permit: Option<OwnedSemaphorePermit>,
+) -> SendMessageFuncType {
+    Box::new(move || {
+        let _ = &permit;
+        let output = output.clone();
+        Box::pin(async move {
+            let _ = output
+                .send(ProxyMessage::O2iSendDataResult(
+                    session_id, generation, data_len,
+                ))
+                .await;
+        })
+    })
+}
+
+/// 设置了发送超时的会话的发送队列, 按顺序把数据交给会话的写入任务
+///
+/// 等待写入的消息达到上限时在这里限时等待, 出口的消息处理不会被卡住的后端阻塞
+struct SendQueue {
+    session_info_map: SessionInfoMap,
+    session_id: u32,
+    generation: u32,
+    // TCP会话丢弃数据后内容不再完整, 第一次超时就关闭
+    is_tcp: bool,
+    sender: InputSenderType,
+    common_info: SessionCommonInfo,
+    output: mpsc::Sender<ProxyMessage>,
+    timeout: Duration,
+    max_timeouts: u32,
+}
+
+impl SendQueue {
+    async fn run(self, mut receiver: UnboundedReceiver<(Vec<u8>, usize)>) {
+        let permits = Arc::new(Semaphore::new(SEND_QUEUE_SIZE));
+        // 连续发送超时的次数
+        let mut timeouts = 0;
+        while let Some((data, data_len)) = receiver.recv().await {
+            match timeout(self.timeout, permits.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => {
+                    timeouts = 0;
+                    let callback = send_result_callback(
+                        self.output.clone(),
+                        self.session_id,
+                        self.generation,
+                        data_len,
+                        Some(permit),
+                    );
+                    let message = self.common_info.write_message(data, callback);
+                    if self.sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                _ => {
+                    timeouts += 1;
+                    warn!(
+                        "session({}) send to backend timeout({timeouts}), {data_len} bytes dropped",
+                        self.session_id
+                    );
+                    // 丢弃的数据同样确认, 入口才能正确统计等待确认的数据
+                    let _ = self
+                        .output
+                        .send(ProxyMessage::O2iSendDataResult(
+                            self.session_id,
+                            self.generation,
+                            data_len,
+                        ))
+                        .await;
+                    if self.is_tcp || timeouts >= self.max_timeouts {
+                        self.close().await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// 后端的写入可能一直卡住, 先删除会话, 不再转发它的数据
+    async fn close(&self) {
+        {
+            let mut session_info_map = self.session_info_map.write().await;
+            if session_info_map
+                .get(&self.session_id)
+                .is_some_and(|x| x.generation == self.generation)
+            {
+                session_info_map.remove(&self.session_id);
+            }
+        }
+        let _ = self.sender.send(WriterMessage::Close);
+        warn!("session({}) closed after send timeouts", self.session_id);
+        let _ = self
+            .output
+            .send(ProxyMessage::O2iDisconnect(
+                self.session_id,
+                self.generation,
+            ))
+            .await;
+    }
+}
+
+/// 回复给入口的后端地址, socks5的udp会话没有固定的后端, 为空
+fn backend_addr_string(addr: SocketAddr) -> String {
+    if addr.ip().is_unspecified() {
+        String::new()
+    } else {
+        addr.to_string()
     }
 }
 
 struct OutletSession {
     session_info_map: SessionInfoMap,
     session_id: u32,
+    generation: u32,
     common_data: SessionCommonInfo,
     output: mpsc::Sender<ProxyMessage>,
     tunnel_type: InletProxyType,
+    // 发送超时和UDP会话最多连续超时的次数, 超时为0时不限制
+    send_timeout: (Duration, u32),
 }
 
 impl OutletSession {
     fn new(
         session_info_map: SessionInfoMap,
+        generation: u32,
         common_data: SessionCommonInfo,
         output: mpsc::Sender<ProxyMessage>,
         tunnel_type: InletProxyType,
+        send_timeout: (Duration, u32),
     ) -> Self {
         Self {
             session_info_map,
             session_id: 0,
+            generation,
             common_data,
             output,
             tunnel_type,
+            send_timeout,
         }
     }
 }
@@ -419,17 +1185,44 @@ impl SessionDelegate for OutletSession {
     ) -> anyhow::Result<()> {
         trace!("outlet on session({session_id}) start {addr}");
         self.session_id = session_id;
+        let (timeout, max_timeouts) = self.send_timeout;
+        let send_queue = (!timeout.is_zero()).then(|| {
+            let (queue_tx, queue_rx) = mpsc::unbounded_channel();
+            let send_queue = SendQueue {
+                session_info_map: self.session_info_map.clone(),
+                session_id,
+                generation: self.generation,
+                is_tcp: self.tunnel_type.is_tcp(),
+                sender: tx.clone(),
+                common_info: self.common_data.clone(),
+                output: self.output.clone(),
+                timeout,
+                max_timeouts,
+            };
+            tokio::spawn(send_queue.run(queue_rx));
+            queue_tx
+        });
         self.session_info_map.write().await.insert(
             session_id,
             SessionInfo {
+                generation: self.generation,
                 sender: tx,
                 common_info: self.common_data.clone(),
+                send_queue,
             },
         );
 
         if let Err(err) = self
             .output
-            .send(ProxyMessage::O2iConnect(session_id, true, "".to_string()))
+            .send(ProxyMessage::O2iConnect(
+                session_id,
+                self.generation,
+                true,
+                "".to_string(),
+                !self.common_data.is_symmetric,
+                backend_addr_string(*addr),
+                0,
+            ))
             .await
         {
             tokio::time::sleep(Duration::from_secs(5)).await;
@@ -441,18 +1234,47 @@ impl SessionDelegate for OutletSession {
 
     async fn on_session_close(&mut self) -> anyhow::Result<()> {
         trace!("outlet on session({}) close", self.session_id);
-        self.session_info_map.write().await.remove(&self.session_id);
+        {
+            // 会话id可能已被新会话占用
+            let mut session_info_map = self.session_info_map.write().await;
+            if session_info_map
+                .get(&self.session_id)
+                .is_some_and(|x| x.generation == self.generation)
+            {
+                session_info_map.remove(&self.session_id);
+            }
+        }
         let _ = self
             .output
-            .send(ProxyMessage::O2iDisconnect(self.session_id))
+            .send(ProxyMessage::O2iDisconnect(
+                self.session_id,
+                self.generation,
+            ))
             .await;
         Ok(())
     }
 
+    async fn on_try_extract_frame(
+        &mut self,
+        buffer: &mut BytesMut,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        // 分帧时每条消息单独转发
+        if self.common_data.length_prefix.is_none() {
+            Ok(Some(buffer.split().to_vec()))
+        } else {
+            self.common_data.length_prefix.extract(buffer)
+        }
+    }
+
     async fn on_recv_frame(&mut self, mut frame: Vec<u8>) -> anyhow::Result<()> {
+        // 入口未确认的数据超过上限时在这里挂起, 暂停读取后端, 收到I2oRecvDataResult后恢复
         frame = self.common_data.encode_data_and_limiting(frame).await?;
         self.output
-            .send(ProxyMessage::O2iRecvData(self.session_id, frame))
+            .send(ProxyMessage::O2iRecvData(
+                self.session_id,
+                self.generation,
+                frame,
+            ))
             .await?;
         Ok(())
     }
@@ -467,6 +1289,7 @@ impl SessionDelegate for OutletSession {
             self.output
                 .send(ProxyMessage::O2iRecvDataFrom(
                     self.session_id,
+                    self.generation,
                     frame,
                     peer_addr.to_string(),
                 ))
@@ -477,3 +1300,277 @@ impl SessionDelegate for OutletSession {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::transport::CallbackTransport;
+    use crate::proxy::OutputFuncType;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test]
+    async fn test_backend_read_throttled_by_inlet_ack() {
+        // 后端一次写入大量数据
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let total = READ_BUF_MAX_LEN * 8;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.write_all(&vec![0u8; total]).await;
+            // 保持连接, 避免出口因后端断开而结束会话
+            std::future::pending::<()>().await;
+        });
+
+        let (tx, mut rx) = unbounded_channel();
+        let output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
+            let _ = tx.send(message);
+            Box::pin(async {})
+        });
+        let outlet = Outlet::new(
+            CallbackTransport::new(output),
+            "".into(),
+            OutletDataEx::new(),
+        );
+        outlet
+            .input(ProxyMessage::I2oConnect {
+                session_id: 1,
+                generation: 1,
+                tunnel_type: InletProxyType::TCP.to_u8(),
+                is_tcp: true,
+                is_compressed: false,
+                addr,
+                encryption_method: "None".into(),
+                encryption_key: "".into(),
+                client_addr: "127.0.0.1:1".into(),
+                o2i_codec: None,
+                write_coalesce: 0,
+                backend_tls: None,
+                compression_dictionary: "".into(),
+                metadata: HashMap::new(),
+                key_derivation: None,
+                compress_threshold: 0,
+            })
+            .await;
+
+        // 入口写得很慢, 一直不确认: 出口读到上限后停止读取后端
+        let mut unacked = 0;
+        while let Ok(Some(message)) = timeout(Duration::from_millis(300), rx.recv()).await {
+            match message {
+                ProxyMessage::O2iConnect(_, _, success, err, ..) => assert!(success, "{err}"),
+                ProxyMessage::O2iRecvData(_, _, data) => unacked += data.len(),
+                _ => {}
+            }
+        }
+        assert!(unacked > READ_BUF_MAX_LEN);
+        assert!(unacked < READ_BUF_MAX_LEN * 2);
+        let stats = outlet.backpressure_stats().await;
+        assert_eq!(stats.sessions[0].read_buf_len, unacked);
+        assert!(stats.sessions[0].stalled);
+
+        // 入口每写完一块就确认, 出口恢复读取直到收完所有数据
+        outlet
+            .input(ProxyMessage::I2oRecvDataResult(1, unacked))
+            .await;
+        let mut received = unacked;
+        while received < total {
+            let message = timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("outlet stalled after ack")
+                .unwrap();
+            if let ProxyMessage::O2iRecvData(_, _, data) = message {
+                received += data.len();
+                outlet
+                    .input(ProxyMessage::I2oRecvDataResult(1, data.len()))
+                    .await;
+            }
+        }
+        assert_eq!(received, total);
+        outlet.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_stuck_backend_closed_after_send_timeouts() {
+        // 后端从不读取, 出口的写入最终卡住
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let listener = socket.listen(1).unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let (tx, mut rx) = unbounded_channel();
+        let output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
+            let _ = tx.send(message);
+            Box::pin(async {})
+        });
+        let outlet = Outlet::new(
+            CallbackTransport::new(output),
+            "".into(),
+            OutletDataEx::new().set_send_timeout(Duration::from_millis(100), 2),
+        );
+        outlet
+            .input(ProxyMessage::I2oConnect {
+                session_id: 1,
+                generation: 1,
+                tunnel_type: InletProxyType::TCP.to_u8(),
+                is_tcp: true,
+                is_compressed: false,
+                addr,
+                encryption_method: "None".into(),
+                encryption_key: "".into(),
+                client_addr: "127.0.0.1:1".into(),
+                o2i_codec: None,
+                write_coalesce: 0,
+                backend_tls: None,
+                compression_dictionary: "".into(),
+                metadata: HashMap::new(),
+                key_derivation: None,
+                compress_threshold: 0,
+            })
+            .await;
+        let message = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert!(matches!(
+            message,
+            Some(ProxyMessage::O2iConnect(_, _, true, ..))
+        ));
+
+        const CHUNK: usize = 64 * 1024;
+        const COUNT: usize = 400;
+        for _ in 0..COUNT {
+            outlet
+                .input(ProxyMessage::I2oSendData(1, vec![0u8; CHUNK]))
+                .await;
+        }
+
+        // 卡住的会话被关闭, 已确认的数据不超过发送的数据
+        let mut acked = 0;
+        loop {
+            let message = timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .expect("stuck session was not closed")
+                .unwrap();
+            match message {
+                ProxyMessage::O2iSendDataResult(_, _, len) => acked += len,
+                ProxyMessage::O2iDisconnect(session_id, generation) => {
+                    assert_eq!((session_id, generation), (1, 1));
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert!(acked < CHUNK * COUNT);
+        assert!(outlet.backpressure_stats().await.sessions.is_empty());
+        outlet.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_stuck_backend_does_not_block_other_sessions() {
+        // 会话1的后端从不读取, 会话2的后端正常读取
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let stuck_addr = socket.local_addr().unwrap().to_string();
+        let listener = socket.listen(1).unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 64 * 1024];
+            while stream.read(&mut buf).await.is_ok_and(|x| x > 0) {}
+        });
+
+        let (tx, mut rx) = unbounded_channel();
+        let output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
+            let _ = tx.send(message);
+            Box::pin(async {})
+        });
+        let outlet = Outlet::new(
+            CallbackTransport::new(output),
+            "".into(),
+            OutletDataEx::new().set_send_timeout(Duration::from_secs(2), 3),
+        );
+        for (session_id, addr) in [(1, stuck_addr), (2, addr)] {
+            outlet
+                .input(ProxyMessage::I2oConnect {
+                    session_id,
+                    generation: 1,
+                    tunnel_type: InletProxyType::TCP.to_u8(),
+                    is_tcp: true,
+                    is_compressed: false,
+                    addr,
+                    encryption_method: "None".into(),
+                    encryption_key: "".into(),
+                    client_addr: "127.0.0.1:1".into(),
+                    o2i_codec: None,
+                    write_coalesce: 0,
+                    backend_tls: None,
+                    compression_dictionary: "".into(),
+                    metadata: HashMap::new(),
+                    key_derivation: None,
+                    compress_threshold: 0,
+                })
+                .await;
+            loop {
+                let message = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+                match message {
+                    Some(ProxyMessage::O2iConnect(id, _, success, ..)) if id == session_id => {
+                        assert!(success);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // 会话1的发送队列卡住后, 会话2的数据仍然立即写入
+        const CHUNK: usize = 64 * 1024;
+        const COUNT: usize = 400;
+        for _ in 0..COUNT {
+            outlet
+                .input(ProxyMessage::I2oSendData(1, vec![0u8; CHUNK]))
+                .await;
+        }
+        outlet
+            .input(ProxyMessage::I2oSendData(2, vec![0u8; CHUNK]))
+            .await;
+        let started = Instant::now();
+        loop {
+            let message = timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .expect("session 2 was blocked by session 1")
+                .unwrap();
+            if let ProxyMessage::O2iSendDataResult(2, _, len) = message {
+                assert_eq!(len, CHUNK);
+                break;
+            }
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // TCP会话第一次丢弃数据就关闭, 不会继续写入不完整的数据
+        let mut acked = 0;
+        loop {
+            let message = timeout(Duration::from_secs(10), rx.recv())
+                .await
+                .expect("stuck session was not closed")
+                .unwrap();
+            match message {
+                ProxyMessage::O2iSendDataResult(1, _, len) => acked += len,
+                ProxyMessage::O2iDisconnect(1, _) => break,
+                _ => {}
+            }
+        }
+        assert!(acked < CHUNK * COUNT);
+        let sessions = outlet.backpressure_stats().await.sessions;
+        assert_eq!(sessions.len(), 1);
+        outlet.stop().await;
+    }
+}
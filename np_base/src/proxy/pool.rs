@@ -0,0 +1,361 @@
+use crate::proxy::outlet::OutletDataEx;
+use crate::proxy::stats::ConnectionPoolStats;
+use log::{debug, trace};
+use socket2::SockRef;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// 连接池中连接默认的最长空闲时间
+pub const DEFAULT_POOL_MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// 所有出口连接池空闲连接总数的默认上限
+pub const DEFAULT_GLOBAL_POOL_LIMIT: usize = 1024;
+
+/// 进程内所有出口连接池共用的空闲连接上限
+static GLOBAL_POOL_REGISTRY: PoolRegistry = PoolRegistry::with_limit(DEFAULT_GLOBAL_POOL_LIMIT);
+
+/// 设置所有出口连接池的空闲连接总数上限, 超过时丢弃空闲最久的连接, 为0时不限制
+pub fn set_global_pool_limit(limit: usize) {
+    GLOBAL_POOL_REGISTRY.limit.store(limit, Ordering::Relaxed);
+    GLOBAL_POOL_REGISTRY.enforce_limit();
+}
+
+/// 所有出口连接池当前的空闲连接总数
+pub fn global_pool_idle() -> usize {
+    GLOBAL_POOL_REGISTRY.idle.load(Ordering::Relaxed)
+}
+
+/// 按放入顺序登记所有连接池的空闲连接, 总数超过上限时直接丢弃最早放入的连接
+pub(crate) struct PoolRegistry {
+    // 连接编号 -> (所属连接池, 地址), 编号按放入顺序递增
+    lru: Mutex<BTreeMap<u64, (Weak<ConnectionPool>, String)>>,
+    next_id: AtomicU64,
+    limit: AtomicUsize,
+    // 与lru中的连接数一致, 不加锁读取
+    idle: AtomicUsize,
+}
+
+impl PoolRegistry {
+    pub(crate) const fn with_limit(limit: usize) -> Self {
+        Self {
+            lru: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            limit: AtomicUsize::new(limit),
+            idle: AtomicUsize::new(0),
+        }
+    }
+
+    /// 登记放入连接池的连接, 返回连接编号
+    fn insert(&self, pool: &Arc<ConnectionPool>, addr: &str) -> u64 {
+        let mut lru = self.lru.lock().unwrap();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        lru.insert(id, (Arc::downgrade(pool), addr.to_string()));
+        self.idle.fetch_add(1, Ordering::Relaxed);
+        id
+    }
+
+    /// 连接离开了连接池
+    fn remove(&self, id: u64) {
+        if self.lru.lock().unwrap().remove(&id).is_some() {
+            self.idle.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        limit > 0 && self.idle.load(Ordering::Relaxed) > limit
+    }
+
+    /// 丢弃最早放入的连接直到不超过上限
+    fn enforce_limit(&self) {
+        while self.exceeded() {
+            let oldest = {
+                let mut lru = self.lru.lock().unwrap();
+                let oldest = lru.pop_first();
+                if oldest.is_some() {
+                    self.idle.fetch_sub(1, Ordering::Relaxed);
+                }
+                oldest
+            };
+            match oldest {
+                Some((id, (pool, addr))) => {
+                    if let Some(pool) = pool.upgrade() {
+                        pool.evict_lru(&addr, id);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// 出口连接池的设置
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionPoolConfig {
+    /// 每个后端地址预先建立的连接数, 为0时不使用连接池
+    pub size: usize,
+    /// 连接的最长空闲时间, 超过后丢弃
+    pub max_idle: Duration,
+}
+
+/// 连接池中的空闲连接
+struct IdleConnection {
+    stream: TcpStream,
+    // 放入连接池的时间
+    since: Instant,
+    // 在 [`PoolRegistry`] 中登记的编号
+    id: u64,
+}
+
+/// 出口到后端的预连接池
+///
+/// 按后端地址保存预先建立好的连接, 新会话直接取用, 取走后在后台补充.
+/// 池中的连接从未发送过数据, 交给会话时与新建的连接没有区别
+pub(crate) struct ConnectionPool {
+    config: ConnectionPoolConfig,
+    idle: Mutex<HashMap<String, VecDeque<IdleConnection>>>,
+    // 正在补充连接的地址, 避免同一地址重复补充
+    refilling: Mutex<HashSet<String>>,
+    closed: AtomicBool,
+    registry: &'static PoolRegistry,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evicted_stale: AtomicU64,
+    evicted_lru: AtomicU64,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(config: ConnectionPoolConfig) -> Arc<Self> {
+        Self::with_registry(config, &GLOBAL_POOL_REGISTRY)
+    }
+
+    fn with_registry(config: ConnectionPoolConfig, registry: &'static PoolRegistry) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            idle: Mutex::new(HashMap::new()),
+            refilling: Mutex::new(HashSet::new()),
+            closed: AtomicBool::new(false),
+            registry,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evicted_stale: AtomicU64::new(0),
+            evicted_lru: AtomicU64::new(0),
+        })
+    }
+
+    /// 超过全局上限, 丢弃编号为 `id` 的连接, 已从登记中移除. 期间连接已被取走时不处理
+    fn evict_lru(&self, addr: &str, id: u64) {
+        let mut idle = self.idle.lock().unwrap();
+        let Some(list) = idle.get_mut(addr) else {
+            return;
+        };
+        if let Some(index) = list.iter().position(|x| x.id == id) {
+            list.remove(index);
+            if list.is_empty() {
+                idle.remove(addr);
+            }
+            self.evicted_lru.fetch_add(1, Ordering::Relaxed);
+            trace!("connection pool: evicted least recently used connection to {addr}");
+        }
+    }
+
+    /// 取出一个可用的连接, 空闲过久或已被后端关闭的连接直接丢弃
+    pub(crate) fn take(&self, addr: &str) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        if let Some(list) = idle.get_mut(addr) {
+            while let Some(conn) = list.pop_front() {
+                self.registry.remove(conn.id);
+                if conn.since.elapsed() < self.config.max_idle && is_alive(&conn.stream) {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(conn.stream);
+                }
+                self.evicted_stale.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// 在后台把地址的空闲连接补充到设定数量
+    pub(crate) fn refill(self: &Arc<Self>, addr: String, data_ex: OutletDataEx) {
+        if self.closed.load(Ordering::Relaxed)
+            || !self.refilling.lock().unwrap().insert(addr.clone())
+        {
+            return;
+        }
+        let pool = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let count = pool.idle.lock().unwrap().get(&addr).map_or(0, |x| x.len());
+                if count >= pool.config.size || pool.closed.load(Ordering::Relaxed) {
+                    break;
+                }
+                match data_ex.dial(&addr).await {
+                    Ok(stream) => {
+                        trace!("connection pool: new connection to {addr}");
+                        {
+                            // 持有锁登记, 避免登记后放入前就被丢弃
+                            let mut idle = pool.idle.lock().unwrap();
+                            let id = pool.registry.insert(&pool, &addr);
+                            idle.entry(addr.clone())
+                                .or_default()
+                                .push_back(IdleConnection {
+                                    stream,
+                                    since: Instant::now(),
+                                    id,
+                                });
+                        }
+                        pool.registry.enforce_limit();
+                    }
+                    Err(err) => {
+                        // 后端不可用时不重试, 等下一个会话再补充
+                        debug!("connection pool: failed to connect to {addr}: {err}");
+                        break;
+                    }
+                }
+            }
+            pool.refilling.lock().unwrap().remove(&addr);
+        });
+    }
+
+    /// 丢弃空闲过久或已被后端关闭的连接
+    pub(crate) fn evict_stale(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        for list in idle.values_mut() {
+            let before = list.len();
+            list.retain(|conn| {
+                let keep = conn.since.elapsed() < self.config.max_idle && is_alive(&conn.stream);
+                if !keep {
+                    self.registry.remove(conn.id);
+                }
+                keep
+            });
+            self.evicted_stale
+                .fetch_add((before - list.len()) as u64, Ordering::Relaxed);
+        }
+        idle.retain(|_, list| !list.is_empty());
+    }
+
+    /// 关闭所有空闲连接, 之后不再补充
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let mut idle = self.idle.lock().unwrap();
+        for conn in idle.drain().flat_map(|(_, list)| list) {
+            self.registry.remove(conn.id);
+        }
+    }
+
+    pub(crate) fn max_idle(&self) -> Duration {
+        self.config.max_idle
+    }
+
+    pub(crate) fn stats(&self) -> ConnectionPoolStats {
+        ConnectionPoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evicted_stale: self.evicted_stale.load(Ordering::Relaxed),
+            evicted_lru: self.evicted_lru.load(Ordering::Relaxed),
+            idle: self.idle.lock().unwrap().values().map(|x| x.len()).sum(),
+        }
+    }
+}
+
+impl Drop for ConnectionPool {
+    fn drop(&mut self) {
+        for conn in self.idle.get_mut().unwrap().values().flatten() {
+            self.registry.remove(conn.id);
+        }
+    }
+}
+
+/// 不阻塞地检查连接是否还可用, 读到EOF或出错说明已被后端关闭
+///
+/// 使用peek, 后端主动发送的数据(例如欢迎信息)留在缓冲区中交给会话
+fn is_alive(stream: &TcpStream) -> bool {
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1];
+    match SockRef::from(stream).peek(&mut buf) {
+        Ok(len) => len > 0,
+        Err(err) => err.kind() == ErrorKind::WouldBlock,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connection_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let pool = ConnectionPool::new(ConnectionPoolConfig {
+            size: 2,
+            max_idle: Duration::from_secs(60),
+        });
+
+        assert!(pool.take(&addr).is_none());
+        pool.refill(addr.clone(), OutletDataEx::new());
+        let (server1, _) = listener.accept().await.unwrap();
+        let (_server2, _) = listener.accept().await.unwrap();
+        while pool.stats().idle < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // 后端关闭的连接被丢弃
+        drop(server1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        pool.evict_stale();
+        assert_eq!((pool.stats().evicted_stale, pool.stats().idle), (1, 1));
+
+        assert!(pool.take(&addr).is_some());
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.idle), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_global_pool_limit() {
+        let registry: &'static PoolRegistry = Box::leak(Box::new(PoolRegistry::with_limit(3)));
+        let config = ConnectionPoolConfig {
+            size: 2,
+            max_idle: Duration::from_secs(60),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.push(stream);
+            }
+        });
+
+        let first = ConnectionPool::with_registry(config.clone(), registry);
+        first.refill(addr.clone(), OutletDataEx::new());
+        while first.stats().idle < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // 另一个连接池的新连接超过全局上限, 丢弃空闲最久的连接
+        let second = ConnectionPool::with_registry(config, registry);
+        second.refill(addr.clone(), OutletDataEx::new());
+        while second.stats().idle < 2 {
+            tokio::task::yield_now().await;
+        }
+        let stats = first.stats();
+        assert_eq!((stats.evicted_lru, stats.idle), (1, 1));
+        assert_eq!(registry.idle.load(Ordering::Relaxed), 3);
+        assert_eq!(registry.lru.lock().unwrap().len(), 3);
+
+        // 连接池关闭后不再计入
+        first.close();
+        assert_eq!(registry.idle.load(Ordering::Relaxed), 2);
+        second.close();
+        assert_eq!(registry.idle.load(Ordering::Relaxed), 0);
+        assert!(registry.lru.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// v2头部签名
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// v1头部的最大长度(包含CRLF)
+const V1_MAX_LEN: usize = 107;
+
+/// PROXY协议头部解析结果
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ProxyHeader {
+    /// 数据不完整, 需要继续接收
+    Incomplete,
+    /// 头部格式错误
+    Invalid,
+    /// 解析成功, 头部长度和客户端地址(LOCAL/UNKNOWN时没有地址)
+    Parsed(usize, Option<SocketAddr>),
+}
+
+/// 解析HAProxy PROXY协议v1/v2头部
+pub(crate) fn parse_proxy_header(data: &[u8]) -> ProxyHeader {
+    let prefix_len = data.len().min(V2_SIGNATURE.len());
+    if data[..prefix_len] == V2_SIGNATURE[..prefix_len] {
+        return if prefix_len < V2_SIGNATURE.len() {
+            ProxyHeader::Incomplete
+        } else {
+            parse_v2(data)
+        };
+    }
+
+    let prefix_len = data.len().min(6);
+    if data[..prefix_len] == b"PROXY "[..prefix_len] {
+        return if prefix_len < 6 {
+            ProxyHeader::Incomplete
+        } else {
+            parse_v1(data)
+        };
+    }
+    ProxyHeader::Invalid
+}
+
+/// 生成PROXY协议v1头部, 出口连接后端后首先发送
+///
+/// [`src`] 客户端地址, [`dst`] 后端地址, 两者地址族不同时都按IPv6发送
+pub(crate) fn encode_v1_header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (family, src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => ("TCP4", src_ip.into(), dst_ip.into()),
+        (src_ip, dst_ip) => ("TCP6", to_ipv6(src_ip), to_ipv6(dst_ip)),
+    };
+    format!(
+        "PROXY {family} {src_ip} {dst_ip} {} {}\r\n",
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
+
+/// 地址未知时发送的v1头部, 后端使用连接的真实地址
+pub(crate) const V1_UNKNOWN_HEADER: &[u8] = b"PROXY UNKNOWN\r\n";
+
+fn to_ipv6(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+        ip => ip,
+    }
+}
+
+fn parse_v1(data: &[u8]) -> ProxyHeader {
+    let Some(end) = data.windows(2).position(|x| x == b"\r\n") else {
+        return if data.len() < V1_MAX_LEN {
+            ProxyHeader::Incomplete
+        } else {
+            ProxyHeader::Invalid
+        };
+    };
+    if end + 2 > V1_MAX_LEN {
+        return ProxyHeader::Invalid;
+    }
+
+    let Ok(line) = std::str::from_utf8(&data[..end]) else {
+        return ProxyHeader::Invalid;
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => ProxyHeader::Parsed(end + 2, None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip = match (*family, src.parse::<IpAddr>()) {
+                ("TCP4", Ok(ip @ IpAddr::V4(_))) | ("TCP6", Ok(ip @ IpAddr::V6(_))) => ip,
+                _ => return ProxyHeader::Invalid,
+            };
+            match src_port.parse::<u16>() {
+                Ok(port) => ProxyHeader::Parsed(end + 2, Some(SocketAddr::new(ip, port))),
+                Err(_) => ProxyHeader::Invalid,
+            }
+        }
+        _ => ProxyHeader::Invalid,
+    }
+}
+
+fn parse_v2(data: &[u8]) -> ProxyHeader {
+    // 签名(12) 版本和命令(1) 地址族和协议(1) 地址长度(2)
+    if data.len() < 16 {
+        return ProxyHeader::Incomplete;
+    }
+    let version_command = data[12];
+    if version_command >> 4 != 2 {
+        return ProxyHeader::Invalid;
+    }
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    if data.len() < 16 + len {
+        return ProxyHeader::Incomplete;
+    }
+    let addr = &data[16..16 + len];
+
+    match version_command & 0x0F {
+        // LOCAL: 代理自身发起的连接(例如健康检查), 使用连接的真实地址
+        0x00 => ProxyHeader::Parsed(16 + len, None),
+        // PROXY
+        0x01 => {
+            let client = match data[13] >> 4 {
+                // AF_INET
+                0x1 if len >= 12 => {
+                    let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+                    let port = u16::from_be_bytes([addr[8], addr[9]]);
+                    Some(SocketAddr::new(IpAddr::V4(ip), port))
+                }
+                // AF_INET6
+                0x2 if len >= 36 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&addr[..16]);
+                    let port = u16::from_be_bytes([addr[32], addr[33]]);
+                    Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+                }
+                // AF_UNSPEC
+                0x0 => None,
+                _ => return ProxyHeader::Invalid,
+            };
+            ProxyHeader::Parsed(16 + len, client)
+        }
+        _ => ProxyHeader::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proxy_header() {
+        let v1 = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /";
+        assert_eq!(
+            parse_proxy_header(v1),
+            ProxyHeader::Parsed(47, Some("192.168.0.1:56324".parse().unwrap()))
+        );
+        assert_eq!(parse_proxy_header(&v1[..20]), ProxyHeader::Incomplete);
+        assert_eq!(parse_proxy_header(b"PRO"), ProxyHeader::Incomplete);
+        assert_eq!(
+            parse_proxy_header(b"PROXY UNKNOWN\r\n"),
+            ProxyHeader::Parsed(15, None)
+        );
+        assert_eq!(
+            parse_proxy_header(b"PROXY TCP4 ::1 ::1 1 2\r\n"),
+            ProxyHeader::Invalid
+        );
+        assert_eq!(
+            parse_proxy_header(b"GET / HTTP/1.1\r\n"),
+            ProxyHeader::Invalid
+        );
+
+        // 生成的头部可以被解析回原地址
+        let src = "192.168.0.1:56324".parse().unwrap();
+        let header = encode_v1_header(src, "10.0.0.2:80".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 80\r\n");
+        assert_eq!(
+            parse_proxy_header(&header),
+            ProxyHeader::Parsed(header.len(), Some(src))
+        );
+        let header = encode_v1_header(src, "[::1]:80".parse().unwrap());
+        assert_eq!(
+            parse_proxy_header(&header),
+            ProxyHeader::Parsed(
+                header.len(),
+                Some("[::ffff:192.168.0.1]:56324".parse().unwrap())
+            )
+        );
+        assert_eq!(
+            parse_proxy_header(V1_UNKNOWN_HEADER),
+            ProxyHeader::Parsed(V1_UNKNOWN_HEADER.len(), None)
+        );
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        v2.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x01, 0xBB]);
+        v2.extend_from_slice(b"payload");
+        assert_eq!(
+            parse_proxy_header(&v2),
+            ProxyHeader::Parsed(28, Some("10.0.0.1:8080".parse().unwrap()))
+        );
+        assert_eq!(parse_proxy_header(&v2[..20]), ProxyHeader::Incomplete);
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(parse_proxy_header(&local), ProxyHeader::Parsed(16, None));
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// 令牌桶, 限制每秒接受的新连接数
+pub(crate) struct RateLimiter {
+    inner: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // 每秒补充的令牌数, 为0时不限制
+    rate: f64,
+    // 桶容量, 允许的突发连接数
+    burst: f64,
+    // 当前令牌数
+    tokens: f64,
+    // 上次补充的时间
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// [`rate`] 每秒允许的新连接数, 为0时不限制
+    ///
+    /// [`burst`] 允许的突发连接数, 为0时与 `rate` 相同
+    pub(crate) fn new(rate: u32, burst: u32) -> Self {
+        let burst = if burst == 0 { rate } else { burst } as f64;
+        Self {
+            inner: Mutex::new(Bucket {
+                rate: rate as f64,
+                burst,
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// 运行中修改限制, 已有的令牌数不超过新的桶容量
+    pub(crate) fn set_rate(&self, rate: u32, burst: u32) {
+        let burst = if burst == 0 { rate } else { burst } as f64;
+        let mut bucket = self.inner.lock().unwrap();
+        if bucket.rate == 0.0 {
+            // 原来不限制时从满桶开始
+            bucket.tokens = burst;
+        }
+        bucket.rate = rate as f64;
+        bucket.burst = burst;
+        bucket.tokens = bucket.tokens.min(burst);
+    }
+
+    /// 令牌是否已经补满, 补满的限制器与新建的没有区别, 可以释放
+    pub(crate) fn is_full(&self) -> bool {
+        let bucket = self.inner.lock().unwrap();
+        if bucket.rate == 0.0 {
+            return true;
+        }
+        let elapsed = bucket.refilled_at.elapsed().as_secs_f64();
+        bucket.tokens + elapsed * bucket.rate >= bucket.burst
+    }
+
+    /// 取一个令牌, 没有令牌时返回false
+    pub(crate) fn allow(&self) -> bool {
+        let mut bucket = self.inner.lock().unwrap();
+        if bucket.rate == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let unlimited = RateLimiter::new(0, 0);
+        assert!((0..1000).all(|_| unlimited.allow()));
+
+        // 突发额度用完后拒绝, 等待补充后恢复
+        let limiter = RateLimiter::new(10, 3);
+        assert_eq!((0..10).filter(|_| limiter.allow()).count(), 3);
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+
+        // 运行中修改限制
+        limiter.set_rate(0, 0);
+        assert!((0..100).all(|_| limiter.allow()));
+        limiter.set_rate(10, 2);
+        assert_eq!((0..10).filter(|_| limiter.allow()).count(), 2);
+    }
+}
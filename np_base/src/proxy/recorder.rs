@@ -0,0 +1,494 @@
+use crate::proxy::inlet::Inlet;
+use crate::proxy::ProxyMessage;
+use anyhow::anyhow;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// 消息相对入口的方向
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// 出口发给入口
+    Input,
+    /// 入口发给出口
+    Output,
+}
+
+/// 只记录摘要时数据的原始长度和哈希值(sha256的前8字节)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadDigest {
+    pub len: usize,
+    pub sha256: String,
+}
+
+/// 录制文件中的一条消息, 每行一条JSON
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// 距开始录制的时间(微秒)
+    pub elapsed: u64,
+    pub direction: Direction,
+    /// 加密密码已清空, 只记录摘要时数据也已清空
+    pub message: ProxyMessage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<PayloadDigest>,
+}
+
+impl RecordedMessage {
+    /// 还原为可以输入的消息, 只记录了摘要的数据以同样长度的0填充
+    pub fn to_message(&self) -> ProxyMessage {
+        let mut message = self.message.clone();
+        if let (Some(digest), Some(payload)) = (&self.digest, payload_mut(&mut message)) {
+            *payload = vec![0; digest.len];
+        }
+        message
+    }
+}
+
+/// 把流经入口的消息按顺序写入文件, 用于排查问题和 [`MessagePlayer`] 回放
+///
+/// 默认只记录数据的长度和哈希值, 加密密码总是不记录
+pub struct MessageRecorder {
+    writer: Mutex<BufWriter<File>>,
+    start: Instant,
+    full_payload: bool,
+    // 写入失败只记录一次日志
+    failed: AtomicBool,
+}
+
+impl MessageRecorder {
+    /// 创建录制文件, `full_payload` 为true时记录完整数据(base64编码)
+    pub fn create(path: &Path, full_payload: bool) -> anyhow::Result<Arc<Self>> {
+        let file = File::create(path)
+            .map_err(|err| anyhow!("failed to create record file {}: {err}", path.display()))?;
+        Ok(Arc::new(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+            start: Instant::now(),
+            full_payload,
+            failed: AtomicBool::new(false),
+        }))
+    }
+
+    /// 在目录中为通道创建录制文件, 文件名为 `tunnel-通道id-unix时间戳.jsonl`
+    pub fn create_for_tunnel(
+        dir: &str,
+        tunnel_id: u32,
+        full_payload: bool,
+    ) -> anyhow::Result<Arc<Self>> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        std::fs::create_dir_all(dir)?;
+        let path = Path::new(dir).join(format!("tunnel-{tunnel_id}-{timestamp}.jsonl"));
+        let recorder = Self::create(&path, full_payload)?;
+        info!(
+            "tunnel({tunnel_id}) recording messages to {}",
+            path.display()
+        );
+        Ok(recorder)
+    }
+
+    pub fn record(&self, direction: Direction, message: &ProxyMessage) {
+        let mut message = message.clone();
+        if let ProxyMessage::I2oConnect {
+            encryption_key,
+            o2i_codec,
+            ..
+        } = &mut message
+        {
+            encryption_key.clear();
+            if let Some((_, _, o2i_encryption_key)) = o2i_codec {
+                o2i_encryption_key.clear();
+            }
+        }
+        let digest = match payload_mut(&mut message) {
+            Some(payload) if !self.full_payload => {
+                let hash = Sha256::digest(&payload);
+                let digest = PayloadDigest {
+                    len: payload.len(),
+                    sha256: hash[..8].iter().map(|x| format!("{x:02x}")).collect(),
+                };
+                payload.clear();
+                Some(digest)
+            }
+            _ => None,
+        };
+        let record = RecordedMessage {
+            elapsed: self.start.elapsed().as_micros() as u64,
+            direction,
+            message,
+            digest,
+        };
+
+        let result = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut writer = self.writer.lock().unwrap();
+                writeln!(writer, "{line}")?;
+                writer.flush()?;
+                Ok(())
+            });
+        if let Err(err) = result {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                error!("failed to write message record: {err}");
+            }
+        }
+    }
+}
+
+/// 读取录制文件
+pub fn load(path: &Path) -> anyhow::Result<Vec<RecordedMessage>> {
+    let file = File::open(path)
+        .map_err(|err| anyhow!("failed to open record file {}: {err}", path.display()))?;
+    let mut records = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(
+            serde_json::from_str(&line)
+                .map_err(|err| anyhow!("invalid record at line {}: {err}", index + 1))?,
+        );
+    }
+    Ok(records)
+}
+
+/// 按录制的顺序回放出口发给入口的消息
+///
+/// 回放器代替出口: 入口每产生一条输出, 先与录制中的下一条输出比较(消息类型和会话id),
+/// 一致时再把录制中紧随其后的输入依次交给入口, 使输入与输出的交错顺序与录制时相同.
+/// 客户端一侧的连接和数据需要由调用方按录制时的方式重新发起.
+/// 只记录了摘要的数据以0填充, 因此回放数据内容时需要录制完整数据并且不加密
+pub struct MessagePlayer {
+    records: VecDeque<RecordedMessage>,
+    // 会话id对应的回放时的会话代数, 入口每次启动时随机生成代数起始值
+    generations: HashMap<u32, u32>,
+}
+
+impl MessagePlayer {
+    pub fn new(records: Vec<RecordedMessage>) -> Self {
+        Self {
+            records: records.into(),
+            generations: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(load(path)?))
+    }
+
+    /// 所有录制的消息都已回放
+    pub fn is_finished(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// 取出下一条输出之前的所有输入
+    pub fn next_inputs(&mut self) -> Vec<ProxyMessage> {
+        let mut inputs = Vec::new();
+        while let Some(record) = self.records.front() {
+            if record.direction != Direction::Input {
+                break;
+            }
+            let mut message = record.to_message();
+            self.records.pop_front();
+            if let Some((session_id, generation)) = generation_mut(&mut message) {
+                if *generation != 0 {
+                    if let Some(actual) = self.generations.get(&session_id) {
+                        *generation = *actual;
+                    }
+                }
+            }
+            inputs.push(message);
+        }
+        inputs
+    }
+
+    /// 收到入口的一条输出, 与录制不一致时返回错误
+    pub fn on_output(&mut self, message: &ProxyMessage) -> anyhow::Result<()> {
+        let Some(record) = self.records.pop_front() else {
+            return Err(anyhow!(
+                "unexpected output after replay: {}",
+                summary(message)
+            ));
+        };
+        let expected = &record.message;
+        if record.direction != Direction::Output
+            || kind(expected) != kind(message)
+            || expected.session_id() != message.session_id()
+        {
+            return Err(anyhow!(
+                "replay diverged at {}us: expected {}, got {}",
+                record.elapsed,
+                summary(expected),
+                summary(message)
+            ));
+        }
+        if let ProxyMessage::I2oConnect {
+            session_id,
+            generation,
+            ..
+        } = message
+        {
+            self.generations.insert(*session_id, *generation);
+        }
+        Ok(())
+    }
+
+    /// 驱动回放直到结束, `output` 接收入口的输出
+    pub async fn run(
+        mut self,
+        mut output: UnboundedReceiver<ProxyMessage>,
+        inlet: &Inlet,
+    ) -> anyhow::Result<()> {
+        loop {
+            for message in self.next_inputs() {
+                inlet.input(message).await;
+            }
+            if self.is_finished() {
+                return Ok(());
+            }
+            let message = output
+                .recv()
+                .await
+                .ok_or_else(|| anyhow!("inlet output closed"))?;
+            self.on_output(&message)?;
+        }
+    }
+}
+
+/// 消息中的数据
+fn payload_mut(message: &mut ProxyMessage) -> Option<&mut Vec<u8>> {
+    match message {
+        ProxyMessage::I2oSendData(_, data)
+        | ProxyMessage::I2oSendToData(_, data, _)
+        | ProxyMessage::O2iRecvDataFrom(_, _, data, _)
+        | ProxyMessage::O2iRecvData(_, _, data) => Some(data),
+        _ => None,
+    }
+}
+
+/// 出口发给入口的消息中的会话id和会话代数
+fn generation_mut(message: &mut ProxyMessage) -> Option<(u32, &mut u32)> {
+    match message {
+        ProxyMessage::O2iConnect(session_id, generation, ..)
+        | ProxyMessage::O2iSendDataResult(session_id, generation, _)
+        | ProxyMessage::O2iRecvDataFrom(session_id, generation, ..)
+        | ProxyMessage::O2iRecvData(session_id, generation, _)
+        | ProxyMessage::O2iDisconnect(session_id, generation) => Some((*session_id, generation)),
+        _ => None,
+    }
+}
+
+fn kind(message: &ProxyMessage) -> &'static str {
+    match message {
+        ProxyMessage::I2oConnect { .. } => "I2oConnect",
+        ProxyMessage::O2iConnect(..) => "O2iConnect",
+        ProxyMessage::I2oSendData(..) => "I2oSendData",
+        ProxyMessage::I2oSendToData(..) => "I2oSendToData",
+        ProxyMessage::O2iSendDataResult(..) => "O2iSendDataResult",
+        ProxyMessage::O2iRecvDataFrom(..) => "O2iRecvDataFrom",
+        ProxyMessage::O2iRecvData(..) => "O2iRecvData",
+        ProxyMessage::I2oRecvDataResult(..) => "I2oRecvDataResult",
+        ProxyMessage::I2oDisconnect(..) => "I2oDisconnect",
+        ProxyMessage::O2iDisconnect(..) => "O2iDisconnect",
+        ProxyMessage::I2oOpenStream(..) => "I2oOpenStream",
+        ProxyMessage::I2oProbe(..) => "I2oProbe",
+        ProxyMessage::O2iProbe(..) => "O2iProbe",
+    }
+}
+
+/// 用于日志的消息概要, 不含数据内容
+fn summary(message: &ProxyMessage) -> String {
+    match message {
+        ProxyMessage::I2oSendData(_, data)
+        | ProxyMessage::I2oSendToData(_, data, _)
+        | ProxyMessage::O2iRecvDataFrom(_, _, data, _)
+        | ProxyMessage::O2iRecvData(_, _, data) => format!(
+            "{}(session:{}, len:{})",
+            kind(message),
+            message.session_id(),
+            data.len()
+        ),
+        _ => format!("{}(session:{})", kind(message), message.session_id()),
+    }
+}
+
+/// 数据字段以base64字符串序列化
+pub(crate) mod base64_bytes {
+    use base64::prelude::*;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64_STANDARD
+            .decode(encoded)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::inlet::{InletDataEx, InletProxyType};
+    use crate::proxy::transport::CallbackTransport;
+    use crate::proxy::OutputFuncType;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn free_addr() -> String {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        format!("127.0.0.1:{port}")
+    }
+
+    async fn start_inlet(
+        listen_addr: &str,
+        recorder: Option<Arc<MessageRecorder>>,
+    ) -> (Arc<Inlet>, UnboundedReceiver<ProxyMessage>) {
+        let (tx, rx) = unbounded_channel();
+        let output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
+            let _ = tx.send(message);
+            Box::pin(async {})
+        });
+        let mut inlet = Inlet::new(CallbackTransport::new(output), "".into());
+        inlet
+            .start(
+                InletProxyType::TCP,
+                listen_addr.into(),
+                "mock:80".into(),
+                false,
+                "None".into(),
+                InletDataEx::new("".into(), "".into()).set_recorder(recorder),
+            )
+            .await
+            .unwrap();
+        (Arc::new(inlet), rx)
+    }
+
+    /// 客户端发送数据并读取回复后断开
+    async fn run_client(listen_addr: &str) -> Vec<u8> {
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut reply = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+            .await
+            .expect("timeout waiting for reply")
+            .unwrap();
+        reply.to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("npipe-record-{}.jsonl", std::process::id()));
+
+        // 录制: 模拟出口原样返回收到的数据
+        let listen_addr = free_addr();
+        let recorder = MessageRecorder::create(&path, true).unwrap();
+        let (inlet, mut rx) = start_inlet(&listen_addr, Some(recorder)).await;
+        let echo_inlet = inlet.clone();
+        let outlet_task = tokio::spawn(async move {
+            let mut generation = 0;
+            while let Some(message) = rx.recv().await {
+                match message {
+                    ProxyMessage::I2oConnect {
+                        session_id,
+                        generation: g,
+                        ..
+                    } => {
+                        generation = g;
+                        echo_inlet
+                            .input(ProxyMessage::O2iConnect(
+                                session_id,
+                                g,
+                                true,
+                                "".into(),
+                                false,
+                                "".into(),
+                                0,
+                            ))
+                            .await;
+                    }
+                    ProxyMessage::I2oSendData(session_id, data) => {
+                        echo_inlet
+                            .input(ProxyMessage::O2iSendDataResult(
+                                session_id,
+                                generation,
+                                data.len(),
+                            ))
+                            .await;
+                        echo_inlet
+                            .input(ProxyMessage::O2iRecvData(session_id, generation, data))
+                            .await;
+                    }
+                    ProxyMessage::I2oDisconnect(_) => break,
+                    _ => {}
+                }
+            }
+        });
+        assert_eq!(run_client(&listen_addr).await, b"hello");
+        tokio::time::timeout(Duration::from_secs(5), outlet_task)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let records = load(&path).unwrap();
+        assert!(matches!(
+            records[0],
+            RecordedMessage {
+                direction: Direction::Output,
+                message: ProxyMessage::I2oConnect { .. },
+                ..
+            }
+        ));
+        assert!(records.iter().any(|x| matches!(
+            &x.message,
+            ProxyMessage::O2iRecvData(_, _, data) if data == b"hello"
+        )));
+
+        // 回放: 没有出口, 回复来自录制的消息
+        let listen_addr = free_addr();
+        let (inlet, rx) = start_inlet(&listen_addr, None).await;
+        let player = MessagePlayer::new(records);
+        let player_task = tokio::spawn(async move { player.run(rx, &inlet).await });
+        assert_eq!(run_client(&listen_addr).await, b"hello");
+        tokio::time::timeout(Duration::from_secs(5), player_task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // 只记录摘要时数据以0填充
+        let recorder = MessageRecorder::create(&path, false).unwrap();
+        recorder.record(
+            Direction::Output,
+            &ProxyMessage::I2oSendData(1, b"abc".to_vec()),
+        );
+        drop(recorder);
+        let records = load(&path).unwrap();
+        assert_eq!(records[0].digest.as_ref().unwrap().len, 3);
+        assert!(matches!(
+            records[0].to_message(),
+            ProxyMessage::I2oSendData(1, data) if data == [0, 0, 0]
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::anyhow;
+use bytes::{Buf, BytesMut};
+use std::fmt::{Display, Formatter};
+
+/// 入口拒绝新连接的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// 通道已暂停
+    Paused,
+    /// 通道流量超过配额
+    QuotaExceeded,
+    /// 超过新建连接速率限制
+    RateLimited,
+    /// 熔断器打开, 出口暂时无法连接后端
+    CircuitOpen,
+}
+
+impl RejectReason {
+    /// HTTP入口回复的默认状态码
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::Paused | Self::CircuitOpen => 503,
+            Self::QuotaExceeded | Self::RateLimited => 429,
+        }
+    }
+
+    /// SOCKS5入口回复的应答码
+    pub fn socks5_reply(&self) -> u8 {
+        match self {
+            // connection not allowed by ruleset
+            Self::Paused | Self::QuotaExceeded => 0x02,
+            // general SOCKS server failure
+            Self::RateLimited => 0x01,
+            // host unreachable
+            Self::CircuitOpen => 0x04,
+        }
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Paused => "tunnel paused",
+            Self::QuotaExceeded => "traffic quota exceeded",
+            Self::RateLimited => "connection rate limit exceeded",
+            Self::CircuitOpen => "backend unavailable",
+        };
+        f.write_str(text)
+    }
+}
+
+/// 拒绝新连接时回复给客户端的内容, 未设置时直接断开
+///
+/// 配置格式为 `[状态码] [正文]`, 状态码省略时按拒绝原因选择, 正文中的 `{reason}` 替换为拒绝原因.
+/// HTTP入口回复完整的HTTP响应, SOCKS5入口完成握手后回复原因对应的应答码, 其他入口直接断开
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RejectResponse {
+    enabled: bool,
+    status: Option<u16>,
+    body: String,
+}
+
+impl RejectResponse {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(Self::default());
+        }
+        let (first, rest) = value.split_once(' ').unwrap_or((value, ""));
+        let (status, body) = if first.len() == 3 && first.bytes().all(|x| x.is_ascii_digit()) {
+            let status: u16 = first.parse()?;
+            if !(100..=599).contains(&status) {
+                return Err(anyhow!("invalid reject response status: {status}"));
+            }
+            (Some(status), rest.trim_start())
+        } else {
+            (None, value)
+        };
+        Ok(Self {
+            enabled: true,
+            status,
+            body: body.to_string(),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// 生成HTTP响应
+    pub(crate) fn http_response(&self, reason: RejectReason) -> Vec<u8> {
+        let status = self.status.unwrap_or(reason.http_status());
+        let body = if self.body.is_empty() {
+            format!("{reason}\n")
+        } else {
+            self.body.replace("{reason}", &reason.to_string())
+        };
+        format!(
+            "HTTP/1.1 {status} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            status_text(status),
+            body.len()
+        )
+        .into_bytes()
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Rejected",
+    }
+}
+
+/// SOCKS5握手进行到的阶段
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Socks5Stage {
+    Greeting,
+    Auth,
+    Request,
+    Done,
+}
+
+/// 拒绝SOCKS5连接: 按客户端提供的认证方式完成握手(不校验密码), 收到请求后回复原因对应的应答码
+///
+/// 直接断开时客户端只能报告连接被关闭, 回复应答码后客户端可以显示具体的失败原因
+pub(crate) struct Socks5Rejection {
+    reason: RejectReason,
+    stage: Socks5Stage,
+}
+
+impl Socks5Rejection {
+    pub fn new(reason: RejectReason) -> Self {
+        Self {
+            reason,
+            stage: Socks5Stage::Greeting,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stage == Socks5Stage::Done
+    }
+
+    /// 处理客户端发来的数据, 返回需要回复的内容, 数据不完整时返回 `Ok(None)`
+    pub fn on_data(&mut self, buffer: &mut BytesMut) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.stage {
+            Socks5Stage::Greeting => {
+                // VER NMETHODS METHODS
+                if buffer.len() < 2 {
+                    return Ok(None);
+                }
+                if buffer[0] != 0x05 {
+                    return Err(anyhow!("invalid socks5 version: {}", buffer[0]));
+                }
+                let len = 2 + buffer[1] as usize;
+                if buffer.len() < len {
+                    return Ok(None);
+                }
+                let methods = buffer.split_to(len);
+                let method = if methods[2..].contains(&0x00) {
+                    self.stage = Socks5Stage::Request;
+                    0x00
+                } else if methods[2..].contains(&0x02) {
+                    self.stage = Socks5Stage::Auth;
+                    0x02
+                } else {
+                    self.stage = Socks5Stage::Done;
+                    0xff
+                };
+                Ok(Some(vec![0x05, method]))
+            }
+            Socks5Stage::Auth => {
+                // VER ULEN UNAME PLEN PASSWD
+                if buffer.len() < 2 {
+                    return Ok(None);
+                }
+                let ulen = buffer[1] as usize;
+                if buffer.len() < 3 + ulen {
+                    return Ok(None);
+                }
+                let len = 3 + ulen + buffer[2 + ulen] as usize;
+                if buffer.len() < len {
+                    return Ok(None);
+                }
+                buffer.advance(len);
+                self.stage = Socks5Stage::Request;
+                Ok(Some(vec![0x01, 0x00]))
+            }
+            Socks5Stage::Request => {
+                // 不需要解析目标地址, 收到请求即可回复
+                if buffer.len() < 2 {
+                    return Ok(None);
+                }
+                buffer.clear();
+                self.stage = Socks5Stage::Done;
+                Ok(Some(vec![
+                    0x05,
+                    self.reason.socks5_reply(),
+                    0x00,
+                    0x01,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                ]))
+            }
+            Socks5Stage::Done => {
+                buffer.clear();
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reject_response() {
+        assert!(!RejectResponse::parse(" ").unwrap().is_enabled());
+        assert!(RejectResponse::parse("999 too large").is_err());
+
+        let response = RejectResponse::parse("try again later: {reason}").unwrap();
+        let http = String::from_utf8(response.http_response(RejectReason::RateLimited)).unwrap();
+        assert!(http.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+        assert!(http.ends_with("\r\n\r\ntry again later: connection rate limit exceeded"));
+
+        let response = RejectResponse::parse("503").unwrap();
+        let http = String::from_utf8(response.http_response(RejectReason::QuotaExceeded)).unwrap();
+        assert!(http.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(http.ends_with("\r\n\r\ntraffic quota exceeded\n"));
+    }
+
+    #[test]
+    fn test_socks5_rejection() {
+        let mut rejection = Socks5Rejection::new(RejectReason::Paused);
+        // 只提供用户名密码认证, 认证请求分两次到达
+        let mut buffer = BytesMut::from(&[0x05, 0x01, 0x02, 0x01, 0x01][..]);
+        assert_eq!(
+            rejection.on_data(&mut buffer).unwrap(),
+            Some(vec![0x05, 0x02])
+        );
+        assert_eq!(rejection.on_data(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&[b'u', 0x01, b'p']);
+        assert_eq!(
+            rejection.on_data(&mut buffer).unwrap(),
+            Some(vec![0x01, 0x00])
+        );
+        buffer.extend_from_slice(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80]);
+        let reply = rejection.on_data(&mut buffer).unwrap().unwrap();
+        assert_eq!(reply[1], 0x02);
+        assert!(rejection.is_done());
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::proxy::common::connect_from;
+use crate::proxy::socks5::target_addr::{read_address, TargetAddr, ToTargetAddr};
+use crate::proxy::socks5::{
+    SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE, SOCKS5_AUTH_METHOD_PASSWORD,
+    SOCKS5_CMD_TCP_CONNECT, SOCKS5_VERSION,
+};
+use anyhow::anyhow;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// 上游socks5代理
+#[derive(Clone, Debug)]
+pub struct Socks5Upstream {
+    pub addr: String,
+    pub username: String,
+    pub password: String,
+}
+
+/// 通过上游socks5代理连接目标地址
+pub async fn connect(
+    upstream: &Socks5Upstream,
+    target: &str,
+    bind_addr: Option<IpAddr>,
+) -> anyhow::Result<TcpStream> {
+    let target_addr = parse_target(target)?;
+    let stream = match bind_addr {
+        Some(bind_addr) => connect_from(bind_addr, &upstream.addr).await,
+        None => TcpStream::connect(&upstream.addr).await.map_err(Into::into),
+    };
+    let mut stream = stream
+        .map_err(|err| anyhow!("upstream socks5 proxy {} unreachable: {err}", upstream.addr))?;
+
+    handshake(&mut stream, upstream, &target_addr)
+        .await
+        .map_err(|err| anyhow!("upstream socks5 proxy {}: {err}", upstream.addr))?;
+    Ok(stream)
+}
+
+async fn handshake(
+    stream: &mut TcpStream,
+    upstream: &Socks5Upstream,
+    target_addr: &TargetAddr,
+) -> anyhow::Result<()> {
+    let use_password = !upstream.username.is_empty() || !upstream.password.is_empty();
+    let method = if use_password {
+        SOCKS5_AUTH_METHOD_PASSWORD
+    } else {
+        SOCKS5_AUTH_METHOD_NONE
+    };
+
+    // 协商认证方式
+    stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+    let mut response = [0u8; 2];
+    stream.read_exact(&mut response).await?;
+    if response[0] != SOCKS5_VERSION {
+        return Err(anyhow!("invalid version {}", response[0]));
+    }
+    if response[1] == SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE || response[1] != method {
+        return Err(anyhow!("no acceptable authentication method"));
+    }
+
+    // 用户名密码认证
+    if method == SOCKS5_AUTH_METHOD_PASSWORD {
+        if upstream.username.len() > u8::MAX as usize || upstream.password.len() > u8::MAX as usize
+        {
+            return Err(anyhow!("username or password too long"));
+        }
+        let mut request = vec![0x01, upstream.username.len() as u8];
+        request.extend_from_slice(upstream.username.as_bytes());
+        request.push(upstream.password.len() as u8);
+        request.extend_from_slice(upstream.password.as_bytes());
+        stream.write_all(&request).await?;
+
+        stream.read_exact(&mut response).await?;
+        if response[1] != 0x00 {
+            return Err(anyhow!("authentication failed"));
+        }
+    }
+
+    // 发起连接
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_TCP_CONNECT, 0x00];
+    request.extend(target_addr.to_be_bytes()?);
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        return Err(anyhow!(
+            "connect {target_addr} failed: {}",
+            reply_message(head[1])
+        ));
+    }
+
+    // 读取并丢弃BND.ADDR和BND.PORT
+    let mut addr_data = vec![0u8; 1];
+    stream.read_exact(&mut addr_data).await?;
+    loop {
+        if read_address(&addr_data, head[3])?.is_some() {
+            break;
+        }
+        let len = addr_data.len();
+        addr_data.resize(len + 1, 0);
+        stream.read_exact(&mut addr_data[len..]).await?;
+    }
+    Ok(())
+}
+
+fn parse_target(target: &str) -> anyhow::Result<TargetAddr> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(TargetAddr::Ip(addr));
+    }
+    let (host, port) = target
+        .rsplit_once(':')
+        .ok_or(anyhow!("invalid target address: {target}"))?;
+    Ok((host, port.parse::<u16>()?).to_target_addr()?)
+}
+
+fn reply_message(rep: u8) -> &'static str {
+    match rep {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
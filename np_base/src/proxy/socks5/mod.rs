@@ -1,3 +1,4 @@
+pub mod client;
 pub mod target_addr;
 
 use crate::net::{SendMessageFuncType, WriterMessage};
@@ -6,10 +7,9 @@ use crate::proxy::inlet::{InletDataEx, InletProxyType};
 use crate::proxy::socks5::target_addr::TargetAddr;
 use crate::proxy::ProxyMessage;
 use anyhow::anyhow;
-use base64::prelude::BASE64_STANDARD;
-use base64::Engine;
-use log::{error, warn};
-use std::net::SocketAddr;
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::UdpSocket;
@@ -68,21 +68,27 @@ pub struct Socks5Context {
     data_ex: Arc<InletDataEx>,
     target_addr: Option<TargetAddr>,
     session_id: u32,
+    generation: u32,
     addr: SocketAddr,
     common_data: SessionCommonInfo,
+    // 连接时带给出口的会话元数据, 认证通过后加入用户名
+    metadata: HashMap<String, String>,
 
     read_input_task_handle: Option<(JoinHandle<()>, oneshot::Sender<()>)>,
     udp_task_handle: Option<(JoinHandle<()>, oneshot::Sender<()>)>,
 }
 
 impl Socks5Context {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         write_msg_tx: mpsc::UnboundedSender<WriterMessage>,
         output: Sender<ProxyMessage>,
         session_id: u32,
+        generation: u32,
         addr: SocketAddr,
         data_ex: Arc<InletDataEx>,
         common_data: SessionCommonInfo,
+        metadata: HashMap<String, String>,
     ) -> (Arc<RwLock<Self>>, mpsc::UnboundedSender<ProxyMessage>) {
         let (proxy_msg_tx, mut proxy_msg_rx) = mpsc::unbounded_channel::<ProxyMessage>();
 
@@ -96,8 +102,10 @@ impl Socks5Context {
             data_ex,
             target_addr: None,
             session_id,
+            generation,
             addr,
             common_data,
+            metadata,
             read_input_task_handle: None,
             udp_task_handle: None,
         }));
@@ -130,7 +138,7 @@ impl Socks5Context {
         (context, proxy_msg_tx)
     }
 
-    pub async fn recv_frame(&mut self, mut frame: Vec<u8>) -> anyhow::Result<()> {
+    pub async fn recv_frame(&mut self, frame: Vec<u8>) -> anyhow::Result<()> {
         match &self.status {
             Status::Init => {
                 self.buffer.extend_from_slice(&frame);
@@ -138,7 +146,7 @@ impl Socks5Context {
             }
             Status::Verification => {
                 self.buffer.extend_from_slice(&frame);
-                self.on_socks5_verification()?;
+                self.on_socks5_verification().await?;
             }
             Status::Connect => {
                 self.buffer.extend_from_slice(&frame);
@@ -148,10 +156,12 @@ impl Socks5Context {
                 warn!("Status::Connecting should not receive other data");
             }
             Status::RunWithTcp => {
-                frame = self.common_data.encode_data_and_limiting(frame).await?;
-                self.output
-                    .send(ProxyMessage::I2oSendData(self.session_id, frame))
-                    .await?;
+                for chunk in self.common_data.split_chunks(frame) {
+                    let chunk = self.common_data.encode_data_and_limiting(chunk).await?;
+                    self.output
+                        .send(ProxyMessage::I2oSendData(self.session_id, chunk))
+                        .await?;
+                }
             }
             Status::RunWithUdp(_) => {
                 warn!("SOCKS5_CMD_UDP_ASSOCIATE mode should not receive other data");
@@ -183,7 +193,7 @@ impl Socks5Context {
 
             let methods = &self.buffer[2..num_of_package];
 
-            let method = if self.data_ex.username.is_empty() && self.data_ex.password.is_empty() {
+            let method = if !self.data_ex.requires_auth() {
                 // 不需要密码
                 if methods.contains(&SOCKS5_AUTH_METHOD_NONE) {
                     SOCKS5_AUTH_METHOD_NONE
@@ -223,23 +233,35 @@ impl Socks5Context {
         Ok(())
     }
 
-    fn on_socks5_verification(&mut self) -> anyhow::Result<()> {
+    async fn on_socks5_verification(&mut self) -> anyhow::Result<()> {
         // +----+-----+-----------+------+----------+
         // |VER | UNL |  UNM      | PWL  | PWD      |
         // +----+-----+-----------+------+----------+
         // | 1  |  1  |  Variable |  1   | Variable |
         // +----+-----+-----------+------+----------+
 
-        if self.buffer.len() < 4 {
+        if self.buffer.len() < 2 {
             return Ok(());
         }
         let ver = self.buffer[0];
         let unl: usize = self.buffer[1] as usize;
+        if self.buffer.len() < 3 + unl {
+            return Ok(());
+        }
         let pwl: usize = self.buffer[2 + unl] as usize;
         let num_of_package = unl + pwl + 3;
+        // 消息未接收完成
+        if self.buffer.len() < num_of_package {
+            return Ok(());
+        }
 
-        let unm = &self.buffer[2..(2 + unl)];
+        let unm = String::from_utf8_lossy(&self.buffer[2..(2 + unl)]).to_string();
         let pwd = &self.buffer[(3 + unl)..(3 + unl + pwl)];
+        let allowed = self
+            .data_ex
+            .authenticator()
+            .authenticate(&unm, pwd, self.addr)
+            .await;
 
         // Response
         // +----+-----+
@@ -248,13 +270,14 @@ impl Socks5Context {
         // | 1  |  1  |
         // +----+-----+
         // 0x00 表示成功，0x01 表示失败
-        if unm == self.data_ex.username.as_bytes() && pwd == self.data_ex.password.as_bytes() {
+        if allowed {
             let response: Vec<u8> = vec![ver, 0x00];
             self.write_msg_tx
                 .send(WriterMessage::Send(response, true))?;
 
             self.buffer.drain(0..num_of_package);
             self.status = Status::Connect;
+            self.metadata.insert("username".into(), unm);
         } else {
             let response: Vec<u8> = vec![ver, 0x01];
             self.write_msg_tx
@@ -296,7 +319,7 @@ impl Socks5Context {
                         let is_tcp = match cmd {
                             SOCKS5_CMD_TCP_CONNECT => true,
                             SOCKS5_CMD_UDP_ASSOCIATE => {
-                                let mut addr = self.addr.clone();
+                                let mut addr = self.addr;
                                 addr.set_port(target_addr.port());
                                 target_addr = TargetAddr::Ip(addr);
                                 false
@@ -306,17 +329,33 @@ impl Socks5Context {
                             }
                         };
 
+                        let (is_compressed, encryption_method, encryption_key) =
+                            self.common_data.outbound.to_remote();
+                        let o2i_codec = if self.common_data.is_symmetric {
+                            None
+                        } else {
+                            Some(self.common_data.inbound.to_remote())
+                        };
                         self.output
-                            .send(ProxyMessage::I2oConnect(
-                                self.session_id,
-                                InletProxyType::SOCKS5.to_u8(),
+                            .send(ProxyMessage::I2oConnect {
+                                session_id: self.session_id,
+                                generation: self.generation,
+                                tunnel_type: InletProxyType::SOCKS5.to_u8(),
                                 is_tcp,
-                                self.common_data.is_compressed,
-                                target_addr.to_string(),
-                                self.common_data.encryption_method.to_string(),
-                                BASE64_STANDARD.encode(&self.common_data.encryption_key),
-                                self.addr.to_string(),
-                            ))
+                                is_compressed,
+                                addr: target_addr.to_string(),
+                                encryption_method,
+                                encryption_key,
+                                client_addr: self.addr.to_string(),
+                                o2i_codec,
+                                write_coalesce: self.common_data.write_coalesce.as_millis() as u32,
+                                backend_tls: None,
+                                compression_dictionary: self.common_data.outbound.dictionary_hash(),
+                                metadata: self.metadata.clone(),
+                                key_derivation: self.common_data.remote_derivation(),
+                                compress_threshold: self.common_data.outbound.compress_threshold
+                                    as u32,
+                            })
                             .await?;
 
                         self.target_addr = Some(target_addr);
@@ -364,17 +403,17 @@ impl Socks5Context {
 
     async fn on_recv_proxy_message(&mut self, proxy_message: ProxyMessage) -> anyhow::Result<()> {
         match proxy_message {
-            ProxyMessage::O2iConnect(_session_id, success, error_msg) => {
+            ProxyMessage::O2iConnect(_session_id, _, success, error_msg, ..) => {
                 if !success {
                     error!("socks5 connect error: {error_msg}");
                 }
                 self.on_recv_o2i_connect(success).await?;
             }
 
-            ProxyMessage::O2iRecvData(session_id, data) => {
+            ProxyMessage::O2iRecvData(session_id, _, data) => {
                 self.on_recv_o2i_recv_data(session_id, data).await?;
             }
-            ProxyMessage::O2iRecvDataFrom(session_id, data, peer_addr) => {
+            ProxyMessage::O2iRecvDataFrom(session_id, _, data, peer_addr) => {
                 self.on_recv_o2i_recv_data_from(session_id, data, peer_addr)
                     .await?;
             }
@@ -405,7 +444,7 @@ impl Socks5Context {
                 });
 
                 self.write_msg_tx
-                    .send(WriterMessage::SendAndThen(data, callback))?;
+                    .send(self.common_data.write_message(data, callback))?;
             }
             _ => {
                 warn!(
@@ -425,6 +464,14 @@ impl Socks5Context {
         match &self.status {
             Status::RunWithUdp(udp_socket) => {
                 let data_len = data.len();
+                // 还未收到客户端的数据包, 无法回复
+                if udp_socket.peer_addr().is_err() {
+                    let _ = self
+                        .output
+                        .send(ProxyMessage::I2oRecvDataResult(session_id, data_len))
+                        .await;
+                    return Ok(());
+                }
                 data = self.common_data.decode_data(data)?;
 
                 let peer_addr = TargetAddr::Ip(peer_addr.parse()?);
@@ -517,20 +564,28 @@ impl Socks5Context {
     }
 
     async fn udp_bind(&mut self) -> anyhow::Result<Vec<u8>> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        if let Some(ref target_addr) = self.target_addr {
-            match target_addr {
-                TargetAddr::Ip(addr) => {
-                    socket.connect(addr).await?;
-                }
-                TargetAddr::Domain(host, port) => {
-                    socket.connect(format!("{host}:{port}")).await?;
-                }
+        // 与控制连接使用相同的地址族
+        let bind_addr: SocketAddr = if self.addr.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+
+        // 客户端声明了UDP端口则直接关联, 否则以收到的第一个数据包的来源地址为准
+        if let Some(TargetAddr::Ip(addr)) = self.target_addr {
+            if addr.port() != 0 {
+                socket.connect(addr).await?;
             }
         }
 
-        let mut buf = vec![SOCKS5_VERSION, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
-        buf.extend_from_slice(&socket.local_addr()?.port().to_be_bytes());
+        // 回复实际绑定的地址
+        let mut bound_addr = socket.local_addr()?;
+        if bound_addr.ip().is_unspecified() {
+            bound_addr.set_ip(local_ip_to(bind_addr, self.addr).await);
+        }
+        let mut buf = vec![SOCKS5_VERSION, 0x00, 0x00];
+        buf.extend(TargetAddr::Ip(bound_addr).to_be_bytes()?);
 
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
         let socket = Arc::new(socket);
@@ -539,6 +594,7 @@ impl Socks5Context {
         let write_msg_tx = self.write_msg_tx.clone();
         let session_id = self.session_id;
         let common_data = self.common_data.clone();
+        let client_ip = self.addr.ip();
 
         let handle = tokio::spawn(async move {
             let task = async {
@@ -549,9 +605,20 @@ impl Socks5Context {
                         continue;
                     }
 
-                    let (amt, _addr) = result.unwrap();
+                    let (amt, addr) = result.unwrap();
+                    // 只接受控制连接所在主机发来的数据
+                    if addr.ip() != client_ip {
+                        continue;
+                    }
+                    if socket.peer_addr().is_err() {
+                        if let Err(err) = socket.connect(addr).await {
+                            warn!("Socks5 UDP connect {addr} error: {err}");
+                            break;
+                        }
+                    }
+
                     if let Err(err) =
-                        recv_udp_data(session_id, amt, &buf, &output, &common_data).await
+                        recv_udp_data(session_id, &buf[..amt], &output, &common_data).await
                     {
                         warn!("Socks5 UDP error: {err}");
                         break;
@@ -573,42 +640,70 @@ impl Socks5Context {
     }
 }
 
-#[inline]
-async fn recv_udp_data(
-    session_id: u32,
-    amt: usize,
-    buf: &[u8],
-    output: &Sender<ProxyMessage>,
-    common_data: &SessionCommonInfo,
-) -> anyhow::Result<()> {
+/// 获取通往对端时使用的本机地址
+async fn local_ip_to(bind_addr: SocketAddr, peer_addr: SocketAddr) -> IpAddr {
+    let probe = async {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(peer_addr).await?;
+        socket.local_addr()
+    };
+    match probe.await {
+        Ok(addr) => addr.ip(),
+        Err(_) => bind_addr.ip(),
+    }
+}
+
+/// 解析客户端发来的UDP请求头, 返回目标地址和数据起始位置
+///
+/// 不支持分片, FRAG不为0的数据包返回None
+fn parse_udp_request(data: &[u8]) -> anyhow::Result<Option<(TargetAddr, usize)>> {
     // +----+------+------+----------+----------+----------+
     // |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
     // +----+------+------+----------+----------+----------+
     // | 2  |  1   |  1   | Variable |    2     | Variable |
     // +----+------+------+----------+----------+----------+
-    if amt < 11 {
+    if data.len() < 5 {
         return Err(anyhow!("received data of illegal length"));
     }
+    if data[2] != 0x00 {
+        return Ok(None);
+    }
 
-    let received_data = Vec::from(&buf[..amt]);
-    let address_type = received_data[3];
-    match target_addr::read_address(&received_data[4..], address_type) {
-        Ok(Some((addr, addr_data_len))) => {
-            let start = 4 + addr_data_len;
-            let data = common_data.decode_data(buf[start..amt].to_vec())?;
-
-            output
-                .send(ProxyMessage::I2oSendToData(
-                    session_id,
-                    data,
-                    addr.to_string(),
-                ))
-                .await?;
+    match target_addr::read_address(&data[4..], data[3])? {
+        Some((addr, addr_data_len)) => Ok(Some((addr, 4 + addr_data_len))),
+        None => Err(anyhow!("address resolution failed")),
+    }
+}
+
+#[inline]
+async fn recv_udp_data(
+    session_id: u32,
+    data: &[u8],
+    output: &Sender<ProxyMessage>,
+    common_data: &SessionCommonInfo,
+) -> anyhow::Result<()> {
+    let (addr, start) = match parse_udp_request(data) {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            debug!("Socks5 UDP fragment dropped");
+            return Ok(());
         }
-        _ => {
-            return Err(anyhow!("address resolution failed"));
+        Err(err) => {
+            warn!("Socks5 UDP invalid datagram dropped: {err}");
+            return Ok(());
         }
-    }
+    };
+
+    let data = common_data
+        .encode_data_and_limiting(data[start..].to_vec())
+        .await?;
+    output
+        .send(ProxyMessage::I2oSendToData(
+            session_id,
+            data,
+            addr.to_string(),
+        ))
+        .await?;
 
     Ok(())
 }
@@ -616,13 +711,46 @@ async fn recv_udp_data(
 #[inline]
 async fn cancel_task(task_handle: Option<(JoinHandle<()>, oneshot::Sender<()>)>) {
     if let Some((task_handle, shutdown_tx)) = task_handle {
-        if let Err(_) = timeout(Duration::from_secs(2), async {
+        if timeout(Duration::from_secs(2), async {
             let _ = shutdown_tx.send(());
             let _ = task_handle.await;
         })
         .await
+        .is_err()
         {
             error!("The task is not completed within 2 seconds");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_udp_request() {
+        let mut data = vec![
+            0x00,
+            0x00,
+            0x00,
+            SOCKS5_ADDR_TYPE_IPV4,
+            127,
+            0,
+            0,
+            1,
+            0x00,
+            0x35,
+        ];
+        data.extend_from_slice(b"hello");
+
+        let (addr, start) = parse_udp_request(&data).unwrap().unwrap();
+        assert_eq!(addr.to_string(), "127.0.0.1:53");
+        assert_eq!(&data[start..], b"hello");
+
+        // 分片数据包直接丢弃
+        data[2] = 0x01;
+        assert!(parse_udp_request(&data).unwrap().is_none());
+
+        assert!(parse_udp_request(&data[..4]).is_err());
+    }
+}
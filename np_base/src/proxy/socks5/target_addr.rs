@@ -20,13 +20,12 @@ pub enum TargetAddr {
 }
 
 impl TargetAddr {
+    #[allow(dead_code)]
     pub fn is_ip(&self) -> bool {
-        match self {
-            TargetAddr::Ip(_) => true,
-            _ => false,
-        }
+        matches!(self, TargetAddr::Ip(_))
     }
 
+    #[allow(dead_code)]
     pub fn is_domain(&self) -> bool {
         !self.is_ip()
     }
@@ -81,8 +80,7 @@ impl std::net::ToSocketAddrs for TargetAddr {
     fn to_socket_addrs(&self) -> io::Result<IntoIter<SocketAddr>> {
         match *self {
             TargetAddr::Ip(addr) => Ok(vec![addr].into_iter()),
-            TargetAddr::Domain(_, _) => Err(io::Error::new(
-                io::ErrorKind::Other,
+            TargetAddr::Domain(_, _) => Err(io::Error::other(
                 "Domain name has to be explicitly resolved, please use TargetAddr::resolve_dns().",
             )),
         }
@@ -105,7 +103,7 @@ pub trait ToTargetAddr {
     fn to_target_addr(&self) -> io::Result<TargetAddr>;
 }
 
-impl<'a> ToTargetAddr for (&'a str, u16) {
+impl ToTargetAddr for (&str, u16) {
     fn to_target_addr(&self) -> io::Result<TargetAddr> {
         // try to parse as an IP first
         if let Ok(addr) = self.0.parse::<Ipv4Addr>() {
@@ -190,9 +188,7 @@ pub fn read_address(data: &[u8], atyp: u8) -> anyhow::Result<Option<(TargetAddr,
             if data.len() < addr_data_len {
                 Addr::Unknown
             } else {
-                let domain = data[1..len]
-                    .try_into()
-                    .expect("Slice with incorrect length");
+                let domain = data[1..len].into();
                 // make sure the bytes are correct utf8 string
                 let domain = String::from_utf8(domain)?;
                 Addr::Domain(domain)
@@ -201,11 +197,8 @@ pub fn read_address(data: &[u8], atyp: u8) -> anyhow::Result<Option<(TargetAddr,
         _ => return Err(anyhow!("incorrect address type")),
     };
 
-    match addr {
-        Addr::Unknown => {
-            return Ok(None);
-        }
-        _ => {}
+    if let Addr::Unknown = addr {
+        return Ok(None);
     }
 
     // Convert (u8 * 2) into u16
@@ -0,0 +1,391 @@
+use crate::proxy::common::SessionCommonInfo;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// 延迟统计保留的最近探测次数
+pub const RTT_WINDOW: usize = 128;
+
+/// 单个会话的背压状态
+#[derive(Clone, Debug)]
+pub struct SessionBackpressure {
+    /// 会话id
+    pub session_id: u32,
+    /// 已发送但还未被对端确认的字节数
+    pub read_buf_len: usize,
+    /// 是否因超过背压阈值而暂停读取
+    pub stalled: bool,
+}
+
+/// 背压统计
+#[derive(Clone, Debug, Default)]
+pub struct BackpressureStats {
+    pub sessions: Vec<SessionBackpressure>,
+    pub min_read_buf_len: usize,
+    pub max_read_buf_len: usize,
+    pub avg_read_buf_len: usize,
+    /// 超过背压阈值的会话数量
+    pub stalled_sessions: usize,
+}
+
+impl BackpressureStats {
+    pub fn from_sessions(sessions: Vec<SessionBackpressure>) -> Self {
+        if sessions.is_empty() {
+            return Self::default();
+        }
+
+        let total: usize = sessions.iter().map(|x| x.read_buf_len).sum();
+        Self {
+            min_read_buf_len: sessions.iter().map(|x| x.read_buf_len).min().unwrap_or(0),
+            max_read_buf_len: sessions.iter().map(|x| x.read_buf_len).max().unwrap_or(0),
+            avg_read_buf_len: total / sessions.len(),
+            stalled_sessions: sessions.iter().filter(|x| x.stalled).count(),
+            sessions,
+        }
+    }
+
+    /// 合并多个统计结果
+    pub fn merge(self, other: Self) -> Self {
+        let mut sessions = self.sessions;
+        sessions.extend(other.sessions);
+        Self::from_sessions(sessions)
+    }
+}
+
+/// 按会话自身的读缓存上限和全局缓存预算判断是否暂停读取
+pub(crate) async fn session_backpressure(
+    session_id: u32,
+    common_info: &SessionCommonInfo,
+) -> SessionBackpressure {
+    let read_buf_len = *common_info.read_buf_len.read().await;
+    SessionBackpressure {
+        session_id,
+        read_buf_len,
+        stalled: common_info.read_buf_stalled(read_buf_len),
+    }
+}
+
+/// 入口会话概要
+#[derive(Clone, Debug)]
+pub struct SessionSummary {
+    /// 会话id
+    pub session_id: u32,
+    /// 客户端地址
+    pub peer_addr: SocketAddr,
+    /// 客户端所在的国家代码, 没有地理位置数据库或查不到时为 `unknown`
+    pub country: String,
+    /// 从客户端收到的字节数
+    pub bytes_in: u64,
+    /// 写给客户端的字节数
+    pub bytes_out: u64,
+    /// 会话已存在的时间
+    pub age: Duration,
+    /// 出口实际连接的后端地址(解析后的IP和端口), 连接成功前或出口不支持时为空
+    pub backend_addr: String,
+}
+
+/// 会话关闭原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionCloseReason {
+    /// 对端关闭
+    Peer,
+    /// 空闲超时
+    Idle,
+    /// 超过最大存活时间
+    Lifetime,
+    /// 被管理员强制关闭
+    Killed,
+    /// 客户端不读取, 等待写入的数据超过上限
+    Overflow,
+    /// 入口停止
+    Shutdown,
+}
+
+impl SessionCloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionCloseReason::Peer => "peer",
+            SessionCloseReason::Idle => "idle",
+            SessionCloseReason::Lifetime => "lifetime",
+            SessionCloseReason::Killed => "killed",
+            SessionCloseReason::Overflow => "overflow",
+            SessionCloseReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// 入口会话的开始和结束事件
+#[derive(Clone, Debug)]
+pub enum SessionEvent {
+    /// 会话已登记, 开始向出口发起连接
+    Open {
+        session_id: u32,
+        peer_addr: SocketAddr,
+    },
+    /// 会话结束
+    Close {
+        session_id: u32,
+        peer_addr: SocketAddr,
+        reason: SessionCloseReason,
+    },
+}
+
+/// 会话事件回调, 在会话所在的任务中同步调用, 不能阻塞
+pub type SessionEventCallback = Arc<dyn Fn(SessionEvent) + Send + Sync>;
+
+/// 流量回调, 参数为字节数, 在转发数据的任务中同步调用, 不能阻塞
+pub type TrafficCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// 按关闭原因统计的会话数量
+#[derive(Clone, Debug, Default)]
+pub struct SessionCloseStats {
+    pub closed_by_peer: u64,
+    pub closed_by_idle: u64,
+    pub closed_by_lifetime: u64,
+    pub closed_by_kill: u64,
+    pub closed_by_overflow: u64,
+    pub closed_by_shutdown: u64,
+    /// 超过新连接速率限制被拒绝的连接数
+    pub rejected_by_rate_limit: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct SessionCloseCounter {
+    peer: AtomicU64,
+    idle: AtomicU64,
+    lifetime: AtomicU64,
+    killed: AtomicU64,
+    overflow: AtomicU64,
+    shutdown: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+impl SessionCloseCounter {
+    pub(crate) fn record(&self, reason: SessionCloseReason) {
+        let counter = match reason {
+            SessionCloseReason::Peer => &self.peer,
+            SessionCloseReason::Idle => &self.idle,
+            SessionCloseReason::Lifetime => &self.lifetime,
+            SessionCloseReason::Killed => &self.killed,
+            SessionCloseReason::Overflow => &self.overflow,
+            SessionCloseReason::Shutdown => &self.shutdown,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 记录一个因速率限制被拒绝的连接
+    pub(crate) fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> SessionCloseStats {
+        SessionCloseStats {
+            closed_by_peer: self.peer.load(Ordering::Relaxed),
+            closed_by_idle: self.idle.load(Ordering::Relaxed),
+            closed_by_lifetime: self.lifetime.load(Ordering::Relaxed),
+            closed_by_kill: self.killed.load(Ordering::Relaxed),
+            closed_by_overflow: self.overflow.load(Ordering::Relaxed),
+            closed_by_shutdown: self.shutdown.load(Ordering::Relaxed),
+            rejected_by_rate_limit: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 入口的累计流量
+#[derive(Clone, Debug)]
+pub struct TrafficStats {
+    /// 从客户端收到的字节数
+    pub bytes_in: u64,
+    /// 写给客户端的字节数
+    pub bytes_out: u64,
+    /// 两个方向上通过splice在内核中转发的字节数, 已计入 `bytes_in` 和 `bytes_out`
+    pub bytes_spliced: u64,
+    /// 开始统计的时间, 即上次重置的时间, 从未重置时为入口创建的时间
+    pub since: SystemTime,
+}
+
+pub(crate) struct TrafficCounter {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    bytes_spliced: AtomicU64,
+    since: Mutex<SystemTime>,
+}
+
+impl TrafficCounter {
+    pub(crate) fn new() -> Self {
+        Self {
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            bytes_spliced: AtomicU64::new(0),
+            since: Mutex::new(SystemTime::now()),
+        }
+    }
+
+    pub(crate) fn add_bytes_in(&self, len: u64) {
+        self.bytes_in.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes_out(&self, len: u64) {
+        self.bytes_out.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// splice转发的数据, 对应方向的流量另外计入
+    pub(crate) fn add_bytes_spliced(&self, len: u64) {
+        self.bytes_spliced.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> TrafficStats {
+        let since = self.since.lock().unwrap();
+        TrafficStats {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            bytes_spliced: self.bytes_spliced.load(Ordering::Relaxed),
+            since: *since,
+        }
+    }
+
+    /// 清零并返回清零前的统计
+    ///
+    /// 计数器通过交换清零, 并发的累加要么计入返回值, 要么计入下一个周期, 不会丢失或重复
+    pub(crate) fn reset(&self) -> TrafficStats {
+        let mut since = self.since.lock().unwrap();
+        let stats = TrafficStats {
+            bytes_in: self.bytes_in.swap(0, Ordering::Relaxed),
+            bytes_out: self.bytes_out.swap(0, Ordering::Relaxed),
+            bytes_spliced: self.bytes_spliced.swap(0, Ordering::Relaxed),
+            since: *since,
+        };
+        *since = SystemTime::now();
+        stats
+    }
+}
+
+/// 出口连接池的统计
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionPoolStats {
+    /// 从连接池取到连接的会话数
+    pub hits: u64,
+    /// 连接池为空, 临时发起连接的会话数
+    pub misses: u64,
+    /// 空闲过久或已被后端关闭而丢弃的连接数
+    pub evicted_stale: u64,
+    /// 所有连接池的空闲连接总数超过全局上限而丢弃的连接数
+    pub evicted_lru: u64,
+    /// 当前池中的空闲连接数
+    pub idle: usize,
+}
+
+/// 入口到出口再返回的往返延迟, 按最近 [`RTT_WINDOW`] 次探测统计
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RttStats {
+    /// 参与统计的探测次数, 为0时其余字段没有意义
+    pub samples: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    pub p99: Duration,
+}
+
+/// 记录延迟探测的结果
+///
+/// 探测的时间戳是相对于创建时间的微秒数, 只在本进程内有意义, 不受系统时间调整影响
+pub(crate) struct RttRecorder {
+    start: Instant,
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl RttRecorder {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            samples: Mutex::new(VecDeque::with_capacity(RTT_WINDOW)),
+        }
+    }
+
+    /// 发出探测时使用的时间戳
+    pub(crate) fn ticks(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    /// 收到探测回复, 时间戳晚于当前时间的回复不是本入口发出的, 忽略
+    pub(crate) fn record(&self, ticks: u64) {
+        let now = self.ticks();
+        if ticks > now {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == RTT_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(Duration::from_micros(now - ticks));
+    }
+
+    pub(crate) fn snapshot(&self) -> RttStats {
+        let mut samples: Vec<Duration> = self.samples.lock().unwrap().iter().copied().collect();
+        if samples.is_empty() {
+            return RttStats::default();
+        }
+        samples.sort();
+        let len = samples.len();
+        RttStats {
+            samples: len,
+            min: samples[0],
+            avg: samples.iter().sum::<Duration>() / len as u32,
+            max: samples[len - 1],
+            // 向上取整的排名, 样本少时即为最大值
+            p99: samples[(len * 99).div_ceil(100) - 1],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::common::DataCodec;
+
+    #[tokio::test]
+    async fn test_session_backpressure_uses_session_limit() {
+        let common_info = SessionCommonInfo::symmetric(DataCodec::from_method_name(false, "None"))
+            .set_read_buf_max_len(16);
+        common_info
+            .encode_data_and_limiting(vec![0u8; 17])
+            .await
+            .unwrap();
+        let stats = session_backpressure(1, &common_info).await;
+        assert_eq!(stats.read_buf_len, 17);
+        assert!(stats.stalled);
+
+        common_info.release_read_buf(17).await;
+        assert!(!session_backpressure(1, &common_info).await.stalled);
+    }
+
+    #[test]
+    fn test_rtt_recorder() {
+        let recorder = RttRecorder::new();
+        assert_eq!(recorder.snapshot(), RttStats::default());
+
+        // 直接写入样本, 不依赖真实的时间
+        recorder
+            .samples
+            .lock()
+            .unwrap()
+            .extend((1..=100).map(Duration::from_millis));
+        let stats = recorder.snapshot();
+        assert_eq!(stats.samples, 100);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.max, Duration::from_millis(100));
+        assert_eq!(stats.avg, Duration::from_micros(50_500));
+        assert_eq!(stats.p99, Duration::from_millis(99));
+
+        // 超过窗口后丢弃最早的样本, 未来的时间戳被忽略
+        for _ in 0..RTT_WINDOW {
+            recorder.record(recorder.ticks());
+        }
+        recorder.record(u64::MAX);
+        let stats = recorder.snapshot();
+        assert_eq!(stats.samples, RTT_WINDOW);
+        assert!(stats.max < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,51 @@
+use crate::proxy::recorder::{Direction, MessageRecorder};
+use crate::proxy::{OutputFuncType, ProxyMessage};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// 代理消息的传输方式, 入口和出口通过它把消息发给对端
+///
+/// 目前的实现经由与服务器之间的TCP连接转发, 替换为其他传输(如QUIC)时只需实现该trait
+#[async_trait]
+pub trait MessageTransport: Send + Sync {
+    /// 发送一条消息, 对端不可达时由实现自行丢弃或缓存
+    async fn send(&self, message: ProxyMessage);
+}
+
+/// 用回调函数实现的传输, 方便测试和在进程内直接对接入口与出口
+pub struct CallbackTransport {
+    callback: OutputFuncType,
+}
+
+impl CallbackTransport {
+    pub fn new(callback: OutputFuncType) -> Arc<Self> {
+        Arc::new(Self { callback })
+    }
+}
+
+#[async_trait]
+impl MessageTransport for CallbackTransport {
+    async fn send(&self, message: ProxyMessage) {
+        (self.callback)(message).await
+    }
+}
+
+/// 录制发出的消息后交给内部的传输
+pub(crate) struct RecordingTransport {
+    inner: Arc<dyn MessageTransport>,
+    recorder: Arc<MessageRecorder>,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Arc<dyn MessageTransport>, recorder: Arc<MessageRecorder>) -> Arc<Self> {
+        Arc::new(Self { inner, recorder })
+    }
+}
+
+#[async_trait]
+impl MessageTransport for RecordingTransport {
+    async fn send(&self, message: ProxyMessage) {
+        self.recorder.record(Direction::Output, &message);
+        self.inner.send(message).await
+    }
+}
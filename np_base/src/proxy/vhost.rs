@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+
+/// 预读数据的最大长度, 超过后不再等待主机名
+pub(crate) const PEEK_MAX_LEN: usize = 16 * 1024;
+
+/// 标签行的最大长度(不含换行)
+pub const MAX_LABEL_LEN: usize = 255;
+
+/// 预读结果
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PeekResult {
+    /// 数据不完整, 需要继续接收
+    Incomplete,
+    /// 解析到主机名
+    Host(String),
+    /// 数据中不包含主机名
+    NoHost,
+}
+
+/// 从TLS ClientHello中提取SNI主机名(不解密、不消耗数据)
+pub(crate) fn peek_tls_sni(data: &[u8]) -> PeekResult {
+    // TLS记录头: ContentType(1) Version(2) Length(2)
+    if data.is_empty() {
+        return PeekResult::Incomplete;
+    }
+    if data[0] != 0x16 {
+        return PeekResult::NoHost;
+    }
+    if data.len() < 5 {
+        return PeekResult::Incomplete;
+    }
+    let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if data.len() < 5 + record_len {
+        return PeekResult::Incomplete;
+    }
+    let record = &data[5..5 + record_len];
+
+    match parse_client_hello(record) {
+        Some(Some(host)) => PeekResult::Host(host),
+        _ => PeekResult::NoHost,
+    }
+}
+
+/// 解析ClientHello, 数据格式错误返回None, 没有SNI扩展返回Some(None)
+fn parse_client_hello(data: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader { data, pos: 0 };
+
+    // HandshakeType(1) Length(3)
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    reader.skip(3)?;
+    // ClientVersion(2) Random(32)
+    reader.skip(34)?;
+    // SessionId
+    let len = reader.u8()? as usize;
+    reader.skip(len)?;
+    // CipherSuites
+    let len = reader.u16()? as usize;
+    reader.skip(len)?;
+    // CompressionMethods
+    let len = reader.u8()? as usize;
+    reader.skip(len)?;
+
+    // 没有扩展
+    if reader.remaining() == 0 {
+        return Some(None);
+    }
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader {
+        data: reader.take(extensions_len)?,
+        pos: 0,
+    };
+    while extensions.remaining() > 0 {
+        let ext_type = extensions.u16()?;
+        let ext_len = extensions.u16()? as usize;
+        let ext_data = extensions.take(ext_len)?;
+        // server_name
+        if ext_type == 0x0000 {
+            let mut ext = Reader {
+                data: ext_data,
+                pos: 0,
+            };
+            let list_len = ext.u16()? as usize;
+            let mut list = Reader {
+                data: ext.take(list_len)?,
+                pos: 0,
+            };
+            while list.remaining() > 0 {
+                let name_type = list.u8()?;
+                let name_len = list.u16()? as usize;
+                let name = list.take(name_len)?;
+                // host_name
+                if name_type == 0x00 {
+                    return String::from_utf8(name.to_vec()).ok().map(Some);
+                }
+            }
+        }
+    }
+    Some(None)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.remaining() < len {
+            return None;
+        }
+        let data = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Some(data)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+}
+
+/// 从HTTP请求头中提取Host(不包含端口)
+pub(crate) fn peek_http_host(data: &[u8]) -> PeekResult {
+    let Some(end) = data.windows(4).position(|x| x == b"\r\n\r\n") else {
+        return PeekResult::Incomplete;
+    };
+
+    let head = String::from_utf8_lossy(&data[..end]);
+    for line in head.split("\r\n").skip(1) {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("host") {
+                let value = value.trim();
+                // 去掉端口, 兼容IPv6字面量
+                let host = match value.rfind(':') {
+                    Some(index) if !value[index..].contains(']') => &value[..index],
+                    _ => value,
+                };
+                return PeekResult::Host(host.to_string());
+            }
+        }
+    }
+    PeekResult::NoHost
+}
+
+/// 标签行的解析结果
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum LabelLine {
+    /// 还没有收到换行, 需要继续接收
+    Incomplete,
+    /// 标签(去掉换行和首尾空白)和标签行含换行的长度, 空行表示没有标签
+    Label(String, usize),
+    /// 超过最大长度仍没有换行, 或不是UTF-8, 数据原样转发
+    NoLabel,
+}
+
+/// 从第一行读取标签, 以 `\n` 或 `\r\n` 结尾
+pub(crate) fn peek_label(data: &[u8]) -> LabelLine {
+    let limit = data.len().min(MAX_LABEL_LEN + 2);
+    let Some(end) = data[..limit].iter().position(|x| *x == b'\n') else {
+        return if data.len() > MAX_LABEL_LEN + 1 {
+            LabelLine::NoLabel
+        } else {
+            LabelLine::Incomplete
+        };
+    };
+    let line = data[..end].strip_suffix(b"\r").unwrap_or(&data[..end]);
+    if line.len() > MAX_LABEL_LEN {
+        return LabelLine::NoLabel;
+    }
+    match std::str::from_utf8(line) {
+        Ok(label) => LabelLine::Label(label.trim().to_string(), end + 1),
+        Err(_) => LabelLine::NoLabel,
+    }
+}
+
+/// 根据主机名选择后端地址, 忽略大小写
+///
+/// 支持 `*.example.com` 形式的通配符, 精确匹配优先, 多个通配符匹配时取最长的
+pub(crate) fn match_route<'a>(
+    routes: &'a HashMap<String, String>,
+    host: &str,
+) -> Option<&'a String> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if let Some((_, endpoint)) = routes
+        .iter()
+        .find(|(pattern, _)| pattern.to_ascii_lowercase() == host)
+    {
+        return Some(endpoint);
+    }
+
+    routes
+        .iter()
+        .filter(|(pattern, _)| {
+            pattern.starts_with("*.") && host.ends_with(&pattern[1..].to_ascii_lowercase())
+        })
+        .max_by_key(|(pattern, _)| pattern.len())
+        .map(|(_, endpoint)| endpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(host: Option<&str>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher suites
+        body.extend_from_slice(&[0x01, 0x00]); // compression methods
+
+        let mut extensions = Vec::new();
+        if let Some(host) = host {
+            let mut list = vec![0x00];
+            list.extend_from_slice(&(host.len() as u16).to_be_bytes());
+            list.extend_from_slice(host.as_bytes());
+            let mut ext = (list.len() as u16).to_be_bytes().to_vec();
+            ext.extend(list);
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+            extensions.extend(ext);
+        }
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend(body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn test_peek_tls_sni() {
+        let data = client_hello(Some("api.example.com"));
+        assert_eq!(
+            peek_tls_sni(&data),
+            PeekResult::Host("api.example.com".into())
+        );
+        assert_eq!(
+            peek_tls_sni(&data[..data.len() - 1]),
+            PeekResult::Incomplete
+        );
+        assert_eq!(peek_tls_sni(&client_hello(None)), PeekResult::NoHost);
+        assert_eq!(peek_tls_sni(b"GET / HTTP/1.1\r\n"), PeekResult::NoHost);
+    }
+
+    #[test]
+    fn test_peek_label() {
+        assert_eq!(peek_label(b"api"), LabelLine::Incomplete);
+        assert_eq!(
+            peek_label(b"api\r\nPING"),
+            LabelLine::Label("api".into(), 5)
+        );
+        assert_eq!(peek_label(b"\nPING"), LabelLine::Label("".into(), 1));
+
+        let mut data = vec![b'a'; MAX_LABEL_LEN];
+        assert_eq!(peek_label(&data), LabelLine::Incomplete);
+        data.extend_from_slice(b"\r\n");
+        assert_eq!(
+            peek_label(&data),
+            LabelLine::Label("a".repeat(MAX_LABEL_LEN), MAX_LABEL_LEN + 2)
+        );
+        data.insert(0, b'a');
+        assert_eq!(peek_label(&data), LabelLine::NoLabel);
+        assert_eq!(peek_label(b"\xff\n"), LabelLine::NoLabel);
+    }
+
+    #[test]
+    fn test_http_host_route() {
+        let request = b"GET / HTTP/1.1\r\nHOST: Api.Example.com:8080\r\n\r\n";
+        assert_eq!(
+            peek_http_host(request),
+            PeekResult::Host("Api.Example.com".into())
+        );
+        assert_eq!(
+            peek_http_host(&request[..request.len() - 2]),
+            PeekResult::Incomplete
+        );
+
+        let routes: HashMap<String, String> = [
+            ("*.example.com", "a:80"),
+            ("*.api.example.com", "b:80"),
+            ("example.com", "c:80"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(match_route(&routes, "Example.com").unwrap(), "c:80");
+        assert_eq!(match_route(&routes, "www.example.com").unwrap(), "a:80");
+        assert_eq!(match_route(&routes, "v1.api.example.com").unwrap(), "b:80");
+        assert!(match_route(&routes, "example.org").is_none());
+    }
+}
@@ -1,19 +1,29 @@
 use crate::CommonArgs;
 use anyhow::anyhow;
-use byteorder::BigEndian;
-use byteorder::ByteOrder;
+use async_trait::async_trait;
 use bytes::BytesMut;
-use log::{debug, error, info};
-use np_base::net::tls;
+use log::{debug, error, info, warn};
+use np_base::net::{tls, ws, BoxedStream};
+use np_base::proxy::allowlist::DestinationAllowlist;
+use np_base::proxy::auth::{Authenticator, DenyAuthenticator, HttpAuthenticator};
+use np_base::proxy::breaker::CircuitBreakerConfig;
+use np_base::proxy::crypto::KeyDerivation;
+use np_base::proxy::dictionary::CompressionDictionary;
+use np_base::proxy::framing::LengthPrefix;
+use np_base::proxy::geoip::GeoDatabase;
 use np_base::proxy::inlet::{Inlet, InletDataEx, InletProxyType};
-use np_base::proxy::outlet::Outlet;
-use np_base::proxy::{OutputFuncType, ProxyMessage};
-use np_proto::class_def::{Tunnel, TunnelPoint};
-use np_proto::client_server::LoginReq;
-use np_proto::message_map::{encode_raw_message, get_message_id, get_message_size, MessageType};
+use np_base::proxy::outlet::{Outlet, OutletDataEx, TcpKeepaliveConfig};
+use np_base::proxy::recorder::MessageRecorder;
+use np_base::proxy::reject::RejectResponse;
+use np_base::proxy::transport::MessageTransport;
+use np_base::proxy::{crypto, ProxyMessage};
+use np_proto::class_def::{EndpointHealth, Tunnel, TunnelPoint};
+use np_proto::client_server::{BindStreamReq, LoginReq, ProbeEndpointAck};
+use np_proto::frame::{decode_frame, encode_frame, try_extract_frame};
+use np_proto::generic;
+use np_proto::message_map::MessageType;
 use np_proto::server_client::ModifyTunnelNtf;
 use np_proto::utils::message_bridge;
-use np_proto::{generic, message_map};
 use socket2::{SockRef, TcpKeepalive};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -22,55 +32,109 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, Wr
 use tokio::net::TcpStream;
 use tokio::select;
 use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinSet;
 use tokio::time::{sleep, timeout, Instant};
-use tokio_rustls::rustls::client::ServerCertVerified;
 use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
-use tokio_rustls::{rustls, TlsConnector};
+use tokio_rustls::TlsConnector;
 use webpki_roots::TLS_SERVER_ROOTS;
 
 const TIMEOUT_TLS: u64 = 30;
 
+type StreamWriter = Arc<Mutex<WriteHalf<BoxedStream>>>;
+
+/// 反向连接通道中出口为每个会话建立的连接, 键为(通道id, 会话id)
+type StreamMap = Arc<RwLock<HashMap<(u32, u32), StreamWriter>>>;
+
+/// 通过与服务器之间的TCP连接转发代理消息, 对端在本机时直接交给对应的入口或出口
+struct TunnelTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    tunnel_id: u32,
+    self_player_id: u32,
+    // 对端所在的玩家, 0表示交给服务器选择
+    player_id: u32,
+    outlets: Arc<RwLock<HashMap<u32, Arc<Outlet>>>>,
+    inlets: Arc<RwLock<HashMap<u32, Inlet>>>,
+    streams: StreamMap,
+    writer: Arc<Mutex<WriteHalf<S>>>,
+}
+
+#[async_trait]
+impl<S> MessageTransport for TunnelTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    async fn send(&self, message: ProxyMessage) {
+        Client::send_proxy_message(
+            self.outlets.clone(),
+            self.inlets.clone(),
+            self.streams.clone(),
+            self.writer.clone(),
+            self.self_player_id,
+            self.player_id,
+            self.tunnel_id,
+            message,
+        )
+        .await;
+    }
+}
+
 struct Client<S>
 where
     S: AsyncRead + AsyncWrite + Send + 'static,
 {
     writer: Arc<Mutex<WriteHalf<S>>>,
+    // 建立反向连接时按相同的参数连接服务器
+    common_args: Arc<CommonArgs>,
     username: String,
     password: String,
     player_id: u32,
     outlets: Arc<RwLock<HashMap<u32, Arc<Outlet>>>>,
     inlets: Arc<RwLock<HashMap<u32, Inlet>>>,
+    streams: StreamMap,
     tunnels: HashMap<u32, Tunnel>,
+    outlet_data_ex: OutletDataEx,
+    max_frame_size: usize,
+    chunk_size: usize,
+    read_timeout: u64,
+    write_timeout: u64,
+    ipv6_only: Option<bool>,
+    listen_backlog: u32,
+    // 入口和出口都在本机的通道是否开启splice
+    splice: bool,
+    // 入口消息录制目录, 为空时不录制
+    record_dir: String,
+    record_payload: bool,
+    geo_database: Option<Arc<GeoDatabase>>,
+    circuit_breaker: CircuitBreakerConfig,
 }
 
-struct NoCertificateVerifier;
-
-impl rustls::client::ServerCertVerifier for NoCertificateVerifier {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &rustls::Certificate,
-        _intermediates: &[rustls::Certificate],
-        _server_name: &ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
-        _ocsp_response: &[u8],
-        _now: SystemTime,
-    ) -> Result<ServerCertVerified, rustls::Error> {
-        Ok(ServerCertVerified::assertion())
+pub async fn run(
+    common_args: &CommonArgs,
+    geo_database: Option<Arc<GeoDatabase>>,
+) -> anyhow::Result<()> {
+    info!("Start connecting to server {}", common_args.server);
+    let stream = connect(common_args).await?;
+    info!("Successful connection with server {}", common_args.server);
+    if !common_args.websocket_path.is_empty() {
+        info!(
+            "WebSocket control channel established: {}",
+            common_args.websocket_path
+        );
     }
+    run_client(common_args, geo_database, stream).await
 }
 
-pub async fn run(common_args: &CommonArgs) -> anyhow::Result<()> {
-    info!("Start connecting to server {}", common_args.server);
-
+/// 连接服务器, 按参数升级为TLS连接和WebSocket
+async fn connect(common_args: &CommonArgs) -> anyhow::Result<BoxedStream> {
     let stream = TcpStream::connect(&common_args.server).await?;
     let ka = TcpKeepalive::new().with_time(Duration::from_secs(30));
     let sf = SockRef::from(&stream);
     sf.set_tcp_keepalive(&ka)?;
 
-    info!("Successful connection with server {}", common_args.server);
-
     // 升级为TLS连接
-    if common_args.enable_tls {
+    let stream: BoxedStream = if common_args.enable_tls {
         let mut root_cert_store = RootCertStore::empty();
 
         // 加载系统默认的根证书
@@ -103,7 +167,7 @@ pub async fn run(common_args: &CommonArgs) -> anyhow::Result<()> {
         if common_args.insecure {
             config
                 .dangerous()
-                .set_certificate_verifier(Arc::new(NoCertificateVerifier {}));
+                .set_certificate_verifier(Arc::new(tls::NoCertificateVerifier));
         }
 
         let str_vec: Vec<&str> = common_args.server.split(":").collect();
@@ -117,14 +181,33 @@ pub async fn run(common_args: &CommonArgs) -> anyhow::Result<()> {
             connector.connect(ServerName::try_from(str_vec[0])?, stream),
         )
         .await??;
-
-        run_client(common_args, stream).await
+        Box::new(stream)
     } else {
-        run_client(common_args, stream).await
+        Box::new(stream)
+    };
+
+    // 按参数选择直接使用连接还是先升级为WebSocket
+    if common_args.websocket_path.is_empty() {
+        return Ok(stream);
     }
+    let stream = timeout(
+        Duration::from_secs(TIMEOUT_TLS),
+        ws::connect(
+            stream,
+            &common_args.server,
+            &common_args.websocket_path,
+            common_args.max_message_size,
+        ),
+    )
+    .await??;
+    Ok(Box::new(stream))
 }
 
-async fn run_client<S>(common_args: &CommonArgs, stream: S) -> anyhow::Result<()>
+async fn run_client<S>(
+    common_args: &CommonArgs,
+    geo_database: Option<Arc<GeoDatabase>>,
+    stream: S,
+) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Send + 'static,
 {
@@ -134,12 +217,47 @@ where
 
     let mut client = Client::<S> {
         writer: writer.clone(),
+        common_args: Arc::new(common_args.clone()),
         username: common_args.username.clone(),
         password: common_args.password.clone(),
         player_id: 0u32,
         outlets: Arc::new(RwLock::new(HashMap::new())),
         inlets: Arc::new(RwLock::new(HashMap::new())),
+        streams: Arc::new(RwLock::new(HashMap::new())),
         tunnels: HashMap::new(),
+        outlet_data_ex: OutletDataEx::new()
+            .set_upstream_socks5(
+                common_args.socks5_proxy.clone(),
+                common_args.socks5_username.clone(),
+                common_args.socks5_password.clone(),
+            )
+            .set_bind_addr(common_args.bind_addr)
+            .set_send_proxy_protocol(common_args.send_proxy_protocol)
+            .set_keepalive(TcpKeepaliveConfig {
+                time: Duration::from_secs(common_args.outlet_keepalive_time),
+                interval: Duration::from_secs(common_args.outlet_keepalive_interval),
+                retries: common_args.outlet_keepalive_retries,
+            })
+            .set_send_timeout(
+                Duration::from_secs(common_args.outlet_send_timeout),
+                common_args.outlet_send_max_timeouts,
+            )
+            .set_allowlist(DestinationAllowlist::parse(&common_args.allow_destination)?),
+        max_frame_size: common_args.max_frame_size,
+        chunk_size: common_args.chunk_size,
+        read_timeout: common_args.read_timeout,
+        write_timeout: common_args.write_timeout,
+        ipv6_only: common_args.ipv6_only,
+        listen_backlog: common_args.listen_backlog,
+        splice: common_args.splice,
+        record_dir: common_args.record_dir.clone(),
+        record_payload: common_args.record_payload,
+        geo_database,
+        circuit_breaker: CircuitBreakerConfig::new(
+            common_args.breaker_threshold,
+            common_args.breaker_window,
+            common_args.breaker_cooldown,
+        ),
     };
 
     client.send_login().await?;
@@ -220,7 +338,7 @@ where
                         break;
                     }
 
-                    let result = try_extract_frame(&mut buffer)?;
+                    let result = try_extract_frame(&mut buffer, self.common_args.max_message_size)?;
                     if let Some(frame) = result {
                         // 收到完整消息
                         self.on_recv_frame(frame).await?;
@@ -250,15 +368,7 @@ where
     }
 
     async fn on_recv_frame(&mut self, frame: Vec<u8>) -> anyhow::Result<()> {
-        if frame.len() < 8 {
-            return Err(anyhow!("message length is too small"));
-        }
-        // 消息序号
-        let serial: i32 = BigEndian::read_i32(&frame[0..4]);
-        // 消息类型id
-        let msg_id: u32 = BigEndian::read_u32(&frame[4..8]);
-
-        let message = message_map::decode_message(msg_id, &frame[8..])?;
+        let (serial, _, message) = decode_frame(&frame)?;
         self.handle_message(serial, message).await
     }
 
@@ -289,12 +399,14 @@ where
         } else {
             if serial == 0 {
                 self.handle_push(message).await?;
+            } else if serial < 0 {
+                self.handle_request(serial, message).await?;
             }
         }
         Ok(())
     }
 
-    async fn sync_tunnels(&mut self, tunnels: &Vec<Tunnel>) {
+    async fn sync_tunnels(&mut self, tunnels: &[Tunnel]) {
         // 收集无效的出口
         let mut keys_to_remove: Vec<_> = self
             .outlets
@@ -305,12 +417,12 @@ where
                 let retain = tunnels.iter().any(|tunnel| {
                     **id == tunnel.id
                         && tunnel.enabled
-                        && tunnel.sender == self.player_id
-                        && &outlet_description(&tunnel) == outlet.description()
+                        && is_outlet_player(tunnel, self.player_id)
+                        && &outlet_description(tunnel) == outlet.description()
                 });
                 !retain
             })
-            .map(|(key, _)| key.clone())
+            .map(|(key, _)| *key)
             .collect();
 
         // 删除无效的出口
@@ -334,17 +446,17 @@ where
                     **id == tunnel.id
                         && tunnel.enabled
                         && tunnel.receiver == self.player_id
-                        && &inlet_description(&tunnel) == inlet.description()
+                        && &inlet_description(tunnel) == inlet.description()
                 });
-                return !retain;
+                !retain
             })
-            .map(|(key, _)| key.clone())
+            .map(|(key, _)| *key)
             .collect();
 
         // 删除无效入口
         for key in keys_to_remove {
             if let Some(mut inlet) = self.inlets.write().await.remove(&key) {
-                let description = inlet.description().to_owned();
+                let description = inlet.redacted_description().to_owned();
                 debug!("start deleting the inlet({description})");
                 inlet.stop().await;
                 debug!("delete inlet({description}) end");
@@ -354,46 +466,44 @@ where
         // 添加代理出口
         for tunnel in tunnels
             .iter()
-            .filter(|tunnel| tunnel.enabled && tunnel.sender == self.player_id)
+            .filter(|tunnel| tunnel.enabled && is_outlet_player(tunnel, self.player_id))
         {
             if !self.outlets.read().await.contains_key(&tunnel.id) {
-                let this_machine = tunnel.receiver == tunnel.sender;
-                let inlets = self.inlets.clone();
-                let outlets = self.outlets.clone();
-                let writer = self.writer.clone();
-                let self_player_id = self.player_id;
                 let tunnel_id = tunnel.id;
-                let player_id = tunnel.receiver;
-
-                let outlet_output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
-                    let inlets = inlets.clone();
-                    let outlets = outlets.clone();
-                    let writer = writer.clone();
-                    Box::pin(async move {
-                        if this_machine {
-                            if let Some(inlet) = inlets.read().await.get(&tunnel_id) {
-                                inlet.input(message).await;
-                            } else {
-                                debug!("unknown inlet({tunnel_id})");
-                            }
-                        } else {
-                            Self::send_proxy_message(
-                                outlets,
-                                inlets,
-                                writer,
-                                self_player_id,
-                                player_id,
-                                tunnel_id,
-                                message,
-                            )
-                            .await;
-                        }
-                    })
+                let outlet_output = Arc::new(TunnelTransport {
+                    tunnel_id,
+                    self_player_id: self.player_id,
+                    player_id: tunnel.receiver,
+                    outlets: self.outlets.clone(),
+                    inlets: self.inlets.clone(),
+                    streams: self.streams.clone(),
+                    writer: self.writer.clone(),
                 });
-                debug!("start outlet({})", outlet_description(&tunnel));
+                debug!("start outlet({})", outlet_description(tunnel));
                 self.outlets.write().await.insert(
                     tunnel_id,
-                    Outlet::new(outlet_output, outlet_description(&tunnel)),
+                    Outlet::new(
+                        outlet_output,
+                        outlet_description(tunnel),
+                        self.outlet_data_ex
+                            .clone()
+                            .set_compression_dictionary(compression_dictionary(tunnel))
+                            .set_connection_pool(
+                                tunnel.pool_size as usize,
+                                Duration::from_secs(tunnel.pool_max_idle as u64),
+                            )
+                            .set_length_prefix(
+                                LengthPrefix::from_u32(tunnel.frame_prefix).unwrap_or_default(),
+                            )
+                            .set_buffer_limit(tunnel.outlet_buffer_limit as usize)
+                            .set_key_derivation(
+                                KeyDerivation::from_u32(tunnel.key_derivation).unwrap_or_default(),
+                                &tunnel.password,
+                            )
+                            .set_nodelay(tunnel.outlet_nodelay)
+                            .set_fast_open(tunnel.fast_open)
+                            .set_label_routes(label_routes(tunnel)),
+                    ),
                 );
             }
         }
@@ -404,13 +514,12 @@ where
             .filter(|tunnel| tunnel.enabled && tunnel.receiver == self.player_id)
         {
             if !self.inlets.read().await.contains_key(&tunnel.id) {
-                let this_machine = tunnel.receiver == tunnel.sender;
-                let tunnel_id = tunnel.id;
-                let inlets = self.inlets.clone();
-                let outlets = self.outlets.clone();
-                let writer = self.writer.clone();
-                let self_player_id = self.player_id;
-                let player_id = tunnel.sender;
+                // 有多个出口时交给服务器选择出口
+                let player_id = if tunnel.extra_senders.is_empty() {
+                    tunnel.sender
+                } else {
+                    0
+                };
 
                 let source = match tunnel.source {
                     Some(ref x) => x.addr.clone(),
@@ -421,35 +530,23 @@ where
                     None => "".to_string(),
                 };
 
-                let inlet_output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
-                    let inlets = inlets.clone();
-                    let outlets = outlets.clone();
-                    let writer = writer.clone();
-                    Box::pin(async move {
-                        if this_machine {
-                            if let Some(outlet) = outlets.read().await.get(&tunnel_id) {
-                                outlet.input(message).await;
-                            } else {
-                                debug!("unknown outlet({tunnel_id})");
-                            }
-                        } else {
-                            Self::send_proxy_message(
-                                outlets,
-                                inlets,
-                                writer,
-                                self_player_id,
-                                player_id,
-                                tunnel_id,
-                                message,
-                            )
-                            .await;
-                        }
-                    })
+                let inlet_output = Arc::new(TunnelTransport {
+                    tunnel_id: tunnel.id,
+                    self_player_id: self.player_id,
+                    player_id,
+                    outlets: self.outlets.clone(),
+                    inlets: self.inlets.clone(),
+                    streams: self.streams.clone(),
+                    writer: self.writer.clone(),
                 });
 
                 if let Some(inlet_proxy_type) = InletProxyType::from_u32(tunnel.tunnel_type as u32)
                 {
-                    let mut inlet = Inlet::new(inlet_output, inlet_description(&tunnel));
+                    let mut inlet = Inlet::new(inlet_output, inlet_description(tunnel))
+                        .set_redacted_description(redacted_description(tunnel))
+                        .set_settings_description(inlet_settings_description(tunnel));
+                    inlet.set_paused(tunnel.paused);
+                    inlet.set_over_quota(tunnel.over_quota);
                     if let Err(err) = inlet
                         .start(
                             inlet_proxy_type,
@@ -457,13 +554,14 @@ where
                             endpoint.clone(),
                             tunnel.is_compressed,
                             tunnel.encryption_method.clone(),
-                            InletDataEx::new(tunnel.username.clone(), tunnel.password.clone()),
+                            self.inlet_data_ex(tunnel)
+                                .set_recorder(self.message_recorder(tunnel.id)),
                         )
                         .await
                     {
                         error!("inlet({}) start error: {}", source, err);
                     } else {
-                        debug!("start inlet({})", inlet.description());
+                        debug!("start inlet({})", inlet.redacted_description());
                         self.inlets.write().await.insert(tunnel.id, inlet);
                     }
                 } else {
@@ -474,11 +572,100 @@ where
                 }
             }
         }
+
+        // 只有可以运行中应用的设置变化时, 不重启入口
+        for (id, inlet) in self.inlets.write().await.iter_mut() {
+            if let Some(tunnel) = tunnels.iter().find(|tunnel| tunnel.id == *id) {
+                let settings_description = inlet_settings_description(tunnel);
+                if &settings_description != inlet.settings_description() {
+                    debug!("reconfigure inlet({})", inlet.redacted_description());
+                    inlet.reconfigure(settings_description, self.inlet_data_ex(tunnel));
+                }
+            }
+        }
+
+        // 同步入口暂停状态
+        for (id, inlet) in self.inlets.read().await.iter() {
+            if let Some(tunnel) = tunnels.iter().find(|tunnel| tunnel.id == *id) {
+                inlet.set_paused(tunnel.paused);
+                inlet.set_over_quota(tunnel.over_quota);
+            }
+        }
+    }
+
+    /// 指定了录制目录时为入口创建录制文件
+    fn message_recorder(&self, tunnel_id: u32) -> Option<Arc<MessageRecorder>> {
+        if self.record_dir.is_empty() {
+            return None;
+        }
+        MessageRecorder::create_for_tunnel(&self.record_dir, tunnel_id, self.record_payload)
+            .map_err(|err| error!("tunnel({tunnel_id}) {err}"))
+            .ok()
+    }
+
+    /// 按通道配置创建入口设置
+    fn inlet_data_ex(&self, tunnel: &Tunnel) -> InletDataEx {
+        let tunnel_id = tunnel.id;
+        InletDataEx::new(tunnel.username.clone(), tunnel.password.clone())
+            .set_max_frame_size(self.max_frame_size)
+            .set_chunk_size(self.chunk_size)
+            .set_idle_deadlines(self.read_timeout, self.write_timeout)
+            .set_circuit_breaker(self.circuit_breaker.clone())
+            .set_on_breaker_change(Arc::new(move |state| {
+                warn!("tunnel({tunnel_id}) circuit breaker {state}");
+            }))
+            .set_max_session_lifetime(tunnel.max_session_lifetime as u64)
+            .set_accept_proxy_protocol(tunnel.accept_proxy_protocol)
+            .set_write_coalesce(tunnel.write_coalesce as u64)
+            .set_connect_retry(tunnel.connect_retries, tunnel.connect_retry_delay as u64)
+            .set_conn_rate_limit(tunnel.conn_rate_limit, tunnel.conn_rate_burst)
+            .set_ipv6_only(self.ipv6_only)
+            .set_listen_backlog(self.listen_backlog)
+            .set_nodelay(tunnel.inlet_nodelay)
+            .set_fast_open(tunnel.fast_open)
+            .set_encryption_fallback(tunnel.encryption_fallback)
+            .set_compress_threshold(tunnel.compress_threshold as usize)
+            .set_bind_device(tunnel.bind_device.clone())
+            .set_tls(
+                tunnel.tls_cert.clone(),
+                tunnel.tls_key.clone(),
+                tunnel.tls_alpn.clone(),
+            )
+            .set_backend_tls(tunnel.backend_tls)
+            .set_compression_dictionary(compression_dictionary(tunnel))
+            .set_authenticator(authenticator(tunnel))
+            .set_reject_response(RejectResponse::parse(&tunnel.reject_response).unwrap_or_default())
+            .set_length_prefix(LengthPrefix::from_u32(tunnel.frame_prefix).unwrap_or_default())
+            .set_key_derivation(
+                KeyDerivation::from_u32(tunnel.key_derivation).unwrap_or_default(),
+                &tunnel.password,
+            )
+            .set_geo_database(self.geo_database.clone())
+            .set_o2i_codec(
+                tunnel.o2i_is_compressed,
+                tunnel.o2i_encryption_method.clone(),
+            )
+            .set_host_routes(tunnel.custom_mapping.clone())
+            .set_splice_outlet(self.splice_outlet(tunnel))
+    }
+
+    /// 入口和出口都在本机时, 入口直接连接后端使用的出口设置
+    fn splice_outlet(&self, tunnel: &Tunnel) -> Option<OutletDataEx> {
+        if !self.splice || tunnel.sender != self.player_id || !tunnel.extra_senders.is_empty() {
+            return None;
+        }
+        Some(
+            self.outlet_data_ex
+                .clone()
+                .set_nodelay(tunnel.outlet_nodelay),
+        )
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn send_proxy_message(
         outlets: Arc<RwLock<HashMap<u32, Arc<Outlet>>>>,
         inlets: Arc<RwLock<HashMap<u32, Inlet>>>,
+        streams: StreamMap,
         writer: Arc<Mutex<WriteHalf<S>>>,
         self_player_id: u32,
         player_id: u32,
@@ -489,13 +676,32 @@ where
             if message_bridge::is_i2o_message(&proxy_message) {
                 if let Some(outlet) = outlets.read().await.get(&tunnel_id) {
                     outlet.input(proxy_message).await;
+                } else {
+                    debug!("unknown outlet({tunnel_id})");
                 }
             } else {
                 if let Some(inlet) = inlets.read().await.get(&tunnel_id) {
                     inlet.input(proxy_message).await;
+                } else {
+                    debug!("unknown inlet({tunnel_id})");
                 }
             }
         } else {
+            // 反向连接的会话通过出口建立的连接发送
+            if !message_bridge::is_i2o_message(&proxy_message) {
+                let key = (tunnel_id, proxy_message.session_id());
+                let stream = streams.read().await.get(&key).cloned();
+                if let Some(stream) = stream {
+                    let is_disconnect = matches!(proxy_message, ProxyMessage::O2iDisconnect(..));
+                    let message = message_bridge::proxy_message_2_pb(proxy_message, tunnel_id);
+                    let _ = package_and_send_message(stream.clone(), 0, &message).await;
+                    if is_disconnect {
+                        close_stream(&streams, key, &stream).await;
+                    }
+                    return;
+                }
+            }
+
             let message = message_bridge::proxy_message_2_pb(proxy_message, tunnel_id);
             if !message.is_none() {
                 let _ = package_and_send_message(writer, 0, &message).await;
@@ -503,17 +709,126 @@ where
         }
     }
 
+    // 收到服务器发起的请求
+    async fn handle_request(&mut self, serial: i32, message: MessageType) -> anyhow::Result<()> {
+        match message {
+            MessageType::ServerClientProbeEndpointReq(msg) => {
+                let endpoints: Vec<(u32, String)> = msg
+                    .tunnel_id_list
+                    .iter()
+                    .filter_map(|id| self.tunnels.get(id))
+                    .filter(|tunnel| is_outlet_player(tunnel, self.player_id))
+                    .map(|tunnel| (tunnel.id, tunnel.endpoint.clone().unwrap_or_default().addr))
+                    .collect();
+                let data_ex = self.outlet_data_ex.clone();
+                let writer = self.writer.clone();
+                let probe_timeout = Duration::from_secs(msg.timeout.max(1) as u64);
+                // 探测耗时较长, 不阻塞控制通道
+                tokio::spawn(async move {
+                    let mut probes = JoinSet::new();
+                    for (tunnel_id, endpoint) in endpoints {
+                        let data_ex = data_ex.clone();
+                        probes.spawn(async move {
+                            match data_ex.probe(&endpoint, probe_timeout).await {
+                                Ok(latency) => EndpointHealth {
+                                    tunnel_id,
+                                    reachable: true,
+                                    latency: latency.as_millis() as u32,
+                                    error: String::new(),
+                                },
+                                Err(err) => EndpointHealth {
+                                    tunnel_id,
+                                    reachable: false,
+                                    latency: 0,
+                                    error: err.to_string(),
+                                },
+                            }
+                        });
+                    }
+                    let mut health_list = Vec::new();
+                    while let Some(result) = probes.join_next().await {
+                        if let Ok(health) = result {
+                            health_list.push(health);
+                        }
+                    }
+                    let _ = package_and_send_message(
+                        writer,
+                        -serial,
+                        &MessageType::ClientServerProbeEndpointAck(ProbeEndpointAck {
+                            health_list,
+                        }),
+                    )
+                    .await;
+                });
+            }
+            _ => {
+                package_and_send_message(
+                    self.writer.clone(),
+                    -serial,
+                    &MessageType::GenericError(generic::Error {
+                        number: generic::ErrorCode::InterfaceAbsent.into(),
+                        message: "interface absent".into(),
+                    }),
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
     // 收到玩家向服务器推送消息
     pub(crate) async fn handle_push(&mut self, message: MessageType) -> anyhow::Result<()> {
         match message {
             MessageType::ServerClientModifyTunnelNtf(msg) => {
                 self.on_server_client_modify_tunnel_ntf(msg).await
             }
+            MessageType::ServerClientShutdownNtf(msg) => {
+                warn!(
+                    "Server is shutting down, sessions will be closed within {}s",
+                    msg.grace_period
+                );
+            }
+            MessageType::ServerClientTunnelBreakerNtf(msg) => {
+                warn!(
+                    "tunnel({}) circuit breaker on server: {}",
+                    msg.tunnel_id, msg.state
+                );
+            }
+            MessageType::ServerClientTunnelHealthNtf(msg) => {
+                if let Some(health) = msg.health {
+                    if health.reachable {
+                        info!(
+                            "tunnel({}) endpoint is reachable from outlet player({})",
+                            health.tunnel_id, msg.player_id
+                        );
+                    } else {
+                        warn!(
+                            "tunnel({}) endpoint is unreachable from outlet player({}): {}",
+                            health.tunnel_id, msg.player_id, health.error
+                        );
+                    }
+                }
+            }
+            MessageType::ServerClientTunnelQuotaNtf(msg) => {
+                warn!(
+                    "tunnel({}) exceeded its traffic quota: {}/{} bytes",
+                    msg.tunnel_id, msg.bytes_used, msg.bytes_quota
+                );
+            }
             _ => {
                 if let Some((msg, tunnel_id)) = message_bridge::pb_2_proxy_message(message) {
+                    if let ProxyMessage::I2oOpenStream(session_id, _, token) = msg {
+                        self.open_stream(tunnel_id, session_id, token);
+                        return Ok(());
+                    }
                     if let Some(tunnel) = self.tunnels.get(&tunnel_id) {
                         let player_id = if message_bridge::is_i2o_message(&msg) {
-                            tunnel.sender
+                            // 服务器已经选择了出口, 出口在本机时直接处理
+                            if is_outlet_player(tunnel, self.player_id) {
+                                self.player_id
+                            } else {
+                                tunnel.sender
+                            }
                         } else {
                             tunnel.receiver
                         };
@@ -521,6 +836,7 @@ where
                         Self::send_proxy_message(
                             self.outlets.clone(),
                             self.inlets.clone(),
+                            self.streams.clone(),
                             self.writer.clone(),
                             self.player_id,
                             player_id,
@@ -536,6 +852,20 @@ where
         Ok(())
     }
 
+    /// 服务器请求为反向连接通道的会话建立新连接
+    fn open_stream(&self, tunnel_id: u32, session_id: u32, token: String) {
+        let common_args = self.common_args.clone();
+        let outlets = self.outlets.clone();
+        let streams = self.streams.clone();
+        tokio::spawn(async move {
+            if let Err(err) =
+                run_stream(common_args, outlets, streams, tunnel_id, session_id, token).await
+            {
+                warn!("tunnel({tunnel_id}) session({session_id}) reverse stream error: {err}");
+            }
+        });
+    }
+
     async fn on_server_client_modify_tunnel_ntf(&mut self, msg: ModifyTunnelNtf) {
         if let Some(tunnel) = msg.tunnel {
             self.tunnels.remove(&tunnel.id);
@@ -543,8 +873,7 @@ where
                 return;
             }
             self.tunnels.insert(tunnel.id, tunnel);
-            let tunnel_list: Vec<Tunnel> =
-                self.tunnels.clone().into_iter().map(|(_, x)| x).collect();
+            let tunnel_list: Vec<Tunnel> = self.tunnels.clone().into_values().collect();
             self.sync_tunnels(&tunnel_list).await;
         }
     }
@@ -559,93 +888,250 @@ pub(crate) async fn package_and_send_message<S>(
 where
     S: AsyncRead + AsyncWrite + Send + 'static,
 {
-    if let Some(message_id) = get_message_id(message) {
-        let message_size = get_message_size(message);
-        let mut buf = Vec::with_capacity(message_size + 14);
-
-        byteorder::WriteBytesExt::write_u8(&mut buf, 33u8)?;
-        byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut buf, (8 + message_size) as u32)?;
-        byteorder::WriteBytesExt::write_i32::<BigEndian>(&mut buf, serial)?;
-        byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut buf, message_id)?;
-        encode_raw_message(message, &mut buf);
-
-        writer.lock().await.write_all(&buf).await?;
+    if let Some(buf) = encode_frame(serial, message) {
+        let mut writer = writer.lock().await;
+        writer.write_all(&buf).await?;
+        // WebSocket连接需要flush才能保证消息完整发出
+        writer.flush().await?;
         Ok(())
     } else {
         Err(anyhow!("Message id not found"))
     }
 }
 
-/// 数据粘包处理
-///
-/// 注意：这个函数只能使用消耗 buffer 数据的函数，否则框架会一直循环调用本函数来驱动处理消息
-///
-fn try_extract_frame(buffer: &mut BytesMut) -> anyhow::Result<Option<Vec<u8>>> {
-    if buffer.len() > 0 {
-        if buffer[0] != 33u8 {
-            return Err(anyhow!("Bad flag"));
+/// 建立到服务器的连接并绑定会话, 之后这个会话的代理消息都通过这个连接收发
+async fn run_stream(
+    common_args: Arc<CommonArgs>,
+    outlets: Arc<RwLock<HashMap<u32, Arc<Outlet>>>>,
+    streams: StreamMap,
+    tunnel_id: u32,
+    session_id: u32,
+    token: String,
+) -> anyhow::Result<()> {
+    let stream = connect(&common_args).await?;
+    let (mut reader, writer) = tokio::io::split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+    package_and_send_message(
+        writer.clone(),
+        -1,
+        &MessageType::ClientServerBindStreamReq(BindStreamReq {
+            tunnel_id,
+            session_id,
+            token,
+        }),
+    )
+    .await?;
+    debug!("tunnel({tunnel_id}) session({session_id}) reverse stream opened");
+
+    let key = (tunnel_id, session_id);
+    streams.write().await.insert(key, writer.clone());
+    let result = read_stream(
+        &mut reader,
+        &outlets,
+        tunnel_id,
+        common_args.max_message_size,
+    )
+    .await;
+
+    // 连接断开时会话还没有结束, 关闭出口的会话
+    if close_stream(&streams, key, &writer).await && !matches!(result, Ok(true)) {
+        if let Some(outlet) = outlets.read().await.get(&tunnel_id) {
+            outlet.input(ProxyMessage::I2oDisconnect(session_id)).await;
         }
     }
-    // 数据小于5字节,继续读取数据
-    if buffer.len() < 5 {
-        return Ok(None);
-    }
+    result.map(|_| ())
+}
 
-    // 读取包长度
-    let buf = buffer.get(1..5).unwrap();
-    let len = BigEndian::read_u32(buf) as usize;
+/// 把反向连接上收到的代理消息交给出口, 收到入口断开的消息时返回true
+async fn read_stream(
+    reader: &mut ReadHalf<BoxedStream>,
+    outlets: &Arc<RwLock<HashMap<u32, Arc<Outlet>>>>,
+    tunnel_id: u32,
+    max_message_size: usize,
+) -> anyhow::Result<bool> {
+    let mut buffer = BytesMut::with_capacity(65536);
+    loop {
+        if reader.read_buf(&mut buffer).await? == 0 {
+            return Ok(false);
+        }
+        while let Some(frame) = try_extract_frame(&mut buffer, max_message_size)? {
+            let (serial, _, message) = decode_frame(&frame)?;
 
-    // 超出最大限制
-    if len <= 0 || len >= 1024 * 1024 * 5 {
-        return Err(anyhow!("Message too long"));
-    }
+            // 绑定请求的回复
+            if serial != 0 {
+                if let MessageType::GenericError(err) = message {
+                    return Err(anyhow!("bind stream failed: {}", err.message));
+                }
+                continue;
+            }
 
-    // 数据不够,继续读取数据
-    if buffer.len() < 5 + len {
-        return Ok(None);
+            if let Some((msg, _)) = message_bridge::pb_2_proxy_message(message) {
+                let is_disconnect = matches!(msg, ProxyMessage::I2oDisconnect(_));
+                if let Some(outlet) = outlets.read().await.get(&tunnel_id) {
+                    outlet.input(msg).await;
+                }
+                if is_disconnect {
+                    return Ok(true);
+                }
+            }
+        }
     }
+}
 
-    // 拆出这个包的数据
-    let frame = buffer.split_to(5 + len).split_off(5).to_vec();
-
-    Ok(Some(frame))
+/// 删除并关闭反向连接, 已经被删除或替换时返回false
+async fn close_stream(streams: &StreamMap, key: (u32, u32), writer: &StreamWriter) -> bool {
+    let removed = {
+        let mut streams = streams.write().await;
+        match streams.get(&key) {
+            Some(x) if Arc::ptr_eq(x, writer) => streams.remove(&key).is_some(),
+            _ => false,
+        }
+    };
+    if removed {
+        let _ = writer.lock().await.shutdown().await;
+    }
+    removed
 }
 
 fn fmt_point(point: &Option<TunnelPoint>) -> String {
     match point {
-        Some(point) => {
-            format!("{}", point.addr)
-        }
+        Some(point) => point.addr.to_string(),
         _ => "none".to_string(),
     }
 }
 
+/// 玩家是否提供该通道的出口
+fn is_outlet_player(tunnel: &Tunnel, player_id: u32) -> bool {
+    tunnel.sender == player_id || tunnel.extra_senders.contains(&player_id)
+}
+
+/// 加载通道的压缩字典, 失败时不使用字典, 会话会因为与对端不一致而连接失败
+fn compression_dictionary(tunnel: &Tunnel) -> Option<Arc<CompressionDictionary>> {
+    CompressionDictionary::load(&tunnel.compression_dictionary).unwrap_or_else(|err| {
+        error!("tunnel({}) {err}", tunnel.id);
+        None
+    })
+}
+
+/// 通道配置的外部认证接口, 地址无效时拒绝所有用户
+fn authenticator(tunnel: &Tunnel) -> Option<Arc<dyn Authenticator>> {
+    if tunnel.auth_url.is_empty() {
+        return None;
+    }
+    match HttpAuthenticator::new(&tunnel.auth_url) {
+        Ok(authenticator) => Some(Arc::new(authenticator)),
+        Err(err) => {
+            error!("tunnel({}) {err}", tunnel.id);
+            Some(Arc::new(DenyAuthenticator))
+        }
+    }
+}
+
 fn outlet_description(tunnel: &Tunnel) -> String {
+    let mut label_routes: Vec<_> = label_routes(tunnel).into_iter().collect();
+    label_routes.sort();
     format!(
-        "id:{}-sender:{}-enabled:{}",
-        tunnel.id, tunnel.sender, tunnel.enabled
+        "id:{}-sender:{}-enabled:{}-compression_dictionary:{}-pool_size:{}-pool_max_idle:{}-frame_prefix:{}-outlet_buffer_limit:{}-key_derivation:{}-outlet_nodelay:{}-fast_open:{}-label_routes:{:?}-password:{}",
+        tunnel.id,
+        tunnel.sender,
+        tunnel.enabled,
+        tunnel.compression_dictionary,
+        tunnel.pool_size,
+        tunnel.pool_max_idle,
+        tunnel.frame_prefix,
+        tunnel.outlet_buffer_limit,
+        tunnel.key_derivation,
+        tunnel.outlet_nodelay,
+        tunnel.fast_open,
+        label_routes,
+        crypto::password_fingerprint(&tunnel.password),
     )
 }
 
+/// 按标签路由的通道由出口选择后端, 路由表为 `custom_mapping`, 其他类型为空
+fn label_routes(tunnel: &Tunnel) -> HashMap<String, String> {
+    match InletProxyType::from_u32(tunnel.tunnel_type as u32) {
+        Some(InletProxyType::LABEL) => tunnel.custom_mapping.clone(),
+        _ => HashMap::new(),
+    }
+}
+
+/// 入口配置的完整描述, 用于判断配置是否变化, 不要写入日志
 fn inlet_description(tunnel: &Tunnel) -> String {
-    let custom_mapping: String = tunnel
-        .custom_mapping
-        .iter()
+    format_inlet_description(
+        tunnel,
+        &tunnel.username,
+        &crypto::password_fingerprint(&tunnel.password),
+    )
+}
+
+/// 隐去用户名和密码的入口描述, 用于日志
+fn redacted_description(tunnel: &Tunnel) -> String {
+    format_inlet_description(tunnel, redact(&tunnel.username), redact(&tunnel.password))
+}
+
+/// 日志中隐去的敏感字段, 未设置时保持为空
+fn redact(value: &str) -> &'static str {
+    if value.is_empty() {
+        ""
+    } else {
+        "***"
+    }
+}
+
+/// 入口中可以在运行中应用的设置, 只有这些变化时不重启入口
+fn inlet_settings_description(tunnel: &Tunnel) -> String {
+    let mut custom_mapping: Vec<_> = tunnel.custom_mapping.iter().collect();
+    custom_mapping.sort();
+    let custom_mapping: String = custom_mapping
+        .into_iter()
         .map(|(key, value)| format!("{}:{}\n", key, value))
         .collect();
     format!(
-        "id:{}-source:{}-endpoint:{}-sender:{}-receiver:{}-tunnel_type:{}-username:{}-password:{}-enabled:{}-is_compressed:{}-encryption_method:{}-custom_mapping:[{}]",
+        "custom_mapping:[{}]-max_session_lifetime:{}-accept_proxy_protocol:{}-write_coalesce:{}-connect_retries:{}-connect_retry_delay:{}-conn_rate_limit:{}-conn_rate_burst:{}-backend_tls:{}-compression_dictionary:{}-auth_url:{}-frame_prefix:{}-reject_response:{}-key_derivation:{}-compress_threshold:{}",
+        custom_mapping,
+        tunnel.max_session_lifetime,
+        tunnel.accept_proxy_protocol,
+        tunnel.write_coalesce,
+        tunnel.connect_retries,
+        tunnel.connect_retry_delay,
+        tunnel.conn_rate_limit,
+        tunnel.conn_rate_burst,
+        tunnel.backend_tls,
+        tunnel.compression_dictionary,
+        tunnel.auth_url,
+        tunnel.frame_prefix,
+        tunnel.reject_response,
+        tunnel.key_derivation,
+        tunnel.compress_threshold,
+    )
+}
+
+/// 需要重启入口才能生效的设置
+fn format_inlet_description(tunnel: &Tunnel, username: &str, password: &str) -> String {
+    format!(
+        "id:{}-source:{}-endpoint:{}-sender:{}-receiver:{}-tunnel_type:{}-username:{}-password:{}-enabled:{}-is_compressed:{}-encryption_method:{}-o2i_is_compressed:{:?}-o2i_encryption_method:{:?}-extra_senders:{:?}-bind_device:{}-balance_policy:{}-inlet_nodelay:{}-fast_open:{}-encryption_fallback:{}-tls_cert:{}-tls_key:{}-tls_alpn:{}",
         tunnel.id,
         fmt_point(&tunnel.source),
         fmt_point(&tunnel.endpoint),
         tunnel.sender,
         tunnel.receiver,
         tunnel.tunnel_type,
-        tunnel.username,
-        tunnel.password,
+        username,
+        password,
         tunnel.enabled,
         tunnel.is_compressed,
         tunnel.encryption_method,
-        custom_mapping,
+        tunnel.o2i_is_compressed,
+        tunnel.o2i_encryption_method,
+        tunnel.extra_senders,
+        tunnel.bind_device,
+        tunnel.balance_policy,
+        tunnel.inlet_nodelay,
+        tunnel.fast_open,
+        tunnel.encryption_fallback,
+        tunnel.tls_cert,
+        tunnel.tls_key,
+        tunnel.tls_alpn,
     )
 }
@@ -2,8 +2,16 @@ use clap::{Args, Parser, Subcommand};
 use flexi_logger::{
     Age, Cleanup, Criterion, Duplicate, FileSpec, Logger, LoggerHandle, Naming, WriteMode,
 };
-use log::error;
+use log::{error, info};
+use np_base::proxy::allowlist::DestinationAllowlist;
+use np_base::proxy::geoip::GeoDatabase;
+use np_base::proxy::outlet::check_bind_addr;
+use np_base::proxy::{
+    default_global_buffer_budget, set_global_buffer_budget, set_global_pool_limit,
+};
 use once_cell::sync::OnceCell;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use std::{env, panic};
 use tokio::time::sleep;
@@ -12,7 +20,7 @@ mod client;
 #[cfg(windows)]
 mod winservice;
 
-#[derive(Args)]
+#[derive(Args, Clone)]
 pub(crate) struct CommonArgs {
     /// print backtracking information
     #[arg(long, default_value_t = false, action = clap::ArgAction::Set)]
@@ -38,10 +46,122 @@ pub(crate) struct CommonArgs {
     #[arg(long, default_value = "false")]
     pub insecure: bool,
 
+    /// run the control channel over WebSocket at this path of the server's web address (e.g. /ctl), so it can pass HTTP proxies and CDNs; --server should then point to the web address
+    #[arg(long, default_value = "")]
+    pub websocket_path: String,
+
     /// ca file path (optional), if not provided, the client’s certificate will not be verified.
     #[arg(long, default_value = "")]
     pub ca_cert: String,
 
+    /// maximum size of a single control channel message from the server, the connection is closed beyond it
+    #[arg(long, default_value_t = np_proto::DEFAULT_MAX_MESSAGE_SIZE)]
+    pub max_message_size: usize,
+
+    /// maximum frame size extracted by inlets per read, 0 means the default
+    #[arg(long, default_value = "0")]
+    pub max_frame_size: usize,
+
+    /// split frames sent by stream inlets into chunks of this size, each encrypted separately, 0 means no chunking; UDP and DNS datagrams are never split
+    #[arg(long, default_value = "0")]
+    pub chunk_size: usize,
+
+    /// pause reading from sessions while the data sent but not yet acknowledged across all sessions exceeds this many MB, 0 means a quarter of physical memory
+    #[arg(long, default_value = "0")]
+    pub buffer_budget: usize,
+
+    /// maximum number of idle connections across all outlet connection pools, the least recently pooled ones are closed beyond it, 0 means no limit
+    #[arg(long, default_value = "1024")]
+    pub pool_limit: usize,
+
+    /// record the messages of each inlet to a file in this directory for debugging and replay, disabled if not provided
+    #[arg(long, default_value = "")]
+    pub record_dir: String,
+
+    /// record full payloads instead of their length and hash
+    #[arg(long, default_value = "false")]
+    pub record_payload: bool,
+
+    /// close inlet sessions with no data transferred in either direction for this many seconds, 0 means no limit
+    #[arg(long, default_value = "0")]
+    pub read_timeout: u64,
+
+    /// close inlet sessions whose peer stops reading pending data for this many seconds, 0 means no limit
+    #[arg(long, default_value = "0")]
+    pub write_timeout: u64,
+
+    /// whether inlets bound to the IPv6 wildcard address ([::]) accept IPv6 connections only, the system default is used if not provided (dual-stack on Linux)
+    #[arg(long)]
+    pub ipv6_only: Option<bool>,
+
+    /// listen backlog of TCP inlets, 0 means 1024, capped by the system limit (net.core.somaxconn on Linux, kern.ipc.somaxconn on macOS)
+    #[arg(long, default_value = "0")]
+    pub listen_backlog: u32,
+
+    /// splice data of plaintext TCP tunnels whose inlet and outlet are both on this client directly between the sockets, Linux only
+    #[arg(long, default_value = "false")]
+    pub splice: bool,
+
+    /// geoip database (CSV lines of `network,country[,asn]`) used to tag inlet client addresses, disabled if not provided
+    #[arg(long, default_value = "")]
+    pub geoip_database: String,
+
+    /// number of backend connect failures within the window that trips an inlet's circuit breaker, 0 disables it
+    #[arg(long, default_value = "5")]
+    pub breaker_threshold: u32,
+
+    /// circuit breaker failure counting window in seconds
+    #[arg(long, default_value = "60")]
+    pub breaker_window: u64,
+
+    /// seconds a tripped circuit breaker rejects new sessions before probing the backend again
+    #[arg(long, default_value = "30")]
+    pub breaker_cooldown: u64,
+
+    /// local address that outlets bind to when connecting to their endpoints (optional)
+    #[arg(long)]
+    pub bind_addr: Option<IpAddr>,
+
+    /// send a PROXY protocol v1 header with the client's address and port to outlet endpoints
+    #[arg(long, default_value = "false")]
+    pub send_proxy_protocol: bool,
+
+    /// seconds an outlet connection to its endpoint may stay idle before TCP keepalive probes are sent, 0 disables keepalive
+    #[arg(long, default_value = "30")]
+    pub outlet_keepalive_time: u64,
+
+    /// seconds between outlet TCP keepalive probes, 0 means the system default
+    #[arg(long, default_value = "0")]
+    pub outlet_keepalive_interval: u64,
+
+    /// failed outlet TCP keepalive probes before the connection is dropped, 0 means the system default
+    #[arg(long, default_value = "0")]
+    pub outlet_keepalive_retries: u32,
+
+    /// seconds an outlet waits for a stuck endpoint to accept each write before dropping it, 0 waits forever
+    #[arg(long, default_value = "10")]
+    pub outlet_send_timeout: u64,
+
+    /// consecutive outlet write timeouts before a UDP session is closed, TCP sessions are closed on the first one
+    #[arg(long, default_value = "3")]
+    pub outlet_send_max_timeouts: u32,
+
+    /// destinations outlets are allowed to connect to, as comma separated `host[:ports]` rules (IP, CIDR, hostname or `*.example.com`, ports may be a range like 8000-9000), unrestricted if not provided
+    #[arg(long, value_delimiter = ',')]
+    pub allow_destination: Vec<String>,
+
+    /// upstream socks5 proxy used by outlets to reach their endpoints (optional)
+    #[arg(long, default_value = "")]
+    pub socks5_proxy: String,
+
+    /// upstream socks5 proxy username
+    #[arg(long, default_value = "")]
+    pub socks5_username: String,
+
+    /// upstream socks5 proxy password
+    #[arg(long, default_value = "")]
+    pub socks5_password: String,
+
     /// set log level
     #[arg(long, default_value = "info")]
     pub log_level: String,
@@ -134,8 +254,27 @@ pub(crate) fn init_logger(common_args: &CommonArgs) -> anyhow::Result<()> {
 }
 
 pub(crate) async fn run_with_args(common_args: CommonArgs) -> anyhow::Result<()> {
+    if let Some(bind_addr) = common_args.bind_addr {
+        check_bind_addr(bind_addr)?;
+    }
+    DestinationAllowlist::parse(&common_args.allow_destination)?;
+    set_global_buffer_budget(match common_args.buffer_budget {
+        0 => default_global_buffer_budget(),
+        mb => mb * 1024 * 1024,
+    });
+    set_global_pool_limit(common_args.pool_limit);
+
+    // 地理位置数据库只在启动时加载一次, 重连时复用
+    let geo_database = if common_args.geoip_database.is_empty() {
+        None
+    } else {
+        let database = GeoDatabase::load(&common_args.geoip_database)?;
+        info!("Loaded {} geoip records", database.len());
+        Some(Arc::new(database))
+    };
+
     loop {
-        if let Err(err) = client::run(&common_args).await {
+        if let Err(err) = client::run(&common_args, geo_database.clone()).await {
             error!("{err}");
             sleep(Duration::from_secs(5)).await;
         } else {
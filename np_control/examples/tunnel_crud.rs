@@ -0,0 +1,65 @@
+//! 新增 -> 查询 -> 删除 一个通道
+//!
+//! cargo run -p np_control --example tunnel_crud -- <server> <username> <password> <source> <endpoint>
+
+use np_control::ControlClient;
+use np_proto::class_def::{Tunnel, TunnelPoint, TunnelType};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 6 {
+        eprintln!(
+            "usage: {} <server> <username> <password> <source> <endpoint>",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+
+    let mut client = ControlClient::connect(&args[1], &args[2], &args[3]).await?;
+    let player_id = client.player_id();
+    println!("logged in as player {player_id}");
+
+    let mut notify = client.subscribe().unwrap();
+
+    let tunnel_id = client
+        .add_tunnel(Tunnel {
+            source: Some(TunnelPoint {
+                addr: args[4].clone(),
+            }),
+            endpoint: Some(TunnelPoint {
+                addr: args[5].clone(),
+            }),
+            enabled: true,
+            sender: player_id,
+            receiver: player_id,
+            tunnel_type: TunnelType::Tcp.into(),
+            encryption_method: "None".into(),
+            ..Default::default()
+        })
+        .await?;
+    println!("added tunnel {tunnel_id}");
+    if let Some(ntf) = notify.recv().await {
+        println!(
+            "notify: is_delete={} tunnel={:?}",
+            ntf.is_delete,
+            ntf.tunnel.map(|x| x.id)
+        );
+    }
+
+    for tunnel in client.query_tunnels().await? {
+        println!(
+            "tunnel {}: {} -> {}",
+            tunnel.id,
+            tunnel.source.map(|x| x.addr).unwrap_or_default(),
+            tunnel.endpoint.map(|x| x.addr).unwrap_or_default()
+        );
+    }
+
+    client.delete_tunnel(tunnel_id).await?;
+    println!("deleted tunnel {tunnel_id}");
+
+    let remaining = client.query_tunnels().await?;
+    println!("{} tunnel(s) remaining", remaining.len());
+    Ok(())
+}
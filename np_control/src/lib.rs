@@ -0,0 +1,238 @@
+//! 控制协议客户端
+//!
+//! 以玩家身份管理登录服务器, 通过带类型的接口管理通道, 并接收通道变更推送.
+//! 管理登录不占用玩家的会话, 同一玩家的客户端在线时也可以使用
+
+use anyhow::anyhow;
+use bytes::BytesMut;
+use log::{debug, error};
+use np_proto::class_def::Tunnel;
+use np_proto::client_server::{AddTunnelReq, DeleteTunnelReq, ManagementLoginReq, TunnelListReq};
+use np_proto::frame::{decode_frame, encode_frame, try_extract_frame};
+use np_proto::message_map::MessageType;
+use np_proto::server_client::ModifyTunnelNtf;
+use np_proto::DEFAULT_MAX_MESSAGE_SIZE;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+
+/// 请求的默认超时时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+type PendingMap = Arc<Mutex<HashMap<i32, oneshot::Sender<MessageType>>>>;
+
+pub struct ControlClient {
+    writer: Mutex<Writer>,
+    pending: PendingMap,
+    serial: AtomicI32,
+    player_id: u32,
+    tunnels: Vec<Tunnel>,
+    notify_rx: Option<UnboundedReceiver<ModifyTunnelNtf>>,
+    reader_task: JoinHandle<()>,
+}
+
+impl ControlClient {
+    /// 使用明文TCP连接服务器并登录
+    pub async fn connect(addr: &str, username: &str, password: &str) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Self::connect_with_stream(stream, username, password).await
+    }
+
+    /// 使用已建立的连接(例如TLS连接)登录服务器
+    pub async fn connect_with_stream<S>(
+        stream: S,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let pending: PendingMap = Arc::new(Mutex::new(HashMap::new()));
+        let (notify_tx, notify_rx) = unbounded_channel();
+        let reader_task = tokio::spawn(Self::poll_read(reader, pending.clone(), notify_tx));
+
+        let mut client = Self {
+            writer: Mutex::new(Box::new(writer)),
+            pending,
+            serial: AtomicI32::new(0),
+            player_id: 0,
+            tunnels: Vec::new(),
+            notify_rx: Some(notify_rx),
+            reader_task,
+        };
+
+        let response = client
+            .request(MessageType::ClientServerManagementLoginReq(
+                ManagementLoginReq {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                },
+            ))
+            .await?;
+        match response {
+            MessageType::ServerClientManagementLoginAck(ack) => {
+                client.player_id = ack.player_id;
+                client.tunnels = ack.tunnel_list;
+                Ok(client)
+            }
+            other => Err(unexpected_response(other)),
+        }
+    }
+
+    /// 登录的玩家id
+    pub fn player_id(&self) -> u32 {
+        self.player_id
+    }
+
+    /// 登录时服务器下发的通道列表
+    pub fn login_tunnels(&self) -> &[Tunnel] {
+        &self.tunnels
+    }
+
+    /// 获取通道变更推送, 只能获取一次
+    pub fn subscribe(&mut self) -> Option<UnboundedReceiver<ModifyTunnelNtf>> {
+        self.notify_rx.take()
+    }
+
+    /// 新增通道, 返回新通道的id
+    pub async fn add_tunnel(&self, tunnel: Tunnel) -> anyhow::Result<u32> {
+        let response = self
+            .request(MessageType::ClientServerAddTunnelReq(AddTunnelReq {
+                tunnel: Some(tunnel),
+            }))
+            .await?;
+        match response {
+            MessageType::ServerClientAddTunnelAck(ack) => Ok(ack.tunnel_id),
+            other => Err(unexpected_response(other)),
+        }
+    }
+
+    /// 删除通道
+    pub async fn delete_tunnel(&self, tunnel_id: u32) -> anyhow::Result<()> {
+        let response = self
+            .request(MessageType::ClientServerDeleteTunnelReq(DeleteTunnelReq {
+                tunnel_id,
+            }))
+            .await?;
+        match response {
+            MessageType::GenericSuccess(_) => Ok(()),
+            other => Err(unexpected_response(other)),
+        }
+    }
+
+    /// 查询与当前玩家相关的通道
+    pub async fn query_tunnels(&self) -> anyhow::Result<Vec<Tunnel>> {
+        let response = self
+            .request(MessageType::ClientServerTunnelListReq(TunnelListReq {}))
+            .await?;
+        match response {
+            MessageType::ServerClientTunnelListAck(ack) => Ok(ack.tunnel_list),
+            other => Err(unexpected_response(other)),
+        }
+    }
+
+    /// 发送请求并等待回复
+    pub async fn request(&self, message: MessageType) -> anyhow::Result<MessageType> {
+        let serial = self.serial.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(serial, tx);
+
+        // 请求使用负数序号, 服务器以对应的正数序号回复
+        if let Err(err) = self.send(-serial, &message).await {
+            self.pending.lock().await.remove(&serial);
+            return Err(err);
+        }
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow!("connection closed")),
+            Err(_) => {
+                self.pending.lock().await.remove(&serial);
+                Err(anyhow!("request timeout"))
+            }
+        }
+    }
+
+    async fn send(&self, serial: i32, message: &MessageType) -> anyhow::Result<()> {
+        let buf = encode_frame(serial, message).ok_or(anyhow!("Message id not found"))?;
+
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn poll_read<R>(
+        mut reader: R,
+        pending: PendingMap,
+        notify_tx: UnboundedSender<ModifyTunnelNtf>,
+    ) where
+        R: AsyncRead + Unpin,
+    {
+        let mut buffer = BytesMut::with_capacity(65536);
+        loop {
+            match reader.read_buf(&mut buffer).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) => {
+                    debug!("control client read error: {err}");
+                    break;
+                }
+            }
+
+            loop {
+                let frame = match try_extract_frame(&mut buffer, DEFAULT_MAX_MESSAGE_SIZE) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("control client: {err}");
+                        pending.lock().await.clear();
+                        return;
+                    }
+                };
+                let (serial, message) = match decode_frame(&frame) {
+                    Ok((serial, _, message)) => (serial, message),
+                    Err(err) => {
+                        debug!("control client decode message error: {err}");
+                        continue;
+                    }
+                };
+
+                if serial > 0 {
+                    if let Some(tx) = pending.lock().await.remove(&serial) {
+                        let _ = tx.send(message);
+                    }
+                } else if let MessageType::ServerClientModifyTunnelNtf(ntf) = message {
+                    let _ = notify_tx.send(ntf);
+                }
+            }
+        }
+
+        // 连接断开, 结束所有等待中的请求
+        pending.lock().await.clear();
+    }
+}
+
+impl Drop for ControlClient {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+fn unexpected_response(message: MessageType) -> anyhow::Error {
+    match message {
+        MessageType::GenericError(err) => anyhow!("{}, code: {}", err.message, err.number),
+        MessageType::GenericFail(err) => anyhow!("{}, code: {}", err.message, err.number),
+        _ => anyhow!("unexpected response"),
+    }
+}
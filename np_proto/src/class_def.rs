@@ -48,10 +48,115 @@ pub struct Tunnel {
     pub encryption_method: ::prost::alloc::string::String,
     /// 自定义域名映射关系
     #[prost(map = "string, string", tag = "12")]
-    pub custom_mapping: ::std::collections::HashMap<
-        ::prost::alloc::string::String,
-        ::prost::alloc::string::String,
-    >,
+    pub custom_mapping: ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+    /// 是否暂停(暂停时不接受新连接)
+    #[prost(bool, tag = "13")]
+    pub paused: bool,
+    /// 会话最大存活时间(秒), 0表示不限制
+    #[prost(uint32, tag = "14")]
+    pub max_session_lifetime: u32,
+    /// 出口到入口方向是否压缩数据, 为空时与is_compressed相同
+    #[prost(bool, optional, tag = "15")]
+    pub o2i_is_compressed: ::core::option::Option<bool>,
+    /// 出口到入口方向的加密方式, 为空时与encryption_method相同
+    #[prost(string, optional, tag = "16")]
+    pub o2i_encryption_method: ::core::option::Option<::prost::alloc::string::String>,
+    /// 入口是否接受PROXY协议头部
+    #[prost(bool, tag = "17")]
+    pub accept_proxy_protocol: bool,
+    /// 除sender外同时提供出口的玩家, 新会话在在线的出口玩家之间分配
+    #[prost(uint32, repeated, tag = "18")]
+    pub extra_senders: ::prost::alloc::vec::Vec<u32>,
+    /// 合并写入的时间窗口(毫秒), 0表示不合并
+    #[prost(uint32, tag = "19")]
+    pub write_coalesce: u32,
+    /// 连接出口失败时的重试次数, 0表示不重试
+    #[prost(uint32, tag = "20")]
+    pub connect_retries: u32,
+    /// 连接重试的间隔(毫秒)
+    #[prost(uint32, tag = "21")]
+    pub connect_retry_delay: u32,
+    /// 每秒接受的新连接数, 0表示不限制
+    #[prost(uint32, tag = "22")]
+    pub conn_rate_limit: u32,
+    /// 允许的突发新连接数, 0表示与每秒连接数相同
+    #[prost(uint32, tag = "23")]
+    pub conn_rate_burst: u32,
+    /// 入口绑定的网卡名称, 为空时不绑定
+    #[prost(string, tag = "24")]
+    pub bind_device: ::prost::alloc::string::String,
+    /// 多出口时新会话选择出口的策略: 0轮询, 1按客户端IP哈希
+    #[prost(uint32, tag = "25")]
+    pub balance_policy: u32,
+    /// 入口终止TLS使用的证书文件, 为空时不终止TLS
+    #[prost(string, tag = "26")]
+    pub tls_cert: ::prost::alloc::string::String,
+    /// 入口终止TLS使用的私钥文件
+    #[prost(string, tag = "27")]
+    pub tls_key: ::prost::alloc::string::String,
+    /// 入口终止TLS时支持的ALPN协议, 逗号分隔
+    #[prost(string, tag = "28")]
+    pub tls_alpn: ::prost::alloc::string::String,
+    /// 出口连接后端的方式: 0明文, 1 TLS并校验证书, 2 TLS不校验证书
+    #[prost(uint32, tag = "29")]
+    pub backend_tls: u32,
+    /// 压缩字典, 为空时不使用, "base64:"开头时为内联的base64编码字典, 否则为入口和出口所在机器上的文件路径
+    #[prost(string, tag = "30")]
+    pub compression_dictionary: ::prost::alloc::string::String,
+    /// SOCKS5入口的外部认证接口地址, 为空时使用通道的用户名密码认证
+    #[prost(string, tag = "31")]
+    pub auth_url: ::prost::alloc::string::String,
+    /// 出口预先建立的连接数量, 0为不使用连接池, 只适合可以复用新连接的无状态协议
+    #[prost(uint32, tag = "32")]
+    pub pool_size: u32,
+    /// 连接池中连接的最长空闲时间(秒), 超过后丢弃重建, 0为默认60秒
+    #[prost(uint32, tag = "33")]
+    pub pool_max_idle: u32,
+    /// TCP通道按长度前缀分帧(0:不分帧 1:u16大端 2:u16小端 3:u32大端 4:u32小端)
+    #[prost(uint32, tag = "34")]
+    pub frame_prefix: u32,
+    /// 拒绝新连接时回复给客户端的内容, 为空时直接断开, 只对HTTP和SOCKS5通道生效
+    #[prost(string, tag = "35")]
+    pub reject_response: ::prost::alloc::string::String,
+    /// 流量超过配额(只读), 超过时paused也为true
+    #[prost(bool, tag = "36")]
+    pub over_quota: bool,
+    /// 出口等待入口确认的数据上限(字节), 超过时暂停读取后端, 0为不限制
+    #[prost(uint32, tag = "37")]
+    pub outlet_buffer_limit: u32,
+    /// 通道启动失败的错误信息(只读), 不为空时通道处于错误状态
+    #[prost(string, tag = "38")]
+    pub error: ::prost::alloc::string::String,
+    /// 外部系统中的通道标识, 不为空时唯一, 按它重复提交同一通道时更新而不是新增
+    #[prost(string, tag = "39")]
+    pub external_id: ::prost::alloc::string::String,
+    /// 会话key的派生方式(0:随机生成并随连接请求发送 1:HKDF-SHA256 2:PBKDF2-SHA256), 派生时入口和出口都用通道配置的密码计算, 密码不做哈希保存
+    #[prost(uint32, tag = "40")]
+    pub key_derivation: u32,
+    /// 出口不接受服务器转发的会话, 而是为每个会话主动建立一条到服务器的连接, 用于出口无法被直接连接的场景
+    #[prost(bool, tag = "41")]
+    pub reverse_stream: bool,
+    /// 多出口时各出口玩家的权重, 格式为逗号分隔的 `玩家id#权重`, 未列出的出口权重为1
+    #[prost(string, tag = "42")]
+    pub outlet_weights: ::prost::alloc::string::String,
+    /// 入口接受的客户端连接关闭Nagle算法, 下行小包立即发出, 延迟低但大量小块写入时吞吐下降
+    #[prost(bool, tag = "43")]
+    pub inlet_nodelay: bool,
+    /// 出口到后端的连接关闭Nagle算法, 上行小包立即发出, 延迟低但大量小块写入时吞吐下降
+    #[prost(bool, tag = "44")]
+    pub outlet_nodelay: bool,
+    /// 启用压缩时小于该字节数的数据不压缩, 每块数据多1字节标记; 0表示全部压缩(旧格式)
+    #[prost(uint32, tag = "45")]
+    pub compress_threshold: u32,
+    /// 入口监听和出口连接后端使用TCP Fast Open, 第一块数据随SYN发出, 需要系统支持
+    #[prost(bool, tag = "46")]
+    pub fast_open: bool,
+    /// 入口地址端口为0(或auto)时系统实际分配的端口(只读), 入口还没有启动时为0
+    #[prost(uint32, tag = "47")]
+    pub assigned_port: u32,
+    /// 加密方法偏好列表允许降级: 出口拒绝第一个方法时新会话改用列表中后面的方法, 默认不降级
+    #[prost(bool, tag = "48")]
+    pub encryption_fallback: bool,
 }
 /// 通道类型
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -62,6 +167,14 @@ pub enum TunnelType {
     Udp = 1,
     Socks5 = 2,
     Unknown = 3,
+    /// 根据TLS SNI选择后端
+    Https = 4,
+    /// 根据HTTP Host请求头选择后端
+    Http = 5,
+    /// DNS over TCP, 每个查询作为单独的UDP包转发
+    Dns = 6,
+    /// 按客户端发送的第一行标签由出口选择后端, 标签行不转发给后端
+    Label = 7,
 }
 impl TunnelType {
     /// String value of the enum field names used in the ProtoBuf definition.
@@ -74,6 +187,10 @@ impl TunnelType {
             TunnelType::Udp => "UDP",
             TunnelType::Socks5 => "SOCKS5",
             TunnelType::Unknown => "UNKNOWN",
+            TunnelType::Https => "HTTPS",
+            TunnelType::Http => "HTTP",
+            TunnelType::Dns => "DNS",
+            TunnelType::Label => "LABEL",
         }
     }
     /// Creates an enum from field names used in the ProtoBuf definition.
@@ -83,7 +200,29 @@ impl TunnelType {
             "UDP" => Some(Self::Udp),
             "SOCKS5" => Some(Self::Socks5),
             "UNKNOWN" => Some(Self::Unknown),
+            "HTTPS" => Some(Self::Https),
+            "HTTP" => Some(Self::Http),
+            "DNS" => Some(Self::Dns),
+            "LABEL" => Some(Self::Label),
             _ => None,
         }
     }
 }
+/// 出口到后端的连通性
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EndpointHealth {
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+    /// 是否可以连接
+    #[prost(bool, tag = "2")]
+    pub reachable: bool,
+    /// 建立连接的耗时(毫秒)
+    #[prost(uint32, tag = "3")]
+    pub latency: u32,
+    /// 无法连接的原因
+    #[prost(string, tag = "4")]
+    pub error: ::prost::alloc::string::String,
+}
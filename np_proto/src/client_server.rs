@@ -29,7 +29,8 @@ pub struct RegisterReq {
     #[prost(string, tag = "2")]
     pub password: ::prost::alloc::string::String,
 }
-/// 管理员登录
+/// 管理登录, 以玩家身份管理通道, 不占用玩家的会话(不会顶掉在线的客户端)
+/// return ManagementLoginAck | Error
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -41,4 +42,65 @@ pub struct ManagementLoginReq {
     /// 密码
     #[prost(string, tag = "2")]
     pub password: ::prost::alloc::string::String,
+    /// 客户端版本号
+    #[prost(string, tag = "3")]
+    pub version: ::prost::alloc::string::String,
+}
+/// 新增通道(玩家只能新增发送方、额外发送方和接收方都是自己的通道)
+/// return AddTunnelAck | Error
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AddTunnelReq {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1009;}
+    /// 通道信息, id字段忽略
+    #[prost(message, optional, tag = "1")]
+    pub tunnel: ::core::option::Option<super::class_def::Tunnel>,
+}
+/// 删除通道(玩家只能删除发送方、额外发送方和接收方都是自己的通道)
+/// return Success | Error
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteTunnelReq {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1011;}
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+}
+/// 查询玩家相关的通道列表
+/// return TunnelListAck | Error
+///
+/// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1013;}
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TunnelListReq {}
+/// 探测出口到后端的连通性回复
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProbeEndpointAck {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1022;}
+    /// 每个通道的探测结果
+    #[prost(message, repeated, tag = "1")]
+    pub health_list: ::prost::alloc::vec::Vec<super::class_def::EndpointHealth>,
+}
+/// 出口在新连接上绑定服务器请求建立的会话连接, 不需要登录
+/// 成功后这个连接只用于转发该会话的代理消息
+/// return Success | Error
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BindStreamReq {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1025;}
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+    /// 会话id
+    #[prost(uint32, tag = "2")]
+    pub session_id: u32,
+    /// I2oOpenStream中的令牌
+    #[prost(string, tag = "3")]
+    pub token: ::prost::alloc::string::String,
 }
@@ -0,0 +1,108 @@
+//! 控制通道消息帧的编解码
+//!
+//! 帧格式: 标志(1字节, 固定为33) + 长度(u32) + 序号(i32) + 消息id(u32) + 消息体, 整数均为大端序,
+//! 长度不包含标志和长度字段本身
+
+use crate::message_map::{decode_message, encode_raw_message, get_message_id, get_message_size, MessageType};
+use anyhow::anyhow;
+use bytes::BytesMut;
+
+/// 帧的起始标志
+pub const FRAME_FLAG: u8 = 33;
+
+/// 标志和长度字段的字节数
+const HEADER_SIZE: usize = 5;
+
+/// 数据粘包处理, 从缓冲区中拆出一个完整的帧(不含标志和长度字段)
+///
+/// 注意：这个函数只能使用消耗 buffer 数据的函数，否则框架会一直循环调用本函数来驱动处理消息
+///
+pub fn try_extract_frame(buffer: &mut BytesMut, max_message_size: usize) -> anyhow::Result<Option<Vec<u8>>> {
+    if !buffer.is_empty() && buffer[0] != FRAME_FLAG {
+        return Err(anyhow!("Bad flag"));
+    }
+    // 数据小于5字节,继续读取数据
+    if buffer.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+
+    // 读取包长度
+    let len = u32::from_be_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]) as usize;
+
+    // 超出最大限制, 在分配内存前返回错误
+    if len == 0 || len > max_message_size {
+        return Err(anyhow!("Message too long: {len}"));
+    }
+
+    // 数据不够,继续读取数据
+    if buffer.len() < HEADER_SIZE + len {
+        return Ok(None);
+    }
+
+    // 拆出这个包的数据
+    Ok(Some(buffer.split_to(HEADER_SIZE + len).split_off(HEADER_SIZE).to_vec()))
+}
+
+/// 解析帧, 返回(序号, 消息id, 消息)
+pub fn decode_frame(frame: &[u8]) -> anyhow::Result<(i32, u32, MessageType)> {
+    if frame.len() < 8 {
+        return Err(anyhow!("message length is too small"));
+    }
+    // 消息序号
+    let serial = i32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+    // 消息类型id
+    let msg_id = u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]);
+
+    let message = decode_message(msg_id, &frame[8..])?;
+    Ok((serial, msg_id, message))
+}
+
+/// 按协议格式打包消息, 消息没有id时返回None
+pub fn encode_frame(serial: i32, message: &MessageType) -> Option<Vec<u8>> {
+    let message_id = get_message_id(message)?;
+    let message_size = get_message_size(message);
+    let mut buf = Vec::with_capacity(HEADER_SIZE + 8 + message_size);
+
+    buf.push(FRAME_FLAG);
+    buf.extend_from_slice(&((8 + message_size) as u32).to_be_bytes());
+    buf.extend_from_slice(&serial.to_be_bytes());
+    buf.extend_from_slice(&message_id.to_be_bytes());
+    encode_raw_message(message, &mut buf);
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generic;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let message = MessageType::GenericPing(generic::Ping { ticks: 12345 });
+        let buf = encode_frame(-7, &message).unwrap();
+
+        // 分两次到达的数据, 第一次不足以组成一个帧
+        let mut buffer = BytesMut::from(&buf[..3]);
+        assert_eq!(try_extract_frame(&mut buffer, 1024).unwrap(), None);
+        buffer.extend_from_slice(&buf[3..]);
+        let frame = try_extract_frame(&mut buffer, 1024).unwrap().unwrap();
+        assert!(buffer.is_empty());
+
+        let (serial, msg_id, decoded) = decode_frame(&frame).unwrap();
+        assert_eq!(serial, -7);
+        assert_eq!(Some(msg_id), get_message_id(&message));
+        assert!(matches!(decoded, MessageType::GenericPing(ping) if ping.ticks == 12345));
+    }
+
+    #[test]
+    fn test_bad_frame() {
+        let mut buffer = BytesMut::from(&[0u8, 0, 0, 0, 1][..]);
+        assert!(try_extract_frame(&mut buffer, 1024).is_err());
+
+        // 声明的长度超出限制
+        let mut buffer = BytesMut::from(&[FRAME_FLAG, 0, 0, 4, 1][..]);
+        assert!(try_extract_frame(&mut buffer, 1024).is_err());
+
+        assert!(decode_frame(&[0u8; 7]).is_err());
+    }
+}
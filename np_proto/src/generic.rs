@@ -46,6 +46,33 @@ pub struct Pong {
     #[prost(int64, tag = "1")]
     pub ticks: i64,
 }
+/// 单方向的数据编码方式
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DataCodec {
+    /// 是否压缩数据
+    #[prost(bool, tag = "1")]
+    pub is_compressed: bool,
+    /// 加密方式
+    #[prost(string, tag = "2")]
+    pub encryption_method: ::prost::alloc::string::String,
+    /// 加密key
+    #[prost(string, tag = "3")]
+    pub encryption_key: ::prost::alloc::string::String,
+}
+/// 出口连接后端时使用的TLS
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BackendTls {
+    /// 是否校验后端证书
+    #[prost(bool, tag = "1")]
+    pub verify: bool,
+    /// 客户端与入口协商出的ALPN协议, 为空时不协商
+    #[prost(string, tag = "2")]
+    pub alpn: ::prost::alloc::string::String,
+}
 /// 向输出端请求发起连接
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -79,6 +106,33 @@ pub struct I2oConnect {
     /// 客户端地址
     #[prost(string, tag = "9")]
     pub client_addr: ::prost::alloc::string::String,
+    /// 出口到入口方向的编码方式, 为空时与入口到出口方向相同
+    #[prost(message, optional, tag = "10")]
+    pub o2i_codec: ::core::option::Option<DataCodec>,
+    /// 合并写入的时间窗口(毫秒), 为0时不合并
+    #[prost(uint32, tag = "11")]
+    pub write_coalesce: u32,
+    /// 会话代数, 会话id被复用时用于区分新旧会话, 0表示未知
+    #[prost(uint32, tag = "12")]
+    pub generation: u32,
+    /// 连接后端时使用的TLS, 为空时明文连接
+    #[prost(message, optional, tag = "13")]
+    pub backend_tls: ::core::option::Option<BackendTls>,
+    /// 压缩字典的哈希值, 为空表示不使用字典
+    #[prost(string, tag = "14")]
+    pub compression_dictionary: ::prost::alloc::string::String,
+    /// 会话元数据(例如通道标签、客户端请求的主机名、认证的用户名), 出口用于日志和路由
+    #[prost(map = "string, string", tag = "15")]
+    pub metadata: ::std::collections::HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+    /// key的派生方式(1:HKDF-SHA256 2:PBKDF2-SHA256), 0表示加密key随消息发送
+    #[prost(uint32, tag = "16")]
+    pub key_derivation: u32,
+    /// 派生key使用的盐(base64编码)
+    #[prost(string, tag = "17")]
+    pub key_salt: ::prost::alloc::string::String,
+    /// 压缩阈值(字节), 小于阈值的数据不压缩, 0表示全部压缩且不带压缩标记
+    #[prost(uint32, tag = "18")]
+    pub compress_threshold: u32,
 }
 /// 连接结果
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -98,6 +152,18 @@ pub struct O2iConnect {
     /// 错误信息
     #[prost(string, tag = "4")]
     pub error_info: ::prost::alloc::string::String,
+    /// 是否使用了独立的出口到入口编码方式
+    #[prost(bool, tag = "5")]
+    pub independent_codec: bool,
+    /// 会话代数
+    #[prost(uint32, tag = "6")]
+    pub generation: u32,
+    /// 出口实际连接的后端地址(解析后的IP和端口), 失败或未知时为空
+    #[prost(string, tag = "7")]
+    pub backend_addr: ::prost::alloc::string::String,
+    /// 应答的出口(玩家id), 由服务器转发时填写
+    #[prost(uint32, tag = "8")]
+    pub outlet_id: u32,
 }
 /// 输出端收到数据返回给输入端
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -130,6 +196,9 @@ pub struct O2iRecvData {
     /// 数据
     #[prost(bytes = "vec", tag = "3")]
     pub data: ::prost::alloc::vec::Vec<u8>,
+    /// 会话代数
+    #[prost(uint32, tag = "4")]
+    pub generation: u32,
 }
 /// 断开连接
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -156,6 +225,9 @@ pub struct O2iDisconnect {
     /// 会话id
     #[prost(uint32, tag = "2")]
     pub session_id: u32,
+    /// 会话代数
+    #[prost(uint32, tag = "3")]
+    pub generation: u32,
 }
 /// 发送结果
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -172,6 +244,9 @@ pub struct O2iSendDataResult {
     /// 完成长度
     #[prost(uint32, tag = "3")]
     pub data_len: u32,
+    /// 会话代数
+    #[prost(uint32, tag = "4")]
+    pub generation: u32,
 }
 /// 接收数据处理结果
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -226,6 +301,9 @@ pub struct O2iRecvDataFrom {
     /// 远程地址
     #[prost(string, tag = "4")]
     pub remote_addr: ::prost::alloc::string::String,
+    /// 会话代数
+    #[prost(uint32, tag = "5")]
+    pub generation: u32,
 }
 /// 通用错误码
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -264,3 +342,48 @@ impl ErrorCode {
         }
     }
 }
+/// 请求出口为会话建立独立的连接
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct I2oOpenStream {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 150016;}
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+    /// 会话id
+    #[prost(uint32, tag = "2")]
+    pub session_id: u32,
+    /// 会话代数
+    #[prost(uint32, tag = "3")]
+    pub generation: u32,
+    /// 绑定连接用的令牌
+    #[prost(string, tag = "4")]
+    pub token: ::prost::alloc::string::String,
+}
+/// 入口发出的延迟探测, 出口收到后原样回复O2iProbe, 不经过会话
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct I2oProbe {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 150017;}
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+    /// 入口发出时的时间戳(微秒), 只有入口自己能解释
+    #[prost(uint64, tag = "2")]
+    pub ticks: u64,
+}
+/// 出口回复的延迟探测
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct O2iProbe {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 150018;}
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+    /// I2oProbe中的时间戳
+    #[prost(uint64, tag = "2")]
+    pub ticks: u64,
+}
@@ -1,6 +1,10 @@
 pub mod class_def;
 pub mod client_server;
+pub mod frame;
 pub mod generic;
 pub mod message_map;
 pub mod server_client;
 pub mod utils;
+
+/// 控制通道单个消息的默认最大长度
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
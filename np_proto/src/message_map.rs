@@ -10,6 +10,18 @@ pub enum MessageType {
     ServerClientLoginAck(super::server_client::LoginAck),
     ServerClientManagementLoginAck(super::server_client::ManagementLoginAck),
     ServerClientModifyTunnelNtf(super::server_client::ModifyTunnelNtf),
+    ClientServerAddTunnelReq(super::client_server::AddTunnelReq),
+    ClientServerDeleteTunnelReq(super::client_server::DeleteTunnelReq),
+    ClientServerTunnelListReq(super::client_server::TunnelListReq),
+    ServerClientAddTunnelAck(super::server_client::AddTunnelAck),
+    ServerClientTunnelListAck(super::server_client::TunnelListAck),
+    ServerClientShutdownNtf(super::server_client::ShutdownNtf),
+    ServerClientTunnelBreakerNtf(super::server_client::TunnelBreakerNtf),
+    ServerClientTunnelQuotaNtf(super::server_client::TunnelQuotaNtf),
+    ServerClientProbeEndpointReq(super::server_client::ProbeEndpointReq),
+    ClientServerProbeEndpointAck(super::client_server::ProbeEndpointAck),
+    ServerClientTunnelHealthNtf(super::server_client::TunnelHealthNtf),
+    ClientServerBindStreamReq(super::client_server::BindStreamReq),
     GenericSuccess(super::generic::Success),
     GenericFail(super::generic::Fail),
     GenericError(super::generic::Error),
@@ -25,14 +37,14 @@ pub enum MessageType {
     GenericI2oRecvDataResult(super::generic::I2oRecvDataResult),
     GenericI2oSendToData(super::generic::I2oSendToData),
     GenericO2iRecvDataFrom(super::generic::O2iRecvDataFrom),
+    GenericI2oOpenStream(super::generic::I2oOpenStream),
+    GenericI2oProbe(super::generic::I2oProbe),
+    GenericO2iProbe(super::generic::O2iProbe),
 }
 
 impl MessageType {
     pub fn is_none(&self) -> bool {
-        match self {
-            MessageType::None => true,
-            _ => false,
-        }
+        matches!(self, MessageType::None)
     }
 }
 
@@ -44,6 +56,18 @@ pub fn get_message_id(message: &MessageType) -> Option<u32> {
         MessageType::ServerClientLoginAck(_) => Some(1002u32),
         MessageType::ServerClientManagementLoginAck(_) => Some(1006u32),
         MessageType::ServerClientModifyTunnelNtf(_) => Some(1008u32),
+        MessageType::ClientServerAddTunnelReq(_) => Some(1009u32),
+        MessageType::ClientServerDeleteTunnelReq(_) => Some(1011u32),
+        MessageType::ClientServerTunnelListReq(_) => Some(1013u32),
+        MessageType::ServerClientAddTunnelAck(_) => Some(1010u32),
+        MessageType::ServerClientTunnelListAck(_) => Some(1014u32),
+        MessageType::ServerClientShutdownNtf(_) => Some(1016u32),
+        MessageType::ServerClientTunnelBreakerNtf(_) => Some(1018u32),
+        MessageType::ServerClientTunnelQuotaNtf(_) => Some(1020u32),
+        MessageType::ServerClientProbeEndpointReq(_) => Some(1021u32),
+        MessageType::ClientServerProbeEndpointAck(_) => Some(1022u32),
+        MessageType::ServerClientTunnelHealthNtf(_) => Some(1024u32),
+        MessageType::ClientServerBindStreamReq(_) => Some(1025u32),
         MessageType::GenericSuccess(_) => Some(150001u32),
         MessageType::GenericFail(_) => Some(150002u32),
         MessageType::GenericError(_) => Some(150003u32),
@@ -59,6 +83,9 @@ pub fn get_message_id(message: &MessageType) -> Option<u32> {
         MessageType::GenericI2oRecvDataResult(_) => Some(150013u32),
         MessageType::GenericI2oSendToData(_) => Some(150014u32),
         MessageType::GenericO2iRecvDataFrom(_) => Some(150015u32),
+        MessageType::GenericI2oOpenStream(_) => Some(150016u32),
+        MessageType::GenericI2oProbe(_) => Some(150017u32),
+        MessageType::GenericO2iProbe(_) => Some(150018u32),
         _ => None,
     }
 }
@@ -89,6 +116,54 @@ pub fn decode_message(message_id: u32, bytes: &[u8]) -> Result<MessageType, Deco
             Ok(message) => Ok(MessageType::ServerClientModifyTunnelNtf(message)),
             Err(err) => Err(err),
         },
+        1009u32 => match super::client_server::AddTunnelReq::decode(bytes) {
+            Ok(message) => Ok(MessageType::ClientServerAddTunnelReq(message)),
+            Err(err) => Err(err),
+        },
+        1011u32 => match super::client_server::DeleteTunnelReq::decode(bytes) {
+            Ok(message) => Ok(MessageType::ClientServerDeleteTunnelReq(message)),
+            Err(err) => Err(err),
+        },
+        1013u32 => match super::client_server::TunnelListReq::decode(bytes) {
+            Ok(message) => Ok(MessageType::ClientServerTunnelListReq(message)),
+            Err(err) => Err(err),
+        },
+        1010u32 => match super::server_client::AddTunnelAck::decode(bytes) {
+            Ok(message) => Ok(MessageType::ServerClientAddTunnelAck(message)),
+            Err(err) => Err(err),
+        },
+        1014u32 => match super::server_client::TunnelListAck::decode(bytes) {
+            Ok(message) => Ok(MessageType::ServerClientTunnelListAck(message)),
+            Err(err) => Err(err),
+        },
+        1016u32 => match super::server_client::ShutdownNtf::decode(bytes) {
+            Ok(message) => Ok(MessageType::ServerClientShutdownNtf(message)),
+            Err(err) => Err(err),
+        },
+        1018u32 => match super::server_client::TunnelBreakerNtf::decode(bytes) {
+            Ok(message) => Ok(MessageType::ServerClientTunnelBreakerNtf(message)),
+            Err(err) => Err(err),
+        },
+        1020u32 => match super::server_client::TunnelQuotaNtf::decode(bytes) {
+            Ok(message) => Ok(MessageType::ServerClientTunnelQuotaNtf(message)),
+            Err(err) => Err(err),
+        },
+        1021u32 => match super::server_client::ProbeEndpointReq::decode(bytes) {
+            Ok(message) => Ok(MessageType::ServerClientProbeEndpointReq(message)),
+            Err(err) => Err(err),
+        },
+        1022u32 => match super::client_server::ProbeEndpointAck::decode(bytes) {
+            Ok(message) => Ok(MessageType::ClientServerProbeEndpointAck(message)),
+            Err(err) => Err(err),
+        },
+        1024u32 => match super::server_client::TunnelHealthNtf::decode(bytes) {
+            Ok(message) => Ok(MessageType::ServerClientTunnelHealthNtf(message)),
+            Err(err) => Err(err),
+        },
+        1025u32 => match super::client_server::BindStreamReq::decode(bytes) {
+            Ok(message) => Ok(MessageType::ClientServerBindStreamReq(message)),
+            Err(err) => Err(err),
+        },
         150001u32 => match super::generic::Success::decode(bytes) {
             Ok(message) => Ok(MessageType::GenericSuccess(message)),
             Err(err) => Err(err),
@@ -149,6 +224,18 @@ pub fn decode_message(message_id: u32, bytes: &[u8]) -> Result<MessageType, Deco
             Ok(message) => Ok(MessageType::GenericO2iRecvDataFrom(message)),
             Err(err) => Err(err),
         },
+        150016u32 => match super::generic::I2oOpenStream::decode(bytes) {
+            Ok(message) => Ok(MessageType::GenericI2oOpenStream(message)),
+            Err(err) => Err(err),
+        },
+        150017u32 => match super::generic::I2oProbe::decode(bytes) {
+            Ok(message) => Ok(MessageType::GenericI2oProbe(message)),
+            Err(err) => Err(err),
+        },
+        150018u32 => match super::generic::O2iProbe::decode(bytes) {
+            Ok(message) => Ok(MessageType::GenericO2iProbe(message)),
+            Err(err) => Err(err),
+        },
         _ => Err(DecodeError::new("unknown message id")),
     }
 }
@@ -161,6 +248,18 @@ pub fn encode_message(message: &MessageType) -> Option<(u32, Vec<u8>)> {
         MessageType::ServerClientLoginAck(msg) => Some((1002u32, msg.encode_to_vec())),
         MessageType::ServerClientManagementLoginAck(msg) => Some((1006u32, msg.encode_to_vec())),
         MessageType::ServerClientModifyTunnelNtf(msg) => Some((1008u32, msg.encode_to_vec())),
+        MessageType::ClientServerAddTunnelReq(msg) => Some((1009u32, msg.encode_to_vec())),
+        MessageType::ClientServerDeleteTunnelReq(msg) => Some((1011u32, msg.encode_to_vec())),
+        MessageType::ClientServerTunnelListReq(msg) => Some((1013u32, msg.encode_to_vec())),
+        MessageType::ServerClientAddTunnelAck(msg) => Some((1010u32, msg.encode_to_vec())),
+        MessageType::ServerClientTunnelListAck(msg) => Some((1014u32, msg.encode_to_vec())),
+        MessageType::ServerClientShutdownNtf(msg) => Some((1016u32, msg.encode_to_vec())),
+        MessageType::ServerClientTunnelBreakerNtf(msg) => Some((1018u32, msg.encode_to_vec())),
+        MessageType::ServerClientTunnelQuotaNtf(msg) => Some((1020u32, msg.encode_to_vec())),
+        MessageType::ServerClientProbeEndpointReq(msg) => Some((1021u32, msg.encode_to_vec())),
+        MessageType::ClientServerProbeEndpointAck(msg) => Some((1022u32, msg.encode_to_vec())),
+        MessageType::ServerClientTunnelHealthNtf(msg) => Some((1024u32, msg.encode_to_vec())),
+        MessageType::ClientServerBindStreamReq(msg) => Some((1025u32, msg.encode_to_vec())),
         MessageType::GenericSuccess(msg) => Some((150001u32, msg.encode_to_vec())),
         MessageType::GenericFail(msg) => Some((150002u32, msg.encode_to_vec())),
         MessageType::GenericError(msg) => Some((150003u32, msg.encode_to_vec())),
@@ -176,6 +275,9 @@ pub fn encode_message(message: &MessageType) -> Option<(u32, Vec<u8>)> {
         MessageType::GenericI2oRecvDataResult(msg) => Some((150013u32, msg.encode_to_vec())),
         MessageType::GenericI2oSendToData(msg) => Some((150014u32, msg.encode_to_vec())),
         MessageType::GenericO2iRecvDataFrom(msg) => Some((150015u32, msg.encode_to_vec())),
+        MessageType::GenericI2oOpenStream(msg) => Some((150016u32, msg.encode_to_vec())),
+        MessageType::GenericI2oProbe(msg) => Some((150017u32, msg.encode_to_vec())),
+        MessageType::GenericO2iProbe(msg) => Some((150018u32, msg.encode_to_vec())),
         _ => None,
     }
 }
@@ -188,6 +290,18 @@ pub fn get_message_size(message: &MessageType) -> usize {
         MessageType::ServerClientLoginAck(msg) => msg.encoded_len(),
         MessageType::ServerClientManagementLoginAck(msg) => msg.encoded_len(),
         MessageType::ServerClientModifyTunnelNtf(msg) => msg.encoded_len(),
+        MessageType::ClientServerAddTunnelReq(msg) => msg.encoded_len(),
+        MessageType::ClientServerDeleteTunnelReq(msg) => msg.encoded_len(),
+        MessageType::ClientServerTunnelListReq(msg) => msg.encoded_len(),
+        MessageType::ServerClientAddTunnelAck(msg) => msg.encoded_len(),
+        MessageType::ServerClientTunnelListAck(msg) => msg.encoded_len(),
+        MessageType::ServerClientShutdownNtf(msg) => msg.encoded_len(),
+        MessageType::ServerClientTunnelBreakerNtf(msg) => msg.encoded_len(),
+        MessageType::ServerClientTunnelQuotaNtf(msg) => msg.encoded_len(),
+        MessageType::ServerClientProbeEndpointReq(msg) => msg.encoded_len(),
+        MessageType::ClientServerProbeEndpointAck(msg) => msg.encoded_len(),
+        MessageType::ServerClientTunnelHealthNtf(msg) => msg.encoded_len(),
+        MessageType::ClientServerBindStreamReq(msg) => msg.encoded_len(),
         MessageType::GenericSuccess(msg) => msg.encoded_len(),
         MessageType::GenericFail(msg) => msg.encoded_len(),
         MessageType::GenericError(msg) => msg.encoded_len(),
@@ -203,6 +317,9 @@ pub fn get_message_size(message: &MessageType) -> usize {
         MessageType::GenericI2oRecvDataResult(msg) => msg.encoded_len(),
         MessageType::GenericI2oSendToData(msg) => msg.encoded_len(),
         MessageType::GenericO2iRecvDataFrom(msg) => msg.encoded_len(),
+        MessageType::GenericI2oOpenStream(msg) => msg.encoded_len(),
+        MessageType::GenericI2oProbe(msg) => msg.encoded_len(),
+        MessageType::GenericO2iProbe(msg) => msg.encoded_len(),
         _ => 0,
     }
 }
@@ -215,6 +332,18 @@ pub fn encode_raw_message(message: &MessageType, buf: &mut impl BufMut) {
         MessageType::ServerClientLoginAck(msg) => msg.encode_raw(buf),
         MessageType::ServerClientManagementLoginAck(msg) => msg.encode_raw(buf),
         MessageType::ServerClientModifyTunnelNtf(msg) => msg.encode_raw(buf),
+        MessageType::ClientServerAddTunnelReq(msg) => msg.encode_raw(buf),
+        MessageType::ClientServerDeleteTunnelReq(msg) => msg.encode_raw(buf),
+        MessageType::ClientServerTunnelListReq(msg) => msg.encode_raw(buf),
+        MessageType::ServerClientAddTunnelAck(msg) => msg.encode_raw(buf),
+        MessageType::ServerClientTunnelListAck(msg) => msg.encode_raw(buf),
+        MessageType::ServerClientShutdownNtf(msg) => msg.encode_raw(buf),
+        MessageType::ServerClientTunnelBreakerNtf(msg) => msg.encode_raw(buf),
+        MessageType::ServerClientTunnelQuotaNtf(msg) => msg.encode_raw(buf),
+        MessageType::ServerClientProbeEndpointReq(msg) => msg.encode_raw(buf),
+        MessageType::ClientServerProbeEndpointAck(msg) => msg.encode_raw(buf),
+        MessageType::ServerClientTunnelHealthNtf(msg) => msg.encode_raw(buf),
+        MessageType::ClientServerBindStreamReq(msg) => msg.encode_raw(buf),
         MessageType::GenericSuccess(msg) => msg.encode_raw(buf),
         MessageType::GenericFail(msg) => msg.encode_raw(buf),
         MessageType::GenericError(msg) => msg.encode_raw(buf),
@@ -230,6 +359,9 @@ pub fn encode_raw_message(message: &MessageType, buf: &mut impl BufMut) {
         MessageType::GenericI2oRecvDataResult(msg) => msg.encode_raw(buf),
         MessageType::GenericI2oSendToData(msg) => msg.encode_raw(buf),
         MessageType::GenericO2iRecvDataFrom(msg) => msg.encode_raw(buf),
+        MessageType::GenericI2oOpenStream(msg) => msg.encode_raw(buf),
+        MessageType::GenericI2oProbe(msg) => msg.encode_raw(buf),
+        MessageType::GenericO2iProbe(msg) => msg.encode_raw(buf),
         _ => {}
     }
 }
@@ -243,6 +375,18 @@ pub fn serialize_to_json(message: &MessageType) -> serde_json::Result<String> {
         MessageType::ServerClientLoginAck(msg) => serde_json::to_string(&msg),
         MessageType::ServerClientManagementLoginAck(msg) => serde_json::to_string(&msg),
         MessageType::ServerClientModifyTunnelNtf(msg) => serde_json::to_string(&msg),
+        MessageType::ClientServerAddTunnelReq(msg) => serde_json::to_string(&msg),
+        MessageType::ClientServerDeleteTunnelReq(msg) => serde_json::to_string(&msg),
+        MessageType::ClientServerTunnelListReq(msg) => serde_json::to_string(&msg),
+        MessageType::ServerClientAddTunnelAck(msg) => serde_json::to_string(&msg),
+        MessageType::ServerClientTunnelListAck(msg) => serde_json::to_string(&msg),
+        MessageType::ServerClientShutdownNtf(msg) => serde_json::to_string(&msg),
+        MessageType::ServerClientTunnelBreakerNtf(msg) => serde_json::to_string(&msg),
+        MessageType::ServerClientTunnelQuotaNtf(msg) => serde_json::to_string(&msg),
+        MessageType::ServerClientProbeEndpointReq(msg) => serde_json::to_string(&msg),
+        MessageType::ClientServerProbeEndpointAck(msg) => serde_json::to_string(&msg),
+        MessageType::ServerClientTunnelHealthNtf(msg) => serde_json::to_string(&msg),
+        MessageType::ClientServerBindStreamReq(msg) => serde_json::to_string(&msg),
         MessageType::GenericSuccess(msg) => serde_json::to_string(&msg),
         MessageType::GenericFail(msg) => serde_json::to_string(&msg),
         MessageType::GenericError(msg) => serde_json::to_string(&msg),
@@ -258,6 +402,9 @@ pub fn serialize_to_json(message: &MessageType) -> serde_json::Result<String> {
         MessageType::GenericI2oRecvDataResult(msg) => serde_json::to_string(&msg),
         MessageType::GenericI2oSendToData(msg) => serde_json::to_string(&msg),
         MessageType::GenericO2iRecvDataFrom(msg) => serde_json::to_string(&msg),
+        MessageType::GenericI2oOpenStream(msg) => serde_json::to_string(&msg),
+        MessageType::GenericI2oProbe(msg) => serde_json::to_string(&msg),
+        MessageType::GenericO2iProbe(msg) => serde_json::to_string(&msg),
         _ => Ok("null".into()),
     }
 }
@@ -12,7 +12,46 @@ pub struct LoginAck {
     #[prost(message, repeated, tag = "2")]
     pub tunnel_list: ::prost::alloc::vec::Vec<super::class_def::Tunnel>,
 }
-/// 管理员登录回复
+/// 服务器即将关闭通知
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ShutdownNtf {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1016;}
+    /// 等待会话结束的最长时间(秒)
+    #[prost(uint32, tag = "1")]
+    pub grace_period: u32,
+}
+/// 通道熔断状态变化通知
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TunnelBreakerNtf {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1018;}
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+    /// 熔断状态: closed, open, half_open
+    #[prost(string, tag = "2")]
+    pub state: ::prost::alloc::string::String,
+}
+/// 通道流量超过配额通知
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TunnelQuotaNtf {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1020;}
+    /// 通道id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+    /// 已使用流量(字节)
+    #[prost(uint64, tag = "2")]
+    pub bytes_used: u64,
+    /// 流量配额(字节)
+    #[prost(uint64, tag = "3")]
+    pub bytes_quota: u64,
+}
+/// 管理登录回复
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -21,6 +60,12 @@ pub struct ManagementLoginAck {
     /// 错误码 0成功
     #[prost(int32, tag = "1")]
     pub code: i32,
+    /// 登录的玩家id
+    #[prost(uint32, tag = "2")]
+    pub player_id: u32,
+    /// 通道列表
+    #[prost(message, repeated, tag = "3")]
+    pub tunnel_list: ::prost::alloc::vec::Vec<super::class_def::Tunnel>,
 }
 /// 修改通道通知
 #[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
@@ -35,3 +80,49 @@ pub struct ModifyTunnelNtf {
     #[prost(message, optional, tag = "2")]
     pub tunnel: ::core::option::Option<super::class_def::Tunnel>,
 }
+/// 新增通道回复
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AddTunnelAck {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1010;}
+    /// 新通道的id
+    #[prost(uint32, tag = "1")]
+    pub tunnel_id: u32,
+}
+/// 通道列表回复
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TunnelListAck {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1014;}
+    #[prost(message, repeated, tag = "1")]
+    pub tunnel_list: ::prost::alloc::vec::Vec<super::class_def::Tunnel>,
+}
+/// 探测出口到后端的连通性(服务器向出口玩家发起的请求)
+/// return ProbeEndpointAck
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProbeEndpointReq {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1021;}
+    /// 需要探测的通道id列表
+    #[prost(uint32, repeated, tag = "1")]
+    pub tunnel_id_list: ::prost::alloc::vec::Vec<u32>,
+    /// 单个后端的连接超时(秒)
+    #[prost(uint32, tag = "2")]
+    pub timeout: u32,
+}
+/// 通道后端连通性变化通知
+#[cfg_attr(feature = "serde-serialize", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TunnelHealthNtf {
+    /// @build_automatically_generate_message_id@  enum MsgId {None = 0; Id = 1024;}
+    /// 出口玩家id, 0表示服务器
+    #[prost(uint32, tag = "1")]
+    pub player_id: u32,
+    /// 连通性
+    #[prost(message, optional, tag = "2")]
+    pub health: ::core::option::Option<super::class_def::EndpointHealth>,
+}
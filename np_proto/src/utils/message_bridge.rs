@@ -4,24 +4,56 @@ use np_base::proxy::ProxyMessage;
 
 pub fn proxy_message_2_pb(proxy_message: ProxyMessage, tunnel_id: u32) -> MessageType {
     match proxy_message {
-        ProxyMessage::I2oConnect(session_id, tunnel_type, is_tcp, is_compressed, addr, encryption_method, encryption_key, client_addr) => {
-            MessageType::GenericI2oConnect(generic::I2oConnect {
-                tunnel_id,
-                session_id,
-                tunnel_type: tunnel_type as u32,
-                addr,
-                is_tcp,
+        ProxyMessage::I2oConnect {
+            session_id,
+            generation,
+            tunnel_type,
+            is_tcp,
+            is_compressed,
+            addr,
+            encryption_method,
+            encryption_key,
+            client_addr,
+            o2i_codec,
+            write_coalesce,
+            backend_tls,
+            compression_dictionary,
+            metadata,
+            key_derivation,
+            compress_threshold,
+        } => MessageType::GenericI2oConnect(generic::I2oConnect {
+            tunnel_id,
+            session_id,
+            tunnel_type: tunnel_type as u32,
+            addr,
+            is_tcp,
+            is_compressed,
+            encryption_method,
+            encryption_key,
+            client_addr,
+            o2i_codec: o2i_codec.map(|(is_compressed, encryption_method, encryption_key)| generic::DataCodec {
                 is_compressed,
                 encryption_method,
                 encryption_key,
-                client_addr,
-            })
-        }
-        ProxyMessage::O2iConnect(session_id, success, error_info) => MessageType::GenericO2iConnect(generic::O2iConnect {
+            }),
+            write_coalesce,
+            generation,
+            backend_tls: backend_tls.map(|(verify, alpn)| generic::BackendTls { verify, alpn }),
+            compression_dictionary,
+            metadata,
+            key_derivation: key_derivation.as_ref().map_or(0, |x| x.0),
+            key_salt: key_derivation.map_or(String::new(), |x| x.1),
+            compress_threshold,
+        }),
+        ProxyMessage::O2iConnect(session_id, generation, success, error_info, independent_codec, backend_addr, outlet_id) => MessageType::GenericO2iConnect(generic::O2iConnect {
             tunnel_id,
             session_id,
             success,
             error_info,
+            independent_codec,
+            generation,
+            backend_addr,
+            outlet_id,
         }),
         ProxyMessage::I2oSendData(session_id, data) => MessageType::GenericI2oSendData(generic::I2oSendData { tunnel_id, session_id, data }),
         ProxyMessage::I2oSendToData(session_id, data, target_addr) => MessageType::GenericI2oSendToData(generic::I2oSendToData {
@@ -30,17 +62,24 @@ pub fn proxy_message_2_pb(proxy_message: ProxyMessage, tunnel_id: u32) -> Messag
             data,
             target_addr,
         }),
-        ProxyMessage::O2iSendDataResult(session_id, data_len) => MessageType::GenericO2iSendDataResult(generic::O2iSendDataResult {
+        ProxyMessage::O2iSendDataResult(session_id, generation, data_len) => MessageType::GenericO2iSendDataResult(generic::O2iSendDataResult {
             tunnel_id,
             session_id,
             data_len: data_len as u32,
+            generation,
         }),
-        ProxyMessage::O2iRecvData(session_id, data) => MessageType::GenericO2iRecvData(generic::O2iRecvData { tunnel_id, session_id, data }),
-        ProxyMessage::O2iRecvDataFrom(session_id, data, remote_addr) => MessageType::GenericO2iRecvDataFrom(generic::O2iRecvDataFrom {
+        ProxyMessage::O2iRecvData(session_id, generation, data) => MessageType::GenericO2iRecvData(generic::O2iRecvData {
+            tunnel_id,
+            session_id,
+            data,
+            generation,
+        }),
+        ProxyMessage::O2iRecvDataFrom(session_id, generation, data, remote_addr) => MessageType::GenericO2iRecvDataFrom(generic::O2iRecvDataFrom {
             tunnel_id,
             session_id,
             data,
             remote_addr,
+            generation,
         }),
         ProxyMessage::I2oRecvDataResult(session_id, data_len) => MessageType::GenericI2oRecvDataResult(generic::I2oRecvDataResult {
             tunnel_id,
@@ -48,7 +87,19 @@ pub fn proxy_message_2_pb(proxy_message: ProxyMessage, tunnel_id: u32) -> Messag
             data_len: data_len as u32,
         }),
         ProxyMessage::I2oDisconnect(session_id) => MessageType::GenericI2oDisconnect(generic::I2oDisconnect { tunnel_id, session_id }),
-        ProxyMessage::O2iDisconnect(session_id) => MessageType::GenericO2iDisconnect(generic::O2iDisconnect { tunnel_id, session_id }),
+        ProxyMessage::O2iDisconnect(session_id, generation) => MessageType::GenericO2iDisconnect(generic::O2iDisconnect {
+            tunnel_id,
+            session_id,
+            generation,
+        }),
+        ProxyMessage::I2oOpenStream(session_id, generation, token) => MessageType::GenericI2oOpenStream(generic::I2oOpenStream {
+            tunnel_id,
+            session_id,
+            generation,
+            token,
+        }),
+        ProxyMessage::I2oProbe(ticks) => MessageType::GenericI2oProbe(generic::I2oProbe { tunnel_id, ticks }),
+        ProxyMessage::O2iProbe(ticks) => MessageType::GenericO2iProbe(generic::O2iProbe { tunnel_id, ticks }),
     }
 }
 
@@ -87,6 +138,10 @@ pub fn pb_2_proxy_message(message: MessageType) -> Option<(ProxyMessage, u32)> {
             let tunnel_id = msg.tunnel_id;
             Some((msg.into(), tunnel_id))
         }
+        MessageType::GenericI2oOpenStream(msg) => {
+            let tunnel_id = msg.tunnel_id;
+            Some((msg.into(), tunnel_id))
+        }
         MessageType::GenericO2iDisconnect(msg) => {
             let tunnel_id = msg.tunnel_id;
             Some((msg.into(), tunnel_id))
@@ -95,44 +150,65 @@ pub fn pb_2_proxy_message(message: MessageType) -> Option<(ProxyMessage, u32)> {
             let tunnel_id = msg.tunnel_id;
             Some((msg.into(), tunnel_id))
         }
+        MessageType::GenericI2oProbe(msg) => Some((ProxyMessage::I2oProbe(msg.ticks), msg.tunnel_id)),
+        MessageType::GenericO2iProbe(msg) => Some((ProxyMessage::O2iProbe(msg.ticks), msg.tunnel_id)),
         _ => None,
     }
 }
 
 pub fn is_i2o_message(proxy_message: &ProxyMessage) -> bool {
     match proxy_message {
-        ProxyMessage::I2oConnect(_, ..)
-        | ProxyMessage::I2oSendData(_, ..)
-        | ProxyMessage::I2oSendToData(_, ..)
+        ProxyMessage::I2oConnect { .. }
+        | ProxyMessage::I2oSendData(..)
+        | ProxyMessage::I2oSendToData(..)
         | ProxyMessage::I2oDisconnect(_)
-        | ProxyMessage::I2oRecvDataResult(_, ..) => true,
+        | ProxyMessage::I2oRecvDataResult(..)
+        | ProxyMessage::I2oOpenStream(..)
+        | ProxyMessage::I2oProbe(_) => true,
 
-        ProxyMessage::O2iConnect(_, ..)
-        | ProxyMessage::O2iSendDataResult(_, ..)
-        | ProxyMessage::O2iRecvData(_, ..)
-        | ProxyMessage::O2iRecvDataFrom(_, ..)
-        | ProxyMessage::O2iDisconnect(_, ..) => false,
+        ProxyMessage::O2iConnect(..)
+        | ProxyMessage::O2iSendDataResult(..)
+        | ProxyMessage::O2iRecvData(..)
+        | ProxyMessage::O2iRecvDataFrom(..)
+        | ProxyMessage::O2iDisconnect(..)
+        | ProxyMessage::O2iProbe(_) => false,
     }
 }
 
 impl From<generic::I2oConnect> for ProxyMessage {
     fn from(msg: generic::I2oConnect) -> Self {
-        ProxyMessage::I2oConnect(
-            msg.session_id,
-            msg.tunnel_type as u8,
-            msg.is_tcp,
-            msg.is_compressed,
-            msg.addr,
-            msg.encryption_method,
-            msg.encryption_key,
-            msg.client_addr,
-        )
+        ProxyMessage::I2oConnect {
+            session_id: msg.session_id,
+            generation: msg.generation,
+            tunnel_type: msg.tunnel_type as u8,
+            is_tcp: msg.is_tcp,
+            is_compressed: msg.is_compressed,
+            addr: msg.addr,
+            encryption_method: msg.encryption_method,
+            encryption_key: msg.encryption_key,
+            client_addr: msg.client_addr,
+            o2i_codec: msg.o2i_codec.map(|x| (x.is_compressed, x.encryption_method, x.encryption_key)),
+            write_coalesce: msg.write_coalesce,
+            backend_tls: msg.backend_tls.map(|x| (x.verify, x.alpn)),
+            compression_dictionary: msg.compression_dictionary,
+            metadata: msg.metadata,
+            key_derivation: if msg.key_derivation == 0 { None } else { Some((msg.key_derivation, msg.key_salt)) },
+            compress_threshold: msg.compress_threshold,
+        }
     }
 }
 
 impl From<generic::O2iConnect> for ProxyMessage {
     fn from(msg: generic::O2iConnect) -> Self {
-        ProxyMessage::O2iConnect(msg.session_id, msg.success, msg.error_info)
+        ProxyMessage::O2iConnect(
+            msg.session_id,
+            msg.generation,
+            msg.success,
+            msg.error_info,
+            msg.independent_codec,
+            msg.backend_addr,
+            msg.outlet_id,
+        )
     }
 }
 
@@ -150,19 +226,25 @@ impl From<generic::I2oSendToData> for ProxyMessage {
 
 impl From<generic::O2iSendDataResult> for ProxyMessage {
     fn from(msg: generic::O2iSendDataResult) -> Self {
-        ProxyMessage::O2iSendDataResult(msg.session_id, msg.data_len as usize)
+        ProxyMessage::O2iSendDataResult(msg.session_id, msg.generation, msg.data_len as usize)
     }
 }
 
 impl From<generic::O2iRecvData> for ProxyMessage {
     fn from(msg: generic::O2iRecvData) -> Self {
-        ProxyMessage::O2iRecvData(msg.session_id, msg.data)
+        ProxyMessage::O2iRecvData(msg.session_id, msg.generation, msg.data)
+    }
+}
+
+impl From<generic::I2oOpenStream> for ProxyMessage {
+    fn from(msg: generic::I2oOpenStream) -> Self {
+        ProxyMessage::I2oOpenStream(msg.session_id, msg.generation, msg.token)
     }
 }
 
 impl From<generic::O2iRecvDataFrom> for ProxyMessage {
     fn from(msg: generic::O2iRecvDataFrom) -> Self {
-        ProxyMessage::O2iRecvDataFrom(msg.session_id, msg.data, msg.remote_addr)
+        ProxyMessage::O2iRecvDataFrom(msg.session_id, msg.generation, msg.data, msg.remote_addr)
     }
 }
 
@@ -180,6 +262,6 @@ impl From<generic::I2oDisconnect> for ProxyMessage {
 
 impl From<generic::O2iDisconnect> for ProxyMessage {
     fn from(msg: generic::O2iDisconnect) -> Self {
-        ProxyMessage::O2iDisconnect(msg.session_id)
+        ProxyMessage::O2iDisconnect(msg.session_id, msg.generation)
     }
 }
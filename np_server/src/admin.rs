@@ -0,0 +1,413 @@
+//! 本地管理接口
+//!
+//! 每行一个JSON-RPC请求, 每行一个回复, 没有认证, 默认只允许监听本机地址
+
+use crate::global::logger;
+use crate::global::manager::GLOBAL_MANAGER;
+use crate::web::{collect_tunnel_sessions, collect_tunnel_stats};
+use anyhow::anyhow;
+use log::{debug, error, info};
+use np_base::proxy::{global_buffered_bytes, unix_socket_path};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::select;
+
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const SERVER_ERROR: i32 = -32000;
+
+/// 每行请求的最大字节数(包括换行符), 超过时回复错误并断开
+const MAX_LINE_LEN: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct TunnelParams {
+    tunnel_id: u32,
+}
+
+#[derive(Deserialize)]
+struct SessionParams {
+    tunnel_id: u32,
+    session_id: u32,
+}
+
+#[derive(Deserialize, Default)]
+struct LogLevelParams {
+    #[serde(default)]
+    level: Option<String>,
+    #[serde(default)]
+    duration: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+struct FilterParams {
+    #[serde(default)]
+    tunnel_id: Option<u32>,
+}
+
+impl FilterParams {
+    fn matches(&self, tunnel_id: u32) -> bool {
+        self.tunnel_id.is_none() || self.tunnel_id == Some(tunnel_id)
+    }
+}
+
+#[derive(Serialize)]
+struct SessionItem {
+    tunnel_id: u32,
+    session_id: u32,
+    read_buf_len: usize,
+    stalled: bool,
+    // 以下字段只有入口会话才有
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_addr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_out: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend_addr: Option<String>,
+}
+
+pub(crate) enum AdminListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, String),
+}
+
+/// 绑定管理接口, 支持 `unix:/path/to.sock` 或TCP地址
+///
+/// 未开启 `allow_remote` 时TCP地址必须是本机回环地址
+pub(crate) async fn bind(addr: &str, allow_remote: bool) -> anyhow::Result<AdminListener> {
+    if let Some(path) = unix_socket_path(addr) {
+        return bind_unix(path);
+    }
+
+    let socket_addr = addr
+        .parse::<SocketAddr>()
+        .map_err(|err| anyhow!("invalid admin_addr '{addr}': {err}"))?;
+    if !allow_remote && !socket_addr.ip().is_loopback() {
+        return Err(anyhow!(
+            "admin_addr '{addr}' is not a loopback address, set admin_allow_remote to allow it"
+        ));
+    }
+    Ok(AdminListener::Tcp(TcpListener::bind(socket_addr).await?))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> anyhow::Result<AdminListener> {
+    Ok(AdminListener::Unix(
+        np_base::proxy::bind_unix_listener(path)?,
+        path.to_string(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str) -> anyhow::Result<AdminListener> {
+    Err(anyhow!("unix socket is not supported on this platform"))
+}
+
+impl AdminListener {
+    /// 接受连接直到收到退出信号
+    pub(crate) async fn run(self, shutdown: impl Future) {
+        select! {
+            _ = self.accept_loop() => {},
+            _ = shutdown => {},
+        }
+    }
+
+    async fn accept_loop(&self) {
+        loop {
+            let result = match self {
+                AdminListener::Tcp(listener) => listener.accept().await.map(|(stream, addr)| {
+                    debug!("admin connection from {addr}");
+                    tokio::spawn(handle_connection(stream));
+                }),
+                #[cfg(unix)]
+                AdminListener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| {
+                    tokio::spawn(handle_connection(stream));
+                }),
+            };
+            if let Err(err) = result {
+                error!("admin accept error: {err}");
+            }
+        }
+    }
+}
+
+impl Drop for AdminListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let AdminListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+async fn handle_connection<S>(stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match (&mut reader)
+            .take(MAX_LINE_LEN as u64)
+            .read_line(&mut line)
+            .await
+        {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        // 读满上限还没有换行符, 回复错误后断开, 不再继续读取
+        let too_long = line.len() >= MAX_LINE_LEN && !line.ends_with('\n');
+        let response = if too_long {
+            error_response(
+                Value::Null,
+                PARSE_ERROR,
+                format!("request exceeds {MAX_LINE_LEN} bytes"),
+            )
+        } else if line.trim().is_empty() {
+            continue;
+        } else {
+            handle_line(&line).await
+        };
+        let mut data = serde_json::to_vec(&response).unwrap_or_default();
+        data.push(b'\n');
+        if writer.write_all(&data).await.is_err() || too_long {
+            break;
+        }
+    }
+}
+
+async fn handle_line(line: &str) -> Response {
+    let request = match serde_json::from_str::<Request>(line) {
+        Ok(request) => request,
+        Err(err) => return error_response(Value::Null, PARSE_ERROR, err.to_string()),
+    };
+
+    info!("admin call: {}", request.method);
+    match call(&request.method, request.params).await {
+        Ok(result) => Response {
+            jsonrpc: "2.0",
+            id: request.id,
+            result: Some(result),
+            error: None,
+        },
+        Err((code, message)) => error_response(request.id, code, message),
+    }
+}
+
+fn error_response(id: Value, code: i32, message: String) -> Response {
+    Response {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(RpcError { code, message }),
+    }
+}
+
+/// 本机的所有会话, 包括入口和出口
+async fn collect_sessions(params: &FilterParams) -> Vec<SessionItem> {
+    let mut sessions = Vec::new();
+    for tunnel in collect_tunnel_stats().await {
+        if !params.matches(tunnel.tunnel_id) {
+            continue;
+        }
+        let summaries = collect_tunnel_sessions(tunnel.tunnel_id)
+            .await
+            .unwrap_or_default();
+        sessions.extend(tunnel.sessions.into_iter().map(|x| {
+            let summary = summaries.iter().find(|y| y.session_id == x.session_id);
+            SessionItem {
+                tunnel_id: tunnel.tunnel_id,
+                session_id: x.session_id,
+                read_buf_len: x.read_buf_len,
+                stalled: x.stalled,
+                peer_addr: summary.map(|y| y.peer_addr.clone()),
+                country: summary.map(|y| y.country.clone()),
+                bytes_in: summary.map(|y| y.bytes_in),
+                bytes_out: summary.map(|y| y.bytes_out),
+                age: summary.map(|y| y.age),
+                backend_addr: summary.map(|y| y.backend_addr.clone()),
+            }
+        }));
+    }
+    sessions
+}
+
+/// 排空状态, 编排工具轮询直到active_sessions为0后再停止本实例
+async fn drain_status() -> Value {
+    let sessions = collect_sessions(&FilterParams::default()).await;
+    json!({
+        "draining": GLOBAL_MANAGER.proxy_manager.is_draining(),
+        "active_sessions": sessions.len(),
+        "sessions": sessions,
+    })
+}
+
+fn parse_params<T: for<'de> Deserialize<'de> + Default>(params: Value) -> Result<T, (i32, String)> {
+    if params.is_null() {
+        return Ok(T::default());
+    }
+    serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))
+}
+
+async fn call(method: &str, params: Value) -> Result<Value, (i32, String)> {
+    let server_error = |err: anyhow::Error| (SERVER_ERROR, err.to_string());
+    match method {
+        "reload" => {
+            // 重新从数据库加载通道并同步本机代理
+            GLOBAL_MANAGER
+                .tunnel_manager
+                .load_all_tunnel()
+                .await
+                .map_err(server_error)?;
+            // 部分通道启动失败时仍然算作重载成功, 失败原因一并返回
+            let error = GLOBAL_MANAGER
+                .proxy_manager
+                .sync_tunnels()
+                .await
+                .err()
+                .map(|err| err.to_string());
+            let count = GLOBAL_MANAGER.tunnel_manager.tunnels.read().await.len();
+            Ok(json!({ "tunnels": count, "error": error }))
+        }
+        "stats" => {
+            let params: FilterParams = parse_params(params)?;
+            let tunnels: Vec<_> = collect_tunnel_stats()
+                .await
+                .into_iter()
+                .filter(|x| params.matches(x.tunnel_id))
+                .collect();
+            Ok(json!({
+                "tunnels": tunnels,
+                "global_buffered_bytes": global_buffered_bytes(),
+            }))
+        }
+        "pause_tunnel" | "resume_tunnel" => {
+            let params: TunnelParams =
+                serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+            GLOBAL_MANAGER
+                .tunnel_manager
+                .set_tunnel_paused(params.tunnel_id, method == "pause_tunnel")
+                .await
+                .map_err(server_error)?;
+            Ok(Value::Bool(true))
+        }
+        "restart_tunnel" => {
+            let params: TunnelParams =
+                serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+            GLOBAL_MANAGER
+                .proxy_manager
+                .restart_tunnel(params.tunnel_id)
+                .await
+                .map_err(server_error)?;
+            Ok(Value::Bool(true))
+        }
+        "reset_traffic" => {
+            let params: TunnelParams =
+                serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+            GLOBAL_MANAGER
+                .tunnel_manager
+                .reset_traffic(params.tunnel_id)
+                .await
+                .map_err(server_error)?;
+            Ok(Value::Bool(true))
+        }
+        "list_sessions" => {
+            let params: FilterParams = parse_params(params)?;
+            Ok(json!({ "sessions": collect_sessions(&params).await }))
+        }
+        "drain" | "undrain" => {
+            GLOBAL_MANAGER
+                .proxy_manager
+                .set_draining(method == "drain")
+                .await;
+            Ok(drain_status().await)
+        }
+        "drain_status" => Ok(drain_status().await),
+        "kill_session" => {
+            let params: SessionParams =
+                serde_json::from_value(params).map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+            if !GLOBAL_MANAGER
+                .proxy_manager
+                .kill_session(params.tunnel_id, params.session_id)
+                .await
+            {
+                return Err((
+                    SERVER_ERROR,
+                    format!("inlet({}) is not running on the server", params.tunnel_id),
+                ));
+            }
+            Ok(Value::Bool(true))
+        }
+        "log_level" => {
+            let params: LogLevelParams = parse_params(params)?;
+            if let Some(level) = params.level {
+                logger::set_log_level(&level, params.duration.map(Duration::from_secs))
+                    .map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+            }
+            Ok(json!({ "level": logger::log_level() }))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("method not found: {method}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_line_too_long() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (reader, mut writer) = tokio::io::split(client);
+        let task = tokio::spawn(handle_connection(server));
+
+        // 超过上限的请求回复错误后断开, 不等待换行符
+        let request = vec![b'x'; MAX_LINE_LEN + 1];
+        let mut reader = BufReader::new(reader);
+        let mut response = String::new();
+        let (_, read_result) =
+            tokio::join!(writer.write_all(&request), reader.read_line(&mut response));
+        read_result.unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use crate::global::manager::tunnel::join_player_ids;
+use crate::global::manager::GLOBAL_MANAGER;
+use crate::global::opts::TunnelCommand;
+use crate::orm_entity::tunnel;
+use anyhow::anyhow;
+use np_base::proxy::crypto;
+use rand::RngCore;
+use std::time::{Duration, Instant};
+
+/// 执行离线通道管理命令, 与运行时使用相同的校验逻辑
+pub(crate) async fn run_tunnel_command(command: &TunnelCommand) -> anyhow::Result<()> {
+    let tunnel_manager = &GLOBAL_MANAGER.tunnel_manager;
+    match command {
+        TunnelCommand::Add {
+            source,
+            endpoint,
+            sender,
+            receiver,
+            tunnel_type,
+            description,
+            username,
+            password,
+            compressed,
+            encryption_method,
+            disabled,
+            max_session_lifetime,
+            accept_proxy_protocol,
+            bytes_quota,
+            extra_senders,
+            write_coalesce,
+            connect_retries,
+            connect_retry_delay,
+            conn_rate_limit,
+            conn_rate_burst,
+            bind_device,
+            balance_policy,
+            tls_cert,
+            tls_key,
+            tls_alpn,
+            backend_tls,
+            compression_dictionary,
+            auth_url,
+            pool_size,
+            pool_max_idle,
+            frame_prefix,
+            reject_response,
+            outlet_buffer_limit,
+            external_id,
+            key_derivation,
+            reverse_stream,
+            outlet_weights,
+            inlet_nodelay,
+            outlet_nodelay,
+            compress_threshold,
+            fast_open,
+            encryption_fallback,
+        } => {
+            let tunnel_id = tunnel_manager
+                .add_tunnel(tunnel::Model {
+                    id: 0,
+                    source: source.clone(),
+                    endpoint: endpoint.clone(),
+                    enabled: !disabled as u8,
+                    sender: *sender,
+                    receiver: *receiver,
+                    description: description.clone(),
+                    tunnel_type: *tunnel_type,
+                    password: password.clone(),
+                    username: username.clone(),
+                    is_compressed: *compressed as u8,
+                    custom_mapping: "{}".into(),
+                    encryption_method: encryption_method.clone(),
+                    paused: 0,
+                    max_session_lifetime: *max_session_lifetime,
+                    o2i_is_compressed: None,
+                    o2i_encryption_method: None,
+                    accept_proxy_protocol: *accept_proxy_protocol as u8,
+                    bytes_quota: *bytes_quota,
+                    bytes_used: 0,
+                    extra_senders: join_player_ids(extra_senders),
+                    write_coalesce: *write_coalesce,
+                    connect_retries: *connect_retries,
+                    connect_retry_delay: *connect_retry_delay,
+                    conn_rate_limit: *conn_rate_limit,
+                    conn_rate_burst: *conn_rate_burst,
+                    bind_device: bind_device.clone(),
+                    balance_policy: *balance_policy,
+                    tls_cert: tls_cert.clone(),
+                    tls_key: tls_key.clone(),
+                    tls_alpn: tls_alpn.clone(),
+                    backend_tls: *backend_tls,
+                    compression_dictionary: compression_dictionary.clone(),
+                    auth_url: auth_url.clone(),
+                    pool_size: *pool_size,
+                    pool_max_idle: *pool_max_idle,
+                    frame_prefix: *frame_prefix,
+                    reject_response: reject_response.clone(),
+                    outlet_buffer_limit: *outlet_buffer_limit,
+                    external_id: external_id.clone(),
+                    key_derivation: *key_derivation,
+                    reverse_stream: *reverse_stream as u8,
+                    outlet_weights: outlet_weights.clone(),
+                    inlet_nodelay: *inlet_nodelay as u8,
+                    outlet_nodelay: *outlet_nodelay as u8,
+                    compress_threshold: *compress_threshold,
+                    fast_open: *fast_open as u8,
+                    assigned_port: 0,
+                    encryption_fallback: *encryption_fallback as u8,
+                })
+                .await?;
+            println!("added tunnel {tunnel_id}");
+        }
+        TunnelCommand::List => {
+            for tunnel in tunnel_manager.tunnels.read().await.iter() {
+                println!(
+                    "{}\ttype:{}\t{} -> {}\treceiver:{}\tsender:{}\tenabled:{}\t{}",
+                    tunnel.id,
+                    tunnel.tunnel_type,
+                    tunnel.source,
+                    tunnel.endpoint,
+                    tunnel.receiver,
+                    tunnel.sender,
+                    tunnel.enabled,
+                    tunnel.description
+                );
+            }
+        }
+        TunnelCommand::Delete { id } => {
+            tunnel_manager.delete_tunnel(*id).await?;
+            println!("deleted tunnel {id}");
+        }
+        TunnelCommand::HashPasswords { dry_run } => {
+            let ids = tunnel_manager.hash_plaintext_passwords(*dry_run).await?;
+            for id in &ids {
+                if *dry_run {
+                    println!("tunnel {id}: plaintext password would be hashed");
+                } else {
+                    println!("tunnel {id}: password hashed");
+                }
+            }
+            println!("{} tunnel(s)", ids.len());
+        }
+    }
+    Ok(())
+}
+
+/// 用指定的加密方法反复加密再解密随机数据, 检查结果一致并输出吞吐量
+///
+/// 每轮使用新生成的key, 失败时返回错误, 进程以非0状态退出
+pub(crate) fn run_crypto_selftest(
+    method: &str,
+    size: usize,
+    iterations: u32,
+) -> anyhow::Result<()> {
+    let method = crypto::parse_method(method)
+        .ok_or_else(|| anyhow!("unknown encryption method: {method} (None, Aes128, Xor)"))?;
+    let mut data = vec![0u8; size];
+    let mut encrypt_time = Duration::ZERO;
+    let mut decrypt_time = Duration::ZERO;
+    for i in 0..iterations {
+        rand::thread_rng().fill_bytes(&mut data);
+        let key = crypto::generate_key(&method);
+
+        let start = Instant::now();
+        let ciphertext = crypto::encrypt(&method, &key, data.clone())?;
+        encrypt_time += start.elapsed();
+        if !method.is_none() && size > 0 && ciphertext == data {
+            return Err(anyhow!(
+                "round {i}: ciphertext is identical to the plaintext"
+            ));
+        }
+
+        let start = Instant::now();
+        let plaintext = crypto::decrypt(&method, &key, ciphertext)?;
+        decrypt_time += start.elapsed();
+        if plaintext != data {
+            return Err(anyhow!(
+                "round {i}: decrypted data does not match the original"
+            ));
+        }
+    }
+
+    let total = size as f64 * iterations as f64 / (1024.0 * 1024.0);
+    let throughput = |time: Duration| total / time.as_secs_f64().max(f64::EPSILON);
+    println!("method: {method}, size: {size} bytes, iterations: {iterations}");
+    println!("encrypt: {:.2} MiB/s", throughput(encrypt_time));
+    println!("decrypt: {:.2} MiB/s", throughput(decrypt_time));
+    println!("round-trip ok");
+    Ok(())
+}
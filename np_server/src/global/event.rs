@@ -0,0 +1,189 @@
+//! 对外发送的事件
+//!
+//! 事件序列化为 `{"v": 1, "type": "...", "ts": 秒, "data": {...}}`, `data` 的内容由 `type` 决定.
+//! 删除字段或改变已有字段的含义时增加 [`EVENT_VERSION`], 新增事件类型或字段不需要
+
+use crate::orm_entity::tunnel;
+use np_base::proxy::stats::SessionEvent;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 事件格式的版本
+pub(crate) const EVENT_VERSION: u32 = 1;
+
+/// 事件及其内容
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub(crate) enum Event {
+    /// `tunnel.created`
+    TunnelCreated(TunnelData),
+    /// `tunnel.updated`
+    TunnelUpdated(TunnelData),
+    /// `tunnel.deleted`
+    TunnelDeleted(TunnelDeletedData),
+    /// `session.opened`, 本机入口的会话开始
+    SessionOpened(SessionOpenedData),
+    /// `session.closed`, 本机入口的会话结束
+    SessionClosed(SessionClosedData),
+}
+
+/// 通道的内容, 不包含密码等敏感信息
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct TunnelData {
+    pub id: u32,
+    pub source: String,
+    pub endpoint: String,
+    pub enabled: bool,
+    pub paused: bool,
+    pub sender: u32,
+    pub receiver: u32,
+    pub tunnel_type: u32,
+    pub description: String,
+    pub external_id: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct TunnelDeletedData {
+    pub id: u32,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct SessionOpenedData {
+    pub tunnel_id: u32,
+    pub session_id: u32,
+    pub peer_addr: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct SessionClosedData {
+    pub tunnel_id: u32,
+    pub session_id: u32,
+    pub peer_addr: String,
+    /// 关闭原因, 与 `SessionCloseReason::as_str` 相同
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    v: u32,
+    #[serde(rename = "type")]
+    event_type: &'static str,
+    ts: u64,
+    data: &'a Event,
+}
+
+impl Event {
+    pub(crate) fn tunnel_created(tunnel: &tunnel::Model) -> Self {
+        Self::TunnelCreated(TunnelData::from(tunnel))
+    }
+
+    pub(crate) fn tunnel_updated(tunnel: &tunnel::Model) -> Self {
+        Self::TunnelUpdated(TunnelData::from(tunnel))
+    }
+
+    pub(crate) fn tunnel_deleted(id: u32) -> Self {
+        Self::TunnelDeleted(TunnelDeletedData { id })
+    }
+
+    pub(crate) fn session(tunnel_id: u32, event: SessionEvent) -> Self {
+        match event {
+            SessionEvent::Open {
+                session_id,
+                peer_addr,
+            } => Self::SessionOpened(SessionOpenedData {
+                tunnel_id,
+                session_id,
+                peer_addr: peer_addr.to_string(),
+            }),
+            SessionEvent::Close {
+                session_id,
+                peer_addr,
+                reason,
+            } => Self::SessionClosed(SessionClosedData {
+                tunnel_id,
+                session_id,
+                peer_addr: peer_addr.to_string(),
+                reason: reason.as_str().to_string(),
+            }),
+        }
+    }
+
+    /// 事件类型
+    pub(crate) fn event_type(&self) -> &'static str {
+        match self {
+            Self::TunnelCreated(_) => "tunnel.created",
+            Self::TunnelUpdated(_) => "tunnel.updated",
+            Self::TunnelDeleted(_) => "tunnel.deleted",
+            Self::SessionOpened(_) => "session.opened",
+            Self::SessionClosed(_) => "session.closed",
+        }
+    }
+
+    /// 按当前时间序列化为带版本的JSON
+    pub(crate) fn to_json(&self) -> String {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.to_json_at(ts)
+    }
+
+    fn to_json_at(&self, ts: u64) -> String {
+        serde_json::to_string(&Envelope {
+            v: EVENT_VERSION,
+            event_type: self.event_type(),
+            ts,
+            data: self,
+        })
+        .unwrap_or_default()
+    }
+}
+
+impl From<&tunnel::Model> for TunnelData {
+    fn from(tunnel: &tunnel::Model) -> Self {
+        Self {
+            id: tunnel.id,
+            source: tunnel.source.clone(),
+            endpoint: tunnel.endpoint.clone(),
+            enabled: tunnel.enabled == 1,
+            paused: tunnel.paused == 1,
+            sender: tunnel.sender,
+            receiver: tunnel.receiver,
+            tunnel_type: tunnel.tunnel_type,
+            description: tunnel.description.clone(),
+            external_id: tunnel.external_id.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use np_base::proxy::stats::SessionCloseReason;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_event_envelope() {
+        let event = Event::tunnel_deleted(3);
+        let value: Value = serde_json::from_str(&event.to_json_at(100)).unwrap();
+        assert_eq!(
+            value,
+            json!({"v": 1, "type": "tunnel.deleted", "ts": 100, "data": {"id": 3}})
+        );
+
+        let event = Event::session(
+            2,
+            SessionEvent::Close {
+                session_id: 7,
+                peer_addr: "127.0.0.1:9000".parse().unwrap(),
+                reason: SessionCloseReason::Idle,
+            },
+        );
+        let value: Value = serde_json::from_str(&event.to_json_at(100)).unwrap();
+        assert_eq!(value["type"], "session.closed");
+        assert_eq!(
+            value["data"],
+            json!({"tunnel_id": 2, "session_id": 7, "peer_addr": "127.0.0.1:9000", "reason": "idle"})
+        );
+    }
+}
@@ -1,41 +1,131 @@
+use super::config::{LogFormat, GLOBAL_CONFIG};
 use super::opts::GLOBAL_OPTS;
-use flexi_logger::{Age, Cleanup, Criterion, Duplicate, FileSpec, Logger, Naming, WriteMode};
+use super::syslog::SyslogWriter;
+use flexi_logger::{
+    Age, Cleanup, Criterion, DeferredNow, Duplicate, FileSpec, LogSpecification, Logger, Naming,
+    Record, WriteMode,
+};
 use std::env;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
 
 static LOGGER_HANDLER: tokio::sync::OnceCell<flexi_logger::LoggerHandle> =
     tokio::sync::OnceCell::const_new();
 
+/// 当前生效的日志级别, 以及用于判断临时级别是否已被再次修改的版本号
+static LOG_LEVEL: Mutex<(String, u64)> = Mutex::new((String::new(), 0));
+
 pub(crate) fn init_logger() -> anyhow::Result<()> {
     if GLOBAL_OPTS.backtrace {
         env::set_var("RUST_BACKTRACE", "1");
     }
 
-    // 日志初始化
-    let logger = Logger::try_with_str(format!(
-        "{}, sqlx=error, actix=error, mio=error, sea_orm=error, np_base={}",
-        GLOBAL_OPTS.log_level, GLOBAL_OPTS.base_log_level,
-    ))?
-    .log_to_file(
-        FileSpec::default()
-            .directory("logs")
-            .suppress_timestamp()
-            .suffix("log"),
-    )
-    .duplicate_to_stdout(Duplicate::All)
-    .format(flexi_logger::opt_format)
-    .format_for_stdout(flexi_logger::colored_opt_format)
-    .rotate(
-        Criterion::AgeOrSize(Age::Day, 1024 * 1024 * 5),
-        Naming::Numbers,
-        Cleanup::KeepLogFiles(30),
-    )
-    .print_message()
-    .write_mode(WriteMode::Async)
-    .start()?;
+    // 配置文件中的日志级别优先于命令行参数
+    let spec = if GLOBAL_CONFIG.log_level.is_empty() {
+        format!(
+            "{}, sqlx=error, actix=error, mio=error, sea_orm=error, np_base={}",
+            GLOBAL_OPTS.log_level, GLOBAL_OPTS.base_log_level,
+        )
+    } else {
+        GLOBAL_CONFIG.log_level.clone()
+    };
+
+    // 日志初始化, 配置了syslog时同时写入syslog
+    let file_spec = FileSpec::default()
+        .directory("logs")
+        .suppress_timestamp()
+        .suffix("log");
+    let logger = Logger::try_with_str(&spec)?;
+    let logger = if GLOBAL_CONFIG.syslog_addr.is_empty() {
+        logger.log_to_file(file_spec)
+    } else {
+        let writer = SyslogWriter::new(
+            &GLOBAL_CONFIG.syslog_addr,
+            &GLOBAL_CONFIG.syslog_facility,
+            &GLOBAL_CONFIG.syslog_severity,
+        )?;
+        logger.log_to_file_and_writer(file_spec, Box::new(writer))
+    }
+    .duplicate_to_stdout(Duplicate::All);
+    let logger = match GLOBAL_CONFIG.log_format {
+        LogFormat::Text => logger
+            .format(flexi_logger::opt_format)
+            .format_for_stdout(flexi_logger::colored_opt_format),
+        LogFormat::Json => logger.format(json_format),
+    };
+    let logger = logger
+        .rotate(
+            Criterion::AgeOrSize(Age::Day, 1024 * 1024 * 5),
+            Naming::Numbers,
+            Cleanup::KeepLogFiles(30),
+        )
+        .print_message()
+        .write_mode(WriteMode::Async)
+        .start()?;
 
     LOGGER_HANDLER
         .set(logger)
         .map_err(|err| anyhow::anyhow!("logger set error: {}", err))?;
+    LOG_LEVEL.lock().unwrap().0 = spec;
 
     Ok(())
 }
+
+/// 每行一个JSON对象, 便于日志系统采集
+fn json_format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    let line = serde_json::json!({
+        "time": now.format_rfc3339(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "file": record.file().unwrap_or("<unnamed>"),
+        "line": record.line(),
+        "message": record.args().to_string(),
+    });
+    write!(w, "{line}")
+}
+
+/// 当前生效的日志级别
+pub(crate) fn log_level() -> String {
+    LOG_LEVEL.lock().unwrap().0.clone()
+}
+
+/// 运行时修改日志级别, 格式与 `RUST_LOG` 相同, 例如 `info, np_base::proxy::inlet=trace`
+///
+/// 设置了 `duration` 时, 到期后恢复为修改前的级别(期间再次修改则不恢复)
+pub(crate) fn set_log_level(spec: &str, duration: Option<Duration>) -> anyhow::Result<()> {
+    let handler = LOGGER_HANDLER
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("logger is not initialized"))?;
+    let new_spec = LogSpecification::parse(spec)?;
+
+    let (previous, generation) = {
+        let mut level = LOG_LEVEL.lock().unwrap();
+        handler.set_new_spec(new_spec);
+        let previous = std::mem::replace(&mut level.0, spec.to_string());
+        level.1 += 1;
+        (previous, level.1)
+    };
+    log::info!("Log level changed to '{spec}'");
+
+    if let Some(duration) = duration {
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            let mut level = LOG_LEVEL.lock().unwrap();
+            if level.1 != generation {
+                return;
+            }
+            if let Ok(spec) = LogSpecification::parse(&previous) {
+                handler.set_new_spec(spec);
+                level.0 = previous;
+                level.1 += 1;
+                log::info!("Log level restored to '{}'", level.0);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use crate::global::config::GLOBAL_CONFIG;
+use crate::global::manager::proxy::outlet_data_ex;
+use crate::global::manager::GLOBAL_MANAGER;
+use crate::orm_entity::tunnel;
+use crate::player::PlayerId;
+use chrono::Utc;
+use log::{info, warn};
+use np_base::proxy::inlet::InletProxyType;
+use np_proto::class_def::EndpointHealth;
+use np_proto::message_map::MessageType;
+use np_proto::server_client;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+
+/// 出口玩家不在线
+const OUTLET_OFFLINE: &str = "outlet offline";
+
+/// 某个出口到通道后端的连通性
+#[derive(Clone, Debug)]
+pub struct EndpointState {
+    pub reachable: bool,
+    /// 建立连接的耗时(毫秒)
+    pub latency: u32,
+    /// 无法连接的原因
+    pub error: String,
+    /// 最后一次收到结果的时间(unix时间戳, 秒)
+    pub checked_at: i64,
+}
+
+pub struct HealthManager {
+    // 键为(通道id, 出口玩家id), 出口玩家id为0表示服务器
+    states: RwLock<HashMap<(u32, PlayerId), EndpointState>>,
+}
+
+impl HealthManager {
+    pub fn new() -> Self {
+        Self {
+            states: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// 所有出口的连通性, 按(通道id, 出口玩家id)排序
+    pub async fn list(&self) -> Vec<(u32, PlayerId, EndpointState)> {
+        let mut list: Vec<_> = self
+            .states
+            .read()
+            .await
+            .iter()
+            .map(|(&(tunnel_id, player_id), state)| (tunnel_id, player_id, state.clone()))
+            .collect();
+        list.sort_by_key(|x| (x.0, x.1));
+        list
+    }
+
+    /// 向所有出口发起一轮探测
+    ///
+    /// 出口在服务器上时直接探测, 在线玩家发送探测请求, 结果异步回到 [`Self::on_probe_result`],
+    /// 不在线的玩家直接记为不可连接
+    pub async fn probe_all(&self) {
+        let probe_timeout = GLOBAL_CONFIG.endpoint_probe_timeout.max(1);
+        let tunnels: Vec<tunnel::Model> = GLOBAL_MANAGER
+            .tunnel_manager
+            .tunnels
+            .read()
+            .await
+            .iter()
+            .filter(|x| x.is_active() && is_probeable(x))
+            .cloned()
+            .collect();
+
+        let mut targets: HashMap<PlayerId, Vec<u32>> = HashMap::new();
+        for tunnel in tunnels.iter() {
+            for player_id in tunnel.outlet_players() {
+                targets.entry(player_id).or_default().push(tunnel.id);
+            }
+        }
+
+        // 清理已删除或不再需要探测的通道
+        let keys: HashSet<(u32, PlayerId)> = targets
+            .iter()
+            .flat_map(|(player_id, ids)| ids.iter().map(|id| (*id, *player_id)))
+            .collect();
+        self.states
+            .write()
+            .await
+            .retain(|key, _| keys.contains(key));
+
+        for (player_id, tunnel_id_list) in targets {
+            if player_id == 0 {
+                let endpoints: Vec<(u32, String)> = tunnels
+                    .iter()
+                    .filter(|x| tunnel_id_list.contains(&x.id))
+                    .map(|x| (x.id, x.endpoint.clone()))
+                    .collect();
+                tokio::spawn(async move {
+                    let data_ex = outlet_data_ex();
+                    let mut probes = JoinSet::new();
+                    for (tunnel_id, endpoint) in endpoints {
+                        let data_ex = data_ex.clone();
+                        probes.spawn(async move {
+                            let result = data_ex
+                                .probe(&endpoint, Duration::from_secs(probe_timeout))
+                                .await;
+                            to_endpoint_health(tunnel_id, result)
+                        });
+                    }
+                    let mut health_list = Vec::new();
+                    while let Some(result) = probes.join_next().await {
+                        if let Ok(health) = result {
+                            health_list.push(health);
+                        }
+                    }
+                    GLOBAL_MANAGER
+                        .health_manager
+                        .on_probe_result(0, health_list)
+                        .await;
+                });
+                continue;
+            }
+
+            let online = match GLOBAL_MANAGER.player_manager.get_player(player_id).await {
+                Some(player) => {
+                    let player = player.read().await;
+                    if player.is_online() {
+                        let _ = player
+                            .send_request(&MessageType::ServerClientProbeEndpointReq(
+                                server_client::ProbeEndpointReq {
+                                    tunnel_id_list: tunnel_id_list.clone(),
+                                    timeout: probe_timeout as u32,
+                                },
+                            ))
+                            .await;
+                    }
+                    player.is_online()
+                }
+                None => false,
+            };
+            if !online {
+                let health_list = tunnel_id_list
+                    .into_iter()
+                    .map(|tunnel_id| EndpointHealth {
+                        tunnel_id,
+                        reachable: false,
+                        latency: 0,
+                        error: OUTLET_OFFLINE.into(),
+                    })
+                    .collect();
+                self.on_probe_result(player_id, health_list).await;
+            }
+        }
+    }
+
+    /// 收到出口的探测结果, 连通性变化时通知入口所在的玩家
+    pub async fn on_probe_result(&self, player_id: PlayerId, health_list: Vec<EndpointHealth>) {
+        let checked_at = Utc::now().timestamp();
+        let mut changed = Vec::new();
+        {
+            let tunnels = GLOBAL_MANAGER.tunnel_manager.tunnels.read().await;
+            let mut states = self.states.write().await;
+            for health in health_list {
+                // 只接受该玩家作为出口的通道
+                let Some(tunnel) = tunnels.iter().find(|x| x.id == health.tunnel_id) else {
+                    continue;
+                };
+                if !tunnel.outlet_players().contains(&player_id) {
+                    continue;
+                }
+                let previous = states.insert(
+                    (health.tunnel_id, player_id),
+                    EndpointState {
+                        reachable: health.reachable,
+                        latency: health.latency,
+                        error: health.error.clone(),
+                        checked_at,
+                    },
+                );
+                if previous.map_or(!health.reachable, |x| x.reachable != health.reachable) {
+                    changed.push((tunnel.receiver, health));
+                }
+            }
+        }
+
+        for (receiver, health) in changed {
+            if health.reachable {
+                info!(
+                    "tunnel({}) endpoint is reachable from outlet player({player_id})",
+                    health.tunnel_id
+                );
+            } else {
+                warn!(
+                    "tunnel({}) endpoint is unreachable from outlet player({player_id}): {}",
+                    health.tunnel_id, health.error
+                );
+            }
+            if receiver == 0 {
+                continue;
+            }
+            if let Some(player) = GLOBAL_MANAGER.player_manager.get_player(receiver).await {
+                let player = player.read().await;
+                if player.is_online() {
+                    let _ = player
+                        .send_push(&MessageType::ServerClientTunnelHealthNtf(
+                            server_client::TunnelHealthNtf {
+                                player_id,
+                                health: Some(health),
+                            },
+                        ))
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+/// 只有连接固定后端的TCP类通道需要探测
+fn is_probeable(tunnel: &tunnel::Model) -> bool {
+    !tunnel.endpoint.is_empty()
+        && matches!(
+            InletProxyType::from_u32(tunnel.tunnel_type),
+            Some(
+                InletProxyType::TCP
+                    | InletProxyType::HTTPS
+                    | InletProxyType::HTTP
+                    | InletProxyType::LABEL
+            )
+        )
+}
+
+/// 将探测结果转为协议消息
+fn to_endpoint_health(tunnel_id: u32, result: anyhow::Result<Duration>) -> EndpointHealth {
+    match result {
+        Ok(latency) => EndpointHealth {
+            tunnel_id,
+            reachable: true,
+            latency: latency.as_millis() as u32,
+            error: String::new(),
+        },
+        Err(err) => EndpointHealth {
+            tunnel_id,
+            reachable: false,
+            latency: 0,
+            error: err.to_string(),
+        },
+    }
+}
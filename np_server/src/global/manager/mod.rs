@@ -1,16 +1,25 @@
+use self::health::HealthManager;
 use self::player::PlayerManager;
 use self::proxy::ProxyManager;
+use self::reconnect::ReconnectManager;
+use self::stream::StreamManager;
 use self::tunnel::TunnelManager;
 use once_cell::sync::Lazy;
 
+pub mod health;
 pub mod player;
 pub mod proxy;
+pub mod reconnect;
+pub mod stream;
 pub mod tunnel;
 
 pub struct GlobalManager {
     pub player_manager: PlayerManager,
     pub tunnel_manager: TunnelManager,
     pub proxy_manager: ProxyManager,
+    pub health_manager: HealthManager,
+    pub stream_manager: StreamManager,
+    pub reconnect_manager: ReconnectManager,
 }
 
 impl GlobalManager {
@@ -19,8 +28,11 @@ impl GlobalManager {
             player_manager: PlayerManager::new(),
             tunnel_manager: TunnelManager::new(),
             proxy_manager: ProxyManager::new(),
+            health_manager: HealthManager::new(),
+            stream_manager: StreamManager::new(),
+            reconnect_manager: ReconnectManager::new(),
         }
     }
 }
 
-pub static GLOBAL_MANAGER: Lazy<GlobalManager> = Lazy::new(|| GlobalManager::new());
+pub static GLOBAL_MANAGER: Lazy<GlobalManager> = Lazy::new(GlobalManager::new);
@@ -1,16 +1,20 @@
-use crate::global::GLOBAL_DB_POOL;
+use crate::global::db;
 use crate::orm_entity::prelude::User;
-use crate::orm_entity::user;
+use crate::orm_entity::{tunnel, user};
 use crate::player::{Player, PlayerId};
 use crate::utils::str::{is_valid_password, is_valid_username};
+use async_trait::async_trait;
 use chrono::Utc;
+use np_proto::message_map::MessageType;
+use np_proto::server_client;
 use rand::prelude::StdRng;
 use rand::{Rng, SeedableRng};
 use sea_orm::ActiveValue::Set;
-use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::global::manager::tunnel::join_player_ids;
 use crate::global::manager::GLOBAL_MANAGER;
 use tokio::sync::RwLock;
 
@@ -20,21 +24,62 @@ pub struct PlayerDbData {
     pub password: String,
 }
 
+/// 玩家管理依赖的数据库和通道管理器, 测试时可以替换为模拟实现
+#[async_trait]
+pub trait PlayerContext: Send + Sync {
+    fn db(&self) -> &DatabaseConnection;
+
+    /// 当前所有通道
+    async fn tunnels(&self) -> Vec<tunnel::Model>;
+
+    async fn delete_tunnel(&self, tunnel_id: u32) -> anyhow::Result<()>;
+
+    async fn update_tunnel(&self, tunnel: tunnel::Model) -> anyhow::Result<()>;
+}
+
+/// 使用全局数据库连接和全局管理器
+struct GlobalPlayerContext;
+
+#[async_trait]
+impl PlayerContext for GlobalPlayerContext {
+    fn db(&self) -> &DatabaseConnection {
+        db()
+    }
+
+    async fn tunnels(&self) -> Vec<tunnel::Model> {
+        GLOBAL_MANAGER.tunnel_manager.tunnels.read().await.clone()
+    }
+
+    async fn delete_tunnel(&self, tunnel_id: u32) -> anyhow::Result<()> {
+        GLOBAL_MANAGER.tunnel_manager.delete_tunnel(tunnel_id).await
+    }
+
+    async fn update_tunnel(&self, tunnel: tunnel::Model) -> anyhow::Result<()> {
+        GLOBAL_MANAGER.tunnel_manager.update_tunnel(tunnel).await
+    }
+}
+
 pub struct PlayerManager {
     players: RwLock<Vec<Arc<RwLock<Player>>>>,
     player_map: RwLock<HashMap<PlayerId, Arc<RwLock<Player>>>>,
+    context: Box<dyn PlayerContext>,
 }
 
 impl PlayerManager {
     pub(crate) fn new() -> PlayerManager {
+        Self::with_context(GlobalPlayerContext)
+    }
+
+    pub fn with_context(context: impl PlayerContext + 'static) -> PlayerManager {
         PlayerManager {
             players: RwLock::new(Vec::new()),
             player_map: RwLock::new(HashMap::new()),
+            context: Box::new(context),
         }
     }
 
     pub async fn load_all_player(&self) -> anyhow::Result<()> {
-        let users = User::find().all(GLOBAL_DB_POOL.get().unwrap()).await?;
+        let users = User::find().all(self.context.db()).await?;
 
         for user in users {
             self.create_player(user.id).await;
@@ -47,14 +92,22 @@ impl PlayerManager {
         self.player_map.read().await.get(&player_id).is_some()
     }
 
-    pub async fn get_player(&self, player_id: PlayerId) -> Option<Arc<RwLock<Player>>> {
-        if let Some(player) = self.player_map.read().await.get(&player_id) {
-            Some(player.clone())
-        } else {
-            None
+    /// 通知所有在线玩家服务器即将关闭
+    pub async fn notify_shutdown(&self, grace_period: u32) {
+        let message =
+            MessageType::ServerClientShutdownNtf(server_client::ShutdownNtf { grace_period });
+        for player in self.players.read().await.iter() {
+            let player = player.read().await;
+            if player.is_online() {
+                let _ = player.send_push(&message).await;
+            }
         }
     }
 
+    pub async fn get_player(&self, player_id: PlayerId) -> Option<Arc<RwLock<Player>>> {
+        self.player_map.read().await.get(&player_id).cloned()
+    }
+
     pub async fn create_player(&self, player_id: PlayerId) -> Arc<RwLock<Player>> {
         let player = Player::new(player_id);
         self.players.write().await.push(player.clone());
@@ -67,10 +120,9 @@ impl PlayerManager {
 
     /// 删除玩家
     pub async fn delete_player(&self, player_id: u32) -> anyhow::Result<()> {
-        let player_tunnels: Vec<_> = GLOBAL_MANAGER
-            .tunnel_manager
-            .tunnels
-            .read()
+        let player_tunnels: Vec<_> = self
+            .context
+            .tunnels()
             .await
             .iter()
             .filter_map(|x| {
@@ -83,13 +135,28 @@ impl PlayerManager {
             .collect();
 
         for tunnel_id in player_tunnels {
-            GLOBAL_MANAGER
-                .tunnel_manager
-                .delete_tunnel(tunnel_id)
-                .await?;
+            self.context.delete_tunnel(tunnel_id).await?;
+        }
+
+        // 只作为额外出口的通道保留, 从出口列表中移除该玩家
+        let shared_tunnels: Vec<_> = self
+            .context
+            .tunnels()
+            .await
+            .into_iter()
+            .filter(|x| x.extra_senders().contains(&player_id))
+            .collect();
+        for mut tunnel in shared_tunnels {
+            let extra_senders: Vec<_> = tunnel
+                .extra_senders()
+                .into_iter()
+                .filter(|x| *x != player_id)
+                .collect();
+            tunnel.extra_senders = join_player_ids(&extra_senders);
+            self.context.update_tunnel(tunnel).await?;
         }
 
-        let db = GLOBAL_DB_POOL.get().unwrap();
+        let db = self.context.db();
         let rows_affected = User::delete_by_id(player_id).exec(db).await?.rows_affected;
         anyhow::ensure!(
             rows_affected == 1,
@@ -127,7 +194,7 @@ impl PlayerManager {
         // 执行查询以检查用户名是否存在
         if User::find()
             .filter(user::Column::Username.eq(username))
-            .one(GLOBAL_DB_POOL.get().unwrap())
+            .one(self.context.db())
             .await?
             .is_some()
         {
@@ -156,7 +223,7 @@ impl PlayerManager {
                 create_time: Set(Utc::now().naive_utc()),
             };
 
-            let _ = new_user.insert(GLOBAL_DB_POOL.get().unwrap()).await?;
+            let _ = new_user.insert(self.context.db()).await?;
             self.create_player(id).await;
             return Ok((0, "".into()));
         }
@@ -164,16 +231,95 @@ impl PlayerManager {
 
     /// 更新玩家数据
     pub async fn update_player(&self, data: PlayerDbData) -> anyhow::Result<()> {
-        let user = User::find_by_id(data.id)
-            .one(GLOBAL_DB_POOL.get().unwrap())
-            .await?;
+        let user = User::find_by_id(data.id).one(self.context.db()).await?;
         anyhow::ensure!(user.is_some(), "can't find user: {}", data.id);
 
         let mut user: user::ActiveModel = user.unwrap().into();
         user.password = Set(data.username.to_owned());
         user.password = Set(data.password.to_owned());
 
-        let _ = user.update(GLOBAL_DB_POOL.get().unwrap()).await?;
+        let _ = user.update(self.context.db()).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global::init_test_db;
+    use np_proto::class_def;
+    use std::sync::Mutex;
+
+    /// 通道列表保存在内存中, 记录删除和修改的通道
+    struct MockContext {
+        db: &'static DatabaseConnection,
+        tunnels: Arc<Mutex<Vec<tunnel::Model>>>,
+        deleted: Arc<Mutex<Vec<u32>>>,
+    }
+
+    #[async_trait]
+    impl PlayerContext for MockContext {
+        fn db(&self) -> &DatabaseConnection {
+            self.db
+        }
+
+        async fn tunnels(&self) -> Vec<tunnel::Model> {
+            self.tunnels.lock().unwrap().clone()
+        }
+
+        async fn delete_tunnel(&self, tunnel_id: u32) -> anyhow::Result<()> {
+            self.tunnels.lock().unwrap().retain(|x| x.id != tunnel_id);
+            self.deleted.lock().unwrap().push(tunnel_id);
+            Ok(())
+        }
+
+        async fn update_tunnel(&self, tunnel: tunnel::Model) -> anyhow::Result<()> {
+            let mut tunnels = self.tunnels.lock().unwrap();
+            if let Some(x) = tunnels.iter_mut().find(|x| x.id == tunnel.id) {
+                *x = tunnel;
+            }
+            Ok(())
+        }
+    }
+
+    fn new_test_tunnel(id: u32, sender: PlayerId, extra_senders: &str) -> tunnel::Model {
+        let mut tunnel = tunnel::Model::from(&class_def::Tunnel::default());
+        tunnel.id = id;
+        tunnel.sender = sender;
+        tunnel.extra_senders = extra_senders.into();
+        tunnel
+    }
+
+    #[tokio::test]
+    async fn test_delete_player_tunnels() {
+        let tunnels = Arc::new(Mutex::new(Vec::new()));
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let manager = PlayerManager::with_context(MockContext {
+            db: init_test_db().await,
+            tunnels: tunnels.clone(),
+            deleted: deleted.clone(),
+        });
+        let username = "delete_player_test".to_string();
+        let (code, _) = manager.add_player(&username, &"pw".into()).await.unwrap();
+        assert_eq!(code, 0);
+        let player_id = User::find()
+            .filter(user::Column::Username.eq(&username))
+            .one(manager.context.db())
+            .await
+            .unwrap()
+            .unwrap()
+            .id;
+        *tunnels.lock().unwrap() = vec![
+            new_test_tunnel(1, player_id, ""),
+            new_test_tunnel(2, 0, &format!("{player_id},7")),
+            new_test_tunnel(3, 0, "7"),
+        ];
+
+        // 作为出口的通道被删除, 作为额外出口的通道只移除该玩家
+        manager.delete_player(player_id).await.unwrap();
+        assert_eq!(*deleted.lock().unwrap(), vec![1]);
+        let tunnels = tunnels.lock().unwrap().clone();
+        assert_eq!(tunnels.len(), 2);
+        assert_eq!(tunnels[0].extra_senders, "7");
+    }
+}
@@ -1,18 +1,187 @@
+use crate::global::config::GLOBAL_CONFIG;
+use crate::global::event::Event;
+use crate::global::manager::stream::StreamInfo;
 use crate::global::manager::GLOBAL_MANAGER;
+use crate::global::{
+    syslog, webhook, GLOBAL_GEO_DATABASE, GLOBAL_OFFLINE_MODE, GLOBAL_OUTLET_ALLOWLIST,
+};
+use crate::orm_entity::tunnel;
 use crate::player::PlayerId;
-use log::{debug, error};
+use crate::utils::str::is_auto_port_address;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use np_base::proxy::auth::{Authenticator, DenyAuthenticator, HttpAuthenticator};
+use np_base::proxy::breaker::{BreakerState, CircuitBreakerConfig};
+use np_base::proxy::crypto::KeyDerivation;
+use np_base::proxy::dictionary::CompressionDictionary;
+use np_base::proxy::framing::LengthPrefix;
 use np_base::proxy::inlet::{Inlet, InletDataEx, InletProxyType};
-use np_base::proxy::outlet::Outlet;
-use np_base::proxy::{OutputFuncType, ProxyMessage};
+use np_base::proxy::outlet::{Outlet, OutletDataEx, TcpKeepaliveConfig};
+use np_base::proxy::recorder::MessageRecorder;
+use np_base::proxy::reject::RejectResponse;
+use np_base::proxy::stats::{
+    BackpressureStats, ConnectionPoolStats, RttStats, SessionCloseStats, SessionSummary,
+    TrafficStats,
+};
+use np_base::proxy::transport::MessageTransport;
+use np_base::proxy::ProxyMessage;
 use np_proto::message_map::MessageType;
+use np_proto::server_client;
 use np_proto::utils::message_bridge;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Mutex as AsyncMutex, RwLock};
+use tokio::task::JoinSet;
 
+/// 出口收到通知后建立反向连接的超时时间
+const REVERSE_STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 多出口通道中新会话选择出口的策略
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BalancePolicy {
+    /// 在在线的出口玩家之间轮流分配
+    RoundRobin = 0,
+    /// 按客户端IP哈希, 同一客户端总是分配到同一出口
+    SourceHash = 1,
+}
+
+impl BalancePolicy {
+    pub(crate) fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Self::RoundRobin),
+            1 => Some(Self::SourceHash),
+            _ => None,
+        }
+    }
+}
+
+/// 最高随机权重(HRW)哈希, 出口集合变化时只有原本分配到变化出口的客户端会重新分配
+///
+/// 按权重加权: 得分为 `权重 / -ln(u)`, u为哈希映射到(0, 1)的值, 权重都为1时与直接比较哈希值的结果相同
+fn rendezvous_pick(key: &str, players: &[(PlayerId, u32)]) -> Option<PlayerId> {
+    players
+        .iter()
+        .map(|(player_id, weight)| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            player_id.hash(&mut hasher);
+            let u = (hasher.finish() as f64 + 0.5) / (u64::MAX as f64 + 1.0);
+            (*player_id, *weight as f64 / -u.ln())
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|x| x.0)
+}
+
+/// 交错加权轮询, 第n个会话选择的出口
+///
+/// 每个周期共 `权重之和` 个会话, 分为若干轮, 第r轮依次分给权重大于r的出口,
+/// 各出口恰好分到自己权重的次数且交错分配; 权重相同时与普通轮询一致
+fn weighted_round_robin_pick(n: usize, players: &[(PlayerId, u32)]) -> Option<PlayerId> {
+    let total: u64 = players.iter().map(|x| x.1 as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut slot = n as u64 % total;
+    for round in 0.. {
+        let mut candidates = players.iter().filter(|x| x.1 > round);
+        let count = candidates.clone().count() as u64;
+        if slot < count {
+            return candidates.nth(slot as usize).map(|x| x.0);
+        }
+        slot -= count;
+    }
+    None
+}
+
+/// 出口发往入口的消息: 入口在服务器上时直接交给入口, 否则通过玩家的TCP连接转发
+struct OutletTransport {
+    tunnel_id: u32,
+    // 入口所在的玩家, 0为服务器
+    player_id: PlayerId,
+    inlets: Arc<RwLock<HashMap<u32, Inlet>>>,
+}
+
+#[async_trait]
+impl MessageTransport for OutletTransport {
+    async fn send(&self, message: ProxyMessage) {
+        if self.player_id == 0 {
+            GLOBAL_MANAGER
+                .proxy_manager
+                .record_traffic(self.tunnel_id, &message);
+            if let Some(inlet) = self.inlets.read().await.get(&self.tunnel_id) {
+                inlet.input(message).await;
+            } else {
+                debug!("unknown inlet({})", self.tunnel_id);
+            }
+        } else {
+            ProxyManager::send_proxy_message(0, self.player_id, self.tunnel_id, message).await;
+        }
+    }
+}
+
+/// 入口发往出口的消息: 出口只在服务器上时直接交给出口, 否则选择出口玩家后通过TCP连接转发
+struct InletTransport {
+    tunnel_id: u32,
+    // 出口玩家及权重
+    outlet_players: Vec<(PlayerId, u32)>,
+    balance_policy: BalancePolicy,
+    outlets: Arc<RwLock<HashMap<u32, Arc<Outlet>>>>,
+}
+
+#[async_trait]
+impl MessageTransport for InletTransport {
+    async fn send(&self, message: ProxyMessage) {
+        if self.outlet_players.len() == 1 && self.outlet_players[0].0 == 0 {
+            GLOBAL_MANAGER
+                .proxy_manager
+                .record_traffic(self.tunnel_id, &message);
+            if let Some(outlet) = self.outlets.read().await.get(&self.tunnel_id) {
+                outlet.input(message).await;
+            } else {
+                debug!("unknown outlet({})", self.tunnel_id);
+            }
+        } else {
+            let player_id = GLOBAL_MANAGER
+                .proxy_manager
+                .route_to_outlet(
+                    self.tunnel_id,
+                    &self.outlet_players,
+                    self.balance_policy,
+                    &message,
+                )
+                .await;
+            ProxyManager::send_proxy_message(0, player_id, self.tunnel_id, message).await;
+        }
+    }
+}
+
+/// 本机入口和出口的运行状态, 以及玩家之间代理消息的转发
+///
+/// 与 [`TunnelManager`](super::tunnel::TunnelManager) 和 [`PlayerManager`](super::player::PlayerManager) 不同,
+/// 这里直接使用全局配置和全局管理器: 入口和出口的回调在各自的任务中调用, 只能通过全局状态找到转发目标,
+/// 其他管理器的全局上下文实现也都经由这里访问本机代理
 pub struct ProxyManager {
     outlets: Arc<RwLock<HashMap<u32, Arc<Outlet>>>>,
     inlets: Arc<RwLock<HashMap<u32, Inlet>>>,
+    // 已停止所有代理, 不再同步通道
+    stopped: AtomicBool,
+    // 正在排空, 所有入口不再接受新连接
+    draining: AtomicBool,
+    // 尚未写入数据库的通道流量
+    traffic: Mutex<HashMap<u32, u64>>,
+    // 多出口通道中每个会话所在的出口玩家, 键为(通道id, 会话id)
+    session_routes: Mutex<HashMap<(u32, u32), PlayerId>>,
+    next_route: AtomicUsize,
+    // 启动失败的通道及错误信息, 下次同步时重试
+    errors: Mutex<HashMap<u32, String>>,
+    // 同步通道和重启单个入口不能同时进行
+    sync_lock: AsyncMutex<()>,
 }
 
 impl ProxyManager {
@@ -20,10 +189,164 @@ impl ProxyManager {
         Self {
             outlets: Arc::new(RwLock::new(HashMap::new())),
             inlets: Arc::new(RwLock::new(HashMap::new())),
+            stopped: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            traffic: Mutex::new(HashMap::new()),
+            session_routes: Mutex::new(HashMap::new()),
+            next_route: AtomicUsize::new(0),
+            errors: Mutex::new(HashMap::new()),
+            sync_lock: AsyncMutex::new(()),
+        }
+    }
+
+    /// 开始或取消排空
+    ///
+    /// 排空时所有入口不再接受新连接, 已有会话不受影响, 会话数量降为0后可以安全地停止本实例
+    pub(crate) async fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Release);
+        // 与sync_tunnels的加锁顺序一致
+        let tunnels = GLOBAL_MANAGER.tunnel_manager.tunnels.read().await;
+        for (id, inlet) in self.inlets.read().await.iter() {
+            let paused = tunnels
+                .iter()
+                .find(|tunnel| tunnel.id == *id)
+                .is_some_and(|tunnel| tunnel.is_paused());
+            inlet.set_paused(draining || paused);
+        }
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// 通道启动失败的错误信息, 正常运行时返回None
+    pub(crate) fn tunnel_error(&self, tunnel_id: u32) -> Option<String> {
+        self.errors.lock().unwrap().get(&tunnel_id).cloned()
+    }
+
+    /// 为入口发往出口的消息选择出口所在的玩家
+    ///
+    /// 新会话按通道的策略在在线的出口玩家中选择, 之后的消息发往同一个玩家;
+    /// 没有在线的出口玩家时发往sender, 由离线处理立即拒绝连接
+    pub(crate) async fn route_to_outlet(
+        &self,
+        tunnel_id: u32,
+        outlet_players: &[(PlayerId, u32)],
+        policy: BalancePolicy,
+        message: &ProxyMessage,
+    ) -> PlayerId {
+        let Some(&(sender, _)) = outlet_players.first() else {
+            return 0;
+        };
+        if outlet_players.len() == 1 {
+            return sender;
+        }
+
+        let session_id = match message {
+            ProxyMessage::I2oConnect {
+                session_id,
+                client_addr,
+                ..
+            } => {
+                let mut online = Vec::new();
+                for (player_id, weight) in outlet_players {
+                    if is_player_online(*player_id).await {
+                        online.push((*player_id, *weight));
+                    }
+                }
+                let player_id = if online.is_empty() {
+                    sender
+                } else if policy == BalancePolicy::SourceHash {
+                    // 只按IP哈希, 同一客户端的不同连接端口不影响选择
+                    let client_ip = client_addr
+                        .parse::<SocketAddr>()
+                        .map(|x| x.ip().to_string())
+                        .unwrap_or_else(|_| client_addr.clone());
+                    rendezvous_pick(&client_ip, &online).unwrap_or(sender)
+                } else {
+                    let n = self.next_route.fetch_add(1, Ordering::Relaxed);
+                    weighted_round_robin_pick(n, &online).unwrap_or(sender)
+                };
+                self.session_routes
+                    .lock()
+                    .unwrap()
+                    .insert((tunnel_id, *session_id), player_id);
+                return player_id;
+            }
+            ProxyMessage::I2oDisconnect(session_id) => {
+                return self
+                    .session_routes
+                    .lock()
+                    .unwrap()
+                    .remove(&(tunnel_id, *session_id))
+                    .unwrap_or(sender);
+            }
+            ProxyMessage::I2oSendData(session_id, ..)
+            | ProxyMessage::I2oSendToData(session_id, ..)
+            | ProxyMessage::I2oRecvDataResult(session_id, ..) => *session_id,
+            _ => return sender,
+        };
+        self.session_routes
+            .lock()
+            .unwrap()
+            .get(&(tunnel_id, session_id))
+            .copied()
+            .unwrap_or(sender)
+    }
+
+    /// 统计经过服务器的通道数据量, 两端都在同一个客户端上的通道不经过服务器, 无法统计
+    pub(crate) fn record_traffic(&self, tunnel_id: u32, message: &ProxyMessage) {
+        let len = match message {
+            ProxyMessage::I2oSendData(_, data)
+            | ProxyMessage::I2oSendToData(_, data, _)
+            | ProxyMessage::O2iRecvData(_, _, data)
+            | ProxyMessage::O2iRecvDataFrom(_, _, data, _) => data.len() as u64,
+            _ => return,
+        };
+        self.record_bytes(tunnel_id, len);
+    }
+
+    /// 统计不经过消息转发的通道数据量, 例如splice转发的数据
+    pub(crate) fn record_bytes(&self, tunnel_id: u32, len: u64) {
+        *self.traffic.lock().unwrap().entry(tunnel_id).or_default() += len;
+    }
+
+    /// 取出尚未写入数据库的通道流量
+    pub(crate) fn take_traffic(&self) -> HashMap<u32, u64> {
+        std::mem::take(&mut *self.traffic.lock().unwrap())
+    }
+
+    /// 停止所有代理: 入口不再接受新连接并等待已有会话结束(最多等待timeout), 然后停止出口
+    pub async fn stop_all(&self, timeout: Duration) {
+        self.stopped.store(true, Ordering::Release);
+
+        let inlets: Vec<Inlet> = self.inlets.write().await.drain().map(|x| x.1).collect();
+        let mut tasks = JoinSet::new();
+        for mut inlet in inlets {
+            tasks.spawn(async move {
+                inlet.stop_graceful(timeout).await;
+                debug!("inlet({}) stopped", inlet.redacted_description());
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        let outlets: Vec<Arc<Outlet>> = self.outlets.write().await.drain().map(|x| x.1).collect();
+        for outlet in outlets {
+            outlet.stop().await;
+            debug!("outlet({}) stopped", outlet.description());
         }
     }
-    pub async fn sync_tunnels(&self) {
+    /// 按通道配置同步本机的入口和出口
+    ///
+    /// 入口启动失败不会中断同步, 失败的通道标记为错误状态并通知相关玩家, 最后返回汇总的错误
+    pub async fn sync_tunnels(&self) -> anyhow::Result<()> {
+        if GLOBAL_OFFLINE_MODE.load(Ordering::Acquire) || self.stopped.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let _sync_guard = self.sync_lock.lock().await;
         let tunnels = GLOBAL_MANAGER.tunnel_manager.tunnels.read().await;
+        let mut errors = HashMap::new();
+        let mut assigned_ports = Vec::new();
 
         // 收集无效的出口
         let mut keys_to_remove: Vec<_> = self
@@ -34,13 +357,13 @@ impl ProxyManager {
             .filter(|(id, outlet)| {
                 let retain = tunnels.iter().any(|tunnel| {
                     **id == tunnel.id
-                        && tunnel.enabled == 1
-                        && tunnel.sender == 0
+                        && tunnel.is_active()
+                        && tunnel.outlet_players().contains(&0)
                         && &tunnel.outlet_description() == outlet.description()
                 });
                 !retain
             })
-            .map(|(key, _)| key.clone())
+            .map(|(key, _)| *key)
             .collect();
 
         // 删除无效的出口
@@ -62,19 +385,19 @@ impl ProxyManager {
             .filter(|(id, inlet)| {
                 let retain = tunnels.iter().any(|tunnel| {
                     **id == tunnel.id
-                        && tunnel.enabled == 1
+                        && tunnel.is_active()
                         && tunnel.receiver == 0
                         && &tunnel.inlet_description() == inlet.description()
                 });
-                return !retain;
+                !retain
             })
-            .map(|(key, _)| key.clone())
+            .map(|(key, _)| *key)
             .collect();
 
         // 删除无效入口
         for key in keys_to_remove {
             if let Some(mut inlet) = self.inlets.write().await.remove(&key) {
-                let description = inlet.description().to_owned();
+                let description = inlet.redacted_description().to_owned();
                 debug!("start deleting the inlet({description})");
                 inlet.stop().await;
                 debug!("delete inlet({description}) end");
@@ -84,33 +407,39 @@ impl ProxyManager {
         // 添加代理出口
         for tunnel in tunnels
             .iter()
-            .filter(|tunnel| tunnel.enabled == 1 && tunnel.sender == 0)
+            .filter(|tunnel| tunnel.is_active() && tunnel.outlet_players().contains(&0))
         {
             if !self.outlets.read().await.contains_key(&tunnel.id) {
-                let this_machine = tunnel.receiver == tunnel.sender;
-                let inlets = self.inlets.clone();
                 let tunnel_id = tunnel.id;
-                let player_id = tunnel.receiver;
-
-                let outlet_output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
-                    let inlets = inlets.clone();
-                    Box::pin(async move {
-                        if this_machine {
-                            if let Some(inlet) = inlets.read().await.get(&tunnel_id) {
-                                inlet.input(message).await;
-                            } else {
-                                debug!("unknown inlet({tunnel_id})");
-                            }
-                        } else {
-                            Self::send_proxy_message(0, player_id as PlayerId, tunnel_id, message)
-                                .await;
-                        }
-                    })
+                let outlet_output = Arc::new(OutletTransport {
+                    tunnel_id,
+                    player_id: tunnel.receiver as PlayerId,
+                    inlets: self.inlets.clone(),
                 });
                 debug!("start outlet({})", tunnel.outlet_description());
                 self.outlets.write().await.insert(
                     tunnel_id,
-                    Outlet::new(outlet_output, tunnel.outlet_description()),
+                    Outlet::new(
+                        outlet_output,
+                        tunnel.outlet_description(),
+                        outlet_data_ex()
+                            .set_compression_dictionary(compression_dictionary(tunnel))
+                            .set_connection_pool(
+                                tunnel.pool_size as usize,
+                                Duration::from_secs(tunnel.pool_max_idle as u64),
+                            )
+                            .set_length_prefix(
+                                LengthPrefix::from_u32(tunnel.frame_prefix).unwrap_or_default(),
+                            )
+                            .set_buffer_limit(tunnel.outlet_buffer_limit as usize)
+                            .set_key_derivation(
+                                KeyDerivation::from_u32(tunnel.key_derivation).unwrap_or_default(),
+                                &tunnel.password,
+                            )
+                            .set_nodelay(tunnel.outlet_nodelay == 1)
+                            .set_fast_open(tunnel.fast_open == 1)
+                            .set_label_routes(tunnel.label_routes()),
+                    ),
                 );
             }
         }
@@ -118,56 +447,402 @@ impl ProxyManager {
         // 添加代理入口
         for tunnel in tunnels
             .iter()
-            .filter(|tunnel| tunnel.enabled == 1 && tunnel.receiver == 0)
+            .filter(|tunnel| tunnel.is_active() && tunnel.receiver == 0)
         {
             if !self.inlets.read().await.contains_key(&tunnel.id) {
-                let tunnel_id = tunnel.id;
-                let this_machine = tunnel.receiver == tunnel.sender;
-                let outlets = self.outlets.clone();
-                let player_id = tunnel.sender;
-
-                let inlet_output: OutputFuncType = Arc::new(move |message: ProxyMessage| {
-                    let outlets = outlets.clone();
-                    Box::pin(async move {
-                        if this_machine {
-                            if let Some(outlet) = outlets.read().await.get(&tunnel_id) {
-                                outlet.input(message).await;
-                            } else {
-                                debug!("unknown outlet({tunnel_id})");
-                            }
-                        } else {
-                            Self::send_proxy_message(0, player_id as PlayerId, tunnel_id, message)
-                                .await;
+                match self.start_inlet(tunnel).await {
+                    Ok(inlet) => {
+                        debug!("start inlet({})", inlet.redacted_description());
+                        if let Some(port) = Self::assigned_port(tunnel, &inlet) {
+                            assigned_ports.push((tunnel.id, port));
                         }
-                    })
-                });
-
-                if let Some(inlet_proxy_type) = InletProxyType::from_u32(tunnel.tunnel_type) {
-                    let mut inlet = Inlet::new(inlet_output, tunnel.inlet_description());
-                    if let Err(err) = inlet
-                        .start(
-                            inlet_proxy_type,
-                            tunnel.source.clone(),
-                            tunnel.endpoint.clone(),
-                            tunnel.is_compressed == 1,
-                            tunnel.encryption_method.clone(),
-                            InletDataEx::new(tunnel.username.clone(), tunnel.password.clone()),
-                        )
-                        .await
-                    {
-                        error!("inlet({}) start error: {}", tunnel.source, err);
-                    } else {
-                        debug!("start inlet({})", inlet.description());
                         self.inlets.write().await.insert(tunnel.id, inlet);
                     }
-                } else {
-                    error!(
-                        "inlet({}) unknown tunnel type: {}",
-                        tunnel.source, tunnel.tunnel_type
-                    );
+                    Err(err) => {
+                        error!("inlet({}) start error: {}", tunnel.source, err);
+                        errors.insert(tunnel.id, err.to_string());
+                    }
+                }
+            }
+        }
+
+        // 只有可以运行中应用的设置变化时, 不重启入口
+        for (id, inlet) in self.inlets.write().await.iter_mut() {
+            if let Some(tunnel) = tunnels.iter().find(|tunnel| tunnel.id == *id) {
+                let settings_description = tunnel.inlet_settings_description();
+                if &settings_description != inlet.settings_description() {
+                    debug!("reconfigure inlet({})", inlet.redacted_description());
+                    inlet.reconfigure(settings_description, Self::inlet_data_ex(tunnel));
                 }
             }
         }
+
+        // 同步入口暂停状态
+        for (id, inlet) in self.inlets.read().await.iter() {
+            if let Some(tunnel) = tunnels.iter().find(|tunnel| tunnel.id == *id) {
+                inlet.set_paused(tunnel.is_paused() || self.is_draining());
+                inlet.set_over_quota(tunnel.is_over_quota());
+            }
+        }
+
+        // 错误状态变化的通道需要通知玩家
+        let changed: Vec<tunnel::Model> = {
+            let mut current = self.errors.lock().unwrap();
+            let changed = tunnels
+                .iter()
+                .filter(|tunnel| current.get(&tunnel.id) != errors.get(&tunnel.id))
+                .cloned()
+                .collect();
+            *current = errors.clone();
+            changed
+        };
+        drop(tunnels);
+        for tunnel in changed.iter() {
+            GLOBAL_MANAGER
+                .tunnel_manager
+                .broadcast_tunnel(tunnel, false)
+                .await;
+        }
+        for (tunnel_id, port) in assigned_ports {
+            if let Err(err) = GLOBAL_MANAGER
+                .tunnel_manager
+                .set_assigned_port(tunnel_id, port)
+                .await
+            {
+                error!("tunnel({tunnel_id}) failed to save assigned port {port}: {err}");
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let mut errors: Vec<_> = errors.into_iter().collect();
+        errors.sort();
+        Err(anyhow!(
+            "failed to start tunnel(s): {}",
+            errors
+                .iter()
+                .map(|(id, err)| format!("tunnel({id}): {err}"))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ))
+    }
+
+    /// 按通道配置创建并启动入口
+    async fn start_inlet(&self, tunnel: &tunnel::Model) -> anyhow::Result<Inlet> {
+        let inlet_proxy_type = InletProxyType::from_u32(tunnel.tunnel_type)
+            .ok_or_else(|| anyhow!("unknown tunnel type: {}", tunnel.tunnel_type))?;
+        let inlet_output = Arc::new(InletTransport {
+            tunnel_id: tunnel.id,
+            outlet_players: tunnel.weighted_outlet_players(),
+            balance_policy: tunnel.balance_policy(),
+            outlets: self.outlets.clone(),
+        });
+        let mut inlet = Inlet::new(inlet_output, tunnel.inlet_description())
+            .set_redacted_description(tunnel.redacted_description())
+            .set_settings_description(tunnel.inlet_settings_description());
+        inlet.set_paused(tunnel.is_paused() || self.is_draining());
+        inlet.set_over_quota(tunnel.is_over_quota());
+        inlet
+            .start(
+                inlet_proxy_type,
+                tunnel.source.clone(),
+                tunnel.endpoint.clone(),
+                tunnel.is_compressed == 1,
+                tunnel.encryption_method.clone(),
+                Self::inlet_data_ex(tunnel).set_recorder(message_recorder(tunnel)),
+            )
+            .await?;
+        Ok(inlet)
+    }
+
+    /// 由系统分配端口的入口启动后实际监听的端口
+    fn assigned_port(tunnel: &tunnel::Model, inlet: &Inlet) -> Option<u16> {
+        if !is_auto_port_address(&tunnel.source) {
+            return None;
+        }
+        inlet.local_addr().map(|addr| addr.port())
+    }
+
+    /// 重启通道在本机的入口(先停止再按当前配置启动), 入口没有运行时直接启动
+    ///
+    /// 只影响这一个通道, 入口上已有的会话会被断开. 启动失败时通道标记为错误状态, 与同步时一样
+    pub async fn restart_tunnel(&self, tunnel_id: u32) -> anyhow::Result<()> {
+        if GLOBAL_OFFLINE_MODE.load(Ordering::Acquire) || self.stopped.load(Ordering::Acquire) {
+            return Err(anyhow!("proxies are stopped"));
+        }
+        let _sync_guard = self.sync_lock.lock().await;
+        let tunnel = GLOBAL_MANAGER
+            .tunnel_manager
+            .tunnels
+            .read()
+            .await
+            .iter()
+            .find(|tunnel| tunnel.id == tunnel_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("tunnel({tunnel_id}) not found"))?;
+        if tunnel.receiver != 0 {
+            return Err(anyhow!("inlet({tunnel_id}) is not running on the server"));
+        }
+        if !tunnel.is_active() {
+            return Err(anyhow!("tunnel({tunnel_id}) is disabled"));
+        }
+
+        let old = self.inlets.write().await.remove(&tunnel_id);
+        if let Some(mut inlet) = old {
+            debug!("stop inlet({}) for restart", inlet.redacted_description());
+            inlet.stop().await;
+        }
+        let mut assigned_port = None;
+        let result = match self.start_inlet(&tunnel).await {
+            Ok(inlet) => {
+                info!("tunnel({tunnel_id}) inlet restarted");
+                assigned_port = Self::assigned_port(&tunnel, &inlet);
+                self.inlets.write().await.insert(tunnel_id, inlet);
+                Ok(())
+            }
+            Err(err) => {
+                error!("inlet({}) restart error: {}", tunnel.source, err);
+                Err(err)
+            }
+        };
+
+        // 错误状态变化时通知玩家
+        let changed = {
+            let mut errors = self.errors.lock().unwrap();
+            let error = result.as_ref().err().map(|err| err.to_string());
+            let changed = errors.get(&tunnel_id) != error.as_ref();
+            match error {
+                Some(error) => errors.insert(tunnel_id, error),
+                None => errors.remove(&tunnel_id),
+            };
+            changed
+        };
+        if changed {
+            GLOBAL_MANAGER
+                .tunnel_manager
+                .broadcast_tunnel(&tunnel, false)
+                .await;
+        }
+        if let Some(port) = assigned_port {
+            GLOBAL_MANAGER
+                .tunnel_manager
+                .set_assigned_port(tunnel_id, port)
+                .await?;
+        }
+        result
+    }
+
+    /// 按通道配置创建入口设置
+    fn inlet_data_ex(tunnel: &tunnel::Model) -> InletDataEx {
+        let tunnel_id = tunnel.id;
+        let player_id = tunnel.sender;
+        let data_ex = InletDataEx::new(tunnel.username.clone(), tunnel.password.clone())
+            .set_max_frame_size(GLOBAL_CONFIG.inlet_max_frame_size)
+            .set_chunk_size(GLOBAL_CONFIG.inlet_chunk_size)
+            .set_idle_deadlines(
+                GLOBAL_CONFIG.inlet_read_timeout,
+                GLOBAL_CONFIG.inlet_write_timeout,
+            )
+            .set_probe_interval(GLOBAL_CONFIG.inlet_probe_interval)
+            .set_write_queue_limit(GLOBAL_CONFIG.inlet_write_queue_limit)
+            .set_max_session_lifetime(tunnel.max_session_lifetime as u64)
+            .set_accept_proxy_protocol(tunnel.accept_proxy_protocol == 1)
+            .set_write_coalesce(tunnel.write_coalesce as u64)
+            .set_connect_retry(tunnel.connect_retries, tunnel.connect_retry_delay as u64)
+            .set_conn_rate_limit(tunnel.conn_rate_limit, tunnel.conn_rate_burst)
+            .set_ipv6_only(GLOBAL_CONFIG.inlet_ipv6_only)
+            .set_listen_backlog(GLOBAL_CONFIG.inlet_listen_backlog)
+            .set_nodelay(tunnel.inlet_nodelay == 1)
+            .set_fast_open(tunnel.fast_open == 1)
+            .set_encryption_fallback(tunnel.encryption_fallback == 1)
+            .set_compress_threshold(tunnel.compress_threshold as usize)
+            .set_bind_device(tunnel.bind_device.clone())
+            .set_tls(
+                tunnel.tls_cert.clone(),
+                tunnel.tls_key.clone(),
+                tunnel.tls_alpn.clone(),
+            )
+            .set_backend_tls(tunnel.backend_tls)
+            .set_compression_dictionary(compression_dictionary(tunnel))
+            .set_authenticator(authenticator(tunnel))
+            .set_reject_response(RejectResponse::parse(&tunnel.reject_response).unwrap_or_default())
+            .set_length_prefix(LengthPrefix::from_u32(tunnel.frame_prefix).unwrap_or_default())
+            .set_key_derivation(
+                KeyDerivation::from_u32(tunnel.key_derivation).unwrap_or_default(),
+                &tunnel.password,
+            )
+            .set_geo_database(GLOBAL_GEO_DATABASE.get().cloned())
+            .set_circuit_breaker(CircuitBreakerConfig::new(
+                GLOBAL_CONFIG.circuit_breaker_threshold,
+                GLOBAL_CONFIG.circuit_breaker_window,
+                GLOBAL_CONFIG.circuit_breaker_cooldown,
+            ))
+            .set_on_breaker_change(Arc::new(move |state| {
+                tokio::spawn(Self::notify_breaker_state(
+                    player_id as PlayerId,
+                    tunnel_id,
+                    state,
+                ));
+            }))
+            .set_o2i_codec(
+                tunnel.o2i_is_compressed.map(|x| x == 1),
+                tunnel.o2i_encryption_method.clone(),
+            )
+            .set_host_routes(serde_json::from_str(&tunnel.custom_mapping).unwrap_or_default())
+            .set_splice_outlet(Self::splice_outlet(tunnel))
+            .set_on_spliced_traffic(Arc::new(move |len| {
+                GLOBAL_MANAGER.proxy_manager.record_bytes(tunnel_id, len);
+            }));
+        let log_access = GLOBAL_CONFIG.syslog_access_events;
+        let notify = GLOBAL_CONFIG.webhook_session_events;
+        if log_access || notify {
+            data_ex.set_on_session_event(Arc::new(move |event| {
+                if log_access {
+                    syslog::log_session_event(tunnel_id, &event);
+                }
+                if notify {
+                    webhook::send_webhook(Event::session(tunnel_id, event));
+                }
+            }))
+        } else {
+            data_ex
+        }
+    }
+
+    /// 入口和出口都在服务器上时, 入口直接连接后端使用的出口设置
+    fn splice_outlet(tunnel: &tunnel::Model) -> Option<OutletDataEx> {
+        if !GLOBAL_CONFIG.inlet_splice || tunnel.receiver != 0 || tunnel.outlet_players() != [0] {
+            return None;
+        }
+        Some(outlet_data_ex().set_nodelay(tunnel.outlet_nodelay == 1))
+    }
+
+    /// 通道在本机上所有会话的背压统计, 通道不在本机运行时返回None
+    pub async fn backpressure_stats(&self, tunnel_id: u32) -> Option<BackpressureStats> {
+        let inlet_stats = match self.inlets.read().await.get(&tunnel_id) {
+            Some(inlet) => Some(inlet.backpressure_stats().await),
+            None => None,
+        };
+        let outlet_stats = match self.outlets.read().await.get(&tunnel_id) {
+            Some(outlet) => Some(outlet.backpressure_stats().await),
+            None => None,
+        };
+
+        match (inlet_stats, outlet_stats) {
+            (Some(a), Some(b)) => Some(a.merge(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// 通道出口连接池的统计, 出口不在本机运行或未开启连接池时返回None
+    pub async fn pool_stats(&self, tunnel_id: u32) -> Option<ConnectionPoolStats> {
+        self.outlets
+            .read()
+            .await
+            .get(&tunnel_id)
+            .and_then(|outlet| outlet.pool_stats())
+    }
+
+    /// 通道入口按关闭原因统计的会话数量, 入口不在本机运行时返回None
+    pub async fn close_stats(&self, tunnel_id: u32) -> Option<SessionCloseStats> {
+        self.inlets
+            .read()
+            .await
+            .get(&tunnel_id)
+            .map(|inlet| inlet.close_stats())
+    }
+
+    /// 通道入口的所有会话, 入口不在本机运行时返回None
+    pub async fn list_sessions(&self, tunnel_id: u32) -> Option<Vec<SessionSummary>> {
+        match self.inlets.read().await.get(&tunnel_id) {
+            Some(inlet) => Some(inlet.list_sessions().await),
+            None => None,
+        }
+    }
+
+    /// 通道入口的累计流量, 入口不在本机运行时返回None
+    pub async fn traffic_stats(&self, tunnel_id: u32) -> Option<TrafficStats> {
+        self.inlets
+            .read()
+            .await
+            .get(&tunnel_id)
+            .map(|inlet| inlet.stats_snapshot())
+    }
+
+    /// 重置通道入口的累计流量, 返回重置前的统计, 入口不在本机运行时返回None
+    pub async fn reset_traffic_stats(&self, tunnel_id: u32) -> Option<TrafficStats> {
+        self.inlets
+            .read()
+            .await
+            .get(&tunnel_id)
+            .map(|inlet| inlet.reset_stats())
+    }
+
+    /// 强制关闭通道入口的会话, 入口不在本机运行时返回false, 会话已经不存在时不算错误
+    pub async fn kill_session(&self, tunnel_id: u32, session_id: u32) -> bool {
+        match self.inlets.read().await.get(&tunnel_id) {
+            Some(inlet) => {
+                inlet.kill_session(session_id).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 重新加载所有终止TLS的入口的证书, 加载失败的入口继续使用原来的证书
+    pub async fn reload_tls(&self) {
+        for (tunnel_id, inlet) in self.inlets.read().await.iter() {
+            match inlet.reload_tls() {
+                Ok(true) => info!("tunnel({tunnel_id}) TLS certificate reloaded"),
+                Ok(false) => {}
+                Err(err) => warn!("tunnel({tunnel_id}) failed to reload TLS certificate: {err}"),
+            }
+        }
+    }
+
+    /// 通道入口的延迟探测结果, 入口不在本机运行时返回None
+    pub async fn rtt_stats(&self, tunnel_id: u32) -> Option<RttStats> {
+        self.inlets
+            .read()
+            .await
+            .get(&tunnel_id)
+            .map(|inlet| inlet.rtt_stats())
+    }
+
+    /// 通道入口的熔断状态, 入口不在本机运行时返回None
+    pub async fn breaker_state(&self, tunnel_id: u32) -> Option<BreakerState> {
+        self.inlets
+            .read()
+            .await
+            .get(&tunnel_id)
+            .map(|inlet| inlet.breaker_state())
+    }
+
+    /// 熔断状态变化时通知通道出口所在的玩家
+    async fn notify_breaker_state(player_id: PlayerId, tunnel_id: u32, state: BreakerState) {
+        if state == BreakerState::Open {
+            warn!("tunnel({tunnel_id}) circuit breaker tripped");
+        } else {
+            debug!("tunnel({tunnel_id}) circuit breaker {state}");
+        }
+
+        if player_id == 0 {
+            return;
+        }
+        if let Some(player) = GLOBAL_MANAGER.player_manager.get_player(player_id).await {
+            let player = player.read().await;
+            if player.is_online() {
+                let _ = player
+                    .send_push(&MessageType::ServerClientTunnelBreakerNtf(
+                        server_client::TunnelBreakerNtf {
+                            tunnel_id,
+                            state: state.to_string(),
+                        },
+                    ))
+                    .await;
+            }
+        }
     }
 
     pub(crate) async fn send_proxy_message(
@@ -176,6 +851,70 @@ impl ProxyManager {
         tunnel_id: u32,
         proxy_message: ProxyMessage,
     ) {
+        // 目标玩家断线等待重连时暂存
+        if let Some(proxy_message) = GLOBAL_MANAGER.reconnect_manager.hold(
+            from_player_id,
+            to_player_id,
+            tunnel_id,
+            proxy_message,
+        ) {
+            Self::forward_proxy_message(from_player_id, to_player_id, tunnel_id, proxy_message)
+                .await;
+        }
+    }
+
+    pub(crate) async fn forward_proxy_message(
+        from_player_id: PlayerId,
+        to_player_id: PlayerId,
+        tunnel_id: u32,
+        proxy_message: ProxyMessage,
+    ) {
+        GLOBAL_MANAGER
+            .proxy_manager
+            .record_traffic(tunnel_id, &proxy_message);
+
+        // 入口按出口分别记录加密方法的协商结果
+        let proxy_message = match proxy_message {
+            ProxyMessage::O2iConnect(
+                session_id,
+                generation,
+                success,
+                error_msg,
+                independent_codec,
+                backend_addr,
+                _,
+            ) => ProxyMessage::O2iConnect(
+                session_id,
+                generation,
+                success,
+                error_msg,
+                independent_codec,
+                backend_addr,
+                from_player_id,
+            ),
+            proxy_message => proxy_message,
+        };
+
+        // 反向连接的会话通过出口建立的连接转发
+        let proxy_message = match GLOBAL_MANAGER
+            .stream_manager
+            .forward(tunnel_id, proxy_message)
+        {
+            Some(proxy_message) => proxy_message,
+            None => return,
+        };
+        let proxy_message = match proxy_message {
+            ProxyMessage::I2oConnect { .. } if to_player_id != 0 => {
+                match open_reverse_stream(from_player_id, to_player_id, tunnel_id, proxy_message)
+                    .await
+                {
+                    Some(proxy_message) => proxy_message,
+                    None => return,
+                }
+            }
+            _ => proxy_message,
+        };
+
         if to_player_id == 0 {
             if message_bridge::is_i2o_message(&proxy_message) {
                 send_input_to_outlet(&tunnel_id, proxy_message).await;
@@ -198,15 +937,24 @@ impl ProxyManager {
 
         // 玩家离线或找不到
         let message = match proxy_message {
-            ProxyMessage::I2oConnect(session_id, ..) => Some(ProxyMessage::O2iConnect(
+            ProxyMessage::I2oConnect {
                 session_id,
+                generation,
+                ..
+            } => Some(ProxyMessage::O2iConnect(
+                session_id,
+                generation,
                 false,
                 format!("no player {to_player_id} or the player is offline"),
+                false,
+                "".into(),
+                to_player_id,
             )),
 
             ProxyMessage::I2oSendData(session_id, ..)
             | ProxyMessage::I2oRecvDataResult(session_id, ..) => {
-                Some(ProxyMessage::O2iDisconnect(session_id))
+                // 这些消息不带会话代数, 入口按未知代数处理
+                Some(ProxyMessage::O2iDisconnect(session_id, 0))
             }
 
             ProxyMessage::O2iConnect(session_id, ..)
@@ -236,6 +984,153 @@ impl ProxyManager {
     }
 }
 
+/// 反向连接通道中, 通知出口为新会话建立连接, 不是反向连接通道或出口离线时原样返回消息
+async fn open_reverse_stream(
+    inlet_player_id: PlayerId,
+    outlet_player_id: PlayerId,
+    tunnel_id: u32,
+    connect: ProxyMessage,
+) -> Option<ProxyMessage> {
+    let is_reverse_stream = GLOBAL_MANAGER
+        .tunnel_manager
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .any(|x| x.id == tunnel_id && x.reverse_stream == 1);
+    if !is_reverse_stream {
+        return Some(connect);
+    }
+    let player = match GLOBAL_MANAGER
+        .player_manager
+        .get_player(outlet_player_id)
+        .await
+    {
+        Some(player) if player.read().await.is_online() => player,
+        _ => return Some(connect),
+    };
+
+    let (session_id, generation) = match connect {
+        ProxyMessage::I2oConnect {
+            session_id,
+            generation,
+            ..
+        } => (session_id, generation),
+        _ => return Some(connect),
+    };
+    let info = StreamInfo {
+        inlet_player_id,
+        outlet_player_id,
+        generation,
+    };
+    let token = GLOBAL_MANAGER.stream_manager.open(tunnel_id, info, connect);
+    let _ = player
+        .read()
+        .await
+        .send_push(&message_bridge::proxy_message_2_pb(
+            ProxyMessage::I2oOpenStream(session_id, generation, token.clone()),
+            tunnel_id,
+        ))
+        .await;
+
+    tokio::spawn(async move {
+        tokio::time::sleep(REVERSE_STREAM_TIMEOUT).await;
+        if let Some(info) = GLOBAL_MANAGER
+            .stream_manager
+            .expire(tunnel_id, session_id, &token)
+        {
+            debug!("tunnel({tunnel_id}) session({session_id}) reverse stream timeout");
+            let message = ProxyMessage::O2iConnect(
+                session_id,
+                info.generation,
+                false,
+                "outlet did not open the reverse stream in time".into(),
+                false,
+                String::new(),
+                info.outlet_player_id,
+            );
+            if info.inlet_player_id == 0 {
+                send_input_to_inlet(&tunnel_id, message).await;
+            } else {
+                push_message_to_player(
+                    info.inlet_player_id,
+                    &message_bridge::proxy_message_2_pb(message, tunnel_id),
+                )
+                .await;
+            }
+        }
+    });
+    None
+}
+
+/// 服务器上出口的连接设置
+pub(crate) fn outlet_data_ex() -> OutletDataEx {
+    OutletDataEx::new()
+        .set_upstream_socks5(
+            GLOBAL_CONFIG.outlet_socks5_proxy.clone(),
+            GLOBAL_CONFIG.outlet_socks5_username.clone(),
+            GLOBAL_CONFIG.outlet_socks5_password.expose().to_string(),
+        )
+        .set_bind_addr(GLOBAL_CONFIG.outlet_bind_addr)
+        .set_send_proxy_protocol(GLOBAL_CONFIG.outlet_send_proxy_protocol)
+        .set_keepalive(TcpKeepaliveConfig {
+            time: Duration::from_secs(GLOBAL_CONFIG.outlet_keepalive_time),
+            interval: Duration::from_secs(GLOBAL_CONFIG.outlet_keepalive_interval),
+            retries: GLOBAL_CONFIG.outlet_keepalive_retries,
+        })
+        .set_send_timeout(
+            Duration::from_secs(GLOBAL_CONFIG.outlet_send_timeout),
+            GLOBAL_CONFIG.outlet_send_max_timeouts,
+        )
+        .set_allowlist(GLOBAL_OUTLET_ALLOWLIST.get().cloned().unwrap_or_default())
+}
+
+/// 加载通道的压缩字典, 失败时不使用字典, 会话会因为与对端不一致而连接失败
+fn compression_dictionary(tunnel: &tunnel::Model) -> Option<Arc<CompressionDictionary>> {
+    CompressionDictionary::load(&tunnel.compression_dictionary).unwrap_or_else(|err| {
+        error!("tunnel({}) {err}", tunnel.id);
+        None
+    })
+}
+
+/// 通道配置的外部认证接口, 地址无效时拒绝所有用户
+fn authenticator(tunnel: &tunnel::Model) -> Option<Arc<dyn Authenticator>> {
+    if tunnel.auth_url.is_empty() {
+        return None;
+    }
+    match HttpAuthenticator::new(&tunnel.auth_url) {
+        Ok(authenticator) => Some(Arc::new(authenticator)),
+        Err(err) => {
+            error!("tunnel({}) {err}", tunnel.id);
+            Some(Arc::new(DenyAuthenticator))
+        }
+    }
+}
+
+/// 配置了录制目录时为入口创建录制文件
+fn message_recorder(tunnel: &tunnel::Model) -> Option<Arc<MessageRecorder>> {
+    if GLOBAL_CONFIG.message_record_dir.is_empty() {
+        return None;
+    }
+    MessageRecorder::create_for_tunnel(
+        &GLOBAL_CONFIG.message_record_dir,
+        tunnel.id,
+        GLOBAL_CONFIG.message_record_payload,
+    )
+    .map_err(|err| error!("tunnel({}) {err}", tunnel.id))
+    .ok()
+}
+
+async fn is_player_online(player_id: PlayerId) -> bool {
+    if player_id == 0 {
+        return true;
+    }
+    match GLOBAL_MANAGER.player_manager.get_player(player_id).await {
+        Some(player) => player.read().await.is_online(),
+        None => false,
+    }
+}
+
 async fn push_message_to_player(player_id: PlayerId, message: &MessageType) {
     if let Some(player) = GLOBAL_MANAGER.player_manager.get_player(player_id).await {
         let _ = player.read().await.send_push(message).await;
@@ -265,3 +1160,54 @@ async fn send_input_to_inlet(tunnel_id: &u32, proxy_message: ProxyMessage) {
         inlet.input(proxy_message).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendezvous_pick() {
+        let players = [(1, 1), (2, 1), (3, 1), (4, 1)];
+        let clients: Vec<String> = (0..200)
+            .map(|x| format!("10.0.{}.{}", x / 256, x % 256))
+            .collect();
+        let picks: Vec<_> = clients
+            .iter()
+            .map(|x| rendezvous_pick(x, &players).unwrap())
+            .collect();
+        assert!(players.iter().all(|x| picks.contains(&x.0)));
+
+        // 移除一个出口后, 只有原本分配到该出口的客户端重新分配
+        let remaining = [(1, 1), (2, 1), (4, 1)];
+        for (client, pick) in clients.iter().zip(&picks) {
+            let new_pick = rendezvous_pick(client, &remaining).unwrap();
+            if *pick != 3 {
+                assert_eq!(new_pick, *pick);
+            }
+        }
+        assert_eq!(rendezvous_pick("10.0.0.1", &[]), None);
+
+        // 按权重分配客户端
+        let weighted = [(1, 4), (2, 1)];
+        let count = clients
+            .iter()
+            .filter(|x| rendezvous_pick(x, &weighted) == Some(1))
+            .count();
+        assert!((140..=180).contains(&count), "{count}");
+    }
+
+    #[test]
+    fn test_weighted_round_robin_pick() {
+        let pick = |players: &[(PlayerId, u32)], count: usize| -> Vec<PlayerId> {
+            (0..count)
+                .map(|n| weighted_round_robin_pick(n, players).unwrap())
+                .collect()
+        };
+        // 权重相同时依次分配
+        assert_eq!(pick(&[(1, 1), (2, 1), (3, 1)], 4), [1, 2, 3, 1]);
+        // 每个周期按权重分配且交错
+        assert_eq!(pick(&[(1, 4), (2, 1)], 10), [1, 2, 1, 1, 1, 1, 2, 1, 1, 1]);
+        assert_eq!(pick(&[(1, 2), (2, 3)], 5), [1, 2, 1, 2, 2]);
+        assert_eq!(weighted_round_robin_pick(0, &[]), None);
+    }
+}
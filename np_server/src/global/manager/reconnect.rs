@@ -0,0 +1,217 @@
+use crate::global::config::GLOBAL_CONFIG;
+use crate::global::manager::proxy::ProxyManager;
+use crate::global::manager::GLOBAL_MANAGER;
+use crate::player::PlayerId;
+use log::{info, warn};
+use np_base::proxy::ProxyMessage;
+use np_proto::utils::message_bridge;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// 每个玩家最多暂存的会话数, 超过后新会话按离线处理
+const MAX_HELD_SESSIONS: usize = 256;
+
+struct HeldMessage {
+    from_player_id: PlayerId,
+    tunnel_id: u32,
+    proxy_message: ProxyMessage,
+}
+
+#[derive(Default)]
+struct GraceState {
+    // 等待重连的计时器, 玩家重连后为None
+    timer: Option<JoinHandle<()>>,
+    // 暂存的会话(tunnel_id, session_id), 同一会话后续的消息也要暂存以保证顺序
+    sessions: HashSet<(u32, u32)>,
+    messages: Vec<HeldMessage>,
+}
+
+/// 玩家断线重连的宽限期
+///
+/// 断线后发往该玩家的新会话先暂存, 重连并发出登录回复后继续转发; 宽限期内没有重连时按离线处理
+pub struct ReconnectManager {
+    players: Mutex<HashMap<PlayerId, GraceState>>,
+}
+
+impl ReconnectManager {
+    pub fn new() -> Self {
+        Self {
+            players: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 玩家断线, 开始计时. 宽限期为0时不处理
+    pub(crate) fn start(&self, player_id: PlayerId) {
+        let grace = GLOBAL_CONFIG.player_reconnect_grace;
+        if grace == 0 {
+            return;
+        }
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(grace)).await;
+            GLOBAL_MANAGER.reconnect_manager.expire(player_id).await;
+        });
+        self.start_with_timer(player_id, timer);
+    }
+
+    fn start_with_timer(&self, player_id: PlayerId, timer: JoinHandle<()>) {
+        let mut players = self.players.lock().unwrap();
+        let state = players.entry(player_id).or_default();
+        if let Some(old) = state.timer.replace(timer) {
+            old.abort();
+        }
+    }
+
+    /// 玩家重连, 停止计时. 暂存的消息由 [`ReconnectManager::release`] 转发
+    pub(crate) fn cancel(&self, player_id: PlayerId) {
+        let mut players = self.players.lock().unwrap();
+        if let Some(timer) = players.get_mut(&player_id).and_then(|x| x.timer.take()) {
+            timer.abort();
+        }
+    }
+
+    /// 暂存发往宽限期内玩家的新会话, 不需要暂存时原样返回
+    pub(crate) fn hold(
+        &self,
+        from_player_id: PlayerId,
+        to_player_id: PlayerId,
+        tunnel_id: u32,
+        proxy_message: ProxyMessage,
+    ) -> Option<ProxyMessage> {
+        let mut players = self.players.lock().unwrap();
+        let state = match players.get_mut(&to_player_id) {
+            Some(state) => state,
+            None => return Some(proxy_message),
+        };
+        if !message_bridge::is_i2o_message(&proxy_message) {
+            return Some(proxy_message);
+        }
+
+        let key = (tunnel_id, proxy_message.session_id());
+        let hold = state.sessions.contains(&key)
+            || matches!(proxy_message, ProxyMessage::I2oConnect { .. })
+                && state.timer.is_some()
+                && state.sessions.len() < MAX_HELD_SESSIONS;
+        if !hold {
+            return Some(proxy_message);
+        }
+        state.sessions.insert(key);
+        state.messages.push(HeldMessage {
+            from_player_id,
+            tunnel_id,
+            proxy_message,
+        });
+        None
+    }
+
+    /// 玩家重连后发出登录回复时调用, 按顺序转发暂存的消息
+    pub(crate) async fn release(&self, player_id: PlayerId) {
+        loop {
+            let messages = {
+                let mut players = self.players.lock().unwrap();
+                let state = match players.get_mut(&player_id) {
+                    Some(state) => state,
+                    None => return,
+                };
+                // 又断线了, 继续暂存
+                if state.timer.is_some() {
+                    return;
+                }
+                if state.messages.is_empty() {
+                    players.remove(&player_id);
+                    return;
+                }
+                std::mem::take(&mut state.messages)
+            };
+            info!(
+                "player({player_id}) reconnected, resuming {} held messages",
+                messages.len()
+            );
+            Self::replay(player_id, messages).await;
+        }
+    }
+
+    /// 宽限期结束仍未重连, 暂存的会话按离线处理
+    async fn expire(&self, player_id: PlayerId) {
+        let state = self.players.lock().unwrap().remove(&player_id);
+        if let Some(state) = state {
+            warn!(
+                "player({player_id}) did not reconnect in time, dropping {} held sessions",
+                state.sessions.len()
+            );
+            Self::replay(player_id, state.messages).await;
+        }
+    }
+
+    async fn replay(to_player_id: PlayerId, messages: Vec<HeldMessage>) {
+        for message in messages {
+            ProxyManager::forward_proxy_message(
+                message.from_player_id,
+                to_player_id,
+                message.tunnel_id,
+                message.proxy_message,
+            )
+            .await;
+        }
+    }
+
+    #[cfg(test)]
+    fn held_count(&self, player_id: PlayerId) -> usize {
+        let players = self.players.lock().unwrap();
+        players.get(&player_id).map_or(0, |x| x.messages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(session_id: u32) -> ProxyMessage {
+        ProxyMessage::I2oConnect {
+            session_id,
+            generation: 1,
+            tunnel_type: 0,
+            is_tcp: true,
+            is_compressed: false,
+            addr: "127.0.0.1:80".into(),
+            encryption_method: "".into(),
+            encryption_key: "".into(),
+            client_addr: "".into(),
+            o2i_codec: None,
+            write_coalesce: 0,
+            backend_tls: None,
+            compression_dictionary: "".into(),
+            metadata: HashMap::new(),
+            key_derivation: None,
+            compress_threshold: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hold_during_grace() {
+        let manager = ReconnectManager::new();
+        // 玩家在线时不暂存
+        assert!(manager.hold(1, 2, 10, connect(5)).is_some());
+
+        manager.start_with_timer(2, tokio::spawn(std::future::pending()));
+        // 宽限期内暂存新会话及其后续消息, 其他会话和其他玩家不受影响
+        assert!(manager.hold(1, 2, 10, connect(5)).is_none());
+        assert!(manager
+            .hold(1, 2, 10, ProxyMessage::I2oSendData(5, vec![1]))
+            .is_none());
+        assert!(manager
+            .hold(1, 2, 10, ProxyMessage::I2oSendData(6, vec![1]))
+            .is_some());
+        assert!(manager.hold(1, 3, 10, connect(5)).is_some());
+        assert_eq!(manager.held_count(2), 2);
+
+        // 重连后不再暂存新会话, 已暂存的会话保持顺序直到转发
+        manager.cancel(2);
+        assert!(manager.hold(1, 2, 10, connect(7)).is_some());
+        assert!(manager
+            .hold(1, 2, 10, ProxyMessage::I2oDisconnect(5))
+            .is_none());
+        assert_eq!(manager.held_count(2), 3);
+    }
+}
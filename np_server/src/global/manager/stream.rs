@@ -0,0 +1,300 @@
+use crate::player::PlayerId;
+use log::debug;
+use np_base::net::WriterMessage;
+use np_base::proxy::ProxyMessage;
+use np_proto::frame::encode_frame;
+use np_proto::utils::message_bridge;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// 出口主动建立的会话连接(反向连接)
+///
+/// 入口发起连接时服务器先通知出口用令牌建立新连接, 绑定前收到的入口消息暂存,
+/// 绑定后这个会话入口到出口方向的消息都通过新连接转发. 键为(通道id, 会话id)
+pub struct StreamManager {
+    streams: Mutex<HashMap<(u32, u32), StreamState>>,
+}
+
+enum StreamState {
+    // 等待出口建立连接
+    Pending(PendingStream),
+    // 已绑定到出口建立的连接
+    Bound(BoundStream),
+}
+
+struct PendingStream {
+    token: String,
+    info: StreamInfo,
+    // 等待绑定期间的入口消息, 第一条为I2oConnect
+    messages: Vec<ProxyMessage>,
+}
+
+struct BoundStream {
+    tx: UnboundedSender<WriterMessage>,
+    // 出口建立的连接在服务器上的会话id
+    peer_session_id: u32,
+    info: StreamInfo,
+}
+
+/// 反向连接所属会话的信息
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct StreamInfo {
+    // 入口所在的玩家, 0表示服务器
+    pub inlet_player_id: PlayerId,
+    pub outlet_player_id: PlayerId,
+    // 入口分配的会话代数
+    pub generation: u32,
+}
+
+impl StreamManager {
+    pub fn new() -> Self {
+        Self {
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 登记等待出口建立连接的会话, 返回出口绑定连接时使用的令牌
+    pub(crate) fn open(&self, tunnel_id: u32, info: StreamInfo, connect: ProxyMessage) -> String {
+        let key = (tunnel_id, connect.session_id());
+        let token = format!("{:032x}", rand::random::<u128>());
+        let stale = self.streams.lock().unwrap().insert(
+            key,
+            StreamState::Pending(PendingStream {
+                token: token.clone(),
+                info,
+                messages: vec![connect],
+            }),
+        );
+        close_stale(key, stale);
+        token
+    }
+
+    /// 出口建立的连接用令牌绑定会话, 成功时把暂存的消息按顺序转发到这个连接
+    pub(crate) fn bind(
+        &self,
+        tunnel_id: u32,
+        session_id: u32,
+        token: &str,
+        peer_session_id: u32,
+        tx: UnboundedSender<WriterMessage>,
+    ) -> bool {
+        let key = (tunnel_id, session_id);
+        let mut streams = self.streams.lock().unwrap();
+        let pending = match streams.remove(&key) {
+            Some(StreamState::Pending(pending)) if pending.token == token => pending,
+            Some(state) => {
+                streams.insert(key, state);
+                return false;
+            }
+            None => return false,
+        };
+        // 持有锁时发送, 保证暂存的消息在之后转发的消息之前
+        for message in pending.messages {
+            send_message(&tx, tunnel_id, message);
+        }
+        streams.insert(
+            key,
+            StreamState::Bound(BoundStream {
+                tx,
+                peer_session_id,
+                info: pending.info,
+            }),
+        );
+        true
+    }
+
+    /// 转发反向连接会话的消息, 不属于反向连接的消息原样返回
+    ///
+    /// 入口到出口方向的消息在绑定前暂存, 绑定后通过出口建立的连接发送;
+    /// 任意一方断开时删除记录
+    pub(crate) fn forward(&self, tunnel_id: u32, message: ProxyMessage) -> Option<ProxyMessage> {
+        let key = (tunnel_id, message.session_id());
+        let mut streams = self.streams.lock().unwrap();
+        if streams.is_empty() {
+            return Some(message);
+        }
+        match message {
+            // 会话id被复用, 旧的反向连接作废
+            ProxyMessage::I2oConnect { .. } => {
+                close_stale(key, streams.remove(&key));
+                Some(message)
+            }
+            ProxyMessage::O2iDisconnect(..) => {
+                if matches!(streams.get(&key), Some(StreamState::Bound(_))) {
+                    streams.remove(&key);
+                }
+                Some(message)
+            }
+            ProxyMessage::I2oDisconnect(..) => match streams.remove(&key) {
+                Some(StreamState::Pending(_)) => None,
+                Some(StreamState::Bound(stream)) => {
+                    send_message(&stream.tx, tunnel_id, message);
+                    None
+                }
+                None => Some(message),
+            },
+            _ if message_bridge::is_i2o_message(&message) => match streams.get_mut(&key) {
+                Some(StreamState::Pending(pending)) => {
+                    pending.messages.push(message);
+                    None
+                }
+                Some(StreamState::Bound(stream)) => {
+                    send_message(&stream.tx, tunnel_id, message);
+                    None
+                }
+                None => Some(message),
+            },
+            _ => Some(message),
+        }
+    }
+
+    /// 出口在超时前没有建立连接时删除记录
+    pub(crate) fn expire(
+        &self,
+        tunnel_id: u32,
+        session_id: u32,
+        token: &str,
+    ) -> Option<StreamInfo> {
+        let key = (tunnel_id, session_id);
+        let mut streams = self.streams.lock().unwrap();
+        match streams.get(&key) {
+            Some(StreamState::Pending(pending)) if pending.token == token => {
+                let info = pending.info;
+                streams.remove(&key);
+                Some(info)
+            }
+            _ => None,
+        }
+    }
+
+    /// 出口建立的连接断开, 会话还没有结束时返回会话信息用于通知入口
+    pub(crate) fn unbind(
+        &self,
+        tunnel_id: u32,
+        session_id: u32,
+        peer_session_id: u32,
+    ) -> Option<StreamInfo> {
+        let key = (tunnel_id, session_id);
+        let mut streams = self.streams.lock().unwrap();
+        match streams.get(&key) {
+            Some(StreamState::Bound(stream)) if stream.peer_session_id == peer_session_id => {
+                let info = stream.info;
+                streams.remove(&key);
+                Some(info)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn send_message(tx: &UnboundedSender<WriterMessage>, tunnel_id: u32, message: ProxyMessage) {
+    let message = message_bridge::proxy_message_2_pb(message, tunnel_id);
+    if let Some(buf) = encode_frame(0, &message) {
+        let _ = tx.send(WriterMessage::Send(buf, true));
+    }
+}
+
+fn close_stale(key: (u32, u32), stale: Option<StreamState>) {
+    if let Some(StreamState::Bound(stream)) = stale {
+        debug!(
+            "tunnel({}) session({}) reused, closing stale stream",
+            key.0, key.1
+        );
+        let _ = stream.tx.send(WriterMessage::Close);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const INFO: StreamInfo = StreamInfo {
+        inlet_player_id: 0,
+        outlet_player_id: 2,
+        generation: 7,
+    };
+
+    fn connect(session_id: u32) -> ProxyMessage {
+        ProxyMessage::I2oConnect {
+            session_id,
+            generation: INFO.generation,
+            tunnel_type: 0,
+            is_tcp: true,
+            is_compressed: false,
+            addr: "127.0.0.1:80".into(),
+            encryption_method: "".into(),
+            encryption_key: "".into(),
+            client_addr: "".into(),
+            o2i_codec: None,
+            write_coalesce: 0,
+            backend_tls: None,
+            compression_dictionary: "".into(),
+            metadata: HashMap::new(),
+            key_derivation: None,
+            compress_threshold: 0,
+        }
+    }
+
+    fn sent_count(rx: &mut tokio::sync::mpsc::UnboundedReceiver<WriterMessage>) -> usize {
+        let mut count = 0;
+        while let Ok(WriterMessage::Send(..)) = rx.try_recv() {
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn test_bind_stream() {
+        let manager = StreamManager::new();
+        let token = manager.open(1, INFO, connect(5));
+
+        // 绑定前的消息暂存, 其他会话不受影响
+        assert!(manager
+            .forward(1, ProxyMessage::I2oSendData(5, vec![1]))
+            .is_none());
+        assert!(manager
+            .forward(1, ProxyMessage::I2oSendData(6, vec![1]))
+            .is_some());
+
+        // 令牌不匹配时不能绑定
+        let (tx, mut rx) = unbounded_channel();
+        assert!(!manager.bind(1, 5, "bad", 100, tx.clone()));
+        assert_eq!(sent_count(&mut rx), 0);
+
+        // 绑定后先转发暂存的消息, 之后的消息直接转发
+        assert!(manager.bind(1, 5, &token, 100, tx));
+        assert_eq!(sent_count(&mut rx), 2);
+        assert!(manager
+            .forward(1, ProxyMessage::I2oSendData(5, vec![2]))
+            .is_none());
+        assert_eq!(sent_count(&mut rx), 1);
+        assert!(manager.expire(1, 5, &token).is_none());
+
+        // 出口断开后入口的消息不再经过反向连接
+        assert!(manager
+            .forward(1, ProxyMessage::O2iDisconnect(5, INFO.generation))
+            .is_some());
+        assert!(manager.unbind(1, 5, 100).is_none());
+        assert!(manager.forward(1, ProxyMessage::I2oDisconnect(5)).is_some());
+    }
+
+    #[test]
+    fn test_stream_expire_and_unbind() {
+        let manager = StreamManager::new();
+        let token = manager.open(1, INFO, connect(5));
+        assert_eq!(manager.expire(1, 5, &token), Some(INFO));
+        assert!(manager
+            .forward(1, ProxyMessage::I2oSendData(5, vec![1]))
+            .is_some());
+
+        // 连接断开时只清理自己绑定的会话
+        let token = manager.open(1, INFO, connect(5));
+        let (tx, _rx) = unbounded_channel();
+        assert!(manager.bind(1, 5, &token, 100, tx));
+        assert!(manager.unbind(1, 5, 101).is_none());
+        assert_eq!(manager.unbind(1, 5, 100), Some(INFO));
+        assert!(manager.unbind(1, 5, 100).is_none());
+    }
+}
@@ -1,38 +1,217 @@
+use crate::global::config::GLOBAL_CONFIG;
+use crate::global::event::Event;
+use crate::global::manager::proxy::BalancePolicy;
 use crate::global::manager::GLOBAL_MANAGER;
-use crate::global::GLOBAL_DB_POOL;
+use crate::global::{db, webhook};
 use crate::orm_entity::prelude::Tunnel;
 use crate::orm_entity::tunnel;
 use crate::player::PlayerId;
 use crate::utils::str::{
-    get_tunnel_address_port, is_valid_tunnel_endpoint_address, is_valid_tunnel_source_address,
+    get_tunnel_address_port, is_auto_port_address, is_listen_addr_overlapped,
+    normalize_tunnel_source_address, parse_tunnel_endpoint_address, parse_tunnel_source_address,
 };
 use anyhow::anyhow;
+use async_trait::async_trait;
+use log::{debug, warn};
+use np_base::proxy::auth::HttpAuthenticator;
+use np_base::proxy::crypto::{self, KeyDerivation};
+use np_base::proxy::dictionary::CompressionDictionary;
+use np_base::proxy::framing::LengthPrefix;
+use np_base::proxy::inlet::InletProxyType;
+use np_base::proxy::reject::RejectResponse;
+use np_base::proxy::unix_socket_path;
 use np_proto::message_map::MessageType;
 use np_proto::{class_def, server_client};
-use sea_orm::ActiveValue::Set;
-use sea_orm::{ActiveModelTrait, EntityTrait};
+use sea_orm::ActiveValue::{Set, Unchanged};
+use sea_orm::{ActiveModelTrait, DatabaseConnection, EntityTrait};
 use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
 use tokio::sync::RwLock;
 
+/// 出口连接池的最大连接数
+const MAX_POOL_SIZE: u32 = 64;
+
+/// 出口权重的上限
+const MAX_OUTLET_WEIGHT: u32 = 1000;
+
+/// 通道操作失败的原因, 需要调用方区分处理的错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelError {
+    /// 玩家拥有的通道数已达到上限
+    QuotaExceeded { player_id: PlayerId, limit: u32 },
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelError::QuotaExceeded { player_id, limit } => write!(
+                f,
+                "player({player_id}) has reached the maximum of {limit} tunnels"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TunnelError {}
+
+/// 通道管理依赖的数据库和其他管理器, 测试时可以替换为模拟实现
+#[async_trait]
+pub trait TunnelContext: Send + Sync {
+    fn db(&self) -> &DatabaseConnection;
+
+    async fn contain_player(&self, player_id: PlayerId) -> bool;
+
+    /// 向在线的玩家和管理登录的会话推送消息
+    async fn send_push(&self, player_id: PlayerId, message: &MessageType);
+
+    /// 按通道配置同步本机的入口和出口
+    async fn sync_tunnels(&self) -> anyhow::Result<()>;
+
+    fn tunnel_error(&self, tunnel_id: u32) -> Option<String>;
+
+    /// 取出统计的通道流量
+    fn take_traffic(&self) -> HashMap<u32, u64>;
+
+    /// 发送事件通知, 不等待发送结果
+    fn send_webhook(&self, event: Event);
+
+    /// 每个玩家作为出口最多拥有的通道数, 为0时不限制
+    fn max_tunnels_per_player(&self) -> u32;
+}
+
+/// 使用全局数据库连接和全局管理器
+struct GlobalTunnelContext;
+
+#[async_trait]
+impl TunnelContext for GlobalTunnelContext {
+    fn db(&self) -> &DatabaseConnection {
+        db()
+    }
+
+    async fn contain_player(&self, player_id: PlayerId) -> bool {
+        GLOBAL_MANAGER.player_manager.contain(player_id).await
+    }
+
+    async fn send_push(&self, player_id: PlayerId, message: &MessageType) {
+        if let Some(player) = GLOBAL_MANAGER.player_manager.get_player(player_id).await {
+            player.read().await.send_notify(message).await;
+        }
+    }
+
+    async fn sync_tunnels(&self) -> anyhow::Result<()> {
+        GLOBAL_MANAGER.proxy_manager.sync_tunnels().await
+    }
+
+    fn tunnel_error(&self, tunnel_id: u32) -> Option<String> {
+        GLOBAL_MANAGER.proxy_manager.tunnel_error(tunnel_id)
+    }
+
+    fn take_traffic(&self) -> HashMap<u32, u64> {
+        GLOBAL_MANAGER.proxy_manager.take_traffic()
+    }
+
+    fn send_webhook(&self, event: Event) {
+        webhook::send_webhook(event);
+    }
+
+    fn max_tunnels_per_player(&self) -> u32 {
+        GLOBAL_CONFIG.max_tunnels_per_player
+    }
+}
+
 pub struct TunnelManager {
     pub tunnels: RwLock<Vec<tunnel::Model>>,
+    context: Box<dyn TunnelContext>,
 }
 
 impl TunnelManager {
     pub fn new() -> Self {
+        Self::with_context(GlobalTunnelContext)
+    }
+
+    pub fn with_context(context: impl TunnelContext + 'static) -> Self {
         Self {
             tunnels: RwLock::new(Vec::new()),
+            context: Box::new(context),
         }
     }
 
     pub async fn load_all_tunnel(&self) -> anyhow::Result<()> {
-        (*self.tunnels.write().await) = Tunnel::find().all(GLOBAL_DB_POOL.get().unwrap()).await?;
+        let tunnels = Tunnel::find().all(self.context.db()).await?;
+
+        // 旧数据中的明文密码仍可使用, 只提示通过离线命令转换, 不自动改写数据库
+        let plaintext = tunnels
+            .iter()
+            .filter(|x| {
+                x.key_derivation == 0
+                    && !x.password.is_empty()
+                    && !crypto::is_password_hash(&x.password)
+            })
+            .count();
+        if plaintext > 0 {
+            warn!("{plaintext} tunnel(s) still store plaintext passwords, run `tunnel hash-passwords` to convert them");
+        }
+
+        (*self.tunnels.write().await) = tunnels;
         Ok(())
     }
 
-    /// 增加通道
-    pub async fn add_tunnel(&self, mut tunnel: tunnel::Model) -> anyhow::Result<()> {
+    /// 将旧数据中的明文密码转为哈希, 返回涉及的通道id
+    ///
+    /// `dry_run` 为true时只返回需要转换的通道, 不修改数据库
+    pub async fn hash_plaintext_passwords(&self, dry_run: bool) -> anyhow::Result<Vec<u32>> {
+        let mut tunnels = self.tunnels.write().await;
+        let mut converted = Vec::new();
+        for tunnel in tunnels.iter_mut() {
+            let mut hashed = tunnel.clone();
+            hash_tunnel_password(&mut hashed);
+            if hashed.password == tunnel.password {
+                continue;
+            }
+            converted.push(tunnel.id);
+            if dry_run {
+                continue;
+            }
+            let mut db_tunnel: tunnel::ActiveModel = tunnel.clone().into();
+            db_tunnel.password = Set(hashed.password.clone());
+            db_tunnel.update(self.context.db()).await?;
+            tunnel.password = hashed.password;
+        }
+        Ok(converted)
+    }
+
+    /// 检查已启用的本机入口是否与服务器自身的监听地址冲突, 需要在任何监听开始前调用
+    pub async fn check_server_listen_conflict(&self) -> anyhow::Result<()> {
+        for tunnel in self.tunnels.read().await.iter().filter(|x| x.enabled == 1) {
+            tunnel
+                .check_encryption_methods()
+                .map_err(|err| anyhow!("tunnel({}) {err}", tunnel.id))?;
+            // Linux网卡名称最长15个字节
+            if tunnel.bind_device.len() > 15
+                || tunnel
+                    .bind_device
+                    .contains(|c: char| c == '/' || c.is_whitespace())
+            {
+                return Err(anyhow!("invalid bind device name '{}'", tunnel.bind_device));
+            }
+            if let Some((name, addr)) = tunnel.server_listen_conflict() {
+                return Err(anyhow!(
+                    "tunnel({}) source '{}' conflicts with {name} '{addr}'",
+                    tunnel.id,
+                    tunnel.source
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 增加通道, 返回新通道的id
+    pub async fn add_tunnel(&self, mut tunnel: tunnel::Model) -> anyhow::Result<u32> {
+        tunnel.source = normalize_tunnel_source_address(&tunnel.source);
         self.tunnel_detection(&tunnel).await?;
+        self.tunnel_quota_detection(&tunnel, None).await?;
+        hash_tunnel_password(&mut tunnel);
 
         let new_tunnel = tunnel::ActiveModel {
             id: Default::default(),
@@ -48,34 +227,71 @@ impl TunnelManager {
             is_compressed: Set(tunnel.is_compressed),
             custom_mapping: Set(tunnel.custom_mapping.to_owned()),
             encryption_method: Set(tunnel.encryption_method.to_owned()),
+            paused: Set(tunnel.paused),
+            max_session_lifetime: Set(tunnel.max_session_lifetime),
+            o2i_is_compressed: Set(tunnel.o2i_is_compressed),
+            o2i_encryption_method: Set(tunnel.o2i_encryption_method.to_owned()),
+            accept_proxy_protocol: Set(tunnel.accept_proxy_protocol),
+            bytes_quota: Set(tunnel.bytes_quota),
+            bytes_used: Set(tunnel.bytes_used),
+            extra_senders: Set(tunnel.extra_senders.to_owned()),
+            write_coalesce: Set(tunnel.write_coalesce),
+            connect_retries: Set(tunnel.connect_retries),
+            connect_retry_delay: Set(tunnel.connect_retry_delay),
+            conn_rate_limit: Set(tunnel.conn_rate_limit),
+            conn_rate_burst: Set(tunnel.conn_rate_burst),
+            bind_device: Set(tunnel.bind_device.to_owned()),
+            balance_policy: Set(tunnel.balance_policy),
+            tls_cert: Set(tunnel.tls_cert.to_owned()),
+            tls_key: Set(tunnel.tls_key.to_owned()),
+            tls_alpn: Set(tunnel.tls_alpn.to_owned()),
+            backend_tls: Set(tunnel.backend_tls),
+            compression_dictionary: Set(tunnel.compression_dictionary.to_owned()),
+            auth_url: Set(tunnel.auth_url.to_owned()),
+            pool_size: Set(tunnel.pool_size),
+            pool_max_idle: Set(tunnel.pool_max_idle),
+            frame_prefix: Set(tunnel.frame_prefix),
+            reject_response: Set(tunnel.reject_response.to_owned()),
+            outlet_buffer_limit: Set(tunnel.outlet_buffer_limit),
+            external_id: Set(tunnel.external_id.to_owned()),
+            key_derivation: Set(tunnel.key_derivation),
+            reverse_stream: Set(tunnel.reverse_stream),
+            outlet_weights: Set(tunnel.outlet_weights.to_owned()),
+            inlet_nodelay: Set(tunnel.inlet_nodelay),
+            outlet_nodelay: Set(tunnel.outlet_nodelay),
+            compress_threshold: Set(tunnel.compress_threshold),
+            fast_open: Set(tunnel.fast_open),
+            assigned_port: Set(0),
+            encryption_fallback: Set(tunnel.encryption_fallback),
         };
 
-        let new_tunnel = new_tunnel.insert(GLOBAL_DB_POOL.get().unwrap()).await?;
+        let new_tunnel = new_tunnel.insert(self.context.db()).await?;
         tunnel.id = new_tunnel.id;
+        let tunnel_id = tunnel.id;
 
-        Self::broadcast_tunnel_info(tunnel.sender, &tunnel, false).await;
-        if tunnel.sender != tunnel.receiver {
-            Self::broadcast_tunnel_info(tunnel.receiver, &tunnel, false).await;
-        }
+        self.broadcast_tunnel(&tunnel, false).await;
+        self.context.send_webhook(Event::tunnel_created(&tunnel));
         self.tunnels.write().await.push(tunnel);
 
-        GLOBAL_MANAGER.proxy_manager.sync_tunnels().await;
+        self.sync_proxies(tunnel_id).await?;
 
-        Ok(())
+        Ok(tunnel_id)
     }
 
     /// 删除通道
+    ///
+    /// 通道已经不存在时也算成功, 方便超时后重试的调用方
     pub async fn delete_tunnel(&self, tunnel_id: u32) -> anyhow::Result<()> {
         let rows_affected = Tunnel::delete_by_id(tunnel_id)
-            .exec(GLOBAL_DB_POOL.get().unwrap())
+            .exec(self.context.db())
             .await?
             .rows_affected;
 
-        anyhow::ensure!(
-            rows_affected == 1,
-            "delete_tunnel: rows_affected = {}",
-            rows_affected
-        );
+        if rows_affected == 0 {
+            debug!("delete_tunnel: tunnel({tunnel_id}) is already deleted");
+        } else {
+            self.context.send_webhook(Event::tunnel_deleted(tunnel_id));
+        }
 
         let position = {
             self.tunnels
@@ -86,19 +302,18 @@ impl TunnelManager {
         };
         if let Some(index) = position {
             let tunnel = self.tunnels.write().await.remove(index);
-            Self::broadcast_tunnel_info(tunnel.sender, &tunnel, true).await;
-            if tunnel.sender != tunnel.receiver {
-                Self::broadcast_tunnel_info(tunnel.receiver, &tunnel, true).await;
-            }
+            self.broadcast_tunnel(&tunnel, true).await;
 
-            GLOBAL_MANAGER.proxy_manager.sync_tunnels().await;
+            let _ = self.context.sync_tunnels().await;
         }
-        return Ok(());
+        Ok(())
     }
 
     /// 更新通道
-    pub async fn update_tunnel(&self, tunnel: tunnel::Model) -> anyhow::Result<()> {
+    pub async fn update_tunnel(&self, mut tunnel: tunnel::Model) -> anyhow::Result<()> {
+        tunnel.source = normalize_tunnel_source_address(&tunnel.source);
         self.tunnel_detection(&tunnel).await?;
+        hash_tunnel_password(&mut tunnel);
 
         let position = {
             self.tunnels
@@ -109,9 +324,23 @@ impl TunnelManager {
         };
 
         if let Some(index) = position {
-            let db_tunnel = Tunnel::find_by_id(tunnel.id)
-                .one(GLOBAL_DB_POOL.get().unwrap())
-                .await?;
+            // 新加入出口的玩家需要检查通道数量上限
+            let current = self.tunnels.read().await[index].clone();
+            self.tunnel_quota_detection(&tunnel, Some(&current)).await?;
+
+            // 已使用流量只由流量统计修改
+            tunnel.bytes_used = self.tunnels.read().await[index].bytes_used;
+            // 分配的端口只由入口启动时修改, 入口地址变化后入口会重启
+            tunnel.assigned_port = {
+                let current = &self.tunnels.read().await[index];
+                if current.source == tunnel.source {
+                    current.assigned_port
+                } else {
+                    0
+                }
+            };
+
+            let db_tunnel = Tunnel::find_by_id(tunnel.id).one(self.context.db()).await?;
             anyhow::ensure!(db_tunnel.is_some(), "Can't find tunnel: {}", tunnel.id);
 
             let mut db_tunnel: tunnel::ActiveModel = db_tunnel.unwrap().into();
@@ -127,70 +356,442 @@ impl TunnelManager {
             db_tunnel.is_compressed = Set(tunnel.is_compressed.to_owned());
             db_tunnel.custom_mapping = Set(tunnel.custom_mapping.to_owned());
             db_tunnel.encryption_method = Set(tunnel.encryption_method.to_owned());
-            db_tunnel.update(GLOBAL_DB_POOL.get().unwrap()).await?;
+            db_tunnel.paused = Set(tunnel.paused);
+            db_tunnel.max_session_lifetime = Set(tunnel.max_session_lifetime);
+            db_tunnel.o2i_is_compressed = Set(tunnel.o2i_is_compressed);
+            db_tunnel.o2i_encryption_method = Set(tunnel.o2i_encryption_method.to_owned());
+            db_tunnel.accept_proxy_protocol = Set(tunnel.accept_proxy_protocol);
+            db_tunnel.bytes_quota = Set(tunnel.bytes_quota);
+            db_tunnel.extra_senders = Set(tunnel.extra_senders.to_owned());
+            db_tunnel.write_coalesce = Set(tunnel.write_coalesce);
+            db_tunnel.connect_retries = Set(tunnel.connect_retries);
+            db_tunnel.connect_retry_delay = Set(tunnel.connect_retry_delay);
+            db_tunnel.conn_rate_limit = Set(tunnel.conn_rate_limit);
+            db_tunnel.conn_rate_burst = Set(tunnel.conn_rate_burst);
+            db_tunnel.bind_device = Set(tunnel.bind_device.to_owned());
+            db_tunnel.balance_policy = Set(tunnel.balance_policy);
+            db_tunnel.tls_cert = Set(tunnel.tls_cert.to_owned());
+            db_tunnel.tls_key = Set(tunnel.tls_key.to_owned());
+            db_tunnel.tls_alpn = Set(tunnel.tls_alpn.to_owned());
+            db_tunnel.backend_tls = Set(tunnel.backend_tls);
+            db_tunnel.compression_dictionary = Set(tunnel.compression_dictionary.to_owned());
+            db_tunnel.auth_url = Set(tunnel.auth_url.to_owned());
+            db_tunnel.pool_size = Set(tunnel.pool_size);
+            db_tunnel.pool_max_idle = Set(tunnel.pool_max_idle);
+            db_tunnel.frame_prefix = Set(tunnel.frame_prefix);
+            db_tunnel.reject_response = Set(tunnel.reject_response.to_owned());
+            db_tunnel.outlet_buffer_limit = Set(tunnel.outlet_buffer_limit);
+            db_tunnel.external_id = Set(tunnel.external_id.to_owned());
+            db_tunnel.key_derivation = Set(tunnel.key_derivation);
+            db_tunnel.reverse_stream = Set(tunnel.reverse_stream);
+            db_tunnel.outlet_weights = Set(tunnel.outlet_weights.to_owned());
+            db_tunnel.inlet_nodelay = Set(tunnel.inlet_nodelay);
+            db_tunnel.outlet_nodelay = Set(tunnel.outlet_nodelay);
+            db_tunnel.compress_threshold = Set(tunnel.compress_threshold);
+            db_tunnel.fast_open = Set(tunnel.fast_open);
+            db_tunnel.assigned_port = Set(tunnel.assigned_port);
+            db_tunnel.encryption_fallback = Set(tunnel.encryption_fallback);
+            db_tunnel.update(self.context.db()).await?;
+
+            // 不再与通道相关的玩家收到删除通知
+            let removed_players = self.tunnels.read().await[index].removed_players(&tunnel);
+            for player_id in removed_players {
+                self.broadcast_tunnel_info(player_id, &tunnel, true).await;
+            }
+            self.broadcast_tunnel(&tunnel, false).await;
+            self.context.send_webhook(Event::tunnel_updated(&tunnel));
 
-            let old_sender = self.tunnels.read().await[index].sender;
-            let old_receiver = self.tunnels.read().await[index].receiver;
+            let tunnel_id = tunnel.id;
+            self.tunnels.write().await[index] = tunnel;
+            self.sync_proxies(tunnel_id).await?;
+            return Ok(());
+        }
+        Err(anyhow!(format!("Unable to find tunnel_id: {}", tunnel.id)))
+    }
+
+    /// 按external_id更新已有的通道, 不存在时新增, 返回通道id和是否为新增
+    ///
+    /// 重复提交同一组通道配置时不会产生重复的通道, 方便用配置文件声明式管理
+    pub async fn apply_tunnel(&self, mut tunnel: tunnel::Model) -> anyhow::Result<(u32, bool)> {
+        if tunnel.external_id.is_empty() {
+            return Err(anyhow!("external_id is required"));
+        }
+        let current = {
+            self.tunnels
+                .read()
+                .await
+                .iter()
+                .find(|it| it.external_id == tunnel.external_id)
+                .map(|it| it.id)
+        };
+        match current {
+            Some(tunnel_id) => {
+                tunnel.id = tunnel_id;
+                self.update_tunnel(tunnel).await?;
+                Ok((tunnel_id, false))
+            }
+            None => Ok((self.add_tunnel(tunnel).await?, true)),
+        }
+    }
+
+    /// 暂停/恢复通道, 暂停时不再接受新连接, 已有连接不受影响
+    pub async fn set_tunnel_paused(&self, tunnel_id: u32, paused: bool) -> anyhow::Result<()> {
+        let tunnel = {
+            self.tunnels
+                .read()
+                .await
+                .iter()
+                .find(|it| it.id == tunnel_id)
+                .cloned()
+        };
+        let Some(mut tunnel) = tunnel else {
+            return Err(anyhow!("Unable to find tunnel_id: {}", tunnel_id));
+        };
+        tunnel.paused = paused as u8;
+        self.update_tunnel(tunnel).await
+    }
 
-            if old_sender != tunnel.sender {
-                Self::broadcast_tunnel_info(old_sender, &tunnel, true).await;
+    /// 记录自动分配端口的入口实际监听的端口, 变化时写入数据库并通知相关玩家
+    pub async fn set_assigned_port(&self, tunnel_id: u32, port: u16) -> anyhow::Result<()> {
+        let tunnel = {
+            let mut tunnels = self.tunnels.write().await;
+            let Some(tunnel) = tunnels.iter_mut().find(|it| it.id == tunnel_id) else {
+                return Err(anyhow!("Unable to find tunnel_id: {}", tunnel_id));
+            };
+            if tunnel.assigned_port == port as u32 {
+                return Ok(());
             }
-            if old_receiver != tunnel.sender {
-                Self::broadcast_tunnel_info(old_receiver, &tunnel, true).await;
+            tunnel.assigned_port = port as u32;
+            tunnel.clone()
+        };
+
+        tunnel::ActiveModel {
+            id: Unchanged(tunnel_id),
+            assigned_port: Set(port as u32),
+            ..Default::default()
+        }
+        .update(self.context.db())
+        .await?;
+        self.broadcast_tunnel(&tunnel, false).await;
+        Ok(())
+    }
+
+    /// 将统计的流量累加到通道并写入数据库, 通道流量超过配额时停止接受新连接并通知玩家
+    pub async fn flush_traffic(&self) -> anyhow::Result<()> {
+        let traffic = self.context.take_traffic();
+        if traffic.is_empty() {
+            return Ok(());
+        }
+
+        let mut updated = Vec::new();
+        let mut exceeded = Vec::new();
+        for tunnel in self.tunnels.write().await.iter_mut() {
+            let Some(bytes) = traffic.get(&tunnel.id) else {
+                continue;
+            };
+            let over_quota = tunnel.is_over_quota();
+            tunnel.bytes_used = tunnel.bytes_used.saturating_add(*bytes as i64);
+            updated.push((tunnel.id, tunnel.bytes_used));
+            if !over_quota && tunnel.is_over_quota() {
+                exceeded.push(tunnel.clone());
             }
-            Self::broadcast_tunnel_info(tunnel.sender, &tunnel, false).await;
-            if tunnel.sender != tunnel.receiver {
-                Self::broadcast_tunnel_info(tunnel.receiver, &tunnel, false).await;
+        }
+
+        for (tunnel_id, bytes_used) in updated {
+            tunnel::ActiveModel {
+                id: Unchanged(tunnel_id),
+                bytes_used: Set(bytes_used),
+                ..Default::default()
             }
+            .update(self.context.db())
+            .await?;
+        }
 
-            self.tunnels.write().await[index] = tunnel;
-            GLOBAL_MANAGER.proxy_manager.sync_tunnels().await;
+        if exceeded.is_empty() {
             return Ok(());
         }
-        Err(anyhow!(format!("Unable to find tunnel_id: {}", tunnel.id)))
+        for tunnel in exceeded.iter() {
+            warn!(
+                "tunnel({}) exceeded its traffic quota: {}/{} bytes",
+                tunnel.id, tunnel.bytes_used, tunnel.bytes_quota
+            );
+            let ntf = MessageType::ServerClientTunnelQuotaNtf(server_client::TunnelQuotaNtf {
+                tunnel_id: tunnel.id,
+                bytes_used: tunnel.bytes_used as u64,
+                bytes_quota: tunnel.bytes_quota as u64,
+            });
+            for player_id in tunnel.players() {
+                self.notify_player(player_id, &ntf).await;
+            }
+            self.broadcast_tunnel(tunnel, false).await;
+        }
+        let _ = self.context.sync_tunnels().await;
+        Ok(())
     }
 
-    /// 广播通道修改通知
-    async fn broadcast_tunnel_info(player_id: PlayerId, tunnel: &tunnel::Model, is_delete: bool) {
+    /// 清零通道已使用的流量
+    pub async fn reset_traffic(&self, tunnel_id: u32) -> anyhow::Result<()> {
+        let tunnel = {
+            let mut tunnels = self.tunnels.write().await;
+            let Some(tunnel) = tunnels.iter_mut().find(|it| it.id == tunnel_id) else {
+                return Err(anyhow!("Unable to find tunnel_id: {}", tunnel_id));
+            };
+            let over_quota = tunnel.is_over_quota();
+            tunnel.bytes_used = 0;
+            over_quota.then(|| tunnel.clone())
+        };
+
+        tunnel::ActiveModel {
+            id: Unchanged(tunnel_id),
+            bytes_used: Set(0),
+            ..Default::default()
+        }
+        .update(self.context.db())
+        .await?;
+
+        // 恢复因超过配额而停止的通道
+        if let Some(tunnel) = tunnel {
+            self.broadcast_tunnel(&tunnel, false).await;
+            let _ = self.context.sync_tunnels().await;
+        }
+        Ok(())
+    }
+
+    async fn notify_player(&self, player_id: PlayerId, message: &MessageType) {
         if player_id != 0 {
-            if let Some(player) = GLOBAL_MANAGER.player_manager.get_player(player_id).await {
-                let _ = player
-                    .read()
-                    .await
-                    .send_push(&MessageType::ServerClientModifyTunnelNtf(
-                        server_client::ModifyTunnelNtf {
-                            is_delete,
-                            tunnel: Some(tunnel.into()),
-                        },
-                    ))
-                    .await;
+            self.context.send_push(player_id, message).await;
+        }
+    }
+
+    /// 与玩家相关的所有通道, 用于登录和查询时下发
+    pub async fn player_tunnel_list(&self, player_id: PlayerId) -> Vec<class_def::Tunnel> {
+        self.tunnels
+            .read()
+            .await
+            .iter()
+            .filter(|x| x.players().contains(&player_id))
+            .map(|x| x.to_class_def(player_id, self.context.tunnel_error(x.id)))
+            .collect()
+    }
+
+    /// 向通道相关的所有玩家广播通道修改通知
+    pub(crate) async fn broadcast_tunnel(&self, tunnel: &tunnel::Model, is_delete: bool) {
+        for player_id in tunnel.players() {
+            self.broadcast_tunnel_info(player_id, tunnel, is_delete)
+                .await;
+        }
+    }
+
+    /// 同步本机代理, 只在指定通道启动失败时返回错误
+    ///
+    /// 其他通道的失败已记录日志并标记为错误状态, 不影响本次修改的结果
+    async fn sync_proxies(&self, tunnel_id: u32) -> anyhow::Result<()> {
+        if self.context.sync_tunnels().await.is_err() {
+            if let Some(err) = self.context.tunnel_error(tunnel_id) {
+                return Err(anyhow!(
+                    "tunnel({tunnel_id}) saved but failed to start: {err}"
+                ));
             }
         }
+        Ok(())
+    }
+
+    /// 广播通道修改通知
+    async fn broadcast_tunnel_info(
+        &self,
+        player_id: PlayerId,
+        tunnel: &tunnel::Model,
+        is_delete: bool,
+    ) {
+        self.notify_player(
+            player_id,
+            &MessageType::ServerClientModifyTunnelNtf(server_client::ModifyTunnelNtf {
+                is_delete,
+                tunnel: Some(tunnel.to_class_def(player_id, self.context.tunnel_error(tunnel.id))),
+            }),
+        )
+        .await;
+    }
+
+    /// 检查玩家作为出口的通道数是否已达到上限, 服务器不受限制
+    /// 检查通道的出口玩家(sender和extra_senders)是否超过通道数量上限
+    ///
+    /// 更新通道时只检查新加入出口的玩家, 已经是出口的玩家不会因为修改其他字段被拒绝
+    async fn tunnel_quota_detection(
+        &self,
+        tunnel: &tunnel::Model,
+        current: Option<&tunnel::Model>,
+    ) -> anyhow::Result<()> {
+        let limit = self.context.max_tunnels_per_player();
+        if limit == 0 {
+            return Ok(());
+        }
+        let current_players = current.map(|x| x.outlet_players()).unwrap_or_default();
+        let tunnels = self.tunnels.read().await;
+        for player_id in tunnel.outlet_players() {
+            if player_id == 0 || current_players.contains(&player_id) {
+                continue;
+            }
+            let owned = tunnels
+                .iter()
+                .filter(|x| x.id != tunnel.id && x.outlet_players().contains(&player_id))
+                .count();
+            if owned >= limit as usize {
+                return Err(TunnelError::QuotaExceeded { player_id, limit }.into());
+            }
+        }
+        Ok(())
     }
 
     async fn tunnel_detection(&self, tunnel: &tunnel::Model) -> anyhow::Result<()> {
         // 地址合法性检测
-        if !is_valid_tunnel_source_address(&tunnel.source) {
-            return Err(anyhow!("source address format error"));
+        let source = parse_tunnel_source_address(&tunnel.source)
+            .map_err(|err| anyhow!("source address '{}': {err}", tunnel.source))?;
+        if let Some((name, addr)) = tunnel.server_listen_conflict() {
+            return Err(anyhow!(
+                "source address conflicts with server {name} '{addr}'"
+            ));
         }
 
-        if !is_valid_tunnel_endpoint_address(&tunnel.endpoint) {
-            return Err(anyhow!("endpoint address format error"));
+        // 按主机名路由的通道可以不设置默认后端
+        let is_vhost = InletProxyType::from_u32(tunnel.tunnel_type).is_some_and(|x| x.is_vhost());
+        let allow_empty = is_vhost && tunnel.endpoint.is_empty();
+        if !allow_empty {
+            parse_tunnel_endpoint_address(&tunnel.endpoint)
+                .map_err(|err| anyhow!("endpoint address '{}': {err}", tunnel.endpoint))?;
         }
 
         // 玩家id检测
         self.player_id_detection(tunnel.sender).await?;
         self.player_id_detection(tunnel.receiver).await?;
+        for player_id in tunnel.extra_senders() {
+            self.player_id_detection(player_id).await?;
+        }
+        if BalancePolicy::from_u32(tunnel.balance_policy).is_none() {
+            return Err(anyhow!("invalid balance_policy: {}", tunnel.balance_policy));
+        }
+        let outlet_players = tunnel.outlet_players();
+        for (player_id, weight) in parse_outlet_weights(&tunnel.outlet_weights)? {
+            if !outlet_players.contains(&player_id) {
+                return Err(anyhow!(
+                    "outlet_weights: player({player_id}) is not an outlet of the tunnel"
+                ));
+            }
+            if weight == 0 || weight > MAX_OUTLET_WEIGHT {
+                return Err(anyhow!(
+                    "outlet_weights: weight of player({player_id}) must be between 1 and {MAX_OUTLET_WEIGHT}"
+                ));
+            }
+        }
+        if !tunnel.external_id.is_empty()
+            && self
+                .tunnels
+                .read()
+                .await
+                .iter()
+                .any(|x| x.id != tunnel.id && x.external_id == tunnel.external_id)
+        {
+            return Err(anyhow!(
+                "external_id '{}' is already used by another tunnel",
+                tunnel.external_id
+            ));
+        }
+
+        tunnel.check_encryption_methods()?;
+        if KeyDerivation::from_u32(tunnel.key_derivation).is_none() {
+            return Err(anyhow!("unknown key_derivation: {}", tunnel.key_derivation));
+        }
+        if tunnel.key_derivation != 0 && tunnel.password.is_empty() {
+            return Err(anyhow!("key_derivation requires a tunnel password"));
+        }
+        // 哈希无法还原出两端共用的密码, 需要重新设置
+        if tunnel.key_derivation != 0 && crypto::is_password_hash(&tunnel.password) {
+            return Err(anyhow!(
+                "key_derivation requires the tunnel password to be set again"
+            ));
+        }
 
-        // 端口冲突检测
-        if self
-            .port_conflict_detection(
-                tunnel.receiver,
-                get_tunnel_address_port(&tunnel.source),
-                Some(tunnel.id),
+        // 服务器上的出口可以直接转发, 不需要反向连接
+        if tunnel.reverse_stream == 1 && tunnel.outlet_players().contains(&0) {
+            return Err(anyhow!("reverse_stream requires all outlets to be players"));
+        }
+
+        // 证书文件在入口所在的机器上, 这里只检查配置是否完整
+        if tunnel.tls_cert.is_empty() != tunnel.tls_key.is_empty() {
+            return Err(anyhow!("tls_cert and tls_key must be set together"));
+        }
+        if !tunnel.tls_cert.is_empty()
+            && !matches!(
+                InletProxyType::from_u32(tunnel.tunnel_type),
+                Some(InletProxyType::TCP | InletProxyType::HTTP | InletProxyType::SOCKS5)
             )
+        {
+            return Err(anyhow!(
+                "TLS termination only supports TCP, HTTP and SOCKS5 tunnels"
+            ));
+        }
+        if tunnel.backend_tls > 2 {
+            return Err(anyhow!("invalid backend_tls: {}", tunnel.backend_tls));
+        }
+        if !tunnel.auth_url.is_empty() {
+            if !matches!(
+                InletProxyType::from_u32(tunnel.tunnel_type),
+                Some(InletProxyType::SOCKS5)
+            ) {
+                return Err(anyhow!("auth_url only supports SOCKS5 tunnels"));
+            }
+            HttpAuthenticator::new(&tunnel.auth_url)?;
+        }
+        if tunnel.pool_size > MAX_POOL_SIZE {
+            return Err(anyhow!("pool_size must not exceed {MAX_POOL_SIZE}"));
+        }
+        if !tunnel.reject_response.is_empty() {
+            if !matches!(
+                InletProxyType::from_u32(tunnel.tunnel_type),
+                Some(InletProxyType::HTTP | InletProxyType::SOCKS5)
+            ) {
+                return Err(anyhow!(
+                    "reject_response only supports HTTP and SOCKS5 tunnels"
+                ));
+            }
+            RejectResponse::parse(&tunnel.reject_response)?;
+        }
+        match LengthPrefix::from_u32(tunnel.frame_prefix) {
+            None => return Err(anyhow!("invalid frame_prefix: {}", tunnel.frame_prefix)),
+            Some(LengthPrefix::None) => {}
+            Some(_) => {
+                if !matches!(
+                    InletProxyType::from_u32(tunnel.tunnel_type),
+                    Some(InletProxyType::TCP)
+                ) {
+                    return Err(anyhow!("frame_prefix only supports TCP tunnels"));
+                }
+            }
+        }
+        // 文件路径在入口和出口所在的机器上, 这里只检查内联的字典
+        if tunnel.compression_dictionary.trim().starts_with("base64:") {
+            CompressionDictionary::load(&tunnel.compression_dictionary)?;
+        }
+        // 玩家上的入口分配的端口无法回报给服务器
+        if is_auto_port_address(&tunnel.source) && tunnel.receiver != 0 {
+            return Err(anyhow!(
+                "automatic source port is only supported for inlets on the server"
+            ));
+        }
+
+        if let Some(path) = unix_socket_path(&tunnel.source) {
+            // 路径冲突检测
+            if self
+                .path_conflict_detection(tunnel.receiver, path, Some(tunnel.id))
+                .await
+            {
+                return Err(anyhow!("path already in use"));
+            }
+        } else if self
+            .port_conflict_detection(tunnel.receiver, source.port(), Some(tunnel.id))
             .await
         {
+            // 端口冲突检测
             return Err(anyhow!("port already in use"));
         }
         Ok(())
@@ -198,20 +799,23 @@ impl TunnelManager {
 
     /// 检测玩家id是否合法
     async fn player_id_detection(&self, player_id: PlayerId) -> anyhow::Result<()> {
-        if player_id != 0 && !GLOBAL_MANAGER.player_manager.contain(player_id).await {
+        if player_id != 0 && !self.context.contain_player(player_id).await {
             Err(anyhow!("player id {} does not exist", player_id))
         } else {
             Ok(())
         }
     }
 
-    /// 检测端口是否冲突
+    /// 检测端口是否冲突, 由系统分配端口的通道不检测, 已分配的端口按实际端口比较
     async fn port_conflict_detection(
         &self,
         receiver: u32,
         port: Option<u16>,
         tunnel_id: Option<u32>,
     ) -> bool {
+        if port == Some(0) {
+            return false;
+        }
         self.tunnels
             .read()
             .await
@@ -219,15 +823,29 @@ impl TunnelManager {
             .position(|x| {
                 x.receiver == receiver
                     && tunnel_id != Some(x.id)
-                    && get_tunnel_address_port(&x.source) == port
+                    && unix_socket_path(&x.source).is_none()
+                    && x.listen_port() == port
             })
             .is_some()
     }
 
+    /// 检测unix套接字路径是否冲突
+    async fn path_conflict_detection(
+        &self,
+        receiver: u32,
+        path: &str,
+        tunnel_id: Option<u32>,
+    ) -> bool {
+        self.tunnels.read().await.iter().any(|x| {
+            x.receiver == receiver
+                && tunnel_id != Some(x.id)
+                && unix_socket_path(&x.source) == Some(path)
+        })
+    }
+
     /// 查询通道
     pub async fn query(&self, page_number: usize, page_size: usize) -> Vec<tunnel::Model> {
-        let page_number = page_number;
-        let page_size = if page_size <= 0 || page_size > 100 {
+        let page_size = if page_size == 0 || page_size > 100 {
             10
         } else {
             page_size
@@ -240,10 +858,7 @@ impl TunnelManager {
         }
 
         if start <= end && end <= tunnel_num {
-            self.tunnels.read().await[start..end]
-                .iter()
-                .map(|x| x.clone())
-                .collect()
+            self.tunnels.read().await[start..end].to_vec()
         } else {
             vec![]
         }
@@ -251,32 +866,364 @@ impl TunnelManager {
 }
 
 impl tunnel::Model {
+    /// 服务器上的TCP类入口与服务器自身的监听地址冲突时, 返回冲突的配置项和地址
+    pub fn server_listen_conflict(&self) -> Option<(&'static str, SocketAddr)> {
+        let is_udp = matches!(
+            InletProxyType::from_u32(self.tunnel_type),
+            Some(InletProxyType::UDP)
+        );
+        if self.receiver != 0 || is_udp {
+            return None;
+        }
+        let source = self.source.parse::<SocketAddr>().ok()?;
+        GLOBAL_CONFIG
+            .listen_addrs()
+            .into_iter()
+            .find(|(_, addr)| is_listen_addr_overlapped(&source, addr))
+    }
+
+    /// 入口监听的端口, 由系统分配端口时为实际分配的端口, 还没有分配时为None
+    pub fn listen_port(&self) -> Option<u16> {
+        match get_tunnel_address_port(&self.source) {
+            Some(0) if self.assigned_port != 0 => Some(self.assigned_port as u16),
+            Some(0) => None,
+            port => port,
+        }
+    }
+
+    /// 加密方法是偏好列表, 出口到入口方向只能是单个方法, 不认识的名称返回错误
+    pub(crate) fn check_encryption_methods(&self) -> anyhow::Result<()> {
+        crypto::parse_method_list(&self.encryption_method)
+            .map_err(|err| anyhow!("encryption_method: {err}"))?;
+        match self.o2i_encryption_method.as_deref() {
+            Some(method) if !method.is_empty() && crypto::parse_method(method).is_none() => Err(
+                anyhow!("o2i_encryption_method: unknown encryption method: '{method}'"),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// 已使用流量是否超过配额, 配额为0时不限制
+    pub fn is_over_quota(&self) -> bool {
+        self.bytes_quota > 0 && self.bytes_used >= self.bytes_quota
+    }
+
+    /// 通道是否需要运行, 超过配额且配置了关闭会话时停止通道
+    pub fn is_active(&self) -> bool {
+        self.enabled == 1 && !(GLOBAL_CONFIG.quota_close_sessions && self.is_over_quota())
+    }
+
+    /// 通道是否不再接受新连接
+    pub fn is_paused(&self) -> bool {
+        self.paused == 1 || self.is_over_quota()
+    }
+
+    /// 除sender外同时提供出口的玩家
+    pub fn extra_senders(&self) -> Vec<PlayerId> {
+        self.extra_senders
+            .split(',')
+            .filter_map(|x| x.trim().parse().ok())
+            .collect()
+    }
+
+    /// 多出口时新会话选择出口的策略
+    pub(crate) fn balance_policy(&self) -> BalancePolicy {
+        BalancePolicy::from_u32(self.balance_policy).unwrap_or(BalancePolicy::RoundRobin)
+    }
+
+    /// 提供出口的所有玩家, sender在最前
+    pub fn outlet_players(&self) -> Vec<PlayerId> {
+        let mut players = vec![self.sender];
+        for player_id in self.extra_senders() {
+            if !players.contains(&player_id) {
+                players.push(player_id);
+            }
+        }
+        players
+    }
+
+    /// 提供出口的所有玩家及生效的权重, 未设置权重的出口为1
+    pub(crate) fn weighted_outlet_players(&self) -> Vec<(PlayerId, u32)> {
+        let weights: HashMap<PlayerId, u32> = parse_outlet_weights(&self.outlet_weights)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        self.outlet_players()
+            .into_iter()
+            .map(|player_id| (player_id, weights.get(&player_id).copied().unwrap_or(1)))
+            .collect()
+    }
+
+    /// 与通道相关的所有玩家(不包括服务器)
+    pub fn players(&self) -> Vec<PlayerId> {
+        let mut players = self.outlet_players();
+        if !players.contains(&self.receiver) {
+            players.push(self.receiver);
+        }
+        players.retain(|x| *x != 0);
+        players
+    }
+
+    /// 入口和所有出口都是该玩家自己, 玩家只能新增或删除这样的通道
+    ///
+    /// 任何一端是服务器(0)或其他玩家时会在别人的机器上监听或发起连接, 只能由管理员操作
+    pub fn owned_by(&self, player_id: PlayerId) -> bool {
+        player_id != 0
+            && self.receiver == player_id
+            && self.outlet_players().iter().all(|x| *x == player_id)
+    }
+
+    /// 修改为新配置后不再与通道相关的玩家
+    pub fn removed_players(&self, new: &Self) -> Vec<PlayerId> {
+        let players = new.players();
+        self.players()
+            .into_iter()
+            .filter(|x| !players.contains(x))
+            .collect()
+    }
+
     pub fn outlet_description(&self) -> String {
+        let label_routes = if self.is_label() {
+            self.custom_mapping.as_str()
+        } else {
+            ""
+        };
         format!(
-            "id:{}-sender:{}-enabled:{}",
-            self.id, self.sender, self.enabled
+            "id:{}-sender:{}-enabled:{}-compression_dictionary:{}-pool_size:{}-pool_max_idle:{}-frame_prefix:{}-outlet_buffer_limit:{}-key_derivation:{}-outlet_nodelay:{}-fast_open:{}-label_routes:{}-password:{}",
+            self.id,
+            self.sender,
+            self.enabled,
+            self.compression_dictionary,
+            self.pool_size,
+            self.pool_max_idle,
+            self.frame_prefix,
+            self.outlet_buffer_limit,
+            self.key_derivation,
+            self.outlet_nodelay,
+            self.fast_open,
+            label_routes,
+            crypto::password_fingerprint(&self.password),
         )
     }
 
+    fn is_label(&self) -> bool {
+        InletProxyType::from_u32(self.tunnel_type).is_some_and(|x| x.is_label())
+    }
+
+    /// 按标签路由的通道由出口选择后端, 路由表为 `custom_mapping`, 其他类型为空
+    pub fn label_routes(&self) -> HashMap<String, String> {
+        if self.is_label() {
+            serde_json::from_str(&self.custom_mapping).unwrap_or_default()
+        } else {
+            HashMap::new()
+        }
+    }
+
+    /// 入口配置的完整描述, 用于判断配置是否变化, 不要写入日志
     pub fn inlet_description(&self) -> String {
+        self.format_inlet_description(
+            &self.username,
+            &crypto::password_fingerprint(&self.password),
+        )
+    }
+
+    /// 隐去用户名和密码的入口描述, 用于日志
+    pub fn redacted_description(&self) -> String {
+        self.format_inlet_description(redact(&self.username), redact(&self.password))
+    }
+
+    /// 入口中可以在运行中应用的设置, 只有这些变化时不重启入口
+    pub fn inlet_settings_description(&self) -> String {
         format!(
-            "id:{}-source:{}-endpoint:{}-sender:{}-receiver:{}-tunnel_type:{}-username:{}-password:{}-enabled:{}-is_compressed:{}-encryption_method:{}-custom_mapping:{}",
+            "custom_mapping:{}-max_session_lifetime:{}-accept_proxy_protocol:{}-write_coalesce:{}-connect_retries:{}-connect_retry_delay:{}-conn_rate_limit:{}-conn_rate_burst:{}-backend_tls:{}-compression_dictionary:{}-auth_url:{}-frame_prefix:{}-reject_response:{}-key_derivation:{}-compress_threshold:{}",
+            self.custom_mapping,
+            self.max_session_lifetime,
+            self.accept_proxy_protocol,
+            self.write_coalesce,
+            self.connect_retries,
+            self.connect_retry_delay,
+            self.conn_rate_limit,
+            self.conn_rate_burst,
+            self.backend_tls,
+            self.compression_dictionary,
+            self.auth_url,
+            self.frame_prefix,
+            self.reject_response,
+            self.key_derivation,
+            self.compress_threshold,
+        )
+    }
+
+    /// 需要重启入口才能生效的设置
+    fn format_inlet_description(&self, username: &str, password: &str) -> String {
+        format!(
+            "id:{}-source:{}-endpoint:{}-sender:{}-receiver:{}-tunnel_type:{}-username:{}-password:{}-enabled:{}-is_compressed:{}-encryption_method:{}-o2i_is_compressed:{:?}-o2i_encryption_method:{:?}-extra_senders:{}-bind_device:{}-balance_policy:{}-outlet_weights:{}-inlet_nodelay:{}-fast_open:{}-encryption_fallback:{}-tls_cert:{}-tls_key:{}-tls_alpn:{}",
             self.id,
             self.source,
             self.endpoint,
             self.sender,
             self.receiver,
             self.tunnel_type,
-            self.username,
-            self.password,
+            username,
+            password,
             self.enabled,
             self.is_compressed,
             self.encryption_method,
-            self.custom_mapping,
+            self.o2i_is_compressed,
+            self.o2i_encryption_method,
+            self.extra_senders,
+            self.bind_device,
+            self.balance_policy,
+            self.outlet_weights,
+            self.inlet_nodelay,
+            self.fast_open,
+            self.encryption_fallback,
+            self.tls_cert,
+            self.tls_key,
+            self.tls_alpn,
         )
     }
 }
 
+/// 日志中隐去的敏感字段, 未设置时保持为空
+fn redact(value: &str) -> &'static str {
+    if value.is_empty() {
+        ""
+    } else {
+        "***"
+    }
+}
+
+/// 玩家id列表保存为逗号分隔的字符串
+pub(crate) fn join_player_ids(player_ids: &[PlayerId]) -> String {
+    player_ids
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 解析出口权重 `玩家id#权重,玩家id#权重`
+pub(crate) fn parse_outlet_weights(value: &str) -> anyhow::Result<Vec<(PlayerId, u32)>> {
+    value
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|item| {
+            item.split_once('#')
+                .and_then(|(player_id, weight)| {
+                    Some((player_id.trim().parse().ok()?, weight.trim().parse().ok()?))
+                })
+                .ok_or_else(|| {
+                    anyhow!("outlet_weights: invalid item '{item}', expected player_id#weight")
+                })
+        })
+        .collect()
+}
+
+/// 出口权重保存为逗号分隔的 `玩家id#权重`
+pub(crate) fn join_outlet_weights(weights: &[(PlayerId, u32)]) -> String {
+    weights
+        .iter()
+        .map(|(player_id, weight)| format!("{player_id}#{weight}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 保存前将明文密码转为哈希, 已经是哈希的保持不变
+///
+/// 开启会话key派生的通道两端都需要原密码, 不做哈希
+fn hash_tunnel_password(tunnel: &mut tunnel::Model) {
+    if tunnel.key_derivation == 0
+        && !tunnel.password.is_empty()
+        && !crypto::is_password_hash(&tunnel.password)
+    {
+        tunnel.password = crypto::hash_password(&tunnel.password);
+    }
+}
+
+impl tunnel::Model {
+    /// 转为下发给玩家的通道信息, 只有入口所在的玩家需要密码哈希用于认证
+    ///
+    /// 开启会话key派生时密码按配置保存, 入口和出口都用它计算预共享密钥
+    ///
+    /// 下发的启用/暂停状态包含流量配额的影响
+    ///
+    /// `error` 为本机代理启动失败的原因
+    pub fn to_class_def(&self, player_id: PlayerId, error: Option<String>) -> class_def::Tunnel {
+        let mut tunnel: class_def::Tunnel = self.into();
+        tunnel.error = error.unwrap_or_default();
+        tunnel.enabled = self.is_active();
+        tunnel.paused = self.is_paused();
+        if player_id != self.receiver && self.key_derivation == 0 {
+            tunnel.password.clear();
+        }
+        tunnel
+    }
+}
+
+impl From<&class_def::Tunnel> for tunnel::Model {
+    fn from(tunnel: &class_def::Tunnel) -> Self {
+        Self {
+            id: tunnel.id,
+            source: tunnel
+                .source
+                .as_ref()
+                .map_or(String::new(), |x| x.addr.clone()),
+            endpoint: tunnel
+                .endpoint
+                .as_ref()
+                .map_or(String::new(), |x| x.addr.clone()),
+            enabled: tunnel.enabled as u8,
+            sender: tunnel.sender,
+            receiver: tunnel.receiver,
+            description: String::new(),
+            tunnel_type: tunnel.tunnel_type as u32,
+            password: tunnel.password.clone(),
+            username: tunnel.username.clone(),
+            is_compressed: tunnel.is_compressed as u8,
+            custom_mapping: serde_json::to_string(&tunnel.custom_mapping).unwrap_or_default(),
+            encryption_method: tunnel.encryption_method.clone(),
+            paused: tunnel.paused as u8,
+            max_session_lifetime: tunnel.max_session_lifetime,
+            o2i_is_compressed: tunnel.o2i_is_compressed.map(|x| x as u8),
+            o2i_encryption_method: tunnel.o2i_encryption_method.clone(),
+            accept_proxy_protocol: tunnel.accept_proxy_protocol as u8,
+            bytes_quota: 0,
+            bytes_used: 0,
+            extra_senders: join_player_ids(&tunnel.extra_senders),
+            write_coalesce: tunnel.write_coalesce,
+            connect_retries: tunnel.connect_retries,
+            connect_retry_delay: tunnel.connect_retry_delay,
+            conn_rate_limit: tunnel.conn_rate_limit,
+            conn_rate_burst: tunnel.conn_rate_burst,
+            bind_device: tunnel.bind_device.clone(),
+            balance_policy: tunnel.balance_policy,
+            tls_cert: tunnel.tls_cert.clone(),
+            tls_key: tunnel.tls_key.clone(),
+            tls_alpn: tunnel.tls_alpn.clone(),
+            backend_tls: tunnel.backend_tls,
+            compression_dictionary: tunnel.compression_dictionary.clone(),
+            auth_url: tunnel.auth_url.clone(),
+            pool_size: tunnel.pool_size,
+            pool_max_idle: tunnel.pool_max_idle,
+            frame_prefix: tunnel.frame_prefix,
+            reject_response: tunnel.reject_response.clone(),
+            outlet_buffer_limit: tunnel.outlet_buffer_limit,
+            external_id: tunnel.external_id.clone(),
+            key_derivation: tunnel.key_derivation,
+            reverse_stream: tunnel.reverse_stream as u8,
+            outlet_weights: tunnel.outlet_weights.clone(),
+            inlet_nodelay: tunnel.inlet_nodelay as u8,
+            outlet_nodelay: tunnel.outlet_nodelay as u8,
+            compress_threshold: tunnel.compress_threshold,
+            fast_open: tunnel.fast_open as u8,
+            assigned_port: tunnel.assigned_port,
+            encryption_fallback: tunnel.encryption_fallback as u8,
+        }
+    }
+}
+
 impl From<&tunnel::Model> for class_def::Tunnel {
     fn from(tunnel: &tunnel::Model) -> Self {
         let custom_mapping: HashMap<String, String> =
@@ -299,6 +1246,374 @@ impl From<&tunnel::Model> for class_def::Tunnel {
             is_compressed: tunnel.is_compressed == 1,
             encryption_method: tunnel.encryption_method.clone(),
             custom_mapping,
+            paused: tunnel.paused == 1,
+            max_session_lifetime: tunnel.max_session_lifetime,
+            o2i_is_compressed: tunnel.o2i_is_compressed.map(|x| x == 1),
+            o2i_encryption_method: tunnel.o2i_encryption_method.clone(),
+            accept_proxy_protocol: tunnel.accept_proxy_protocol == 1,
+            extra_senders: tunnel.extra_senders(),
+            write_coalesce: tunnel.write_coalesce,
+            connect_retries: tunnel.connect_retries,
+            connect_retry_delay: tunnel.connect_retry_delay,
+            conn_rate_limit: tunnel.conn_rate_limit,
+            conn_rate_burst: tunnel.conn_rate_burst,
+            bind_device: tunnel.bind_device.clone(),
+            balance_policy: tunnel.balance_policy,
+            tls_cert: tunnel.tls_cert.clone(),
+            tls_key: tunnel.tls_key.clone(),
+            tls_alpn: tunnel.tls_alpn.clone(),
+            backend_tls: tunnel.backend_tls,
+            compression_dictionary: tunnel.compression_dictionary.clone(),
+            auth_url: tunnel.auth_url.clone(),
+            pool_size: tunnel.pool_size,
+            pool_max_idle: tunnel.pool_max_idle,
+            frame_prefix: tunnel.frame_prefix,
+            reject_response: tunnel.reject_response.clone(),
+            outlet_buffer_limit: tunnel.outlet_buffer_limit,
+            external_id: tunnel.external_id.clone(),
+            key_derivation: tunnel.key_derivation,
+            reverse_stream: tunnel.reverse_stream == 1,
+            outlet_weights: tunnel.outlet_weights.clone(),
+            inlet_nodelay: tunnel.inlet_nodelay == 1,
+            outlet_nodelay: tunnel.outlet_nodelay == 1,
+            compress_threshold: tunnel.compress_threshold,
+            fast_open: tunnel.fast_open == 1,
+            assigned_port: tunnel.assigned_port,
+            encryption_fallback: tunnel.encryption_fallback == 1,
+            over_quota: tunnel.is_over_quota(),
+            error: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global::init_test_db;
+    use std::sync::{Arc, Mutex};
+
+    /// 记录推送的通道修改通知(玩家id, 是否删除)
+    type Pushes = Arc<Mutex<Vec<(PlayerId, bool)>>>;
+
+    struct MockContext {
+        db: &'static DatabaseConnection,
+        players: Vec<PlayerId>,
+        pushes: Pushes,
+        max_tunnels_per_player: u32,
+    }
+
+    #[async_trait]
+    impl TunnelContext for MockContext {
+        fn db(&self) -> &DatabaseConnection {
+            self.db
+        }
+
+        async fn contain_player(&self, player_id: PlayerId) -> bool {
+            self.players.contains(&player_id)
+        }
+
+        async fn send_push(&self, player_id: PlayerId, message: &MessageType) {
+            if let MessageType::ServerClientModifyTunnelNtf(ntf) = message {
+                self.pushes.lock().unwrap().push((player_id, ntf.is_delete));
+            }
+        }
+
+        async fn sync_tunnels(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn tunnel_error(&self, _tunnel_id: u32) -> Option<String> {
+            None
+        }
+
+        fn take_traffic(&self) -> HashMap<u32, u64> {
+            HashMap::new()
+        }
+
+        fn send_webhook(&self, _event: Event) {}
+
+        fn max_tunnels_per_player(&self) -> u32 {
+            self.max_tunnels_per_player
+        }
+    }
+
+    async fn new_test_manager(players: &[PlayerId]) -> (TunnelManager, Pushes) {
+        new_test_manager_with_limit(players, 0).await
+    }
+
+    async fn new_test_manager_with_limit(
+        players: &[PlayerId],
+        max_tunnels_per_player: u32,
+    ) -> (TunnelManager, Pushes) {
+        let pushes = Pushes::default();
+        let context = MockContext {
+            db: init_test_db().await,
+            players: players.to_vec(),
+            pushes: pushes.clone(),
+            max_tunnels_per_player,
+        };
+        (TunnelManager::with_context(context), pushes)
+    }
+
+    /// 服务器作为入口和出口的TCP通道
+    fn new_test_tunnel(port: u16) -> tunnel::Model {
+        let mut tunnel = tunnel::Model::from(&class_def::Tunnel::default());
+        tunnel.source = format!("0.0.0.0:{port}");
+        tunnel.endpoint = "127.0.0.1:80".into();
+        tunnel.enabled = 1;
+        tunnel.encryption_method = "None".into();
+        tunnel
+    }
+
+    /// 直接写入数据库和通道列表, 不做合法性检查
+    async fn insert_test_tunnel(manager: &TunnelManager, mut tunnel: tunnel::Model) -> u32 {
+        let mut active: tunnel::ActiveModel = tunnel.clone().into();
+        active.id = Default::default();
+        tunnel.id = active.insert(manager.context.db()).await.unwrap().id;
+        manager.tunnels.write().await.push(tunnel.clone());
+        tunnel.id
+    }
+
+    #[test]
+    fn test_owned_by() {
+        let mut tunnel = new_test_tunnel(39100);
+        assert!(!tunnel.owned_by(0));
+        tunnel.sender = 7;
+        tunnel.receiver = 7;
+        assert!(tunnel.owned_by(7));
+        assert!(!tunnel.owned_by(8));
+
+        // 任何一端是服务器或其他玩家都不属于该玩家, 额外出口也一样
+        tunnel.receiver = 0;
+        assert!(!tunnel.owned_by(7));
+        tunnel.receiver = 7;
+        tunnel.extra_senders = "7, 8".into();
+        assert!(!tunnel.owned_by(7));
+        tunnel.extra_senders = "0".into();
+        assert!(!tunnel.owned_by(7));
+        tunnel.extra_senders = "7".into();
+        assert!(tunnel.owned_by(7));
+    }
+
+    #[tokio::test]
+    async fn test_add_tunnel_conflict() {
+        let (manager, _) = new_test_manager(&[39103]).await;
+        insert_test_tunnel(&manager, new_test_tunnel(39101)).await;
+
+        let err = manager
+            .add_tunnel(new_test_tunnel(39101))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "port already in use");
+        let err = manager.add_tunnel(new_test_tunnel(8118)).await.unwrap_err();
+        assert!(err.to_string().contains("conflicts with server"));
+        let mut tunnel = new_test_tunnel(39102);
+        tunnel.receiver = 39102;
+        let err = manager.add_tunnel(tunnel.clone()).await.unwrap_err();
+        assert_eq!(err.to_string(), "player id 39102 does not exist");
+        assert_eq!(manager.tunnels.read().await.len(), 1);
+
+        // 不同玩家的入口可以使用相同的端口
+        tunnel.receiver = 39103;
+        tunnel.source = "0.0.0.0:39101".into();
+        let tunnel_id = manager.add_tunnel(tunnel).await.unwrap();
+        let saved = Tunnel::find_by_id(tunnel_id)
+            .one(manager.context.db())
+            .await
+            .unwrap();
+        assert_eq!(saved.map(|x| x.receiver), Some(39103));
+        assert_eq!(manager.tunnels.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_add_tunnel_encryption_methods() {
+        let (manager, _) = new_test_manager(&[]).await;
+        let mut tunnel = new_test_tunnel(39111);
+        tunnel.encryption_method = "Aes128, Xor,None".into();
+        manager.add_tunnel(tunnel).await.unwrap();
+
+        let mut tunnel = new_test_tunnel(39112);
+        tunnel.encryption_method = "Aes128,Aes256".into();
+        let err = manager.add_tunnel(tunnel.clone()).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "encryption_method: unknown encryption method: 'Aes256'"
+        );
+        tunnel.encryption_method = "Aes128".into();
+        tunnel.o2i_encryption_method = Some("Xor,None".into());
+        assert!(manager.add_tunnel(tunnel).await.is_err());
+        assert_eq!(manager.tunnels.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_add_tunnel_quota() {
+        let (manager, _) = new_test_manager_with_limit(&[39401, 39402], 2).await;
+        let new_tunnel = |port: u16, sender: PlayerId| {
+            let mut tunnel = new_test_tunnel(port);
+            tunnel.sender = sender;
+            tunnel
+        };
+        manager.add_tunnel(new_tunnel(39401, 39401)).await.unwrap();
+        // 达到上限前的最后一个
+        manager.add_tunnel(new_tunnel(39402, 39401)).await.unwrap();
+
+        // 正好达到上限时拒绝
+        let err = manager
+            .add_tunnel(new_tunnel(39403, 39401))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TunnelError>(),
+            Some(&TunnelError::QuotaExceeded {
+                player_id: 39401,
+                limit: 2
+            })
+        );
+        assert_eq!(manager.tunnels.read().await.len(), 2);
+
+        // 只统计作为出口的通道, 其他玩家和服务器不受影响
+        let mut tunnel = new_tunnel(39404, 39402);
+        tunnel.receiver = 39401;
+        manager.add_tunnel(tunnel).await.unwrap();
+        for port in 39405..39408 {
+            manager.add_tunnel(new_tunnel(port, 0)).await.unwrap();
         }
+
+        // 作为额外出口的通道也计入数量
+        let mut tunnel = new_tunnel(39408, 0);
+        tunnel.extra_senders = "39402".into();
+        manager.add_tunnel(tunnel.clone()).await.unwrap();
+        tunnel.source = "0.0.0.0:39409".into();
+        assert!(manager.add_tunnel(tunnel).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_tunnel_quota() {
+        let (manager, _) = new_test_manager_with_limit(&[39411, 39412], 1).await;
+        let mut owned = new_test_tunnel(39411);
+        owned.sender = 39411;
+        owned.id = manager.add_tunnel(owned.clone()).await.unwrap();
+        let mut shared = new_test_tunnel(39412);
+        shared.external_id = "shared".into();
+        shared.id = manager.add_tunnel(shared.clone()).await.unwrap();
+
+        // 已经是出口的玩家修改其他字段不受上限影响
+        owned.endpoint = "127.0.0.1:81".into();
+        manager.update_tunnel(owned.clone()).await.unwrap();
+
+        // 新加入出口的玩家超过上限时拒绝, 更新和按external_id应用都一样
+        let mut tunnel = shared.clone();
+        tunnel.sender = 39411;
+        assert!(manager.update_tunnel(tunnel).await.is_err());
+        let mut tunnel = shared.clone();
+        tunnel.id = 0;
+        tunnel.extra_senders = "39411".into();
+        let err = manager.apply_tunnel(tunnel.clone()).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TunnelError>(),
+            Some(&TunnelError::QuotaExceeded {
+                player_id: 39411,
+                limit: 1
+            })
+        );
+        assert_eq!(manager.tunnels.read().await[1].extra_senders, "");
+
+        // 其他玩家还没有达到上限
+        tunnel.extra_senders = "39412".into();
+        manager.apply_tunnel(tunnel).await.unwrap();
+        assert_eq!(manager.tunnels.read().await[1].extra_senders, "39412");
+    }
+
+    #[tokio::test]
+    async fn test_update_tunnel_players() {
+        let (manager, pushes) = new_test_manager(&[39201, 39202, 39203]).await;
+        let mut tunnel = new_test_tunnel(39201);
+        tunnel.sender = 39201;
+        tunnel.receiver = 39202;
+        tunnel.id = insert_test_tunnel(&manager, tunnel.clone()).await;
+
+        // 出口从39201换到39203, 入口不变
+        let mut new_tunnel = tunnel.clone();
+        new_tunnel.sender = 39203;
+        assert_eq!(tunnel.removed_players(&new_tunnel), vec![39201]);
+        manager.update_tunnel(new_tunnel.clone()).await.unwrap();
+        assert_eq!(
+            *pushes.lock().unwrap(),
+            vec![(39201, true), (39203, false), (39202, false)]
+        );
+        let saved = Tunnel::find_by_id(tunnel.id)
+            .one(manager.context.db())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((saved.sender, saved.receiver), (39203, 39202));
+        assert_eq!(manager.tunnels.read().await[0].sender, 39203);
+
+        // 出口和入口互换时没有玩家需要删除通知
+        let mut swapped = new_tunnel.clone();
+        swapped.sender = new_tunnel.receiver;
+        swapped.receiver = new_tunnel.sender;
+        assert!(new_tunnel.removed_players(&swapped).is_empty());
+
+        // 改为服务器时不通知玩家0
+        let mut local = new_tunnel.clone();
+        local.sender = 0;
+        local.receiver = 0;
+        assert_eq!(new_tunnel.removed_players(&local), vec![39203, 39202]);
+
+        let mut unknown = new_tunnel.clone();
+        unknown.id = u32::MAX;
+        assert!(manager.update_tunnel(unknown).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hash_plaintext_passwords() {
+        let (manager, _) = new_test_manager(&[]).await;
+        let mut tunnel = new_test_tunnel(39501);
+        tunnel.password = "plain".into();
+        let tunnel_id = insert_test_tunnel(&manager, tunnel).await;
+        insert_test_tunnel(&manager, new_test_tunnel(39502)).await;
+        // 派生会话key的通道保留原密码
+        let mut tunnel = new_test_tunnel(39503);
+        tunnel.password = "psk".into();
+        tunnel.key_derivation = 1;
+        insert_test_tunnel(&manager, tunnel).await;
+
+        // 预览时不修改数据
+        let ids = manager.hash_plaintext_passwords(true).await.unwrap();
+        assert_eq!(ids, vec![tunnel_id]);
+        assert_eq!(manager.tunnels.read().await[0].password, "plain");
+
+        let ids = manager.hash_plaintext_passwords(false).await.unwrap();
+        assert_eq!(ids, vec![tunnel_id]);
+        let saved = Tunnel::find_by_id(tunnel_id)
+            .one(manager.context.db())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(crypto::verify_password(&saved.password, b"plain"));
+        assert_eq!(manager.tunnels.read().await[0].password, saved.password);
+        assert!(manager
+            .hash_plaintext_passwords(false)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_tunnel() {
+        let (manager, _) = new_test_manager(&[]).await;
+        let tunnel_id = insert_test_tunnel(&manager, new_test_tunnel(39301)).await;
+
+        manager.delete_tunnel(tunnel_id).await.unwrap();
+        assert!(manager.tunnels.read().await.is_empty());
+        let saved = Tunnel::find_by_id(tunnel_id)
+            .one(manager.context.db())
+            .await;
+        assert!(saved.unwrap().is_none());
+
+        // 重复删除也算成功
+        manager.delete_tunnel(tunnel_id).await.unwrap();
+        assert!(manager.tunnels.read().await.is_empty());
     }
 }
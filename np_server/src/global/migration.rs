@@ -0,0 +1,773 @@
+use crate::orm_entity::{tunnel, user};
+use log::info;
+use np_base::proxy::inlet::DEFAULT_COMPRESS_THRESHOLD;
+use np_base::proxy::outlet::DEFAULT_BUFFER_LIMIT;
+use sea_orm::sea_query::{
+    Alias, ColumnDef, Expr, Query, Table, TableAlterStatement, TableCreateStatement,
+};
+use sea_orm::{ConnectionTrait, DatabaseConnection, Schema};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 记录已执行迁移的表名, 与SeaORM迁移工具保持一致
+const MIGRATION_TABLE: &str = "seaql_migrations";
+
+/// 迁移步骤
+enum Step {
+    /// 创建表(已存在则跳过)
+    CreateTable(TableCreateStatement),
+    /// 添加字段(已存在则跳过)
+    AddColumn(&'static str, &'static str, TableAlterStatement),
+}
+
+struct Migration {
+    version: &'static str,
+    steps: fn(&Schema) -> Vec<Step>,
+}
+
+/// 所有迁移, 按版本顺序追加, 已发布的迁移不要修改
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: "m20240601_000001_create_tables",
+        steps: |schema| {
+            vec![
+                Step::CreateTable(
+                    schema
+                        .create_table_from_entity(user::Entity)
+                        .if_not_exists()
+                        .to_owned(),
+                ),
+                Step::CreateTable(
+                    schema
+                        .create_table_from_entity(tunnel::Entity)
+                        .if_not_exists()
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000001_add_tunnel_paused",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "paused",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::Paused)
+                            .tiny_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000002_add_tunnel_max_session_lifetime",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "max_session_lifetime",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::MaxSessionLifetime)
+                            .unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000003_add_tunnel_o2i_codec",
+        steps: |_| {
+            vec![
+                Step::AddColumn(
+                    "tunnel",
+                    "o2i_is_compressed",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::O2iIsCompressed)
+                                .tiny_unsigned()
+                                .null(),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "o2i_encryption_method",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::O2iEncryptionMethod)
+                                .string()
+                                .null(),
+                        )
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000004_add_tunnel_accept_proxy_protocol",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "accept_proxy_protocol",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::AcceptProxyProtocol)
+                            .tiny_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000005_add_tunnel_traffic_quota",
+        steps: |_| {
+            vec![
+                Step::AddColumn(
+                    "tunnel",
+                    "bytes_quota",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::BytesQuota)
+                                .big_integer()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "bytes_used",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::BytesUsed)
+                                .big_integer()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000006_add_tunnel_extra_senders",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "extra_senders",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::ExtraSenders)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000007_add_tunnel_write_coalesce",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "write_coalesce",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::WriteCoalesce)
+                            .unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000008_add_tunnel_connect_retry",
+        steps: |_| {
+            vec![
+                Step::AddColumn(
+                    "tunnel",
+                    "connect_retries",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::ConnectRetries)
+                                .unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "connect_retry_delay",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::ConnectRetryDelay)
+                                .unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000009_add_tunnel_conn_rate_limit",
+        steps: |_| {
+            vec![
+                Step::AddColumn(
+                    "tunnel",
+                    "conn_rate_limit",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::ConnRateLimit)
+                                .unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "conn_rate_burst",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::ConnRateBurst)
+                                .unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000010_add_tunnel_bind_device",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "bind_device",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::BindDevice)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000011_add_tunnel_balance_policy",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "balance_policy",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::BalancePolicy)
+                            .unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000012_add_tunnel_tls",
+        steps: |_| {
+            vec![
+                Step::AddColumn(
+                    "tunnel",
+                    "tls_cert",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::TlsCert)
+                                .string()
+                                .not_null()
+                                .default(""),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "tls_key",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::TlsKey)
+                                .string()
+                                .not_null()
+                                .default(""),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "tls_alpn",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::TlsAlpn)
+                                .string()
+                                .not_null()
+                                .default(""),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "backend_tls",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::BackendTls)
+                                .unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000013_add_tunnel_compression_dictionary",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "compression_dictionary",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::CompressionDictionary)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000014_add_tunnel_auth_url",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "auth_url",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::AuthUrl)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000015_add_tunnel_connection_pool",
+        steps: |_| {
+            vec![
+                Step::AddColumn(
+                    "tunnel",
+                    "pool_size",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::PoolSize)
+                                .unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "pool_max_idle",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::PoolMaxIdle)
+                                .unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000016_add_tunnel_frame_prefix",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "frame_prefix",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::FramePrefix)
+                            .unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000017_add_tunnel_reject_response",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "reject_response",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::RejectResponse)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000018_add_tunnel_outlet_buffer_limit",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "outlet_buffer_limit",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::OutletBufferLimit)
+                            .unsigned()
+                            .not_null()
+                            .default(DEFAULT_BUFFER_LIMIT),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000019_add_tunnel_external_id",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "external_id",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::ExternalId)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000020_add_tunnel_key_derivation",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "key_derivation",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::KeyDerivation)
+                            .unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000021_add_tunnel_reverse_stream",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "reverse_stream",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::ReverseStream)
+                            .tiny_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000022_add_tunnel_outlet_weights",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "outlet_weights",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::OutletWeights)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000023_add_tunnel_nodelay",
+        steps: |_| {
+            vec![
+                Step::AddColumn(
+                    "tunnel",
+                    "inlet_nodelay",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::InletNodelay)
+                                .tiny_unsigned()
+                                .not_null()
+                                .default(1),
+                        )
+                        .to_owned(),
+                ),
+                Step::AddColumn(
+                    "tunnel",
+                    "outlet_nodelay",
+                    Table::alter()
+                        .table(tunnel::Entity)
+                        .add_column(
+                            ColumnDef::new(tunnel::Column::OutletNodelay)
+                                .tiny_unsigned()
+                                .not_null()
+                                .default(0),
+                        )
+                        .to_owned(),
+                ),
+            ]
+        },
+    },
+    Migration {
+        version: "m20261015_000024_add_tunnel_compress_threshold",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "compress_threshold",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::CompressThreshold)
+                            .unsigned()
+                            .not_null()
+                            .default(DEFAULT_COMPRESS_THRESHOLD),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000025_add_tunnel_fast_open",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "fast_open",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::FastOpen)
+                            .tiny_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000026_add_tunnel_assigned_port",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "assigned_port",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::AssignedPort)
+                            .unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+    Migration {
+        version: "m20261015_000027_add_tunnel_encryption_fallback",
+        steps: |_| {
+            vec![Step::AddColumn(
+                "tunnel",
+                "encryption_fallback",
+                Table::alter()
+                    .table(tunnel::Entity)
+                    .add_column(
+                        ColumnDef::new(tunnel::Column::EncryptionFallback)
+                            .tiny_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )]
+        },
+    },
+];
+
+/// 执行所有未执行的迁移, 返回当前的迁移版本
+pub(crate) async fn run_migrations(db: &DatabaseConnection) -> anyhow::Result<String> {
+    let backend = db.get_database_backend();
+    let schema = Schema::new(backend);
+
+    db.execute(
+        backend.build(
+            Table::create()
+                .table(Alias::new(MIGRATION_TABLE))
+                .if_not_exists()
+                .col(
+                    ColumnDef::new(Alias::new("version"))
+                        .string()
+                        .not_null()
+                        .primary_key(),
+                )
+                .col(
+                    ColumnDef::new(Alias::new("applied_at"))
+                        .big_integer()
+                        .not_null(),
+                ),
+        ),
+    )
+    .await?;
+
+    let applied = applied_versions(db).await?;
+    for migration in MIGRATIONS {
+        if applied.iter().any(|x| x == migration.version) {
+            continue;
+        }
+
+        for step in (migration.steps)(&schema) {
+            match step {
+                Step::CreateTable(statement) => {
+                    db.execute(backend.build(&statement)).await?;
+                }
+                Step::AddColumn(table, column, statement) => {
+                    if !column_exists(db, table, column).await {
+                        db.execute(backend.build(&statement)).await?;
+                    }
+                }
+            }
+        }
+
+        let applied_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        db.execute(
+            backend.build(
+                Query::insert()
+                    .into_table(Alias::new(MIGRATION_TABLE))
+                    .columns([Alias::new("version"), Alias::new("applied_at")])
+                    .values_panic([migration.version.into(), applied_at.into()]),
+            ),
+        )
+        .await?;
+        info!("Applied database migration: {}", migration.version);
+    }
+
+    Ok(current_version(db).await?.unwrap_or_default())
+}
+
+/// 当前的迁移版本, 未执行过迁移时返回None
+pub(crate) async fn current_version(db: &DatabaseConnection) -> anyhow::Result<Option<String>> {
+    if !table_exists(db, MIGRATION_TABLE).await {
+        return Ok(None);
+    }
+    Ok(applied_versions(db).await?.into_iter().max())
+}
+
+async fn applied_versions(db: &DatabaseConnection) -> anyhow::Result<Vec<String>> {
+    let backend = db.get_database_backend();
+    let rows = db
+        .query_all(
+            backend.build(
+                Query::select()
+                    .column(Alias::new("version"))
+                    .from(Alias::new(MIGRATION_TABLE)),
+            ),
+        )
+        .await?;
+    let mut versions = Vec::with_capacity(rows.len());
+    for row in rows {
+        versions.push(row.try_get::<String>("", "version")?);
+    }
+    Ok(versions)
+}
+
+async fn table_exists(db: &DatabaseConnection, table: &str) -> bool {
+    let statement = Query::select()
+        .expr(Expr::val(1))
+        .from(Alias::new(table))
+        .limit(1)
+        .to_owned();
+    db.query_one(db.get_database_backend().build(&statement))
+        .await
+        .is_ok()
+}
+
+/// 检查表中是否存在指定字段
+pub(crate) async fn column_exists(db: &DatabaseConnection, table: &str, column: &str) -> bool {
+    // 使用带表名的列, sqlite中不存在的单独列名会被当作字符串常量
+    let statement = Query::select()
+        .column((Alias::new(table), Alias::new(column)))
+        .from(Alias::new(table))
+        .limit(1)
+        .to_owned();
+    db.query_one(db.get_database_backend().build(&statement))
+        .await
+        .is_ok()
+}
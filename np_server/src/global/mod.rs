@@ -1,29 +1,112 @@
 use crate::global::config::GLOBAL_CONFIG;
 use crate::global::logger::init_logger;
 use crate::global::manager::GLOBAL_MANAGER;
+use crate::global::webhook::Webhook;
 use crate::orm_entity::{tunnel, user};
-use sea_orm::sea_query::{MysqlQueryBuilder, PostgresQueryBuilder, SqliteQueryBuilder};
-use sea_orm::{
-    ConnectOptions, ConnectionTrait, Database, DatabaseConnection, DbBackend, Schema, Statement,
+use anyhow::anyhow;
+use log::{error, info};
+use np_base::net::tcp_server::StreamReceiverType;
+use np_base::net::BoxedStream;
+use np_base::proxy::allowlist::DestinationAllowlist;
+use np_base::proxy::geoip::GeoDatabase;
+use np_base::proxy::{
+    default_global_buffer_budget, set_global_buffer_budget, set_global_pool_limit,
 };
+use sea_orm::{ConnectOptions, Database, DatabaseConnection, EntityTrait, IdenStatic, Iterable};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::OnceCell;
 
 pub mod config;
+pub(crate) mod event;
 pub mod logger;
 pub mod manager;
+mod migration;
 pub mod opts;
+pub(crate) mod syslog;
+pub(crate) mod webhook;
 
 pub(crate) static GLOBAL_DB_POOL: OnceCell<DatabaseConnection> = OnceCell::const_new();
 
+/// 全局数据库连接, 需要在全局初始化之后使用
+pub(crate) fn db() -> &'static DatabaseConnection {
+    GLOBAL_DB_POOL.get().expect("database not initialized")
+}
+
+/// 单元测试使用的内存数据库, 已执行迁移
+///
+/// 同时进入离线模式, 修改通道时不会启动代理
+#[cfg(test)]
+pub(crate) async fn init_test_db() -> &'static DatabaseConnection {
+    use sqlx::Connection;
+
+    const TEST_DATABASE_URL: &str = "sqlite:file:npipe_test?mode=memory&cache=shared";
+
+    GLOBAL_OFFLINE_MODE.store(true, Ordering::Release);
+    GLOBAL_DB_POOL
+        .get_or_init(|| async {
+            // 每个测试的运行时结束时连接池的连接可能被关闭, 保留一个连接避免内存数据库被释放
+            let keeper = sqlx::SqliteConnection::connect(TEST_DATABASE_URL)
+                .await
+                .expect("Test database initialization failed");
+            std::mem::forget(keeper);
+
+            let mut opt = ConnectOptions::new(TEST_DATABASE_URL);
+            opt.max_connections(1).sqlx_logging(false);
+            let db = Database::connect(opt)
+                .await
+                .expect("Test database initialization failed");
+            migration::run_migrations(&db)
+                .await
+                .expect("Test database migration failed");
+            db
+        })
+        .await
+}
+
+/// 全局初始化是否完成
+pub(crate) static GLOBAL_INIT_FINISHED: AtomicBool = AtomicBool::new(false);
+
+/// tcp服务器是否已开始监听
+pub(crate) static GLOBAL_TCP_SERVER_LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// web服务器是否已开始监听
+pub(crate) static GLOBAL_WEB_SERVER_LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// 离线管理模式, 只操作数据库, 不启动代理
+pub(crate) static GLOBAL_OFFLINE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// 通过WebSocket连接的控制通道, 由web服务器转交给TCP服务器
+pub(crate) static GLOBAL_CONTROL_STREAMS: OnceLock<UnboundedSender<(BoxedStream, SocketAddr)>> =
+    OnceLock::new();
+
+/// 创建WebSocket控制通道的转交队列, 返回TCP服务器的接收端
+pub(crate) fn init_control_streams() -> Option<StreamReceiverType> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    GLOBAL_CONTROL_STREAMS.set(tx).ok()?;
+    Some(rx)
+}
+
+/// 地理位置数据库, 没有配置时为空
+pub(crate) static GLOBAL_GEO_DATABASE: OnceLock<Arc<GeoDatabase>> = OnceLock::new();
+
+/// 事件通知, 没有配置地址时为空
+pub(crate) static GLOBAL_WEBHOOK: OnceLock<Webhook> = OnceLock::new();
+
+/// 出口允许连接的目标地址白名单
+pub(crate) static GLOBAL_OUTLET_ALLOWLIST: OnceLock<DestinationAllowlist> = OnceLock::new();
+
 pub(crate) async fn init_global() -> anyhow::Result<()> {
     init_logger()?;
 
-    let mut opt = ConnectOptions::new(&GLOBAL_CONFIG.database_url);
-    opt.max_connections(100)
-        .min_connections(5)
-        .connect_timeout(Duration::from_secs(8))
-        .acquire_timeout(Duration::from_secs(8))
+    let mut opt = ConnectOptions::new(GLOBAL_CONFIG.database_url.expose());
+    opt.max_connections(GLOBAL_CONFIG.database_max_connections)
+        .min_connections(GLOBAL_CONFIG.database_min_connections)
+        .connect_timeout(Duration::from_secs(GLOBAL_CONFIG.database_connect_timeout))
+        .acquire_timeout(Duration::from_secs(GLOBAL_CONFIG.database_acquire_timeout))
         .idle_timeout(Duration::from_secs(8))
         .max_lifetime(Duration::from_secs(8))
         .sqlx_logging(true)
@@ -38,74 +121,112 @@ pub(crate) async fn init_global() -> anyhow::Result<()> {
                 .expect("Database initialization failed")
         })
         .await;
-    let db = GLOBAL_DB_POOL.get().unwrap();
-    let backend = db.get_database_backend();
-    let schema = Schema::new(backend);
-
-    match backend {
-        DbBackend::MySql => {
-            db.execute(Statement::from_string(
-                backend,
-                schema
-                    .create_table_from_entity(user::Entity)
-                    .if_not_exists()
-                    .to_string(MysqlQueryBuilder),
-            ))
-            .await?;
-            db.execute(Statement::from_string(
-                backend,
-                schema
-                    .create_table_from_entity(tunnel::Entity)
-                    .if_not_exists()
-                    .to_string(MysqlQueryBuilder),
-            ))
-            .await?;
-        }
-        DbBackend::Postgres => {
-            db.execute(Statement::from_string(
-                backend,
-                schema
-                    .create_table_from_entity(user::Entity)
-                    .if_not_exists()
-                    .to_string(PostgresQueryBuilder),
-            ))
-            .await?;
-            db.execute(Statement::from_string(
-                backend,
-                schema
-                    .create_table_from_entity(tunnel::Entity)
-                    .if_not_exists()
-                    .to_string(PostgresQueryBuilder),
-            ))
-            .await?;
-        }
-        DbBackend::Sqlite => {
-            db.execute(Statement::from_string(
-                backend,
-                schema
-                    .create_table_from_entity(user::Entity)
-                    .if_not_exists()
-                    .to_string(SqliteQueryBuilder),
-            ))
-            .await?;
-            db.execute(Statement::from_string(
-                backend,
-                schema
-                    .create_table_from_entity(tunnel::Entity)
-                    .if_not_exists()
-                    .to_string(SqliteQueryBuilder),
-            ))
-            .await?;
-        }
+    let db = db();
+
+    // 自动迁移, 表结构由外部管理时可在配置中关闭
+    if GLOBAL_CONFIG.auto_migrate {
+        let version = migration::run_migrations(db).await?;
+        info!("Database migration version: {version}");
+    } else {
+        let version = migration::current_version(db).await?;
+        info!(
+            "Database auto migration disabled, current version: {}",
+            version.as_deref().unwrap_or("none")
+        );
     }
 
+    // 表结构检查, 避免旧数据库缺少字段导致运行时才出错
+    check_table_schema(db, tunnel::Entity).await?;
+    check_table_schema(db, user::Entity).await?;
+
     // 加载所有通道信息
     GLOBAL_MANAGER.tunnel_manager.load_all_tunnel().await?;
 
+    // 离线管理模式不启动监听, 允许通过命令行修正冲突的通道
+    if !GLOBAL_OFFLINE_MODE.load(Ordering::Acquire) {
+        GLOBAL_MANAGER
+            .tunnel_manager
+            .check_server_listen_conflict()
+            .await?;
+    }
+
     // 加载所有的玩家信息
     GLOBAL_MANAGER.player_manager.load_all_player().await?;
 
-    GLOBAL_MANAGER.proxy_manager.sync_tunnels().await;
+    if !GLOBAL_CONFIG.geoip_database.is_empty() && !GLOBAL_OFFLINE_MODE.load(Ordering::Acquire) {
+        let database = GeoDatabase::load(&GLOBAL_CONFIG.geoip_database)?;
+        info!("Loaded {} geoip records", database.len());
+        let _ = GLOBAL_GEO_DATABASE.set(Arc::new(database));
+    }
+
+    if !GLOBAL_CONFIG.webhook_urls.is_empty() && !GLOBAL_OFFLINE_MODE.load(Ordering::Acquire) {
+        let webhook = Webhook::start(
+            &GLOBAL_CONFIG.webhook_urls,
+            GLOBAL_CONFIG.webhook_secret.expose(),
+            GLOBAL_CONFIG.webhook_max_retries,
+            GLOBAL_CONFIG.webhook_queue_size,
+        )?;
+        info!(
+            "Webhook enabled for {} urls",
+            GLOBAL_CONFIG.webhook_urls.len()
+        );
+        let _ = GLOBAL_WEBHOOK.set(webhook);
+    }
+
+    let allowlist = DestinationAllowlist::parse(&GLOBAL_CONFIG.outlet_allowed_destinations)
+        .map_err(|err| anyhow!("outlet_allowed_destinations: {err}"))?;
+    if !allowlist.is_empty() {
+        info!(
+            "Outlet destinations restricted to {} allowlist rules",
+            GLOBAL_CONFIG.outlet_allowed_destinations.len()
+        );
+    }
+    let _ = GLOBAL_OUTLET_ALLOWLIST.set(allowlist);
 
+    let budget = match GLOBAL_CONFIG.global_buffer_budget {
+        0 => default_global_buffer_budget(),
+        mb => mb * 1024 * 1024,
+    };
+    set_global_buffer_budget(budget);
+    info!("Global buffer budget: {} MB", budget / 1024 / 1024);
+    set_global_pool_limit(GLOBAL_CONFIG.global_pool_limit);
+
+    // 启动失败的通道已标记为错误状态, 不影响服务器启动
+    let _ = GLOBAL_MANAGER.proxy_manager.sync_tunnels().await;
+
+    GLOBAL_INIT_FINISHED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// 退出前清理全局资源
+pub(crate) async fn shutdown_global() {
+    if let Some(db) = GLOBAL_DB_POOL.get() {
+        if let Err(err) = db.clone().close().await {
+            error!("Failed to close database pool: {err}");
+        } else {
+            info!("Database pool closed");
+        }
+    }
+}
+
+/// 检查数据库表是否包含实体定义的全部字段
+async fn check_table_schema<E: EntityTrait>(
+    db: &DatabaseConnection,
+    entity: E,
+) -> anyhow::Result<()> {
+    let mut missing_columns = Vec::new();
+    for column in E::Column::iter() {
+        if !migration::column_exists(db, entity.table_name(), column.as_str()).await {
+            missing_columns.push(column.as_str().to_string());
+        }
+    }
+
+    if !missing_columns.is_empty() {
+        return Err(anyhow!(
+            "table `{}` does not match the entity, missing columns: {}",
+            entity.table_name(),
+            missing_columns.join(", ")
+        ));
+    }
     Ok(())
 }
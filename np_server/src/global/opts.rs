@@ -1,7 +1,10 @@
-use clap::Parser;
+use crate::utils::str::parse_byte_size;
+use clap::{Parser, Subcommand};
+use np_base::proxy::inlet::DEFAULT_COMPRESS_THRESHOLD;
+use np_base::proxy::outlet::DEFAULT_BUFFER_LIMIT;
 use once_cell::sync::Lazy;
 
-pub static GLOBAL_OPTS: Lazy<Opts> = Lazy::new(|| Opts::parse());
+pub static GLOBAL_OPTS: Lazy<Opts> = Lazy::new(Opts::parse);
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -10,8 +13,8 @@ pub struct Opts {
     #[arg(short, long, default_value_t = false, action = clap::ArgAction::Set)]
     pub backtrace: bool,
 
-    /// Config file
-    #[arg(short, long, default_value = "config.json")]
+    /// Config file (.json/.toml/.yaml/.yml)
+    #[arg(short, long, alias = "config", default_value = "config.json")]
     pub config_file: String,
 
     /// Set log level  warn
@@ -21,4 +24,177 @@ pub struct Opts {
     /// Set log level
     #[arg(long, default_value = "error")]
     pub base_log_level: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// 只在启动时解析一次, 不需要为变体大小装箱
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage tunnels in the database without starting the server
+    #[command(subcommand)]
+    Tunnel(TunnelCommand),
+    /// Run encrypt/decrypt round-trips with an encryption method and print the throughput
+    CryptoSelftest {
+        /// Encryption method (None, Aes128, Xor)
+        #[arg(long, default_value = "Aes128")]
+        method: String,
+        /// Size of each message, e.g. 4096, 64KiB, 1MiB
+        #[arg(long, default_value = "1MiB", value_parser = parse_byte_size)]
+        size: usize,
+        /// Number of round-trips, each with a new key and random data
+        #[arg(long, default_value_t = 10)]
+        iterations: u32,
+    },
+    /// Run as a Windows service, used as the start command of the service
+    #[cfg(all(windows, feature = "windows-service"))]
+    RunService,
+}
+
+// 只在启动时解析一次, 不需要为变体大小装箱
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum TunnelCommand {
+    /// Add a tunnel
+    Add {
+        /// Inlet address, use port 0 or `auto` to let the system pick one
+        #[arg(long)]
+        source: String,
+        /// Outlet address
+        #[arg(long, default_value = "")]
+        endpoint: String,
+        /// Player id of the outlet side, 0 means the server
+        #[arg(long, default_value_t = 0)]
+        sender: u32,
+        /// Player id of the inlet side, 0 means the server
+        #[arg(long, default_value_t = 0)]
+        receiver: u32,
+        /// Tunnel type (0:TCP 1:UDP 2:SOCKS5 4:HTTPS 5:HTTP 6:DNS)
+        #[arg(long, default_value_t = 0)]
+        tunnel_type: u32,
+        #[arg(long, default_value = "")]
+        description: String,
+        #[arg(long, default_value = "")]
+        username: String,
+        #[arg(long, default_value = "")]
+        password: String,
+        /// Compress data
+        #[arg(long, default_value_t = false)]
+        compressed: bool,
+        /// Encryption method, or a comma separated preference list such as Aes128,Xor,None
+        #[arg(long, default_value = "None")]
+        encryption_method: String,
+        /// Add the tunnel disabled
+        #[arg(long, default_value_t = false)]
+        disabled: bool,
+        /// Max session lifetime in seconds, 0 means unlimited
+        #[arg(long, default_value_t = 0)]
+        max_session_lifetime: u32,
+        /// Accept a PROXY protocol header in front of each inlet connection
+        #[arg(long, default_value_t = false)]
+        accept_proxy_protocol: bool,
+        /// Traffic quota in bytes, 0 means unlimited
+        #[arg(long, default_value_t = 0)]
+        bytes_quota: i64,
+        /// Additional outlet player ids sharing the load with the sender (comma separated)
+        #[arg(long, value_delimiter = ',')]
+        extra_senders: Vec<u32>,
+        /// Coalesce small writes within this window in milliseconds, 0 disables it
+        #[arg(long, default_value_t = 0)]
+        write_coalesce: u32,
+        /// Retries when the outlet fails to connect to the endpoint, 0 disables it
+        #[arg(long, default_value_t = 0)]
+        connect_retries: u32,
+        /// Delay between connect retries in milliseconds
+        #[arg(long, default_value_t = 0)]
+        connect_retry_delay: u32,
+        /// New connections accepted per second, 0 means unlimited
+        #[arg(long, default_value_t = 0)]
+        conn_rate_limit: u32,
+        /// Burst of new connections allowed, 0 means the same as the rate
+        #[arg(long, default_value_t = 0)]
+        conn_rate_burst: u32,
+        /// Network interface the inlet binds to (Linux only, requires CAP_NET_RAW)
+        #[arg(long, default_value = "")]
+        bind_device: String,
+        /// Policy for picking among multiple outlets: 0 round-robin, 1 source-hash
+        #[arg(long, default_value_t = 0)]
+        balance_policy: u32,
+        /// Certificate file for terminating client TLS on the inlet, disabled if empty
+        #[arg(long, default_value = "")]
+        tls_cert: String,
+        /// Private key file for terminating client TLS on the inlet
+        #[arg(long, default_value = "")]
+        tls_key: String,
+        /// Comma separated ALPN protocols offered when terminating TLS
+        #[arg(long, default_value = "")]
+        tls_alpn: String,
+        /// How the outlet connects to the endpoint: 0 plaintext, 1 TLS, 2 TLS without certificate verification
+        #[arg(long, default_value_t = 0)]
+        backend_tls: u32,
+        /// compression dictionary: a file path on both ends, or "base64:<data>"; empty to disable
+        #[arg(long, default_value = "")]
+        compression_dictionary: String,
+        /// external authentication endpoint (http(s)://...) for SOCKS5 inlets; empty to use the tunnel username and password
+        #[arg(long, default_value = "")]
+        auth_url: String,
+        /// number of pre-connected backend sockets kept by the outlet, 0 disables the pool
+        #[arg(long, default_value_t = 0)]
+        pool_size: u32,
+        /// max idle seconds for pooled connections before they are discarded, 0 means 60
+        #[arg(long, default_value_t = 0)]
+        pool_max_idle: u32,
+        /// length prefix framing for tcp tunnels (0: raw stream, 1: u16 big endian, 2: u16 little endian, 3: u32 big endian, 4: u32 little endian)
+        #[arg(long, default_value_t = 0)]
+        frame_prefix: u32,
+        /// response sent to http and socks5 clients when a connection is rejected, empty closes the connection
+        #[arg(long, default_value = "")]
+        reject_response: String,
+        /// bytes the outlet may buffer before the inlet acks them, 0 = unbounded
+        #[arg(long, default_value_t = DEFAULT_BUFFER_LIMIT)]
+        outlet_buffer_limit: u32,
+        /// identifier of the tunnel in an external system, must be unique when set
+        #[arg(long, default_value = "")]
+        external_id: String,
+        /// session key derivation from the tunnel password, which is then stored unhashed (0: random key sent with each connection, 1: hkdf-sha256, 2: pbkdf2-sha256 stretched once, then hkdf-sha256)
+        #[arg(long, default_value_t = 0)]
+        key_derivation: u32,
+        /// the outlet opens a new connection to the server for each session instead of being reached over its control connection
+        #[arg(long, default_value_t = false)]
+        reverse_stream: bool,
+        /// weights of outlet players for balancing, as comma separated player_id#weight, unlisted outlets weigh 1
+        #[arg(long, default_value = "")]
+        outlet_weights: String,
+        /// disable nagle on client connections accepted by the inlet: lower download latency, more packets for bulk transfers
+        #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+        inlet_nodelay: bool,
+        /// disable nagle on outlet connections to the backend: lower upload latency, more packets for bulk transfers
+        #[arg(long, default_value_t = false, action = clap::ArgAction::Set)]
+        outlet_nodelay: bool,
+        /// with compression on, frames smaller than this many bytes are sent uncompressed, 0 = compress everything (legacy format)
+        #[arg(long, default_value_t = DEFAULT_COMPRESS_THRESHOLD)]
+        compress_threshold: u32,
+        /// use tcp fast open on the inlet listener and outlet connections to the backend (linux only)
+        #[arg(long, default_value_t = false)]
+        fast_open: bool,
+        /// let new sessions use the next method of the encryption method list when outlets reject the first one
+        #[arg(long, default_value_t = false)]
+        encryption_fallback: bool,
+    },
+    /// List all tunnels
+    List,
+    /// Delete a tunnel
+    Delete {
+        /// Tunnel id
+        id: u32,
+    },
+    /// Convert plaintext tunnel passwords left by older versions into Argon2 hashes.
+    /// This cannot be undone, back up the database first
+    HashPasswords {
+        /// Only list the tunnels that would be converted
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
 }
@@ -0,0 +1,400 @@
+use anyhow::anyhow;
+use flexi_logger::writers::LogWriter;
+use flexi_logger::DeferredNow;
+use log::{Level, Record};
+use np_base::proxy::stats::SessionEvent;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+
+/// 访问事件使用的日志目标, 写入syslog时MSGID为 `access`
+pub(crate) const ACCESS_TARGET: &str = "access";
+
+/// 连接或写入syslog的超时时间, 避免syslog服务异常时长时间阻塞发送线程
+const SYSLOG_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 等待发送的日志条数上限, 超出时丢弃新的日志
+const SYSLOG_QUEUE_SIZE: usize = 1024;
+
+/// 连接失败后重新连接的间隔, 期间的日志直接丢弃
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// syslog服务地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SyslogAddr {
+    Udp(String),
+    Tcp(String),
+    Unix(String),
+}
+
+impl SyslogAddr {
+    /// 地址格式为 `udp://主机:端口`、`tcp://主机:端口` 或 `unix:/dev/log`
+    pub(crate) fn parse(addr: &str) -> anyhow::Result<Self> {
+        if let Some(rest) = addr.strip_prefix("udp://") {
+            Ok(Self::Udp(rest.to_string()))
+        } else if let Some(rest) = addr.strip_prefix("tcp://") {
+            Ok(Self::Tcp(rest.to_string()))
+        } else if let Some(rest) = addr.strip_prefix("unix:") {
+            Ok(Self::Unix(rest.to_string()))
+        } else {
+            Err(anyhow!("invalid syslog address: {addr}"))
+        }
+    }
+
+    fn connect(&self) -> std::io::Result<Connection> {
+        match self {
+            Self::Udp(addr) => {
+                let server = addr
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| std::io::Error::other(format!("unresolved address {addr}")))?;
+                let local = if server.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(server)?;
+                Ok(Connection::Udp(socket))
+            }
+            Self::Tcp(addr) => {
+                let server = addr
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| std::io::Error::other(format!("unresolved address {addr}")))?;
+                let stream = TcpStream::connect_timeout(&server, SYSLOG_TIMEOUT)?;
+                stream.set_write_timeout(Some(SYSLOG_TIMEOUT))?;
+                Ok(Connection::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                // /dev/log 通常是数据报套接字, 不是时再尝试流式套接字
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                match socket.connect(path) {
+                    Ok(()) => Ok(Connection::UnixDatagram(socket)),
+                    Err(_) => {
+                        let stream = std::os::unix::net::UnixStream::connect(path)?;
+                        stream.set_write_timeout(Some(SYSLOG_TIMEOUT))?;
+                        Ok(Connection::UnixStream(stream))
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            Self::Unix(_) => Err(std::io::Error::other(
+                "unix syslog socket is not supported on this platform",
+            )),
+        }
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    // 流式连接按RFC 6587用长度前缀分隔消息
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    UnixDatagram(std::os::unix::net::UnixDatagram),
+    #[cfg(unix)]
+    UnixStream(std::os::unix::net::UnixStream),
+}
+
+impl Connection {
+    fn send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message).map(|_| ()),
+            Self::Tcp(stream) => {
+                stream.write_all(format!("{} ", message.len()).as_bytes())?;
+                stream.write_all(message)
+            }
+            #[cfg(unix)]
+            Self::UnixDatagram(socket) => socket.send(message).map(|_| ()),
+            #[cfg(unix)]
+            Self::UnixStream(stream) => {
+                stream.write_all(format!("{} ", message.len()).as_bytes())?;
+                stream.write_all(message)
+            }
+        }
+    }
+}
+
+enum Command {
+    Send(String),
+    // 之前的日志都已处理后回复
+    Flush(SyncSender<()>),
+}
+
+/// 发送线程, 域名解析、连接和发送都在这个线程中进行, 不阻塞写日志的线程
+///
+/// 连接在第一条日志时建立, 发送失败后丢弃连接, 下一条日志重新连接.
+/// 连接失败后的一段时间内不再尝试, 期间的日志直接丢弃
+fn run_sender(addr: SyslogAddr, receiver: Receiver<Command>) {
+    let mut conn: Option<Connection> = None;
+    let mut retry_at = Instant::now();
+    // 连续失败时只输出一次错误, 日志系统本身不可用, 只能写到标准错误
+    let mut reported = false;
+    for command in receiver {
+        let message = match command {
+            Command::Send(message) => message,
+            Command::Flush(ack) => {
+                let _ = ack.send(());
+                continue;
+            }
+        };
+        if conn.is_none() {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            match addr.connect() {
+                Ok(c) => {
+                    conn = Some(c);
+                    reported = false;
+                }
+                Err(err) => {
+                    if !reported {
+                        eprintln!("syslog connect {addr:?} error: {err}");
+                        reported = true;
+                    }
+                    retry_at = Instant::now() + RECONNECT_INTERVAL;
+                    continue;
+                }
+            }
+        }
+        if let Some(ref mut c) = conn {
+            if c.send(message.as_bytes()).is_err() {
+                conn = None;
+            }
+        }
+    }
+}
+
+/// 以RFC 5424格式把日志写入syslog
+///
+/// 日志交给发送线程发送, 队列已满或syslog服务不可用时丢弃
+pub(crate) struct SyslogWriter {
+    facility: u8,
+    // 按日志级别(Error..Trace)对应的严重性
+    severities: [u8; 5],
+    hostname: String,
+    app_name: String,
+    pid: u32,
+    sender: SyncSender<Command>,
+}
+
+impl SyslogWriter {
+    /// `severity` 为日志级别到syslog严重性的映射, 例如 `{"info": "notice"}`, 未配置的级别使用默认映射
+    pub(crate) fn new(
+        addr: &str,
+        facility: &str,
+        severity: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let facility = parse_facility(facility)?;
+        // 默认映射: error->err, warn->warning, info->info, debug/trace->debug
+        let mut severities = [3, 4, 6, 7, 7];
+        for (level, name) in severity.iter() {
+            let level: Level = level
+                .parse()
+                .map_err(|_| anyhow!("invalid log level: {level}"))?;
+            severities[level as usize - 1] = parse_severity(name)?;
+        }
+        let addr = SyslogAddr::parse(addr)?;
+        let (sender, receiver) = sync_channel(SYSLOG_QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("syslog".into())
+            .spawn(move || run_sender(addr, receiver))?;
+        Ok(Self {
+            facility,
+            severities,
+            hostname: hostname(),
+            app_name: "np_server".to_string(),
+            pid: std::process::id(),
+            sender,
+        })
+    }
+
+    fn format(&self, now: &mut DeferredNow, record: &Record) -> String {
+        let severity = self.severities[record.level() as usize - 1];
+        let msg_id = if record.target() == ACCESS_TARGET {
+            ACCESS_TARGET
+        } else {
+            "-"
+        };
+        format!(
+            "<{}>1 {} {} {} {} {msg_id} - {}",
+            self.facility * 8 + severity,
+            now.format_rfc3339(),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            record.args()
+        )
+    }
+}
+
+impl LogWriter for SyslogWriter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        let message = self.format(now, record);
+        match self.sender.try_send(Command::Send(message)) {
+            // 队列已满时丢弃, 不阻塞写日志的线程
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(std::io::Error::other("syslog sender thread exited"))
+            }
+        }
+    }
+
+    /// 等待队列中的日志处理完, 最多等待连接超时时间
+    fn flush(&self) -> std::io::Result<()> {
+        let (ack_tx, ack_rx) = sync_channel(1);
+        self.sender
+            .send(Command::Flush(ack_tx))
+            .map_err(|_| std::io::Error::other("syslog sender thread exited"))?;
+        let _ = ack_rx.recv_timeout(SYSLOG_TIMEOUT * 2);
+        Ok(())
+    }
+}
+
+/// 本机入口的会话开始和结束作为访问事件写入日志
+pub(crate) fn log_session_event(tunnel_id: u32, event: &SessionEvent) {
+    match event {
+        SessionEvent::Open {
+            session_id,
+            peer_addr,
+        } => log::info!(
+            target: ACCESS_TARGET,
+            "session.opened tunnel_id={tunnel_id} session_id={session_id} peer_addr={peer_addr}"
+        ),
+        SessionEvent::Close {
+            session_id,
+            peer_addr,
+            reason,
+        } => log::info!(
+            target: ACCESS_TARGET,
+            "session.closed tunnel_id={tunnel_id} session_id={session_id} peer_addr={peer_addr} reason={}",
+            reason.as_str()
+        ),
+    }
+}
+
+fn parse_facility(name: &str) -> anyhow::Result<u8> {
+    const FACILITIES: [&str; 12] = [
+        "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron",
+        "authpriv", "ftp",
+    ];
+    if let Some(index) = FACILITIES.iter().position(|x| *x == name) {
+        return Ok(index as u8);
+    }
+    match name
+        .strip_prefix("local")
+        .and_then(|x| x.parse::<u8>().ok())
+    {
+        Some(index) if index < 8 => Ok(16 + index),
+        _ => Err(anyhow!("invalid syslog facility: {name}")),
+    }
+}
+
+fn parse_severity(name: &str) -> anyhow::Result<u8> {
+    const SEVERITIES: [&str; 8] = [
+        "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+    ];
+    SEVERITIES
+        .iter()
+        .position(|x| *x == name)
+        .map(|x| x as u8)
+        .ok_or_else(|| anyhow!("invalid syslog severity: {name}"))
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syslog_writer_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(SYSLOG_TIMEOUT)).unwrap();
+        let addr = format!("udp://{}", server.local_addr().unwrap());
+        let severity = HashMap::from([("info".to_string(), "notice".to_string())]);
+        let writer = SyslogWriter::new(&addr, "local0", &severity).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let mut receive = |level: Level, target: &str| {
+            writer
+                .write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .level(level)
+                        .target(target)
+                        .args(format_args!("hello"))
+                        .build(),
+                )
+                .unwrap();
+            let len = server.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..len]).to_string()
+        };
+
+        // local0(16) * 8 + notice(5)
+        let message = receive(Level::Info, ACCESS_TARGET);
+        assert!(message.starts_with("<133>1 "));
+        assert!(message.ends_with(" access - hello"));
+        // local0(16) * 8 + err(3)
+        let message = receive(Level::Error, "np_server");
+        assert!(message.starts_with("<131>1 "));
+        assert!(message.ends_with(" - - hello"));
+
+        assert!(SyslogWriter::new(&addr, "local8", &HashMap::new()).is_err());
+        assert!(SyslogWriter::new("127.0.0.1:514", "daemon", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_syslog_writer_tcp_does_not_block() {
+        let write = |writer: &SyslogWriter| {
+            writer
+                .write(
+                    &mut DeferredNow::new(),
+                    &Record::builder()
+                        .level(Level::Info)
+                        .args(format_args!("hello"))
+                        .build(),
+                )
+                .unwrap();
+        };
+
+        // 连接不上时写日志立即返回
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_addr = listener.local_addr().unwrap();
+        drop(listener);
+        let writer =
+            SyslogWriter::new(&format!("tcp://{closed_addr}"), "daemon", &HashMap::new()).unwrap();
+        let start = Instant::now();
+        for _ in 0..SYSLOG_QUEUE_SIZE * 2 {
+            write(&writer);
+        }
+        assert!(start.elapsed() < SYSLOG_TIMEOUT);
+        writer.flush().unwrap();
+
+        // 连接正常时按长度前缀发送
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("tcp://{}", listener.local_addr().unwrap());
+        let writer = SyslogWriter::new(&addr, "daemon", &HashMap::new()).unwrap();
+        write(&writer);
+        writer.flush().unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(SYSLOG_TIMEOUT)).unwrap();
+        let mut buf = [0u8; 1024];
+        let len = std::io::Read::read(&mut stream, &mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]).to_string();
+        let (prefix, body) = message.split_once(' ').unwrap();
+        assert_eq!(prefix.parse::<usize>().unwrap(), body.len());
+        assert!(body.ends_with(" - - hello"));
+    }
+}
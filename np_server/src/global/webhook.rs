@@ -0,0 +1,115 @@
+use crate::global::config::SecretUrl;
+use crate::global::event::Event;
+use crate::global::GLOBAL_WEBHOOK;
+use log::{debug, warn};
+use np_base::net::http::HttpEndpoint;
+use np_base::proxy::crypto;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::timeout;
+
+/// 单次请求的超时时间
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 第一次重试前的等待时间, 之后每次翻倍
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// 重试间隔的上限
+const WEBHOOK_MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// 向外部地址POST事件通知
+///
+/// 每个地址有独立的发送队列和任务, 队列满时丢弃新事件, 不阻塞管理和代理流程
+pub(crate) struct Webhook {
+    queues: Vec<(SecretUrl, mpsc::Sender<Arc<String>>)>,
+}
+
+impl Webhook {
+    /// 为每个地址启动发送任务, 需要在tokio运行时中调用
+    pub(crate) fn start(
+        urls: &[SecretUrl],
+        secret: &str,
+        max_retries: u32,
+        queue_size: usize,
+    ) -> anyhow::Result<Self> {
+        let mut queues = Vec::with_capacity(urls.len());
+        for url in urls {
+            let endpoint = HttpEndpoint::parse(url.expose())?;
+            let (tx, rx) = mpsc::channel(queue_size.max(1));
+            tokio::spawn(deliver_loop(endpoint, secret.to_string(), max_retries, rx));
+            queues.push((url.clone(), tx));
+        }
+        Ok(Self { queues })
+    }
+
+    /// 发送事件, 请求体为带版本的事件JSON, 格式见 [`Event`]
+    pub(crate) fn send(&self, event: &Event) {
+        let body = Arc::new(event.to_json());
+        let event = event.event_type();
+        for (url, tx) in self.queues.iter() {
+            match tx.try_send(body.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("webhook {url} queue is full, event {event} dropped")
+                }
+                Err(TrySendError::Closed(_)) => {
+                    warn!("webhook {url} is stopped, event {event} dropped")
+                }
+            }
+        }
+    }
+}
+
+/// 发送事件通知, 没有配置地址时忽略
+pub(crate) fn send_webhook(event: Event) {
+    if let Some(webhook) = GLOBAL_WEBHOOK.get() {
+        webhook.send(&event);
+    }
+}
+
+async fn deliver_loop(
+    endpoint: HttpEndpoint,
+    secret: String,
+    max_retries: u32,
+    mut rx: mpsc::Receiver<Arc<String>>,
+) {
+    while let Some(body) = rx.recv().await {
+        let mut headers = Vec::new();
+        if !secret.is_empty() {
+            let signature = crypto::hmac_sha256_hex(secret.as_bytes(), body.as_bytes());
+            headers.push(("X-Npipe-Signature", format!("sha256={signature}")));
+        }
+
+        let mut delay = WEBHOOK_RETRY_DELAY;
+        for attempt in 0..=max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(WEBHOOK_MAX_RETRY_DELAY);
+            }
+            let error = match timeout(WEBHOOK_TIMEOUT, endpoint.post(&headers, &body)).await {
+                Ok(Ok(status)) if (200..300).contains(&status) => {
+                    debug!("webhook {} delivered: {status}", endpoint.url());
+                    break;
+                }
+                Ok(Ok(status)) => format!("status {status}"),
+                Ok(Err(err)) => err.to_string(),
+                Err(_) => "timeout".to_string(),
+            };
+            if attempt == max_retries {
+                warn!(
+                    "webhook {} failed after {} attempts, event dropped: {error}",
+                    endpoint.url(),
+                    attempt + 1
+                );
+            } else {
+                debug!(
+                    "webhook {} attempt {} failed: {error}",
+                    endpoint.url(),
+                    attempt + 1
+                );
+            }
+        }
+    }
+}
@@ -1,22 +1,36 @@
+mod admin;
+mod cli;
 mod global;
 mod orm_entity;
 mod peer;
 mod player;
+mod sd_notify;
 mod utils;
 mod web;
+#[cfg(all(windows, feature = "windows-service"))]
+mod winservice;
 
 use crate::global::config::GLOBAL_CONFIG;
-use crate::global::opts::GLOBAL_OPTS;
+use crate::global::manager::GLOBAL_MANAGER;
+use crate::global::opts::{Command, GLOBAL_OPTS};
+use crate::global::{
+    GLOBAL_OFFLINE_MODE, GLOBAL_TCP_SERVER_LISTENING, GLOBAL_WEB_SERVER_LISTENING,
+};
 use crate::peer::Peer;
 use anyhow::anyhow;
-use log::info;
+use log::{error, info};
 use np_base::net::session_delegate::SessionDelegate;
 use np_base::net::tcp_server;
 use once_cell::sync::Lazy;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
 use tokio::{select, signal};
 
-pub async fn run_tcp_server() -> anyhow::Result<()> {
+pub async fn run_tcp_server(shutdown: impl Future) -> anyhow::Result<()> {
     let mut builder = tcp_server::Builder::new(Box::new(|| -> Box<dyn SessionDelegate> {
         Box::new(Peer::new())
     }));
@@ -25,39 +39,257 @@ pub async fn run_tcp_server() -> anyhow::Result<()> {
         builder = builder.set_tls_configuration(&GLOBAL_CONFIG.tls_cert, &GLOBAL_CONFIG.tls_key);
     }
 
-    builder
-        .build(GLOBAL_CONFIG.listen_addr.as_str(), signal::ctrl_c())
-        .await
+    if GLOBAL_CONFIG.web_control_channel {
+        if let Some(stream_receiver) = global::init_control_streams() {
+            builder = builder.set_stream_receiver(stream_receiver);
+        }
+    }
+
+    let listener = TcpListener::bind(GLOBAL_CONFIG.listen_addr.as_str()).await?;
+    GLOBAL_TCP_SERVER_LISTENING.store(true, Ordering::Release);
+    let result = builder.build_with_listener(listener, shutdown).await;
+    GLOBAL_TCP_SERVER_LISTENING.store(false, Ordering::Release);
+    result
 }
 
-pub async fn run_web_server() -> anyhow::Result<()> {
+pub async fn run_web_server(
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
     info!("HttpServer listening: {}", GLOBAL_CONFIG.web_addr);
     let addr = GLOBAL_CONFIG.web_addr.parse::<SocketAddr>();
-    return match addr {
-        Ok(addr) => web::run_http_server(&addr, GLOBAL_CONFIG.web_base_dir.clone()).await,
+    match addr {
+        Ok(addr) => web::run_http_server(&addr, GLOBAL_CONFIG.web_base_dir.clone(), shutdown).await,
         Err(parse_error) => Err(anyhow!(parse_error.to_string())),
+    }
+}
+
+/// 等待退出信号(ctrl_c 或 SIGTERM)
+async fn shutdown_signal() {
+    select! {
+        _ = signal::ctrl_c() => info!("Received ctrl_c"),
+        _ = terminate_signal() => info!("Received SIGTERM"),
+    }
+}
+
+#[cfg(unix)]
+async fn terminate_signal() {
+    match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(err) => {
+            error!("Failed to listen for SIGTERM: {err}");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate_signal() {
+    std::future::pending::<()>().await;
+}
+
+/// 收到SIGHUP时重新加载入口的TLS证书, 证书文件变化时也会自动重新加载
+#[cfg(unix)]
+async fn reload_tls_loop() {
+    match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(mut sighup) => {
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading TLS certificates");
+                GLOBAL_MANAGER.proxy_manager.reload_tls().await;
+            }
+        }
+        Err(err) => error!("Failed to listen for SIGHUP: {err}"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_tls_loop() {}
+
+/// 定时将通道流量写入数据库
+async fn flush_traffic_loop() {
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        GLOBAL_CONFIG.traffic_flush_interval.max(1),
+    ));
+    loop {
+        interval.tick().await;
+        if let Err(err) = GLOBAL_MANAGER.tunnel_manager.flush_traffic().await {
+            error!("Failed to flush tunnel traffic: {err}");
+        }
+    }
+}
+
+/// 定时探测出口到后端的连通性
+async fn probe_endpoints_loop() {
+    if GLOBAL_CONFIG.endpoint_probe_interval == 0 {
+        return;
+    }
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(GLOBAL_CONFIG.endpoint_probe_interval));
+    loop {
+        interval.tick().await;
+        GLOBAL_MANAGER.health_manager.probe_all().await;
+    }
+}
+
+/// 等待开启的服务器都开始监听
+async fn wait_listening() {
+    loop {
+        let tcp_listening =
+            !GLOBAL_CONFIG.enable_tcp_server || GLOBAL_TCP_SERVER_LISTENING.load(Ordering::Acquire);
+        let web_listening = !GLOBAL_CONFIG.web_server_enabled()
+            || GLOBAL_WEB_SERVER_LISTENING.load(Ordering::Acquire);
+        if tcp_listening && web_listening {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// 启动完成, 通知systemd或Windows服务管理器
+fn notify_ready() {
+    sd_notify::notify("READY=1");
+    #[cfg(all(windows, feature = "windows-service"))]
+    winservice::set_running();
+}
+
+/// 开始退出, 通知systemd或Windows服务管理器
+fn notify_stopping() {
+    sd_notify::notify("STOPPING=1");
+    #[cfg(all(windows, feature = "windows-service"))]
+    winservice::set_stopping();
+}
+
+async fn wait_shutdown(mut shutdown_rx: watch::Receiver<bool>) {
+    let _ = shutdown_rx.wait_for(|x| *x).await;
+}
+
+async fn run_servers(shutdown_rx: watch::Receiver<bool>) -> anyhow::Result<()> {
+    // 关闭的服务器不启动, 一直等待, 只有开启的服务器退出时才结束
+    let tcp_server = async {
+        if GLOBAL_CONFIG.enable_tcp_server {
+            run_tcp_server(wait_shutdown(shutdown_rx.clone())).await
+        } else {
+            info!("TCP server disabled");
+            std::future::pending().await
+        }
+    };
+    let web_server = async {
+        if GLOBAL_CONFIG.web_server_enabled() {
+            run_web_server(wait_shutdown(shutdown_rx.clone())).await
+        } else {
+            info!("Web server disabled");
+            std::future::pending().await
+        }
     };
+
+    select! {
+        result = tcp_server => result,
+        result = web_server => result,
+    }
 }
 
-#[tokio::main]
-pub async fn main() -> anyhow::Result<()> {
+pub fn main() -> anyhow::Result<()> {
     Lazy::force(&GLOBAL_OPTS);
+
+    // 只检查加密方法, 不需要配置文件
+    if let Some(Command::CryptoSelftest {
+        method,
+        size,
+        iterations,
+    }) = &GLOBAL_OPTS.command
+    {
+        return cli::run_crypto_selftest(method, *size, *iterations);
+    }
+
+    // 服务的工作目录在加载配置前设置, 运行时由服务入口创建, 这里还不能有运行时
+    #[cfg(all(windows, feature = "windows-service"))]
+    if let Some(Command::RunService) = &GLOBAL_OPTS.command {
+        return winservice::run_as_service();
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async_main())
+}
+
+async fn async_main() -> anyhow::Result<()> {
     Lazy::force(&GLOBAL_CONFIG);
+
+    // 离线管理命令, 执行完直接退出
+    if let Some(Command::Tunnel(command)) = &GLOBAL_OPTS.command {
+        GLOBAL_OFFLINE_MODE.store(true, Ordering::Release);
+        global::init_global().await?;
+        return cli::run_tunnel_command(command).await;
+    }
+
+    run_server(shutdown_signal()).await
+}
+
+/// 运行服务器直到 `shutdown` 完成, 然后等待会话结束后退出
+///
+/// 初始化完成且开启的服务器都开始监听后通知服务管理器, 不受服务管理器管理时与普通进程相同
+pub(crate) async fn run_server(shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
     global::init_global().await?;
 
-    if GLOBAL_CONFIG.web_username.is_empty()
-        || GLOBAL_CONFIG.web_password.is_empty()
-        || GLOBAL_CONFIG.web_addr.is_empty()
-    {
-        run_tcp_server().await
-    } else {
-        let result: anyhow::Result<()>;
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-        select! {
-            r1 = run_tcp_server() => { result = r1 },
-            r2 = run_web_server() => { result = r2 },
+    if !GLOBAL_CONFIG.admin_addr.is_empty() {
+        let admin =
+            admin::bind(&GLOBAL_CONFIG.admin_addr, GLOBAL_CONFIG.admin_allow_remote).await?;
+        info!("Admin socket listening: {}", GLOBAL_CONFIG.admin_addr);
+        tokio::spawn(admin.run(wait_shutdown(shutdown_rx.clone())));
+    }
+
+    let flush_traffic = tokio::spawn(flush_traffic_loop());
+    let probe_endpoints = tokio::spawn(probe_endpoints_loop());
+    tokio::spawn(reload_tls_loop());
+    tokio::spawn(sd_notify::watchdog_loop());
+    let mut servers = tokio::spawn(run_servers(shutdown_rx));
+    let ready = tokio::spawn(async {
+        wait_listening().await;
+        notify_ready();
+    });
+    select! {
+        result = &mut servers => return result?,
+        _ = shutdown => {},
+    }
+    ready.abort();
+    notify_stopping();
+
+    let timeout = Duration::from_secs(GLOBAL_CONFIG.shutdown_timeout);
+    info!(
+        "Shutting down, waiting up to {}s for sessions to finish",
+        timeout.as_secs()
+    );
+
+    // 先排空入口会话, 再停止监听并断开玩家, 最后关闭数据库连接池
+    let graceful = async {
+        GLOBAL_MANAGER
+            .player_manager
+            .notify_shutdown(timeout.as_secs() as u32)
+            .await;
+        GLOBAL_MANAGER.proxy_manager.stop_all(timeout).await;
+        let _ = shutdown_tx.send(true);
+        let _ = (&mut servers).await;
+        flush_traffic.abort();
+        probe_endpoints.abort();
+        if let Err(err) = GLOBAL_MANAGER.tunnel_manager.flush_traffic().await {
+            error!("Failed to flush tunnel traffic: {err}");
         }
+        global::shutdown_global().await;
+    };
 
-        result
+    // 超时后强制退出, 剩余的任务随运行时一起销毁
+    if tokio::time::timeout(timeout + Duration::from_secs(10), graceful)
+        .await
+        .is_err()
+    {
+        error!("Graceful shutdown timeout, forced exit");
+        servers.abort();
     }
+
+    info!("Server shutdown finish");
+    Ok(())
 }
@@ -20,6 +20,42 @@ pub struct Model {
     #[sea_orm(column_type = "Text")]
     pub custom_mapping: String,
     pub encryption_method: String,
+    pub paused: u8,
+    pub max_session_lifetime: u32,
+    pub o2i_is_compressed: Option<u8>,
+    pub o2i_encryption_method: Option<String>,
+    pub accept_proxy_protocol: u8,
+    pub bytes_quota: i64,
+    pub bytes_used: i64,
+    pub extra_senders: String,
+    pub write_coalesce: u32,
+    pub connect_retries: u32,
+    pub connect_retry_delay: u32,
+    pub conn_rate_limit: u32,
+    pub conn_rate_burst: u32,
+    pub bind_device: String,
+    pub balance_policy: u32,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub tls_alpn: String,
+    pub backend_tls: u32,
+    pub compression_dictionary: String,
+    pub auth_url: String,
+    pub pool_size: u32,
+    pub pool_max_idle: u32,
+    pub frame_prefix: u32,
+    pub reject_response: String,
+    pub outlet_buffer_limit: u32,
+    pub external_id: String,
+    pub key_derivation: u32,
+    pub reverse_stream: u8,
+    pub outlet_weights: String,
+    pub inlet_nodelay: u8,
+    pub outlet_nodelay: u8,
+    pub compress_threshold: u32,
+    pub fast_open: u8,
+    pub assigned_port: u32,
+    pub encryption_fallback: u8,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -17,7 +17,16 @@ impl Peer {
                 .find(|x| x.id == tunnel_id)
             {
                 let (from_player_id, to_player_id) = if message_bridge::is_i2o_message(&msg) {
-                    (tunnel.receiver, tunnel.sender)
+                    let to_player_id = GLOBAL_MANAGER
+                        .proxy_manager
+                        .route_to_outlet(
+                            tunnel.id,
+                            &tunnel.weighted_outlet_players(),
+                            tunnel.balance_policy(),
+                            &msg,
+                        )
+                        .await;
+                    (tunnel.receiver, to_player_id)
                 } else {
                     (tunnel.sender, tunnel.receiver)
                 };
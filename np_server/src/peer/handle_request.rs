@@ -1,8 +1,9 @@
 use super::Peer;
+use crate::global::db;
 use crate::global::manager::GLOBAL_MANAGER;
-use crate::global::GLOBAL_DB_POOL;
 use crate::orm_entity::prelude::User;
-use crate::orm_entity::user;
+use crate::orm_entity::{tunnel, user};
+use anyhow::anyhow;
 use log::trace;
 use np_proto::message_map::MessageType;
 use np_proto::{client_server, generic, server_client};
@@ -17,9 +18,32 @@ impl Peer {
         match message {
             MessageType::GenericPing(msg) => return self.on_ping_request(msg).await,
             MessageType::ClientServerLoginReq(msg) => return self.on_login_request(msg).await,
+            MessageType::ClientServerManagementLoginReq(msg) => {
+                return self.on_management_login_request(msg).await
+            }
             MessageType::ClientServerRegisterReq(msg) => {
                 return self.on_register_request(msg).await
             }
+            // 反向连接用令牌认证, 不需要登录
+            MessageType::ClientServerBindStreamReq(msg) => {
+                return self.on_bind_stream_request(msg).await
+            }
+            // 通道管理请求不持有玩家锁处理, 避免广播通道变更时死锁
+            MessageType::ClientServerAddTunnelReq(msg) => {
+                if let Some(player_id) = self.player_id().await {
+                    return self.on_add_tunnel_request(player_id, msg).await;
+                }
+            }
+            MessageType::ClientServerDeleteTunnelReq(msg) => {
+                if let Some(player_id) = self.player_id().await {
+                    return self.on_delete_tunnel_request(player_id, msg).await;
+                }
+            }
+            MessageType::ClientServerTunnelListReq(_) => {
+                if let Some(player_id) = self.player_id().await {
+                    return self.on_tunnel_list_request(player_id).await;
+                }
+            }
             _ => {
                 if let Some(ref player) = self.player {
                     return player.write().await.handle_request(message).await;
@@ -40,11 +64,41 @@ impl Peer {
         }))
     }
 
+    async fn on_bind_stream_request(
+        &mut self,
+        message: client_server::BindStreamReq,
+    ) -> anyhow::Result<MessageType> {
+        if self.player.is_some() || self.management.is_some() || self.stream.is_some() {
+            return Ok(MessageType::GenericError(generic::Error {
+                number: -1,
+                message: "connection is already in use".into(),
+            }));
+        }
+        let tx = match self.tx {
+            Some(ref tx) => tx.clone(),
+            None => return Err(anyhow!("tx is none")),
+        };
+        if !GLOBAL_MANAGER.stream_manager.bind(
+            message.tunnel_id,
+            message.session_id,
+            &message.token,
+            self.session_id,
+            tx,
+        ) {
+            return Ok(MessageType::GenericError(generic::Error {
+                number: -2,
+                message: "invalid or expired stream token".into(),
+            }));
+        }
+        self.stream = Some((message.tunnel_id, message.session_id));
+        Ok(MessageType::GenericSuccess(generic::Success {}))
+    }
+
     async fn on_login_request(
         &mut self,
         message: client_server::LoginReq,
     ) -> anyhow::Result<MessageType> {
-        if self.player.is_some() {
+        if self.player.is_some() || self.management.is_some() {
             // 重复发送登录请求
             return Ok(MessageType::GenericError(generic::Error {
                 number: -1,
@@ -55,7 +109,7 @@ impl Peer {
         let user_result = User::find()
             .filter(user::Column::Username.eq(message.username))
             .filter(user::Column::Password.eq(message.password))
-            .one(GLOBAL_DB_POOL.get().unwrap())
+            .one(db())
             .await?;
 
         if user_result.is_none() {
@@ -77,16 +131,12 @@ impl Peer {
             player
                 .on_connect_session(self.session_id, self.tx.clone().unwrap())
                 .await;
+            GLOBAL_MANAGER.reconnect_manager.cancel(user.id);
 
             let tunnel_list = GLOBAL_MANAGER
                 .tunnel_manager
-                .tunnels
-                .read()
-                .await
-                .iter()
-                .filter(|x| x.receiver == user.id || x.sender == user.id)
-                .map(|x| x.into())
-                .collect();
+                .player_tunnel_list(user.id)
+                .await;
             trace!("login success, player_id:{}", user.id);
             return Ok(MessageType::ServerClientLoginAck(server_client::LoginAck {
                 player_id: user.id,
@@ -94,10 +144,65 @@ impl Peer {
             }));
         }
 
-        return Ok(MessageType::GenericError(generic::Error {
+        Ok(MessageType::GenericError(generic::Error {
             number: -3,
             message: "unable to find player".into(),
-        }));
+        }))
+    }
+
+    /// 管理登录只订阅玩家的通道通知, 不绑定玩家的会话, 在线的客户端不受影响
+    async fn on_management_login_request(
+        &mut self,
+        message: client_server::ManagementLoginReq,
+    ) -> anyhow::Result<MessageType> {
+        if self.player.is_some() || self.management.is_some() || self.stream.is_some() {
+            // 重复发送登录请求
+            return Ok(MessageType::GenericError(generic::Error {
+                number: -1,
+                message: "repeat login".into(),
+            }));
+        }
+        let Some(tx) = self.tx.clone() else {
+            return Err(anyhow!("tx is none"));
+        };
+
+        let Some(user) = User::find()
+            .filter(user::Column::Username.eq(message.username))
+            .filter(user::Column::Password.eq(message.password))
+            .one(db())
+            .await?
+        else {
+            return Ok(MessageType::GenericError(generic::Error {
+                number: -2,
+                message: "Incorrect username or password".into(),
+            }));
+        };
+
+        let Some(player) = GLOBAL_MANAGER.player_manager.get_player(user.id).await else {
+            return Ok(MessageType::GenericError(generic::Error {
+                number: -3,
+                message: "unable to find player".into(),
+            }));
+        };
+        player.write().await.add_watcher(self.session_id, tx);
+        self.management = Some(player);
+
+        let tunnel_list = GLOBAL_MANAGER
+            .tunnel_manager
+            .player_tunnel_list(user.id)
+            .await;
+        trace!(
+            "management login success, player_id:{}, version:{}",
+            user.id,
+            message.version
+        );
+        Ok(MessageType::ServerClientManagementLoginAck(
+            server_client::ManagementLoginAck {
+                code: 0,
+                player_id: user.id,
+                tunnel_list,
+            },
+        ))
     }
 
     async fn on_register_request(
@@ -117,4 +222,84 @@ impl Peer {
             }))
         }
     }
+
+    async fn player_id(&self) -> Option<u32> {
+        match self.player.as_ref().or(self.management.as_ref()) {
+            Some(player) => Some(player.read().await.get_player_id()),
+            None => None,
+        }
+    }
+
+    async fn on_add_tunnel_request(
+        &self,
+        player_id: u32,
+        message: client_server::AddTunnelReq,
+    ) -> anyhow::Result<MessageType> {
+        let Some(tunnel) = message.tunnel else {
+            return Ok(error_message("tunnel is empty"));
+        };
+        let mut tunnel: tunnel::Model = (&tunnel).into();
+        tunnel.id = 0;
+        // 只能新增两端都是自己的通道
+        if !tunnel.owned_by(player_id) {
+            return Ok(error_message("permission denied"));
+        }
+
+        match GLOBAL_MANAGER.tunnel_manager.add_tunnel(tunnel).await {
+            Ok(tunnel_id) => Ok(MessageType::ServerClientAddTunnelAck(
+                server_client::AddTunnelAck { tunnel_id },
+            )),
+            Err(err) => Ok(error_message(&err.to_string())),
+        }
+    }
+
+    async fn on_delete_tunnel_request(
+        &self,
+        player_id: u32,
+        message: client_server::DeleteTunnelReq,
+    ) -> anyhow::Result<MessageType> {
+        let owned = GLOBAL_MANAGER
+            .tunnel_manager
+            .tunnels
+            .read()
+            .await
+            .iter()
+            .find(|x| x.id == message.tunnel_id)
+            .map(|x| x.owned_by(player_id));
+        match owned {
+            // 已经删除的通道直接返回成功, 客户端超时重试时不会失败
+            None => {
+                trace!("tunnel({}) is already deleted", message.tunnel_id);
+                return Ok(MessageType::GenericSuccess(generic::Success {}));
+            }
+            Some(false) => return Ok(error_message("permission denied")),
+            Some(true) => {}
+        }
+
+        match GLOBAL_MANAGER
+            .tunnel_manager
+            .delete_tunnel(message.tunnel_id)
+            .await
+        {
+            Ok(_) => Ok(MessageType::GenericSuccess(generic::Success {})),
+            Err(err) => Ok(error_message(&err.to_string())),
+        }
+    }
+
+    async fn on_tunnel_list_request(&self, player_id: u32) -> anyhow::Result<MessageType> {
+        let tunnel_list = GLOBAL_MANAGER
+            .tunnel_manager
+            .player_tunnel_list(player_id)
+            .await;
+        Ok(MessageType::ServerClientTunnelListAck(
+            server_client::TunnelListAck { tunnel_list },
+        ))
+    }
+}
+
+fn error_message(message: &str) -> MessageType {
+    MessageType::GenericError(generic::Error {
+        number: -1,
+        message: message.into(),
+    })
 }
@@ -1,9 +1,20 @@
 use super::Peer;
+use crate::global::manager::GLOBAL_MANAGER;
 use np_proto::message_map::MessageType;
 
 impl Peer {
     // 收到玩家向服务器回复消息
-    pub(crate) async fn handle_response(&self, _message: MessageType) -> anyhow::Result<()> {
+    pub(crate) async fn handle_response(&self, message: MessageType) -> anyhow::Result<()> {
+        let Some(ref player) = self.player else {
+            return Ok(());
+        };
+        if let MessageType::ClientServerProbeEndpointAck(msg) = message {
+            let player_id = player.read().await.get_player_id();
+            GLOBAL_MANAGER
+                .health_manager
+                .on_probe_result(player_id, msg.health_list)
+                .await;
+        }
         Ok(())
     }
 }
@@ -3,16 +3,19 @@ mod handle_request;
 mod handle_response;
 
 use crate::global::config::GLOBAL_CONFIG;
+use crate::global::manager::proxy::ProxyManager;
+use crate::global::manager::GLOBAL_MANAGER;
 use crate::player::Player;
 use anyhow::anyhow;
 use async_trait::async_trait;
-use byteorder::{BigEndian, ByteOrder};
 use bytes::BytesMut;
 use log::{debug, error, trace};
 use np_base::net::session_delegate::SessionDelegate;
 use np_base::net::WriterMessage;
-use np_proto::message_map::{encode_raw_message, get_message_id, get_message_size, MessageType};
-use np_proto::{generic, message_map};
+use np_base::proxy::ProxyMessage;
+use np_proto::frame::{decode_frame, encode_frame, try_extract_frame, FRAME_FLAG};
+use np_proto::generic;
+use np_proto::message_map::MessageType;
 use socket2::{SockRef, TcpKeepalive};
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -26,17 +29,30 @@ use tokio::time::Instant;
 pub struct Peer {
     tx: Option<UnboundedSender<WriterMessage>>,
     player: Option<Arc<RwLock<Player>>>,
+    // 管理登录的玩家, 与player互斥, 不占用玩家的会话
+    management: Option<Arc<RwLock<Player>>>,
     session_id: u32,
     traffic_forward_writer: Option<WriteHalf<TcpStream>>,
+    // 出口为反向连接通道建立的会话连接, (通道id, 会话id)
+    stream: Option<(u32, u32)>,
+    // 单个消息的最大长度
+    max_message_size: usize,
 }
 
 impl Peer {
     pub(crate) fn new() -> Self {
+        Self::with_max_message_size(GLOBAL_CONFIG.max_message_size)
+    }
+
+    pub(crate) fn with_max_message_size(max_message_size: usize) -> Self {
         Peer {
             tx: None,
             player: None,
+            management: None,
             session_id: 0,
             traffic_forward_writer: None,
+            stream: None,
+            max_message_size,
         }
     }
 
@@ -126,16 +142,12 @@ impl Peer {
             let tx = tx.clone();
             tokio::spawn(async move {
                 let mut buffer = BytesMut::with_capacity(4096);
-                loop {
-                    if let Ok(size) = reader.read_buf(&mut buffer).await {
-                        if size == 0 {
-                            break;
-                        }
-                        let frame = buffer.split().to_vec();
-                        let _ = tx.send(WriterMessage::Send(frame, true));
-                    } else {
+                while let Ok(size) = reader.read_buf(&mut buffer).await {
+                    if size == 0 {
                         break;
                     }
+                    let frame = buffer.split().to_vec();
+                    let _ = tx.send(WriterMessage::Send(frame, true));
                 }
                 let _ = tx.send(WriterMessage::Close);
             });
@@ -166,13 +178,36 @@ impl SessionDelegate for Peer {
         // 清退对应玩家
         if let Some(player) = self.player.take() {
             if player.read().await.get_session_id() == self.session_id {
-                player.write().await.on_disconnect_session().await;
+                let mut player = player.write().await;
+                player.on_disconnect_session().await;
+                GLOBAL_MANAGER
+                    .reconnect_manager
+                    .start(player.get_player_id());
             }
         }
+        if let Some(player) = self.management.take() {
+            player.write().await.remove_watcher(self.session_id);
+        }
         // 关闭流量转发通道
         if let Some(mut writer) = self.traffic_forward_writer.take() {
             let _ = writer.shutdown().await;
         }
+        // 会话还没有结束时通知入口断开
+        if let Some((tunnel_id, session_id)) = self.stream.take() {
+            if let Some(info) =
+                GLOBAL_MANAGER
+                    .stream_manager
+                    .unbind(tunnel_id, session_id, self.session_id)
+            {
+                ProxyManager::send_proxy_message(
+                    info.outlet_player_id,
+                    info.inlet_player_id,
+                    tunnel_id,
+                    ProxyMessage::O2iDisconnect(session_id, info.generation),
+                )
+                .await;
+            }
+        }
         Ok(())
     }
 
@@ -184,14 +219,14 @@ impl SessionDelegate for Peer {
         &mut self,
         buffer: &mut BytesMut,
     ) -> anyhow::Result<Option<Vec<u8>>> {
-        if buffer.len() > 0 && self.traffic_forward_writer.is_none() {
-            if buffer[0] != 33u8 {
-                if self.create_traffic_forward_channel().await.is_err() {
-                    debug!("bad flag");
-                    self.send_http_404_response().await?;
-                    return Err(anyhow!("Bad flag"));
-                }
-            }
+        if !buffer.is_empty()
+            && self.traffic_forward_writer.is_none()
+            && buffer[0] != FRAME_FLAG
+            && self.create_traffic_forward_channel().await.is_err()
+        {
+            debug!("bad flag");
+            self.send_http_404_response().await?;
+            return Err(anyhow!("Bad flag"));
         }
 
         if let Some(ref mut writer) = self.traffic_forward_writer {
@@ -200,47 +235,21 @@ impl SessionDelegate for Peer {
             return Ok(None);
         }
 
-        // 数据小于5字节,继续读取数据
-        if buffer.len() < 5 {
-            return Ok(None);
-        }
-
-        // 读取包长度
-        let buf = buffer.get(1..5).unwrap();
-        let len = BigEndian::read_u32(buf) as usize;
-
-        // 超出最大限制
-        if len <= 0 || len >= 1024 * 1024 * 2 {
-            debug!("Message too long");
-            self.send_http_404_response().await?;
-            return Err(anyhow!("Message too long"));
-        }
-
-        // 数据不够,继续读取数据
-        if buffer.len() < 5 + len {
-            return Ok(None);
+        // 超出最大限制时在分配内存前断开连接
+        match try_extract_frame(buffer, self.max_message_size) {
+            Ok(frame) => Ok(frame),
+            Err(err) => {
+                debug!("{err}");
+                self.send_http_404_response().await?;
+                Err(err)
+            }
         }
-
-        // 拆出这个包的数据
-        let frame = buffer.split_to(5 + len).split_off(5).to_vec();
-
-        Ok(Some(frame))
     }
 
     // 收到一个完整的消息包
     async fn on_recv_frame(&mut self, frame: Vec<u8>) -> anyhow::Result<()> {
-        if frame.len() < 8 {
-            debug!("message length is too small");
-            self.send_http_404_response().await?;
-            return Err(anyhow!("message length is too small"));
-        }
-        // 消息序号
-        let serial: i32 = BigEndian::read_i32(&frame[0..4]);
-        // 消息类型id
-        let msg_id: u32 = BigEndian::read_u32(&frame[4..8]);
-
-        match message_map::decode_message(msg_id, &frame[8..]) {
-            Ok(message) => {
+        match decode_frame(&frame) {
+            Ok((serial, msg_id, message)) => {
                 let start_time = Instant::now();
 
                 let result = self.handle_message(serial, message).await;
@@ -267,6 +276,13 @@ impl SessionDelegate for Peer {
                                 .await?;
                             } else {
                                 self.send_response(serial, &msg).await?;
+                                // 登录回复发出后才能转发重连前暂存的会话
+                                if let MessageType::ServerClientLoginAck(ref ack) = msg {
+                                    GLOBAL_MANAGER
+                                        .reconnect_manager
+                                        .release(ack.player_id)
+                                        .await;
+                                }
                             }
                         }
                     }
@@ -298,7 +314,7 @@ impl SessionDelegate for Peer {
                 // )
                 // .await?;
 
-                return Err(anyhow!(err));
+                return Err(err);
             }
         }
 
@@ -314,16 +330,7 @@ pub(crate) async fn package_and_send_message(
     flush: bool,
 ) -> anyhow::Result<()> {
     if let Some(ref tx) = tx {
-        if let Some(message_id) = get_message_id(message) {
-            let message_size = get_message_size(message);
-            let mut buf = Vec::with_capacity(message_size + 14);
-
-            byteorder::WriteBytesExt::write_u8(&mut buf, 33u8)?;
-            byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut buf, (8 + message_size) as u32)?;
-            byteorder::WriteBytesExt::write_i32::<BigEndian>(&mut buf, serial)?;
-            byteorder::WriteBytesExt::write_u32::<BigEndian>(&mut buf, message_id)?;
-            encode_raw_message(message, &mut buf);
-
+        if let Some(buf) = encode_frame(serial, message) {
             if let Err(error) = tx.send(WriterMessage::Send(buf, flush)) {
                 error!("Send message error: {}", error);
             }
@@ -333,3 +340,135 @@ pub(crate) async fn package_and_send_message(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global::init_test_db;
+    use crate::orm_entity::prelude::User;
+    use crate::orm_entity::user;
+    use np_base::net::tcp_server;
+    use np_control::ControlClient;
+    use np_proto::class_def::{Tunnel, TunnelPoint, TunnelType};
+    use np_proto::DEFAULT_MAX_MESSAGE_SIZE;
+    use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_oversized_message() {
+        let mut peer = Peer::with_max_message_size(1024);
+        let (tx, mut rx) = unbounded_channel();
+        peer.on_session_start(1, &SocketAddr::from(([127, 0, 0, 1], 0)), tx)
+            .await
+            .unwrap();
+
+        // 未超出限制的消息等待数据到齐
+        let mut buffer = BytesMut::from(&[33u8, 0, 0, 4, 0, 1, 2][..]);
+        assert_eq!(peer.on_try_extract_frame(&mut buffer).await.unwrap(), None);
+
+        // 声明的长度远大于限制, 不分配内存直接断开
+        let claimed = u32::MAX - 1;
+        let mut buffer = BytesMut::with_capacity(64);
+        buffer.extend_from_slice(&[33u8]);
+        buffer.extend_from_slice(&claimed.to_be_bytes());
+        buffer.extend_from_slice(&[0u8; 16]);
+        assert!(peer.on_try_extract_frame(&mut buffer).await.is_err());
+        assert!(buffer.capacity() < 1024);
+
+        assert!(matches!(rx.recv().await, Some(WriterMessage::Send(..))));
+        assert!(matches!(rx.recv().await, Some(WriterMessage::Close)));
+    }
+
+    #[tokio::test]
+    async fn test_control_client_round_trip() {
+        let db = init_test_db().await;
+        let username = "control_client_test".to_string();
+        let password = "pw".to_string();
+        let (code, _) = GLOBAL_MANAGER
+            .player_manager
+            .add_player(&username, &password)
+            .await
+            .unwrap();
+        assert_eq!(code, 0);
+        let player_id = User::find()
+            .filter(user::Column::Username.eq(&username))
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap()
+            .id;
+
+        // 模拟在线的客户端
+        let player = GLOBAL_MANAGER
+            .player_manager
+            .get_player(player_id)
+            .await
+            .unwrap();
+        let (client_tx, mut client_rx) = unbounded_channel();
+        player
+            .write()
+            .await
+            .on_connect_session(u32::MAX, client_tx)
+            .await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(
+            tcp_server::Builder::new(Box::new(|| -> Box<dyn SessionDelegate> {
+                Box::new(Peer::with_max_message_size(DEFAULT_MAX_MESSAGE_SIZE))
+            }))
+            .build_with_listener(listener, shutdown_rx),
+        );
+
+        let mut client = ControlClient::connect(&addr, &username, &password)
+            .await
+            .unwrap();
+        assert_eq!(client.player_id(), player_id);
+        assert!(client.login_tunnels().is_empty());
+        let mut notify = client.subscribe().unwrap();
+
+        // 管理登录不会顶掉在线的客户端
+        assert!(player.read().await.is_online());
+        assert!(client_rx.try_recv().is_err());
+
+        let tunnel_id = client
+            .add_tunnel(Tunnel {
+                source: Some(TunnelPoint {
+                    addr: "127.0.0.1:40311".into(),
+                }),
+                endpoint: Some(TunnelPoint {
+                    addr: "127.0.0.1:1".into(),
+                }),
+                enabled: true,
+                sender: player_id,
+                receiver: player_id,
+                tunnel_type: TunnelType::Tcp.into(),
+                encryption_method: "None".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let ntf = notify.recv().await.unwrap();
+        assert!(!ntf.is_delete);
+        assert_eq!(ntf.tunnel.unwrap().id, tunnel_id);
+
+        let tunnels = client.query_tunnels().await.unwrap();
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].id, tunnel_id);
+
+        client.delete_tunnel(tunnel_id).await.unwrap();
+        assert!(client.query_tunnels().await.unwrap().is_empty());
+
+        // 客户端会话一直在线, 收到的只有通道变更通知
+        assert!(player.read().await.is_online());
+        while let Ok(message) = client_rx.try_recv() {
+            assert!(matches!(message, WriterMessage::Send(..)));
+        }
+
+        drop(client);
+        let _ = shutdown_tx.send(());
+        server.await.unwrap().unwrap();
+    }
+}
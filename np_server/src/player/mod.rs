@@ -1,8 +1,10 @@
 use crate::peer::package_and_send_message;
 use log::trace;
 use np_base::net::WriterMessage;
+use np_proto::frame::encode_frame;
 use np_proto::generic;
 use np_proto::message_map::MessageType;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::RwLock;
@@ -15,6 +17,8 @@ pub struct Player {
     player_id: PlayerId,
     // 会话id
     session_id: u32,
+    // 管理登录的会话, 会话id -> 发送队列, 只接收通道变更等通知
+    watchers: HashMap<u32, UnboundedSender<WriterMessage>>,
 }
 
 impl Player {
@@ -23,6 +27,7 @@ impl Player {
             tx: None,
             player_id,
             session_id: 0,
+            watchers: HashMap::new(),
         }))
     }
 
@@ -52,17 +57,50 @@ impl Player {
         package_and_send_message(&self.tx, -serial, message, true).await
     }
 
-    // #[inline]
-    // pub async fn send_request(&self, _message: &MessageType) -> anyhow::Result<MessageType> {
-    //     todo!();
-    // }
+    // 向玩家发起请求, 不等待回复, 回复由 Peer::handle_response 按消息类型处理
+    #[inline]
+    pub async fn send_request(&self, message: &MessageType) -> anyhow::Result<()> {
+        package_and_send_message(&self.tx, -1, message, true).await
+    }
 
     #[inline]
-    #[allow(dead_code)]
     pub async fn send_push(&self, message: &MessageType) -> anyhow::Result<()> {
         package_and_send_message(&self.tx, 0, message, true).await
     }
 
+    // 通知玩家和管理登录的会话
+    pub async fn send_notify(&self, message: &MessageType) {
+        if self.is_online() {
+            let _ = self.send_push(message).await;
+        }
+        if self.watchers.is_empty() {
+            return;
+        }
+        if let Some(buf) = encode_frame(0, message) {
+            for tx in self.watchers.values() {
+                let _ = tx.send(WriterMessage::Send(buf.clone(), true));
+            }
+        }
+    }
+
+    // 管理登录的会话上线
+    pub fn add_watcher(&mut self, session_id: u32, tx: UnboundedSender<WriterMessage>) {
+        trace!(
+            "add_watcher, player_id: {}, session_id: {session_id}",
+            self.player_id
+        );
+        self.watchers.insert(session_id, tx);
+    }
+
+    // 管理登录的会话离线
+    pub fn remove_watcher(&mut self, session_id: u32) {
+        trace!(
+            "remove_watcher, player_id: {}, session_id: {session_id}",
+            self.player_id
+        );
+        self.watchers.remove(&session_id);
+    }
+
     #[inline]
     #[allow(dead_code)]
     pub fn flush(&self) {
@@ -94,7 +132,7 @@ impl Player {
         tx: UnboundedSender<WriterMessage>,
     ) {
         trace!("on_connect_session, player_id: {}", self.player_id);
-        assert_eq!(self.is_online(), false);
+        assert!(!self.is_online());
         self.session_id = session_id;
         self.tx = Some(tx);
     }
@@ -0,0 +1,91 @@
+//! systemd的就绪通知和watchdog
+//!
+//! 由systemd以 `Type=notify` 启动时环境变量 `NOTIFY_SOCKET` 指向接收通知的Unix数据报套接字,
+//! 没有该变量或不是Linux时什么也不做, 与普通进程相同
+
+use log::{info, warn};
+use std::ffi::OsStr;
+use std::time::Duration;
+
+/// 向systemd发送状态, 例如 `READY=1`
+pub(crate) fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(err) = send(&path, state) {
+        warn!("sd_notify {state} failed: {err}");
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send(path: &OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    // 以@开头的是抽象命名空间的套接字
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_path: &OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// systemd要求的watchdog间隔, 没有开启watchdog或不是发给本进程时为None
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// 开启了watchdog时按间隔的一半发送 `WATCHDOG=1`, 运行时卡住不再发送时由systemd重启进程
+pub(crate) async fn watchdog_loop() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    info!(
+        "systemd watchdog enabled, interval: {}ms",
+        interval.as_millis()
+    );
+    let mut interval = tokio::time::interval(interval / 2);
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    #[test]
+    fn test_send() {
+        let path = std::env::temp_dir().join(format!("np_server_notify_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        let _ = std::fs::remove_file(&path);
+
+        let name = format!("@np_server_notify_{}", std::process::id());
+        let server =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name[1..]).unwrap()).unwrap();
+        send(OsStr::new(&name), "STOPPING=1").unwrap();
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+    }
+}
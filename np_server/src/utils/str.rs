@@ -1,4 +1,6 @@
-use std::net::SocketAddr;
+use np_base::proxy::unix_socket_path;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 /// 是否只包含ASCII码并且不包含空格
 pub fn is_ascii_nospace(s: &str) -> bool {
@@ -7,12 +9,12 @@ pub fn is_ascii_nospace(s: &str) -> bool {
 
 /// 是否是有效的用户名
 pub fn is_valid_username(s: &str) -> bool {
-    s.len() >= 1 && s.len() <= 30 && is_ascii_nospace(s)
+    !s.is_empty() && s.len() <= 30 && is_ascii_nospace(s)
 }
 
 /// 是否是有效的密码
 pub fn is_valid_password(s: &str) -> bool {
-    s.len() >= 1 && s.len() <= 15 && is_ascii_nospace(s)
+    !s.is_empty() && s.len() <= 15 && is_ascii_nospace(s)
 }
 
 /// 是否是有效的域名
@@ -44,33 +46,276 @@ pub fn is_valid_domain(domain: &str) -> bool {
     true
 }
 
-/// 是否是有效的隧道入口地址
-pub fn is_valid_tunnel_source_address(addr: &str) -> bool {
-    addr.parse::<SocketAddr>().is_ok()
+/// 解析后的隧道地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedAddress {
+    /// IP地址和端口
+    Socket(SocketAddr),
+    /// 域名和端口
+    Domain(String, u16),
+    /// unix套接字路径
+    Unix(String),
 }
 
-/// 是否是有效的隧道出口地址
-pub fn is_valid_tunnel_endpoint_address(addr: &str) -> bool {
-    if addr.parse::<SocketAddr>().is_ok() {
-        true
+impl ParsedAddress {
+    /// 端口, unix套接字没有端口
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ParsedAddress::Socket(addr) => Some(addr.port()),
+            ParsedAddress::Domain(_, port) => Some(*port),
+            ParsedAddress::Unix(_) => None,
+        }
+    }
+}
+
+/// 隧道地址格式错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrError {
+    /// 缺少端口
+    MissingPort,
+    /// 端口不是数字或超出范围
+    InvalidPort(String),
+    /// 主机不是有效的IP地址或域名
+    InvalidHost(String),
+    /// IPv6地址没有用方括号包围, 或方括号不匹配
+    BadIpv6Brackets,
+    /// unix套接字路径不是绝对路径
+    InvalidUnixPath(String),
+}
+
+impl fmt::Display for AddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddrError::MissingPort => write!(f, "missing port, expected host:port"),
+            AddrError::InvalidPort(port) => {
+                write!(f, "invalid port '{port}', expected a number in 0-65535")
+            }
+            AddrError::InvalidHost(host) => write!(f, "invalid host '{host}'"),
+            AddrError::BadIpv6Brackets => {
+                write!(
+                    f,
+                    "IPv6 address must be enclosed in brackets, e.g. [::1]:8080"
+                )
+            }
+            AddrError::InvalidUnixPath(path) => {
+                write!(f, "unix socket path '{path}' must be absolute")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddrError {}
+
+/// 拆分 `host:port` 或 `[IPv6]:port`, 返回主机(不含方括号)和端口
+fn split_host_port(addr: &str) -> Result<(&str, u16), AddrError> {
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or(AddrError::BadIpv6Brackets)?;
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(AddrError::BadIpv6Brackets);
+        }
+        match rest.strip_prefix(':') {
+            Some(port) => (host, port),
+            None if rest.is_empty() => return Err(AddrError::MissingPort),
+            None => return Err(AddrError::BadIpv6Brackets),
+        }
     } else {
-        let s: Vec<&str> = addr.split(":").collect();
-        if s.len() == 2 && is_valid_domain(s[0]) && s[1].parse::<u16>().is_ok() {
-            return true;
+        if addr.contains(['[', ']']) {
+            return Err(AddrError::BadIpv6Brackets);
+        }
+        let (host, port) = addr.rsplit_once(':').ok_or(AddrError::MissingPort)?;
+        // 多个冒号只能是没有方括号的IPv6地址
+        if host.contains(':') {
+            return Err(AddrError::BadIpv6Brackets);
         }
-        false
+        (host, port)
+    };
+
+    if port.is_empty() {
+        return Err(AddrError::MissingPort);
+    }
+    if host.is_empty() {
+        return Err(AddrError::InvalidHost(host.to_string()));
     }
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| AddrError::InvalidPort(port.to_string()))?;
+    Ok((host, port))
 }
 
-/// 获取隧道端口
+/// 解析隧道入口地址, 支持 `IP:端口` 和 `unix:/path/to.sock`
+pub fn parse_tunnel_source_address(addr: &str) -> Result<ParsedAddress, AddrError> {
+    if let Some(path) = unix_socket_path(addr) {
+        if !path.starts_with('/') {
+            return Err(AddrError::InvalidUnixPath(path.to_string()));
+        }
+        return Ok(ParsedAddress::Unix(path.to_string()));
+    }
+    let (host, port) = split_host_port(addr)?;
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(ParsedAddress::Socket(SocketAddr::new(ip, port))),
+        Err(_) => Err(AddrError::InvalidHost(host.to_string())),
+    }
+}
+
+/// 解析隧道出口地址, 支持 `IP:端口` 和 `域名:端口`
+pub fn parse_tunnel_endpoint_address(addr: &str) -> Result<ParsedAddress, AddrError> {
+    let (host, port) = split_host_port(addr)?;
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        // 方括号中只能是IPv6地址
+        if ip.is_ipv4() && addr.starts_with('[') {
+            return Err(AddrError::BadIpv6Brackets);
+        }
+        return Ok(ParsedAddress::Socket(SocketAddr::new(ip, port)));
+    }
+    if is_valid_domain(host) {
+        Ok(ParsedAddress::Domain(host.to_string(), port))
+    } else {
+        Err(AddrError::InvalidHost(host.to_string()))
+    }
+}
+
+/// 获取隧道端口, 支持 `[::]:8080` 形式的IPv6地址
 pub fn get_tunnel_address_port(addr: &str) -> Option<u16> {
-    let s: Vec<&str> = addr.split(":").collect();
-    if s.len() == 2 {
-        return if let Ok(value) = s[1].parse::<u16>() {
-            Some(value)
-        } else {
+    split_host_port(addr).ok().map(|(_, port)| port)
+}
+
+/// 入口地址的端口写 `auto` 时由系统分配, 保存时转为端口0
+pub fn normalize_tunnel_source_address(addr: &str) -> String {
+    match addr.strip_suffix(":auto") {
+        Some(host) => format!("{host}:0"),
+        None => addr.to_string(),
+    }
+}
+
+/// 入口地址是否由系统分配端口
+pub fn is_auto_port_address(addr: &str) -> bool {
+    unix_socket_path(addr).is_none() && get_tunnel_address_port(addr) == Some(0)
+}
+
+/// 两个监听地址是否冲突: 端口相同, 并且IP相同或其中之一是可以覆盖对方的通配地址
+///
+/// IPv6通配地址按双栈处理, 同时覆盖IPv4地址
+pub fn is_listen_addr_overlapped(a: &SocketAddr, b: &SocketAddr) -> bool {
+    let covers =
+        |x: &SocketAddr, y: &SocketAddr| x.ip().is_unspecified() && (x.is_ipv6() || y.is_ipv4());
+    a.port() == b.port() && (a.ip() == b.ip() || covers(a, b) || covers(b, a))
+}
+
+/// 解析字节数, 支持 `K`/`KiB`、`M`/`MiB`、`G`/`GiB` 后缀(均按1024进位), 例如 `1MiB`
+pub fn parse_byte_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let index = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(index);
+    let number: usize = number.parse().map_err(|_| format!("invalid size: {s}"))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return Err(format!("invalid size unit: {s}")),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size is too large: {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addr() {
+        assert_eq!(get_tunnel_address_port("0.0.0.0:8118"), Some(8118));
+        assert_eq!(get_tunnel_address_port("[::]:8118"), Some(8118));
+        assert_eq!(get_tunnel_address_port("example.com:443"), Some(443));
+        assert_eq!(get_tunnel_address_port("::1"), None);
+        assert_eq!(get_tunnel_address_port("unix:/tmp/np.sock"), None);
+        assert_eq!(normalize_tunnel_source_address("[::]:auto"), "[::]:0");
+        assert_eq!(normalize_tunnel_source_address("0.0.0.0:80"), "0.0.0.0:80");
+        assert!(is_auto_port_address("0.0.0.0:0"));
+        assert!(!is_auto_port_address("0.0.0.0:80"));
+
+        assert_eq!(
+            parse_tunnel_source_address("[::]:8118"),
+            Ok(ParsedAddress::Socket("[::]:8118".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_tunnel_source_address("unix:/tmp/np.sock")
+                .unwrap()
+                .port(),
             None
-        };
+        );
+        assert_eq!(
+            parse_tunnel_source_address("unix:np.sock"),
+            Err(AddrError::InvalidUnixPath("np.sock".into()))
+        );
+        assert_eq!(
+            parse_tunnel_source_address("example.com:80"),
+            Err(AddrError::InvalidHost("example.com".into()))
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("example.com:443"),
+            Ok(ParsedAddress::Domain("example.com".into(), 443))
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("127.0.0.1"),
+            Err(AddrError::MissingPort)
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("127.0.0.1:"),
+            Err(AddrError::MissingPort)
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("127.0.0.1:65536"),
+            Err(AddrError::InvalidPort("65536".into()))
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("::1:80"),
+            Err(AddrError::BadIpv6Brackets)
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("[::1:80"),
+            Err(AddrError::BadIpv6Brackets)
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("[127.0.0.1]:80"),
+            Err(AddrError::BadIpv6Brackets)
+        );
+        assert_eq!(
+            parse_tunnel_endpoint_address("bad_host:80"),
+            Err(AddrError::InvalidHost("bad_host".into()))
+        );
+
+        let addr = |x: &str| x.parse::<SocketAddr>().unwrap();
+        assert!(is_listen_addr_overlapped(
+            &addr("0.0.0.0:80"),
+            &addr("127.0.0.1:80")
+        ));
+        assert!(is_listen_addr_overlapped(
+            &addr("[::]:80"),
+            &addr("127.0.0.1:80")
+        ));
+        assert!(!is_listen_addr_overlapped(
+            &addr("0.0.0.0:80"),
+            &addr("[::1]:80")
+        ));
+        assert!(!is_listen_addr_overlapped(
+            &addr("127.0.0.1:80"),
+            &addr("127.0.0.2:80")
+        ));
+        assert!(!is_listen_addr_overlapped(
+            &addr("0.0.0.0:80"),
+            &addr("0.0.0.0:81")
+        ));
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!(parse_byte_size("4096"), Ok(4096));
+        assert_eq!(parse_byte_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_byte_size("1MiB"), Ok(1024 * 1024));
+        assert_eq!(parse_byte_size("2 gb"), Ok(2 << 30));
+        assert!(parse_byte_size("MiB").is_err());
+        assert!(parse_byte_size("1TiB").is_err());
     }
-    None
 }
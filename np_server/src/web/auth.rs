@@ -0,0 +1,88 @@
+use super::proto;
+use crate::global::config::GLOBAL_CONFIG;
+use actix_identity::IdentityExt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use log::{info, warn};
+use np_base::proxy::crypto::constant_time_eq;
+use std::future::{ready, Future};
+use std::pin::Pin;
+
+type AuthFuture<B> = Pin<Box<dyn Future<Output = Result<ServiceResponse<EitherBody<B>>, Error>>>>;
+
+/// `/api` 下需要认证的接口的中间件, 未通过认证时返回401, 不再调用接口
+pub(super) fn require_auth<S, B>(request: ServiceRequest, service: &S) -> AuthFuture<B>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    if let Err(msg) = authenticate(&request) {
+        let response = HttpResponse::Unauthorized().json(proto::GeneralResponse {
+            code: 401,
+            msg: msg.into(),
+        });
+        return Box::pin(ready(Ok(request
+            .into_response(response)
+            .map_into_right_body())));
+    }
+    let response = service.call(request);
+    Box::pin(async move { Ok(response.await?.map_into_left_body()) })
+}
+
+/// 携带了令牌则只按令牌校验, 否则检查登录会话
+fn authenticate(request: &ServiceRequest) -> Result<(), &'static str> {
+    match verify_bearer_token(request.request()) {
+        Some(Ok(label)) => {
+            info!(
+                "api token `{label}` access {} from {:?}",
+                request.path(),
+                request.peer_addr()
+            );
+            return Ok(());
+        }
+        Some(Err(_)) => {
+            warn!(
+                "invalid api token access {} from {:?}",
+                request.path(),
+                request.peer_addr()
+            );
+            return Err("Invalid api token.");
+        }
+        None => {}
+    }
+
+    match request.get_identity().and_then(|x| x.id()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Session expired, please log in again."),
+    }
+}
+
+/// 校验请求中的Bearer令牌
+///
+/// 请求未携带Authorization头时返回None, 否则返回校验结果(成功时为令牌标签)
+pub(super) fn verify_bearer_token(request: &HttpRequest) -> Option<Result<String, ()>> {
+    let value = request.headers().get(header::AUTHORIZATION)?;
+    let token = value
+        .to_str()
+        .ok()
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(str::trim)
+        .unwrap_or_default();
+    if token.is_empty() {
+        return Some(Err(()));
+    }
+
+    // 遍历全部令牌, 不提前退出
+    let mut label = None;
+    for api_token in &GLOBAL_CONFIG.web_api_tokens {
+        if constant_time_eq(api_token.token.expose().as_bytes(), token.as_bytes())
+            && label.is_none()
+        {
+            label = Some(api_token.label.clone());
+        }
+    }
+    Some(label.ok_or(()))
+}
@@ -0,0 +1,105 @@
+//! 通过WebSocket运行的控制通道
+//!
+//! 客户端所在网络只允许访问web端口时使用, 二进制消息中的数据与TCP控制通道完全相同,
+//! 握手完成后连接转交给TCP服务器, 与直接连接的客户端使用同一套会话逻辑
+
+use crate::global::config::GLOBAL_CONFIG;
+use crate::global::GLOBAL_CONTROL_STREAMS;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{handshake, Codec, Frame, Item, Message};
+use actix_web::body::BodyStream;
+use actix_web::{error, web, HttpRequest, HttpResponse};
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use log::debug;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// 会话与WebSocket之间的缓冲区大小
+const BRIDGE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 等待发给客户端的消息数量上限, 客户端读取慢时会话的写入被挂起
+const FRAME_QUEUE_SIZE: usize = 64;
+
+pub(crate) async fn control_channel(
+    request: HttpRequest,
+    mut payload: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let Some(streams) = GLOBAL_CONTROL_STREAMS.get() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let mut response = handshake(request.head())?;
+    let addr = request
+        .peer_addr()
+        .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+
+    let (session_stream, bridge) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+    streams
+        .send((Box::new(session_stream), addr))
+        .map_err(|_| error::ErrorServiceUnavailable("control channel is not running"))?;
+    debug!("control channel websocket from {addr}");
+
+    let (mut bridge_reader, mut bridge_writer) = tokio::io::split(bridge);
+    let (frame_tx, frame_rx) = mpsc::channel::<Bytes>(FRAME_QUEUE_SIZE);
+
+    // 客户端发来的消息写入会话
+    let pong_tx = frame_tx.clone();
+    actix_web::rt::spawn(async move {
+        let mut codec = Codec::new().max_size(GLOBAL_CONFIG.max_message_size);
+        let mut buffer = BytesMut::new();
+        while let Some(Ok(chunk)) = payload.next().await {
+            buffer.extend_from_slice(&chunk);
+            loop {
+                let data = match codec.decode(&mut buffer) {
+                    Ok(Some(
+                        Frame::Binary(data)
+                        | Frame::Continuation(
+                            Item::FirstBinary(data) | Item::Continue(data) | Item::Last(data),
+                        ),
+                    )) => data,
+                    Ok(Some(Frame::Ping(data))) => {
+                        let mut encoded = BytesMut::new();
+                        if codec.encode(Message::Pong(data), &mut encoded).is_ok() {
+                            let _ = pong_tx.send(encoded.freeze()).await;
+                        }
+                        continue;
+                    }
+                    Ok(Some(Frame::Close(_))) | Err(_) => return,
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                };
+                if bridge_writer.write_all(&data).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    // 会话写出的数据作为二进制消息发给客户端
+    actix_web::rt::spawn(async move {
+        let mut codec = Codec::new();
+        let mut data = vec![0u8; BRIDGE_BUFFER_SIZE];
+        let mut encoded = BytesMut::new();
+        loop {
+            let message = match bridge_reader.read(&mut data).await {
+                Ok(0) | Err(_) => Message::Close(None),
+                Ok(n) => Message::Binary(Bytes::copy_from_slice(&data[..n])),
+            };
+            let is_close = matches!(message, Message::Close(_));
+            if codec.encode(message, &mut encoded).is_err()
+                || frame_tx.send(encoded.split().freeze()).await.is_err()
+                || is_close
+            {
+                break;
+            }
+        }
+    });
+
+    let body = futures_util::stream::unfold(frame_rx, |mut frame_rx| async move {
+        let frame = frame_rx.recv().await?;
+        Some((Ok::<_, actix_web::Error>(frame), frame_rx))
+    });
+    let response = response.body(BodyStream::new(body));
+    Ok(HttpResponse::from(response.map_into_boxed_body()))
+}
@@ -1,9 +1,16 @@
-mod proto;
+mod auth;
+mod ctl;
+pub(crate) mod proto;
 
 use crate::global::config::GLOBAL_CONFIG;
+use crate::global::logger;
 use crate::global::manager::player::PlayerDbData;
+use crate::global::manager::tunnel::{join_outlet_weights, join_player_ids, TunnelError};
 use crate::global::manager::GLOBAL_MANAGER;
-use crate::global::GLOBAL_DB_POOL;
+use crate::global::{
+    db, GLOBAL_DB_POOL, GLOBAL_INIT_FINISHED, GLOBAL_TCP_SERVER_LISTENING,
+    GLOBAL_WEB_SERVER_LISTENING,
+};
 use crate::orm_entity::prelude::User;
 use crate::orm_entity::tunnel;
 use crate::utils::str::{is_valid_password, is_valid_username};
@@ -15,15 +22,28 @@ use actix_web::{
     error, middleware, web, App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer,
     Responder,
 };
+use log::info;
+use np_base::proxy::global_buffered_bytes;
+use np_base::proxy::stats::TrafficStats;
 use sea_orm::{EntityTrait, PaginatorTrait};
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::UNIX_EPOCH;
+
+/// 玩家的通道数已达到上限
+const CODE_TUNNEL_QUOTA_EXCEEDED: i32 = -3;
 
 /// http server
-pub async fn run_http_server(addr: &SocketAddr, web_base_dir: String) -> anyhow::Result<()> {
+pub async fn run_http_server(
+    addr: &SocketAddr,
+    web_base_dir: String,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
     let secret_key = Key::generate();
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             // 添加 Cors 中间件，并允许所有跨域请求
             .wrap(
@@ -32,17 +52,17 @@ pub async fn run_http_server(addr: &SocketAddr, web_base_dir: String) -> anyhow:
                     .allow_any_method()
                     .allow_any_header(),
             )
+            .service(web::resource("/healthz").route(web::get().to(healthz)))
+            .service(web::resource("/readyz").route(web::get().to(readyz)))
+            .service(web::resource("/ctl").route(web::get().to(ctl::control_channel)))
             .service(web::resource("/api/login").route(web::post().to(login)))
             .service(web::resource("/api/logout").route(web::post().to(logout)))
-            .service(web::resource("/api/test_auth").route(web::post().to(test_auth)))
-            .service(web::resource("/api/player_list").route(web::post().to(player_list)))
-            .service(web::resource("/api/remove_player").route(web::post().to(remove_player)))
-            .service(web::resource("/api/add_player").route(web::post().to(add_player)))
-            .service(web::resource("/api/update_player").route(web::post().to(update_player)))
-            .service(web::resource("/api/tunnel_list").route(web::post().to(tunnel_list)))
-            .service(web::resource("/api/remove_tunnel").route(web::post().to(remove_tunnel)))
-            .service(web::resource("/api/add_tunnel").route(web::post().to(add_tunnel)))
-            .service(web::resource("/api/update_tunnel").route(web::post().to(update_tunnel)))
+            // 其余接口都需要认证, 未认证时返回401
+            .service(
+                web::scope("/api")
+                    .wrap_fn(auth::require_auth)
+                    .configure(api_routes),
+            )
             .service(actix_files::Files::new("/", web_base_dir.as_str()).index_file("index.html"))
             .wrap(IdentityMiddleware::default())
             .wrap(
@@ -57,67 +77,104 @@ pub async fn run_http_server(addr: &SocketAddr, web_base_dir: String) -> anyhow:
             .wrap(middleware::NormalizePath::trim())
     })
     .workers(1)
+    .disable_signals()
     .bind(addr)?
-    .run()
-    .await?;
-    Ok(())
+    .run();
+    GLOBAL_WEB_SERVER_LISTENING.store(true, Ordering::Release);
+
+    // 退出信号由调用方统一处理
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown.await;
+        handle.stop(true).await;
+    });
+
+    let result = server.await;
+    GLOBAL_WEB_SERVER_LISTENING.store(false, Ordering::Release);
+    Ok(result?)
 }
 
-fn authentication(identity: Option<Identity>) -> Option<actix_web::Result<HttpResponse, Error>> {
-    let id = match identity.map(|id| id.id()) {
-        None => "anonymous".to_owned(),
-        Some(Ok(id)) => id,
-        Some(Err(err)) => return Some(Err(error::ErrorInternalServerError(err))),
-    };
+/// 需要认证的接口, 路径相对于 `/api`
+fn api_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/test_auth").route(web::post().to(test_auth)))
+        .service(web::resource("/player_list").route(web::post().to(player_list)))
+        .service(web::resource("/remove_player").route(web::post().to(remove_player)))
+        .service(web::resource("/add_player").route(web::post().to(add_player)))
+        .service(web::resource("/update_player").route(web::post().to(update_player)))
+        .service(web::resource("/tunnel_list").route(web::post().to(tunnel_list)))
+        .service(web::resource("/remove_tunnel").route(web::post().to(remove_tunnel)))
+        .service(web::resource("/add_tunnel").route(web::post().to(add_tunnel)))
+        .service(web::resource("/apply_tunnel").route(web::post().to(apply_tunnel)))
+        .service(web::resource("/update_tunnel").route(web::post().to(update_tunnel)))
+        .service(web::resource("/pause_tunnel").route(web::post().to(pause_tunnel)))
+        .service(web::resource("/restart_tunnel").route(web::post().to(restart_tunnel)))
+        .service(web::resource("/reset_tunnel_traffic").route(web::post().to(reset_tunnel_traffic)))
+        .service(web::resource("/tunnel_stats").route(web::post().to(tunnel_stats)))
+        .service(
+            web::resource("/tunnels/{id}/stats")
+                .route(web::get().to(get_tunnel_traffic_stats))
+                .route(web::delete().to(reset_tunnel_traffic_stats)),
+        )
+        .service(web::resource("/tunnel_health").route(web::post().to(tunnel_health)))
+        .service(web::resource("/tunnel_sessions").route(web::post().to(tunnel_sessions)))
+        .service(web::resource("/kill_session").route(web::post().to(kill_session)))
+        .service(web::resource("/log_level").route(web::post().to(log_level)))
+        .service(web::resource("/config").route(web::get().to(get_config)));
+}
 
-    if id == "anonymous" {
-        Some(Ok(HttpResponse::Ok().json(proto::GeneralResponse {
-            code: 10086,
-            msg: "Session expired, please log in again.".into(),
-        })))
-    } else {
-        None
+/// 存活探针, 进程在运行即返回200
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// 就绪探针, 初始化完成、tcp服务器已监听(未关闭时)且数据库可用时返回200
+async fn readyz() -> HttpResponse {
+    if !GLOBAL_INIT_FINISHED.load(Ordering::Acquire) {
+        return HttpResponse::ServiceUnavailable().body("initializing");
+    }
+    if GLOBAL_CONFIG.enable_tcp_server && !GLOBAL_TCP_SERVER_LISTENING.load(Ordering::Acquire) {
+        return HttpResponse::ServiceUnavailable().body("tcp server not listening");
+    }
+    if GLOBAL_MANAGER.proxy_manager.is_draining() {
+        return HttpResponse::ServiceUnavailable().body("draining");
+    }
+    match GLOBAL_DB_POOL.get() {
+        Some(db) if db.ping().await.is_ok() => HttpResponse::Ok().body("ok"),
+        _ => HttpResponse::ServiceUnavailable().body("database unavailable"),
     }
 }
 
 async fn test_auth(identity: Option<Identity>) -> actix_web::Result<impl Responder> {
+    // 已通过认证中间件, 使用令牌访问时没有登录会话
     let id = match identity.map(|id| id.id()) {
-        None => "anonymous".to_owned(),
         Some(Ok(id)) => id,
-        Some(Err(err)) => return Err(error::ErrorInternalServerError(err)),
+        _ => "api token".to_owned(),
     };
-
-    if id == "anonymous" {
-        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
-            code: 10086,
-            msg: "Session expired, please log in again.".into(),
-        }))
-    } else {
-        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
-            code: 0,
-            msg: format!("hello {}", id),
-        }))
-    }
+    Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+        code: 0,
+        msg: format!("hello {}", id),
+    }))
 }
 
 async fn logout(id: Identity) -> actix_web::Result<HttpResponse, Error> {
     id.logout();
     Ok(HttpResponse::Ok().json(proto::GeneralResponse {
-        code: 10086,
-        msg: "Session expired, please log in again.".into(),
+        code: 0,
+        msg: "Success".into(),
     }))
 }
 
 async fn login(request: HttpRequest, body: String) -> actix_web::Result<HttpResponse, Error> {
     let req = serde_json::from_str::<proto::LoginReq>(&body)?;
 
-    if GLOBAL_CONFIG.web_username.len() > 0
+    if !GLOBAL_CONFIG.web_username.is_empty()
         && GLOBAL_CONFIG.web_username == req.username
-        && GLOBAL_CONFIG.web_password == req.password
+        && GLOBAL_CONFIG.web_password.expose() == req.password
     {
         let content = format!(
             "{}-{}",
-            GLOBAL_CONFIG.web_username, GLOBAL_CONFIG.web_password
+            GLOBAL_CONFIG.web_username,
+            GLOBAL_CONFIG.web_password.expose()
         );
         let digest = md5::compute(content.as_bytes());
         let md5 = format!("{:x}", digest);
@@ -137,39 +194,28 @@ async fn login(request: HttpRequest, body: String) -> actix_web::Result<HttpResp
     }
 }
 
-async fn player_list(
-    identity: Option<Identity>,
-    body: String,
-) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
-    }
-
+async fn player_list(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::PlayerListRequest>(&body)?;
 
     let page_number = req.page_number;
-    let page_size = if req.page_size <= 0 || req.page_size > 100 {
+    let page_size = if req.page_size == 0 || req.page_size > 100 {
         1
     } else {
         req.page_size
     };
 
     // 分页查询玩家信息
-    let paginator = User::find().paginate(GLOBAL_DB_POOL.get().unwrap(), page_size as u64);
+    let paginator = User::find().paginate(db(), page_size as u64);
     let users = paginator
         .fetch_page(page_number as u64)
         .await
-        .map_err(|err| {
-            error::ErrorInternalServerError(format!("sqlx error:{}", err.to_string()))
-        })?;
+        .map_err(|err| error::ErrorInternalServerError(format!("sqlx error:{}", err)))?;
 
     // 查询玩家总条数
     let total_count = User::find()
-        .count(GLOBAL_DB_POOL.get().unwrap())
+        .count(db())
         .await
-        .map_err(|err| {
-            error::ErrorInternalServerError(format!("sqlx error:{}", err.to_string()))
-        })?;
+        .map_err(|err| error::ErrorInternalServerError(format!("sqlx error:{}", err)))?;
 
     let mut players: Vec<proto::PlayerListItem> = Vec::new();
 
@@ -195,14 +241,7 @@ async fn player_list(
     }))
 }
 
-async fn remove_player(
-    identity: Option<Identity>,
-    body: String,
-) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
-    }
-
+async fn remove_player(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::PlayerRemoveReq>(&body)?;
 
     if let Err(err) = GLOBAL_MANAGER.player_manager.delete_player(req.id).await {
@@ -218,11 +257,7 @@ async fn remove_player(
     }
 }
 
-async fn add_player(identity: Option<Identity>, body: String) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
-    }
-
+async fn add_player(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::PlayerAddReq>(&body)?;
 
     return match GLOBAL_MANAGER
@@ -235,14 +270,7 @@ async fn add_player(identity: Option<Identity>, body: String) -> actix_web::Resu
     };
 }
 
-async fn update_player(
-    identity: Option<Identity>,
-    body: String,
-) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
-    }
-
+async fn update_player(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::PlayerUpdateReq>(&body)?;
 
     // 参数长度越界检查
@@ -273,14 +301,7 @@ async fn update_player(
     }
 }
 
-async fn tunnel_list(
-    identity: Option<Identity>,
-    body: String,
-) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
-    }
-
+async fn tunnel_list(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::PlayerListRequest>(&body)?;
     let tunnel_list = GLOBAL_MANAGER
         .tunnel_manager
@@ -295,6 +316,12 @@ async fn tunnel_list(
     for data in tunnel_list {
         let custom_mapping: HashMap<String, String> =
             serde_json::from_str(&data.custom_mapping).map_or(HashMap::new(), |x| x);
+        let extra_senders = data.extra_senders();
+        let outlet_weights = data
+            .weighted_outlet_players()
+            .into_iter()
+            .map(|(player_id, weight)| proto::OutletWeight { player_id, weight })
+            .collect();
 
         tunnels.push(proto::TunnelListItem {
             id: data.id,
@@ -310,6 +337,46 @@ async fn tunnel_list(
             is_compressed: data.is_compressed == 1,
             encryption_method: data.encryption_method,
             custom_mapping,
+            paused: data.paused == 1,
+            max_session_lifetime: data.max_session_lifetime,
+            o2i_is_compressed: data.o2i_is_compressed.map(|x| x == 1),
+            o2i_encryption_method: data.o2i_encryption_method,
+            accept_proxy_protocol: data.accept_proxy_protocol == 1,
+            bytes_quota: data.bytes_quota,
+            bytes_used: data.bytes_used,
+            extra_senders,
+            write_coalesce: data.write_coalesce,
+            connect_retries: data.connect_retries,
+            connect_retry_delay: data.connect_retry_delay,
+            conn_rate_limit: data.conn_rate_limit,
+            conn_rate_burst: data.conn_rate_burst,
+            bind_device: data.bind_device,
+            balance_policy: data.balance_policy,
+            tls_cert: data.tls_cert,
+            tls_key: data.tls_key,
+            tls_alpn: data.tls_alpn,
+            backend_tls: data.backend_tls,
+            compression_dictionary: data.compression_dictionary,
+            auth_url: data.auth_url,
+            pool_size: data.pool_size,
+            pool_max_idle: data.pool_max_idle,
+            frame_prefix: data.frame_prefix,
+            reject_response: data.reject_response,
+            outlet_buffer_limit: data.outlet_buffer_limit,
+            external_id: data.external_id,
+            key_derivation: data.key_derivation,
+            reverse_stream: data.reverse_stream == 1,
+            outlet_weights,
+            inlet_nodelay: data.inlet_nodelay == 1,
+            outlet_nodelay: data.outlet_nodelay == 1,
+            compress_threshold: data.compress_threshold,
+            fast_open: data.fast_open == 1,
+            assigned_port: data.assigned_port,
+            encryption_fallback: data.encryption_fallback == 1,
+            error: GLOBAL_MANAGER
+                .proxy_manager
+                .tunnel_error(data.id)
+                .unwrap_or_default(),
         })
     }
 
@@ -320,14 +387,7 @@ async fn tunnel_list(
     }))
 }
 
-async fn remove_tunnel(
-    identity: Option<Identity>,
-    body: String,
-) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
-    }
-
+async fn remove_tunnel(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::TunnelRemoveReq>(&body)?;
     if let Err(err) = GLOBAL_MANAGER.tunnel_manager.delete_tunnel(req.id).await {
         Ok(HttpResponse::Ok().json(proto::GeneralResponse {
@@ -342,34 +402,19 @@ async fn remove_tunnel(
     }
 }
 
-async fn add_tunnel(identity: Option<Identity>, body: String) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
-    }
-
+async fn add_tunnel(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::TunnelAddReq>(&body)?;
     if let Err(err) = GLOBAL_MANAGER
         .tunnel_manager
-        .add_tunnel(tunnel::Model {
-            source: req.source,
-            endpoint: req.endpoint,
-            id: 0,
-            enabled: req.enabled,
-            sender: req.sender,
-            receiver: req.receiver,
-            description: req.description,
-            tunnel_type: req.tunnel_type,
-            password: req.password,
-            username: req.username,
-            is_compressed: req.is_compressed,
-            custom_mapping: serde_json::to_string(&req.custom_mapping)
-                .map_or("".to_string(), |x| x),
-            encryption_method: req.encryption_method,
-        })
+        .add_tunnel(new_tunnel_model(req))
         .await
     {
+        let code = match err.downcast_ref::<TunnelError>() {
+            Some(TunnelError::QuotaExceeded { .. }) => CODE_TUNNEL_QUOTA_EXCEEDED,
+            None => -1,
+        };
         Ok(HttpResponse::Ok().json(proto::GeneralResponse {
-            code: -1,
+            code,
             msg: err.to_string(),
         }))
     } else {
@@ -380,33 +425,198 @@ async fn add_tunnel(identity: Option<Identity>, body: String) -> actix_web::Resu
     }
 }
 
-async fn update_tunnel(
-    identity: Option<Identity>,
-    body: String,
-) -> actix_web::Result<impl Responder> {
-    if let Some(result) = authentication(identity) {
-        return result;
+/// 由新增通道请求创建通道
+fn new_tunnel_model(req: proto::TunnelAddReq) -> tunnel::Model {
+    tunnel::Model {
+        source: req.source,
+        endpoint: req.endpoint,
+        id: 0,
+        enabled: req.enabled,
+        sender: req.sender,
+        receiver: req.receiver,
+        description: req.description,
+        tunnel_type: req.tunnel_type,
+        password: req.password,
+        username: req.username,
+        is_compressed: req.is_compressed,
+        custom_mapping: serde_json::to_string(&req.custom_mapping).map_or("".to_string(), |x| x),
+        encryption_method: req.encryption_method,
+        paused: req.paused,
+        max_session_lifetime: req.max_session_lifetime,
+        o2i_is_compressed: req.o2i_is_compressed,
+        o2i_encryption_method: req.o2i_encryption_method,
+        accept_proxy_protocol: req.accept_proxy_protocol,
+        bytes_quota: req.bytes_quota,
+        bytes_used: 0,
+        extra_senders: join_player_ids(&req.extra_senders),
+        write_coalesce: req.write_coalesce,
+        connect_retries: req.connect_retries,
+        connect_retry_delay: req.connect_retry_delay,
+        conn_rate_limit: req.conn_rate_limit,
+        conn_rate_burst: req.conn_rate_burst,
+        bind_device: req.bind_device,
+        balance_policy: req.balance_policy,
+        tls_cert: req.tls_cert,
+        tls_key: req.tls_key,
+        tls_alpn: req.tls_alpn,
+        backend_tls: req.backend_tls,
+        compression_dictionary: req.compression_dictionary,
+        auth_url: req.auth_url,
+        pool_size: req.pool_size,
+        pool_max_idle: req.pool_max_idle,
+        frame_prefix: req.frame_prefix,
+        reject_response: req.reject_response,
+        outlet_buffer_limit: req.outlet_buffer_limit,
+        external_id: req.external_id,
+        key_derivation: req.key_derivation,
+        reverse_stream: req.reverse_stream,
+        outlet_weights: join_weights(&req.outlet_weights),
+        inlet_nodelay: req.inlet_nodelay,
+        outlet_nodelay: req.outlet_nodelay,
+        compress_threshold: req.compress_threshold,
+        fast_open: req.fast_open,
+        assigned_port: 0,
+        encryption_fallback: req.encryption_fallback,
     }
+}
 
+/// 把修改请求应用到现有通道上, 请求中没有设置的字段保持原设置
+fn apply_tunnel_update(mut tunnel: tunnel::Model, req: proto::TunnelUpdateReq) -> tunnel::Model {
+    tunnel.source = req.source;
+    tunnel.endpoint = req.endpoint;
+    tunnel.enabled = req.enabled;
+    tunnel.sender = req.sender;
+    tunnel.receiver = req.receiver;
+    tunnel.description = req.description;
+    tunnel.tunnel_type = req.tunnel_type;
+    tunnel.password = req.password;
+    tunnel.username = req.username;
+    tunnel.is_compressed = req.is_compressed;
+    tunnel.encryption_method = req.encryption_method;
+    tunnel.custom_mapping =
+        serde_json::to_string(&req.custom_mapping).map_or("".to_string(), |x| x);
+
+    macro_rules! patch {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if let Some(value) = req.$field {
+                    tunnel.$field = value;
+                }
+            )*
+        };
+    }
+    patch!(
+        paused,
+        max_session_lifetime,
+        o2i_is_compressed,
+        o2i_encryption_method,
+        accept_proxy_protocol,
+        bytes_quota,
+        write_coalesce,
+        connect_retries,
+        connect_retry_delay,
+        conn_rate_limit,
+        conn_rate_burst,
+        bind_device,
+        balance_policy,
+        tls_cert,
+        tls_key,
+        tls_alpn,
+        backend_tls,
+        compression_dictionary,
+        auth_url,
+        pool_size,
+        pool_max_idle,
+        frame_prefix,
+        reject_response,
+        outlet_buffer_limit,
+        external_id,
+        key_derivation,
+        reverse_stream,
+        inlet_nodelay,
+        outlet_nodelay,
+        compress_threshold,
+        fast_open,
+        encryption_fallback,
+    );
+    if let Some(extra_senders) = req.extra_senders {
+        tunnel.extra_senders = join_player_ids(&extra_senders);
+    }
+    if let Some(outlet_weights) = req.outlet_weights {
+        tunnel.outlet_weights = join_weights(&outlet_weights);
+    }
+    tunnel
+}
+
+fn join_weights(weights: &[proto::OutletWeight]) -> String {
+    join_outlet_weights(
+        &weights
+            .iter()
+            .map(|x| (x.player_id, x.weight))
+            .collect::<Vec<_>>(),
+    )
+}
+
+async fn apply_tunnel(body: String) -> actix_web::Result<impl Responder> {
+    let req = serde_json::from_str::<proto::TunnelAddReq>(&body)?;
+    match GLOBAL_MANAGER
+        .tunnel_manager
+        .apply_tunnel(new_tunnel_model(req))
+        .await
+    {
+        Ok((id, created)) => Ok(HttpResponse::Ok().json(proto::TunnelApplyResponse {
+            msg: "Success".into(),
+            code: 0,
+            id,
+            created,
+        })),
+        Err(err) => Ok(HttpResponse::Ok().json(proto::TunnelApplyResponse {
+            msg: err.to_string(),
+            code: -1,
+            id: 0,
+            created: false,
+        })),
+    }
+}
+
+async fn update_tunnel(body: String) -> actix_web::Result<impl Responder> {
     let req = serde_json::from_str::<proto::TunnelUpdateReq>(&body)?;
+    let current = GLOBAL_MANAGER
+        .tunnel_manager
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .find(|x| x.id == req.id)
+        .cloned();
+    let Some(current) = current else {
+        return Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: -1,
+            msg: format!("Unable to find tunnel_id: {}", req.id),
+        }));
+    };
     if let Err(err) = GLOBAL_MANAGER
         .tunnel_manager
-        .update_tunnel(tunnel::Model {
-            source: req.source,
-            endpoint: req.endpoint,
-            id: req.id,
-            enabled: req.enabled,
-            sender: req.sender,
-            receiver: req.receiver,
-            description: req.description,
-            tunnel_type: req.tunnel_type,
-            password: req.password,
-            username: req.username,
-            is_compressed: req.is_compressed,
-            custom_mapping: serde_json::to_string(&req.custom_mapping)
-                .map_or("".to_string(), |x| x),
-            encryption_method: req.encryption_method,
-        })
+        .update_tunnel(apply_tunnel_update(current, req))
+        .await
+    {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: -1,
+            msg: err.to_string(),
+        }))
+    } else {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: 0,
+            msg: "Success".into(),
+        }))
+    }
+}
+
+async fn pause_tunnel(body: String) -> actix_web::Result<impl Responder> {
+    let req = serde_json::from_str::<proto::TunnelPauseReq>(&body)?;
+    if let Err(err) = GLOBAL_MANAGER
+        .tunnel_manager
+        .set_tunnel_paused(req.id, req.paused)
         .await
     {
         Ok(HttpResponse::Ok().json(proto::GeneralResponse {
@@ -420,3 +630,366 @@ async fn update_tunnel(
         }))
     }
 }
+
+async fn restart_tunnel(body: String) -> actix_web::Result<impl Responder> {
+    let req = serde_json::from_str::<proto::TunnelRestartReq>(&body)?;
+    if let Err(err) = GLOBAL_MANAGER.proxy_manager.restart_tunnel(req.id).await {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: -1,
+            msg: err.to_string(),
+        }))
+    } else {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: 0,
+            msg: "Success".into(),
+        }))
+    }
+}
+
+async fn reset_tunnel_traffic(body: String) -> actix_web::Result<impl Responder> {
+    let req = serde_json::from_str::<proto::TunnelResetTrafficReq>(&body)?;
+    if let Err(err) = GLOBAL_MANAGER.tunnel_manager.reset_traffic(req.id).await {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: -1,
+            msg: err.to_string(),
+        }))
+    } else {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: 0,
+            msg: "Success".into(),
+        }))
+    }
+}
+
+async fn get_tunnel_traffic_stats(path: web::Path<u32>) -> actix_web::Result<impl Responder> {
+    let tunnel_id = path.into_inner();
+    let stats = GLOBAL_MANAGER.proxy_manager.traffic_stats(tunnel_id).await;
+    Ok(traffic_stats_response(tunnel_id, stats))
+}
+
+async fn reset_tunnel_traffic_stats(path: web::Path<u32>) -> actix_web::Result<impl Responder> {
+    let tunnel_id = path.into_inner();
+    let stats = GLOBAL_MANAGER
+        .proxy_manager
+        .reset_traffic_stats(tunnel_id)
+        .await;
+    if stats.is_some() {
+        info!("tunnel({tunnel_id}) traffic stats reset");
+    }
+    Ok(traffic_stats_response(tunnel_id, stats))
+}
+
+fn traffic_stats_response(tunnel_id: u32, stats: Option<TrafficStats>) -> HttpResponse {
+    match stats {
+        Some(stats) => HttpResponse::Ok().json(proto::TunnelTrafficStatsResponse {
+            tunnel_id,
+            bytes_in: stats.bytes_in,
+            bytes_out: stats.bytes_out,
+            bytes_spliced: stats.bytes_spliced,
+            bytes_copied: (stats.bytes_in + stats.bytes_out).saturating_sub(stats.bytes_spliced),
+            last_reset: stats
+                .since
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs()),
+        }),
+        None => HttpResponse::Ok().json(proto::GeneralResponse {
+            code: -1,
+            msg: format!("inlet({tunnel_id}) is not running on the server"),
+        }),
+    }
+}
+
+async fn log_level(body: String) -> actix_web::Result<impl Responder> {
+    let req = serde_json::from_str::<proto::LogLevelReq>(&body)?;
+    if let Some(level) = req.level {
+        if let Err(err) =
+            logger::set_log_level(&level, req.duration.map(std::time::Duration::from_secs))
+        {
+            return Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+                code: -1,
+                msg: err.to_string(),
+            }));
+        }
+    }
+    Ok(HttpResponse::Ok().json(proto::LogLevelResponse {
+        level: logger::log_level(),
+    }))
+}
+
+/// 实际生效的配置, 敏感项已替换
+async fn get_config() -> actix_web::Result<impl Responder> {
+    Ok(HttpResponse::Ok().json(GLOBAL_CONFIG.redacted()))
+}
+
+/// 通道入口的会话概要, 入口不在本机运行时返回None
+pub(crate) async fn collect_tunnel_sessions(
+    tunnel_id: u32,
+) -> Option<Vec<proto::SessionSummaryItem>> {
+    let sessions = GLOBAL_MANAGER
+        .proxy_manager
+        .list_sessions(tunnel_id)
+        .await?;
+    Some(
+        sessions
+            .into_iter()
+            .map(|x| proto::SessionSummaryItem {
+                session_id: x.session_id,
+                peer_addr: x.peer_addr.to_string(),
+                country: x.country,
+                bytes_in: x.bytes_in,
+                bytes_out: x.bytes_out,
+                age: x.age.as_secs(),
+                backend_addr: x.backend_addr,
+            })
+            .collect(),
+    )
+}
+
+async fn tunnel_sessions(body: String) -> actix_web::Result<impl Responder> {
+    let req = serde_json::from_str::<proto::TunnelSessionsReq>(&body)?;
+    match collect_tunnel_sessions(req.tunnel_id).await {
+        Some(sessions) => Ok(HttpResponse::Ok().json(proto::TunnelSessionsResponse { sessions })),
+        None => Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: -1,
+            msg: format!("inlet({}) is not running on the server", req.tunnel_id),
+        })),
+    }
+}
+
+async fn kill_session(body: String) -> actix_web::Result<impl Responder> {
+    let req = serde_json::from_str::<proto::KillSessionReq>(&body)?;
+    if GLOBAL_MANAGER
+        .proxy_manager
+        .kill_session(req.tunnel_id, req.session_id)
+        .await
+    {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: 0,
+            msg: "Success".into(),
+        }))
+    } else {
+        Ok(HttpResponse::Ok().json(proto::GeneralResponse {
+            code: -1,
+            msg: format!("inlet({}) is not running on the server", req.tunnel_id),
+        }))
+    }
+}
+
+/// 收集本机运行的通道统计
+pub(crate) async fn collect_tunnel_stats() -> Vec<proto::TunnelStatsItem> {
+    let tunnel_ids: Vec<u32> = GLOBAL_MANAGER
+        .tunnel_manager
+        .tunnels
+        .read()
+        .await
+        .iter()
+        .map(|x| x.id)
+        .collect();
+
+    let mut tunnels: Vec<proto::TunnelStatsItem> = Vec::new();
+    for tunnel_id in tunnel_ids {
+        if let Some(stats) = GLOBAL_MANAGER
+            .proxy_manager
+            .backpressure_stats(tunnel_id)
+            .await
+        {
+            let close_stats = GLOBAL_MANAGER
+                .proxy_manager
+                .close_stats(tunnel_id)
+                .await
+                .unwrap_or_default();
+            let breaker_state = GLOBAL_MANAGER
+                .proxy_manager
+                .breaker_state(tunnel_id)
+                .await
+                .map(|x| x.to_string())
+                .unwrap_or_default();
+            let pool_stats = GLOBAL_MANAGER
+                .proxy_manager
+                .pool_stats(tunnel_id)
+                .await
+                .unwrap_or_default();
+            let rtt_stats = GLOBAL_MANAGER
+                .proxy_manager
+                .rtt_stats(tunnel_id)
+                .await
+                .unwrap_or_default();
+            tunnels.push(proto::TunnelStatsItem {
+                tunnel_id,
+                session_count: stats.sessions.len(),
+                min_read_buf_len: stats.min_read_buf_len,
+                max_read_buf_len: stats.max_read_buf_len,
+                avg_read_buf_len: stats.avg_read_buf_len,
+                stalled_sessions: stats.stalled_sessions,
+                closed_by_peer: close_stats.closed_by_peer,
+                closed_by_idle: close_stats.closed_by_idle,
+                closed_by_lifetime: close_stats.closed_by_lifetime,
+                closed_by_kill: close_stats.closed_by_kill,
+                closed_by_overflow: close_stats.closed_by_overflow,
+                closed_by_shutdown: close_stats.closed_by_shutdown,
+                rejected_by_rate_limit: close_stats.rejected_by_rate_limit,
+                breaker_state,
+                pool_hits: pool_stats.hits,
+                pool_misses: pool_stats.misses,
+                pool_evicted_stale: pool_stats.evicted_stale,
+                pool_evicted_lru: pool_stats.evicted_lru,
+                pool_idle: pool_stats.idle,
+                rtt_samples: rtt_stats.samples,
+                rtt_min: rtt_stats.min.as_micros() as u64,
+                rtt_avg: rtt_stats.avg.as_micros() as u64,
+                rtt_max: rtt_stats.max.as_micros() as u64,
+                rtt_p99: rtt_stats.p99.as_micros() as u64,
+                sessions: stats
+                    .sessions
+                    .into_iter()
+                    .map(|x| proto::SessionStatsItem {
+                        session_id: x.session_id,
+                        read_buf_len: x.read_buf_len,
+                        stalled: x.stalled,
+                    })
+                    .collect(),
+            });
+        }
+    }
+
+    tunnels
+}
+
+async fn tunnel_health() -> actix_web::Result<impl Responder> {
+    let endpoints = GLOBAL_MANAGER
+        .health_manager
+        .list()
+        .await
+        .into_iter()
+        .map(|(tunnel_id, player_id, state)| proto::TunnelHealthItem {
+            tunnel_id,
+            player_id,
+            reachable: state.reachable,
+            latency: state.latency,
+            error: state.error,
+            checked_at: state.checked_at,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(proto::TunnelHealthResponse { endpoints }))
+}
+
+async fn tunnel_stats() -> actix_web::Result<impl Responder> {
+    let tunnels = collect_tunnel_stats().await;
+    Ok(HttpResponse::Ok().json(proto::TunnelStatsResponse {
+        tunnels,
+        global_buffered_bytes: global_buffered_bytes(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TUNNEL_FIELDS: &str = r#""source": "0.0.0.0:8000", "endpoint": "127.0.0.1:80",
+        "enabled": 1, "sender": 1, "receiver": 2, "tunnel_type": 0,
+        "password": "", "username": "", "is_compressed": 1, "encryption_method": "Aes128",
+        "custom_mapping": {}"#;
+
+    #[actix_web::test]
+    async fn test_api_requires_auth() {
+        use actix_web::http::{header, StatusCode};
+        use actix_web::test;
+
+        let app = test::init_service(
+            App::new()
+                .service(web::resource("/api/login").route(web::post().to(login)))
+                .service(web::resource("/test_login").route(web::post().to(
+                    |request: HttpRequest| async move {
+                        Identity::login(&request.extensions(), "admin".into()).unwrap();
+                        HttpResponse::Ok().finish()
+                    },
+                )))
+                .service(
+                    web::scope("/api")
+                        .wrap_fn(auth::require_auth)
+                        .configure(api_routes),
+                )
+                .wrap(IdentityMiddleware::default())
+                .wrap(
+                    SessionMiddleware::builder(CookieSessionStore::default(), Key::generate())
+                        .cookie_name("auth-id".to_owned())
+                        .cookie_secure(false)
+                        .build(),
+                ),
+        )
+        .await;
+
+        // 未登录和令牌错误时返回401, 不调用接口
+        let request = test::TestRequest::get().uri("/api/config").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let request = test::TestRequest::get()
+            .uri("/api/config")
+            .insert_header((header::AUTHORIZATION, "Bearer wrong"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::post().uri("/api/test_auth").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // 登录接口不需要认证
+        let request = test::TestRequest::post()
+            .uri("/api/login")
+            .set_payload(r#"{"username": "admin", "password": "wrong"}"#)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // 登录后可以访问
+        let request = test::TestRequest::post().uri("/test_login").to_request();
+        let response = test::call_service(&app, request).await;
+        let cookie = response.response().cookies().next().unwrap().into_owned();
+        let request = test::TestRequest::get()
+            .uri("/api/config")
+            .cookie(cookie.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = test::TestRequest::post()
+            .uri("/api/test_auth")
+            .cookie(cookie)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_apply_tunnel_update() {
+        let add: proto::TunnelAddReq = serde_json::from_str(&format!(
+            r#"{{ {TUNNEL_FIELDS}, "description": "", "o2i_is_compressed": 0, "o2i_encryption_method": "None",
+            "pool_size": 4, "extra_senders": [3] }}"#
+        ))
+        .unwrap();
+        let current = new_tunnel_model(add);
+
+        // 只修改描述, 其余可选字段保持原设置
+        let req: proto::TunnelUpdateReq = serde_json::from_str(&format!(
+            r#"{{ "id": 1, {TUNNEL_FIELDS}, "description": "updated" }}"#
+        ))
+        .unwrap();
+        let tunnel = apply_tunnel_update(current.clone(), req);
+        assert_eq!(tunnel.description, "updated");
+        assert_eq!(tunnel.o2i_is_compressed, Some(0));
+        assert_eq!(tunnel.o2i_encryption_method, Some("None".to_string()));
+        assert_eq!(tunnel.pool_size, 4);
+        assert_eq!(tunnel.extra_senders, current.extra_senders);
+
+        // 显式设置为null时恢复为与入口到出口方向相同
+        let req: proto::TunnelUpdateReq = serde_json::from_str(&format!(
+            r#"{{ "id": 1, {TUNNEL_FIELDS}, "description": "", "o2i_is_compressed": null,
+            "o2i_encryption_method": null, "pool_size": 0 }}"#
+        ))
+        .unwrap();
+        let tunnel = apply_tunnel_update(current, req);
+        assert_eq!(tunnel.o2i_is_compressed, None);
+        assert_eq!(tunnel.o2i_encryption_method, None);
+        assert_eq!(tunnel.pool_size, 0);
+    }
+}
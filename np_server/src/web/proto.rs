@@ -1,4 +1,6 @@
-use serde::{Deserialize, Serialize};
+use np_base::proxy::inlet::DEFAULT_COMPRESS_THRESHOLD;
+use np_base::proxy::outlet::DEFAULT_BUFFER_LIMIT;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 
 /// 登录请求
@@ -62,6 +64,7 @@ pub struct PlayerUpdateReq {
 }
 
 /// 玩家列表回复
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 pub struct TunnelListRequest {
     // 页码  从1开始
@@ -85,6 +88,46 @@ pub struct TunnelListItem {
     pub is_compressed: bool,
     pub encryption_method: String,
     pub custom_mapping: HashMap<String, String>,
+    pub paused: bool,
+    pub max_session_lifetime: u32,
+    pub o2i_is_compressed: Option<bool>,
+    pub o2i_encryption_method: Option<String>,
+    pub accept_proxy_protocol: bool,
+    pub bytes_quota: i64,
+    pub bytes_used: i64,
+    pub extra_senders: Vec<u32>,
+    pub write_coalesce: u32,
+    pub connect_retries: u32,
+    pub connect_retry_delay: u32,
+    pub conn_rate_limit: u32,
+    pub conn_rate_burst: u32,
+    pub bind_device: String,
+    pub balance_policy: u32,
+    pub tls_cert: String,
+    pub tls_key: String,
+    pub tls_alpn: String,
+    pub backend_tls: u32,
+    pub compression_dictionary: String,
+    pub auth_url: String,
+    pub pool_size: u32,
+    pub pool_max_idle: u32,
+    pub frame_prefix: u32,
+    pub reject_response: String,
+    pub outlet_buffer_limit: u32,
+    pub external_id: String,
+    pub key_derivation: u32,
+    pub reverse_stream: bool,
+    /// 所有出口玩家生效的权重
+    pub outlet_weights: Vec<OutletWeight>,
+    pub inlet_nodelay: bool,
+    pub outlet_nodelay: bool,
+    pub compress_threshold: u32,
+    pub fast_open: bool,
+    /// 入口地址端口为0(或auto)时系统实际分配的端口, 入口还没有启动时为0
+    pub assigned_port: u32,
+    pub encryption_fallback: bool,
+    /// 启动失败的错误信息, 为空时正常
+    pub error: String,
 }
 
 /// 通道列表回复
@@ -104,6 +147,7 @@ pub struct TunnelRemoveReq {
 /// 新增通道请求
 #[derive(Serialize, Deserialize)]
 pub struct TunnelAddReq {
+    /// 入口地址, 端口为0或 `auto` 时由系统分配, 分配的端口见通道列表的 `assigned_port`
     pub source: String,
     pub endpoint: String,
     pub enabled: u8,
@@ -114,8 +158,127 @@ pub struct TunnelAddReq {
     pub password: String,
     pub username: String,
     pub is_compressed: u8,
+    /// 加密方式, 可以是逗号分隔的偏好列表(例如 `Aes128,Xor,None`), 入口选择第一个出口支持的方法
     pub encryption_method: String,
     pub custom_mapping: HashMap<String, String>,
+    #[serde(default)]
+    pub paused: u8,
+    /// 会话最大存活时间(秒), 0表示不限制
+    #[serde(default)]
+    pub max_session_lifetime: u32,
+    /// 出口到入口方向是否压缩, 为空时与is_compressed相同
+    #[serde(default)]
+    pub o2i_is_compressed: Option<u8>,
+    /// 出口到入口方向的加密方式, 只能是单个方法, 为空时与入口选择的加密方式相同
+    #[serde(default)]
+    pub o2i_encryption_method: Option<String>,
+    /// 入口是否接受PROXY协议头部
+    #[serde(default)]
+    pub accept_proxy_protocol: u8,
+    /// 流量配额(字节), 为0时不限制
+    #[serde(default)]
+    pub bytes_quota: i64,
+    /// 除sender外同时提供出口的玩家
+    #[serde(default)]
+    pub extra_senders: Vec<u32>,
+    /// 合并写入的时间窗口(毫秒), 为0时不合并
+    #[serde(default)]
+    pub write_coalesce: u32,
+    /// 连接出口失败时的重试次数, 0表示不重试
+    #[serde(default)]
+    pub connect_retries: u32,
+    /// 连接重试的间隔(毫秒)
+    #[serde(default)]
+    pub connect_retry_delay: u32,
+    /// 每秒接受的新连接数, 0表示不限制
+    #[serde(default)]
+    pub conn_rate_limit: u32,
+    /// 允许的突发新连接数, 0表示与每秒连接数相同
+    #[serde(default)]
+    pub conn_rate_burst: u32,
+    /// 入口绑定的网卡名称, 为空时不绑定
+    #[serde(default)]
+    pub bind_device: String,
+    /// 多出口时新会话选择出口的策略: 0轮询, 1按客户端IP哈希
+    #[serde(default)]
+    pub balance_policy: u32,
+    /// 入口终止TLS使用的证书文件, 为空时不终止TLS
+    #[serde(default)]
+    pub tls_cert: String,
+    /// 入口终止TLS使用的私钥文件
+    #[serde(default)]
+    pub tls_key: String,
+    /// 入口终止TLS时支持的ALPN协议, 逗号分隔
+    #[serde(default)]
+    pub tls_alpn: String,
+    /// 出口连接后端的方式: 0明文, 1 TLS并校验证书, 2 TLS不校验证书
+    #[serde(default)]
+    pub backend_tls: u32,
+    /// 压缩字典, 为空时不使用, "base64:"开头时为内联的base64编码字典, 否则为入口和出口所在机器上的文件路径
+    #[serde(default)]
+    pub compression_dictionary: String,
+    /// SOCKS5入口的外部认证接口地址, 为空时使用通道的用户名密码认证
+    #[serde(default)]
+    pub auth_url: String,
+    /// 出口预先建立的连接数量, 0为不使用连接池, 只适合可以复用新连接的无状态协议
+    #[serde(default)]
+    pub pool_size: u32,
+    /// 连接池中连接的最长空闲时间(秒), 超过后丢弃重建, 0为默认60秒
+    #[serde(default)]
+    pub pool_max_idle: u32,
+    /// TCP通道按长度前缀分帧(0:不分帧 1:u16大端 2:u16小端 3:u32大端 4:u32小端)
+    #[serde(default)]
+    pub frame_prefix: u32,
+    /// 拒绝新连接时回复给客户端的内容, 为空时直接断开, 只对HTTP和SOCKS5通道生效
+    #[serde(default)]
+    pub reject_response: String,
+    /// 出口等待入口确认的数据上限(字节), 超过时暂停读取后端, 0为不限制
+    #[serde(default = "default_outlet_buffer_limit")]
+    pub outlet_buffer_limit: u32,
+    /// 外部系统中的通道标识, 不为空时唯一
+    #[serde(default)]
+    pub external_id: String,
+    /// 会话key的派生方式(0:随机 1:HKDF-SHA256 2:PBKDF2-SHA256), 派生时需要设置密码, 密码作为两端的预共享密钥不做哈希保存
+    #[serde(default)]
+    pub key_derivation: u32,
+    /// 出口是否为每个会话主动建立到服务器的连接, 用于出口无法被直接连接的场景
+    #[serde(default)]
+    pub reverse_stream: u8,
+    /// 多出口时各出口玩家的权重, 未列出的出口权重为1
+    #[serde(default)]
+    pub outlet_weights: Vec<OutletWeight>,
+    /// 入口接受的客户端连接是否关闭Nagle算法: 关闭时下行延迟低, 适合交互式流量; 开启时合并小包, 适合大量下载
+    #[serde(default = "default_inlet_nodelay")]
+    pub inlet_nodelay: u8,
+    /// 出口到后端的连接是否关闭Nagle算法: 关闭时上行延迟低, 适合交互式流量; 开启时合并小包, 适合大量上传
+    #[serde(default)]
+    pub outlet_nodelay: u8,
+    /// 启用压缩时小于该字节数的数据不压缩, 0表示全部压缩(旧格式, 兼容不支持压缩标记的出口)
+    #[serde(default = "default_compress_threshold")]
+    pub compress_threshold: u32,
+    /// 入口监听和出口连接后端是否使用TCP Fast Open, 只支持Linux, 需要系统开启 `net.ipv4.tcp_fastopen`
+    #[serde(default)]
+    pub fast_open: u8,
+    /// 出口拒绝偏好列表中的第一个加密方法时, 新会话是否改用后面的方法, 默认不降级
+    #[serde(default)]
+    pub encryption_fallback: u8,
+}
+
+/// 出口玩家的负载均衡权重
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutletWeight {
+    pub player_id: u32,
+    pub weight: u32,
+}
+
+/// 按external_id新增或更新通道的回复
+#[derive(Serialize, Deserialize)]
+pub struct TunnelApplyResponse {
+    pub msg: String,
+    pub code: i32,
+    pub id: u32,
+    /// true为新增, false为更新已有的通道
+    pub created: bool,
 }
 
 /// 修改通道请求
@@ -132,6 +295,302 @@ pub struct TunnelUpdateReq {
     pub password: String,
     pub username: String,
     pub is_compressed: u8,
+    /// 加密方式, 可以是逗号分隔的偏好列表(例如 `Aes128,Xor,None`), 入口选择第一个出口支持的方法
     pub encryption_method: String,
     pub custom_mapping: HashMap<String, String>,
+    /// 为空时保持原状态
+    #[serde(default)]
+    pub paused: Option<u8>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub max_session_lifetime: Option<u32>,
+    /// 出口到入口方向是否压缩, 没有设置时保持原设置, 为null时与is_compressed相同
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub o2i_is_compressed: Option<Option<u8>>,
+    /// 出口到入口方向的加密方式, 只能是单个方法, 没有设置时保持原设置, 为null时与入口选择的加密方式相同
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub o2i_encryption_method: Option<Option<String>>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub accept_proxy_protocol: Option<u8>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub bytes_quota: Option<i64>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub extra_senders: Option<Vec<u32>>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub write_coalesce: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub connect_retries: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub connect_retry_delay: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub conn_rate_limit: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub conn_rate_burst: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub bind_device: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub balance_policy: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub tls_key: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub tls_alpn: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub backend_tls: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub compression_dictionary: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub auth_url: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub pool_size: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub pool_max_idle: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub frame_prefix: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub reject_response: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub outlet_buffer_limit: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub external_id: Option<String>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub key_derivation: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub reverse_stream: Option<u8>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub outlet_weights: Option<Vec<OutletWeight>>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub inlet_nodelay: Option<u8>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub outlet_nodelay: Option<u8>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub compress_threshold: Option<u32>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub fast_open: Option<u8>,
+    /// 为空时保持原设置
+    #[serde(default)]
+    pub encryption_fallback: Option<u8>,
+}
+
+/// 暂停/恢复通道请求
+#[derive(Serialize, Deserialize)]
+pub struct TunnelPauseReq {
+    pub id: u32,
+    pub paused: bool,
+}
+
+/// 重启通道入口请求
+#[derive(Serialize, Deserialize)]
+pub struct TunnelRestartReq {
+    pub id: u32,
+}
+
+/// 清零通道流量请求
+#[derive(Serialize, Deserialize)]
+pub struct TunnelResetTrafficReq {
+    pub id: u32,
+}
+
+/// 会话背压状态
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionStatsItem {
+    pub session_id: u32,
+    pub read_buf_len: usize,
+    pub stalled: bool,
+}
+
+/// 通道统计子项
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TunnelStatsItem {
+    pub tunnel_id: u32,
+    pub session_count: usize,
+    pub min_read_buf_len: usize,
+    pub max_read_buf_len: usize,
+    pub avg_read_buf_len: usize,
+    pub stalled_sessions: usize,
+    pub closed_by_peer: u64,
+    pub closed_by_idle: u64,
+    pub closed_by_lifetime: u64,
+    pub closed_by_kill: u64,
+    /// 客户端不读取, 等待写入的数据超过上限被关闭的会话数
+    pub closed_by_overflow: u64,
+    /// 入口停止时被关闭的会话数, 包括通道超过配额被停止
+    pub closed_by_shutdown: u64,
+    /// 超过新连接速率限制被拒绝的连接数
+    pub rejected_by_rate_limit: u64,
+    /// 入口熔断状态: closed, open, half_open, 入口不在本机时为空
+    pub breaker_state: String,
+    /// 出口连接池的命中次数, 出口不在本机或未开启连接池时为0
+    pub pool_hits: u64,
+    /// 出口连接池为空时临时发起连接的次数
+    pub pool_misses: u64,
+    /// 出口连接池因空闲过久或已断开而丢弃的连接数
+    pub pool_evicted_stale: u64,
+    /// 所有连接池的空闲连接总数超过全局上限而丢弃的连接数
+    pub pool_evicted_lru: u64,
+    /// 出口连接池当前的空闲连接数
+    pub pool_idle: usize,
+    /// 最近参与统计的延迟探测次数, 入口不在本机或没有开启探测时为0
+    pub rtt_samples: usize,
+    /// 入口到出口的往返延迟(微秒)
+    pub rtt_min: u64,
+    pub rtt_avg: u64,
+    pub rtt_max: u64,
+    pub rtt_p99: u64,
+    pub sessions: Vec<SessionStatsItem>,
+}
+
+/// 入口会话列表请求
+#[derive(Serialize, Deserialize)]
+pub struct TunnelSessionsReq {
+    pub tunnel_id: u32,
+}
+
+/// 入口会话概要
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionSummaryItem {
+    pub session_id: u32,
+    /// 客户端地址
+    pub peer_addr: String,
+    /// 客户端所在的国家代码, 未知时为 `unknown`
+    pub country: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// 会话已存在的时间(秒)
+    pub age: u64,
+    /// 出口实际连接的后端地址(解析后的IP和端口), 连接成功前为空
+    pub backend_addr: String,
+}
+
+/// 入口会话列表回复
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TunnelSessionsResponse {
+    pub sessions: Vec<SessionSummaryItem>,
+}
+
+/// 强制关闭会话请求
+#[derive(Serialize, Deserialize)]
+pub struct KillSessionReq {
+    pub tunnel_id: u32,
+    pub session_id: u32,
+}
+
+/// 日志级别请求, 不设置级别时只返回当前级别
+#[derive(Serialize, Deserialize)]
+pub struct LogLevelReq {
+    #[serde(default)]
+    pub level: Option<String>,
+    /// 临时修改的持续时间(秒), 到期后恢复
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+/// 日志级别回复
+#[derive(Serialize, Deserialize)]
+pub struct LogLevelResponse {
+    pub level: String,
+}
+
+/// 通道统计回复
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TunnelStatsResponse {
+    pub tunnels: Vec<TunnelStatsItem>,
+    /// 所有会话已发送但还未被对端确认的字节数
+    pub global_buffered_bytes: usize,
+}
+
+/// 通道入口的累计流量回复, 重置时为重置前的统计
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TunnelTrafficStatsResponse {
+    pub tunnel_id: u32,
+    /// 从客户端收到的字节数
+    pub bytes_in: u64,
+    /// 写给客户端的字节数
+    pub bytes_out: u64,
+    /// 其中通过splice在内核中转发的字节数
+    pub bytes_spliced: u64,
+    /// 经过帧转发复制的字节数
+    pub bytes_copied: u64,
+    /// 上次重置的时间(unix时间戳, 秒), 从未重置时为入口启动的时间
+    pub last_reset: u64,
+}
+
+/// 出口到通道后端的连通性
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TunnelHealthItem {
+    pub tunnel_id: u32,
+    /// 出口玩家id, 0表示服务器
+    pub player_id: u32,
+    pub reachable: bool,
+    /// 建立连接的耗时(毫秒)
+    pub latency: u32,
+    /// 无法连接的原因
+    pub error: String,
+    /// 最后一次探测的时间(unix时间戳, 秒)
+    pub checked_at: i64,
+}
+
+/// 通道连通性回复
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TunnelHealthResponse {
+    pub endpoints: Vec<TunnelHealthItem>,
+}
+
+/// 区分没有设置的字段(None)和显式设置为null的字段(Some(None))
+fn double_option<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn default_outlet_buffer_limit() -> u32 {
+    DEFAULT_BUFFER_LIMIT
+}
+
+fn default_inlet_nodelay() -> u8 {
+    1
+}
+
+fn default_compress_threshold() -> u32 {
+    DEFAULT_COMPRESS_THRESHOLD
 }
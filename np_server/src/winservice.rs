@@ -0,0 +1,133 @@
+//! 作为Windows服务运行, 需要开启 `windows-service` 特性
+//!
+//! 服务的启动命令为 `np_server.exe --config-file <配置文件> run-service`, 启动完成后报告Running,
+//! 收到停止请求后与收到退出信号一样等待会话结束, 然后报告Stopped
+
+use crate::global::config::GLOBAL_CONFIG;
+use anyhow::Context;
+use log::error;
+use once_cell::sync::Lazy;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::{define_windows_service, service_dispatcher};
+
+const SERVICE_NAME: &str = "np_server";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// 启动期间告诉服务管理器的预计等待时间
+const START_WAIT_HINT: Duration = Duration::from_secs(30);
+
+static STATUS_HANDLE: OnceLock<ServiceStatusHandle> = OnceLock::new();
+
+// 等待状态每次报告时递增
+static CHECKPOINT: AtomicU32 = AtomicU32::new(0);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// 交给服务管理器运行, 服务停止后返回
+///
+/// 服务入口在服务管理器的线程上创建自己的运行时, 必须在创建任何运行时之前调用
+pub(crate) fn run_as_service() -> anyhow::Result<()> {
+    // 服务的工作目录是系统目录, 相对路径按可执行文件所在的目录解析
+    let mut path = std::env::current_exe()?;
+    path.pop();
+    std::env::set_current_dir(&path)?;
+    Lazy::force(&GLOBAL_CONFIG);
+
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("error registering generated `ffi_service_main` with the system")?;
+    Ok(())
+}
+
+fn service_main(_args: Vec<OsString>) {
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let stop_tx = Mutex::new(Some(stop_tx));
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(stop_tx) = stop_tx.lock().unwrap().take() {
+                    let _ = stop_tx.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let status_handle = match service_control_handler::register(SERVICE_NAME, event_handler) {
+        Ok(status_handle) => status_handle,
+        Err(err) => {
+            error!("windows service: failed to register control handler: {err}");
+            return;
+        }
+    };
+    let _ = STATUS_HANDLE.set(status_handle);
+    set_state(ServiceState::StartPending, START_WAIT_HINT, 0);
+
+    let result = Runtime::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| {
+            runtime.block_on(crate::run_server(async {
+                let _ = stop_rx.await;
+            }))
+        });
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("windows service: server stopped with error: {err}");
+            1
+        }
+    };
+    set_state(ServiceState::Stopped, Duration::ZERO, exit_code);
+}
+
+/// 报告服务状态, 不是作为服务运行时什么也不做
+fn set_state(state: ServiceState, wait_hint: Duration, exit_code: u32) {
+    let Some(status_handle) = STATUS_HANDLE.get() else {
+        return;
+    };
+    let (controls_accepted, checkpoint) = match state {
+        ServiceState::Running => (
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            0,
+        ),
+        ServiceState::StartPending | ServiceState::StopPending => (
+            ServiceControlAccept::empty(),
+            CHECKPOINT.fetch_add(1, Ordering::Relaxed) + 1,
+        ),
+        _ => (ServiceControlAccept::empty(), 0),
+    };
+    let status = ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint,
+        wait_hint,
+        process_id: None,
+    };
+    if let Err(err) = status_handle.set_service_status(status) {
+        error!("windows service: failed to set service status: {err}");
+    }
+}
+
+/// 启动完成
+pub(crate) fn set_running() {
+    set_state(ServiceState::Running, Duration::ZERO, 0);
+}
+
+/// 开始退出, 预计等待时间为等待会话结束的超时时间
+pub(crate) fn set_stopping() {
+    let wait_hint = Duration::from_secs(GLOBAL_CONFIG.shutdown_timeout + 10);
+    set_state(ServiceState::StopPending, wait_hint, 0);
+}